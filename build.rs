@@ -0,0 +1,72 @@
+//! Embeds the build metadata exposed at runtime (`proksi version`, admin API)
+
+use std::{env, fs, path::Path, process::Command};
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let manifest_dir = Path::new(&manifest_dir);
+
+    // Builds without the git directory (ex: from a source archive) can set it themselves
+    println!("cargo:rerun-if-env-changed=PROKSI_GIT_COMMIT");
+    let commit = env::var("PROKSI_GIT_COMMIT")
+        .ok()
+        .or_else(|| git_commit(manifest_dir))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let lock_file = manifest_dir.join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock_file.to_string_lossy());
+    let pingora = fs::read_to_string(&lock_file)
+        .ok()
+        .and_then(|lock| locked_version(&lock, "pingora"))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=PROKSI_GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=PROKSI_PINGORA_VERSION={pingora}");
+    println!(
+        "cargo:rustc-env=PROKSI_BUILD_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=PROKSI_BUILD_PROFILE={}",
+        env::var("PROFILE").unwrap_or_default()
+    );
+}
+
+/// Short hash of the checked out commit, rebuilt when it changes
+fn git_commit(dir: &Path) -> Option<String> {
+    let git_dir = dir.join(".git");
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+
+    println!(
+        "cargo:rerun-if-changed={}",
+        git_dir.join("HEAD").to_string_lossy()
+    );
+    if let Some(reference) = head.trim().strip_prefix("ref: ") {
+        let reference = git_dir.join(reference);
+        if reference.exists() {
+            println!("cargo:rerun-if-changed={}", reference.to_string_lossy());
+        }
+    }
+
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .current_dir(dir)
+        .output()
+        .ok()?;
+
+    let commit = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !commit.trim().is_empty()).then(|| commit.trim().to_string())
+}
+
+/// Version of the given package in `Cargo.lock`
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let entry = format!("name = \"{name}\"");
+    let mut lines = lock.lines();
+
+    lines.find(|line| *line == entry)?;
+    lines
+        .next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+        .map(str::to_string)
+}
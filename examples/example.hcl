@@ -125,7 +125,7 @@ routes = [
       },
       # New upstream
       {
-        ip = "10.1.2.23/24"
+        ip = "10.1.2.23"
         network = "shared"
         port = 3000
       }
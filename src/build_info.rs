@@ -0,0 +1,47 @@
+//! Version and build metadata embedded at compile time (see `build.rs`)
+
+use serde_json::{json, Value};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short hash of the commit the binary was built from (`unknown` without git)
+pub const GIT_COMMIT: &str = env!("PROKSI_GIT_COMMIT");
+
+/// Version of pingora the binary was built with (from `Cargo.lock`)
+pub const PINGORA_VERSION: &str = env!("PROKSI_PINGORA_VERSION");
+
+/// Target triple (ex: `x86_64-unknown-linux-gnu`)
+pub const TARGET: &str = env!("PROKSI_BUILD_TARGET");
+
+/// Cargo profile (`debug` or `release`)
+pub const PROFILE: &str = env!("PROKSI_BUILD_PROFILE");
+
+/// One line summary, as printed by `proksi version`
+pub fn summary() -> String {
+    format!(
+        "proksi {VERSION} (commit {GIT_COMMIT}, pingora {PINGORA_VERSION}, {TARGET}, {PROFILE})"
+    )
+}
+
+/// Build information served by the admin API
+pub fn to_json() -> Value {
+    json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "pingora_version": PINGORA_VERSION,
+        "target": TARGET,
+        "profile": PROFILE,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        assert!(summary().starts_with(&format!("proksi {VERSION} (commit ")));
+        assert_eq!(to_json()["version"], VERSION);
+        assert!(!PINGORA_VERSION.is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+//! Coalescing of the concurrent misses of a cache key: the first request
+//! (the writer of the cache lock) goes to the upstream, the other ones wait
+//! for its response to be cached and are then served from the cache.
+//!
+//! When the writer fails (upstream error or 5xx response), the lock is released
+//! as a transient error: one of the waiting requests becomes the new writer
+//! and tries the upstream again, the other ones keep waiting.
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use pingora_cache::{lock::CacheLock, HttpCache, NoCacheReason};
+
+/// The locks live as long as the process (pingora requires `'static` locks),
+/// one per timeout configured by the routes
+static CACHE_LOCKS: Lazy<DashMap<u64, &'static CacheLock>> = Lazy::new(DashMap::new);
+
+/// The cache lock of the routes waiting up to `timeout_secs` for the writer
+pub fn cache_lock(timeout_secs: u64) -> &'static CacheLock {
+    *CACHE_LOCKS
+        .entry(timeout_secs)
+        .or_insert_with(|| Box::leak(Box::new(CacheLock::new(Duration::from_secs(timeout_secs)))))
+}
+
+/// Releases the lock of a writer that failed to get a response, so that
+/// a waiting request retries the upstream right away
+pub fn release_failed_writer(cache: &mut HttpCache) {
+    // The lock can only be held while the cache is enabled
+    if cache.enabled() && cache.is_cache_lock_writer() {
+        cache.disable(NoCacheReason::InternalError);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_lock_is_shared_per_timeout() {
+        assert!(std::ptr::eq(cache_lock(5), cache_lock(5)));
+        assert!(!std::ptr::eq(cache_lock(5), cache_lock(10)));
+    }
+}
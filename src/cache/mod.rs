@@ -1,3 +1,6 @@
+pub mod coalescing;
 pub mod disk;
 pub mod memory_storage;
+pub mod requests;
+pub mod stale;
 pub mod tinyufo;
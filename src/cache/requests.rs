@@ -0,0 +1,136 @@
+//! Which requests use the cache of a route. `GET` and `HEAD` requests share
+//! the same entries: a `HEAD` hit is answered with the cached headers and no
+//! body (pingora stops after the header), without contacting the upstream.
+//!
+//! A `HEAD` miss is forwarded to the upstream as it is, so its response has
+//! no body and is never stored: the entry would answer the `GET` requests
+//! with an empty body.
+//!
+//! The `GET` requests upgrading their connection (ex: websockets) skip the
+//! cache, their exchange goes on with the upstream after its response.
+
+use http::{header, uri::PathAndQuery, Method, StatusCode};
+use openssl::base64;
+use pingora::http::RequestHeader;
+use pingora_cache::{CacheKey, NoCacheReason};
+
+/// Whether the cache is looked up for the request, the other methods (and
+/// the upgrades) always go to the upstream
+pub fn is_cached(req: &RequestHeader) -> bool {
+    matches!(req.method, Method::GET | Method::HEAD) && !req.headers.contains_key(header::UPGRADE)
+}
+
+/// The key of the request, the same for `GET` and `HEAD` requests
+pub fn cache_key(host: &str, req: &RequestHeader) -> CacheKey {
+    CacheKey::new(
+        host.to_string(),
+        base64::encode_block(
+            req.uri
+                .path_and_query()
+                .unwrap_or(&PathAndQuery::from_static("/"))
+                .as_str()
+                .as_bytes(),
+        ),
+        "",
+    )
+}
+
+/// Why the response to the request is not stored, if it is not
+pub fn uncacheable_reason(req: &RequestHeader, status: StatusCode) -> Option<NoCacheReason> {
+    if req.method == Method::HEAD {
+        return Some(NoCacheReason::Custom("HEAD responses have no body"));
+    }
+
+    if !is_cached(req) {
+        return Some(NoCacheReason::Custom("method or status not cacheable"));
+    }
+
+    // A transient error: the requests coalesced with this one try the upstream again
+    if status.is_server_error() {
+        return Some(NoCacheReason::InternalError);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use once_cell::sync::Lazy;
+    use pingora::http::ResponseHeader;
+    use pingora_cache::{key::CacheHashKey, trace::Span, CacheMeta, MemCache, Storage};
+
+    use super::*;
+
+    fn request(method: &str, path: &str) -> RequestHeader {
+        RequestHeader::build(method, path.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn test_head_hit() {
+        static STORAGE: Lazy<MemCache> = Lazy::new(MemCache::new);
+        let span = &Span::inactive().handle();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        // Stored by a `GET` request
+        let get = request("GET", "/assets/app.js?v=2");
+        assert!(is_cached(&get));
+        assert!(uncacheable_reason(&get, StatusCode::OK).is_none());
+
+        let mut headers = ResponseHeader::build(200, None).unwrap();
+        headers.insert_header("content-length", "5").unwrap();
+        let now = SystemTime::now();
+        let meta = CacheMeta::new(now + Duration::from_secs(60), now, 0, 0, headers);
+
+        runtime.block_on(async {
+            let key = cache_key("cache.test", &get);
+            let mut miss = STORAGE.get_miss_handler(&key, &meta, span).await.unwrap();
+            miss.write_body(bytes::Bytes::from_static(b"hello"), true)
+                .await
+                .unwrap();
+            miss.finish().await.unwrap();
+        });
+
+        // Then found by a `HEAD` request, with the headers of the `GET` response
+        let head = request("HEAD", "/assets/app.js?v=2");
+        assert!(is_cached(&head));
+        runtime.block_on(async {
+            let (meta, _) = STORAGE
+                .lookup(&cache_key("cache.test", &head), span)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(meta.headers().get("content-length").unwrap(), "5");
+        });
+    }
+
+    #[test]
+    fn test_head_miss() {
+        // Forwarded to the upstream, its (empty) response is not stored
+        let head = request("HEAD", "/assets/app.js");
+        assert!(is_cached(&head));
+        assert!(uncacheable_reason(&head, StatusCode::OK).is_some());
+
+        // Other requests do not use the cache at all
+        let post = request("POST", "/assets/app.js");
+        assert!(!is_cached(&post));
+        assert!(uncacheable_reason(&post, StatusCode::OK).is_some());
+
+        let mut upgrade = request("GET", "/chat");
+        upgrade.insert_header("upgrade", "websocket").unwrap();
+        assert!(!is_cached(&upgrade));
+
+        let get = request("GET", "/assets/app.js");
+        assert_eq!(
+            uncacheable_reason(&get, StatusCode::BAD_GATEWAY),
+            Some(NoCacheReason::InternalError)
+        );
+        assert_eq!(
+            cache_key("cache.test", &get).primary(),
+            cache_key("cache.test", &head).primary()
+        );
+    }
+}
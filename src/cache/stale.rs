@@ -0,0 +1,94 @@
+//! Expired responses served when the upstream fails, instead of an error. By
+//! default (as pingora does), only the failures of the upstream itself (refused
+//! connection, 5xx response...) are answered from the cache, for
+//! `stale_if_error_secs` past the expiry of the response.
+//!
+//! With `serve_stale_on_error`, any failure to get a response is (no healthy
+//! upstream included), for `max_stale_secs`. The stale responses are marked
+//! with `Warning: 110` and `X-Cache: STALE`.
+
+use pingora::{http::ResponseHeader, ErrorSource};
+
+use crate::config::RouteCache;
+
+/// The warning of the stale responses (RFC 7234, section 5.5.1)
+pub const WARNING: &str = "110 - \"Response is Stale\"";
+
+/// Whether the failure to get a response is answered with the stale response.
+/// `None` while revalidating: stale responses are only served on errors.
+pub fn should_serve(cache: &RouteCache, error: Option<&pingora::Error>) -> bool {
+    let Some(error) = error else {
+        return false;
+    };
+
+    if cache.serve_stale_on_error {
+        // The client itself is gone
+        return error.esource() != &ErrorSource::Downstream;
+    }
+
+    error.esource() == &ErrorSource::Upstream
+}
+
+/// How long past its expiry a response of the route can be served on errors
+pub fn stale_if_error_secs(cache: &RouteCache) -> u32 {
+    match cache.max_stale_secs {
+        Some(max_stale_secs) if cache.serve_stale_on_error => max_stale_secs,
+        _ => cache.stale_if_error_secs,
+    }
+}
+
+/// Marks a response served from the cache past its expiry
+pub fn mark(response: &mut ResponseHeader) -> pingora::Result<()> {
+    response.insert_header(http::header::WARNING, WARNING)?;
+    response.insert_header("X-Cache", "STALE")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pingora::ErrorType::{ConnectRefused, HTTPStatus, ReadError};
+
+    use super::*;
+
+    fn cache(serve_stale_on_error: bool, max_stale_secs: Option<u32>) -> RouteCache {
+        let mut cache: RouteCache = serde_json::from_str(r#"{ "enabled": true }"#).unwrap();
+        cache.serve_stale_on_error = serve_stale_on_error;
+        cache.max_stale_secs = max_stale_secs;
+        cache
+    }
+
+    #[test]
+    fn test_should_serve_stale() {
+        let refused = pingora::Error::new_up(ConnectRefused);
+        let no_upstream = pingora::Error::explain(HTTPStatus(503), "no healthy upstream");
+        let client_gone = pingora::Error::new_down(ReadError);
+
+        // Only the failures of the upstream by default, never while revalidating
+        let default = cache(false, None);
+        assert!(should_serve(&default, Some(refused.as_ref())));
+        assert!(!should_serve(&default, Some(no_upstream.as_ref())));
+        assert!(!should_serve(&default, None));
+
+        let on_error = cache(true, None);
+        assert!(should_serve(&on_error, Some(refused.as_ref())));
+        assert!(should_serve(&on_error, Some(no_upstream.as_ref())));
+        assert!(!should_serve(&on_error, Some(client_gone.as_ref())));
+        assert!(!should_serve(&on_error, None));
+    }
+
+    #[test]
+    fn test_stale_if_error_secs() {
+        assert_eq!(stale_if_error_secs(&cache(false, None)), 60);
+        assert_eq!(stale_if_error_secs(&cache(false, Some(3600))), 60);
+        assert_eq!(stale_if_error_secs(&cache(true, None)), 60);
+        assert_eq!(stale_if_error_secs(&cache(true, Some(3600))), 3600);
+    }
+
+    #[test]
+    fn test_mark() {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        mark(&mut response).unwrap();
+        assert_eq!(response.headers["warning"], WARNING);
+        assert_eq!(response.headers["x-cache"], "STALE");
+    }
+}
@@ -1,16 +1,20 @@
-use std::{borrow::Cow, collections::HashMap, path::PathBuf};
+use std::{borrow::Cow, collections::HashMap, path::PathBuf, time::Duration};
 
-use clap::{Args, Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use figment::{
     providers::{Env, Format, Serialized, Yaml},
     Figment, Provider,
 };
 use hcl::Hcl;
+use pingora::listeners::TcpSocketOptions;
 
 use serde::{Deserialize, Deserializer, Serialize};
 use tracing::level_filters::LevelFilter;
 
+use crate::{error::Error, proxy_server::matching::route_key};
+
 mod hcl;
+mod profiles;
 mod validate;
 
 /// Default fn for boolean values
@@ -34,6 +38,54 @@ fn default_cache_expire_secs() -> u64 {
     3600
 }
 
+fn default_coalesce_timeout_secs() -> u64 {
+    5
+}
+
+fn default_substitute_max_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_crl_refresh_secs() -> u64 {
+    3600
+}
+
+fn default_min_rsa_bits() -> u32 {
+    2048
+}
+
+fn default_min_ec_bits() -> u32 {
+    256
+}
+
+fn default_key_algorithms() -> Vec<KeyAlgorithm> {
+    vec![KeyAlgorithm::Rsa, KeyAlgorithm::Ec, KeyAlgorithm::Ed25519]
+}
+
+fn default_sticky_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_sticky_max_entries() -> usize {
+    100_000
+}
+
+fn default_sticky_virtual_nodes() -> usize {
+    160
+}
+
+fn default_queue_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_request_compression_min_bytes() -> u64 {
+    1024
+}
+
+fn default_request_compression_level() -> u32 {
+    6
+}
+
 fn default_cache_type() -> RouteCacheType {
     RouteCacheType::MemCache
 }
@@ -42,6 +94,55 @@ fn default_cache_path() -> PathBuf {
     PathBuf::from("/tmp")
 }
 
+fn default_compression_level() -> u32 {
+    6
+}
+
+fn default_compression_algorithms() -> Vec<CompressionAlgorithm> {
+    vec![CompressionAlgorithm::Brotli, CompressionAlgorithm::Gzip]
+}
+
+fn default_compression_zstd_level() -> u32 {
+    3
+}
+
+fn default_compression_content_types() -> Vec<Cow<'static, str>> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .into_iter()
+    .map(Cow::Borrowed)
+    .collect()
+}
+
+fn default_health_check_type() -> RouteHealthCheckType {
+    RouteHealthCheckType::Tcp
+}
+
+fn default_health_check_timeout_secs() -> u64 {
+    1
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    15
+}
+
+fn default_health_check_consecutive() -> usize {
+    1
+}
+
+fn default_health_check_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_check_expected_status() -> u16 {
+    200
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, ValueEnum)]
 pub(crate) enum DockerServiceMode {
     Swarm,
@@ -72,6 +173,30 @@ pub struct Docker {
     )]
     pub endpoint: Option<Cow<'static, str>>,
 
+    /// The docker host to connect to (ex: `tcp://host:2376`).
+    /// Same as `endpoint`, but takes precedence over it when set.
+    #[arg(long = "docker.host", required = false, value_parser)]
+    pub host: Option<Cow<'static, str>>,
+
+    /// Path to the client certificate used to connect to a TLS enabled docker daemon
+    /// (ex: `tcp://host:2376`). Requires `tls_key` and `tls_ca` as well.
+    #[arg(long = "docker.tls_cert", required = false, value_parser)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to the client certificate key
+    #[arg(long = "docker.tls_key", required = false, value_parser)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Path to the certificate authority that signed the docker daemon certificate
+    #[arg(long = "docker.tls_ca", required = false, value_parser)]
+    pub tls_ca: Option<PathBuf>,
+
+    /// The docker network whose IP is used as the upstream address of a container
+    /// attached to several networks. Can be overridden per container with the
+    /// `proksi.network` label.
+    #[arg(long = "docker.network", required = false, value_parser)]
+    pub network: Option<Cow<'static, str>>,
+
     /// Enables the docker label service
     /// (default: false)
     #[arg(
@@ -99,6 +224,11 @@ impl Default for Docker {
         Self {
             interval_secs: Some(15),
             endpoint: Some(Cow::Borrowed("unix:///var/run/docker.sock")),
+            host: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca: None,
+            network: None,
             enabled: Some(false),
             mode: DockerServiceMode::Container,
         }
@@ -112,8 +242,53 @@ pub struct LetsEncrypt {
     /// Whether to enable the background service that renews the certificates (default: true)
     pub enabled: Option<bool>,
 
-    /// Use the staging let's encrypt server (default: true)
+    /// Use the staging let's encrypt server (default: true).
+    /// Ignored when `directory_url` is set.
     pub staging: Option<bool>,
+
+    /// ACME directory of another provider (ex: ZeroSSL, Buypass).
+    /// Defaults to the Let's Encrypt one (production or staging)
+    pub directory_url: Option<Cow<'static, str>>,
+
+    /// Key ID of the external account binding (EAB), given by providers that
+    /// require accounts to be bound to an existing one (ex: ZeroSSL)
+    pub eab_kid: Option<Cow<'static, str>>,
+
+    /// HMAC key of the external account binding (base64url encoded)
+    pub eab_hmac_key: Option<Cow<'static, str>>,
+
+    /// URL that receives a JSON POST whenever a certificate is issued,
+    /// renewed or fails to be (default: none)
+    pub webhook_url: Option<Cow<'static, str>>,
+
+    /// How many times an order is attempted before giving up (default: 5)
+    pub challenge_attempts: Option<u32>,
+
+    /// Interval (in seconds) between validation polls. Also the base delay
+    /// for the (exponential) backoff between attempts (default: 5)
+    pub challenge_interval_secs: Option<u64>,
+
+    /// Overall time (in seconds) allowed for all attempts of an order (default: 300)
+    pub challenge_timeout_secs: Option<u64>,
+
+    /// How many orders are placed at once, the other hosts wait their turn (default: 4)
+    pub max_concurrent_orders: Option<usize>,
+
+    /// How long (in seconds) a challenge is answered when its order never
+    /// ends, such as an order abandoned by a crash (default: 3600)
+    pub challenge_ttl_secs: Option<u64>,
+
+    /// Whether the certificates of the configured hosts are issued (or loaded)
+    /// on boot, before their first request (default: false)
+    pub preissue: Option<bool>,
+
+    /// Certificates ordered without a route of their own (ex: a wildcard one,
+    /// serving the subdomains without a certificate)
+    #[serde(default)]
+    pub certificates: Vec<LetsEncryptCertificate>,
+
+    /// The DNS provider writing the TXT records of the DNS-01 challenges
+    pub dns01: Option<LetsEncryptDns01>,
 }
 
 impl Default for LetsEncrypt {
@@ -122,21 +297,130 @@ impl Default for LetsEncrypt {
             email: Cow::Borrowed("contact@example.com"),
             enabled: Some(true),
             staging: Some(true),
+            directory_url: None,
+            eab_kid: None,
+            eab_hmac_key: None,
+            webhook_url: None,
+            challenge_attempts: Some(5),
+            challenge_interval_secs: Some(5),
+            challenge_timeout_secs: Some(300),
+            max_concurrent_orders: Some(4),
+            challenge_ttl_secs: Some(3600),
+            preissue: Some(false),
+            certificates: vec![],
+            dns01: None,
         }
     }
 }
 
+/// How the control of the domain of a certificate is proven to the ACME provider
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AcmeChallenge {
+    /// A token answered on `/.well-known/acme-challenge/` (port 80)
+    #[default]
+    Http01,
+    /// A TXT record of `_acme-challenge.<domain>`, the only one for the
+    /// wildcard certificates
+    Dns01,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LetsEncryptCertificate {
+    /// The domain of the certificate (ex: `*.example.com`)
+    pub domain: Cow<'static, str>,
+
+    /// The challenge of the order (default: http01)
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LetsEncryptDns01 {
+    /// Records managed through the Cloudflare API
+    pub cloudflare: Option<Dns01Cloudflare>,
+
+    /// Records managed through the dynamic updates (RFC 2136) of a DNS server
+    pub rfc2136: Option<Dns01Rfc2136>,
+
+    /// The DNS server (`ip:port`) queried for the TXT records before they are
+    /// validated (default: the `rfc2136` server, or `1.1.1.1:53`)
+    pub resolver: Option<Cow<'static, str>>,
+
+    /// How long (in seconds) a TXT record is waited for (default: 120)
+    #[serde(default = "default_dns01_propagation_timeout_secs")]
+    pub propagation_timeout_secs: u64,
+
+    /// Interval (in seconds) between the queries of the TXT records (default: 5)
+    #[serde(default = "default_dns01_propagation_interval_secs")]
+    pub propagation_interval_secs: u64,
+}
+
+fn default_dns01_propagation_timeout_secs() -> u64 {
+    120
+}
+
+fn default_dns01_propagation_interval_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Dns01Cloudflare {
+    /// API token allowed to edit the DNS records of the zone
+    pub api_token: Cow<'static, str>,
+
+    /// The zone of the records (default: looked up from the domain)
+    pub zone_id: Option<Cow<'static, str>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Dns01Rfc2136 {
+    /// The primary server (`host:port`) accepting the updates
+    pub server: Cow<'static, str>,
+
+    /// The zone of the records (ex: `example.com`)
+    pub zone: Cow<'static, str>,
+
+    /// Name of the TSIG key signing the updates
+    pub key_name: Cow<'static, str>,
+
+    /// Secret of the TSIG key (base64)
+    pub key_secret: Cow<'static, str>,
+
+    /// Algorithm of the TSIG key (default: hmac-sha256)
+    #[serde(default)]
+    pub key_algorithm: TsigAlgorithm,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TsigAlgorithm {
+    #[default]
+    HmacSha256,
+    HmacSha512,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Path {
     // TLS
     /// Path to the certificates directory (where the certificates are stored)
     pub lets_encrypt: PathBuf,
+
+    /// Path to the file holding the pid of the running instance,
+    /// read by `proksi upgrade` to find the instance to take over
+    pub pid_file: PathBuf,
+
+    /// Path to the unix socket the listening sockets are handed over through
+    /// during an upgrade. The old and new instances must use the same one.
+    pub upgrade_sock: PathBuf,
 }
 
 impl Default for Path {
     fn default() -> Self {
         Self {
             lets_encrypt: PathBuf::from("/etc/proksi/letsencrypt"),
+            pid_file: PathBuf::from("/tmp/proksi.pid"),
+            upgrade_sock: PathBuf::from("/tmp/proksi_upgrade.sock"),
         }
     }
 }
@@ -156,13 +440,117 @@ pub struct RouteHeaderRemove {
     pub name: Cow<'static, str>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct RouteHeader {
-    /// The name of the header
+    /// Headers added to the responses (also `add_response`)
+    #[serde(alias = "add_response")]
     pub add: Option<Vec<RouteHeaderAdd>>,
 
-    /// The value of the header
+    /// Headers removed from the responses (also `remove_response`)
+    #[serde(alias = "remove_response")]
     pub remove: Option<Vec<RouteHeaderRemove>>,
+
+    /// Headers set on the requests sent to the upstreams (ex: `Host`)
+    pub add_request: Option<Vec<RouteHeaderAdd>>,
+
+    /// Headers removed from the requests sent to the upstreams
+    pub remove_request: Option<Vec<RouteHeaderRemove>>,
+
+    /// Whether the requests sent to the upstreams get the `X-Forwarded-For`
+    /// (the IP of the client appended to it), `X-Forwarded-Proto` and
+    /// `X-Forwarded-Host` headers (default: false)
+    #[serde(default)]
+    pub forwarded: bool,
+}
+
+/// A named set of middleware (headers, plugins) that routes can reference
+/// through `middleware_profiles` instead of repeating it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MiddlewareProfile {
+    /// Other profiles whose middleware is applied before this one
+    #[serde(default)]
+    pub include: Vec<Cow<'static, str>>,
+
+    /// Header modifications (same format as the route `headers`)
+    pub headers: Option<RouteHeader>,
+
+    /// Plugins (same format as the route `plugins`)
+    pub plugins: Option<Vec<RoutePlugin>>,
+}
+
+/// The HTTP version spoken to an upstream
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteUpstreamProtocol {
+    /// Negotiated through ALPN (h2, falling back to HTTP/1.1), HTTP/1.1 without TLS
+    #[default]
+    Auto,
+    /// Always HTTP/1.1
+    H1,
+    /// Always h2 (h2c without TLS)
+    H2,
+}
+
+/// How an upstream is reached, also given as a prefix of its `ip`
+/// (ex: 'https://10.0.0.1')
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamScheme {
+    /// Plain HTTP/1.1
+    Http,
+    /// TLS, the HTTP version is picked by `protocol`
+    Https,
+    /// Plain h2, without TLS (prior knowledge)
+    H2c,
+}
+
+impl UpstreamScheme {
+    /// Splits the scheme prefix (if any) off the address of an upstream
+    pub fn split(address: &str) -> Result<(Option<Self>, &str), String> {
+        let Some((scheme, rest)) = address.split_once("://") else {
+            return Ok((None, address));
+        };
+
+        let scheme = match scheme.to_ascii_lowercase().as_str() {
+            "http" => Self::Http,
+            "https" => Self::Https,
+            "h2c" => Self::H2c,
+            _ => {
+                return Err(format!(
+                    "has an unknown scheme {scheme}:// (expected http, https or h2c)"
+                ))
+            }
+        };
+
+        Ok((Some(scheme), rest.trim_end_matches('/')))
+    }
+}
+
+/// When the request bodies sent to an upstream are compressed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestCompressionMode {
+    /// Once the upstream advertised gzip (`Accept-Encoding` of its responses)
+    #[default]
+    Auto,
+    /// Always
+    Always,
+}
+
+/// Compression (gzip) of the request bodies sent to an upstream
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpstreamRequestCompression {
+    #[serde(default)]
+    pub mode: RequestCompressionMode,
+
+    /// Bodies smaller than this (in bytes) are sent as is (default: 1024).
+    /// Bodies of unknown length (chunked) are always compressed.
+    #[serde(default = "default_request_compression_min_bytes")]
+    pub min_bytes: u64,
+
+    /// The gzip level, from 1 to 9 (default: 6)
+    #[serde(default = "default_request_compression_level")]
+    pub level: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -183,6 +571,80 @@ pub struct RouteUpstream {
     pub sni: Option<String>,
 
     pub headers: Option<RouteHeader>,
+
+    /// Optional: The pool of the upstream, picked through the `geo_routing`
+    /// of the route. Upstreams without a pool form the default pool.
+    pub pool: Option<Cow<'static, str>>,
+
+    /// Optional: The availability zone of the upstream. Upstreams in the
+    /// `local_zone` of this instance are preferred while any is healthy.
+    pub zone: Option<Cow<'static, str>>,
+
+    /// Optional: The HTTP version spoken to the upstream (default: auto)
+    #[serde(default)]
+    pub protocol: RouteUpstreamProtocol,
+
+    /// Optional: How the upstream is reached (`http`, `https` or `h2c`), also
+    /// given as a prefix of `ip`. Without it, TLS is only used on port 443.
+    pub scheme: Option<UpstreamScheme>,
+
+    /// Optional: Compression of the request bodies sent to the upstream
+    pub request_compression: Option<UpstreamRequestCompression>,
+
+    /// Optional: Another address and/or port probed by the health check of the
+    /// route, while the traffic still goes to `ip` and `port`
+    pub health_check: Option<UpstreamHealthCheck>,
+
+    /// Optional: At most this many connections in use at once to the upstream
+    /// (one per request in flight, or per stream with h2). The other requests
+    /// go to the other upstreams of the route.
+    pub max_connections: Option<usize>,
+
+    /// Optional: Sends the header names in their original case to HTTP/1.1
+    /// upstreams, also for the requests of HTTP/2 clients (default: false)
+    #[serde(default)]
+    pub preserve_header_case: bool,
+
+    /// Optional: The HTTP/2 connections to the upstream
+    pub h2: Option<UpstreamH2>,
+
+    /// Optional: The paths served by the upstream (`path.patterns`). The
+    /// upstreams with the same patterns form a group, the requests matching
+    /// none of the groups go to the upstreams without `match_with`.
+    pub match_with: Option<RouteMatcher>,
+}
+
+/// The HTTP/2 connections to an upstream. Their flow-control windows are the
+/// ones of pingora: 8 MiB per stream and per connection.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamH2 {
+    /// Requests multiplexed on each connection, the other ones open new
+    /// connections (default: 2)
+    #[serde(default = "default_h2_max_streams")]
+    pub max_streams: usize,
+
+    /// Seconds between the pings checking the idle connections are alive,
+    /// 0 to send none (default: 60)
+    #[serde(default = "default_h2_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+}
+
+fn default_h2_max_streams() -> usize {
+    2
+}
+
+fn default_h2_ping_interval_secs() -> u64 {
+    60
+}
+
+/// Target of the health check of an upstream (ex: a management port)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UpstreamHealthCheck {
+    /// The address probed (defaults to the `ip` of the upstream)
+    pub address: Option<Cow<'static, str>>,
+
+    /// The port probed (defaults to the `port` of the upstream)
+    pub port: Option<u16>,
 }
 
 impl Default for RouteUpstream {
@@ -194,6 +656,50 @@ impl Default for RouteUpstream {
             weight: None,
             sni: None,
             headers: None,
+            pool: None,
+            zone: None,
+            protocol: RouteUpstreamProtocol::default(),
+            scheme: None,
+            request_compression: None,
+            health_check: None,
+            max_connections: None,
+            preserve_header_case: false,
+            h2: None,
+            match_with: None,
+        }
+    }
+}
+
+impl RouteUpstream {
+    /// Moves the scheme prefix of `ip` (if any) to `scheme`
+    pub fn split_scheme(&mut self) -> Result<(), String> {
+        let (Some(scheme), ip) = UpstreamScheme::split(&self.ip)? else {
+            return Ok(());
+        };
+
+        if self.scheme.is_some_and(|existing| existing != scheme) {
+            return Err("has a scheme prefix that conflicts with `scheme`".to_string());
+        }
+
+        self.ip = Cow::Owned(ip.to_string());
+        self.scheme = Some(scheme);
+        Ok(())
+    }
+
+    /// Whether the upstream is reached over TLS (on the given port)
+    pub fn tls(&self, port: u16) -> bool {
+        match self.scheme {
+            Some(UpstreamScheme::Https) => true,
+            Some(UpstreamScheme::Http | UpstreamScheme::H2c) => false,
+            None => port == 443,
+        }
+    }
+
+    /// The HTTP version spoken to the upstream, h2 with the `h2c` scheme
+    pub fn effective_protocol(&self) -> RouteUpstreamProtocol {
+        match self.scheme {
+            Some(UpstreamScheme::H2c) => RouteUpstreamProtocol::H2,
+            _ => self.protocol,
         }
     }
 }
@@ -204,6 +710,9 @@ pub struct RouteSslCertificate {
     /// retrieved from the path or object storage (or generated from letsencrypt)
     /// (defaults to true)
     pub self_signed_on_failure: Option<bool>,
+
+    /// The ACME challenge of the certificate of the host (default: http01)
+    pub challenge: Option<AcmeChallenge>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -235,7 +744,8 @@ pub struct RouteSslPath {
     /// Path to the certificate .key file (e.g. `/etc/proksi/certs/my-host.key`)
     pub key: PathBuf,
 
-    /// Path to the certificate .pem file (e.g. `/etc/proksi/certs/my-host.pem`)
+    /// Path to the certificate .pem file (e.g. `/etc/proksi/certs/my-host.pem`).
+    /// It can be a bundle: the certificate first, then its intermediates in order.
     pub pem: PathBuf,
 }
 
@@ -288,666 +798,4864 @@ pub struct RouteSsl {
     /// The default value is <true>.
     #[serde(default = "bool_true")]
     pub self_signed_fallback: bool,
-}
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
-pub enum RouteCacheType {
-    Disk,
-    MemCache,
+    /// Verification of the client certificates (mTLS). Without it,
+    /// no client certificate is requested.
+    pub client_auth: Option<RouteSslClientAuth>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct RouteCache {
-    pub enabled: Option<bool>,
-
-    #[serde(
-        default = "default_cache_type",
-        deserialize_with = "deserialize_cache_type"
-    )]
-    pub cache_type: RouteCacheType,
+/// Client certificates required by a route (mTLS)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteSslClientAuth {
+    /// Path to the .pem bundle of the CAs issuing the client certificates
+    pub ca: PathBuf,
 
-    #[serde(default = "default_cache_expire_secs")]
-    pub expires_in_secs: u64,
-    #[serde(default = "default_stale_secs")]
-    pub stale_if_error_secs: u32,
-    #[serde(default = "default_stale_secs")]
-    pub stale_while_revalidate_secs: u32,
+    /// Revocation lists the client certificates are checked against
+    pub crl: Option<RouteSslCrl>,
 
-    #[serde(default = "default_cache_path")]
-    pub path: PathBuf,
+    /// Keys accepted in the client certificates. The requests of the clients
+    /// whose certificate is valid but has another key are answered with a `403`.
+    pub key_policy: Option<RouteSslKeyPolicy>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Route {
-    /// The hostname that the proxy will accept
-    /// requests for the upstreams in the route.
-    /// (ex: 'example.com', 'www.example.com', etc.)
-    ///
-    /// This is the host header that the proxy will match and will
-    /// also be used to create the certificate for the domain when `letsencrypt` is enabled.
-    pub host: Cow<'static, str>,
+/// Types and sizes of the keys accepted in the client certificates of a route
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteSslKeyPolicy {
+    /// The accepted key types (default: rsa, ec and ed25519)
+    #[serde(default = "default_key_algorithms")]
+    pub algorithms: Vec<KeyAlgorithm>,
+
+    /// Minimum size (in bits) of the RSA keys (default: 2048)
+    #[serde(default = "default_min_rsa_bits")]
+    pub min_rsa_bits: u32,
+
+    /// Minimum size (in bits) of the curve of the EC keys (default: 256)
+    #[serde(default = "default_min_ec_bits")]
+    pub min_ec_bits: u32,
+}
 
-    pub cache: Option<RouteCache>,
+/// Type of the key of a certificate
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyAlgorithm {
+    Rsa,
+    Ec,
+    Ed25519,
+    Ed448,
+}
 
-    /// Plugins that will be applied to the route/host
-    /// (ex: rate limiting, oauth2, etc.)
-    pub plugins: Option<Vec<RoutePlugin>>,
+/// Certificate revocation lists (CRL) of a route, PEM or DER encoded
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteSslCrl {
+    /// Paths to CRL files (ex: `/etc/proksi/crl/clients.crl`)
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
 
-    /// SSL certificate configurations for the given host
-    /// (ex: self-signed, path/object storage, etc.)
-    pub ssl_certificate: Option<RouteSslCertificate>,
+    /// URLs the CRLs are downloaded from (ex: the distribution point of the CA)
+    #[serde(default)]
+    pub urls: Vec<Cow<'static, str>>,
 
-    /// SSL configuration for the route
-    pub ssl: Option<RouteSsl>,
+    /// How often the CRLs are loaded again (default: 3600)
+    #[serde(default = "default_crl_refresh_secs")]
+    pub refresh_secs: u64,
 
-    /// Header modifications for the given route (remove, add, etc. )
-    pub headers: Option<RouteHeader>,
+    /// Whether clients are accepted when a CRL is unavailable (it could not
+    /// be loaded or it expired). Revoked certificates of the other CRLs are
+    /// rejected either way. Defaults to false: clients are rejected.
+    #[serde(default)]
+    pub fail_open: bool,
+}
 
-    /// The upstreams to which the request will be proxied,
-    pub upstreams: Vec<RouteUpstream>,
+/// How the upstream of each request is selected among the healthy ones
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteSelection {
+    /// Weighted round-robin
+    #[default]
+    RoundRobin,
+    /// Power of two choices: picks two random upstreams and uses the one
+    /// with the fewest active requests (relative to its weight)
+    LeastRequest,
+    /// The upstream with the fewest active requests (relative to its weight)
+    LeastConnections,
+    /// Weighted random
+    Random,
+    /// The client IP hashed on a consistent hashing ring of the upstreams
+    /// (`sticky` without pinning the clients)
+    Consistent,
+}
 
-    /// The matcher for the route
-    /// (ex: path, query, etc.)
-    pub match_with: Option<RouteMatcher>,
+/// What identifies the clients pinned to an upstream
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteStickyBy {
+    /// The IP of the client (see `client_ip` for the forwarded ones)
+    #[default]
+    Ip,
+    /// The `sub` claim of the JWT validated by the `oauth2` plugin, the IP
+    /// of the clients without one
+    #[serde(rename = "jwt:sub")]
+    JwtSub,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
-pub enum LogLevel {
-    Debug,
-    Info,
-    Warn,
-    Error,
-    Trace,
+/// Sends each client to the same upstream, without cookies: its IP (or the
+/// subject of its JWT) is hashed on a consistent hashing ring of the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteSticky {
+    #[serde(default)]
+    pub by: RouteStickyBy,
+
+    /// How long (in seconds) a client stays pinned to its upstream after its
+    /// last request, even when the ring changes (ex: an upstream comes back).
+    /// 0 only relies on the ring (default: 3600)
+    #[serde(default = "default_sticky_ttl_secs")]
+    pub ttl_secs: u64,
+
+    /// The maximum amount of clients pinned at once, the other ones only
+    /// rely on the ring (default: 100000)
+    #[serde(default = "default_sticky_max_entries")]
+    pub max_entries: usize,
+
+    /// Points of each upstream on the ring, per unit of weight (default: 160)
+    #[serde(default = "default_sticky_virtual_nodes")]
+    pub virtual_nodes: usize,
+
+    /// Mixed into the hashes of the ring, which places the clients differently
+    /// (default: none, the same placement as any instance without a seed)
+    pub hash_seed: Option<u64>,
 }
 
-/// Transforms our custom `LogLevel` enum into a `tracing::level_filters::LevelFilter`
-/// enum used by the `tracing` crate.
-impl From<&LogLevel> for tracing::level_filters::LevelFilter {
-    fn from(val: &LogLevel) -> Self {
-        match val {
-            LogLevel::Debug => LevelFilter::DEBUG,
-            LogLevel::Info => LevelFilter::INFO,
-            LogLevel::Warn => LevelFilter::WARN,
-            LogLevel::Error => LevelFilter::ERROR,
-            LogLevel::Trace => LevelFilter::TRACE,
+impl RouteSticky {
+    pub const MAX_VIRTUAL_NODES: usize = 4096;
+
+    /// The ring of `selection: consistent`: by IP, without pinning the clients
+    pub fn consistent() -> Self {
+        RouteSticky {
+            by: RouteStickyBy::Ip,
+            ttl_secs: 0,
+            max_entries: default_sticky_max_entries(),
+            virtual_nodes: default_sticky_virtual_nodes(),
+            hash_seed: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
-pub enum LogRotation {
+/// What identifies the clients that get connections of their own to the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteConnectionReuseBy {
+    /// Every client shares the connections (the default)
     #[default]
-    Never,
-    Daily,
-    Hourly,
-    Minutely,
+    Shared,
+    /// The certificate of the client (`ssl.client_auth`)
+    ClientCertificate,
+    /// The value of the request header `header` (ex: a tenant ID)
+    Header,
+    /// The `sub` claim of the JWT validated by a `jwt` or `oauth2` middleware
+    #[serde(rename = "jwt:sub")]
+    JwtSub,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
-pub enum LogFormat {
-    Json,
-    Pretty,
+/// Which requests share the connections to the upstreams of a route. An
+/// upstream trusting the identity forwarded on a connection gets a pool of
+/// connections per client, none is used for the requests of another one.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RouteConnectionReuse {
+    #[serde(default)]
+    pub by: RouteConnectionReuseBy,
+
+    /// The header identifying the clients (`by: header`). When it is one of
+    /// the `copy_headers` of `forward_auth`, the value of the authentication
+    /// server is used.
+    pub header: Option<Cow<'static, str>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Args)]
-#[group(id = "logging", requires = "level")]
-pub struct Logging {
-    /// If logging is enabled at all. Setting this to `false` will disable all logging output.
-    #[arg(
-        long = "log.enabled",
-        required = false,
-        value_parser,
-        default_value = "true",
-        id = "log.enabled"
-    )]
-    pub enabled: bool,
+/// Timeouts of the requests of a route
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RouteTimeouts {
+    /// How long (in seconds) the upstream may take to send the whole response,
+    /// from the moment it is picked. Long-lived requests (websockets, server-sent
+    /// events) are exempt, `timeouts.idle_secs` bounds them instead.
+    pub response_secs: Option<u64>,
 
-    /// The level of logging to be used.
-    #[serde(deserialize_with = "log_level_deser")]
-    #[arg(
-        long = "log.level",
-        required = false,
-        value_enum,
-        default_value = "info"
-    )]
-    pub level: LogLevel,
+    /// Overrides the global `timeouts.connect_ms`
+    pub connect_ms: Option<u64>,
 
-    /// Whether to log access logs (request, duration, headers etc).
-    #[arg(
-        long = "log.access_logs_enabled",
-        required = false,
-        value_parser,
-        default_value = "true"
-    )]
-    pub access_logs_enabled: bool,
+    /// Overrides the global `timeouts.read_ms`
+    pub read_ms: Option<u64>,
 
-    /// Whether to log error logs (errors, panics, etc) from the Rust runtime.
-    #[arg(
-        long = "log.error_logs_enabled",
-        required = false,
-        value_parser,
-        default_value = "true"
-    )]
-    pub error_logs_enabled: bool,
+    /// Overrides the global `timeouts.write_ms`
+    pub write_ms: Option<u64>,
+}
 
-    /// The format of the log output
-    #[serde(deserialize_with = "log_format_deser")]
-    #[arg(
-        long = "log.format",
-        required = false,
-        value_enum,
-        default_value = "json"
-    )]
-    pub format: LogFormat,
+/// A path of a route that is never proxied
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockedPath {
+    /// This path only (ex: '/metrics')
+    Exact(String),
+    /// The paths starting with it (ex: '/internal/')
+    Prefix(String),
+    /// The paths matching this regular expression (ex: '\.(bak|sql)$')
+    Regex(String),
+}
 
-    /// If set, logs will be written to the specified file
+/// Sets of paths blocked at once
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockedPathsPreset {
+    /// Version control metadata (`.git`, `.svn`, `.hg`), SSH keys (`.ssh`),
+    /// environment files (`.env`, `.env.*`) and `.htaccess`/`.htpasswd`
+    Common,
+}
 
-    #[arg(long = "log.path", required = false, value_parser)]
-    pub path: Option<PathBuf>,
+/// A pattern of the `User-Agent` of the requests
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAgentPattern {
+    /// The agents containing it, ignoring case (ex: 'MJ12bot')
+    Contains(String),
+    /// The agents matching this regular expression (ex: '^curl/')
+    Regex(String),
+}
 
-    #[clap(skip)]
-    #[serde(deserialize_with = "log_rotation_deser", default)]
-    pub rotation: LogRotation,
+/// Sets of agents blocked at once
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAgentPreset {
+    /// Aggressive SEO crawlers (ex: `AhrefsBot`, `MJ12bot`) and vulnerability
+    /// scanners (ex: `sqlmap`, `Nikto`)
+    BadBots,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Args)]
-#[group(id = "auto_reload")]
-pub struct AutoReload {
-    /// Enables the auto-reload service
-    #[arg(long = "auto_reload.enabled", default_value = "false")]
-    pub enabled: Option<bool>,
+/// Requests of a route answered with a 403 because of their `User-Agent`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteUserAgent {
+    /// Agents blocked on top of `block`
+    pub preset: Option<UserAgentPreset>,
 
-    /// The interval (in seconds) to check for changes in the configuration file
-    #[arg(
-        long = "auto_reload.interval_secs",
-        default_value = "30",
-        group = "auto_reload",
-        id = "auto_reload.interval_secs"
-    )]
-    pub interval_secs: Option<u64>,
+    /// The agents blocked, unless allowed by `allow`
+    #[serde(default)]
+    pub block: Vec<UserAgentPattern>,
 
-    #[clap(skip)]
-    pub paths: Vec<PathBuf>,
+    /// The agents never blocked, even when matching `block` or the preset
+    #[serde(default)]
+    pub allow: Vec<UserAgentPattern>,
+
+    /// Whether the requests without a `User-Agent` are blocked (default: false)
+    #[serde(default)]
+    pub block_missing: bool,
 }
 
-impl Default for AutoReload {
-    fn default() -> Self {
-        Self {
-            enabled: Some(false),
-            interval_secs: Some(30),
-            paths: vec![],
-        }
-    }
+/// Requests of each client of a route allowed through a token bucket, the
+/// other ones answered with a `429` and a `Retry-After`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RouteRateLimit {
+    /// Requests allowed per second, on average (the refill rate of the bucket)
+    pub requests_per_second: f64,
+
+    /// Requests allowed at once (the size of the bucket, default:
+    /// `requests_per_second` rounded up)
+    pub burst: Option<u32>,
+
+    /// How the clients are told apart: `ip` or `header:<name>`
+    /// (ex: `header:X-Api-Key`), by IP without the header (default: `ip`)
+    #[serde(default = "default_rate_limit_key")]
+    pub key: Cow<'static, str>,
+
+    /// The response to the limited requests, as the `response` of the
+    /// `rate_limit` plugin: `status`, `content_type`, `body` and `headers`
+    /// (default: an empty `429`)
+    pub response: Option<serde_json::Value>,
 }
 
-/// The main configuration struct.
-/// A configuration file (YAML, TOML or through ENV) will be parsed into this struct.
-/// Example:
-///
-/// ```yaml
-///
-/// # Example configuration file
-/// service_name: "proksi"
-/// logging:
-///   level: "INFO"
-///   access_logs_enabled: true
-///   error_logs_enabled: false
-/// letsencrypt:
-///   enabled: true
-///   email: "youremail@example.com"
-///   production: true
-/// paths:
-///   config_file: "/etc/proksi/config.toml"
-///   lets_encrypt: "/etc/proksi/letsencrypt"
-/// routes:
-///   - host: "example.com"
-///     match_with:
-///       path:
-///         patterns:
-///          - "/api/v1/*"
-///     headers:
-///       add:
-///         - name: "X-Forwarded-For"
-///           value: "<value>"
-///         - name: "X-Api-Version"
-///           value: "1.0"
-///       remove:
-///         - name: "Server"
-///     upstreams:
-///       - ip: "10.1.2.24/24"
-///         port: 3000
-///         network: "public"
-///       - ip: "10.1.2.23/24"
-///         port: 3000
-///         network: "shared"
-/// ```
-///
-#[derive(Debug, Serialize, Deserialize, Parser)]
-#[command(name = "Proksi")]
-#[command(version, about, long_about = None)]
-pub(crate) struct Config {
-    /// The name of the service (will appear as a log property)
-    #[serde(default)]
-    #[clap(short, long, default_value = "proksi")]
-    pub service_name: Cow<'static, str>,
+fn default_rate_limit_key() -> Cow<'static, str> {
+    Cow::Borrowed("ip")
+}
 
-    /// Runs the service in the background (daemon mode)
-    #[clap(short, long, default_value = "false")]
-    pub daemon: bool,
+impl RouteRateLimit {
+    /// The size of the bucket
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn burst(&self) -> u32 {
+        self.burst
+            .unwrap_or_else(|| self.requests_per_second.ceil() as u32)
+            .max(1)
+    }
+}
 
-    /// Upgrades the service from an existing running instance
-    #[clap(short, long, default_value = "false")]
-    pub upgrade: bool,
+/// Warmup of a newly added route (ex: by a reload of the configuration)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct RouteWarmup {
+    /// How long (in seconds) the requests are answered with a 503 at most,
+    /// when none of the upstreams passed a health check before (default: 30)
+    #[serde(default = "default_warmup_period_secs")]
+    pub period_secs: u64,
+}
 
-    /// The number of worker threads to be used by the HTTPS proxy service.
-    ///
-    /// For background services the default is always (1) and cannot be changed.
-    #[clap(short, long, required = false, default_value = "2")]
-    pub worker_threads: Option<usize>,
+fn default_warmup_period_secs() -> u64 {
+    30
+}
 
-    /// The PATH to the configuration file to be used.
-    ///
-    /// The configuration file should be named either `proksi.hcl` or `proksi.yaml`
-    ///
-    /// and be present in that path. Defaults to the current directory.
-    #[clap(short, required = false, long, default_value = "./")]
-    #[allow(clippy::struct_field_names)]
-    pub config_path: Cow<'static, str>,
+/// Upstream of each request of a route, picked from a segment of its path.
+/// Requests whose segment is missing or maps to no upstream get a 404.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteDynamicUpstream {
+    /// Index of the path segment, from 0 (ex: 1 for the tenant of `/t/{tenant}/...`)
+    pub segment: usize,
 
-    /// General config
-    #[command(flatten)]
-    pub logging: Logging,
+    /// The upstream (`host:port`) of each value of the segment
+    #[serde(default)]
+    pub upstreams: HashMap<Cow<'static, str>, Cow<'static, str>>,
 
-    #[command(flatten)]
-    pub auto_reload: AutoReload,
+    /// The upstream of the values missing from `upstreams`, `{segment}` being
+    /// replaced by the value (ex: `{segment}.tenants.svc:3000`). Only the values
+    /// made of ASCII letters, digits and `-` are used, the other ones get a 404
+    /// as do the values whose upstream does not resolve.
+    pub template: Option<Cow<'static, str>>,
 
-    #[command(flatten)]
-    pub docker: Docker,
+    /// Whether the upstreams are reached over TLS (default: false)
+    #[serde(default)]
+    pub tls: bool,
+}
 
-    #[clap(skip)]
-    pub lets_encrypt: LetsEncrypt,
+/// Paths of a route answered by Proksi instead of its upstreams
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteBlockedPaths {
+    /// Paths blocked on top of `paths`
+    pub preset: Option<BlockedPathsPreset>,
 
-    /// Configuration for paths (TLS, config file, etc.)
-    #[clap(skip)]
-    pub paths: Path,
+    /// Status of the blocked requests, 403 or 404 (default: 404)
+    #[serde(default = "default_blocked_paths_status")]
+    pub status: u16,
 
-    /// The routes to be proxied to.
-    #[clap(skip)]
-    pub routes: Vec<Route>,
-    // Listeners -- a list of specific listeners and upstrems
-    // that don't necessarily need to be HTTP/HTTPS related
-    // pub listeners: Vec<ConfigListener>,
+    #[serde(default)]
+    pub paths: Vec<BlockedPath>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            config_path: Cow::Borrowed("/etc/proksi/config"),
-            service_name: Cow::Borrowed("proksi"),
-            worker_threads: Some(2),
-            upgrade: false,
-            daemon: false,
-            docker: Docker::default(),
-            lets_encrypt: LetsEncrypt::default(),
-            routes: vec![],
-            auto_reload: AutoReload::default(),
-            logging: Logging {
-                enabled: true,
-                level: LogLevel::Info,
-                access_logs_enabled: true,
-                error_logs_enabled: false,
-                format: LogFormat::Json,
-                path: None,
-                rotation: LogRotation::Never,
-            },
-            paths: Path::default(),
-        }
-    }
+fn default_blocked_paths_status() -> u16 {
+    404
 }
 
-// impl Config {
-//     // Allow the configuration to be extracted from any `Provider`.
-//     fn from<T: figment::Provider>(provider: T) -> Result<Config, figment::Error> {
-//         Figment::from(provider).extract()
-//     }
-
-//     // Provide a default provider, a `Figment`.
-//     fn figment() -> Figment {
-//         use figment::providers::Env;
+/// Copies of the requests of a route sent to a shadow upstream, whose
+/// responses are discarded
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RouteMirror {
+    /// Base URL of the shadow upstream (ex: `http://10.0.1.1:3000`), the path
+    /// and query of each request are appended to it
+    pub upstream: String,
+
+    /// Percentage of the requests mirrored (default: 100)
+    #[serde(default = "default_mirror_percentage")]
+    pub percentage: f64,
+
+    /// Requests with a larger body (in bytes) are not mirrored (default: 65536)
+    #[serde(default = "default_mirror_max_body_size")]
+    pub max_body_size: usize,
+
+    /// How long (in milliseconds) the shadow upstream may take to answer (default: 5000)
+    #[serde(default = "default_mirror_timeout_ms")]
+    pub timeout_ms: u64,
+}
 
-//         // In reality, whatever the library desires.
-//         Figment::from(Config::default()).merge(Env::prefixed("APP_"))
-//     }
-// }
+fn default_mirror_percentage() -> f64 {
+    100.0
+}
 
-/// Implement the `Provider` trait for the `Config` struct.
-/// This allows the `Config` struct to be used as a configuration provider with *defaults*.
-impl Provider for Config {
-    fn metadata(&self) -> figment::Metadata {
-        figment::Metadata::named("proksi")
-    }
+fn default_mirror_max_body_size() -> usize {
+    64 * 1024
+}
 
-    fn data(
-        &self,
-    ) -> Result<figment::value::Map<figment::Profile, figment::value::Dict>, figment::Error> {
-        Serialized::defaults(Config::default()).data()
-    }
+fn default_mirror_timeout_ms() -> u64 {
+    5000
 }
 
-/// Load the configuration from the configuration file(s) as a `Config` struct.
-/// In theory one could create all 3 configurations formats and they will overlap each other
-///
-/// Nested keys can be separated by double underscores (__) in the environment variables.
-/// E.g. `PROKSI__LOGGING__LEVEL=DEBUG` will set the `level` key in the
-/// `logging` key in the `proksi` key.
-pub fn load(fallback: &str) -> Result<Config, figment::Error> {
-    let parsed_commands = Config::parse();
+/// External server authenticating the requests of a route, before any plugin
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RouteForwardAuth {
+    /// URL the server is sent a `GET` at, with the headers of each request
+    /// (ex: `http://auth:9000/verify`)
+    pub address: String,
 
-    let path_with_fallback = if parsed_commands.config_path.is_empty() {
-        fallback
-    } else {
-        &parsed_commands.config_path
-    };
+    /// Headers of the responses of the server copied onto the allowed
+    /// requests (ex: `X-User`), replacing the ones sent by the clients
+    #[serde(default)]
+    pub copy_headers: Vec<Cow<'static, str>>,
 
-    let config: Config = Figment::new()
-        .merge(Config::default())
-        .merge(Serialized::defaults(&parsed_commands))
-        .merge(Yaml::file(format!("{path_with_fallback}/proksi.yml")))
-        .merge(Yaml::file(format!("{path_with_fallback}/proksi.yaml")))
-        .merge(Hcl::file(format!("{path_with_fallback}/proksi.hcl")))
-        .merge(Env::prefixed("PROKSI_").split("__"))
-        .extract()?;
+    /// How long (in milliseconds) the server may take to answer (default: 2000)
+    #[serde(default = "default_forward_auth_timeout_ms")]
+    pub timeout_ms: u64,
 
-    // validate configuration and throw error upwards
-    validate::check_config(&config).map_err(|err| figment::Error::from(err.to_string()))?;
+    /// How long (in seconds) the allowed requests are trusted for the same
+    /// credentials, 0 to ask the server every time (default: 5)
+    #[serde(default = "default_forward_auth_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+}
 
-    Ok(config)
+fn default_forward_auth_timeout_ms() -> u64 {
+    2000
 }
 
-/// Deserialize function to convert a string to a `LogLevel` Enum
-fn log_level_deser<'de, D>(deserializer: D) -> Result<LogLevel, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "debug" => Ok(LogLevel::Debug),
-        "info" => Ok(LogLevel::Info),
-        "warn" => Ok(LogLevel::Warn),
-        "error" => Ok(LogLevel::Error),
-        "trace" => Ok(LogLevel::Trace),
-        _ => Err(serde::de::Error::custom(
-            "expected one of DEBUG, INFO, WARN, ERROR, TRACE",
-        )),
-    }
+fn default_forward_auth_cache_ttl_secs() -> u64 {
+    5
 }
 
-/// Deserialize function to convert a string to a `DockerServiceMode` Enum
-fn docker_mode_deser<'de, D>(deserializer: D) -> Result<DockerServiceMode, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "swarm" => Ok(DockerServiceMode::Swarm),
-        "container" => Ok(DockerServiceMode::Container),
-        _ => Err(serde::de::Error::custom(
-            "expected one of: Swarm, Container",
-        )),
-    }
+/// What happens to the requests of a route above `max_concurrent_requests`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteOverflow {
+    /// Answered right away with a 503
+    #[default]
+    Reject,
+    /// Wait (up to `queue_timeout_ms`) for another request to end
+    Queue,
 }
 
-/// Deserialize function to convert a string to a `LogLevel` Enum
-fn log_format_deser<'de, D>(deserializer: D) -> Result<LogFormat, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "json" => Ok(LogFormat::Json),
-        "pretty" => Ok(LogFormat::Pretty),
-        _ => Err(serde::de::Error::custom("expected one of: json, pretty")),
-    }
+/// Bounds the requests of a route proxied at once, so a slow upstream
+/// is not buried under new requests
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteConcurrency {
+    /// The maximum amount of requests of the route proxied at once
+    pub max_concurrent_requests: usize,
+
+    /// What happens to the requests above the bound (default: `reject`)
+    #[serde(default)]
+    pub overflow: RouteOverflow,
+
+    /// How long (in milliseconds) a queued request waits before it gets
+    /// a 503 (default: 1000)
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+
+    /// The maximum amount of queued requests, the other ones are rejected
+    /// (defaults to `max_concurrent_requests`)
+    pub max_queued: Option<usize>,
 }
 
-fn log_rotation_deser<'de, D>(deserializer: D) -> Result<LogRotation, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "daily" => Ok(LogRotation::Daily),
-        "hourly" => Ok(LogRotation::Hourly),
-        "minutely" => Ok(LogRotation::Minutely),
-        "never" => Ok(LogRotation::Never),
-        _ => Err(serde::de::Error::custom(
-            "expected one of: daily, hourly, minutely, never",
-        )),
-    }
+/// Sends the requests to an upstream pool based on the value of a request
+/// header, such as the country code set by a CDN (ex: `CF-IPCountry`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteGeoRouting {
+    /// The request header holding the location of the client
+    pub header: Cow<'static, str>,
+
+    /// The header values (case-insensitive) served by each pool
+    /// (ex: `eu: ["DE", "FR"]`). Requests without the header, with a value
+    /// no pool serves or whose pool has no healthy upstream go to the default pool.
+    pub pools: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
 }
 
-/// Deserialize function to convert a string to a `LogLevel` Enum
-fn proto_version_deser<'de, D>(deserializer: D) -> Result<ProtoVersion, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "v1.1" => Ok(ProtoVersion::V1_1),
-        "v1.2" => Ok(ProtoVersion::V1_2),
-        "v1.3" => Ok(ProtoVersion::V1_3),
-        _ => Err(serde::de::Error::custom(
-            "expected one of: v1.1, v1.2, v1.3",
-        )),
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum RouteCacheType {
+    Disk,
+    MemCache,
 }
 
-fn deserialize_cache_type<'de, D>(deserializer: D) -> Result<RouteCacheType, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "disk" => Ok(RouteCacheType::Disk),
-        "memcache" => Ok(RouteCacheType::MemCache),
-        _ => Err(serde::de::Error::custom("expected one of: disk, memcache")),
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteCache {
+    pub enabled: Option<bool>,
+
+    #[serde(
+        default = "default_cache_type",
+        deserialize_with = "deserialize_cache_type"
+    )]
+    pub cache_type: RouteCacheType,
+
+    #[serde(default = "default_cache_expire_secs")]
+    pub expires_in_secs: u64,
+    #[serde(default = "default_stale_secs")]
+    pub stale_if_error_secs: u32,
+    #[serde(default = "default_stale_secs")]
+    pub stale_while_revalidate_secs: u32,
+
+    /// Whether any failure to get a response (no healthy upstream included) is
+    /// answered with the expired response, instead of only the failures of the
+    /// upstream itself (default: false)
+    #[serde(default)]
+    pub serve_stale_on_error: bool,
+    /// With `serve_stale_on_error`, how long (in seconds) past its expiry a
+    /// response can be served (default: `stale_if_error_secs`)
+    pub max_stale_secs: Option<u32>,
+
+    #[serde(default = "default_cache_path")]
+    pub path: PathBuf,
+
+    /// Whether concurrent misses of the same key wait for the first one to be
+    /// cached, instead of all of them reaching the upstream (default: true)
+    #[serde(default = "bool_true")]
+    pub coalesce: bool,
+    /// How long the coalesced requests wait before reaching the upstream
+    /// themselves, without caching their response
+    #[serde(default = "default_coalesce_timeout_secs")]
+    pub coalesce_timeout_secs: u64,
+}
+
+/// Compression settings of a route. Each setting of the route takes precedence
+/// over the global one (`compression`), the other ones are inherited from it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteCompression {
+    /// Whether the responses of the route are compressed. Defaults to true when
+    /// the route has a `compression` block, regardless of `compression.enabled`,
+    /// so `enabled: false` is needed to opt a route out of the global compression.
+    pub enabled: Option<bool>,
+
+    /// The compression level, from 1 (fastest) to 11 (smallest output).
+    /// Gzip levels are capped at 9 (defaults to `compression.level`)
+    pub level: Option<u32>,
+
+    /// The encodings offered, in order of preference
+    /// (defaults to `compression.algorithms`)
+    pub algorithms: Option<Vec<CompressionAlgorithm>>,
+
+    /// The zstd level, from 1 to 22 (defaults to `compression.zstd_level`)
+    pub zstd_level: Option<u32>,
+
+    /// The content types that are compressed (ex: 'text/*', 'application/json').
+    /// Binary or already compressed types (images, archives, etc.) are never compressed.
+    /// (defaults to `compression.content_types`)
+    pub content_types: Option<Vec<Cow<'static, str>>>,
+
+    /// The `Content-Length` (in bytes) below which the responses are sent
+    /// uncompressed (defaults to `compression.min_length`)
+    pub min_length: Option<usize>,
+}
+
+/// Rewriting of the responses of a route
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteResponse {
+    /// Substitutions applied, in order, to the bodies of the text responses
+    /// (HTML, CSS, JavaScript, JSON, XML, etc.)
+    #[serde(default)]
+    pub substitute: Vec<RouteSubstitution>,
+
+    /// Size above which a body is sent unchanged (default: 1 MiB).
+    /// Bodies are buffered in memory to be rewritten.
+    #[serde(default = "default_substitute_max_bytes")]
+    pub substitute_max_bytes: usize,
+}
+
+/// A substitution of the body of the responses
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteSubstitution {
+    /// The text replaced (ex: 'http://app.internal:8080')
+    pub from: Cow<'static, str>,
+
+    /// The replacement (ex: 'https://example.com'). With `regex`, it can
+    /// refer to the groups of `from` (ex: '$1', '${name}')
+    pub to: Cow<'static, str>,
+
+    /// Whether `from` is a regular expression (default: false)
+    #[serde(default)]
+    pub regex: bool,
+}
+
+/// A status of the upstream responses sent to the clients as another one
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteStatusMapping {
+    /// The status sent by the upstream (ex: 418)
+    pub from: u16,
+
+    /// The status sent to the client instead (ex: 503)
+    pub to: u16,
+
+    /// The `Location` of the response, for the redirects
+    /// (ex: 'https://example.com/login')
+    pub location: Option<Cow<'static, str>>,
+}
+
+/// The upstream responses proksi answers itself instead of passing them through
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteErrorHandling {
+    /// The statuses of the upstream responses intercepted (ex: 502, 503).
+    /// The other responses pass through, as all of them do by default.
+    #[serde(default)]
+    pub intercept_statuses: Vec<u16>,
+
+    /// What is done with an intercepted response (default: `error_page`)
+    #[serde(default)]
+    pub action: InterceptAction,
+
+    /// How many times a request is sent to an upstream again with `retry`,
+    /// before its last intercepted response gets the error page (default: 1)
+    #[serde(default = "default_intercept_retries")]
+    pub retries: usize,
+
+    /// HTML file served as the body of the error page (default: the error
+    /// response of proksi)
+    pub page: Option<PathBuf>,
+
+    /// How the `Retry-After` of the intercepted `503` responses is honored
+    /// with `retry` (default: `failover`)
+    #[serde(default)]
+    pub retry_after: RetryAfterAction,
+
+    /// Longest `Retry-After` honored (in seconds), longer ones are cut to it
+    /// (default: 10)
+    #[serde(default = "default_max_retry_after_secs")]
+    pub max_retry_after_secs: u64,
+}
+
+/// What is done with an intercepted `503` response with a `Retry-After`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryAfterAction {
+    /// Sent again right away, to another upstream: the upstream is not
+    /// picked again until the end of the delay
+    #[default]
+    Failover,
+    /// Sent again once the delay is over
+    Delay,
+    /// Not intercepted, the client gets the response of the upstream
+    PassThrough,
+}
+
+fn default_max_retry_after_secs() -> u64 {
+    10
+}
+
+/// What is done with the intercepted upstream responses
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InterceptAction {
+    /// The client gets the error page, with the status of the upstream
+    #[default]
+    ErrorPage,
+    /// The request is sent to an upstream again (the next one of the load
+    /// balancer), then gets the error page
+    Retry,
+}
+
+fn default_intercept_retries() -> usize {
+    1
+}
+
+/// The failed requests sent again to another upstream of the route
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteRetries {
+    /// How many times a request is sent again after its first attempt
+    /// failed (default: 2)
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: usize,
+
+    /// What fails an attempt: `connect_error` (the upstream could not be
+    /// connected to, or closed the connection before its response) and the
+    /// statuses of the upstream responses (default: `[connect_error]`)
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+
+    /// Whether the requests of the non idempotent methods (ex: `POST`,
+    /// `PATCH`) are retried too, which may write twice (default: false)
+    #[serde(default)]
+    pub non_idempotent: bool,
+}
+
+/// A failure of an attempt retried (`retries.retry_on`)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RetryOn {
+    /// A status of the upstream response (ex: 502)
+    Status(u16),
+    Condition(RetryCondition),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryCondition {
+    /// The upstream could not be connected to, or closed the connection
+    /// before its response
+    ConnectError,
+}
+
+fn default_retry_attempts() -> usize {
+    2
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::Condition(RetryCondition::ConnectError)]
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum RouteHealthCheckType {
+    Tcp,
+    Http,
+    Grpc,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RouteHealthCheck {
+    /// The kind of probe sent to each upstream (ex: 'tcp', 'http', 'grpc')
+    /// (defaults to 'tcp')
+    #[serde(
+        alias = "type",
+        default = "default_health_check_type",
+        deserialize_with = "health_check_type_deser"
+    )]
+    pub check_type: RouteHealthCheckType,
+
+    /// How often (in seconds) the upstreams are checked (defaults to 15)
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+
+    /// Failed checks in a row marking a healthy upstream unhealthy (defaults to 1)
+    #[serde(default = "default_health_check_consecutive")]
+    pub consecutive_failures: usize,
+
+    /// Successful checks in a row marking an unhealthy upstream healthy
+    /// again (defaults to 1)
+    #[serde(default = "default_health_check_consecutive")]
+    pub consecutive_successes: usize,
+
+    /// The path (and query) of the `GET` sent by the 'http' checks (defaults to '/')
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+
+    /// The status the 'http' checks expect (defaults to 200)
+    #[serde(default = "default_health_check_expected_status")]
+    pub expected_status: u16,
+
+    /// Whether the 'http' checks are sent over TLS (defaults to false)
+    #[serde(default)]
+    pub tls: bool,
+
+    /// Maximum time (in seconds) a single probe may take before the
+    /// upstream is considered unhealthy (defaults to 1)
+    #[serde(default = "default_health_check_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// Optional: the service name sent in the gRPC `HealthCheckRequest`.
+    /// When empty, the overall health of the upstream server is checked.
+    pub grpc_service: Option<String>,
+}
+
+impl Default for RouteHealthCheck {
+    fn default() -> Self {
+        RouteHealthCheck {
+            check_type: default_health_check_type(),
+            interval_secs: default_health_check_interval_secs(),
+            consecutive_failures: default_health_check_consecutive(),
+            consecutive_successes: default_health_check_consecutive(),
+            path: default_health_check_path(),
+            expected_status: default_health_check_expected_status(),
+            tls: false,
+            timeout_secs: default_health_check_timeout_secs(),
+            grpc_service: None,
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use serde_json::json;
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Route {
+    /// The hostname that the proxy will accept
+    /// requests for the upstreams in the route.
+    /// (ex: 'example.com', 'www.example.com', etc.)
+    ///
+    /// This is the host header that the proxy will match and will
+    /// also be used to create the certificate for the domain when `letsencrypt` is enabled.
+    pub host: Cow<'static, str>,
 
-    use super::*;
+    pub cache: Option<RouteCache>,
 
-    fn helper_config_file() -> &'static str {
-        r#"
-        service_name: "proksi"
-        lets_encrypt:
-          email: "user@domain.net"
-        logging:
-          level: "INFO"
-          access_logs_enabled: true
-          error_logs_enabled: false
-        paths:
-          lets_encrypt: "/test/letsencrypt"
-        routes:
-          - host: "example.com"
-            plugins:
-              - name: "cors"
-                config:
-                  allowed_origins: ["*"]
-            headers:
-              add:
-                - name: "X-Forwarded-For"
-                  value: "<value>"
-                - name: "X-Api-Version"
-                  value: "1.0"
-              remove:
-                - name: "Server"
-            upstreams:
-              - ip: "10.0.1.3/25"
-                port: 3000
-                network: "public"
-      "#
+    /// Compression of the responses sent to the clients (gzip, brotli or zstd)
+    pub compression: Option<RouteCompression>,
+
+    /// Rewriting of the bodies of the responses (text content types only)
+    pub response: Option<RouteResponse>,
+
+    /// Statuses of the upstream responses sent to the clients as other ones
+    /// (the other statuses are sent unchanged)
+    #[serde(default)]
+    pub status_map: Vec<RouteStatusMapping>,
+
+    /// Plugins that will be applied to the route/host
+    /// (ex: rate limiting, oauth2, etc.)
+    pub plugins: Option<Vec<RoutePlugin>>,
+
+    /// Authentication middlewares (`basic_auth`, `api_key`, `jwt`, `oauth2`),
+    /// tried in order: the first one authenticating a request lets it through
+    #[serde(default)]
+    pub auth: Vec<RoutePlugin>,
+
+    /// External server authenticating the requests, after `auth`
+    pub forward_auth: Option<RouteForwardAuth>,
+
+    /// Names of the middleware profiles applied to the route, in order.
+    /// The headers and plugins of the route itself take precedence.
+    pub middleware_profiles: Option<Vec<Cow<'static, str>>>,
+
+    /// SSL certificate configurations for the given host
+    /// (ex: self-signed, path/object storage, etc.)
+    pub ssl_certificate: Option<RouteSslCertificate>,
+
+    /// SSL configuration for the route
+    pub ssl: Option<RouteSsl>,
+
+    /// Header modifications for the given route (remove, add, etc. )
+    pub headers: Option<RouteHeader>,
+
+    /// The upstreams to which the request will be proxied,
+    pub upstreams: Vec<RouteUpstream>,
+
+    /// The upstream the requests are sent to when none of `upstreams` is
+    /// healthy (ex: a maintenance page shared by several routes)
+    pub fallback_upstream: Option<RouteUpstream>,
+
+    /// The matcher for the route
+    /// (ex: path, query, etc.)
+    pub match_with: Option<RouteMatcher>,
+
+    /// Health check performed against the upstreams of the route
+    /// (defaults to a TCP connect check)
+    pub health_check: Option<RouteHealthCheck>,
+
+    /// Sampling of the access logs of the route
+    pub tracing: Option<RouteTracing>,
+
+    /// Access logs of the route (enabled, sample rate)
+    pub access_log: Option<RouteAccessLog>,
+
+    /// How the upstream of each request is selected (default: `round_robin`)
+    #[serde(alias = "algorithm")]
+    pub selection: Option<RouteSelection>,
+
+    /// Sends each client to the same upstream, taking precedence over `selection`
+    pub sticky: Option<RouteSticky>,
+
+    /// Connections to the upstreams per client (ex: per client certificate)
+    /// instead of shared by all of them
+    pub connection_reuse: Option<RouteConnectionReuse>,
+
+    /// Upstream pool of each request, picked from a request header
+    pub geo_routing: Option<RouteGeoRouting>,
+
+    /// Bounds the requests of the route proxied at once
+    pub concurrency: Option<RouteConcurrency>,
+
+    /// Timeouts of the requests of the route
+    pub timeouts: Option<RouteTimeouts>,
+
+    /// Copies of the requests sent to a shadow upstream
+    pub mirror: Option<RouteMirror>,
+
+    /// Paths answered with a 403 or a 404 instead of being proxied
+    pub blocked_paths: Option<RouteBlockedPaths>,
+
+    /// Requests answered with a 403 because of their `User-Agent`
+    pub user_agent: Option<RouteUserAgent>,
+
+    /// Requests of each client allowed per second, before the plugins run
+    pub rate_limit: Option<RouteRateLimit>,
+
+    /// Answers the requests with a 503 once the route is added, until one of
+    /// its upstreams passes a health check
+    pub warmup: Option<RouteWarmup>,
+
+    /// Picks the upstream of each request from a segment of its path (ex: the
+    /// tenant of `/t/{tenant}/...`) instead of `upstreams`
+    pub dynamic_upstream: Option<RouteDynamicUpstream>,
+
+    /// Upstream responses answered by proksi (error page or retry) instead
+    /// of being passed through
+    pub error_handling: Option<RouteErrorHandling>,
+
+    /// Sends the requests whose upstream failed (connection or status) again
+    /// to another upstream, only for the idempotent methods by default
+    pub retries: Option<RouteRetries>,
+
+    /// Decides which route serves the host when several routes declare it
+    /// (default: 0). Only the route with the highest priority is used, routes
+    /// sharing a host without a single highest priority are rejected.
+    pub priority: Option<i32>,
+}
+
+impl Route {
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or_default()
+    }
+
+    /// Whether another route of the same host has a higher priority,
+    /// in which case this route is ignored
+    pub fn is_shadowed_by(&self, other: &Route) -> bool {
+        route_key(&self.host) == route_key(&other.host) && other.priority() > self.priority()
+    }
+
+    /// Whether the requests of the route are logged (errors always are)
+    pub fn access_log_enabled(&self) -> bool {
+        self.access_log
+            .as_ref()
+            .and_then(|log| log.enabled)
+            .unwrap_or(true)
+    }
+
+    /// Share of the requests of the route that are logged, `access_log`
+    /// taking precedence over `tracing` (default: `tracing.sample_rate`)
+    pub fn access_log_sample_rate(&self) -> Option<f64> {
+        self.access_log
+            .as_ref()
+            .and_then(|log| log.sample_rate)
+            .or_else(|| self.tracing.as_ref().and_then(|t| t.sample_rate))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Trace,
+}
+
+/// Transforms our custom `LogLevel` enum into a `tracing::level_filters::LevelFilter`
+/// enum used by the `tracing` crate.
+impl From<&LogLevel> for tracing::level_filters::LevelFilter {
+    fn from(val: &LogLevel) -> Self {
+        match val {
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Daily,
+    Hourly,
+    Minutely,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
+pub enum LogFormat {
+    Json,
+    /// Human-readable lines (`text` is accepted as well)
+    #[value(alias = "text")]
+    Pretty,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Args)]
+#[group(id = "logging", requires = "level")]
+pub struct Logging {
+    /// If logging is enabled at all. Setting this to `false` will disable all logging output.
+    #[arg(
+        long = "log.enabled",
+        required = false,
+        value_parser,
+        default_value = "true",
+        id = "log.enabled"
+    )]
+    pub enabled: bool,
+
+    /// The level of logging to be used.
+    #[serde(deserialize_with = "log_level_deser")]
+    #[arg(
+        long = "log.level",
+        required = false,
+        value_enum,
+        default_value = "info"
+    )]
+    pub level: LogLevel,
+
+    /// Whether to log access logs (request, duration, headers etc).
+    #[arg(
+        long = "log.access_logs_enabled",
+        required = false,
+        value_parser,
+        default_value = "true"
+    )]
+    pub access_logs_enabled: bool,
+
+    /// Whether to log error logs (errors, panics, etc) from the Rust runtime.
+    #[arg(
+        long = "log.error_logs_enabled",
+        required = false,
+        value_parser,
+        default_value = "true"
+    )]
+    pub error_logs_enabled: bool,
+
+    /// The format of the log output
+    #[serde(deserialize_with = "log_format_deser")]
+    #[arg(
+        long = "log.format",
+        required = false,
+        value_enum,
+        default_value = "json"
+    )]
+    pub format: LogFormat,
+
+    /// If set, logs will be written to the specified file
+
+    #[arg(long = "log.path", required = false, value_parser)]
+    pub path: Option<PathBuf>,
+
+    #[clap(skip)]
+    #[serde(deserialize_with = "log_rotation_deser", default)]
+    pub rotation: LogRotation,
+
+    /// Requests slower than this (in milliseconds) are logged as a warning,
+    /// whether their access log is sampled or not (default: disabled)
+    #[clap(skip)]
+    #[serde(default)]
+    pub slow_request_threshold: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Args)]
+#[group(id = "auto_reload")]
+pub struct AutoReload {
+    /// Enables the auto-reload service
+    #[arg(long = "auto_reload.enabled", default_value = "false")]
+    pub enabled: Option<bool>,
+
+    /// The interval (in seconds) to check for changes in the configuration file
+    #[arg(
+        long = "auto_reload.interval_secs",
+        default_value = "30",
+        group = "auto_reload",
+        id = "auto_reload.interval_secs"
+    )]
+    pub interval_secs: Option<u64>,
+
+    #[clap(skip)]
+    pub paths: Vec<PathBuf>,
+}
+
+impl Default for AutoReload {
+    fn default() -> Self {
+        Self {
+            enabled: Some(false),
+            interval_secs: Some(30),
+            paths: vec![],
+        }
+    }
+}
+
+/// Limits applied to the in-memory stores that grow with client traffic
+/// (ex: rate limit buckets)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Stores {
+    /// Entries not used for longer than this (in seconds) are evicted.
+    /// Should be larger than the longest rate limit interval (default: 3600)
+    pub ttl_secs: u64,
+
+    /// The maximum amount of entries kept in each store. When full, the least
+    /// recently used entries are evicted first (default: 100000)
+    pub max_entries: usize,
+
+    /// How often (in seconds) stale entries are evicted (default: 60)
+    pub eviction_interval_secs: u64,
+}
+
+impl Default for Stores {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 3600,
+            max_entries: 100_000,
+            eviction_interval_secs: 60,
+        }
+    }
+}
+
+/// Shutdown (or upgrade) of the instance
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Shutdown {
+    /// Seconds the background services (docker, letsencrypt, etc.) are given to
+    /// stop once the shutdown started, before they are force-stopped (default: 10)
+    pub background_services_timeout_secs: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            background_services_timeout_secs: 10,
+        }
+    }
+}
+
+/// How the IP of the client is resolved when proksi sits behind other proxies
+/// (ex: a CDN). Only one of `trust_hops` and `trusted_proxies` can be set.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ClientIp {
+    /// Networks (CIDR) of the proxies allowed to set `X-Forwarded-For` (default: none)
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cow<'static, str>>,
+
+    /// Number of proxies in front of proksi. The client IP is the Nth-from-last
+    /// entry of `X-Forwarded-For` (default: none)
+    pub trust_hops: Option<usize>,
+}
+
+/// TCP keepalive probes sent on accepted connections
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct TcpKeepalive {
+    /// Seconds a connection is idle before the first probe is sent
+    pub idle_secs: u64,
+    /// Seconds between two probes
+    pub interval_secs: u64,
+    /// Unanswered probes before the connection is dropped
+    pub count: usize,
+}
+
+/// Socket options of the TCP listeners (HTTP, HTTPS and TCP streams).
+/// Options a platform does not support are no-ops there (ex: TCP Fast Open outside Linux).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TcpListenerOptions {
+    /// Enables TCP Fast Open with the given queue length (Linux only)
+    pub fastopen_backlog: Option<usize>,
+
+    /// Only accepts IPv6 connections on listeners bound to an IPv6 address (ex: `[::]:443`)
+    pub ipv6_only: Option<bool>,
+
+    /// TCP keepalive of the accepted connections (default: the OS settings)
+    pub keepalive: Option<TcpKeepalive>,
+
+    /// DSCP value (0-63) set on the accepted connections
+    pub dscp: Option<u8>,
+
+    /// The accept backlog, fixed at 65535 by the listeners. Set
+    /// `net.core.somaxconn` (Linux) or `kern.ipc.somaxconn` (BSD/macOS) to raise the effective limit
+    pub backlog: Option<u32>,
+
+    /// `SO_REUSEPORT` is not supported by the listeners yet (only `false` is accepted)
+    pub reuse_port: Option<bool>,
+
+    /// `TCP_NODELAY` is always enabled on accepted connections (only `true` is accepted)
+    pub nodelay: Option<bool>,
+}
+
+impl TcpListenerOptions {
+    /// The accept backlog of the listeners
+    pub const BACKLOG: u32 = 65_535;
+
+    /// Returns the pingora socket options for the tunable settings
+    pub fn socket_options(&self) -> TcpSocketOptions {
+        let mut options = TcpSocketOptions::default();
+        options.tcp_fastopen = self.fastopen_backlog;
+        options.ipv6_only = self.ipv6_only;
+        options.dscp = self.dscp;
+        options.tcp_keepalive = self.keepalive.map(|ka| pingora::protocols::TcpKeepalive {
+            idle: Duration::from_secs(ka.idle_secs),
+            interval: Duration::from_secs(ka.interval_secs),
+            count: ka.count,
+        });
+        options
+    }
+}
+
+/// Addresses the HTTP (ACME challenges) and HTTPS services listen on
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Listeners {
+    /// Address of the HTTP service (default: `0.0.0.0:80`)
+    pub http_address: Cow<'static, str>,
+
+    /// Address of the HTTPS service (default: `0.0.0.0:443`)
+    pub https_address: Cow<'static, str>,
+
+    /// Address of a listener answering only the ACME HTTP challenges (ex:
+    /// `127.0.0.1:8080`, behind the component managing port 80). When set,
+    /// `http_address` redirects the challenges to HTTPS like any other
+    /// request (default: none, `http_address` answers them)
+    #[serde(default)]
+    pub acme_challenge_address: Option<Cow<'static, str>>,
+
+    /// Whether the requests to `http_address` (other than the ACME challenges)
+    /// are redirected to HTTPS, instead of answered with a 404 (default: true)
+    #[serde(default = "default_listeners_http_redirect")]
+    pub http_redirect: bool,
+
+    /// Socket options of all the TCP listeners
+    #[serde(default)]
+    pub tcp: TcpListenerOptions,
+
+    /// PROXY protocol (v1 and v2) on the HTTP and HTTPS listeners
+    #[serde(default)]
+    pub proxy_protocol: ListenersProxyProtocol,
+}
+
+impl Default for Listeners {
+    fn default() -> Self {
+        Self {
+            http_address: Cow::Borrowed("0.0.0.0:80"),
+            https_address: Cow::Borrowed("0.0.0.0:443"),
+            acme_challenge_address: None,
+            http_redirect: default_listeners_http_redirect(),
+            tcp: TcpListenerOptions::default(),
+            proxy_protocol: ListenersProxyProtocol::default(),
+        }
+    }
+}
+
+fn default_listeners_http_redirect() -> bool {
+    true
+}
+
+/// The listeners whose connections start with a PROXY protocol header (sent by
+/// an L4 load balancer), the client address being read from it. The
+/// connections without a valid header are closed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ListenersProxyProtocol {
+    /// Whether the connections to `http_address` start with a header (default: false)
+    #[serde(default)]
+    pub http: bool,
+
+    /// Whether the connections to `https_address` start with a header (default: false)
+    #[serde(default)]
+    pub https: bool,
+
+    /// Loopback address the HTTP service listens on behind its PROXY listener
+    /// (default: `127.0.0.1:10080`)
+    #[serde(default = "default_proxy_protocol_internal_http_address")]
+    pub internal_http_address: Cow<'static, str>,
+
+    /// Loopback address the HTTPS service listens on behind its PROXY listener
+    /// (default: `127.0.0.1:10443`)
+    #[serde(default = "default_proxy_protocol_internal_https_address")]
+    pub internal_https_address: Cow<'static, str>,
+
+    /// How long (in seconds) a connection may take to send its header (default: 5)
+    #[serde(default = "default_proxy_protocol_header_timeout_secs")]
+    pub header_timeout_secs: u64,
+}
+
+fn default_proxy_protocol_internal_http_address() -> Cow<'static, str> {
+    Cow::Borrowed("127.0.0.1:10080")
+}
+
+fn default_proxy_protocol_internal_https_address() -> Cow<'static, str> {
+    Cow::Borrowed("127.0.0.1:10443")
+}
+
+fn default_proxy_protocol_header_timeout_secs() -> u64 {
+    5
+}
+
+impl Default for ListenersProxyProtocol {
+    fn default() -> Self {
+        Self {
+            http: false,
+            https: false,
+            internal_http_address: default_proxy_protocol_internal_http_address(),
+            internal_https_address: default_proxy_protocol_internal_https_address(),
+            header_timeout_secs: default_proxy_protocol_header_timeout_secs(),
+        }
+    }
+}
+
+/// Header settings applied to every route
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Headers {
+    /// Response headers removed from every upstream response (ex: `X-Powered-By`).
+    /// Hop-by-hop headers are always removed (default: none)
+    #[serde(default)]
+    pub strip_response: Vec<Cow<'static, str>>,
+}
+
+/// Admin API, serving JSON on its own address (ex: `POST /routes/test`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Admin {
+    /// Whether the admin API is served (default: false)
+    pub enabled: bool,
+
+    /// Address the admin API listens on (default: `127.0.0.1:9091`).
+    /// Addresses other than loopback require a `token`.
+    pub bind: Cow<'static, str>,
+
+    /// Bearer token required in the `Authorization` header of every request.
+    /// Certificates can only be revoked when one is set.
+    pub token: Option<Cow<'static, str>>,
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: Cow::Borrowed("127.0.0.1:9091"),
+            token: None,
+        }
+    }
+}
+
+/// Prometheus metrics, served as text on `/metrics` of their own address
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Metrics {
+    /// Whether the metrics are served (default: false)
+    pub enabled: bool,
+
+    /// Address the metrics listen on (default: `127.0.0.1:9090`)
+    pub bind: Cow<'static, str>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: Cow::Borrowed("127.0.0.1:9090"),
+        }
+    }
+}
+
+/// Limits applied to the requests of the HTTPS service
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Limits {
+    /// Maximum size (in bytes) of all the request headers together, counted as
+    /// `name: value\r\n` for each header (default: 65536, max: 1048575)
+    pub max_header_size: usize,
+
+    /// Maximum number of request headers (default: 100, max: 256)
+    pub max_headers: usize,
+
+    /// Maximum number of connections open at once from a client IP, on both
+    /// listeners (default: unlimited). The trusted proxies of `client_ip` are exempt,
+    /// so it cannot be used with `client_ip.trust_hops`.
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl Limits {
+    /// Hard limits of the HTTP/1 parser, requests above them are always rejected
+    pub const PARSER_MAX_HEADER_SIZE: usize = 1_048_575;
+    pub const PARSER_MAX_HEADERS: usize = 256;
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_header_size: 64 * 1024,
+            max_headers: 100,
+            max_connections_per_ip: None,
+        }
+    }
+}
+
+/// What is done with the client side of a response whose upstream closed the
+/// connection (or failed) after the response headers were sent
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncatedResponseAction {
+    /// Closes the HTTP/1 connection before the body is complete and resets the
+    /// HTTP/2 stream, so clients (and caches) see the response as incomplete
+    #[default]
+    Abort,
+    /// Ends HTTP/2 responses with a trailer flagging the truncation instead of
+    /// resetting the stream. HTTP/1 responses are aborted, as trailers are not
+    /// supported there.
+    Trailer,
+}
+
+/// Handling of truncated upstream responses, which are always logged and counted
+/// (`proksi_http_truncated_responses_total`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TruncatedResponses {
+    /// Default: abort
+    pub action: TruncatedResponseAction,
+
+    /// Name of the trailer sent with the `trailer` action (default: proksi-upstream-error)
+    pub trailer: Cow<'static, str>,
+}
+
+impl Default for TruncatedResponses {
+    fn default() -> Self {
+        Self {
+            action: TruncatedResponseAction::default(),
+            trailer: Cow::Borrowed("proksi-upstream-error"),
+        }
+    }
+}
+
+/// What is done with an upstream response whose framing is ambiguous: both a
+/// `Transfer-Encoding` and a `Content-Length`, or `Content-Length`s that differ
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyLengthMismatchAction {
+    /// Answers the request with a 502, the upstream connection is dropped
+    #[default]
+    Reject,
+    /// Relays the response with the framing its body is read with (the
+    /// `Transfer-Encoding`, or the first `Content-Length`), then closes the
+    /// client connection
+    Pass,
+}
+
+/// Handling of the upstream responses whose body does not match their
+/// `Content-Length`, which are always logged and counted
+/// (`proksi_upstream_body_length_mismatches_total`)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct BodyLengthMismatches {
+    /// Default: reject
+    pub action: BodyLengthMismatchAction,
+}
+
+/// Timeouts of the connections to the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Timeouts {
+    /// How long a long-lived connection (websocket, server-sent events) stays
+    /// open without any byte sent either way, in seconds (default: 3600).
+    /// Other requests keep the read timeout of the upstream connections.
+    pub idle_secs: u64,
+
+    /// How long connecting to an upstream may take, in milliseconds
+    /// (default: 10000)
+    pub connect_ms: u64,
+
+    /// How long an upstream may send nothing while its response is read,
+    /// in milliseconds (default: 360000)
+    pub read_ms: u64,
+
+    /// How long writing the request to an upstream may be blocked,
+    /// in milliseconds (default: 60000)
+    pub write_ms: u64,
+
+    /// The `timeouts.response_secs` of the routes without their own
+    pub response_secs: Option<u64>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle_secs: 3600,
+            connect_ms: 10_000,
+            read_ms: 360_000,
+            write_ms: 60_000,
+            response_secs: None,
+        }
+    }
+}
+
+/// The path of the requests sent to the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardPath {
+    /// The path as sent by the client
+    #[default]
+    Raw,
+    /// The normalized path the routes were matched with
+    Normalized,
+}
+
+/// Handling of the requests sent to the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Request {
+    /// Bodies up to this size (in bytes) are read before the upstream is
+    /// connected, then sent at once (default: 0, bodies are streamed).
+    /// At most 65536, larger bodies and bodies of unknown length are streamed.
+    pub buffer_size: usize,
+
+    /// Whether the upstreams get the path as sent by the client (default)
+    /// or as normalized for the routes and `blocked_paths`
+    #[serde(default)]
+    pub forward_path: ForwardPath,
+
+    /// How the requests sent with `Expect: 100-continue` (waiting for a
+    /// `100 Continue` before their body) are handled (default: forward)
+    #[serde(default)]
+    pub expect_continue: ExpectContinue,
+}
+
+/// Who tells the clients sending `Expect: 100-continue` to send their body
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpectContinue {
+    /// The upstream, which gets the expectation: the body is only sent once it
+    /// answers `100 Continue`, not at all when it rejects the request first
+    #[default]
+    Forward,
+    /// Proksi, right away, for the upstreams that never answer `100 Continue`
+    Answer,
+}
+
+/// Buffers of the connections to the upstreams, through which the bodies
+/// are streamed between the clients and the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Proxy {
+    /// Size (in bytes) of the receive buffer of the upstream sockets
+    /// (default: 8192, between 4096 and 16777216)
+    #[serde(default = "default_proxy_buffer_size")]
+    pub buffer_size: usize,
+
+    /// Whether the `1xx` responses of the upstreams (`100 Continue`,
+    /// `103 Early Hints`) are relayed to the HTTP/1.1 clients (default: true)
+    #[serde(default = "default_proxy_informational_responses")]
+    pub informational_responses: bool,
+}
+
+impl Proxy {
+    pub const MIN_BUFFER_SIZE: usize = 4 * 1024;
+    pub const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+}
+
+fn default_proxy_buffer_size() -> usize {
+    8 * 1024
+}
+
+fn default_proxy_informational_responses() -> bool {
+    true
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Proxy {
+            buffer_size: default_proxy_buffer_size(),
+            informational_responses: default_proxy_informational_responses(),
+        }
+    }
+}
+
+/// Retries of the requests to the upstreams (failed connections, intercepted
+/// responses...), bounded so that they do not multiply the load of upstreams
+/// already failing. A token is earned by each `1 / ratio` requests sent to an
+/// upstream, and `min_retries_per_sec` each second; each retry spends one.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct RetryBudget {
+    /// Whether the retries are bounded (default: false)
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Share of the requests that can be retried, on top of the minimum
+    /// (default: 0.1, the retries add at most 10% to the requests)
+    #[serde(default = "default_retry_budget_ratio")]
+    pub ratio: f64,
+
+    /// Retries allowed each second whatever the number of requests, so that
+    /// the requests of low traffic can be retried (default: 10)
+    #[serde(default = "default_retry_budget_min_retries_per_sec")]
+    pub min_retries_per_sec: u32,
+
+    /// Most tokens saved for the retries to come (default: 100)
+    #[serde(default = "default_retry_budget_burst")]
+    pub burst: u32,
+}
+
+fn default_retry_budget_ratio() -> f64 {
+    0.1
+}
+
+fn default_retry_budget_min_retries_per_sec() -> u32 {
+    10
+}
+
+fn default_retry_budget_burst() -> u32 {
+    100
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget {
+            enabled: false,
+            ratio: default_retry_budget_ratio(),
+            min_retries_per_sec: default_retry_budget_min_retries_per_sec(),
+            burst: default_retry_budget_burst(),
+        }
+    }
+}
+
+/// The response of the requests whose route has no upstream to send them to
+/// (all of them removed by discovery, or unhealthy, without a fallback)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct NoUpstream {
+    /// Status of the response (default: 503)
+    #[serde(default = "default_no_upstream_status")]
+    pub status: u16,
+}
+
+fn default_no_upstream_status() -> u16 {
+    503
+}
+
+impl Default for NoUpstream {
+    fn default() -> Self {
+        NoUpstream {
+            status: default_no_upstream_status(),
+        }
+    }
+}
+
+/// How the `OPTIONS` requests (other than `OPTIONS *`) are handled
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionsHandling {
+    /// Sent to the upstream of the route, like any other request
+    #[default]
+    Proxy,
+    /// Answered by Proksi (`204` with the allowed methods), except for CORS
+    /// preflight requests, which are still proxied
+    Respond,
+}
+
+/// Request methods handled by Proksi itself, before any route
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Methods {
+    /// Methods answered with a `405 Method Not Allowed` (default: TRACE and CONNECT)
+    pub deny: Vec<Cow<'static, str>>,
+
+    /// Default: proxy
+    pub options: OptionsHandling,
+}
+
+impl Default for Methods {
+    fn default() -> Self {
+        Self {
+            deny: vec![Cow::Borrowed("TRACE"), Cow::Borrowed("CONNECT")],
+            options: OptionsHandling::default(),
+        }
+    }
+}
+
+/// Encoding of the compressed responses
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    #[serde(rename = "br", alias = "brotli")]
+    Brotli,
+    Gzip,
+    Zstd,
+}
+
+/// Compression of the responses (gzip, brotli or zstd) of the routes that do
+/// not override it with their own `compression` block
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Compression {
+    /// Whether the responses of every route are compressed (default: false)
+    pub enabled: bool,
+
+    /// The compression level of gzip and brotli, from 1 (fastest) to 11
+    /// (smallest output). Gzip levels are capped at 9 (default: 6)
+    pub level: u32,
+
+    /// The encodings offered, in order of preference: the first one the client
+    /// accepts is used (default: br, gzip)
+    pub algorithms: Vec<CompressionAlgorithm>,
+
+    /// The zstd level, from 1 (fastest) to 22 (smallest output) (default: 3)
+    pub zstd_level: u32,
+
+    /// The content types that are compressed (default: text, JSON, JavaScript, XML and SVG)
+    pub content_types: Vec<Cow<'static, str>>,
+
+    /// Responses whose `Content-Length` is below this many bytes are sent
+    /// uncompressed (default: 0, every response). Responses of unknown length
+    /// are compressed.
+    pub min_length: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: default_compression_level(),
+            algorithms: default_compression_algorithms(),
+            zstd_level: default_compression_zstd_level(),
+            content_types: default_compression_content_types(),
+            min_length: 0,
+        }
+    }
+}
+
+/// Sampling of the access logs of the proxied requests. Request counters
+/// (`proksi_http_requests_total`) are always updated, sampled or not.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tracing {
+    /// Share of the requests (0.0 to 1.0) that are logged (default: 1.0, every request).
+    /// Can be overridden per route.
+    pub sample_rate: f64,
+
+    /// Requests slower than this (in milliseconds) are always logged (default: 1000)
+    pub slow_request_ms: u64,
+}
+
+impl Default for Tracing {
+    fn default() -> Self {
+        Self {
+            sample_rate: 1.0,
+            slow_request_ms: 1000,
+        }
+    }
+}
+
+/// Tracing settings of a route
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteTracing {
+    /// Share of the requests of the route that are logged (default: `tracing.sample_rate`)
+    pub sample_rate: Option<f64>,
+}
+
+/// Access logs of a route
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteAccessLog {
+    /// Whether the requests of the route are logged (default: true). Failed
+    /// requests and server errors (5xx) are logged either way.
+    pub enabled: Option<bool>,
+
+    /// Share of the requests of the route that are logged
+    /// (default: `tracing.sample_rate`)
+    pub sample_rate: Option<f64>,
+}
+
+/// TLS settings of the HTTPS service
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tls {
+    /// Present an in-memory self-signed certificate (generated at startup)
+    /// when no certificate exists for the requested SNI (default: true)
+    pub fallback_cert: bool,
+
+    /// What happens to the handshakes requesting a server name (SNI) that
+    /// matches no route nor certificate (default: reject)
+    #[serde(default)]
+    pub unknown_sni: UnknownSni,
+
+    /// Resumption of the TLS sessions of returning clients
+    #[serde(default)]
+    pub session_resumption: TlsSessionResumption,
+
+    /// Certificates issued elsewhere (ex: internal or wildcard ones), loaded
+    /// on startup. They are served instead of the ACME ones for their hosts,
+    /// which are not ordered.
+    #[serde(default)]
+    pub certificates: Vec<TlsCertificate>,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self {
+            fallback_cert: true,
+            unknown_sni: UnknownSni::default(),
+            session_resumption: TlsSessionResumption::default(),
+            certificates: vec![],
+        }
+    }
+}
+
+/// A certificate provided as files (`tls.certificates`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsCertificate {
+    /// The host served, or a wildcard one (ex: '*.internal.example.com')
+    pub host: Cow<'static, str>,
+
+    /// Path to the PEM certificate. It can be a bundle: the certificate
+    /// first, then its intermediates in order.
+    pub cert_path: PathBuf,
+
+    /// Path to the PEM private key of the certificate
+    pub key_path: PathBuf,
+}
+
+/// How returning clients resume their TLS session, skipping a full handshake.
+/// The defaults are the ones of OpenSSL.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsSessionResumption {
+    /// Sessions kept in memory by the instance, resumed by their id (default: true)
+    #[serde(default = "bool_true")]
+    pub cache: bool,
+
+    /// Maximum number of sessions in the cache (default: 20480)
+    pub cache_size: Option<u32>,
+
+    /// Sessions resumed from a ticket kept by the client (default: true)
+    #[serde(default = "bool_true")]
+    pub tickets: bool,
+
+    /// Number of tickets sent after a full TLS 1.3 handshake (default: 2)
+    pub tls13_tickets: Option<usize>,
+}
+
+impl Default for TlsSessionResumption {
+    fn default() -> Self {
+        Self {
+            cache: true,
+            cache_size: None,
+            tickets: true,
+            tls13_tickets: None,
+        }
+    }
+}
+
+/// Handling of the handshakes for a server name proksi does not know
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownSni {
+    /// The handshake is aborted, hiding whether the host is served
+    #[default]
+    Reject,
+    /// The handshake completes with the fallback certificate, the requests get a 404
+    Fallback,
+}
+
+fn default_udp_idle_timeout_secs() -> u64 {
+    60
+}
+
+/// The transport protocol of a stream
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// A TCP or UDP port forwarded (without any HTTP processing) to a pool of upstreams
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Stream {
+    /// The address the stream listens on (ex: '0.0.0.0:5432')
+    pub listen: Cow<'static, str>,
+
+    /// Either 'tcp' or 'udp' (defaults to 'tcp')
+    #[serde(default)]
+    pub protocol: StreamProtocol,
+
+    /// The upstreams connections (or datagrams) are forwarded to
+    pub upstreams: Vec<RouteUpstream>,
+
+    /// Health check performed against the upstreams.
+    /// TCP streams default to a TCP connect check, UDP streams are only
+    /// checked when this is set (ex: a TCP probe on a DNS server)
+    pub health_check: Option<RouteHealthCheck>,
+
+    /// UDP only: how long (in seconds) a client keeps being forwarded to the
+    /// same upstream without any traffic (defaults to 60)
+    #[serde(default = "default_udp_idle_timeout_secs")]
+    pub udp_idle_timeout_secs: u64,
+}
+
+/// The main configuration struct.
+/// A configuration file (YAML, TOML or through ENV) will be parsed into this struct.
+/// Example:
+///
+/// ```yaml
+///
+/// # Example configuration file
+/// service_name: "proksi"
+/// logging:
+///   level: "INFO"
+///   access_logs_enabled: true
+///   error_logs_enabled: false
+/// letsencrypt:
+///   enabled: true
+///   email: "youremail@example.com"
+///   production: true
+/// paths:
+///   config_file: "/etc/proksi/config.toml"
+///   lets_encrypt: "/etc/proksi/letsencrypt"
+/// routes:
+///   - host: "example.com"
+///     match_with:
+///       path:
+///         patterns:
+///          - "/api/v1/*"
+///     headers:
+///       add:
+///         - name: "X-Api-Version"
+///           value: "1.0"
+///       remove:
+///         - name: "Server"
+///       add_request:
+///         - name: "Host"
+///           value: "internal.example.com"
+///       forwarded: true
+///     upstreams:
+///       - ip: "10.1.2.24"
+///         port: 3000
+///         network: "public"
+///       - ip: "10.1.2.23"
+///         port: 3000
+///         network: "shared"
+/// ```
+///
+/// Commands run instead of starting a new instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Starts a new instance that takes over the listening sockets of the
+    /// running one (found through `paths.pid_file`) without dropping connections.
+    /// The running instance exits once its in-flight requests are done.
+    Upgrade,
+    /// Prints the version, git commit and pingora version of the binary
+    Version,
+    /// Inspects the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+/// Commands run on the configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Subcommand)]
+pub enum ConfigCommand {
+    /// Prints the effective configuration: the files merged with the
+    /// environment and the defaults, with the secrets redacted
+    Dump {
+        #[clap(long, value_enum, default_value = "yaml")]
+        format: DumpFormat,
+    },
+}
+
+/// Format of `proksi config dump`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+/// Format of the startup summary
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum SummaryFormat {
+    /// Logged along with the other logs
+    #[default]
+    Text,
+    /// Printed to stdout as a single JSON line (ex: for deploy checks)
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Parser)]
+#[command(name = "Proksi")]
+#[command(version, about, long_about = None)]
+pub(crate) struct Config {
+    /// The name of the service (will appear as a log property)
+    #[serde(default)]
+    #[clap(short, long, default_value = "proksi")]
+    pub service_name: Cow<'static, str>,
+
+    /// Runs the service in the background (daemon mode)
+    #[clap(short, long, default_value = "false")]
+    pub daemon: bool,
+
+    /// Upgrades the service from an existing running instance
+    /// (the running instance has to be sent a `SIGQUIT` separately)
+    #[clap(short, long, default_value = "false")]
+    pub upgrade: bool,
+
+    #[serde(skip)]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Format of the summary emitted at startup (routes, listeners, services)
+    #[serde(skip)]
+    #[clap(long, value_enum, default_value = "text")]
+    pub format: SummaryFormat,
+
+    /// The number of worker threads to be used by the HTTPS proxy service.
+    ///
+    /// For background services the default is always (1) and cannot be changed.
+    #[clap(short, long, required = false, default_value = "2")]
+    pub worker_threads: Option<usize>,
+
+    /// The PATH to the configuration file to be used.
+    ///
+    /// The configuration file should be named either `proksi.hcl` or `proksi.yaml`
+    ///
+    /// and be present in that path. Defaults to the current directory.
+    #[clap(short, required = false, long, default_value = "./")]
+    #[allow(clippy::struct_field_names)]
+    pub config_path: Cow<'static, str>,
+
+    /// General config
+    #[command(flatten)]
+    pub logging: Logging,
+
+    #[command(flatten)]
+    pub auto_reload: AutoReload,
+
+    #[command(flatten)]
+    pub docker: Docker,
+
+    #[clap(skip)]
+    pub lets_encrypt: LetsEncrypt,
+
+    /// Configuration for paths (TLS, config file, etc.)
+    #[clap(skip)]
+    pub paths: Path,
+
+    /// Bounds for the in-memory stores (TTL, max entries, etc.)
+    #[clap(skip)]
+    pub stores: Stores,
+
+    /// Shutdown of the background services
+    #[clap(skip)]
+    pub shutdown: Shutdown,
+
+    /// TLS settings (fallback certificate, etc.)
+    #[clap(skip)]
+    pub tls: Tls,
+
+    /// Client IP resolution (trusted proxies, `X-Forwarded-For` depth)
+    #[clap(skip)]
+    pub client_ip: ClientIp,
+
+    /// Addresses of the HTTP and HTTPS services
+    #[clap(skip)]
+    pub listeners: Listeners,
+
+    /// Headers applied to every route (ex: response headers to strip)
+    #[clap(skip)]
+    pub headers: Headers,
+
+    /// Compression of the responses, which the routes can override
+    #[clap(skip)]
+    pub compression: Compression,
+
+    /// Sampling of the access logs
+    #[clap(skip)]
+    pub tracing: Tracing,
+
+    /// Limits applied to the requests (ex: header size)
+    #[clap(skip)]
+    pub limits: Limits,
+
+    /// Handling of upstream responses that end before they are complete
+    #[clap(skip)]
+    pub truncated_responses: TruncatedResponses,
+
+    /// Handling of upstream responses whose body does not match their length
+    #[clap(skip)]
+    pub body_length_mismatches: BodyLengthMismatches,
+
+    /// Methods denied or answered before reaching the routes (ex: TRACE)
+    #[clap(skip)]
+    pub methods: Methods,
+
+    /// Timeouts of the upstream connections (ex: idle websockets)
+    #[clap(skip)]
+    pub timeouts: Timeouts,
+
+    /// Buffering of the request bodies before they reach the upstreams
+    #[clap(skip)]
+    pub request: Request,
+
+    /// Buffers used to stream the bodies to and from the upstreams
+    #[clap(skip)]
+    pub proxy: Proxy,
+
+    /// Response of the requests of a route without any healthy upstream
+    #[clap(skip)]
+    pub no_upstream: NoUpstream,
+
+    /// Bounds the retries of the requests to the upstreams, of all routes
+    #[clap(skip)]
+    pub retry_budget: RetryBudget,
+
+    /// Admin API (disabled by default)
+    #[clap(skip)]
+    pub admin: Admin,
+
+    /// Prometheus metrics (disabled by default)
+    #[clap(skip)]
+    pub metrics: Metrics,
+
+    /// Availability zone of this instance: upstreams of the same `zone`
+    /// are preferred over the other ones
+    #[clap(skip)]
+    pub local_zone: Option<Cow<'static, str>>,
+
+    /// Hosts the requests are accepted for besides the ones of the routes,
+    /// `*.example.com` for the subdomains of `example.com` (default: any host)
+    #[serde(default)]
+    #[clap(skip)]
+    pub allowed_hosts: Vec<Cow<'static, str>>,
+
+    /// Reusable middleware (headers, plugins) referenced by name from the routes
+    #[clap(skip)]
+    pub middleware_profiles: HashMap<Cow<'static, str>, MiddlewareProfile>,
+
+    /// The routes to be proxied to.
+    #[clap(skip)]
+    pub routes: Vec<Route>,
+
+    /// TCP/UDP ports forwarded to upstreams without HTTP processing
+    #[clap(skip)]
+    pub streams: Vec<Stream>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            config_path: Cow::Borrowed("/etc/proksi/config"),
+            service_name: Cow::Borrowed("proksi"),
+            worker_threads: Some(2),
+            upgrade: false,
+            command: None,
+            format: SummaryFormat::default(),
+            daemon: false,
+            docker: Docker::default(),
+            lets_encrypt: LetsEncrypt::default(),
+            stores: Stores::default(),
+            shutdown: Shutdown::default(),
+            tls: Tls::default(),
+            client_ip: ClientIp::default(),
+            listeners: Listeners::default(),
+            headers: Headers::default(),
+            compression: Compression::default(),
+            tracing: Tracing::default(),
+            limits: Limits::default(),
+            truncated_responses: TruncatedResponses::default(),
+            body_length_mismatches: BodyLengthMismatches::default(),
+            methods: Methods::default(),
+            timeouts: Timeouts::default(),
+            request: Request::default(),
+            proxy: Proxy::default(),
+            no_upstream: NoUpstream::default(),
+            retry_budget: RetryBudget::default(),
+            admin: Admin::default(),
+            metrics: Metrics::default(),
+            local_zone: None,
+            allowed_hosts: Vec::new(),
+            middleware_profiles: HashMap::new(),
+            routes: vec![],
+            streams: vec![],
+            auto_reload: AutoReload::default(),
+            logging: Logging {
+                enabled: true,
+                level: LogLevel::Info,
+                access_logs_enabled: true,
+                error_logs_enabled: false,
+                format: LogFormat::Json,
+                path: None,
+                rotation: LogRotation::Never,
+                slow_request_threshold: None,
+            },
+            paths: Path::default(),
+        }
+    }
+}
+
+// impl Config {
+//     // Allow the configuration to be extracted from any `Provider`.
+//     fn from<T: figment::Provider>(provider: T) -> Result<Config, figment::Error> {
+//         Figment::from(provider).extract()
+//     }
+
+//     // Provide a default provider, a `Figment`.
+//     fn figment() -> Figment {
+//         use figment::providers::Env;
+
+//         // In reality, whatever the library desires.
+//         Figment::from(Config::default()).merge(Env::prefixed("APP_"))
+//     }
+// }
+
+/// Implement the `Provider` trait for the `Config` struct.
+/// This allows the `Config` struct to be used as a configuration provider with *defaults*.
+impl Provider for Config {
+    fn metadata(&self) -> figment::Metadata {
+        figment::Metadata::named("proksi")
+    }
+
+    fn data(
+        &self,
+    ) -> Result<figment::value::Map<figment::Profile, figment::value::Dict>, figment::Error> {
+        Serialized::defaults(Config::default()).data()
+    }
+}
+
+/// Load the configuration from the configuration file(s) as a `Config` struct.
+/// In theory one could create all 3 configurations formats and they will overlap each other
+///
+/// Nested keys can be separated by double underscores (__) in the environment variables.
+/// E.g. `PROKSI__LOGGING__LEVEL=DEBUG` will set the `level` key in the
+/// `logging` key in the `proksi` key.
+pub fn load(fallback: &str) -> Result<Config, Error> {
+    let parsed_commands = Config::parse();
+
+    // `proksi version` only prints the build information, whatever the configuration
+    if parsed_commands.command == Some(Command::Version) {
+        return Ok(parsed_commands);
+    }
+
+    let path_with_fallback = if parsed_commands.config_path.is_empty() {
+        fallback
+    } else {
+        &parsed_commands.config_path
+    };
+
+    let mut config: Config = Figment::new()
+        .merge(Config::default())
+        .merge(Serialized::defaults(&parsed_commands))
+        .merge(Yaml::file(format!("{path_with_fallback}/proksi.yml")))
+        .merge(Yaml::file(format!("{path_with_fallback}/proksi.yaml")))
+        .merge(Hcl::file(format!("{path_with_fallback}/proksi.hcl")))
+        .merge(Env::prefixed("PROKSI_").split("__"))
+        .extract()?;
+
+    // subcommands (and the summary format) are not part of the configuration sources
+    config.command = parsed_commands.command;
+    config.format = parsed_commands.format;
+
+    // hosts are case-insensitive and stored without port nor trailing dot
+    // (the regexes as written)
+    for route in &mut config.routes {
+        route.host = Cow::Owned(route_key(&route.host).into_owned());
+    }
+
+    // upstreams may be given as `scheme://ip`
+    for (route_index, route) in config.routes.iter_mut().enumerate() {
+        for (upstream_index, upstream) in route.upstreams.iter_mut().enumerate() {
+            upstream.split_scheme().map_err(|err| {
+                Error::Config(format!(
+                    "routes{route_index}.upstreams{upstream_index}.ip {err}"
+                ))
+            })?;
+        }
+
+        if let Some(upstream) = route.fallback_upstream.as_mut() {
+            upstream.split_scheme().map_err(|err| {
+                Error::Config(format!("routes{route_index}.fallback_upstream.ip {err}"))
+            })?;
+        }
+    }
+
+    // expand the middleware profiles referenced by the routes
+    profiles::expand(&mut config).map_err(|err| Error::Config(err.to_string()))?;
+
+    // validate configuration and throw error upwards
+    validate::check_config(&config).map_err(|err| Error::Config(err.to_string()))?;
+
+    Ok(config)
+}
+
+/// Deserialize function to convert a string to a `LogLevel` Enum
+fn log_level_deser<'de, D>(deserializer: D) -> Result<LogLevel, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "debug" => Ok(LogLevel::Debug),
+        "info" => Ok(LogLevel::Info),
+        "warn" => Ok(LogLevel::Warn),
+        "error" => Ok(LogLevel::Error),
+        "trace" => Ok(LogLevel::Trace),
+        _ => Err(serde::de::Error::custom(
+            "expected one of DEBUG, INFO, WARN, ERROR, TRACE",
+        )),
+    }
+}
+
+/// Deserialize function to convert a string to a `DockerServiceMode` Enum
+fn docker_mode_deser<'de, D>(deserializer: D) -> Result<DockerServiceMode, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "swarm" => Ok(DockerServiceMode::Swarm),
+        "container" => Ok(DockerServiceMode::Container),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: Swarm, Container",
+        )),
+    }
+}
+
+/// Deserialize function to convert a string to a `LogLevel` Enum
+fn log_format_deser<'de, D>(deserializer: D) -> Result<LogFormat, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "json" => Ok(LogFormat::Json),
+        "pretty" | "text" => Ok(LogFormat::Pretty),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: json, text, pretty",
+        )),
+    }
+}
+
+fn log_rotation_deser<'de, D>(deserializer: D) -> Result<LogRotation, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "daily" => Ok(LogRotation::Daily),
+        "hourly" => Ok(LogRotation::Hourly),
+        "minutely" => Ok(LogRotation::Minutely),
+        "never" => Ok(LogRotation::Never),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: daily, hourly, minutely, never",
+        )),
+    }
+}
+
+/// Deserialize function to convert a string to a `LogLevel` Enum
+fn proto_version_deser<'de, D>(deserializer: D) -> Result<ProtoVersion, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "v1.1" => Ok(ProtoVersion::V1_1),
+        "v1.2" => Ok(ProtoVersion::V1_2),
+        "v1.3" => Ok(ProtoVersion::V1_3),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: v1.1, v1.2, v1.3",
+        )),
+    }
+}
+
+fn deserialize_cache_type<'de, D>(deserializer: D) -> Result<RouteCacheType, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "disk" => Ok(RouteCacheType::Disk),
+        "memcache" => Ok(RouteCacheType::MemCache),
+        _ => Err(serde::de::Error::custom("expected one of: disk, memcache")),
+    }
+}
+
+fn health_check_type_deser<'de, D>(deserializer: D) -> Result<RouteHealthCheckType, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "tcp" => Ok(RouteHealthCheckType::Tcp),
+        "http" => Ok(RouteHealthCheckType::Http),
+        "grpc" => Ok(RouteHealthCheckType::Grpc),
+        _ => Err(serde::de::Error::custom("expected one of: tcp, http, grpc")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn helper_config_file() -> &'static str {
+        r#"
+        service_name: "proksi"
+        lets_encrypt:
+          email: "user@domain.net"
+        logging:
+          level: "INFO"
+          access_logs_enabled: true
+          error_logs_enabled: false
+        paths:
+          lets_encrypt: "/test/letsencrypt"
+        routes:
+          - host: "example.com"
+            plugins:
+              - name: "cors"
+                config:
+                  allowed_origins: ["*"]
+            headers:
+              add:
+                - name: "X-Forwarded-For"
+                  value: "<value>"
+                - name: "X-Api-Version"
+                  value: "1.0"
+              remove:
+                - name: "Server"
+            upstreams:
+              - ip: "10.0.1.3"
+                port: 3000
+                network: "public"
+      "#
+    }
+
+    #[test]
+    fn test_load_config_from_yaml() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), helper_config_file())?;
+
+            let config = load(&tmp_dir);
+            let proxy_config = config.unwrap();
+            assert_eq!(proxy_config.service_name, "proksi");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_from_yaml_and_env_vars() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                format!("{}/proksi.yaml", jail.directory().to_str().unwrap()),
+                helper_config_file(),
+            )?;
+            jail.set_env("PROKSI_SERVICE_NAME", "new_name");
+            jail.set_env("PROKSI_LOGGING__ENABLED", "false");
+            jail.set_env("PROKSI_LOGGING__LEVEL", "warn");
+            jail.set_env("PROKSI_DOCKER__ENABLED", "true");
+            jail.set_env("PROKSI_DOCKER__INTERVAL_SECS", "30");
+            jail.set_env("PROKSI_DOCKER__ENDPOINT", "http://localhost:2375");
+            jail.set_env("PROKSI_LETS_ENCRYPT__STAGING", "false");
+            jail.set_env("PROKSI_LETS_ENCRYPT__EMAIL", "my-real-email@domain.com");
+            jail.set_env(
+                "PROKSI_ROUTES",
+                r#"[{
+              host="changed.example.com",
+              match_with={ path={ patterns=["/api/v1/:entity/:action*"] } },
+              plugins=[{ name="cors", config={ allowed_origins=["*"] } }],
+              upstreams=[{ ip="10.0.1.2", port=3000, weight=1 }] }]
+            "#,
+            );
+
+            let config = load(jail.directory().to_str().unwrap());
+
+            let proxy_config = config.unwrap();
+            assert_eq!(proxy_config.service_name, "new_name");
+            assert!(!proxy_config.logging.enabled);
+            assert_eq!(proxy_config.logging.level, LogLevel::Warn);
+
+            assert_eq!(proxy_config.docker.enabled, Some(true));
+            assert_eq!(proxy_config.docker.interval_secs, Some(30));
+            assert_eq!(
+                proxy_config.docker.endpoint,
+                Some(Cow::Borrowed("http://localhost:2375"))
+            );
+
+            assert_eq!(proxy_config.lets_encrypt.staging, Some(false));
+            assert_eq!(proxy_config.lets_encrypt.email, "my-real-email@domain.com");
+
+            assert_eq!(proxy_config.routes[0].host, "changed.example.com");
+            assert_eq!(proxy_config.routes[0].upstreams[0].ip, "10.0.1.2");
+
+            let matcher = proxy_config.routes[0].match_with.as_ref().unwrap();
+
+            assert_eq!(
+                matcher.path.as_ref().unwrap().patterns,
+                vec![Cow::Borrowed("/api/v1/:entity/:action*")]
+            );
+
+            assert_eq!(
+                proxy_config.paths.lets_encrypt,
+                PathBuf::from("/test/letsencrypt")
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_defaults_only() {
+        figment::Jail::expect_with(|jail| {
+            jail.set_env("PROKSI_LETS_ENCRYPT__EMAIL", "my-real-email@domain.com");
+            let config = load("/non-existent");
+            let proxy_config = config.unwrap();
+
+            let logging = proxy_config.logging;
+            assert_eq!(proxy_config.service_name, "proksi");
+            assert_eq!(logging.level, LogLevel::Info);
+            assert!(logging.access_logs_enabled);
+            assert!(logging.error_logs_enabled);
+            assert_eq!(logging.slow_request_threshold, None);
+
+            let resumption = proxy_config.tls.session_resumption;
+            assert!(resumption.cache && resumption.tickets);
+            assert_eq!(resumption.cache_size, None);
+            assert_eq!(proxy_config.no_upstream.status, 503);
+            assert_eq!(proxy_config.proxy.buffer_size, 8192);
+            assert!(proxy_config.proxy.informational_responses);
+            assert_eq!(proxy_config.request.forward_path, ForwardPath::Raw);
+            assert_eq!(
+                proxy_config.body_length_mismatches.action,
+                BodyLengthMismatchAction::Reject
+            );
+            assert_eq!(
+                proxy_config.request.expect_continue,
+                ExpectContinue::Forward
+            );
+            assert_eq!(proxy_config.shutdown.background_services_timeout_secs, 10);
+
+            assert_eq!(proxy_config.routes.len(), 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_load_config_with_defaults_and_yaml() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                    plugins:
+                      - name: "cors"
+                        config:
+                          allowed_origins: ["*"]
+                    ssl:
+                      path:
+                        key: "/etc/proksi/certs/my-host.key"
+                        pem: "/etc/proksi/certs/my-host.pem"
+                "#,
+            )?;
+
+            let config = load(&tmp_dir);
+            let proxy_config = config.unwrap();
+            let logging = proxy_config.logging;
+            let paths = proxy_config.paths;
+            let letsencrypt = proxy_config.lets_encrypt;
+
+            assert_eq!(proxy_config.service_name, "proksi");
+            assert_eq!(logging.level, LogLevel::Info);
+            assert!(logging.access_logs_enabled);
+            assert!(logging.error_logs_enabled);
+            assert_eq!(proxy_config.routes.len(), 1);
+
+            assert_eq!(proxy_config.docker.enabled, Some(false));
+            assert_eq!(proxy_config.docker.interval_secs, Some(15));
+            assert_eq!(
+                proxy_config.docker.endpoint,
+                Some(Cow::Borrowed("unix:///var/run/docker.sock"))
+            );
+
+            assert_eq!(letsencrypt.email, "domain@valid.com");
+            assert_eq!(letsencrypt.enabled, Some(true));
+            assert_eq!(letsencrypt.staging, Some(true));
+
+            assert_eq!(paths.lets_encrypt.as_os_str(), "/etc/proksi/letsencrypt");
+
+            let route = &proxy_config.routes[0];
+            let plugins = route.plugins.as_ref().unwrap();
+            let plugin_config = plugins[0].config.as_ref().unwrap();
+            assert_eq!(plugins[0].name, "cors");
+            assert_eq!(plugin_config.get("allowed_origins"), Some(&json!(["*"])));
+
+            let ssl = route.ssl.as_ref().unwrap();
+            let path = ssl.path.as_ref().unwrap();
+            assert!(ssl.self_signed_fallback);
+            assert_eq!(path.key.as_os_str(), "/etc/proksi/certs/my-host.key");
+            assert_eq!(path.pem.as_os_str(), "/etc/proksi/certs/my-host.pem");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_health_check() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 50051
+                    health_check:
+                      check_type: "GRPC"
+                      grpc_service: "api.Users"
+                  - host: "other.com"
+                    upstreams:
+                      - ip: "10.1.2.25"
+                        port: 3000
+                    health_check: {}
+                "#,
+            )?;
+
+            let proxy_config = load(&tmp_dir).unwrap();
+
+            let health_check = proxy_config.routes[0].health_check.as_ref().unwrap();
+            assert_eq!(health_check.check_type, RouteHealthCheckType::Grpc);
+            assert_eq!(health_check.grpc_service.as_deref(), Some("api.Users"));
+            assert_eq!(health_check.timeout_secs, 1);
+
+            let health_check = proxy_config.routes[1].health_check.as_ref().unwrap();
+            assert_eq!(health_check.check_type, RouteHealthCheckType::Tcp);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_http_health_check() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |health_check: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                    health_check: {health_check}
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"{ type: "http", path: "/healthz", expected_status: 204, interval_secs: 5, consecutive_failures: 3 }"#,
+                ),
+            )?;
+            let health_check = load(&tmp_dir).unwrap().routes[0]
+                .health_check
+                .clone()
+                .unwrap();
+            assert_eq!(health_check.check_type, RouteHealthCheckType::Http);
+            assert_eq!(health_check.path, "/healthz");
+            assert_eq!(health_check.expected_status, 204);
+            assert_eq!(health_check.interval_secs, 5);
+            assert_eq!(health_check.consecutive_failures, 3);
+            assert_eq!(health_check.consecutive_successes, 1);
+            assert!(!health_check.tls);
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("{}"))?;
+            let health_check = load(&tmp_dir).unwrap().routes[0]
+                .health_check
+                .clone()
+                .unwrap();
+            assert_eq!(health_check.interval_secs, 15);
+            assert_eq!(health_check.path, "/");
+
+            for (health_check, expected) in [
+                (
+                    r#"{ interval_secs: 0 }"#,
+                    "health_check.interval_secs must be greater than 0",
+                ),
+                (
+                    r#"{ consecutive_failures: 0 }"#,
+                    "health_check.consecutive_failures must be greater than 0",
+                ),
+                (
+                    r#"{ type: "http", path: "healthz" }"#,
+                    "health_check.path must start with /",
+                ),
+                (
+                    r#"{ type: "http", expected_status: 600 }"#,
+                    "health_check.expected_status must be between 100 and 599",
+                ),
+            ] {
+                jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(health_check))?;
+                let err = load(&tmp_dir).unwrap_err().to_string();
+                assert!(err.contains(expected), "{err}");
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_connection_limits() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |client_ip: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                limits:
+                  max_connections_per_ip: 10
+                client_ip:
+                  {client_ip}
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"trusted_proxies: ["10.0.0.0/8"]"#),
+            )?;
+            let config_from_file = load(&tmp_dir).unwrap();
+            assert_eq!(config_from_file.limits.max_connections_per_ip, Some(10));
+
+            // The limit would never apply
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("trust_hops: 1"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains(
+                    "limits.max_connections_per_ip cannot be used with client_ip.trust_hops"
+                ),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_duplicate_hosts() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let routes = |priority: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                  - host: "Example.com."
+                    {priority}
+                    upstreams:
+                      - ip: "10.1.2.25"
+                        port: 3000
+                "#
+                )
+            };
+
+            // Same host (case insensitive), same priority
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &routes(""))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("routes0 and routes1"), "{err}");
+
+            // The second route takes precedence
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &routes("priority: 1"))?;
+            let config = load(&tmp_dir).unwrap();
+            assert!(config.routes[0].is_shadowed_by(&config.routes[1]));
+            assert!(!config.routes[1].is_shadowed_by(&config.routes[0]));
+            // Stored in lowercase, without the trailing dot
+            assert_eq!(config.routes[1].host, "example.com");
+
+            // Regexes are case-sensitive: `\D` and `\d` are distinct hosts
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: '~^app-\d+\.example\.com$'
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                  - host: '~^app-\D+\.example\.com$'
+                    upstreams:
+                      - ip: "10.1.2.25"
+                        port: 3000
+                "#,
+            )?;
+            let config = load(&tmp_dir).unwrap();
+            assert_eq!(config.routes[1].host, r"~^app-\D+\.example\.com$");
+            assert!(!config.routes[0].is_shadowed_by(&config.routes[1]));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_geo_routing() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |pools: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    geo_routing:
+                      header: "CF-IPCountry"
+                      pools:
+                        {pools}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                        pool: "eu"
+                      - ip: "10.1.2.25"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"eu: ["DE", "FR"]"#),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let geo = route.geo_routing.as_ref().unwrap();
+            assert_eq!(geo.header, "CF-IPCountry");
+            assert_eq!(geo.pools["eu"], vec!["DE", "FR"]);
+            assert_eq!(route.upstreams[0].pool.as_deref(), Some("eu"));
+            assert_eq!(route.upstreams[1].pool, None);
+
+            // A pool without upstreams
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(r#"us: ["US"]"#))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("geo_routing.pools.us has no upstream"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_upstream_path_groups() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |web: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                        match_with:
+                          path:
+                            patterns: ["/api/*"]
+                      - ip: "10.1.2.25"
+                        port: 3000
+                        {web}
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(""))?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let path = route.upstreams[0]
+                .match_with
+                .as_ref()
+                .unwrap()
+                .path
+                .as_ref();
+            assert_eq!(path.unwrap().patterns, vec!["/api/*"]);
+            assert!(route.upstreams[1].match_with.is_none());
+
+            // Every upstream in a group, none serving the other paths
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"match_with: { path: { patterns: ["/static/*"] } }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("need one without match_with"), "{err}");
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"match_with: { path: { patterns: [] } }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("upstreams1.match_with.path.patterns must not be empty"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_selection() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                  - host: "least.example.com"
+                    selection: "least_connections"
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                  - host: "consistent.example.com"
+                    algorithm: "consistent"
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#,
+            )?;
+
+            let routes = load(&tmp_dir).unwrap().routes;
+            assert_eq!(
+                routes[0].selection.unwrap_or_default(),
+                RouteSelection::RoundRobin
+            );
+            assert_eq!(routes[1].selection, Some(RouteSelection::LeastConnections));
+            assert_eq!(routes[2].selection, Some(RouteSelection::Consistent));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_sticky_clients() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |sticky: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    sticky:
+                      {sticky}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(r#"by: "ip""#))?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let sticky = route.sticky.as_ref().unwrap();
+            assert_eq!(sticky.by, RouteStickyBy::Ip);
+            assert_eq!(sticky.ttl_secs, 3600);
+            assert_eq!(sticky.max_entries, 100_000);
+            assert_eq!(sticky.virtual_nodes, 160);
+            assert_eq!(sticky.hash_seed, None);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ virtual_nodes: 40, hash_seed: 7 }"),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let sticky = route.sticky.as_ref().unwrap();
+            assert_eq!(sticky.virtual_nodes, 40);
+            assert_eq!(sticky.hash_seed, Some(7));
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("max_entries: 0"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("sticky.max_entries must be greater than 0"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("virtual_nodes: 0"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("sticky.virtual_nodes must be between 1 and 4096"),
+                "{err}"
+            );
+
+            // The subject of the JWT requires the plugin validating it
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"by: "jwt:sub""#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("sticky.by jwt:sub requires the oauth2 plugin"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"by: "jwt:sub"
+                    plugins:
+                      - name: "oauth2"
+                        config:
+                          provider: "github""#,
+                ),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            assert_eq!(route.sticky.as_ref().unwrap().by, RouteStickyBy::JwtSub);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_concurrency() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |concurrency: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    concurrency: {concurrency}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ max_concurrent_requests: 10 }"),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let concurrency = route.concurrency.as_ref().unwrap();
+            assert_eq!(concurrency.max_concurrent_requests, 10);
+            assert_eq!(concurrency.overflow, RouteOverflow::Reject);
+            assert_eq!(concurrency.queue_timeout_ms, 1000);
+            assert_eq!(concurrency.max_queued, None);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"{ max_concurrent_requests: 10, overflow: "queue", queue_timeout_ms: 0 }"#,
+                ),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("concurrency.queue_timeout_ms must be greater than 0"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_upstream_schemes() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |ip: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    upstreams:
+                      - ip: "{ip}"
+                        port: 8443
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("HTTPS://10.1.2.24/"),
+            )?;
+            let upstream = &load(&tmp_dir).unwrap().routes[0].upstreams[0];
+            assert_eq!(upstream.ip, "10.1.2.24");
+            assert_eq!(upstream.scheme, Some(UpstreamScheme::Https));
+            assert!(upstream.tls(8443));
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("h2c://grpc"))?;
+            let upstream = &load(&tmp_dir).unwrap().routes[0].upstreams[0];
+            assert_eq!(upstream.ip, "grpc");
+            assert!(!upstream.tls(443));
+            assert_eq!(upstream.effective_protocol(), RouteUpstreamProtocol::H2);
+
+            // Bare addresses keep plain HTTP, TLS only on port 443
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("10.1.2.24"))?;
+            let upstream = &load(&tmp_dir).unwrap().routes[0].upstreams[0];
+            assert_eq!(upstream.scheme, None);
+            assert!(!upstream.tls(8443) && upstream.tls(443));
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("ftp://10.1.2.24"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0.upstreams0.ip has an unknown scheme ftp://"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_upstream_h2() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |h2: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    upstreams:
+                      - ip: "h2c://grpc"
+                        port: 50051
+                        h2: {h2}
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ max_streams: 100 }"),
+            )?;
+            let h2 = load(&tmp_dir).unwrap().routes[0].upstreams[0].h2.unwrap();
+            assert_eq!(h2.max_streams, 100);
+            assert_eq!(h2.ping_interval_secs, 60);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ max_streams: 0 }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0.upstreams0.h2.max_streams must be between 1 and 2147483647"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_route_timeouts() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |global: &str, route: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                timeouts: {global}
+                routes:
+                  - host: "example.com"
+                    timeouts: {route}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    "{ connect_ms: 2000, response_secs: 60 }",
+                    "{ response_secs: 30, read_ms: 5000 }",
+                ),
+            )?;
+            let loaded = load(&tmp_dir).unwrap();
+            let timeouts = loaded.routes[0].timeouts.unwrap();
+            assert_eq!(timeouts.response_secs, Some(30));
+            assert_eq!(timeouts.read_ms, Some(5000));
+            assert_eq!(timeouts.connect_ms, None);
+            assert_eq!(loaded.timeouts.connect_ms, 2000);
+            assert_eq!(loaded.timeouts.read_ms, 360_000);
+            assert_eq!(loaded.timeouts.response_secs, Some(60));
+            assert_eq!(loaded.timeouts.idle_secs, 3600);
+
+            for (global, route, expected) in [
+                (
+                    "{}",
+                    "{ response_secs: 0 }",
+                    "routes0.timeouts.response_secs",
+                ),
+                ("{}", "{ write_ms: 0 }", "routes0.timeouts.write_ms"),
+                ("{ connect_ms: 0 }", "{}", "timeouts.connect_ms"),
+                ("{ response_secs: 0 }", "{}", "timeouts.response_secs"),
+            ] {
+                jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(global, route))?;
+                let err = load(&tmp_dir).unwrap_err().to_string();
+                assert!(
+                    err.contains(&format!("{expected} must be greater than 0")),
+                    "{err}"
+                );
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_route_warmup() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |warmup: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    warmup: {warmup}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("{}"))?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            assert_eq!(route.warmup.unwrap().period_secs, 30);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ period_secs: 0 }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("warmup.period_secs must be greater than 0"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_blocked_paths() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |blocked_paths: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    blocked_paths:
+                      {blocked_paths}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"{ preset: "common", paths: [{ exact: "/metrics" }, { regex: '\.sql$' }] }"#,
+                ),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let blocked_paths = route.blocked_paths.as_ref().unwrap();
+            assert_eq!(blocked_paths.preset, Some(BlockedPathsPreset::Common));
+            assert_eq!(blocked_paths.status, 404);
+            assert_eq!(
+                blocked_paths.paths,
+                vec![
+                    BlockedPath::Exact("/metrics".to_string()),
+                    BlockedPath::Regex(r"\.sql$".to_string())
+                ]
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ status: 401, preset: "common" }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("blocked_paths.status must be 403 or 404"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ paths: [{ prefix: "internal/" }] }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("blocked_paths.paths0 must start with /"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_user_agent() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |user_agent: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    user_agent:
+                      {user_agent}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"{ preset: "bad_bots", block: [{ regex: '^curl/' }], allow: [{ contains: "UptimeRobot" }] }"#,
+                ),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let user_agent = route.user_agent.as_ref().unwrap();
+            assert_eq!(user_agent.preset, Some(UserAgentPreset::BadBots));
+            assert_eq!(
+                user_agent.block,
+                vec![UserAgentPattern::Regex("^curl/".to_string())]
+            );
+            assert_eq!(
+                user_agent.allow,
+                vec![UserAgentPattern::Contains("UptimeRobot".to_string())]
+            );
+            assert!(!user_agent.block_missing);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ allow: [{ regex: "(bot" }] }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("user_agent.allow0 is an invalid regex"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ block: [{ contains: "" }] }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("user_agent.block0 must not be empty"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_rate_limit() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |rate_limit: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    rate_limit: {rate_limit}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ requests_per_second: 2.5 }"),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let rate_limit = route.rate_limit.as_ref().unwrap();
+            assert_eq!(rate_limit.key, "ip");
+            assert_eq!(rate_limit.burst(), 3);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ requests_per_second: 10, burst: 20, key: "header:X-Api-Key" }"#),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            assert_eq!(route.rate_limit.as_ref().unwrap().burst(), 20);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ requests_per_second: 0 }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("rate_limit.requests_per_second must be greater than 0"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ requests_per_second: 1, key: "path" }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("rate_limit.key must be ip or header"), "{err}");
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"{ requests_per_second: 1, response: { status: 503, body: "slow down" } }"#,
+                ),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            assert!(route.rate_limit.as_ref().unwrap().response.is_some());
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ requests_per_second: 1, response: { status: 200 } }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0.rate_limit.response.status must be between 400 and 599"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_rate_limit_plugin() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |rule: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    plugins:
+                      - name: rate_limit
+                        config:
+                          rules: [{rule}]
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ name: "anonymous", key: "ip", limit: 60, interval_secs: 60 }"#),
+            )?;
+            assert!(load(&tmp_dir).is_ok());
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ name: "anonymous", key: "ip", limit: 0, interval_secs: 60 }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0.plugins0.config.rules0.limit must be greater than 0"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ name: "anonymous", key: "ip", limit: 1, interval_secs: 0 }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("rules0.interval_secs must be greater than 0"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ name: "anonymous", key: "cookie", limit: 1, interval_secs: 1 }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0.plugins0.config.rules is invalid"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_mirror() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |mirror: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    mirror:
+                      {mirror}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"upstream: "http://10.1.2.25:3000""#),
+            )?;
+            let mirror = load(&tmp_dir).unwrap().routes[0].mirror.clone().unwrap();
+            assert_eq!(mirror.upstream, "http://10.1.2.25:3000");
+            assert_eq!(mirror.percentage, 100.0);
+            assert_eq!(mirror.max_body_size, 65536);
+            assert_eq!(mirror.timeout_ms, 5000);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"upstream: "10.1.2.25:3000""#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("mirror.upstream must be an http(s) URL"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ upstream: "http://10.1.2.25:3000", percentage: 150 }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("mirror.percentage must be between 0 and 100"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_status_map() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |status_map: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    status_map:
+                      {status_map}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"- { from: 418, to: 503 }
+                      - { from: 401, to: 302, location: "https://example.com/login" }"#,
+                ),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            assert_eq!(route.status_map.len(), 2);
+            assert_eq!(route.status_map[0].to, 503);
+            assert_eq!(route.status_map[0].location, None);
+            assert_eq!(
+                route.status_map[1].location.as_deref(),
+                Some("https://example.com/login")
+            );
+
+            // A location without a redirect
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"- { from: 401, to: 403, location: "/login" }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("status_map0.location needs a 3xx status"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_route_access_log() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "health.example.com"
+                    tracing:
+                      sample_rate: 0.5
+                    access_log:
+                      enabled: false
+                      sample_rate: 0.1
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                  - host: "example.com"
+                    tracing:
+                      sample_rate: 0.5
+                    upstreams:
+                      - ip: "10.1.2.25"
+                        port: 3000
+                "#,
+            )?;
+
+            let config = load(&tmp_dir).unwrap();
+            assert!(!config.routes[0].access_log_enabled());
+            assert_eq!(config.routes[0].access_log_sample_rate(), Some(0.1));
+            assert!(config.routes[1].access_log_enabled());
+            assert_eq!(config.routes[1].access_log_sample_rate(), Some(0.5));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_client_auth_crl() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |urls: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    ssl:
+                      client_auth:
+                        ca: "/etc/proksi/certs/clients-ca.pem"
+                        crl:
+                          paths: ["/etc/proksi/crl/clients.crl"]
+                          urls: {urls}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"["https://ca.example.com/clients.crl"]"#),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let client_auth = route.ssl.as_ref().unwrap().client_auth.as_ref().unwrap();
+            assert_eq!(
+                client_auth.ca.as_os_str(),
+                "/etc/proksi/certs/clients-ca.pem"
+            );
+            let crl = client_auth.crl.as_ref().unwrap();
+            assert_eq!(crl.urls, vec!["https://ca.example.com/clients.crl"]);
+            assert_eq!(crl.refresh_secs, 3600);
+            assert!(!crl.fail_open);
+            assert!(client_auth.key_policy.is_none());
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"["ldap://ca.example.com"]"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("ssl.client_auth.crl.urls must be http(s) URLs"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_error_handling() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |error_handling: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    error_handling: {error_handling}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ intercept_statuses: [502, 503], action: retry }"),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let error_handling = route.error_handling.as_ref().unwrap();
+            assert_eq!(error_handling.intercept_statuses, vec![502, 503]);
+            assert_eq!(error_handling.action, InterceptAction::Retry);
+            assert_eq!(error_handling.retries, 1);
+            assert_eq!(error_handling.retry_after, RetryAfterAction::Failover);
+            assert_eq!(error_handling.max_retry_after_secs, 10);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ intercept_statuses: [200] }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("error_handling.intercept_statuses must be 4xx or 5xx statuses: 200"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_retries() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |retries: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    retries: {retries}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("{}"))?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let retries = route.retries.as_ref().unwrap();
+            assert_eq!(retries.attempts, 2);
+            assert_eq!(
+                retries.retry_on,
+                vec![RetryOn::Condition(RetryCondition::ConnectError)]
+            );
+            assert!(!retries.non_idempotent);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ attempts: 1, retry_on: [connect_error, 502, 503, 504], non_idempotent: true }"),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let retries = route.retries.as_ref().unwrap();
+            assert_eq!(retries.attempts, 1);
+            assert_eq!(
+                retries.retry_on,
+                vec![
+                    RetryOn::Condition(RetryCondition::ConnectError),
+                    RetryOn::Status(502),
+                    RetryOn::Status(503),
+                    RetryOn::Status(504),
+                ]
+            );
+            assert!(retries.non_idempotent);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ retry_on: [404] }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("retries.retry_on must be connect_error or 5xx statuses: 404"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ attempts: 0 }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("retries.attempts must be greater than 0"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_tls_certificates() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |host: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                tls:
+                  certificates:
+                    - host: "{host}"
+                      cert_path: "/etc/proksi/certs/internal.pem"
+                      key_path: "/etc/proksi/certs/internal.key"
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("*.internal.example.com"),
+            )?;
+            let tls = load(&tmp_dir).unwrap().tls;
+            assert_eq!(tls.certificates.len(), 1);
+            assert_eq!(tls.certificates[0].host, "*.internal.example.com");
+            assert_eq!(
+                tls.certificates[0].cert_path,
+                PathBuf::from("/etc/proksi/certs/internal.pem")
+            );
+            assert_eq!(
+                tls.certificates[0].key_path,
+                PathBuf::from("/etc/proksi/certs/internal.key")
+            );
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("*."))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("tls.certificates0.host cannot be empty"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_dynamic_upstream() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |template: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    dynamic_upstream:
+                      segment: 1
+                      upstreams:
+                        acme: "10.0.0.5:3000"
+                      template: "{template}"
+                    upstreams: []
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{segment}.tenants.svc:3000"),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let dynamic = route.dynamic_upstream.as_ref().unwrap();
+            assert_eq!(dynamic.segment, 1);
+            assert_eq!(dynamic.upstreams["acme"], "10.0.0.5:3000");
+            assert!(!dynamic.tls);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("tenants.svc:3000"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains(
+                    "dynamic_upstream.template must be a host:port address with {segment}"
+                ),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_client_auth_key_policy() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |key_policy: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    ssl:
+                      client_auth:
+                        ca: "/etc/proksi/certs/clients-ca.pem"
+                        key_policy: {key_policy}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ min_rsa_bits: 3072 }"),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let client_auth = route.ssl.as_ref().unwrap().client_auth.as_ref().unwrap();
+            let key_policy = client_auth.key_policy.as_ref().unwrap();
+            assert_eq!(key_policy.min_rsa_bits, 3072);
+            assert_eq!(key_policy.min_ec_bits, 256);
+            assert_eq!(
+                key_policy.algorithms,
+                vec![KeyAlgorithm::Rsa, KeyAlgorithm::Ec, KeyAlgorithm::Ed25519]
+            );
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ algorithms: [] }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("ssl.client_auth.key_policy.algorithms must not be empty"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_response_substitutions() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |from: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    response:
+                      substitute:
+                        - from: "http://app.internal:8080"
+                          to: "https://example.com"
+                        - from: "{from}"
+                          to: "/v$1"
+                          regex: true
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(r"/api/(\\d+)"))?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let response = route.response.as_ref().unwrap();
+            assert_eq!(response.substitute_max_bytes, 1024 * 1024);
+            assert_eq!(response.substitute[0].from, "http://app.internal:8080");
+            assert!(!response.substitute[0].regex);
+            assert_eq!(response.substitute[1].from, r"/api/(\d+)");
+            assert!(response.substitute[1].regex);
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("(unclosed"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("response.substitute1.from is an invalid regex"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_methods() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |methods: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                {methods}
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(""))?;
+            let methods = load(&tmp_dir).unwrap().methods;
+            assert_eq!(methods.deny, vec!["TRACE", "CONNECT"]);
+            assert_eq!(methods.options, OptionsHandling::Proxy);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("methods: { deny: [\"TRACE\"], options: \"respond\" }"),
+            )?;
+            let methods = load(&tmp_dir).unwrap().methods;
+            assert_eq!(methods.deny, vec!["TRACE"]);
+            assert_eq!(methods.options, OptionsHandling::Respond);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("methods: { deny: [\"NOT A METHOD\"] }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("methods.deny contains an invalid method"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_invalid_routes() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |routes: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes: {routes}
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"[
+                      { host: "example.com", upstreams: [{ ip: "[::1]", port: 3000 }, { ip: "api_v2.svc", port: 3000 }] }
+                    ]"#,
+                ),
+            )?;
+            assert!(load(&tmp_dir).is_ok());
+
+            // Every problem of every route is reported
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"[
+                      { host: "", upstreams: [{ ip: "10.0.0.1", port: 3000 }] },
+                      { host: "empty.example.com", upstreams: [] },
+                      { host: "typo.example.com", upstreams: [{ ip: "10.0.0.300", port: 0 }] },
+                      {
+                        host: "patterns.example.com",
+                        match_with: { path: { patterns: ["api/*", "/users/:id", "/users/:name"] } },
+                        upstreams: [{ ip: "10.1.2.24/24", port: 3000 }]
+                      }
+                    ]"#,
+                ),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            for expected in [
+                "7 problems in the routes",
+                "routes0.host cannot be empty",
+                "routes1.upstreams cannot be empty",
+                r#"routes2.upstreams0.ip must be an IP address (IPv6 in brackets) or a host name, not "10.0.0.300""#,
+                "routes2.upstreams0.port must be greater than 0",
+                "routes3.match_with.path.patterns0 must start with /: api/*",
+                "routes3.match_with.path.patterns2 matches the same paths as patterns1: /users/:name",
+                r#"routes3.upstreams0.ip must be an IP address (IPv6 in brackets) or a host name, not "10.1.2.24/24""#,
+            ] {
+                assert!(err.contains(expected), "{err}");
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_host_patterns() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |host: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: '{host}'
+                    upstreams:
+                      - ip: "10.0.1.24"
+                        port: 3000
+                "#
+                )
+            };
+
+            for host in ["*.example.com", r"~^app-\d+\.example\.com$"] {
+                jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(host))?;
+                assert_eq!(load(&tmp_dir).unwrap().routes[0].host, host);
+            }
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("api.*.com"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0.host can only have a leading wildcard"),
+                "{err}"
+            );
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("~app-(\\d+"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("routes0.host is an invalid regex"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_docker_tls() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                docker:
+                  enabled: true
+                  host: "tcp://10.0.0.1:2376"
+                  tls_cert: "/certs/cert.pem"
+                  tls_key: "/certs/key.pem"
+                  tls_ca: "/certs/ca.pem"
+                "#,
+            )?;
+
+            let docker = load(&tmp_dir).unwrap().docker;
+            assert_eq!(docker.host, Some(Cow::Borrowed("tcp://10.0.0.1:2376")));
+            assert_eq!(docker.tls_cert, Some(PathBuf::from("/certs/cert.pem")));
+            assert_eq!(docker.tls_key, Some(PathBuf::from("/certs/key.pem")));
+            assert_eq!(docker.tls_ca, Some(PathBuf::from("/certs/ca.pem")));
+
+            // TLS files must be provided together
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                docker:
+                  tls_cert: "/certs/cert.pem"
+                "#,
+            )?;
+            assert!(load(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_acme_provider() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                  directory_url: "https://acme.zerossl.com/v2/DV90"
+                  eab_kid: "kid-1"
+                  eab_hmac_key: "c2VjcmV0LWtleQ"
+                "#,
+            )?;
+
+            let lets_encrypt = load(&tmp_dir).unwrap().lets_encrypt;
+            assert_eq!(
+                lets_encrypt.directory_url.as_deref(),
+                Some("https://acme.zerossl.com/v2/DV90")
+            );
+            assert_eq!(lets_encrypt.eab_kid.as_deref(), Some("kid-1"));
+
+            // EAB credentials must be provided together
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                  eab_kid: "kid-1"
+                "#,
+            )?;
+            assert!(load(&tmp_dir).is_err());
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                  directory_url: "http://acme.example.com/directory"
+                "#,
+            )?;
+            assert!(load(&tmp_dir).is_err());
+
+            // Challenges must be answered for as long as their orders last
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                  challenge_timeout_secs: 600
+                  challenge_ttl_secs: 300
+                "#,
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("lets_encrypt.challenge_ttl_secs must be at least"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_tcp_listener_options() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                listeners:
+                  http_address: "[::]:80"
+                  https_address: "[::]:443"
+                  tcp:
+                    fastopen_backlog: 128
+                    ipv6_only: true
+                    dscp: 46
+                    keepalive:
+                      idle_secs: 60
+                      interval_secs: 5
+                      count: 3
+                "#,
+            )?;
+
+            let options = load(&tmp_dir).unwrap().listeners.tcp.socket_options();
+            assert_eq!(options.tcp_fastopen, Some(128));
+            assert_eq!(options.ipv6_only, Some(true));
+            assert_eq!(options.dscp, Some(46));
+            let keepalive = options.tcp_keepalive.unwrap();
+            assert_eq!(keepalive.idle, Duration::from_secs(60));
+            assert_eq!(keepalive.count, 3);
+
+            // Options that cannot be applied are rejected
+            for tcp in [
+                "reuse_port: true",
+                "nodelay: false",
+                "backlog: 1024",
+                "dscp: 64",
+                "ipv6_only: true", // with the default IPv4 addresses
+            ] {
+                jail.create_file(
+                    format!("{}/proksi.yaml", tmp_dir),
+                    &format!(
+                        "lets_encrypt:\n  email: \"domain@valid.com\"\nlisteners:\n  tcp:\n    {tcp}\n"
+                    ),
+                )?;
+                assert!(load(&tmp_dir).is_err(), "{tcp} should be rejected");
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_proxy_protocol() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |proxy_protocol: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                listeners:
+                  proxy_protocol:
+                    {proxy_protocol}
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("https: true"))?;
+            let proxy_protocol = load(&tmp_dir).unwrap().listeners.proxy_protocol;
+            assert!(proxy_protocol.https && !proxy_protocol.http);
+            assert_eq!(proxy_protocol.internal_https_address, "127.0.0.1:10443");
+            assert_eq!(proxy_protocol.header_timeout_secs, 5);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ http: true, internal_http_address: \"0.0.0.0:10080\" }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("internal_http_address must be a loopback address"),
+                "{err}"
+            );
+
+            Ok(())
+        });
     }
 
     #[test]
-    fn test_load_config_from_yaml() {
+    fn test_load_config_with_retry_budget() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
+            let config = |retry_budget: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                retry_budget: {retry_budget}
+                "#
+                )
+            };
 
-            jail.create_file(format!("{}/proksi.yaml", tmp_dir), helper_config_file())?;
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ enabled: true, ratio: 0.2 }"),
+            )?;
+            let retry_budget = load(&tmp_dir).unwrap().retry_budget;
+            assert!(retry_budget.enabled);
+            assert_eq!(retry_budget.ratio, 0.2);
+            assert_eq!(retry_budget.min_retries_per_sec, 10);
+            assert_eq!(retry_budget.burst, 100);
 
-            let config = load(&tmp_dir);
-            let proxy_config = config.unwrap();
-            assert_eq!(proxy_config.service_name, "proksi");
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("{ enabled: true, ratio: 1.5 }"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("retry_budget.ratio must be between 0 and 1"),
+                "{err}"
+            );
 
             Ok(())
         });
     }
 
     #[test]
-    fn test_load_config_from_yaml_and_env_vars() {
+    fn test_load_config_with_acme_challenge_address() {
         figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |address: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                listeners:
+                  acme_challenge_address: "{address}"
+                "#
+                )
+            };
+
             jail.create_file(
-                format!("{}/proksi.yaml", jail.directory().to_str().unwrap()),
-                helper_config_file(),
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("127.0.0.1:8080"),
             )?;
-            jail.set_env("PROKSI_SERVICE_NAME", "new_name");
-            jail.set_env("PROKSI_LOGGING__ENABLED", "false");
-            jail.set_env("PROKSI_LOGGING__LEVEL", "warn");
-            jail.set_env("PROKSI_DOCKER__ENABLED", "true");
-            jail.set_env("PROKSI_DOCKER__INTERVAL_SECS", "30");
-            jail.set_env("PROKSI_DOCKER__ENDPOINT", "http://localhost:2375");
-            jail.set_env("PROKSI_LETS_ENCRYPT__STAGING", "false");
-            jail.set_env("PROKSI_LETS_ENCRYPT__EMAIL", "my-real-email@domain.com");
-            jail.set_env(
-                "PROKSI_ROUTES",
-                r#"[{
-              host="changed.example.com",
-              match_with={ path={ patterns=["/api/v1/:entity/:action*"] } },
-              plugins=[{ name="cors", config={ allowed_origins=["*"] } }],
-              upstreams=[{ ip="10.0.1.2/24", port=3000, weight=1 }] }]
-            "#,
+            let listeners = load(&tmp_dir).unwrap().listeners;
+            assert_eq!(
+                listeners.acme_challenge_address.as_deref(),
+                Some("127.0.0.1:8080")
+            );
+            assert_eq!(listeners.http_address, "0.0.0.0:80");
+            assert!(listeners.http_redirect);
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("0.0.0.0:80"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("acme_challenge_address must differ"), "{err}");
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("localhost"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("acme_challenge_address must be an IP address and port"),
+                "{err}"
             );
 
-            let config = load(jail.directory().to_str().unwrap());
+            Ok(())
+        });
+    }
 
-            let proxy_config = config.unwrap();
-            assert_eq!(proxy_config.service_name, "new_name");
-            assert!(!proxy_config.logging.enabled);
-            assert_eq!(proxy_config.logging.level, LogLevel::Warn);
+    #[test]
+    fn test_load_config_with_dns01_challenge() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |challenge: &str, dns01: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                  certificates:
+                    - domain: "*.example.com"
+                      challenge: {challenge}
+                  {dns01}
+                routes:
+                  - host: "api.example.com"
+                    ssl_certificate:
+                      challenge: dns01
+                    upstreams:
+                      - ip: "10.0.0.1"
+                        port: 3000
+                "#
+                )
+            };
+            let rfc2136 = r#"dns01:
+                    rfc2136:
+                      server: "10.0.0.53:53"
+                      zone: "example.com"
+                      key_name: "proksi"
+                      key_secret: "c2VjcmV0"
+                      key_algorithm: "hmac-sha512""#;
 
-            assert_eq!(proxy_config.docker.enabled, Some(true));
-            assert_eq!(proxy_config.docker.interval_secs, Some(30));
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("dns01", rfc2136),
+            )?;
+            let config_loaded = load(&tmp_dir).unwrap();
+            let lets_encrypt = &config_loaded.lets_encrypt;
+            assert_eq!(lets_encrypt.certificates[0].domain, "*.example.com");
+            assert_eq!(lets_encrypt.certificates[0].challenge, AcmeChallenge::Dns01);
+            let dns01 = lets_encrypt.dns01.as_ref().unwrap();
+            let rfc2136_loaded = dns01.rfc2136.as_ref().unwrap();
+            assert_eq!(rfc2136_loaded.key_algorithm, TsigAlgorithm::HmacSha512);
+            assert_eq!(dns01.propagation_timeout_secs, 120);
             assert_eq!(
-                proxy_config.docker.endpoint,
-                Some(Cow::Borrowed("http://localhost:2375"))
+                config_loaded.routes[0]
+                    .ssl_certificate
+                    .as_ref()
+                    .unwrap()
+                    .challenge,
+                Some(AcmeChallenge::Dns01)
             );
 
-            assert_eq!(proxy_config.lets_encrypt.staging, Some(false));
-            assert_eq!(proxy_config.lets_encrypt.email, "my-real-email@domain.com");
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("http01", rfc2136),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("its challenge must be dns01"), "{err}");
 
-            assert_eq!(proxy_config.routes[0].host, "changed.example.com");
-            assert_eq!(proxy_config.routes[0].upstreams[0].ip, "10.0.1.2/24");
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("dns01", ""))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("lets_encrypt.dns01 must be set"), "{err}");
 
-            let matcher = proxy_config.routes[0].match_with.as_ref().unwrap();
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config("dns01", "dns01: {}"),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("needs one provider"), "{err}");
 
-            assert_eq!(
-                matcher.path.as_ref().unwrap().patterns,
-                vec![Cow::Borrowed("/api/v1/:entity/:action*")]
-            );
+            Ok(())
+        });
+    }
 
-            assert_eq!(
-                proxy_config.paths.lets_encrypt,
-                PathBuf::from("/test/letsencrypt")
+    #[test]
+    fn test_load_config_with_metrics() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |bind: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                metrics:
+                  enabled: true
+                  bind: "{bind}"
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                "lets_encrypt:\n  email: \"domain@valid.com\"",
+            )?;
+            let config_loaded = load(&tmp_dir).unwrap();
+            assert!(!config_loaded.metrics.enabled);
+            assert_eq!(config_loaded.metrics.bind, "127.0.0.1:9090");
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("0.0.0.0:9100"))?;
+            let config_loaded = load(&tmp_dir).unwrap();
+            assert!(config_loaded.metrics.enabled);
+            assert_eq!(config_loaded.metrics.bind, "0.0.0.0:9100");
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("localhost"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("metrics.bind must be an IP address"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_route_headers() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |request_header: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    headers:
+                      add_response:
+                        - name: "X-Api-Version"
+                          value: "1.0"
+                      remove_response:
+                        - name: "Server"
+                      add_request:
+                        - name: "{request_header}"
+                          value: "internal.example.com"
+                      remove_request:
+                        - name: "X-Debug"
+                      forwarded: true
+                    upstreams:
+                      - ip: "10.0.0.1"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("Host"))?;
+            let config_loaded = load(&tmp_dir).unwrap();
+            let headers = config_loaded.routes[0].headers.as_ref().unwrap();
+            assert_eq!(headers.add.as_ref().unwrap()[0].name, "X-Api-Version");
+            assert_eq!(headers.remove.as_ref().unwrap()[0].name, "Server");
+            assert_eq!(headers.add_request.as_ref().unwrap()[0].name, "Host");
+            assert_eq!(headers.remove_request.as_ref().unwrap()[0].name, "X-Debug");
+            assert!(headers.forwarded);
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("Invalid Host"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0.headers.add_request0 is not a valid header"),
+                "{err}"
             );
+
             Ok(())
         });
     }
 
     #[test]
-    fn test_load_config_with_defaults_only() {
+    fn test_load_config_with_allowed_hosts() {
         figment::Jail::expect_with(|jail| {
-            jail.set_env("PROKSI_LETS_ENCRYPT__EMAIL", "my-real-email@domain.com");
-            let config = load("/non-existent");
-            let proxy_config = config.unwrap();
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |hosts: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                allowed_hosts: {hosts}
+                "#
+                )
+            };
 
-            let logging = proxy_config.logging;
-            assert_eq!(proxy_config.service_name, "proksi");
-            assert_eq!(logging.level, LogLevel::Info);
-            assert!(logging.access_logs_enabled);
-            assert!(logging.error_logs_enabled);
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"["example.com", "*.example.com"]"#),
+            )?;
+            let allowed_hosts = load(&tmp_dir).unwrap().allowed_hosts;
+            assert_eq!(allowed_hosts, ["example.com", "*.example.com"]);
+
+            for invalid in [r#"["*"]"#, r#"["api.*.example.com"]"#, r#"[""]"#] {
+                jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(invalid))?;
+                let err = load(&tmp_dir).unwrap_err().to_string();
+                assert!(
+                    err.contains("allowed_hosts contains an invalid host"),
+                    "{err}"
+                );
+            }
 
-            assert_eq!(proxy_config.routes.len(), 0);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_shutdown() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                shutdown:
+                  background_services_timeout_secs: 30
+                "#,
+            )?;
+
+            let shutdown = load(&tmp_dir).unwrap().shutdown;
+            assert_eq!(shutdown.background_services_timeout_secs, 30);
 
             Ok(())
-        })
+        });
     }
 
     #[test]
-    fn test_load_config_with_defaults_and_yaml() {
+    fn test_load_config_with_log_format() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |format: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                logging:
+                  level: "INFO"
+                  format: "{format}"
+                "#
+                )
+            };
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("text"))?;
+            assert_eq!(load(&tmp_dir).unwrap().logging.format, LogFormat::Pretty);
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("JSON"))?;
+            assert_eq!(load(&tmp_dir).unwrap().logging.format, LogFormat::Json);
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config("xml"))?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("expected one of: json, text, pretty"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_compression_algorithms() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
+            let config = |compression: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                compression: {compression}
+                "#
+                )
+            };
 
             jail.create_file(
                 format!("{}/proksi.yaml", tmp_dir),
-                r#"
+                &config(r#"{ algorithms: ["zstd", "brotli", "gzip"], zstd_level: 19 }"#),
+            )?;
+            let compression = load(&tmp_dir).unwrap().compression;
+            assert_eq!(
+                compression.algorithms,
+                [
+                    CompressionAlgorithm::Zstd,
+                    CompressionAlgorithm::Brotli,
+                    CompressionAlgorithm::Gzip
+                ]
+            );
+            assert_eq!(compression.zstd_level, 19);
+            assert_eq!(compression.level, 6);
+            assert_eq!(compression.min_length, 0);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ enabled: true, min_length: 1024 }"#),
+            )?;
+            let compression = load(&tmp_dir).unwrap().compression;
+            assert!(compression.enabled);
+            assert_eq!(compression.min_length, 1024);
+
+            for (compression, expected) in [
+                (
+                    r#"{ zstd_level: 23 }"#,
+                    "compression.zstd_level must be between 1 and 22",
+                ),
+                (
+                    r#"{ algorithms: [] }"#,
+                    "compression.algorithms cannot be empty",
+                ),
+            ] {
+                jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(compression))?;
+                let err = load(&tmp_dir).unwrap_err().to_string();
+                assert!(err.contains(expected), "{err}");
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_connection_reuse() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |connection_reuse: &str| {
+                format!(
+                    r#"
                 lets_encrypt:
                   email: "domain@valid.com"
                 routes:
                   - host: "example.com"
+                    connection_reuse: {connection_reuse}
                     upstreams:
-                      - ip: "10.1.2.24/24"
+                      - ip: "10.1.2.24"
                         port: 3000
-                    plugins:
-                      - name: "cors"
-                        config:
-                          allowed_origins: ["*"]
-                    ssl:
-                      path:
-                        key: "/etc/proksi/certs/my-host.key"
-                        pem: "/etc/proksi/certs/my-host.pem"
-                "#,
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ by: "header", header: "x-tenant-id" }"#),
             )?;
+            let reuse = load(&tmp_dir).unwrap().routes[0]
+                .connection_reuse
+                .clone()
+                .unwrap();
+            assert_eq!(reuse.by, RouteConnectionReuseBy::Header);
+            assert_eq!(reuse.header.as_deref(), Some("x-tenant-id"));
+
+            for (connection_reuse, expected) in [
+                (
+                    r#"{ by: "client_certificate" }"#,
+                    "connection_reuse.by client_certificate requires ssl.client_auth",
+                ),
+                (
+                    r#"{ by: "header" }"#,
+                    "connection_reuse.header must be a header name with by: header",
+                ),
+                (
+                    r#"{ by: "jwt:sub" }"#,
+                    "connection_reuse.by jwt:sub requires the oauth2 plugin or a jwt auth middleware",
+                ),
+            ] {
+                jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(connection_reuse))?;
+                let err = load(&tmp_dir).unwrap_err().to_string();
+                assert!(err.contains(expected), "{err}");
+            }
 
-            let config = load(&tmp_dir);
-            let proxy_config = config.unwrap();
-            let logging = proxy_config.logging;
-            let paths = proxy_config.paths;
-            let letsencrypt = proxy_config.lets_encrypt;
+            Ok(())
+        });
+    }
 
-            assert_eq!(proxy_config.service_name, "proksi");
-            assert_eq!(logging.level, LogLevel::Info);
-            assert!(logging.access_logs_enabled);
-            assert!(logging.error_logs_enabled);
-            assert_eq!(proxy_config.routes.len(), 1);
+    #[test]
+    fn test_load_config_with_auth() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |auth: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    auth: {auth}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
 
-            assert_eq!(proxy_config.docker.enabled, Some(false));
-            assert_eq!(proxy_config.docker.interval_secs, Some(15));
-            assert_eq!(
-                proxy_config.docker.endpoint,
-                Some(Cow::Borrowed("unix:///var/run/docker.sock"))
-            );
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"[
+                      { name: "api_key", config: { api_keys: ["key"] } },
+                      { name: "basic_auth", config: { user: "admin", pass: "secret" } }
+                    ]"#,
+                ),
+            )?;
+            let route = &load(&tmp_dir).unwrap().routes[0];
+            let names = route
+                .auth
+                .iter()
+                .map(|middleware| middleware.name.as_ref())
+                .collect::<Vec<_>>();
+            assert_eq!(names, ["api_key", "basic_auth"]);
 
-            assert_eq!(letsencrypt.email, "domain@valid.com");
-            assert_eq!(letsencrypt.enabled, Some(true));
-            assert_eq!(letsencrypt.staging, Some(true));
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(
+                    r#"[{ name: "basic_auth", config: { users: ["admin:$2y$05$abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0"] } }]"#,
+                ),
+            )?;
+            assert!(load(&tmp_dir).is_ok());
+
+            for (auth, expected) in [
+                (
+                    r#"[{ name: "digest" }]"#,
+                    "auth0.name must be one of basic_auth, api_key, jwt, oauth2",
+                ),
+                (
+                    r#"[{ name: "basic_auth", config: { user: "admin" } }]"#,
+                    "auth0.config.pass is required by basic_auth",
+                ),
+                (
+                    r#"[{ name: "api_key", config: { api_keys: [] } }]"#,
+                    "auth0.config.api_keys must be a list of keys",
+                ),
+                (
+                    r#"[{ name: "basic_auth", config: { users: ["admin:secret"] } }]"#,
+                    "auth0.config.users must be a list of <user>:<bcrypt hash>",
+                ),
+            ] {
+                jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(auth))?;
+                let err = load(&tmp_dir).unwrap_err().to_string();
+                assert!(err.contains(expected), "{err}");
+            }
 
-            assert_eq!(paths.lets_encrypt.as_os_str(), "/etc/proksi/letsencrypt");
+            Ok(())
+        });
+    }
 
-            let route = &proxy_config.routes[0];
-            let plugins = route.plugins.as_ref().unwrap();
-            let plugin_config = plugins[0].config.as_ref().unwrap();
-            assert_eq!(plugins[0].name, "cors");
-            assert_eq!(plugin_config.get("allowed_origins"), Some(&json!(["*"])));
+    #[test]
+    fn test_load_config_with_forward_auth() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |forward_auth: &str| {
+                format!(
+                    r#"
+                lets_encrypt:
+                  email: "domain@valid.com"
+                routes:
+                  - host: "example.com"
+                    forward_auth: {forward_auth}
+                    upstreams:
+                      - ip: "10.1.2.24"
+                        port: 3000
+                "#
+                )
+            };
 
-            let ssl = route.ssl.as_ref().unwrap();
-            let path = ssl.path.as_ref().unwrap();
-            assert!(ssl.self_signed_fallback);
-            assert_eq!(path.key.as_os_str(), "/etc/proksi/certs/my-host.key");
-            assert_eq!(path.pem.as_os_str(), "/etc/proksi/certs/my-host.pem");
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(r#"{ address: "http://auth:9000/verify", copy_headers: ["X-User"] }"#),
+            )?;
+            let forward_auth = load(&tmp_dir).unwrap().routes[0]
+                .forward_auth
+                .clone()
+                .unwrap();
+            assert_eq!(forward_auth.address, "http://auth:9000/verify");
+            assert_eq!(forward_auth.copy_headers, ["X-User"]);
+            assert_eq!(forward_auth.timeout_ms, 2000);
+            assert_eq!(forward_auth.cache_ttl_secs, 5);
+
+            for (forward_auth, expected) in [
+                (
+                    r#"{ address: "auth:9000" }"#,
+                    "forward_auth.address must be an http(s) URL",
+                ),
+                (
+                    r#"{ address: "http://auth:9000", timeout_ms: 0 }"#,
+                    "forward_auth.timeout_ms must be greater than 0",
+                ),
+                (
+                    r#"{ address: "http://auth:9000", copy_headers: ["X User"] }"#,
+                    "forward_auth.copy_headers0 is not a valid header name",
+                ),
+            ] {
+                jail.create_file(format!("{}/proksi.yaml", tmp_dir), &config(forward_auth))?;
+                let err = load(&tmp_dir).unwrap_err().to_string();
+                assert!(err.contains(expected), "{err}");
+            }
 
             Ok(())
         });
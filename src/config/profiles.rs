@@ -0,0 +1,203 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::anyhow;
+
+use super::{Config, MiddlewareProfile, Route, RouteHeader, RoutePlugin};
+
+/// Expands the middleware profiles referenced by each route into the route itself.
+/// Profiles are applied in the order they are listed (included profiles first)
+/// and the headers/plugins defined on the route are applied last, overriding
+/// plugins with the same name.
+pub fn expand(config: &mut Config) -> Result<(), anyhow::Error> {
+    for route in &mut config.routes {
+        let Some(names) = route.middleware_profiles.clone() else {
+            continue;
+        };
+
+        let mut resolved = Vec::new();
+        for name in &names {
+            resolve(
+                &config.middleware_profiles,
+                name,
+                &mut Vec::new(),
+                &mut resolved,
+            )
+            .map_err(|err| anyhow!("route {}: {err}", route.host))?;
+        }
+
+        apply(route, &resolved);
+    }
+
+    Ok(())
+}
+
+/// Appends the given profile (after the profiles it includes) to `resolved`.
+/// `stack` holds the chain of profiles being resolved, to detect cycles.
+fn resolve<'a>(
+    profiles: &'a HashMap<Cow<'static, str>, MiddlewareProfile>,
+    name: &'a str,
+    stack: &mut Vec<&'a str>,
+    resolved: &mut Vec<&'a MiddlewareProfile>,
+) -> Result<(), anyhow::Error> {
+    if stack.contains(&name) {
+        stack.push(name);
+        return Err(anyhow!(
+            "circular middleware profile reference: {}",
+            stack.join(" -> ")
+        ));
+    }
+
+    let profile = profiles
+        .get(name)
+        .ok_or_else(|| anyhow!("middleware profile {name} does not exist"))?;
+
+    // A profile included several times (ex: from two other profiles) is applied once
+    if resolved.iter().any(|p| std::ptr::eq(*p, profile)) {
+        return Ok(());
+    }
+
+    stack.push(name);
+    for include in &profile.include {
+        resolve(profiles, include, stack, resolved)?;
+    }
+    stack.pop();
+
+    resolved.push(profile);
+    Ok(())
+}
+
+fn apply(route: &mut Route, profiles: &[&MiddlewareProfile]) {
+    let mut headers = RouteHeader::default();
+    let mut plugins: Vec<RoutePlugin> = Vec::new();
+
+    let route_headers = route.headers.take();
+    let route_plugins = route.plugins.take();
+
+    let all_headers = profiles
+        .iter()
+        .filter_map(|p| p.headers.as_ref())
+        .chain(route_headers.as_ref());
+    for h in all_headers {
+        if let Some(add) = &h.add {
+            headers
+                .add
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(add);
+        }
+        if let Some(remove) = &h.remove {
+            headers
+                .remove
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(remove);
+        }
+        if let Some(add) = &h.add_request {
+            headers
+                .add_request
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(add);
+        }
+        if let Some(remove) = &h.remove_request {
+            headers
+                .remove_request
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(remove);
+        }
+        headers.forwarded |= h.forwarded;
+    }
+
+    let all_plugins = profiles
+        .iter()
+        .filter_map(|p| p.plugins.as_ref())
+        .chain(route_plugins.as_ref())
+        .flatten();
+    for plugin in all_plugins {
+        plugins.retain(|p| p.name != plugin.name);
+        plugins.push(plugin.clone());
+    }
+
+    if headers.add.is_some()
+        || headers.remove.is_some()
+        || headers.add_request.is_some()
+        || headers.remove_request.is_some()
+        || headers.forwarded
+    {
+        route.headers = Some(headers);
+    }
+    if !plugins.is_empty() {
+        route.plugins = Some(plugins);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(profiles: serde_json::Value, route: serde_json::Value) -> Config {
+        Config {
+            middleware_profiles: serde_json::from_value(profiles).unwrap(),
+            routes: vec![serde_json::from_value(route).unwrap()],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_expand_profiles() {
+        let mut config = config(
+            json!({
+                "security": {
+                    "headers": { "add": [{ "name": "x-frame-options", "value": "DENY" }] }
+                },
+                "cors": {
+                    "include": ["security"],
+                    "plugins": [{ "name": "cors", "config": { "origins": ["*"] } }]
+                }
+            }),
+            json!({
+                "host": "example.com",
+                "upstreams": [],
+                "middleware_profiles": ["cors", "security"],
+                "headers": { "remove": [{ "name": "server" }] },
+                "plugins": [{ "name": "cors", "config": { "origins": ["example.com"] } }]
+            }),
+        );
+
+        expand(&mut config).unwrap();
+
+        let route = &config.routes[0];
+        let headers = route.headers.as_ref().unwrap();
+        // The security profile is only applied once
+        assert_eq!(headers.add.as_ref().unwrap().len(), 1);
+        assert_eq!(headers.remove.as_ref().unwrap()[0].name, "server");
+
+        // The plugin of the route overrides the one of the profile
+        let plugins = route.plugins.as_ref().unwrap();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(
+            plugins[0].config.as_ref().unwrap()["origins"],
+            json!(["example.com"])
+        );
+    }
+
+    #[test]
+    fn test_expand_detects_cycles_and_unknown_profiles() {
+        let profiles = json!({
+            "a": { "include": ["b"] },
+            "b": { "include": ["a"] }
+        });
+
+        let mut cyclic = config(
+            profiles.clone(),
+            json!({ "host": "example.com", "upstreams": [], "middleware_profiles": ["a"] }),
+        );
+        let err = expand(&mut cyclic).unwrap_err().to_string();
+        assert!(err.contains("a -> b -> a"), "{err}");
+
+        let mut unknown = config(
+            profiles,
+            json!({ "host": "example.com", "upstreams": [], "middleware_profiles": ["c"] }),
+        );
+        assert!(expand(&mut unknown).is_err());
+    }
+}
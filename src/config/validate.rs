@@ -1,6 +1,1175 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, Ipv6Addr},
+    panic::{self, AssertUnwindSafe},
+};
+
 use anyhow::anyhow;
+use path_tree::PathTree;
+
+use crate::{
+    plugins::{
+        auth,
+        rate_limit::{RateLimitRules, RouteRateLimiter},
+    },
+    proxy_server::{matching::route_key, request_buffer},
+};
+
+use super::{
+    AcmeChallenge, BlockedPath, Config, Limits, Proxy, RetryOn, Route, RouteConnectionReuseBy,
+    RouteHealthCheckType, RouteOverflow, RouteSticky, RouteStickyBy, RouteUpstreamProtocol,
+    StreamProtocol, TcpListenerOptions, UpstreamScheme, UserAgentPattern,
+};
+
+/// Highest number of streams an HTTP/2 connection can carry at once
+const H2_MAX_STREAMS: usize = (1 << 31) - 1;
+
+/// Validates what the routes need to serve requests, reporting the problems of
+/// every route at once: a host, upstreams (unless `dynamic_upstream` picks
+/// them) with a valid address, and path patterns
+fn check_route_targets(config: &Config) -> Result<(), anyhow::Error> {
+    let mut problems = Vec::new();
+    for (route_index, route) in config.routes.iter().enumerate() {
+        if route.host.trim().is_empty() {
+            problems.push(format!("routes{route_index}.host cannot be empty"));
+        }
+
+        if route.upstreams.is_empty() && route.dynamic_upstream.is_none() {
+            problems.push(format!("routes{route_index}.upstreams cannot be empty"));
+        }
+
+        for (upstream_index, upstream) in route.upstreams.iter().enumerate() {
+            if !is_upstream_address(&upstream.ip) {
+                problems.push(format!(
+                    "routes{route_index}.upstreams{upstream_index}.ip must be an IP address (IPv6 in brackets) or a host name, not {:?}",
+                    upstream.ip
+                ));
+            }
+
+            if upstream.port == 0 {
+                problems.push(format!(
+                    "routes{route_index}.upstreams{upstream_index}.port must be greater than 0"
+                ));
+            }
+        }
+
+        let patterns = route
+            .match_with
+            .as_ref()
+            .and_then(|matcher| matcher.path.as_ref())
+            .map(|path| path.patterns.as_slice())
+            .unwrap_or_default();
+        // Inserted into a tree as the route store does: a pattern of the same
+        // shape as an earlier one (parameter names aside) replaces it
+        let mut tree = PathTree::new();
+        let mut inserted = Vec::new();
+        for (index, pattern) in patterns.iter().enumerate() {
+            if !pattern.starts_with('/') {
+                problems.push(format!(
+                    "routes{route_index}.match_with.path.patterns{index} must start with /: {pattern}"
+                ));
+                continue;
+            }
+
+            match panic::catch_unwind(AssertUnwindSafe(|| tree.insert(pattern, index))) {
+                Err(_) => problems.push(format!(
+                    "routes{route_index}.match_with.path.patterns{index} is not a valid path pattern: {pattern}"
+                )),
+                Ok(id) => match inserted.get(id) {
+                    Some(earlier) => problems.push(format!(
+                        "routes{route_index}.match_with.path.patterns{index} matches the same paths as patterns{earlier}: {pattern}"
+                    )),
+                    None => inserted.push(index),
+                },
+            }
+        }
+    }
+
+    match problems.as_slice() {
+        [] => Ok(()),
+        [problem] => Err(anyhow!("{problem}")),
+        _ => Err(anyhow!(
+            "{} problems in the routes:\n- {}",
+            problems.len(),
+            problems.join("\n- ")
+        )),
+    }
+}
+
+/// Whether the address of an upstream can be resolved: an IP (IPv6 ones in
+/// brackets) or a host name (ex: the name of a docker service)
+fn is_upstream_address(ip: &str) -> bool {
+    if let Some(ipv6) = ip.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+        return ipv6.parse::<Ipv6Addr>().is_ok();
+    }
+
+    if ip.parse::<Ipv4Addr>().is_ok() {
+        return true;
+    }
+
+    // All-numeric labels are a mistyped IPv4 (ex: 10.0.0.300)
+    let name = ip.strip_suffix('.').unwrap_or(ip);
+    let labels = name.split('.').collect::<Vec<_>>();
+    let numeric = labels
+        .iter()
+        .all(|label| label.bytes().all(|b| b.is_ascii_digit()));
+    let valid_labels = labels.iter().all(|label| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    });
+
+    !numeric && valid_labels && name.len() <= 253
+}
+
+/// Validates the host of a route: a host, a leading wildcard (`*.example.com`)
+/// or a regex (`~<regex>`)
+fn check_host(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    if let Some(pattern) = route.host.strip_prefix('~') {
+        if let Err(err) = regex::Regex::new(pattern) {
+            return Err(anyhow!(
+                "routes{route_index}.host is an invalid regex: {err}"
+            ));
+        }
+        return Ok(());
+    }
+
+    let domain = route.host.strip_prefix("*.").unwrap_or(&route.host);
+    if domain.contains('*') || (domain.is_empty() && route.host.starts_with('*')) {
+        return Err(anyhow!(
+            "routes{route_index}.host can only have a leading wildcard (ex: *.example.com)"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the shadow upstream of a route and its sampling
+fn check_mirror(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(mirror) = &route.mirror else {
+        return Ok(());
+    };
+
+    let valid_url = mirror.upstream.parse::<http::Uri>().is_ok_and(|uri| {
+        matches!(uri.scheme_str(), Some("http" | "https")) && uri.authority().is_some()
+    });
+    if !valid_url {
+        return Err(anyhow!(
+            "routes{}.mirror.upstream must be an http(s) URL (ex: http://10.0.1.1:3000)",
+            route_index
+        ));
+    }
+
+    if !(0.0..=100.0).contains(&mirror.percentage) {
+        return Err(anyhow!(
+            "routes{}.mirror.percentage must be between 0 and 100",
+            route_index
+        ));
+    }
+
+    if mirror.timeout_ms == 0 {
+        return Err(anyhow!(
+            "routes{}.mirror.timeout_ms must be greater than 0",
+            route_index
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the authentication server of a route and the headers it sets
+fn check_forward_auth(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(forward_auth) = &route.forward_auth else {
+        return Ok(());
+    };
+
+    let valid_url = forward_auth.address.parse::<http::Uri>().is_ok_and(|uri| {
+        matches!(uri.scheme_str(), Some("http" | "https")) && uri.authority().is_some()
+    });
+    if !valid_url {
+        return Err(anyhow!(
+            "routes{route_index}.forward_auth.address must be an http(s) URL (ex: http://auth:9000/verify)"
+        ));
+    }
+
+    if forward_auth.timeout_ms == 0 {
+        return Err(anyhow!(
+            "routes{route_index}.forward_auth.timeout_ms must be greater than 0"
+        ));
+    }
+
+    for (header_index, name) in forward_auth.copy_headers.iter().enumerate() {
+        if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(anyhow!(
+                "routes{route_index}.forward_auth.copy_headers{header_index} is not a valid header name"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the names and values of the headers a route adds and removes
+fn check_route_headers(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(headers) = &route.headers else {
+        return Ok(());
+    };
+
+    let added = [("add", &headers.add), ("add_request", &headers.add_request)];
+    for (field, added) in added {
+        for (header_index, header) in added.iter().flatten().enumerate() {
+            if http::HeaderName::from_bytes(header.name.as_bytes()).is_err()
+                || http::HeaderValue::from_str(&header.value).is_err()
+            {
+                return Err(anyhow!(
+                    "routes{route_index}.headers.{field}{header_index} is not a valid header"
+                ));
+            }
+        }
+    }
+
+    let removed = [
+        ("remove", &headers.remove),
+        ("remove_request", &headers.remove_request),
+    ];
+    for (field, removed) in removed {
+        for (header_index, header) in removed.iter().flatten().enumerate() {
+            if http::HeaderName::from_bytes(header.name.as_bytes()).is_err() {
+                return Err(anyhow!(
+                    "routes{route_index}.headers.{field}{header_index} is not a valid header name"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates that the requests of a route carry the identity their connections
+/// to the upstreams are isolated by
+fn check_connection_reuse(
+    route: &Route,
+    route_index: usize,
+    has_jwt: bool,
+) -> Result<(), anyhow::Error> {
+    let Some(reuse) = &route.connection_reuse else {
+        return Ok(());
+    };
+
+    match reuse.by {
+        RouteConnectionReuseBy::Shared => {}
+        RouteConnectionReuseBy::ClientCertificate => {
+            if !route
+                .ssl
+                .as_ref()
+                .is_some_and(|ssl| ssl.client_auth.is_some())
+            {
+                return Err(anyhow!(
+                    "routes{route_index}.connection_reuse.by client_certificate requires ssl.client_auth"
+                ));
+            }
+        }
+        RouteConnectionReuseBy::Header => {
+            let valid = reuse
+                .header
+                .as_ref()
+                .is_some_and(|name| http::HeaderName::from_bytes(name.as_bytes()).is_ok());
+            if !valid {
+                return Err(anyhow!(
+                    "routes{route_index}.connection_reuse.header must be a header name with by: header"
+                ));
+            }
+        }
+        RouteConnectionReuseBy::JwtSub => {
+            if !has_jwt {
+                return Err(anyhow!(
+                    "routes{route_index}.connection_reuse.by jwt:sub requires the oauth2 plugin or a jwt auth middleware"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the frequency, thresholds and request of the health check of a route
+fn check_health_check(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(health_check) = &route.health_check else {
+        return Ok(());
+    };
+
+    if health_check.interval_secs == 0 {
+        return Err(anyhow!(
+            "routes{route_index}.health_check.interval_secs must be greater than 0"
+        ));
+    }
+
+    if health_check.consecutive_failures == 0 {
+        return Err(anyhow!(
+            "routes{route_index}.health_check.consecutive_failures must be greater than 0"
+        ));
+    }
+
+    if health_check.consecutive_successes == 0 {
+        return Err(anyhow!(
+            "routes{route_index}.health_check.consecutive_successes must be greater than 0"
+        ));
+    }
+
+    if health_check.check_type == RouteHealthCheckType::Http {
+        if !health_check.path.starts_with('/')
+            || health_check
+                .path
+                .parse::<http::uri::PathAndQuery>()
+                .is_err()
+        {
+            return Err(anyhow!(
+                "routes{route_index}.health_check.path must start with / (ex: /healthz)"
+            ));
+        }
+
+        if !(100..=599).contains(&health_check.expected_status) {
+            return Err(anyhow!(
+                "routes{route_index}.health_check.expected_status must be between 100 and 599"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the status and the rules of the blocked paths of a route
+fn check_blocked_paths(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(blocked_paths) = &route.blocked_paths else {
+        return Ok(());
+    };
+
+    if ![403, 404].contains(&blocked_paths.status) {
+        return Err(anyhow!(
+            "routes{route_index}.blocked_paths.status must be 403 or 404"
+        ));
+    }
+
+    for (index, rule) in blocked_paths.paths.iter().enumerate() {
+        match rule {
+            BlockedPath::Exact(path) | BlockedPath::Prefix(path) if !path.starts_with('/') => {
+                return Err(anyhow!(
+                    "routes{route_index}.blocked_paths.paths{index} must start with /"
+                ));
+            }
+            BlockedPath::Regex(pattern) => {
+                if let Err(err) = regex::Regex::new(pattern) {
+                    return Err(anyhow!(
+                        "routes{route_index}.blocked_paths.paths{index} is an invalid regex: {err}"
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the patterns of the blocked and allowed agents of a route
+fn check_user_agent(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(user_agent) = &route.user_agent else {
+        return Ok(());
+    };
+
+    let lists = [("block", &user_agent.block), ("allow", &user_agent.allow)];
+    for (name, patterns) in lists {
+        for (index, pattern) in patterns.iter().enumerate() {
+            match pattern {
+                UserAgentPattern::Contains(value) if value.is_empty() => {
+                    return Err(anyhow!(
+                        "routes{route_index}.user_agent.{name}{index} must not be empty"
+                    ));
+                }
+                UserAgentPattern::Regex(pattern) => {
+                    if let Err(err) = regex::Regex::new(pattern) {
+                        return Err(anyhow!(
+                            "routes{route_index}.user_agent.{name}{index} is an invalid regex: {err}"
+                        ));
+                    }
+                }
+                UserAgentPattern::Contains(_) => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the rules of the `rate_limit` plugin of a route, which are
+/// otherwise only compiled once the route is added
+fn check_rate_limit_plugin(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let plugins = route.plugins.iter().flatten().enumerate();
+    for (index, plugin) in plugins.filter(|(_, plugin)| plugin.name == "rate_limit") {
+        if let Err(err) = RateLimitRules::new(plugin) {
+            return Err(anyhow!("routes{route_index}.plugins{index}.config.{err}"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the rate, the key and the response of the rate limit of a route
+fn check_rate_limit(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(rate_limit) = &route.rate_limit else {
+        return Ok(());
+    };
+
+    if !rate_limit.requests_per_second.is_finite() || rate_limit.requests_per_second <= 0.0 {
+        return Err(anyhow!(
+            "routes{route_index}.rate_limit.requests_per_second must be greater than 0"
+        ));
+    }
+
+    if rate_limit.burst == Some(0) {
+        return Err(anyhow!(
+            "routes{route_index}.rate_limit.burst must be greater than 0"
+        ));
+    }
+
+    let valid_key = match rate_limit.key.split_once(':') {
+        Some((kind, name)) => {
+            kind.eq_ignore_ascii_case("header")
+                && http::HeaderName::from_bytes(name.trim().as_bytes()).is_ok()
+        }
+        None => rate_limit.key.eq_ignore_ascii_case("ip"),
+    };
+    if !valid_key {
+        return Err(anyhow!(
+            "routes{route_index}.rate_limit.key must be ip or header:<name> (ex: header:X-Api-Key)"
+        ));
+    }
+
+    if let Err(err) = RouteRateLimiter::new(rate_limit) {
+        return Err(anyhow!("routes{route_index}.rate_limit.{err}"));
+    }
+
+    Ok(())
+}
+
+/// Validates the request limits, which cannot go past the ones of the parser,
+/// and the handling of truncated responses
+fn check_limits(config: &Config) -> Result<(), anyhow::Error> {
+    let limits = &config.limits;
+    if !(1..=Limits::PARSER_MAX_HEADER_SIZE).contains(&limits.max_header_size) {
+        return Err(anyhow!(
+            "limits.max_header_size must be between 1 and {}",
+            Limits::PARSER_MAX_HEADER_SIZE
+        ));
+    }
+
+    if !(1..=Limits::PARSER_MAX_HEADERS).contains(&limits.max_headers) {
+        return Err(anyhow!(
+            "limits.max_headers must be between 1 and {}",
+            Limits::PARSER_MAX_HEADERS
+        ));
+    }
+
+    if limits.max_connections_per_ip == Some(0) {
+        return Err(anyhow!(
+            "limits.max_connections_per_ip must be greater than 0"
+        ));
+    }
+
+    // Every peer is a proxy with trust_hops, and the proxies are exempt
+    if limits.max_connections_per_ip.is_some()
+        && config.client_ip.trust_hops.is_some_and(|hops| hops > 0)
+    {
+        return Err(anyhow!(
+            "limits.max_connections_per_ip cannot be used with client_ip.trust_hops: \
+             every peer is then a proxy, exempt from the limit"
+        ));
+    }
+
+    if http::HeaderName::from_bytes(config.truncated_responses.trailer.as_bytes()).is_err() {
+        return Err(anyhow!(
+            "truncated_responses.trailer must be a valid header name"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates that each host is served by a single route: the routes of the store
+/// are keyed by host, whatever their matchers. Routes sharing a host need
+/// a single highest `priority`, the other ones being ignored.
+fn check_duplicate_hosts(config: &Config) -> Result<(), anyhow::Error> {
+    let mut by_host: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, route) in config.routes.iter().enumerate() {
+        by_host
+            .entry(route_key(&route.host).into_owned())
+            .or_default()
+            .push(index);
+    }
+
+    for (host, indexes) in by_host {
+        let Some(highest) = indexes.iter().map(|i| config.routes[*i].priority()).max() else {
+            continue;
+        };
+
+        let winners = indexes
+            .iter()
+            .filter(|i| config.routes[**i].priority() == highest)
+            .map(|i| format!("routes{i}"))
+            .collect::<Vec<_>>();
+
+        if winners.len() > 1 {
+            return Err(anyhow!(
+                "{} declare the same host {host}: merge them (using match_with) \
+                 or give the one to use a higher priority",
+                winners.join(" and ")
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the geo routing of a route: every pool serves upstreams of the route,
+/// a header value is served by a single pool and a default pool remains
+/// Validates the client certificate revocation lists and key policy of a route
+fn check_client_auth(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(client_auth) = route.ssl.as_ref().and_then(|ssl| ssl.client_auth.as_ref()) else {
+        return Ok(());
+    };
+
+    if let Some(key_policy) = &client_auth.key_policy {
+        if key_policy.algorithms.is_empty() {
+            return Err(anyhow!(
+                "routes{route_index}.ssl.client_auth.key_policy.algorithms must not be empty"
+            ));
+        }
+    }
+
+    let Some(crl) = &client_auth.crl else {
+        return Ok(());
+    };
+
+    if crl.paths.is_empty() && crl.urls.is_empty() {
+        return Err(anyhow!(
+            "routes{route_index}.ssl.client_auth.crl needs paths or urls"
+        ));
+    }
+
+    if crl.refresh_secs == 0 {
+        return Err(anyhow!(
+            "routes{route_index}.ssl.client_auth.crl.refresh_secs must be greater than 0"
+        ));
+    }
+
+    for url in &crl.urls {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(anyhow!(
+                "routes{route_index}.ssl.client_auth.crl.urls must be http(s) URLs: {url}"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the authentication middlewares of a route and their required settings
+fn check_auth(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    for (index, middleware) in route.auth.iter().enumerate() {
+        let config = middleware.config.as_ref();
+        let users = config.and_then(|config| config.get("users"));
+        let required: &[&str] = match middleware.name.as_ref() {
+            // The `users` replace the `user` and `pass`
+            "basic_auth" if users.is_some() => &[],
+            "basic_auth" => &["user", "pass"],
+            "api_key" => &["api_keys"],
+            "jwt" => &["secret"],
+            "oauth2" => &["provider", "client_id", "client_secret", "jwt_secret"],
+            name => {
+                return Err(anyhow!(
+                    "routes{route_index}.auth{index}.name must be one of {}, not {name}",
+                    auth::MIDDLEWARES.join(", ")
+                ));
+            }
+        };
+
+        for key in required {
+            if config.and_then(|config| config.get(*key)).is_none() {
+                return Err(anyhow!(
+                    "routes{route_index}.auth{index}.config.{key} is required by {}",
+                    middleware.name
+                ));
+            }
+        }
+
+        let api_keys = config.and_then(|config| config.get("api_keys"));
+        if middleware.name == "api_key"
+            && !api_keys
+                .and_then(serde_json::Value::as_array)
+                .is_some_and(|keys| !keys.is_empty() && keys.iter().all(|key| key.is_string()))
+        {
+            return Err(anyhow!(
+                "routes{route_index}.auth{index}.config.api_keys must be a list of keys"
+            ));
+        }
+
+        let valid_users = users.map_or(true, |users| {
+            users.as_array().is_some_and(|users| {
+                !users.is_empty() && users.iter().all(|user| is_bcrypt_user(user.as_str()))
+            })
+        });
+        if middleware.name == "basic_auth" && !valid_users {
+            return Err(anyhow!(
+                "routes{route_index}.auth{index}.config.users must be a list of <user>:<bcrypt hash>"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the entry is a user and the bcrypt hash of its password
+/// (ex: `admin:$2y$05$...`, as written by `htpasswd -nB`)
+fn is_bcrypt_user(entry: Option<&str>) -> bool {
+    let Some((user, hash)) = entry.and_then(|entry| entry.split_once(':')) else {
+        return false;
+    };
+
+    let prefixed = ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix));
+    !user.is_empty() && prefixed && hash.len() == 60
+}
+
+/// Validates the retries of the failed requests of a route
+fn check_retries(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(retries) = &route.retries else {
+        return Ok(());
+    };
+
+    if retries.attempts == 0 {
+        return Err(anyhow!(
+            "routes{route_index}.retries.attempts must be greater than 0"
+        ));
+    }
+
+    if retries.retry_on.is_empty() {
+        return Err(anyhow!(
+            "routes{route_index}.retries.retry_on cannot be empty"
+        ));
+    }
+
+    for retry_on in &retries.retry_on {
+        if let RetryOn::Status(status) = retry_on {
+            if !(500..=599).contains(status) {
+                return Err(anyhow!(
+                    "routes{route_index}.retries.retry_on must be connect_error or 5xx statuses: {status}"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the upstreams picked from the path of the requests of a route
+fn check_dynamic_upstream(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(dynamic) = &route.dynamic_upstream else {
+        return Ok(());
+    };
+
+    if dynamic.upstreams.is_empty() && dynamic.template.is_none() {
+        return Err(anyhow!(
+            "routes{route_index}.dynamic_upstream needs upstreams or a template"
+        ));
+    }
+
+    let is_address = |address: &str| {
+        address
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+    };
+
+    for (value, upstream) in &dynamic.upstreams {
+        if !is_address(upstream) {
+            return Err(anyhow!(
+                "routes{route_index}.dynamic_upstream.upstreams.{value} must be a host:port address"
+            ));
+        }
+    }
+
+    if let Some(template) = &dynamic.template {
+        if !template.contains("{segment}") || !is_address(template) {
+            return Err(anyhow!(
+                "routes{route_index}.dynamic_upstream.template must be a host:port address with {{segment}}"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the substitutions of the response bodies of a route
+fn check_substitutions(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let Some(response) = &route.response else {
+        return Ok(());
+    };
+
+    if response.substitute_max_bytes == 0 {
+        return Err(anyhow!(
+            "routes{route_index}.response.substitute_max_bytes must be greater than 0"
+        ));
+    }
+
+    for (index, rule) in response.substitute.iter().enumerate() {
+        if rule.from.is_empty() {
+            return Err(anyhow!(
+                "routes{route_index}.response.substitute{index}.from cannot be empty"
+            ));
+        }
+
+        if rule.regex {
+            if let Err(err) = regex::bytes::Regex::new(&rule.from) {
+                return Err(anyhow!(
+                    "routes{route_index}.response.substitute{index}.from is an invalid regex: {err}"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_status_map(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let mut mapped = HashSet::new();
+
+    for (index, mapping) in route.status_map.iter().enumerate() {
+        if !(100..=599).contains(&mapping.from) {
+            return Err(anyhow!(
+                "routes{route_index}.status_map{index}.from must be a status between 100 and 599"
+            ));
+        }
+
+        // Informational responses cannot replace a final one
+        if !(200..=599).contains(&mapping.to) {
+            return Err(anyhow!(
+                "routes{route_index}.status_map{index}.to must be a status between 200 and 599"
+            ));
+        }
+
+        if !mapped.insert(mapping.from) {
+            return Err(anyhow!(
+                "routes{route_index}.status_map maps {} more than once",
+                mapping.from
+            ));
+        }
+
+        let Some(location) = &mapping.location else {
+            continue;
+        };
+
+        if !(300..=399).contains(&mapping.to) {
+            return Err(anyhow!(
+                "routes{route_index}.status_map{index}.location needs a 3xx status"
+            ));
+        }
+
+        if location.is_empty() || http::HeaderValue::from_str(location).is_err() {
+            return Err(anyhow!(
+                "routes{route_index}.status_map{index}.location must be a valid header value"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_geo_routing(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let pools = route
+        .upstreams
+        .iter()
+        .filter_map(|u| u.pool.as_deref())
+        .collect::<HashSet<_>>();
+
+    let Some(geo) = &route.geo_routing else {
+        if !pools.is_empty() {
+            return Err(anyhow!(
+                "routes{route_index}.upstreams use a pool without geo_routing"
+            ));
+        }
+        return Ok(());
+    };
+
+    if http::HeaderName::from_bytes(geo.header.as_bytes()).is_err() {
+        return Err(anyhow!(
+            "routes{route_index}.geo_routing.header must be a valid header name"
+        ));
+    }
+
+    if route.upstreams.iter().all(|u| u.pool.is_some()) {
+        return Err(anyhow!(
+            "routes{route_index}.geo_routing needs upstreams without a pool (the default pool)"
+        ));
+    }
+
+    let mut served = HashMap::new();
+    for (pool, values) in &geo.pools {
+        if !pools.contains(pool.as_ref()) {
+            return Err(anyhow!(
+                "routes{route_index}.geo_routing.pools.{pool} has no upstream"
+            ));
+        }
+
+        for value in values {
+            let value = value.trim().to_ascii_uppercase();
+            if let Some(other) = served.insert(value.clone(), pool) {
+                return Err(anyhow!(
+                    "routes{route_index}.geo_routing serves {value} by both pools {other} and {pool}"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the path groups of the upstreams: patterns for each one, and
+/// upstreams without `match_with` serving the other paths
+fn check_path_groups(route: &Route, route_index: usize) -> Result<(), anyhow::Error> {
+    let mut grouped = 0;
+    for (upstream_index, upstream) in route.upstreams.iter().enumerate() {
+        let Some(match_with) = &upstream.match_with else {
+            continue;
+        };
+        if match_with
+            .path
+            .as_ref()
+            .map_or(true, |path| path.patterns.is_empty())
+        {
+            return Err(anyhow!(
+                "routes{route_index}.upstreams{upstream_index}.match_with.path.patterns must not be empty"
+            ));
+        }
+        grouped += 1;
+    }
+
+    if grouped > 0 && grouped == route.upstreams.len() {
+        return Err(anyhow!(
+            "routes{route_index}.upstreams need one without match_with (the default group)"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the ACME provider: an https directory URL, complete external
+/// account binding credentials and a challenge TTL longer than the orders
+fn check_acme_provider(config: &Config) -> Result<(), anyhow::Error> {
+    let lets_encrypt = &config.lets_encrypt;
+
+    if let Some(url) = lets_encrypt.directory_url.as_deref() {
+        let is_https = url
+            .parse::<http::Uri>()
+            .is_ok_and(|uri| uri.scheme_str() == Some("https") && uri.host().is_some());
+        if !is_https {
+            return Err(anyhow!("lets_encrypt.directory_url must be an https URL"));
+        }
+    }
+
+    // A challenge must outlive its order, or it is removed while still validated
+    if let Some(ttl) = lets_encrypt.challenge_ttl_secs {
+        if ttl < lets_encrypt.challenge_timeout_secs.unwrap_or(300) {
+            return Err(anyhow!(
+                "lets_encrypt.challenge_ttl_secs must be at least lets_encrypt.challenge_timeout_secs"
+            ));
+        }
+    }
+
+    match (&lets_encrypt.eab_kid, &lets_encrypt.eab_hmac_key) {
+        (Some(_), None) | (None, Some(_)) => Err(anyhow!(
+            "lets_encrypt.eab_kid and lets_encrypt.eab_hmac_key must be set together"
+        )),
+        (Some(_), Some(key))
+            if key.is_empty()
+                || !key
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'=') =>
+        {
+            Err(anyhow!(
+                "lets_encrypt.eab_hmac_key must be base64url encoded"
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Validates the challenges of the certificates: the wildcard ones need the
+/// DNS-01 challenge, which needs a single, complete DNS provider
+fn check_acme_challenges(config: &Config) -> Result<(), anyhow::Error> {
+    let lets_encrypt = &config.lets_encrypt;
+
+    let mut dns01 = false;
+    for (index, certificate) in lets_encrypt.certificates.iter().enumerate() {
+        if certificate.domain.trim_start_matches("*.").is_empty() {
+            return Err(anyhow!(
+                "lets_encrypt.certificates{index}.domain cannot be empty"
+            ));
+        }
+        if certificate.domain.starts_with("*.") && certificate.challenge != AcmeChallenge::Dns01 {
+            return Err(anyhow!(
+                "lets_encrypt.certificates{index} is a wildcard certificate, its challenge must be dns01"
+            ));
+        }
+        dns01 |= certificate.challenge == AcmeChallenge::Dns01;
+    }
+    dns01 |= config.routes.iter().any(|route| {
+        route
+            .ssl_certificate
+            .as_ref()
+            .is_some_and(|ssl| ssl.challenge == Some(AcmeChallenge::Dns01))
+    });
+
+    let Some(provider) = &lets_encrypt.dns01 else {
+        if dns01 {
+            return Err(anyhow!(
+                "lets_encrypt.dns01 must be set for the certificates with the dns01 challenge"
+            ));
+        }
+        return Ok(());
+    };
+
+    match (&provider.cloudflare, &provider.rfc2136) {
+        (Some(cloudflare), None) => {
+            if cloudflare.api_token.is_empty() {
+                return Err(anyhow!(
+                    "lets_encrypt.dns01.cloudflare.api_token cannot be empty"
+                ));
+            }
+        }
+        (None, Some(rfc2136)) => {
+            if rfc2136.server.is_empty() || rfc2136.zone.is_empty() || rfc2136.key_name.is_empty() {
+                return Err(anyhow!(
+                    "lets_encrypt.dns01.rfc2136 needs a server, a zone and a key_name"
+                ));
+            }
+            if !openssl::base64::decode_block(&rfc2136.key_secret).is_ok_and(|key| !key.is_empty())
+            {
+                return Err(anyhow!(
+                    "lets_encrypt.dns01.rfc2136.key_secret must be base64 encoded"
+                ));
+            }
+        }
+        _ => {
+            return Err(anyhow!(
+                "lets_encrypt.dns01 needs one provider: cloudflare or rfc2136"
+            ))
+        }
+    }
+
+    if provider.propagation_interval_secs == 0 {
+        return Err(anyhow!(
+            "lets_encrypt.dns01.propagation_interval_secs must be greater than 0"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the PROXY protocol listeners: Proksi binds their public address
+/// itself, and relays their connections to the services on loopback addresses
+fn check_proxy_protocol(config: &Config) -> Result<(), anyhow::Error> {
+    let proxy_protocol = &config.listeners.proxy_protocol;
+    let listeners = [
+        (
+            "http",
+            proxy_protocol.http,
+            &config.listeners.http_address,
+            &proxy_protocol.internal_http_address,
+        ),
+        (
+            "https",
+            proxy_protocol.https,
+            &config.listeners.https_address,
+            &proxy_protocol.internal_https_address,
+        ),
+    ];
+
+    for (name, enabled, address, internal) in listeners {
+        if !enabled {
+            continue;
+        }
+
+        if address.parse::<std::net::SocketAddr>().is_err() {
+            return Err(anyhow!(
+                "listeners.{name}_address must be an IP address and port with the PROXY protocol"
+            ));
+        }
+
+        if !internal
+            .parse::<std::net::SocketAddr>()
+            .is_ok_and(|addr| addr.ip().is_loopback())
+        {
+            return Err(anyhow!(
+                "listeners.proxy_protocol.internal_{name}_address must be a loopback address and port"
+            ));
+        }
+    }
+
+    if (proxy_protocol.http || proxy_protocol.https) && proxy_protocol.header_timeout_secs == 0 {
+        return Err(anyhow!(
+            "listeners.proxy_protocol.header_timeout_secs must be greater than 0"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the address of the ACME challenge listener, which cannot share
+/// the one of the other services
+fn check_acme_challenge_listener(config: &Config) -> Result<(), anyhow::Error> {
+    let listeners = &config.listeners;
+    let Some(address) = &listeners.acme_challenge_address else {
+        return Ok(());
+    };
+
+    if address.parse::<std::net::SocketAddr>().is_err() {
+        return Err(anyhow!(
+            "listeners.acme_challenge_address must be an IP address and port"
+        ));
+    }
+
+    if address == &listeners.http_address || address == &listeners.https_address {
+        return Err(anyhow!(
+            "listeners.acme_challenge_address must differ from the HTTP and HTTPS addresses"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the socket options of the TCP listeners, rejecting the ones
+/// that cannot be applied instead of silently ignoring them
+fn check_tcp_listener_options(config: &Config) -> Result<(), anyhow::Error> {
+    let tcp = &config.listeners.tcp;
+
+    if tcp.reuse_port == Some(true) {
+        return Err(anyhow!("listeners.tcp.reuse_port is not supported yet"));
+    }
+
+    if tcp.nodelay == Some(false) {
+        return Err(anyhow!(
+            "listeners.tcp.nodelay cannot be disabled, TCP_NODELAY is always set"
+        ));
+    }
+
+    if tcp
+        .backlog
+        .is_some_and(|b| b != TcpListenerOptions::BACKLOG)
+    {
+        return Err(anyhow!(
+            "listeners.tcp.backlog is fixed at {}, raise net.core.somaxconn (Linux) or kern.ipc.somaxconn (BSD/macOS) instead",
+            TcpListenerOptions::BACKLOG
+        ));
+    }
+
+    if tcp.fastopen_backlog == Some(0) {
+        return Err(anyhow!(
+            "listeners.tcp.fastopen_backlog must be greater than 0"
+        ));
+    }
+
+    if tcp.dscp.is_some_and(|dscp| dscp > 63) {
+        return Err(anyhow!("listeners.tcp.dscp must be between 0 and 63"));
+    }
+
+    if let Some(keepalive) = tcp.keepalive {
+        if keepalive.idle_secs == 0 || keepalive.interval_secs == 0 || keepalive.count == 0 {
+            return Err(anyhow!(
+                "listeners.tcp.keepalive values must be greater than 0"
+            ));
+        }
+    }
+
+    // IPV6_V6ONLY cannot be set on IPv4 sockets
+    if tcp.ipv6_only.is_some() {
+        let tcp_streams = config
+            .streams
+            .iter()
+            .filter(|s| s.protocol == StreamProtocol::Tcp)
+            .map(|s| &s.listen);
+        let listeners = [
+            &config.listeners.http_address,
+            &config.listeners.https_address,
+        ];
+
+        for address in listeners.into_iter().chain(tcp_streams) {
+            if address
+                .parse::<std::net::SocketAddr>()
+                .is_ok_and(|addr| addr.is_ipv4())
+            {
+                return Err(anyhow!(
+                    "listeners.tcp.ipv6_only cannot be used with the IPv4 listener {address}"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the allowed hosts, whose wildcards must be a whole first label
+fn check_allowed_hosts(config: &Config) -> Result<(), anyhow::Error> {
+    for host in &config.allowed_hosts {
+        let domain = host.strip_prefix("*.").unwrap_or(host);
+        if domain.is_empty() || domain.contains(['*', '/', ' ']) {
+            return Err(anyhow!(
+                "allowed_hosts contains an invalid host: {host} (ex: example.com or *.example.com)"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the admin API address, which must not be reachable
+/// from other hosts without a token
+fn check_admin(config: &Config) -> Result<(), anyhow::Error> {
+    let admin = &config.admin;
+    if !admin.enabled {
+        return Ok(());
+    }
+
+    let bind = admin
+        .bind
+        .parse::<std::net::SocketAddr>()
+        .map_err(|_| anyhow!("admin.bind must be an IP address and port (ex: 127.0.0.1:9091)"))?;
 
-use super::Config;
+    if admin.token.as_ref().is_some_and(|t| t.is_empty()) {
+        return Err(anyhow!("admin.token cannot be empty"));
+    }
+
+    if !bind.ip().is_loopback() && admin.token.is_none() {
+        return Err(anyhow!(
+            "admin.token is required when admin.bind is not a loopback address"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the address of the metrics
+fn check_metrics(config: &Config) -> Result<(), anyhow::Error> {
+    if config.metrics.enabled && config.metrics.bind.parse::<std::net::SocketAddr>().is_err() {
+        return Err(anyhow!(
+            "metrics.bind must be an IP address and port (ex: 127.0.0.1:9090)"
+        ));
+    }
+
+    Ok(())
+}
 
 /// given a Config struct, validate the values to ensure
 /// That we program won't panic when we try to use them
@@ -15,34 +1184,352 @@ pub fn check_config(config: &Config) -> Result<(), anyhow::Error> {
         return Err(anyhow!("docker.interval_secs must be greater than 0"));
     }
 
+    // Validate that the docker TLS files are either all set or none of them
+    let docker = &config.docker;
+    let tls_files = [&docker.tls_cert, &docker.tls_key, &docker.tls_ca];
+    if tls_files.iter().any(|v| v.is_some()) && !tls_files.iter().all(|v| v.is_some()) {
+        return Err(anyhow!(
+            "docker.tls_cert, docker.tls_key and docker.tls_ca must be set together"
+        ));
+    }
+
     // validate that the lets encrypt email does not contain @example or is empty
-    if config.lets_encrypt.email.contains("@example") || config.lets_encrypt.email.is_empty() {
+    // (only relevant when the lets encrypt service is enabled)
+    let lets_encrypt_enabled = config.lets_encrypt.enabled.unwrap_or(true);
+    if lets_encrypt_enabled
+        && (config.lets_encrypt.email.contains("@example") || config.lets_encrypt.email.is_empty())
+    {
         return Err(anyhow!(
             "lets_encrypt.email cannot be empty or an email from @example.com (the default value)"
         ));
     }
 
+    check_acme_provider(config)?;
+
+    check_acme_challenges(config)?;
+
+    check_duplicate_hosts(config)?;
+
+    check_allowed_hosts(config)?;
+
+    // Validate that the headers to strip are valid header names
+    for name in &config.headers.strip_response {
+        if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(anyhow!(
+                "headers.strip_response contains an invalid header name: {name}"
+            ));
+        }
+    }
+
+    // Validate that the denied methods are valid methods
+    for method in &config.methods.deny {
+        if http::Method::from_bytes(method.to_uppercase().as_bytes()).is_err() {
+            return Err(anyhow!("methods.deny contains an invalid method: {method}"));
+        }
+    }
+
+    if config
+        .local_zone
+        .as_ref()
+        .is_some_and(|zone| zone.is_empty())
+    {
+        return Err(anyhow!("local_zone cannot be empty"));
+    }
+
+    let timeouts = &config.timeouts;
+    for (name, value) in [
+        ("idle_secs", timeouts.idle_secs),
+        ("connect_ms", timeouts.connect_ms),
+        ("read_ms", timeouts.read_ms),
+        ("write_ms", timeouts.write_ms),
+        ("response_secs", timeouts.response_secs.unwrap_or(1)),
+    ] {
+        if value == 0 {
+            return Err(anyhow!("timeouts.{name} must be greater than 0"));
+        }
+    }
+
+    if let Some(cache_size) = config.tls.session_resumption.cache_size {
+        if cache_size == 0 || i32::try_from(cache_size).is_err() {
+            return Err(anyhow!(
+                "tls.session_resumption.cache_size must be between 1 and {}",
+                i32::MAX
+            ));
+        }
+    }
+
+    for (index, certificate) in config.tls.certificates.iter().enumerate() {
+        if certificate.host.trim_start_matches("*.").is_empty() {
+            return Err(anyhow!("tls.certificates{index}.host cannot be empty"));
+        }
+    }
+
+    if config.request.buffer_size > request_buffer::MAX_BUFFER_SIZE {
+        return Err(anyhow!(
+            "request.buffer_size cannot be more than {} (64 KiB)",
+            request_buffer::MAX_BUFFER_SIZE
+        ));
+    }
+
+    if !(Proxy::MIN_BUFFER_SIZE..=Proxy::MAX_BUFFER_SIZE).contains(&config.proxy.buffer_size) {
+        return Err(anyhow!(
+            "proxy.buffer_size must be between {} and {}",
+            Proxy::MIN_BUFFER_SIZE,
+            Proxy::MAX_BUFFER_SIZE
+        ));
+    }
+
+    if !(400..=599).contains(&config.no_upstream.status) {
+        return Err(anyhow!("no_upstream.status must be between 400 and 599"));
+    }
+
+    let retry_budget = &config.retry_budget;
+    if !(0.0..=1.0).contains(&retry_budget.ratio) {
+        return Err(anyhow!("retry_budget.ratio must be between 0 and 1"));
+    }
+
+    if retry_budget.burst == 0 {
+        return Err(anyhow!("retry_budget.burst must be greater than 0"));
+    }
+
+    check_tcp_listener_options(config)?;
+    check_proxy_protocol(config)?;
+    check_acme_challenge_listener(config)?;
+
+    check_limits(config)?;
+
+    check_admin(config)?;
+    check_metrics(config)?;
+
+    if !(1..=11).contains(&config.compression.level) {
+        return Err(anyhow!("compression.level must be between 1 and 11"));
+    }
+    if !(1..=22).contains(&config.compression.zstd_level) {
+        return Err(anyhow!("compression.zstd_level must be between 1 and 22"));
+    }
+    if config.compression.algorithms.is_empty() {
+        return Err(anyhow!("compression.algorithms cannot be empty"));
+    }
+
+    // Validate the sample rate of the access logs
+    if !(0.0..=1.0).contains(&config.tracing.sample_rate) {
+        return Err(anyhow!("tracing.sample_rate must be between 0.0 and 1.0"));
+    }
+
     // Validate that the lets_encrypt pathbuf is not an empty string
     if config.paths.lets_encrypt.as_os_str() == "" {
         return Err(anyhow!("paths.lets_encrypt cannot be empty"));
     }
 
     // Validate the routes
+    check_route_targets(config)?;
     for (route_index, route) in config.routes.iter().enumerate() {
+        check_host(route, route_index)?;
+
+        // Validate the route's compression level
+        let level = route.compression.as_ref().and_then(|c| c.level);
+        if level.is_some_and(|level| !(1..=11).contains(&level)) {
+            return Err(anyhow!(
+                "routes{}.compression.level must be between 1 and 11",
+                route_index
+            ));
+        }
+        let zstd_level = route.compression.as_ref().and_then(|c| c.zstd_level);
+        if zstd_level.is_some_and(|level| !(1..=22).contains(&level)) {
+            return Err(anyhow!(
+                "routes{route_index}.compression.zstd_level must be between 1 and 22"
+            ));
+        }
+        let algorithms = route
+            .compression
+            .as_ref()
+            .and_then(|c| c.algorithms.as_ref());
+        if algorithms.is_some_and(Vec::is_empty) {
+            return Err(anyhow!(
+                "routes{route_index}.compression.algorithms cannot be empty"
+            ));
+        }
+
+        // Validate the route's sample rate
+        let sample_rate = route.tracing.as_ref().and_then(|t| t.sample_rate);
+        if sample_rate.is_some_and(|rate| !(0.0..=1.0).contains(&rate)) {
+            return Err(anyhow!(
+                "routes{}.tracing.sample_rate must be between 0.0 and 1.0",
+                route_index
+            ));
+        }
+
+        let sample_rate = route.access_log.as_ref().and_then(|log| log.sample_rate);
+        if sample_rate.is_some_and(|rate| !(0.0..=1.0).contains(&rate)) {
+            return Err(anyhow!(
+                "routes{}.access_log.sample_rate must be between 0.0 and 1.0",
+                route_index
+            ));
+        }
+
+        // Validate how long coalesced cache misses wait for the first one
+        let cache = route.cache.as_ref();
+        if cache.is_some_and(|cache| cache.coalesce && cache.coalesce_timeout_secs == 0) {
+            return Err(anyhow!(
+                "routes{}.cache.coalesce_timeout_secs must be greater than 0",
+                route_index
+            ));
+        }
+
+        check_geo_routing(route, route_index)?;
+        check_path_groups(route, route_index)?;
+
+        // Validate the clients pinned to an upstream
+        let sticky = route.sticky.as_ref();
+        if sticky.is_some_and(|sticky| sticky.max_entries == 0) {
+            return Err(anyhow!(
+                "routes{}.sticky.max_entries must be greater than 0",
+                route_index
+            ));
+        }
+
+        // The subject comes from the JWT validated by the oauth2 plugin (or
+        // the jwt and oauth2 authentication middlewares)
+        let has_oauth2 = route
+            .plugins
+            .as_ref()
+            .is_some_and(|plugins| plugins.iter().any(|plugin| plugin.name == "oauth2"));
+        let has_jwt = route
+            .auth
+            .iter()
+            .any(|middleware| matches!(middleware.name.as_ref(), "jwt" | "oauth2"));
+        if sticky.is_some_and(|sticky| sticky.by == RouteStickyBy::JwtSub)
+            && !has_oauth2
+            && !has_jwt
+        {
+            return Err(anyhow!(
+                "routes{}.sticky.by jwt:sub requires the oauth2 plugin or a jwt auth middleware",
+                route_index
+            ));
+        }
+
+        check_connection_reuse(route, route_index, has_oauth2 || has_jwt)?;
+
+        if sticky.is_some_and(|sticky| {
+            !(1..=RouteSticky::MAX_VIRTUAL_NODES).contains(&sticky.virtual_nodes)
+        }) {
+            return Err(anyhow!(
+                "routes{}.sticky.virtual_nodes must be between 1 and {}",
+                route_index,
+                RouteSticky::MAX_VIRTUAL_NODES
+            ));
+        }
+
+        if let Some(timeouts) = &route.timeouts {
+            for (name, value) in [
+                ("response_secs", timeouts.response_secs),
+                ("connect_ms", timeouts.connect_ms),
+                ("read_ms", timeouts.read_ms),
+                ("write_ms", timeouts.write_ms),
+            ] {
+                if value == Some(0) {
+                    return Err(anyhow!(
+                        "routes{route_index}.timeouts.{name} must be greater than 0"
+                    ));
+                }
+            }
+        }
+
+        if route.warmup.is_some_and(|warmup| warmup.period_secs == 0) {
+            return Err(anyhow!(
+                "routes{}.warmup.period_secs must be greater than 0",
+                route_index
+            ));
+        }
+
+        check_dynamic_upstream(route, route_index)?;
+        check_auth(route, route_index)?;
+
+        if let Some(error_handling) = &route.error_handling {
+            if let Some(status) = error_handling
+                .intercept_statuses
+                .iter()
+                .find(|status| !(400..=599).contains(*status))
+            {
+                return Err(anyhow!(
+                    "routes{route_index}.error_handling.intercept_statuses must be 4xx or 5xx statuses: {status}"
+                ));
+            }
+        }
+
+        check_retries(route, route_index)?;
+
+        // Validate the bound of the requests proxied at once
+        if let Some(concurrency) = &route.concurrency {
+            if concurrency.max_concurrent_requests == 0 {
+                return Err(anyhow!(
+                    "routes{}.concurrency.max_concurrent_requests must be greater than 0",
+                    route_index
+                ));
+            }
+
+            if concurrency.overflow == RouteOverflow::Queue && concurrency.queue_timeout_ms == 0 {
+                return Err(anyhow!(
+                    "routes{}.concurrency.queue_timeout_ms must be greater than 0",
+                    route_index
+                ));
+            }
+        }
+
+        check_mirror(route, route_index)?;
+        check_health_check(route, route_index)?;
+        check_forward_auth(route, route_index)?;
+        check_route_headers(route, route_index)?;
+        check_blocked_paths(route, route_index)?;
+        check_user_agent(route, route_index)?;
+        check_rate_limit(route, route_index)?;
+        check_rate_limit_plugin(route, route_index)?;
+        check_substitutions(route, route_index)?;
+        check_status_map(route, route_index)?;
+        check_client_auth(route, route_index)?;
+
         // Validate the route's upstreams
         for (upstream_index, upstream) in route.upstreams.iter().enumerate() {
-            // Validate the upstream's address
-            if upstream.ip.is_empty() {
+            if upstream.max_connections == Some(0) {
                 return Err(anyhow!(
-                    "routes{}.upstreams{}.id cannot be empty",
+                    "routes{}.upstreams{}.max_connections must be greater than 0",
                     route_index,
                     upstream_index
                 ));
             }
 
-            if upstream.port == 0 {
+            let compression = upstream.request_compression.as_ref();
+            if compression.is_some_and(|compression| !(1..=9).contains(&compression.level)) {
+                return Err(anyhow!(
+                    "routes{}.upstreams{}.request_compression.level must be between 1 and 9",
+                    route_index,
+                    upstream_index
+                ));
+            }
+
+            // Stream counts are 31-bit integers (RFC 9113, section 5.1.1)
+            let max_streams = upstream.h2.map(|h2| h2.max_streams);
+            if max_streams.is_some_and(|streams| !(1..=H2_MAX_STREAMS).contains(&streams)) {
+                return Err(anyhow!(
+                    "routes{route_index}.upstreams{upstream_index}.h2.max_streams must be between 1 and {H2_MAX_STREAMS}"
+                ));
+            }
+
+            let health_check = upstream.health_check.as_ref();
+            if health_check.is_some_and(|target| target.address.is_none() && target.port.is_none())
+            {
+                return Err(anyhow!(
+                    "routes{}.upstreams{}.health_check must set an address or a port",
+                    route_index,
+                    upstream_index
+                ));
+            }
+
+            if upstream.scheme == Some(UpstreamScheme::H2c)
+                && upstream.protocol == RouteUpstreamProtocol::H1
+            {
                 return Err(anyhow!(
-                    "routes{}.upstreams{}.port must be greater than 0",
+                    "routes{}.upstreams{}.protocol cannot be h1 with the h2c scheme",
                     route_index,
                     upstream_index
                 ));
@@ -50,5 +1537,22 @@ pub fn check_config(config: &Config) -> Result<(), anyhow::Error> {
         }
     }
 
+    // Validate the streams
+    for (index, stream) in config.streams.iter().enumerate() {
+        if stream.listen.is_empty() {
+            return Err(anyhow!("streams{index}.listen cannot be empty"));
+        }
+
+        if stream.upstreams.is_empty() {
+            return Err(anyhow!("streams{index}.upstreams cannot be empty"));
+        }
+
+        if stream.udp_idle_timeout_secs == 0 {
+            return Err(anyhow!(
+                "streams{index}.udp_idle_timeout_secs must be greater than 0"
+            ));
+        }
+    }
+
     Ok(())
 }
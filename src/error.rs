@@ -0,0 +1,163 @@
+//! Errors returned by the modules of proksi, so callers (ex: the admin API)
+//! can tell the kind of a failure apart. `anyhow` is kept for the failures
+//! that are only logged, and at the boundary of the binary (`main`).
+
+use std::fmt;
+
+/// The kind of failure, holding the message of the failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The configuration could not be loaded or is invalid
+    Config(String),
+    /// An ACME operation (order, revocation) failed, or could not be started
+    Acme(AcmeFailure, String),
+    /// The upstreams of a route could not be resolved or balanced
+    Upstream(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// What the ACME operation failed on, for the ACME errors
+    pub fn acme_failure(&self) -> Option<AcmeFailure> {
+        match self {
+            Error::Acme(failure, _) => Some(*failure),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) | Error::Acme(_, message) | Error::Upstream(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+/// What an ACME operation failed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcmeFailure {
+    /// The provider rate limited the account
+    RateLimited,
+    /// The domain has no certificate issued through ACME
+    NotFound,
+    /// The provider could not be reached, or did not answer with a problem
+    Network,
+    /// A challenge of the order failed or timed out
+    Validation,
+    /// Anything else (ex: a DNS-01 challenge without DNS provider)
+    Other,
+}
+
+impl AcmeFailure {
+    /// The label of the failure in the metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AcmeFailure::RateLimited => "rate_limited",
+            AcmeFailure::NotFound => "not_found",
+            AcmeFailure::Network => "network",
+            AcmeFailure::Validation => "validation_failed",
+            AcmeFailure::Other => "other",
+        }
+    }
+}
+
+impl From<&acme_v2::Error> for AcmeFailure {
+    /// Classifies the errors of the ACME client by the problem type of the
+    /// provider (`urn:ietf:params:acme:error:<type>`). The client reports the
+    /// responses that are not a problem (and the transport errors) as `httpReqError`.
+    fn from(err: &acme_v2::Error) -> Self {
+        let acme_v2::Error::ApiProblem(problem) = err else {
+            return AcmeFailure::Other;
+        };
+
+        match problem._type.rsplit(':').next().unwrap_or_default() {
+            "rateLimited" => AcmeFailure::RateLimited,
+            "httpReqError"
+                if problem
+                    .detail
+                    .as_deref()
+                    .is_some_and(|detail| detail.starts_with("429 ")) =>
+            {
+                AcmeFailure::RateLimited
+            }
+            "httpReqError" => AcmeFailure::Network,
+            "unauthorized" | "incorrectResponse" | "connection" | "dns" | "caa" | "tls"
+            | "rejectedIdentifier" => AcmeFailure::Validation,
+            _ => AcmeFailure::Other,
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<figment::Error> for Error {
+    fn from(err: figment::Error) -> Self {
+        Error::Config(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_kinds() {
+        let err = Error::Upstream("could not resolve upstream-a:80".to_string());
+        assert!(matches!(err, Error::Upstream(_)));
+        assert_eq!(err.to_string(), "could not resolve upstream-a:80");
+        assert_eq!(err.acme_failure(), None);
+
+        // Converted at the boundary of the binary
+        let err: anyhow::Error = Error::Config("local_zone cannot be empty".to_string()).into();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::Config("local_zone cannot be empty".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_acme_failures() {
+        let failure = |problem_type: &str, detail: &str| {
+            AcmeFailure::from(&acme_v2::Error::ApiProblem(acme_v2::api::ApiProblem {
+                _type: problem_type.to_string(),
+                detail: Some(detail.to_string()),
+                subproblems: None,
+            }))
+        };
+
+        assert_eq!(
+            failure(
+                "urn:ietf:params:acme:error:rateLimited",
+                "too many certificates"
+            ),
+            AcmeFailure::RateLimited
+        );
+        assert_eq!(
+            failure("httpReqError", "429 Too Many Requests body: slow down"),
+            AcmeFailure::RateLimited
+        );
+        assert_eq!(
+            failure("httpReqError", "Transport error"),
+            AcmeFailure::Network
+        );
+        assert_eq!(
+            failure(
+                "urn:ietf:params:acme:error:unauthorized",
+                "Invalid response"
+            ),
+            AcmeFailure::Validation
+        );
+        assert_eq!(
+            failure("urn:ietf:params:acme:error:serverInternal", "try again"),
+            AcmeFailure::Other
+        );
+        assert_eq!(
+            AcmeFailure::from(&acme_v2::Error::Other("Certificate is empty".to_string())),
+            AcmeFailure::Other
+        );
+    }
+}
@@ -1,20 +1,31 @@
 use ::pingora::server::Server;
 
+use anyhow::{anyhow, Context};
 use bytes::Bytes;
-use clap::crate_version;
-use config::{load, LogFormat, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin};
+use config::{
+    load, Command, ConfigCommand, DumpFormat, LogFormat, Route, RouteHeaderAdd, RouteHeaderRemove,
+    RoutePlugin, RouteUpstream,
+};
 use tracing_subscriber::EnvFilter;
 
 use std::{borrow::Cow, sync::Arc};
 
-use pingora::{listeners::TlsSettings, proxy::http_proxy_service, server::configuration::Opt};
+use pingora::{
+    listeners::TlsSettings,
+    proxy::http_proxy_service,
+    server::configuration::{Opt, ServerConf},
+    tls::ssl::{SslOptions, SslSessionCacheMode},
+};
 
 use proxy_server::cert_store::CertStore;
 use services::{logger::ProxyLoggerReceiver, BackgroundFunctionService};
 
+mod build_info;
 mod cache;
 mod channel;
 mod config;
+mod error;
+mod metrics;
 mod plugins;
 mod proxy_server;
 mod server;
@@ -25,7 +36,7 @@ mod tools;
 #[derive(Clone, Default)]
 pub struct MsgRoute {
     host: Cow<'static, str>,
-    upstreams: Vec<String>,
+    upstreams: Vec<RouteUpstream>,
     path_matchers: Vec<String>,
     host_headers_add: Vec<RouteHeaderAdd>,
     host_headers_remove: Vec<RouteHeaderRemove>,
@@ -43,6 +54,10 @@ pub struct MsgCert {
 #[derive(Clone)]
 pub enum MsgProxy {
     NewRoute(MsgRoute),
+    /// A route of the configuration, added or changed by a reload
+    UpdatedRoute(Arc<Route>),
+    /// The host of a route removed from the configuration by a reload
+    RemovedRoute(String),
     NewCertificate(MsgCert),
     ConfigUpdate(()),
 }
@@ -61,13 +76,36 @@ fn main() -> Result<(), anyhow::Error> {
 
     // Loads configuration from command-line, YAML or TOML sources
     let proxy_config =
-        Arc::new(load("/etc/proksi/configs").expect("Failed to load configuration: "));
+        Arc::new(load("/etc/proksi/configs").context("Failed to load configuration")?);
+
+    match proxy_config.command {
+        Some(Command::Version) => {
+            println!("{}", build_info::summary());
+            return Ok(());
+        }
+        Some(Command::Config {
+            command: ConfigCommand::Dump { format },
+        }) => {
+            let config = services::admin::effective_config(&proxy_config);
+            let dump = match format {
+                DumpFormat::Yaml => serde_yaml::to_string(&config)?,
+                DumpFormat::Json => serde_json::to_string_pretty(&config)?,
+            };
+            println!("{dump}");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Client IP resolution (X-Forwarded-For trust) used by plugins and access logs
+    tools::client_ip::init(&proxy_config.client_ip)?;
 
     // Logging channel
     let (log_sender, log_receiver) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
 
-    // Receiver channel for Routes/Certificates/etc
-    let (sender, mut _receiver) = tokio::sync::broadcast::channel::<MsgProxy>(10);
+    // Receiver channel for Routes/Certificates/etc, large enough for the routes
+    // of a reload (or of a docker tick) sent at once
+    let (sender, mut _receiver) = tokio::sync::broadcast::channel::<MsgProxy>(1024);
     // let (appender, _guard) = get_non_blocking_writer(&proxy_config);
     let appender = services::logger::ProxyLog::new(
         log_sender,
@@ -93,42 +131,122 @@ fn main() -> Result<(), anyhow::Error> {
             .init();
     };
 
+    // Routes, listeners and services this instance starts with
+    server::summary::print_startup_summary(&proxy_config);
+
+    // `proksi upgrade` takes over the listening sockets of the running instance
+    let upgrading = proxy_config.command == Some(Command::Upgrade);
+
     // Pingora load balancer server
     let pingora_opts = Opt {
         daemon: proxy_config.daemon,
-        upgrade: proxy_config.upgrade,
+        upgrade: proxy_config.upgrade || upgrading,
         conf: None,
         nocapture: false,
         test: false,
     };
 
-    let mut pingora_server = Server::new(Some(pingora_opts))?;
+    let mut pingora_conf =
+        ServerConf::new().ok_or_else(|| anyhow!("failed to create the server configuration"))?;
+    pingora_conf.pid_file = proxy_config.paths.pid_file.to_string_lossy().to_string();
+    pingora_conf.upgrade_sock = proxy_config
+        .paths
+        .upgrade_sock
+        .to_string_lossy()
+        .to_string();
+
+    let mut pingora_server = Server::new_with_opt_and_conf(pingora_opts, pingora_conf);
+
+    let handoff = if upgrading {
+        server::upgrade::preload_routes(proxy_config.clone(), sender.clone())?;
+        Some(server::upgrade::request_handoff(
+            &proxy_config.paths.pid_file,
+            &proxy_config.paths.upgrade_sock,
+        )?)
+    } else {
+        None
+    };
+
+    // Receives the listening sockets from the running instance when upgrading
     pingora_server.bootstrap();
 
+    if let Some(handoff) = handoff {
+        handoff
+            .join()
+            .map_err(|_| anyhow!("failed to hand off the listening sockets"))??;
+    }
+
+    // The daemon writes its own pid file once forked
+    if !proxy_config.daemon {
+        server::upgrade::write_pid_file(&proxy_config.paths.pid_file)?;
+    }
+
     // Service: HTTP Load Balancer (only used by acme-challenges)
     // As we don't necessarily need an upstream to handle the acme-challenges,
     // we can use a simple mock LoadBalancer
     let mut http_public_service = http_proxy_service(
         &pingora_server.configuration,
-        proxy_server::http_proxy::HttpLB {},
+        proxy_server::http_proxy::HttpLB::public(&proxy_config),
     );
 
     // Service: HTTPS Load Balancer (main service)
     // The router will also handle health checks and failover in case of upstream failure
-    let router = proxy_server::https_proxy::Router {};
+    let router = proxy_server::https_proxy::Router::new(&proxy_config);
     let mut https_secure_service = http_proxy_service(&pingora_server.configuration, router);
-    http_public_service.add_tcp("0.0.0.0:80");
+    let tcp_options = proxy_config.listeners.tcp.socket_options();
+    // Behind a PROXY listener, the services listen on loopback addresses
+    let http_address =
+        proxy_server::proxy_protocol::service_address(&mut pingora_server, &proxy_config, false)?;
+    let https_address =
+        proxy_server::proxy_protocol::service_address(&mut pingora_server, &proxy_config, true)?;
+    http_public_service.add_tcp_with_settings(http_address, tcp_options.clone());
+
+    // Service: ACME challenges on their own listener, when configured
+    if let Some(address) = &proxy_config.listeners.acme_challenge_address {
+        let mut acme_challenge_service = http_proxy_service(
+            &pingora_server.configuration,
+            proxy_server::http_proxy::HttpLB::acme_challenges(&proxy_config),
+        );
+        acme_challenge_service.add_tcp_with_settings(address, tcp_options.clone());
+        pingora_server.add_service(acme_challenge_service);
+    }
 
     // Worker threads per configuration
     https_secure_service.threads = proxy_config.worker_threads;
 
+    // Client certificates of the mTLS routes, verified from the first handshake
+    proxy_server::client_auth::load_from_config(&proxy_config)?;
+
+    // Certificates provided as files, served instead of the ones of ACME
+    proxy_server::cert_store::load_from_config(&proxy_config)?;
+
     // Setup tls settings and Enable HTTP/2
-    let cert_store = CertStore::new();
+    let cert_store = CertStore::new(proxy_config.tls.fallback_cert);
     let mut tls_settings = TlsSettings::with_callbacks(Box::new(cert_store)).unwrap();
     tls_settings.enable_h2();
 
-    // tls_settings.set_session_cache_mode(SslSessionCacheMode::SERVER);
-    tls_settings.set_servername_callback(move |ssl_ref, _| CertStore::sni_callback(ssl_ref));
+    // Resumption of the sessions of returning clients. The ticket keys are
+    // generated by each instance at startup, they are not shared.
+    let resumption = &proxy_config.tls.session_resumption;
+    tls_settings.set_session_cache_mode(if resumption.cache {
+        SslSessionCacheMode::SERVER
+    } else {
+        SslSessionCacheMode::OFF
+    });
+    if let Some(size) = resumption.cache_size {
+        tls_settings.set_session_cache_size(i32::try_from(size).unwrap_or(i32::MAX));
+    }
+    if !resumption.tickets {
+        tls_settings.set_options(SslOptions::NO_TICKET);
+    }
+    if let Some(tickets) = resumption.tls13_tickets {
+        tls_settings.set_num_tickets(tickets)?;
+    }
+
+    let unknown_sni = proxy_config.tls.unknown_sni;
+    tls_settings
+        .set_servername_callback(move |ssl_ref, _| CertStore::sni_callback(ssl_ref, unknown_sni));
+    tls_settings.set_client_hello_callback(proxy_server::connections::client_hello_callback);
 
     // For now this is a hardcoded recommendation based on
     // https://developers.cloudflare.com/ssl/reference/protocols/
@@ -137,12 +255,16 @@ fn main() -> Result<(), anyhow::Error> {
     tls_settings.set_max_proto_version(Some(pingora::tls::ssl::SslVersion::TLS1_3))?;
 
     // Add TLS settings to the HTTPS service
-    https_secure_service.add_tls_with_settings("0.0.0.0:443", None, tls_settings);
+    https_secure_service.add_tls_with_settings(https_address, Some(tcp_options), tls_settings);
+
+    // TCP/UDP streams (port forwarding to health checked upstreams)
+    proxy_server::stream::add_services(&mut pingora_server, &proxy_config)?;
+
+    // Admin API (JSON, loopback by default)
+    services::admin::add_service(&mut pingora_server, &proxy_config);
 
-    // Add Prometheus service
-    // let mut prometheus_service_http = Service::prometheus_http_service();
-    // prometheus_service_http.add_tcp("0.0.0.0:9090");
-    // pingora_server.add_service(prometheus_service_http);
+    // Prometheus metrics (text, loopback by default)
+    services::metrics::add_service(&mut pingora_server, &proxy_config);
 
     // Non-dedicated background services
     pingora_server.add_service(BackgroundFunctionService::new(proxy_config.clone(), sender));
@@ -154,11 +276,5 @@ fn main() -> Result<(), anyhow::Error> {
     pingora_server.add_service(http_public_service);
     pingora_server.add_service(https_secure_service);
 
-    tracing::info!(
-        version = crate_version!(),
-        workers = proxy_config.worker_threads,
-        "running on :443 and :80"
-    );
-
     pingora_server.run_forever();
 }
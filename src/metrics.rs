@@ -0,0 +1,304 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+/// Amount of entries currently held by each in-memory store
+pub static STORE_ENTRIES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_store_entries",
+        "Number of entries held by an in-memory store",
+        &["store"]
+    )
+    .unwrap()
+});
+
+/// Amount of entries removed from each in-memory store by the eviction service
+pub static STORE_EVICTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_store_evictions_total",
+        "Number of entries evicted from an in-memory store",
+        &["store"]
+    )
+    .unwrap()
+});
+
+/// Amount of ACME orders (issuances and renewals) started
+pub static ACME_ORDERS_STARTED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "proksi_acme_orders_started_total",
+        "Number of ACME certificate orders started"
+    )
+    .unwrap()
+});
+
+/// Amount of ACME orders that ended with a certificate (issuances and renewals)
+pub static ACME_ORDERS_COMPLETED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "proksi_acme_orders_completed_total",
+        "Number of ACME certificate orders completed successfully"
+    )
+    .unwrap()
+});
+
+/// Amount of certificates successfully renewed
+pub static ACME_ORDERS_RENEWED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "proksi_acme_orders_renewed_total",
+        "Number of certificates renewed through ACME"
+    )
+    .unwrap()
+});
+
+/// Amount of ACME orders that failed, by coarse reason
+/// (`rate_limited`, `validation_failed`, `network` or `other`)
+pub static ACME_ORDERS_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_acme_orders_failed_total",
+        "Number of ACME certificate orders that failed",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Amount of ACME orders currently in progress
+pub static ACME_ORDERS_PENDING: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "proksi_acme_orders_pending",
+        "Number of ACME certificate orders in progress"
+    )
+    .unwrap()
+});
+
+/// Amount of proxied requests, by status class (ex: `2xx`), sampled or not
+pub static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_requests_total",
+        "Number of requests proxied by the HTTPS service",
+        &["status"]
+    )
+    .unwrap()
+});
+
+/// Amount of proxied requests, by route host and status class (ex: `2xx`)
+pub static HTTP_HOST_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_host_requests_total",
+        "Number of requests proxied by the HTTPS service, by route",
+        &["host", "status"]
+    )
+    .unwrap()
+});
+
+/// Amount of requests that were logged, by the reason they were sampled
+/// (`error`, `slow` or `rate`)
+pub static HTTP_REQUESTS_SAMPLED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_requests_sampled_total",
+        "Number of requests whose access log was emitted",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Amount of upstream responses that ended before they were complete, by route
+pub static HTTP_TRUNCATED_RESPONSES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_truncated_responses_total",
+        "Number of responses whose upstream failed after the response header was sent",
+        &["route"]
+    )
+    .unwrap()
+});
+
+/// Amount of upstream responses whose body did not match their
+/// `Content-Length`, by route and kind (`short`, `long` or `conflicting`)
+pub static UPSTREAM_BODY_LENGTH_MISMATCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_upstream_body_length_mismatches_total",
+        "Number of upstream responses whose body did not match their Content-Length",
+        &["route", "kind"]
+    )
+    .unwrap()
+});
+
+/// Amount of requests waiting for a slot of their route, by route
+/// (`concurrency.overflow: queue`)
+pub static HTTP_QUEUED_REQUESTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_http_queued_requests",
+        "Number of requests queued until their route is below its concurrency limit",
+        &["route"]
+    )
+    .unwrap()
+});
+
+/// Amount of connections in use to each upstream, by route and upstream address
+pub static UPSTREAM_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_upstream_connections",
+        "Number of connections in use to an upstream",
+        &["route", "upstream"]
+    )
+    .unwrap()
+});
+
+/// Amount of requests answered with a 503 because their route was at capacity,
+/// by reason (`rejected`, `queue_full` or `queue_timeout`)
+pub static HTTP_OVERFLOW_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_overflow_requests_total",
+        "Number of requests rejected because their route was at its concurrency limit",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Amount of requests copied to the shadow upstream of their route (`mirror`),
+/// by result (`sent` or `failed`)
+pub static HTTP_MIRRORED_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_mirrored_requests_total",
+        "Number of requests mirrored to a shadow upstream",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// Amount of retries of requests to the upstreams not made because the retry
+/// budget was spent, by route
+pub static HTTP_RETRIES_THROTTLED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_retries_throttled_total",
+        "Number of retries denied by the retry budget",
+        &["route"]
+    )
+    .unwrap()
+});
+
+/// Amount of requests sent again to another upstream (`retries`), by route
+/// and by what failed (`connect_error` or `status`)
+pub static HTTP_UPSTREAM_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_upstream_retries_total",
+        "Number of requests sent again to another upstream",
+        &["route", "reason"]
+    )
+    .unwrap()
+});
+
+/// Amount of health checks of the upstreams, by route, upstream address and
+/// result (`success` or `failure`)
+pub static HEALTH_CHECKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_health_checks_total",
+        "Number of health checks of an upstream",
+        &["route", "upstream", "result"]
+    )
+    .unwrap()
+});
+
+/// Amount of times each upstream turned healthy or unhealthy, by route and
+/// upstream address
+pub static HEALTH_CHECK_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_health_check_transitions_total",
+        "Number of times an upstream turned healthy or unhealthy",
+        &["route", "upstream"]
+    )
+    .unwrap()
+});
+
+/// When each upstream last turned healthy or unhealthy (unix timestamp, in
+/// seconds), by route and upstream address
+pub static HEALTH_CHECK_LAST_TRANSITION: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_health_check_last_transition_timestamp_seconds",
+        "When an upstream last turned healthy or unhealthy",
+        &["route", "upstream"]
+    )
+    .unwrap()
+});
+
+/// Amount of requests received, by listener (`http` or `https`)
+pub static LISTENER_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_listener_requests_total",
+        "Number of requests received by a listener",
+        &["listener"]
+    )
+    .unwrap()
+});
+
+/// Amount of connections accepted, by listener
+pub static LISTENER_CONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_listener_connections_total",
+        "Number of connections accepted by a listener",
+        &["listener"]
+    )
+    .unwrap()
+});
+
+/// Amount of connections currently open, by listener
+pub static LISTENER_ACTIVE_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_listener_active_connections",
+        "Number of connections currently open on a listener",
+        &["listener"]
+    )
+    .unwrap()
+});
+
+/// Amount of connections refused by `limits.max_connections_per_ip`, by listener
+pub static CONNECTIONS_PER_IP_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_listener_connections_per_ip_rejected_total",
+        "Number of connections refused for going over the limit of their client IP",
+        &["listener"]
+    )
+    .unwrap()
+});
+
+/// Amount of TLS handshakes, by result (`completed` or `failed`)
+pub static TLS_HANDSHAKES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_tls_handshakes_total",
+        "Number of TLS handshakes of the HTTPS listener",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// Amount of failed TLS handshakes, by reason (see `connections::HandshakeFailure`)
+pub static TLS_HANDSHAKE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_tls_handshake_failures_total",
+        "Number of failed TLS handshakes of the HTTPS listener, by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Whether each upstream is healthy (1) or not (0), as its load balancer sees
+/// it, by route and upstream address. Refreshed on every scrape.
+pub static UPSTREAM_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_upstream_healthy",
+        "Whether an upstream is healthy (1) or not (0)",
+        &["route", "upstream"]
+    )
+    .unwrap()
+});
+
+/// Days until each certificate of the store expires (negative once expired),
+/// by host. Refreshed on every scrape.
+pub static CERTIFICATE_EXPIRY_DAYS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_certificate_expiry_days",
+        "Number of days until a certificate expires",
+        &["host"]
+    )
+    .unwrap()
+});
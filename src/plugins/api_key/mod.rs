@@ -0,0 +1,112 @@
+use async_trait::async_trait;
+use openssl::memcmp;
+use pingora::http::RequestHeader;
+
+use crate::config::RoutePlugin;
+
+use super::auth::{AuthMiddleware, AuthResult};
+
+/// Header carrying the key when the configuration sets none
+const DEFAULT_HEADER: &str = "x-api-key";
+
+/// Authenticates the requests carrying one of the configured keys (`api_keys`)
+/// in a header (`header`, default: `x-api-key`)
+pub struct ApiKey;
+
+impl ApiKey {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /// Whether the key is one of the keys, compared in constant time
+    fn is_valid(key: &[u8], keys: &[serde_json::Value]) -> bool {
+        keys.iter()
+            .filter_map(serde_json::Value::as_str)
+            .any(|valid| valid.len() == key.len() && memcmp::eq(valid.as_bytes(), key))
+    }
+}
+
+#[async_trait]
+impl AuthMiddleware for ApiKey {
+    async fn authenticate(
+        &self,
+        request: &RequestHeader,
+        _host: &str,
+        plugin: &RoutePlugin,
+    ) -> anyhow::Result<AuthResult> {
+        let config = plugin.config.as_ref();
+        let header = config
+            .and_then(|config| config.get("header"))
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or(DEFAULT_HEADER);
+        let keys = config
+            .and_then(|config| config.get("api_keys"))
+            .and_then(serde_json::Value::as_array);
+
+        let valid = match (request.headers.get(header), keys) {
+            (Some(key), Some(keys)) => Self::is_valid(key.as_bytes(), keys),
+            _ => false,
+        };
+        if !valid {
+            return AuthResult::unauthorized();
+        }
+
+        Ok(AuthResult::Authenticated { subject: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn plugin(config: serde_json::Value) -> RoutePlugin {
+        RoutePlugin {
+            name: "api_key".into(),
+            config: Some(serde_json::from_value::<HashMap<_, _>>(config).unwrap()),
+        }
+    }
+
+    fn authenticate(plugin: &RoutePlugin, headers: &[(&str, &str)]) -> AuthResult {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            request.insert_header(*name, *value).unwrap();
+        }
+
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(ApiKey::new().authenticate(&request, "example.com", plugin))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let plugin = plugin(json!({ "api_keys": ["first-key", "second-key"] }));
+        assert!(matches!(
+            authenticate(&plugin, &[("x-api-key", "second-key")]),
+            AuthResult::Authenticated { .. }
+        ));
+        assert!(matches!(
+            authenticate(&plugin, &[("x-api-key", "second")]),
+            AuthResult::Denied(_)
+        ));
+        assert!(matches!(authenticate(&plugin, &[]), AuthResult::Denied(_)));
+    }
+
+    #[test]
+    fn test_api_key_header() {
+        let plugin = plugin(json!({ "api_keys": ["first-key"], "header": "x-token" }));
+        assert!(matches!(
+            authenticate(&plugin, &[("x-token", "first-key")]),
+            AuthResult::Authenticated { .. }
+        ));
+        assert!(matches!(
+            authenticate(&plugin, &[("x-api-key", "first-key")]),
+            AuthResult::Denied(_)
+        ));
+    }
+}
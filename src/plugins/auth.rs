@@ -0,0 +1,183 @@
+//! Authentication of the requests of a route (`auth`). Its middlewares are
+//! tried in order, the first one authenticating the request lets it through
+//! (ex: an API key for the scripts, basic auth for the browsers).
+//!
+//! When none does, the client gets the rejection of the first one, along with
+//! the `WWW-Authenticate` challenges of all of them (RFC 9110, section 11.6.1).
+//! A middleware answering the request itself (ex: the OAuth callback) ends
+//! the chain. The middlewares failing to run reject the request.
+
+use async_trait::async_trait;
+use http::{header, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::PLUGINS;
+
+/// The names of the authentication middlewares
+pub const MIDDLEWARES: &[&str] = &["basic_auth", "api_key", "jwt", "oauth2"];
+
+/// What an authentication middleware made of a request
+#[derive(Debug)]
+pub enum AuthResult {
+    /// Authenticated, as `subject` when the credentials name one (ex: the `sub` of a JWT)
+    Authenticated { subject: Option<String> },
+    /// Rejected with the response, unless a later middleware authenticates it
+    Denied(Box<ResponseHeader>),
+    /// Answered with the response, whatever the later middlewares make of it
+    Respond(Box<ResponseHeader>),
+}
+
+impl AuthResult {
+    /// A rejection without a challenge
+    pub fn unauthorized() -> anyhow::Result<Self> {
+        let response = ResponseHeader::build_no_case(StatusCode::UNAUTHORIZED, Some(1))?;
+        Ok(AuthResult::Denied(Box::new(response)))
+    }
+}
+
+/// A way of authenticating the requests (basic auth, API keys, etc.)
+#[async_trait]
+pub trait AuthMiddleware: Sync {
+    /// Authenticates the request to the host with the configuration of the middleware
+    async fn authenticate(
+        &self,
+        request: &RequestHeader,
+        host: &str,
+        plugin: &RoutePlugin,
+    ) -> anyhow::Result<AuthResult>;
+}
+
+/// The middleware of the name, `None` when there is none
+fn middleware(name: &str) -> Option<&'static dyn AuthMiddleware> {
+    match name {
+        "basic_auth" => Some(&*PLUGINS.basic_auth),
+        "api_key" => Some(&*PLUGINS.api_key),
+        "jwt" => Some(&*PLUGINS.jwt),
+        "oauth2" => Some(&*PLUGINS.oauth2),
+        _ => None,
+    }
+}
+
+/// Runs the middlewares in order. Returns `true` when the request was answered
+/// (rejected or redirected), `false` when it was authenticated.
+pub async fn authenticate(
+    session: &mut Session,
+    ctx: &mut RouterContext,
+    middlewares: &[RoutePlugin],
+) -> pingora::Result<bool> {
+    if middlewares.is_empty() {
+        return Ok(false);
+    }
+
+    let mut rejections = Vec::new();
+    for plugin in middlewares {
+        let Some(middleware) = middleware(&plugin.name) else {
+            continue;
+        };
+
+        let result = middleware
+            .authenticate(session.req_header(), &ctx.host, plugin)
+            .await
+            .or_else(|err| {
+                tracing::warn!("{} failed to authenticate a request: {err}", plugin.name);
+                AuthResult::unauthorized()
+            });
+
+        match result {
+            Ok(AuthResult::Authenticated { subject }) => {
+                // Pins the client to its upstream (`sticky.by: jwt:sub`)
+                if let Some(subject) = subject.filter(|subject| !subject.is_empty()) {
+                    ctx.jwt_subject = Some(subject);
+                }
+                return Ok(false);
+            }
+            Ok(AuthResult::Denied(response)) => rejections.push(response),
+            Ok(AuthResult::Respond(response)) => {
+                session.write_response_header(response, true).await?;
+                return Ok(true);
+            }
+            Err(_) => {}
+        }
+    }
+
+    let response = rejection(rejections)?;
+    session.write_response_header(response, true).await?;
+    Ok(true)
+}
+
+/// The first rejection, with the challenges of all of them
+fn rejection(rejections: Vec<Box<ResponseHeader>>) -> pingora::Result<Box<ResponseHeader>> {
+    let mut rejections = rejections.into_iter();
+    let Some(mut response) = rejections.next() else {
+        return Ok(Box::new(ResponseHeader::build_no_case(
+            StatusCode::UNAUTHORIZED,
+            Some(1),
+        )?));
+    };
+
+    for other in rejections {
+        for challenge in other.headers.get_all(header::WWW_AUTHENTICATE) {
+            response.append_header(header::WWW_AUTHENTICATE, challenge.clone())?;
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejection_with(status: u16, challenge: Option<&str>) -> Box<ResponseHeader> {
+        let mut response = ResponseHeader::build_no_case(status, None).unwrap();
+        if let Some(challenge) = challenge {
+            response
+                .insert_header(header::WWW_AUTHENTICATE, challenge)
+                .unwrap();
+        }
+        Box::new(response)
+    }
+
+    #[test]
+    fn test_rejection_gathers_the_challenges() {
+        let response = rejection(vec![
+            rejection_with(401, Some("Bearer")),
+            rejection_with(401, None),
+            rejection_with(401, Some("Basic realm=\"example.com\"")),
+        ])
+        .unwrap();
+
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response
+                .headers
+                .get_all(header::WWW_AUTHENTICATE)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["Bearer", "Basic realm=\"example.com\""]
+        );
+
+        // The first rejection wins (ex: the redirect to an OAuth provider)
+        let response = rejection(vec![
+            rejection_with(307, None),
+            rejection_with(401, Some("Bearer")),
+        ])
+        .unwrap();
+        assert_eq!(response.status, StatusCode::TEMPORARY_REDIRECT);
+
+        let response = rejection(Vec::new()).unwrap();
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_every_middleware_is_registered() {
+        for name in MIDDLEWARES {
+            assert!(middleware(name).is_some(), "{name}");
+        }
+        assert!(middleware("rate_limit").is_none());
+    }
+}
@@ -2,7 +2,7 @@ use std::{borrow::Cow, collections::HashMap};
 
 use async_trait::async_trait;
 use http::{header, StatusCode};
-use openssl::base64;
+use openssl::{base64, memcmp};
 use pingora::{
     http::{RequestHeader, ResponseHeader},
     proxy::Session,
@@ -10,8 +10,39 @@ use pingora::{
 
 use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
 
-use super::MiddlewarePlugin;
+use super::{
+    auth::{self, AuthMiddleware, AuthResult},
+    MiddlewarePlugin,
+};
+
+/// The password of a user of the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Password {
+    /// As written in the configuration (`pass`)
+    Plain(String),
+    /// A bcrypt hash (the `users` entries, ex: from `htpasswd -nB`)
+    Bcrypt(String),
+}
+
+impl Password {
+    /// Whether the password matches, compared in constant time. The bcrypt
+    /// hashes are verified on the blocking threads: they are slow on purpose.
+    async fn verify(self, pass: String) -> bool {
+        match self {
+            Password::Plain(valid) => {
+                valid.len() == pass.len() && memcmp::eq(valid.as_bytes(), pass.as_bytes())
+            }
+            Password::Bcrypt(hash) => {
+                tokio::task::spawn_blocking(move || bcrypt::verify(pass, &hash).unwrap_or(false))
+                    .await
+                    .unwrap_or(false)
+            }
+        }
+    }
+}
 
+/// Authenticates the requests with the `user` and `pass` of the
+/// configuration, or one of its `users` (`<user>:<bcrypt hash>`)
 pub struct BasicAuth;
 impl BasicAuth {
     pub fn new() -> Self {
@@ -20,74 +51,97 @@ impl BasicAuth {
 
     /// Returns a WWW-Authenticate header response indicating to downstream that
     /// This request requires basic auth
-    fn respond_with_authenticate(host: &str) -> anyhow::Result<Box<ResponseHeader>> {
+    fn respond_with_authenticate(realm: &str) -> anyhow::Result<Box<ResponseHeader>> {
         let mut res_headers = ResponseHeader::build_no_case(StatusCode::UNAUTHORIZED, Some(1))?;
-        let realm = format!("Basic realm=\"{host}\", charset=\"UTF-8\"");
+        let realm = format!("Basic realm=\"{realm}\", charset=\"UTF-8\"");
         res_headers.insert_header(header::WWW_AUTHENTICATE, &realm)?;
 
         Ok(Box::new(res_headers))
     }
 
-    /// Extracts the 'user' and 'pass' from the plugin configuration
-    fn get_auth_config(
+    /// Extracts the password of the user from the plugin configuration:
+    /// the `user` and `pass`, then the `users`
+    fn get_password(
         config: &HashMap<Cow<'static, str>, serde_json::Value>,
-    ) -> Option<(String, String)> {
-        let user = config.get("user")?.as_str()?.to_string();
-        let pass = config.get("pass")?.as_str()?.to_string();
-        Some((user, pass))
+        user: &str,
+    ) -> Option<Password> {
+        let plain = match (config.get("user"), config.get("pass")) {
+            (Some(valid), Some(pass)) if valid.as_str() == Some(user) => {
+                pass.as_str().map(|pass| Password::Plain(pass.to_string()))
+            }
+            _ => None,
+        };
+
+        plain.or_else(|| {
+            config
+                .get("users")?
+                .as_array()?
+                .iter()
+                .filter_map(serde_json::Value::as_str)
+                .filter_map(|entry| entry.split_once(':'))
+                .find(|(valid, _)| *valid == user)
+                .map(|(_, hash)| Password::Bcrypt(hash.to_string()))
+        })
     }
 
-    /// Validates the 'Authorization' header against the configured 'user' and 'pass'
-    fn validate_auth_header(auth_header: &str, user: &str, pass: &str) -> anyhow::Result<bool> {
-        let encoded = auth_header.trim_start_matches("Basic ");
-        let decoded = String::from_utf8(base64::decode_block(encoded)?)?;
-        let (auth_user, auth_pass) = decoded.split_once(':').unwrap_or_default();
-        Ok(auth_user == user && auth_pass == pass)
+    /// Extracts the user and password of the 'Authorization' header
+    fn parse_auth_header(auth_header: &str) -> Option<(String, String)> {
+        let encoded = auth_header.strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(base64::decode_block(encoded.trim()).ok()?).ok()?;
+        let (user, pass) = decoded.split_once(':')?;
+        Some((user.to_string(), pass.to_string()))
     }
 }
 
 #[async_trait]
-impl MiddlewarePlugin for BasicAuth {
-    async fn request_filter(
+impl AuthMiddleware for BasicAuth {
+    async fn authenticate(
         &self,
-        session: &mut Session,
-        ctx: &mut RouterContext,
+        request: &RequestHeader,
+        host: &str,
         plugin: &RoutePlugin,
-    ) -> anyhow::Result<bool> {
-        if plugin.config.is_none() {
+    ) -> anyhow::Result<AuthResult> {
+        let Some(config) = plugin.config.as_ref() else {
             // Nothing to do if the plugin configuration is not present
-            return Ok(false);
-        }
-
-        let config = plugin.config.as_ref().unwrap();
-
-        let Some((user, pass)) = Self::get_auth_config(config) else {
-            session
-                .write_response_header(Self::respond_with_authenticate(&ctx.host)?, true)
-                .await?;
-            return Ok(true);
+            return Ok(AuthResult::Authenticated { subject: None });
         };
 
+        let realm = config
+            .get("realm")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or(host);
+        let challenge = || Self::respond_with_authenticate(realm).map(AuthResult::Denied);
+
         // Get auth header but if missing returns 401
-        let auth_header = if let Some(header) = session.req_header().headers.get("authorization") {
-            header.to_str()?
-        } else {
-            session
-                .write_response_header(Self::respond_with_authenticate(&ctx.host)?, true)
-                .await?;
-            return Ok(true);
+        let Some((user, pass)) = request
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::parse_auth_header)
+        else {
+            return challenge();
         };
 
-        if !auth_header.starts_with("Basic ")
-            || !Self::validate_auth_header(auth_header, &user, &pass)?
-        {
-            session
-                .write_response_header(Self::respond_with_authenticate(&ctx.host)?, true)
-                .await?;
-            return Ok(true);
+        let Some(password) = Self::get_password(config, &user) else {
+            return challenge();
+        };
+        if !password.verify(pass).await {
+            return challenge();
         }
 
-        Ok(false)
+        Ok(AuthResult::Authenticated { subject: None })
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for BasicAuth {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> anyhow::Result<bool> {
+        Ok(auth::authenticate(session, ctx, std::slice::from_ref(plugin)).await?)
     }
 
     async fn upstream_request_filter(
@@ -119,3 +173,76 @@ impl MiddlewarePlugin for BasicAuth {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn plugin(config: serde_json::Value) -> RoutePlugin {
+        RoutePlugin {
+            name: "basic_auth".into(),
+            config: Some(serde_json::from_value::<HashMap<_, _>>(config).unwrap()),
+        }
+    }
+
+    fn authenticate(plugin: &RoutePlugin, credentials: Option<&str>) -> AuthResult {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(credentials) = credentials {
+            let value = format!("Basic {}", base64::encode_block(credentials.as_bytes()));
+            request.insert_header(header::AUTHORIZATION, value).unwrap();
+        }
+
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(BasicAuth::new().authenticate(&request, "example.com", plugin))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_basic_auth_user_and_pass() {
+        let plugin = plugin(json!({ "user": "admin", "pass": "secret" }));
+        assert!(matches!(
+            authenticate(&plugin, Some("admin:secret")),
+            AuthResult::Authenticated { .. }
+        ));
+        assert!(matches!(
+            authenticate(&plugin, Some("admin:secre")),
+            AuthResult::Denied(_)
+        ));
+        assert!(matches!(
+            authenticate(&plugin, Some("other:secret")),
+            AuthResult::Denied(_)
+        ));
+        assert!(matches!(authenticate(&plugin, None), AuthResult::Denied(_)));
+    }
+
+    #[test]
+    fn test_basic_auth_bcrypt_users() {
+        let hash = bcrypt::hash("s3cret:pass", 4).unwrap();
+        let plugin = plugin(json!({
+            "users": [format!("alice:{hash}"), "bob:$2b$04$invalid"],
+            "realm": "dashboard",
+        }));
+
+        assert!(matches!(
+            authenticate(&plugin, Some("alice:s3cret:pass")),
+            AuthResult::Authenticated { .. }
+        ));
+        assert!(matches!(
+            authenticate(&plugin, Some("bob:s3cret:pass")),
+            AuthResult::Denied(_)
+        ));
+
+        let AuthResult::Denied(response) = authenticate(&plugin, Some("alice:wrong")) else {
+            panic!("the wrong password was accepted");
+        };
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers[header::WWW_AUTHENTICATE],
+            "Basic realm=\"dashboard\", charset=\"UTF-8\""
+        );
+    }
+}
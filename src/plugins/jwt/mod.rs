@@ -3,9 +3,19 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+use async_trait::async_trait;
+use http::{header, StatusCode};
 use jsonwebtoken::{encode, DecodingKey, EncodingKey, Header, Validation};
+use pingora::http::{RequestHeader, ResponseHeader};
 use serde::{Deserialize, Serialize};
 
+use crate::config::RoutePlugin;
+
+use super::{
+    auth::{AuthMiddleware, AuthResult},
+    get_required_config,
+};
+
 /// Struct that holds the claims for a JWT token
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct JwtClaims {
@@ -54,10 +64,113 @@ pub(crate) fn decode_jwt(token: &str, secret: &[u8]) -> Result<JwtClaims, anyhow
     Ok(data.claims)
 }
 
+/// The claims of the bearer tokens authenticated by [`Jwt`]
+#[derive(Debug, Deserialize)]
+struct BearerClaims {
+    #[serde(default)]
+    sub: Option<String>,
+}
+
+/// Authenticates the requests carrying a bearer token (`Authorization: Bearer`)
+/// signed with the configured `secret` (HS256), as the `sub` of the token
+pub struct Jwt;
+
+impl Jwt {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /// Asks the client for a bearer token
+    fn challenge() -> anyhow::Result<AuthResult> {
+        let mut response = ResponseHeader::build_no_case(StatusCode::UNAUTHORIZED, Some(1))?;
+        response.insert_header(header::WWW_AUTHENTICATE, "Bearer")?;
+        Ok(AuthResult::Denied(Box::new(response)))
+    }
+}
+
+#[async_trait]
+impl AuthMiddleware for Jwt {
+    async fn authenticate(
+        &self,
+        request: &RequestHeader,
+        _host: &str,
+        plugin: &RoutePlugin,
+    ) -> anyhow::Result<AuthResult> {
+        let Some(config) = plugin.config.as_ref() else {
+            return Self::challenge();
+        };
+        let secret = get_required_config(config, "secret")?;
+
+        let token = request
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let Some(token) = token else {
+            return Self::challenge();
+        };
+
+        // Expired tokens (or without an expiration) are rejected
+        let Ok(data) = jsonwebtoken::decode::<BearerClaims>(
+            token.trim(),
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::default(),
+        ) else {
+            return Self::challenge();
+        };
+
+        Ok(AuthResult::Authenticated {
+            subject: data.claims.sub,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use super::*;
 
+    fn authenticate(authorization: Option<&str>) -> AuthResult {
+        let plugin = RoutePlugin {
+            name: Cow::Borrowed("jwt"),
+            config: Some(HashMap::from([(
+                Cow::Borrowed("secret"),
+                serde_json::json!("secret"),
+            )])),
+        };
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(authorization) = authorization {
+            request
+                .insert_header(header::AUTHORIZATION, authorization)
+                .unwrap();
+        }
+
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(Jwt::new().authenticate(&request, "example.com", &plugin))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_bearer_tokens() {
+        let token = encode_jwt("user-1", b"secret").unwrap();
+        let AuthResult::Authenticated { subject } = authenticate(Some(&format!("Bearer {token}")))
+        else {
+            panic!("the token is valid");
+        };
+        assert_eq!(subject.as_deref(), Some("user-1"));
+
+        let forged = encode_jwt("user-1", b"other").unwrap();
+        for authorization in [None, Some(format!("Bearer {forged}")), Some(token)] {
+            let AuthResult::Denied(response) = authenticate(authorization.as_deref()) else {
+                panic!("the token is invalid: {authorization:?}");
+            };
+            assert_eq!(response.headers[header::WWW_AUTHENTICATE], "Bearer");
+        }
+    }
+
     #[test]
     fn test_encode_jwt_with_secret() {
         let token = encode_jwt("test", b"secret");
@@ -1,31 +1,43 @@
 use std::{borrow::Cow, collections::HashMap};
 
 use anyhow::{anyhow, Result};
+use api_key::ApiKey;
 use async_trait::async_trait;
 use basic_auth::BasicAuth;
+use jwt::Jwt;
 use oauth2::Oauth2;
 use once_cell::sync::Lazy;
 use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::proxy::Session;
+use rate_limit::RateLimit;
 use request_id::RequestId;
 
 use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
 
+pub mod api_key;
+pub mod auth;
 pub mod basic_auth;
 pub mod jwt;
 pub mod oauth2;
+pub mod rate_limit;
 pub mod request_id;
 
 pub(crate) struct ProxyPlugins {
+    pub api_key: Lazy<ApiKey>,
     pub basic_auth: Lazy<BasicAuth>,
+    pub jwt: Lazy<Jwt>,
     pub oauth2: Lazy<Oauth2>,
+    pub rate_limit: Lazy<RateLimit>,
     pub request_id: Lazy<RequestId>,
 }
 
 /// Static plugin registry (plugins that don't generate a new instance for each request)
 pub static PLUGINS: Lazy<ProxyPlugins> = Lazy::new(|| ProxyPlugins {
+    api_key: Lazy::new(ApiKey::new),
     basic_auth: Lazy::new(BasicAuth::new),
+    jwt: Lazy::new(Jwt::new),
     oauth2: Lazy::new(Oauth2::new),
+    rate_limit: Lazy::new(RateLimit::new),
     request_id: Lazy::new(RequestId::new),
 });
 
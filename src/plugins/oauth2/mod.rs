@@ -13,7 +13,10 @@ use provider::{OauthType, OauthUser, Provider};
 
 use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
 
-use super::{get_required_config, jwt, MiddlewarePlugin};
+use super::{
+    auth::{self, AuthMiddleware, AuthResult},
+    get_required_config, jwt, MiddlewarePlugin,
+};
 
 // New providers can be added here
 mod github;
@@ -62,13 +65,12 @@ impl Oauth2 {
     }
 
     /// Redirects the user to the Oauth provider to authenticate.
-    async fn redirect_to_oauth_callback(
+    fn redirect_to_oauth_callback(
         &self,
-        session: &mut Session,
+        request: &RequestHeader,
         oauth_provider: &Provider,
-    ) -> Result<bool> {
-        let current_address = session.req_header().uri.to_string();
-        // let host = session.req_header().uri.host().unwrap_or_default();
+    ) -> Result<AuthResult> {
+        let current_address = request.uri.to_string();
 
         let state = format!("{};{}", get_current_timestamp(), current_address);
         let state = self.short_crypt.encrypt_to_url_component(&state);
@@ -90,44 +92,32 @@ impl Oauth2 {
             "no-store, no-cache, must-revalidate, max-age=0",
         )?;
 
-        // Store the current path in the state
-        session
-            .write_response_header(Box::new(res_headers), true)
-            .await?;
-
-        // Finish the request, we don't want to continue processing
-        // and the user has been redirected
-        Ok(true)
+        // The user is redirected, unless a later middleware authenticates the request
+        Ok(AuthResult::Denied(Box::new(res_headers)))
     }
 
-    /// Ends the Oauth2 flow and returns HTTP unauthorized if errors occur during the Oauth process
-    async fn unauthorized_response(&self, session: &mut Session) -> Result<bool> {
+    /// HTTP unauthorized, ending the Oauth2 flow when errors occur during the Oauth process
+    fn unauthorized_response() -> Result<Box<ResponseHeader>> {
         let res_headers = ResponseHeader::build_no_case(StatusCode::UNAUTHORIZED, Some(1))?;
-
-        // Store the current path in the stat
-        session
-            .write_response_header(Box::new(res_headers), true)
-            .await?;
-
-        // Finish the request, we don't want to continue processing
-        // and the user has been redirected
-        Ok(true)
+        Ok(Box::new(res_headers))
     }
 
-    /// Validates a given cookie and returns true if
-    /// the user is authorized
-    async fn validate_cookie(
-        &self,
-        session: &mut Session,
+    /// Validates the secure cookie of the request, `None` without a valid one
+    /// (the user is redirected to the Oauth provider). The subject of an
+    /// authorized user pins it to its upstream.
+    fn validate_cookie(
+        request: &RequestHeader,
         jwt_secret: &str,
         validations: Option<&serde_json::Value>,
-    ) -> Result<bool> {
-        let cookie_header = session.req_header().headers.get_all("cookie");
+    ) -> Result<Option<AuthResult>> {
+        let cookie_header = request.headers.get_all("cookie");
 
         let mut secure_jwt: Option<Cookie> = None;
 
         for cookie in cookie_header {
-            let cookie_str = cookie.to_str().unwrap();
+            let Ok(cookie_str) = cookie.to_str() else {
+                continue;
+            };
 
             let Ok(ck) = Cookie::parse(cookie_str) else {
                 continue;
@@ -138,22 +128,25 @@ impl Oauth2 {
             }
         }
 
-        if secure_jwt.is_none() {
-            return Ok(false); // will redirect to oauth callback
-        }
+        let Some(secure_jwt) = secure_jwt else {
+            return Ok(None); // will redirect to oauth callback
+        };
 
-        let decoded = jwt::decode_jwt(secure_jwt.unwrap().value(), jwt_secret.as_bytes());
+        let decoded = jwt::decode_jwt(secure_jwt.value(), jwt_secret.as_bytes());
 
         // Token expired or another err
-        if decoded.is_err() {
-            return Ok(false); // will redirect to oauth callback
-        }
+        let Ok(claims) = decoded else {
+            return Ok(None); // will redirect to oauth callback
+        };
 
-        if !Self::is_authorized(&decoded?.into(), validations) {
-            return self.unauthorized_response(session).await;
+        let subject = claims.sub.to_string();
+        if !Self::is_authorized(&claims.into(), validations) {
+            return Ok(Some(AuthResult::Denied(Self::unauthorized_response()?)));
         }
 
-        Ok(true)
+        Ok(Some(AuthResult::Authenticated {
+            subject: Some(subject),
+        }))
     }
 
     fn parse_provider(
@@ -173,42 +166,22 @@ impl Oauth2 {
 }
 
 #[async_trait]
-impl MiddlewarePlugin for Oauth2 {
-    async fn upstream_request_filter(
-        &self,
-        _: &mut Session,
-        _: &mut RequestHeader,
-        _: &mut RouterContext,
-    ) -> Result<()> {
-        Ok(())
-    }
-
-    fn upstream_response_filter(
-        &self,
-        _: &mut Session,
-        _: &mut ResponseHeader,
-        _: &mut RouterContext,
-    ) -> Result<()> {
-        Ok(())
-    }
-
+impl AuthMiddleware for Oauth2 {
     /// Oauth2 filters requests with/without the required Secure Cookie
     /// If the request has the required cookie, the request is allowed to pass through
     /// and we perform a JWT validation
     /// If the request does not have the required cookie, the request is blocked
     /// and we return a redirect to the oauth login flow (HTTP 307)
-    async fn request_filter(
+    async fn authenticate(
         &self,
-        session: &mut Session,
-        ctx: &mut RouterContext,
+        request: &RequestHeader,
+        host: &str,
         plugin: &RoutePlugin,
-    ) -> Result<bool> {
+    ) -> Result<AuthResult> {
         // Nothing to do if the plugin configuration is not present
-        if plugin.config.is_none() {
-            return Ok(false);
-        }
-
-        let plugin_config = plugin.config.as_ref().unwrap();
+        let Some(plugin_config) = plugin.config.as_ref() else {
+            return Ok(AuthResult::Authenticated { subject: None });
+        };
 
         let provider = Self::parse_provider(plugin_config)?;
 
@@ -227,35 +200,35 @@ impl MiddlewarePlugin for Oauth2 {
             typ: provider,
         };
 
-        let uri = &session.req_header().uri;
+        let uri = &request.uri;
 
         // Step 0. Check if the request is for the Oauth Callback URL
         if uri.path() == callback_path {
             let Some(query) = uri.query() else {
-                return self.unauthorized_response(session).await;
+                return Ok(AuthResult::Respond(Self::unauthorized_response()?));
             };
 
             let query_params = shared::from_string_to_query_params(query);
 
             let Some(code) = query_params.get("code") else {
                 tracing::info!("missing code in the query");
-                return self.unauthorized_response(session).await;
+                return Ok(AuthResult::Respond(Self::unauthorized_response()?));
             };
 
             let Some(state) = query_params.get("state") else {
                 tracing::info!("missing state in the query");
-                return self.unauthorized_response(session).await;
+                return Ok(AuthResult::Respond(Self::unauthorized_response()?));
             };
 
             // Get encrypted state and decrypt it
             let Ok(encrypted) = self.short_crypt.decrypt_url_component(state) else {
                 tracing::info!("state does not exist or was removed");
-                return self.unauthorized_response(session).await;
+                return Ok(AuthResult::Respond(Self::unauthorized_response()?));
             };
 
             let Ok(redirect_from_state) = String::from_utf8(encrypted) else {
                 tracing::info!("state is not a valid UTF-8 string");
-                return self.unauthorized_response(session).await;
+                return Ok(AuthResult::Respond(Self::unauthorized_response()?));
             };
 
             let (timestamp, current_address) = redirect_from_state.split_once(';').unwrap();
@@ -265,7 +238,7 @@ impl MiddlewarePlugin for Oauth2 {
             // Check if the state is still valid from the last 120 seconds (2 minutes)
             if timestamp + 120 < get_current_timestamp() {
                 tracing::info!("state has expired");
-                return self.unauthorized_response(session).await;
+                return Ok(AuthResult::Respond(Self::unauthorized_response()?));
             }
 
             // Step 1: Exchange the code for an access token
@@ -274,7 +247,7 @@ impl MiddlewarePlugin for Oauth2 {
                     tracing::error!(
                         "Failed to exchange code {code}, state {redirect_from_state}: {err}"
                     );
-                    return self.unauthorized_response(session).await;
+                    return Ok(AuthResult::Respond(Self::unauthorized_response()?));
                 }
                 Ok(user) => user,
             };
@@ -282,10 +255,10 @@ impl MiddlewarePlugin for Oauth2 {
             // Validate if user is authorized to access the protected resource
             if !Self::is_authorized(&user, validations) {
                 tracing::info!("user is not authorized {:?}", user);
-                return self.unauthorized_response(session).await;
+                return Ok(AuthResult::Respond(Self::unauthorized_response()?));
             }
 
-            let jwt_cookie = secure_cookie::create_secure_cookie(&user, &jwt_secret, &ctx.host)?;
+            let jwt_cookie = secure_cookie::create_secure_cookie(&user, &jwt_secret, host)?;
 
             let mut res_headers = ResponseHeader::build_no_case(StatusCode::FOUND, Some(1))?;
             res_headers.insert_header(http::header::SET_COOKIE, jwt_cookie.to_string())?;
@@ -295,24 +268,43 @@ impl MiddlewarePlugin for Oauth2 {
                 "no-store, no-cache, must-revalidate, max-age=0",
             )?;
 
-            session
-                .write_response_header(Box::new(res_headers), true)
-                .await?;
-
-            return Ok(true);
+            return Ok(AuthResult::Respond(Box::new(res_headers)));
         }
 
-        if self
-            .validate_cookie(session, &jwt_secret, validations)
-            .await?
-        {
-            // If the user is not authorized, return true to
-            // interrupt the request
-            return Ok(false);
+        match Self::validate_cookie(request, &jwt_secret, validations)? {
+            Some(result) => Ok(result),
+            None => self.redirect_to_oauth_callback(request, &oauth_provider),
         }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Oauth2 {
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
 
-        self.redirect_to_oauth_callback(session, &oauth_provider)
-            .await
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(auth::authenticate(session, ctx, std::slice::from_ref(plugin)).await?)
     }
 
     // Nothing to do after upstream response
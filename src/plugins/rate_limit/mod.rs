@@ -0,0 +1,722 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::StatusCode;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+    ErrorType::HTTPStatus,
+};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    config::{RoutePlugin, RouteRateLimit},
+    proxy_server::https_proxy::RouterContext,
+    stores::bounded::{EvictableStore, TtlMap},
+    tools::client_ip,
+};
+
+use super::{MiddlewarePlugin, PLUGINS};
+
+/// Which part of the request identifies a client for a rate limit rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RateLimitKey {
+    Ip,
+    Header(String),
+    Path,
+}
+
+impl RateLimitKey {
+    /// Parses `ip`, `path` or `header:<name>`
+    fn parse(value: &str) -> Option<Self> {
+        match value.split_once(':') {
+            Some((kind, name)) if kind.eq_ignore_ascii_case("header") && !name.is_empty() => {
+                Some(RateLimitKey::Header(name.trim().to_lowercase()))
+            }
+            _ => match value.to_lowercase().as_str() {
+                "ip" => Some(RateLimitKey::Ip),
+                "path" => Some(RateLimitKey::Path),
+                _ => None,
+            },
+        }
+    }
+
+    /// Extracts the key from the request.
+    /// Returns `None` when the request does not carry the key (ex: missing header)
+    fn extract(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> Option<String> {
+        match self {
+            RateLimitKey::Ip => client_ip.map(|ip| ip.to_string()),
+            RateLimitKey::Header(name) => req
+                .headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .filter(|v| !v.is_empty())
+                .map(ToString::to_string),
+            RateLimitKey::Path => Some(req.uri.path().to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RateLimitRule {
+    /// Name of the rule, used to separate buckets of different rules
+    name: String,
+
+    /// The key strategy (ex: 'ip', 'header:X-Api-Key', 'path')
+    #[serde(deserialize_with = "rate_limit_key_deser")]
+    key: RateLimitKey,
+
+    /// Maximum number of requests allowed within `interval_secs`
+    limit: u32,
+
+    /// The window (in seconds) in which `limit` requests are allowed
+    interval_secs: u64,
+
+    /// The refill rate of the bucket instead of `limit / interval_secs`,
+    /// set by the `rate_limit` of the routes
+    #[serde(skip)]
+    requests_per_second: Option<f64>,
+}
+
+impl RateLimitRule {
+    /// Tokens added to a bucket per second
+    #[allow(clippy::cast_precision_loss)]
+    fn rate(&self) -> f64 {
+        self.requests_per_second
+            .unwrap_or_else(|| f64::from(self.limit) / self.interval_secs.max(1) as f64)
+    }
+}
+
+/// Replaced by the seconds the client has to wait in the body and headers of the response
+const RETRY_AFTER_PLACEHOLDER: &str = "{retry_after}";
+
+/// Parses and validates the response to the throttled requests, an empty
+/// `429` when none is configured
+fn parse_response(response: Option<&serde_json::Value>) -> Result<RateLimitResponse> {
+    let Some(response) = response else {
+        return Ok(RateLimitResponse::default());
+    };
+
+    let response: RateLimitResponse = serde_json::from_value(response.clone())
+        .map_err(|err| anyhow!("response is invalid: {err}"))?;
+    if !(400..=599).contains(&response.status) {
+        return Err(anyhow!("response.status must be between 400 and 599"));
+    }
+
+    Ok(response)
+}
+
+/// The response to the throttled requests, an empty `429` by default
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct RateLimitResponse {
+    /// Status of the response (`400` to `599`)
+    #[serde(default = "default_response_status")]
+    status: u16,
+
+    /// Content type of the body (ex: `application/json`)
+    #[serde(default)]
+    content_type: Option<String>,
+
+    /// Template of the body, with the `{retry_after}` placeholder
+    #[serde(default)]
+    body: String,
+
+    /// Additional headers (templates too), replacing the default `Retry-After`
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+fn default_response_status() -> u16 {
+    StatusCode::TOO_MANY_REQUESTS.as_u16()
+}
+
+impl Default for RateLimitResponse {
+    fn default() -> Self {
+        Self {
+            status: default_response_status(),
+            content_type: None,
+            body: String::new(),
+            headers: HashMap::new(),
+        }
+    }
+}
+
+/// Token bucket refilled continuously at `limit / interval_secs` tokens per second
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rule: &RateLimitRule, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(rule.limit),
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token from the bucket.
+    /// Returns `Err(wait)` with the time until the next token is available if empty.
+    fn take(&mut self, rule: &RateLimitRule, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(rule.limit);
+        let rate = rule.rate();
+
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            // A tiny rate waits longer than a `Duration` can hold
+            let wait = Duration::try_from_secs_f64((1.0 - self.tokens) / rate);
+            return Err(wait.unwrap_or(Duration::MAX));
+        }
+
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// The rules and response of a `rate_limit` plugin, parsed and checked once
+/// when its route is added
+#[derive(Debug)]
+pub struct RateLimitRules {
+    rules: Vec<RateLimitRule>,
+    response: RateLimitResponse,
+}
+
+impl RateLimitRules {
+    pub fn new(plugin: &RoutePlugin) -> Result<Self> {
+        let rules = RateLimit::get_rules(plugin)?;
+        for (index, rule) in rules.iter().enumerate() {
+            if rule.limit == 0 {
+                return Err(anyhow!("rules{index}.limit must be greater than 0"));
+            }
+
+            if rule.interval_secs == 0 {
+                return Err(anyhow!("rules{index}.interval_secs must be greater than 0"));
+            }
+        }
+
+        Ok(Self {
+            rules,
+            response: RateLimit::get_response(plugin)?,
+        })
+    }
+}
+
+/// A plugin that limits the amount of requests per client, where the client
+/// is identified by one of the configured rules (evaluated in order).
+/// The first rule whose key is present in the request is applied.
+pub struct RateLimit {
+    buckets: TtlMap<TokenBucket>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self {
+            buckets: TtlMap::new("rate_limit"),
+        }
+    }
+
+    /// The token buckets of every client, bounded by the eviction service
+    pub fn store(&self) -> &dyn EvictableStore {
+        &self.buckets
+    }
+
+    /// Parses the ordered list of rules from the plugin configuration
+    fn get_rules(plugin: &RoutePlugin) -> Result<Vec<RateLimitRule>> {
+        let rules = plugin
+            .config
+            .as_ref()
+            .and_then(|config| config.get("rules"))
+            .ok_or_else(|| anyhow!("rules is required"))?;
+
+        serde_json::from_value(rules.clone()).map_err(|err| anyhow!("rules is invalid: {err}"))
+    }
+
+    /// Parses the response to the throttled requests from the plugin configuration
+    fn get_response(plugin: &RoutePlugin) -> Result<RateLimitResponse> {
+        parse_response(
+            plugin
+                .config
+                .as_ref()
+                .and_then(|config| config.get("response")),
+        )
+    }
+
+    /// Applies the first matching rule to the request.
+    /// Returns the time the client has to wait if the request is over the limit.
+    fn check(
+        &self,
+        host: &str,
+        rules: &[RateLimitRule],
+        req: &RequestHeader,
+        client_ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Option<Duration> {
+        let (rule, key) = rules
+            .iter()
+            .find_map(|rule| rule.key.extract(req, client_ip).map(|key| (rule, key)))?;
+
+        let composite_key = format!("{host}:{}:{key}", rule.name);
+        self.buckets.with_entry(
+            composite_key,
+            now,
+            || TokenBucket::new(rule, now),
+            |bucket| bucket.take(rule, now).err(),
+        )
+    }
+
+    /// Answers the request with the response of the rules when its client is
+    /// over the limit. Returns whether the request was answered.
+    pub async fn enforce(
+        &self,
+        session: &mut Session,
+        host: &str,
+        rules: &RateLimitRules,
+    ) -> Result<bool> {
+        let client_ip = client_ip::client_ip(session);
+        let Some(wait) = self.check(
+            host,
+            &rules.rules,
+            session.req_header(),
+            client_ip,
+            Instant::now(),
+        ) else {
+            return Ok(false);
+        };
+
+        Self::respond(session, &rules.response, wait).await?;
+        Ok(true)
+    }
+
+    /// Answers a throttled request with the response
+    async fn respond(
+        session: &mut Session,
+        response: &RateLimitResponse,
+        wait: Duration,
+    ) -> Result<()> {
+        let (res_headers, body) = Self::respond_with_too_many_requests(response, wait)?;
+        if body.is_empty() {
+            session.write_response_header(res_headers, true).await?;
+        } else {
+            session.write_response_header(res_headers, false).await?;
+            session.write_response_body(Some(body), true).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configured response (a 429 by default) indicating when the client can retry
+    fn respond_with_too_many_requests(
+        response: &RateLimitResponse,
+        wait: Duration,
+    ) -> Result<(Box<ResponseHeader>, Bytes)> {
+        let retry_after = wait
+            .as_secs()
+            .saturating_add(u64::from(wait.subsec_nanos() > 0))
+            .to_string();
+        let render = |template: &str| template.replace(RETRY_AFTER_PLACEHOLDER, &retry_after);
+        let body = Bytes::from(render(&response.body));
+
+        let mut res_headers =
+            ResponseHeader::build(response.status, Some(3 + response.headers.len()))?;
+        res_headers.insert_header(http::header::RETRY_AFTER, &retry_after)?;
+        if let Some(content_type) = &response.content_type {
+            res_headers.insert_header(http::header::CONTENT_TYPE, content_type)?;
+        }
+        for (name, value) in &response.headers {
+            res_headers.insert_header(name.to_string(), render(value))?;
+        }
+        res_headers.insert_header(http::header::CONTENT_LENGTH, body.len().to_string())?;
+
+        Ok((Box::new(res_headers), body))
+    }
+}
+
+/// Separates the buckets of the `rate_limit` of the routes from the ones of
+/// the rules of the plugin
+const ROUTE_RULE: &str = "route";
+
+/// The `rate_limit` of a route, checked before its authentication and plugins.
+/// Its buckets are kept with the ones of the plugin (`PLUGINS.rate_limit`),
+/// shared by every worker thread and bounded by the eviction service.
+#[derive(Debug)]
+pub struct RouteRateLimiter {
+    rules: Vec<RateLimitRule>,
+    response: RateLimitResponse,
+}
+
+impl RouteRateLimiter {
+    pub fn new(config: &RouteRateLimit) -> Result<Self> {
+        let key = RateLimitKey::parse(&config.key)
+            .filter(|key| *key != RateLimitKey::Path)
+            .ok_or_else(|| anyhow!("invalid rate_limit key: {}", config.key))?;
+
+        let rule = |name: &str, key: RateLimitKey| RateLimitRule {
+            name: name.to_string(),
+            key,
+            limit: config.burst(),
+            interval_secs: 1,
+            requests_per_second: Some(config.requests_per_second),
+        };
+
+        // The requests without the header are limited by IP
+        let mut rules = vec![rule(ROUTE_RULE, key.clone())];
+        if key != RateLimitKey::Ip {
+            rules.push(rule(&format!("{ROUTE_RULE}:ip"), RateLimitKey::Ip));
+        }
+
+        let response = parse_response(config.response.as_ref())?;
+
+        Ok(Self { rules, response })
+    }
+
+    /// Answers the request with the configured response (a `429` by default)
+    /// when its client is over the limit. Returns whether the request was
+    /// answered, failing with a `429` when it could not be.
+    pub async fn enforce(&self, session: &mut Session, host: &str) -> pingora::Result<bool> {
+        let client_ip = client_ip::client_ip(session);
+        let Some(wait) = PLUGINS.rate_limit.check(
+            host,
+            &self.rules,
+            session.req_header(),
+            client_ip,
+            Instant::now(),
+        ) else {
+            return Ok(false);
+        };
+
+        RateLimit::respond(session, &self.response, wait)
+            .await
+            .map_err(|err| {
+                pingora::Error::explain(
+                    HTTPStatus(429),
+                    format!("failed to answer a rate limited request: {err}"),
+                )
+            })?;
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for RateLimit {
+    /// Only used when the rules were not compiled with the route, see [RateLimit::enforce]
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let rules = RateLimitRules::new(plugin)?;
+        self.enforce(session, &ctx.host, &rules).await
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn rate_limit_key_deser<'de, D>(deserializer: D) -> Result<RateLimitKey, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    RateLimitKey::parse(&s)
+        .ok_or_else(|| serde::de::Error::custom("expected one of: ip, path, header:<name>"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, collections::HashMap};
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn plugin_with_rules(rules: serde_json::Value) -> RoutePlugin {
+        RoutePlugin {
+            name: Cow::Borrowed("rate_limit"),
+            config: Some(HashMap::from([(Cow::Borrowed("rules"), rules)])),
+        }
+    }
+
+    fn rules() -> Vec<RateLimitRule> {
+        RateLimit::get_rules(&plugin_with_rules(json!([
+            { "name": "authenticated", "key": "header:X-Api-Key", "limit": 3, "interval_secs": 60 },
+            { "name": "anonymous", "key": "ip", "limit": 1, "interval_secs": 60 }
+        ])))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = rules();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].key, RateLimitKey::Header("x-api-key".into()));
+        assert_eq!(rules[1].key, RateLimitKey::Ip);
+
+        let invalid = plugin_with_rules(json!([
+            { "name": "bad", "key": "cookie", "limit": 1, "interval_secs": 1 }
+        ]));
+        assert!(RateLimit::get_rules(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_compile_rules() {
+        let rules = RateLimitRules::new(&plugin_with_rules(json!([
+            { "name": "anonymous", "key": "ip", "limit": 1, "interval_secs": 60 }
+        ])))
+        .unwrap();
+        assert_eq!(rules.rules.len(), 1);
+        assert_eq!(rules.response, RateLimitResponse::default());
+
+        let err = RateLimitRules::new(&plugin_with_rules(json!([
+            { "name": "anonymous", "key": "ip", "limit": 0, "interval_secs": 60 }
+        ])))
+        .unwrap_err();
+        assert_eq!(err.to_string(), "rules0.limit must be greater than 0");
+
+        let mut plugin = plugin_with_rules(json!([]));
+        plugin
+            .config
+            .as_mut()
+            .unwrap()
+            .insert(Cow::Borrowed("response"), json!({ "status": 200 }));
+        assert!(RateLimitRules::new(&plugin).is_err());
+    }
+
+    #[test]
+    fn test_tiny_rate_does_not_panic() {
+        let rule = RateLimitRule {
+            name: "route".into(),
+            key: RateLimitKey::Ip,
+            limit: 1,
+            interval_secs: 1,
+            requests_per_second: Some(f64::MIN_POSITIVE),
+        };
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&rule, now);
+        assert!(bucket.take(&rule, now).is_ok());
+        assert_eq!(bucket.take(&rule, now), Err(Duration::MAX));
+
+        let (headers, _) =
+            RateLimit::respond_with_too_many_requests(&RateLimitResponse::default(), Duration::MAX)
+                .unwrap();
+        assert_eq!(
+            headers.headers.get("retry-after").unwrap(),
+            &u64::MAX.to_string()
+        );
+    }
+
+    #[test]
+    fn test_first_matching_rule_is_applied() {
+        let plugin = RateLimit::new();
+        let rules = rules();
+        let ip = Some(IpAddr::from([10, 0, 0, 1]));
+        let now = Instant::now();
+
+        let anonymous = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(plugin.check("a.com", &rules, &anonymous, ip, now).is_none());
+        assert!(plugin.check("a.com", &rules, &anonymous, ip, now).is_some());
+
+        // Requests with an API key use their own (larger) bucket
+        let mut authenticated = RequestHeader::build("GET", b"/", None).unwrap();
+        authenticated.insert_header("x-api-key", "key-1").unwrap();
+        for _ in 0..3 {
+            assert!(plugin
+                .check("a.com", &rules, &authenticated, ip, now)
+                .is_none());
+        }
+        assert!(plugin
+            .check("a.com", &rules, &authenticated, ip, now)
+            .is_some());
+
+        // Buckets are not shared across hosts
+        assert!(plugin.check("b.com", &rules, &anonymous, ip, now).is_none());
+    }
+
+    #[test]
+    fn test_default_response() {
+        let plugin = plugin_with_rules(json!([]));
+        let response = RateLimit::get_response(&plugin).unwrap();
+        assert_eq!(response, RateLimitResponse::default());
+
+        let (headers, body) =
+            RateLimit::respond_with_too_many_requests(&response, Duration::from_millis(1500))
+                .unwrap();
+        assert_eq!(headers.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers.headers.get("retry-after").unwrap(), "2");
+        assert_eq!(headers.headers.get("content-length").unwrap(), "0");
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_custom_response() {
+        let mut plugin = plugin_with_rules(json!([]));
+        plugin.config.as_mut().unwrap().insert(
+            Cow::Borrowed("response"),
+            json!({
+                "status": 503,
+                "content_type": "application/json",
+                "body": "{\"error\":\"rate_limited\",\"retry_after\":{retry_after}}",
+                "headers": { "Retry-After": "{retry_after}0", "X-Rate-Limited": "true" }
+            }),
+        );
+
+        let response = RateLimit::get_response(&plugin).unwrap();
+        let (headers, body) =
+            RateLimit::respond_with_too_many_requests(&response, Duration::from_secs(3)).unwrap();
+        assert_eq!(headers.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            headers.headers.get("content-type").unwrap(),
+            "application/json"
+        );
+        assert_eq!(headers.headers.get("retry-after").unwrap(), "30");
+        assert_eq!(headers.headers.get("x-rate-limited").unwrap(), "true");
+        assert_eq!(&body[..], br#"{"error":"rate_limited","retry_after":3}"#);
+        assert_eq!(
+            headers.headers.get("content-length").unwrap(),
+            &body.len().to_string()
+        );
+
+        plugin
+            .config
+            .as_mut()
+            .unwrap()
+            .insert(Cow::Borrowed("response"), json!({ "status": 200 }));
+        assert!(RateLimit::get_response(&plugin).is_err());
+    }
+
+    #[test]
+    fn test_route_rate_limit() {
+        let config = |key: &str| RouteRateLimit {
+            requests_per_second: 2.0,
+            burst: Some(3),
+            key: key.to_string().into(),
+            response: None,
+        };
+        let plugin = RateLimit::new();
+        let ip = Some(IpAddr::from([10, 0, 0, 1]));
+        let now = Instant::now();
+
+        let limiter = RouteRateLimiter::new(&config("ip")).unwrap();
+        assert_eq!(limiter.response, RateLimitResponse::default());
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        for _ in 0..3 {
+            assert!(plugin
+                .check("a.com", &limiter.rules, &req, ip, now)
+                .is_none());
+        }
+        let wait = plugin
+            .check("a.com", &limiter.rules, &req, ip, now)
+            .unwrap();
+        assert_eq!(wait, Duration::from_millis(500));
+        let later = now + Duration::from_millis(500);
+        assert!(plugin
+            .check("a.com", &limiter.rules, &req, ip, later)
+            .is_none());
+
+        // Keyed by the header, by IP without it
+        let limiter = RouteRateLimiter::new(&config("header:X-Api-Key")).unwrap();
+        let mut with_key = RequestHeader::build("GET", b"/", None).unwrap();
+        with_key.insert_header("x-api-key", "key-1").unwrap();
+        for _ in 0..3 {
+            assert!(plugin
+                .check("b.com", &limiter.rules, &with_key, ip, now)
+                .is_none());
+        }
+        assert!(plugin
+            .check("b.com", &limiter.rules, &with_key, ip, now)
+            .is_some());
+        assert!(plugin
+            .check("b.com", &limiter.rules, &req, ip, now)
+            .is_none());
+
+        assert!(RouteRateLimiter::new(&config("path")).is_err());
+
+        // The response is the one of the plugin
+        let limiter = RouteRateLimiter::new(&RouteRateLimit {
+            response: Some(json!({ "status": 503, "body": "retry in {retry_after}s" })),
+            ..config("ip")
+        })
+        .unwrap();
+        let (res_headers, body) =
+            RateLimit::respond_with_too_many_requests(&limiter.response, Duration::from_secs(2))
+                .unwrap();
+        assert_eq!(res_headers.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(&body[..], b"retry in 2s");
+        let err = RouteRateLimiter::new(&RouteRateLimit {
+            response: Some(json!({ "status": 200 })),
+            ..config("ip")
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "response.status must be between 400 and 599"
+        );
+        assert_eq!(
+            RouteRateLimit {
+                burst: None,
+                ..config("ip")
+            }
+            .burst(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_bucket_refills_and_is_evicted() {
+        let plugin = RateLimit::new();
+        plugin.store().configure(Duration::from_secs(120), 10);
+        let rules = rules();
+        let ip = Some(IpAddr::from([10, 0, 0, 1]));
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        let now = Instant::now();
+
+        assert!(plugin.check("a.com", &rules, &req, ip, now).is_none());
+        let wait = plugin.check("a.com", &rules, &req, ip, now).unwrap();
+        assert!(wait <= Duration::from_secs(60));
+
+        let later = now + Duration::from_secs(61);
+        assert!(plugin.check("a.com", &rules, &req, ip, later).is_none());
+        assert_eq!(plugin.store().len(), 1);
+
+        // Unused buckets are dropped once their TTL is over
+        assert_eq!(plugin.store().evict(later + Duration::from_secs(121)), 1);
+        assert_eq!(plugin.store().len(), 0);
+    }
+}
@@ -0,0 +1,89 @@
+//! The hosts the listeners accept requests for (`allowed_hosts`), the other
+//! ones getting a `400` unless a route serves them. Forged `Host` headers then
+//! never reach the upstreams nor the HTTPS redirects (ex: the links of password
+//! reset emails built from the host of the request).
+//!
+//! `*.example.com` allows every subdomain of `example.com`, at any depth, but
+//! not `example.com` itself.
+
+use std::collections::HashSet;
+
+use super::matching;
+use crate::stores;
+
+/// The hosts allowed on top of the ones of the routes
+#[derive(Debug, Default)]
+pub struct AllowedHosts {
+    exact: HashSet<String>,
+    /// The domains of the wildcards, with their leading dot (ex: `.example.com`)
+    suffixes: Vec<String>,
+}
+
+impl AllowedHosts {
+    /// The allowed hosts, `None` when every host is
+    pub fn new(hosts: &[impl AsRef<str>]) -> Option<Self> {
+        if hosts.is_empty() {
+            return None;
+        }
+
+        let mut allowed = AllowedHosts::default();
+        for host in hosts {
+            let host = matching::normalize_host(host.as_ref());
+            match host.strip_prefix('*') {
+                Some(suffix) => allowed.suffixes.push(suffix.to_string()),
+                None => {
+                    allowed.exact.insert(host.into_owned());
+                }
+            }
+        }
+
+        Some(allowed)
+    }
+
+    /// Whether the (normalized) host is allowed
+    pub fn allows(&self, host: &str) -> bool {
+        self.exact.contains(host)
+            || self
+                .suffixes
+                .iter()
+                .any(|suffix| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+    }
+
+    /// Whether the requests for the (normalized) host are accepted: it is
+    /// allowed or has a route
+    pub fn accepts(&self, host: &str) -> bool {
+        self.allows(host) || stores::find_route_by_host(host).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_hosts() {
+        let allowed = AllowedHosts::new(&["Example.com", "*.apps.example.com"]).unwrap();
+        assert!(allowed.allows("example.com"));
+        assert!(allowed.allows("api.apps.example.com"));
+        assert!(allowed.allows("v2.api.apps.example.com"));
+
+        // The wildcard is for the subdomains only
+        assert!(!allowed.allows("apps.example.com"));
+        assert!(!allowed.allows("evilapps.example.com"));
+        assert!(!allowed.allows("www.example.com"));
+
+        assert!(AllowedHosts::new(&[] as &[&str]).is_none());
+    }
+
+    #[test]
+    fn test_route_hosts_are_accepted() {
+        stores::insert_route(
+            "routed.allowed.test".to_string(),
+            stores::routes::RouteStoreContainer::default(),
+        );
+
+        let allowed = AllowedHosts::new(&["example.com"]).unwrap();
+        assert!(allowed.accepts("routed.allowed.test"));
+        assert!(!allowed.accepts("forged.allowed.test"));
+    }
+}
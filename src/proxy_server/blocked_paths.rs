@@ -0,0 +1,136 @@
+//! Paths of a route answered with a 403 or a 404 before any plugin runs or any
+//! upstream is picked (`blocked_paths`), such as the `.git` or `.env` files
+//! deployed by mistake.
+//!
+//! The rules match the normalized path (see `tools::path`), so `/%2egit/config`
+//! or `/static/../.git/config` are blocked like `/.git/config`.
+
+use std::collections::HashSet;
+
+use regex::RegexSet;
+
+use crate::{
+    config::{BlockedPath, BlockedPathsPreset, RouteBlockedPaths},
+    tools::path,
+};
+
+/// Version control metadata, SSH keys, environment and server access files,
+/// anywhere in the path
+const COMMON_PATTERN: &str =
+    r"/\.(git|svn|hg|ssh|htpasswd|htaccess|DS_Store)(/|$)|/\.env(\.[^/]*)?$";
+
+/// The blocked paths of a route
+#[derive(Debug)]
+pub struct BlockedPaths {
+    exact: HashSet<String>,
+    prefixes: Vec<String>,
+    patterns: RegexSet,
+    pub status: u16,
+}
+
+impl BlockedPaths {
+    /// Compiles the rules of a route, including the ones of its preset
+    pub fn new(config: &RouteBlockedPaths) -> Result<Self, regex::Error> {
+        let mut exact = HashSet::new();
+        let mut prefixes = vec![];
+        let mut patterns = vec![];
+
+        if config.preset == Some(BlockedPathsPreset::Common) {
+            patterns.push(COMMON_PATTERN.to_string());
+        }
+
+        for rule in &config.paths {
+            match rule {
+                BlockedPath::Exact(path) => {
+                    exact.insert(without_trailing_slash(&path::normalize(path)).to_string());
+                }
+                BlockedPath::Prefix(prefix) => prefixes.push(prefix.to_string()),
+                BlockedPath::Regex(pattern) => patterns.push(pattern.to_string()),
+            }
+        }
+
+        Ok(BlockedPaths {
+            exact,
+            prefixes,
+            patterns: RegexSet::new(patterns)?,
+            status: config.status,
+        })
+    }
+
+    /// Whether the (normalized) path of a request is blocked
+    pub fn is_blocked(&self, path: &str) -> bool {
+        self.exact.contains(without_trailing_slash(path))
+            || self.prefixes.iter().any(|prefix| path.starts_with(prefix))
+            || self.patterns.is_match(path)
+    }
+}
+
+/// The exact rules match with or without a trailing slash (`/metrics/` like `/metrics`)
+fn without_trailing_slash(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(file) if !file.is_empty() => file,
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocked_paths(preset: Option<BlockedPathsPreset>, paths: Vec<BlockedPath>) -> BlockedPaths {
+        BlockedPaths::new(&RouteBlockedPaths {
+            preset,
+            status: 404,
+            paths,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_common_preset() {
+        let blocked = blocked_paths(Some(BlockedPathsPreset::Common), vec![]);
+
+        for path in [
+            "/.git/config",
+            "/.git",
+            "/app/.svn/entries",
+            "/.env",
+            "/.env.production",
+            "/%2Eenv",
+            "/assets/../.ssh/authorized_keys",
+            "/.htpasswd",
+        ] {
+            assert!(blocked.is_blocked(&path::normalize(path)), "{path}");
+        }
+
+        for path in [
+            "/",
+            "/.well-known/acme-challenge/token",
+            "/gitlab",
+            "/.environment/x",
+        ] {
+            assert!(!blocked.is_blocked(&path::normalize(path)), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_blocked_path_rules() {
+        let blocked = blocked_paths(
+            None,
+            vec![
+                BlockedPath::Exact("/metrics".to_string()),
+                BlockedPath::Prefix("/internal/".to_string()),
+                BlockedPath::Regex(r"\.(bak|sql)$".to_string()),
+            ],
+        );
+
+        assert!(blocked.is_blocked(&path::normalize("/metrics")));
+        assert!(blocked.is_blocked(&path::normalize("//metrics/")));
+        assert!(!blocked.is_blocked(&path::normalize("/metrics/cpu")));
+        assert!(blocked.is_blocked(&path::normalize("/internal/users")));
+        assert!(blocked.is_blocked(&path::normalize("/public/%2e%2e/internal/users")));
+        assert!(!blocked.is_blocked(&path::normalize("/internals")));
+        assert!(blocked.is_blocked(&path::normalize("/dump.sql")));
+        assert!(!blocked.is_blocked(&path::normalize("/dump.sql.html")));
+    }
+}
@@ -0,0 +1,214 @@
+//! The bodies of the upstream responses against their `Content-Length`.
+//!
+//! An upstream sending fewer bytes than announced closes the connection (or
+//! times out) mid-body: the response is truncated (see `truncated_responses`)
+//! and the connection dropped. One sending more has the extra bytes read
+//! along with the end of the body discarded by pingora; the ones sent later
+//! fail the next response of the connection, which is then retried on a new
+//! one. HTTP/2 upstreams have their streams reset on any mismatch.
+//!
+//! The responses framed both by a `Transfer-Encoding` and a `Content-Length`
+//! (or by `Content-Length`s that differ) can be read in different ways, the
+//! way request smuggling desyncs connections. They are rejected, or relayed
+//! with the framing pingora reads them with (`body_length_mismatches.action`).
+//!
+//! Every mismatch is logged with its route and upstream, and counted.
+
+use bytes::Bytes;
+use http::{header, HeaderValue, StatusCode};
+use pingora::{http::ResponseHeader, protocols::l4::socket::SocketAddr};
+
+use crate::metrics;
+
+/// How the body of a response did not match its `Content-Length`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// Fewer bytes than announced
+    Short,
+    /// More bytes than announced
+    Long,
+    /// Both a `Transfer-Encoding` and a `Content-Length`, or `Content-Length`s that differ
+    Conflicting,
+}
+
+impl Mismatch {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mismatch::Short => "short",
+            Mismatch::Long => "long",
+            Mismatch::Conflicting => "conflicting",
+        }
+    }
+}
+
+/// The body of the upstream response of a request, as announced and as received
+#[derive(Debug, Default)]
+pub struct BodyLength {
+    expected: Option<usize>,
+    received: usize,
+    conflicting: bool,
+}
+
+impl BodyLength {
+    /// Reads the framing of the final response of the upstream, before it is
+    /// changed by the filters
+    pub fn response(&mut self, response: &ResponseHeader, head_request: bool) {
+        let lengths = response
+            .headers
+            .get_all(header::CONTENT_LENGTH)
+            .iter()
+            .map(parse_length)
+            .collect::<Vec<_>>();
+        let chunked = response.headers.contains_key(header::TRANSFER_ENCODING);
+        // After a `101`, the bytes are the ones of the protocol switched to
+        let no_body = head_request
+            || matches!(
+                response.status,
+                StatusCode::SWITCHING_PROTOCOLS | StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+            );
+
+        self.conflicting =
+            !lengths.is_empty() && (chunked || lengths.windows(2).any(|pair| pair[0] != pair[1]));
+        self.expected = match lengths.first() {
+            Some(Some(length)) if !chunked && !no_body && !self.conflicting => Some(*length),
+            _ => None,
+        };
+        self.received = 0;
+    }
+
+    /// Whether the framing of the response is ambiguous
+    pub fn is_conflicting(&self) -> bool {
+        self.conflicting
+    }
+
+    /// Counts a chunk of the body, the mismatch if any once it ends
+    pub fn body(&mut self, body: Option<&Bytes>, end_of_stream: bool) -> Option<Mismatch> {
+        self.received += body.map_or(0, Bytes::len);
+        if !end_of_stream {
+            return None;
+        }
+
+        match self.expected? {
+            expected if self.received < expected => Some(Mismatch::Short),
+            expected if self.received > expected => Some(Mismatch::Long),
+            _ => None,
+        }
+    }
+
+    /// The mismatch of a body its upstream failed to send entirely
+    pub fn truncated(&self) -> Option<Mismatch> {
+        self.expected
+            .is_some_and(|expected| self.received < expected)
+            .then_some(Mismatch::Short)
+    }
+}
+
+fn parse_length(value: &HeaderValue) -> Option<usize> {
+    value.to_str().ok()?.trim().parse().ok()
+}
+
+/// Leaves the ambiguous response with the framing pingora reads its body with:
+/// the `Transfer-Encoding`, otherwise the first `Content-Length`
+pub fn normalize(response: &mut ResponseHeader) -> pingora::Result<()> {
+    let first = response.headers.get(header::CONTENT_LENGTH).cloned();
+    response.remove_header(&header::CONTENT_LENGTH);
+
+    match first {
+        Some(length) if !response.headers.contains_key(header::TRANSFER_ENCODING) => {
+            response.insert_header(header::CONTENT_LENGTH, length)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Logs and counts the mismatch of the response of the upstream of the route
+pub fn report(mismatch: Mismatch, route: &str, upstream: Option<&SocketAddr>) {
+    metrics::UPSTREAM_BODY_LENGTH_MISMATCHES
+        .with_label_values(&[route, mismatch.as_str()])
+        .inc();
+    let upstream = upstream.map(ToString::to_string);
+    tracing::warn!(
+        route,
+        upstream,
+        kind = mismatch.as_str(),
+        "upstream response body does not match its Content-Length"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)]) -> ResponseHeader {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in headers {
+            response.append_header(*name, *value).unwrap();
+        }
+        response
+    }
+
+    #[test]
+    fn test_body_against_its_length() {
+        let mut length = BodyLength::default();
+        length.response(&response(&[("content-length", "10")]), false);
+        assert_eq!(
+            length.body(Some(&Bytes::from_static(b"hello")), false),
+            None
+        );
+        assert_eq!(length.truncated(), Some(Mismatch::Short));
+        assert_eq!(length.body(Some(&Bytes::from_static(b"world")), true), None);
+        assert_eq!(length.truncated(), None);
+
+        length.response(&response(&[("content-length", "4")]), false);
+        assert_eq!(
+            length.body(Some(&Bytes::from_static(b"hello")), true),
+            Some(Mismatch::Long)
+        );
+
+        // Nothing is announced by chunked responses, nor sent for HEAD requests
+        length.response(&response(&[("transfer-encoding", "chunked")]), false);
+        assert_eq!(length.body(None, true), None);
+        length.response(&response(&[("content-length", "10")]), true);
+        assert_eq!(length.body(None, true), None);
+
+        // Nor by the switch of protocol of an upgrade
+        let mut switching = ResponseHeader::build(101, None).unwrap();
+        switching.insert_header("content-length", "10").unwrap();
+        length.response(&switching, false);
+        assert_eq!(length.body(Some(&Bytes::from_static(b"frame")), true), None);
+    }
+
+    #[test]
+    fn test_conflicting_framing() {
+        let mut length = BodyLength::default();
+        length.response(&response(&[("content-length", "10")]), false);
+        assert!(!length.is_conflicting());
+
+        let mut chunked = response(&[("transfer-encoding", "chunked"), ("content-length", "10")]);
+        length.response(&chunked, false);
+        assert!(length.is_conflicting());
+        assert_eq!(length.truncated(), None);
+        normalize(&mut chunked).unwrap();
+        assert!(chunked.headers.get(header::CONTENT_LENGTH).is_none());
+
+        let mut lengths = response(&[("content-length", "10"), ("content-length", "12")]);
+        length.response(&lengths, false);
+        assert!(length.is_conflicting());
+        normalize(&mut lengths).unwrap();
+        assert_eq!(
+            lengths
+                .headers
+                .get_all(header::CONTENT_LENGTH)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["10"]
+        );
+
+        // The same length repeated is no conflict
+        length.response(
+            &response(&[("content-length", "10"), ("content-length", "10")]),
+            false,
+        );
+        assert!(!length.is_conflicting());
+    }
+}
@@ -4,33 +4,96 @@ use pingora::listeners::TlsAccept;
 use pingora::tls::ext;
 use pingora::tls::ssl::NameType;
 
-use crate::stores::{self};
+use crate::{
+    config::{Config, UnknownSni},
+    stores::{self, certificates::Certificate},
+};
+
+use super::{connections, matching::normalize_host};
+
+/// Common name of the fallback certificate
+const FALLBACK_CERT_NAME: &str = "proksi.fallback";
 
 /// Provides the correct certificates when performing SSL handshakes
 #[derive(Debug, Clone)]
-pub struct CertStore {}
+pub struct CertStore {
+    /// Presented when no certificate exists for the requested server name
+    fallback: Option<Certificate>,
+}
 
 impl CertStore {
-    pub fn new() -> Self {
-        CertStore {}
+    pub fn new(fallback_cert: bool) -> Self {
+        let fallback = fallback_cert
+            .then(|| {
+                Certificate::self_signed(FALLBACK_CERT_NAME)
+                    .map_err(|e| tracing::error!("failed to create the fallback certificate: {e}"))
+                    .ok()
+            })
+            .flatten();
+
+        CertStore { fallback }
+    }
+
+    /// Loads the certificate (and chain) into the SSL session
+    fn use_certificate(ssl: &mut SslRef, cert: &Certificate) {
+        ext::ssl_use_private_key(ssl, &cert.key).unwrap();
+        ext::ssl_use_certificate(ssl, &cert.leaf).unwrap();
+
+        for intermediate in &cert.chain {
+            ext::ssl_add_chain_cert(ssl, intermediate).unwrap();
+        }
+
+        connections::certified(ssl);
     }
 
     // This function is called when the servername callback executes
-    // It is used to check if the server name is in the certificate store
-    // If it is, the handshake continues, otherwise it is aborted
-    // and the client is disconnected
-    #[allow(clippy::unnecessary_wraps)]
-    pub fn sni_callback(ssl_ref: &mut SslRef) -> Result<(), SniError> {
-        let servername = ssl_ref.servername(NameType::HOST_NAME).unwrap_or("");
+    // It is used to check if the server name is known (a route or a
+    // certificate exists for it). If it is not, the handshake is aborted
+    // and the client disconnected, unless `tls.unknown_sni` is `fallback`.
+    // Clients sending no server name are not rejected.
+    pub fn sni_callback(ssl_ref: &mut SslRef, unknown_sni: UnknownSni) -> Result<(), SniError> {
+        let servername = ssl_ref
+            .servername(NameType::HOST_NAME)
+            .unwrap_or("")
+            .to_string();
         tracing::debug!("Received SNI: {}", servername);
+        connections::server_name(ssl_ref, &servername);
+
+        if unknown_sni == UnknownSni::Reject && !servername.is_empty() && !is_known(&servername) {
+            tracing::debug!("rejecting the handshake for unknown server name {servername}");
+            connections::no_certificate(ssl_ref, &servername);
+            return Err(SniError::ALERT_FATAL);
+        }
 
-        // if stores::get_certificate_by_key(servername).is_some() {
         Ok(())
-        // }
+    }
+}
+
+/// Loads the certificates provided as files (`tls.certificates`) into the store,
+/// where they take precedence over the ones of ACME
+pub fn load_from_config(config: &Config) -> Result<(), anyhow::Error> {
+    for certificate in &config.tls.certificates {
+        let host = normalize_host(&certificate.host);
+        let loaded = Certificate::from_files(&certificate.cert_path, &certificate.key_path)
+            .map_err(|err| anyhow::anyhow!("invalid tls.certificates for host {host}: {err}"))?;
 
-        // Abort the handshake
-        // Err(SniError::ALERT_FATAL)
+        if let Some(issue) = loaded.chain_issue() {
+            tracing::warn!(
+                "certificate of host {host} ({:?}) {issue}",
+                certificate.cert_path
+            );
+        }
+
+        stores::insert_manual_certificate(host.into_owned(), loaded);
     }
+
+    Ok(())
+}
+
+/// Whether a route or a certificate exists for the server name
+fn is_known(server_name: &str) -> bool {
+    stores::find_route_by_host(server_name).is_some()
+        || stores::get_certificate_by_key(server_name).is_some()
 }
 
 #[async_trait]
@@ -40,20 +103,111 @@ impl TlsAccept for CertStore {
     /// based on the server name
     async fn certificate_callback(&self, ssl: &mut pingora::tls::ssl::SslRef) {
         // Due to the sni_callback function, we can safely unwrap here
-        let host_name = ssl.servername(NameType::HOST_NAME).unwrap_or_default();
+        let host_name = ssl
+            .servername(NameType::HOST_NAME)
+            .unwrap_or_default()
+            .to_string();
 
-        let Some(cert) = stores::get_certificate_by_key(host_name) else {
+        // Routes with mTLS request a client certificate. Without it, the
+        // handshake is aborted (no certificate is presented).
+        if let Some(client_auth) = stores::find_client_auth_by_host(&host_name) {
+            if let Err(err) = client_auth.apply(ssl) {
+                tracing::error!("failed to request a client certificate for {host_name}: {err}");
+                return;
+            }
+        }
+
+        let Some(cert) = stores::get_certificate_by_key(&host_name) else {
             tracing::debug!("No certificate found for host: {:?}", host_name);
+
+            match &self.fallback {
+                Some(fallback) => Self::use_certificate(ssl, fallback),
+                None => connections::no_certificate(ssl, &host_name),
+            }
             return;
         };
 
-        let cert = cert.value();
+        Self::use_certificate(ssl, cert.value());
+    }
+}
 
-        ext::ssl_use_private_key(ssl, &cert.key).unwrap();
-        ext::ssl_use_certificate(ssl, &cert.leaf).unwrap();
+#[cfg(test)]
+mod tests {
+    use pingora::lb::{selection::RoundRobin, LoadBalancer};
 
-        if let Some(chain) = &cert.chain {
-            ext::ssl_add_chain_cert(ssl, chain).unwrap();
-        }
+    use crate::stores::routes::RouteStoreContainer;
+
+    use super::*;
+
+    #[test]
+    fn test_server_name_is_known() {
+        assert!(!is_known("unknown-sni.test"));
+
+        let route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4002"]).unwrap(),
+        );
+        stores::insert_route("known-sni.test".to_string(), route);
+        assert!(is_known("known-sni.test"));
+        assert!(is_known("Known-SNI.test"));
+
+        let certificate = Certificate::self_signed("certified-sni.test").unwrap();
+        stores::insert_certificate("certified-sni.test".to_string(), certificate);
+        assert!(is_known("certified-sni.test"));
+    }
+
+    #[test]
+    fn test_wildcard_certificate_serves_the_subdomains() {
+        let certificate = Certificate::self_signed("*.wildcard-sni.test").unwrap();
+        stores::insert_certificate("*.wildcard-sni.test".to_string(), certificate);
+
+        assert!(is_known("api.wildcard-sni.test"));
+        let served = stores::get_certificate_by_key("api.wildcard-sni.test").unwrap();
+        assert_eq!(served.key(), "*.wildcard-sni.test");
+
+        // A wildcard only covers a single label
+        assert!(!is_known("wildcard-sni.test"));
+        assert!(!is_known("v1.api.wildcard-sni.test"));
+    }
+
+    #[test]
+    fn test_provided_certificates_win_over_acme() {
+        let dir = std::env::temp_dir().join(format!("proksi-certs-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let provided = Certificate::self_signed("*.provided-sni.test").unwrap();
+        std::fs::write(dir.join("cert.pem"), provided.leaf.to_pem().unwrap()).unwrap();
+        std::fs::write(
+            dir.join("key.pem"),
+            provided.key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        config.tls.certificates = vec![crate::config::TlsCertificate {
+            host: "*.Provided-SNI.test".into(),
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        }];
+        load_from_config(&config).unwrap();
+
+        // The certificate of ACME for the host is not served
+        let acme = Certificate::self_signed("api.provided-sni.test").unwrap();
+        stores::insert_certificate("api.provided-sni.test".to_string(), acme);
+        let served = stores::get_certificate_by_key("api.provided-sni.test").unwrap();
+        assert_eq!(served.key(), "*.provided-sni.test");
+        assert_eq!(
+            served.leaf.to_der().unwrap(),
+            provided.leaf.to_der().unwrap()
+        );
+        assert!(stores::has_manual_certificate("api.provided-sni.test"));
+        assert!(!stores::has_manual_certificate("provided-sni.test"));
+
+        config.tls.certificates[0].key_path = dir.join("missing.pem");
+        let err = load_from_config(&config).unwrap_err().to_string();
+        assert!(
+            err.contains("invalid tls.certificates for host *.provided-sni.test"),
+            "{err}"
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
     }
 }
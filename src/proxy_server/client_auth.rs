@@ -0,0 +1,527 @@
+//! Verification of the client certificates of the routes with `ssl.client_auth`
+//! (mTLS), including their revocation.
+//!
+//! The certificate revocation lists (CRL) are loaded at startup and then
+//! refreshed periodically (see `services::crl`). A CRL that fails to load keeps
+//! the previous version until it expires, after which it is unavailable.
+//!
+//! With a `key_policy`, the handshake of a valid certificate whose key is of
+//! another type (or too small) goes on, and its requests are answered with a
+//! `403`: the client is told why, instead of a failed handshake.
+
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use dashmap::DashSet;
+use once_cell::sync::Lazy;
+use openssl::{
+    asn1::Asn1Time,
+    error::ErrorStack,
+    hash::MessageDigest,
+    pkey::Id,
+    ssl::{SslRef, SslVerifyMode},
+    stack::Stack,
+    x509::{store::X509StoreBuilder, CrlStatus, X509Crl, X509Ref, X509StoreContextRef, X509},
+};
+use pingora::proxy::Session;
+
+use crate::{
+    config::{Config, KeyAlgorithm, RouteSslClientAuth, RouteSslKeyPolicy},
+    stores,
+};
+
+use super::connections::{self, HandshakeFailure};
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// Where a CRL is loaded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrlSource {
+    Path(PathBuf),
+    Url(String),
+}
+
+/// What the revocation lists tell about a certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Revocation {
+    NotRevoked,
+    Revoked,
+    /// Not revoked by the available lists, but a list is unavailable
+    Unknown,
+}
+
+/// The client certificates accepted by a route
+pub struct ClientAuth {
+    host: String,
+    /// CAs issuing the client certificates
+    cas: Vec<X509>,
+    crl_sources: Vec<CrlSource>,
+    crl_refresh: Duration,
+    fail_open: bool,
+    /// The CRL of each source, `None` while it is unavailable
+    crls: ArcSwap<Vec<Option<Arc<X509Crl>>>>,
+    key_policy: Option<RouteSslKeyPolicy>,
+    /// SHA-256 digests of the valid certificates whose key the policy rejects.
+    /// Only the certificates issued by the CAs get here, so it stays small.
+    rejected_keys: DashSet<Vec<u8>>,
+}
+
+impl ClientAuth {
+    /// Loads the CAs and the CRL files (the URLs are only fetched by [`Self::refresh`])
+    pub fn new(host: &str, config: &RouteSslClientAuth) -> Result<Self, anyhow::Error> {
+        let pem = fs::read(&config.ca)
+            .map_err(|err| anyhow!("failed to read the client CAs {:?}: {err}", config.ca))?;
+        let cas = X509::stack_from_pem(&pem)?;
+        if cas.is_empty() {
+            return Err(anyhow!("no client CA found in {:?}", config.ca));
+        }
+
+        let (crl_sources, crl_refresh, fail_open) = match &config.crl {
+            Some(crl) => {
+                let paths = crl.paths.iter().cloned().map(CrlSource::Path);
+                let urls = crl.urls.iter().map(|url| CrlSource::Url(url.to_string()));
+                (
+                    paths.chain(urls).collect(),
+                    Duration::from_secs(crl.refresh_secs),
+                    crl.fail_open,
+                )
+            }
+            None => (Vec::new(), Duration::ZERO, false),
+        };
+
+        let auth = ClientAuth {
+            host: host.to_string(),
+            cas,
+            crl_sources,
+            crl_refresh,
+            fail_open,
+            crls: ArcSwap::from_pointee(Vec::new()),
+            key_policy: config.key_policy.clone(),
+            rejected_keys: DashSet::new(),
+        };
+
+        let crls = auth
+            .crl_sources
+            .iter()
+            .map(|source| match source {
+                CrlSource::Path(path) => auth.load(source, fs::read(path).map_err(Into::into)),
+                CrlSource::Url(_) => None,
+            })
+            .collect();
+        auth.crls.store(Arc::new(crls));
+
+        Ok(auth)
+    }
+
+    pub fn has_crls(&self) -> bool {
+        !self.crl_sources.is_empty()
+    }
+
+    /// How often the CRLs are loaded again
+    pub fn crl_refresh(&self) -> Duration {
+        self.crl_refresh
+    }
+
+    /// Loads every CRL again. A CRL that fails to load is kept until it expires.
+    pub async fn refresh(&self) {
+        let previous = self.crls.load_full();
+        let mut crls = Vec::with_capacity(self.crl_sources.len());
+
+        for (index, source) in self.crl_sources.iter().enumerate() {
+            let content = match source {
+                CrlSource::Path(path) => fs::read(path).map_err(Into::into),
+                CrlSource::Url(url) => fetch(url).await,
+            };
+
+            let crl = self.load(source, content).or_else(|| {
+                previous
+                    .get(index)
+                    .and_then(Option::as_ref)
+                    .filter(|crl| !is_expired(crl))
+                    .cloned()
+            });
+            crls.push(crl);
+        }
+
+        if crls.iter().any(Option::is_none) {
+            tracing::warn!(
+                "a CRL of host {} is unavailable, its clients are {}",
+                self.host,
+                if self.fail_open {
+                    "accepted unless revoked by another CRL"
+                } else {
+                    "rejected"
+                }
+            );
+        }
+
+        self.crls.store(Arc::new(crls));
+    }
+
+    /// Parses a CRL (PEM or DER) signed by one of the CAs, `None` when it is invalid
+    fn load(
+        &self,
+        source: &CrlSource,
+        content: Result<Vec<u8>, anyhow::Error>,
+    ) -> Option<Arc<X509Crl>> {
+        let result = content.and_then(|content| {
+            let crl = X509Crl::from_pem(&content).or_else(|_| X509Crl::from_der(&content))?;
+
+            let signed = self.cas.iter().any(|ca| {
+                ca.subject_name()
+                    .try_cmp(crl.issuer_name())
+                    .is_ok_and(|ordering| ordering.is_eq())
+                    && ca
+                        .public_key()
+                        .and_then(|key| crl.verify(&key))
+                        .unwrap_or(false)
+            });
+            if !signed {
+                return Err(anyhow!("the CRL is not signed by a client CA"));
+            }
+
+            if is_expired(&crl) {
+                return Err(anyhow!("the CRL expired"));
+            }
+
+            Ok(crl)
+        });
+
+        match result {
+            Ok(crl) => Some(Arc::new(crl)),
+            Err(err) => {
+                tracing::error!(
+                    "failed to load a CRL of host {} {source:?}: {err}",
+                    self.host
+                );
+                None
+            }
+        }
+    }
+
+    /// Whether the certificate is revoked by one of the CRLs
+    pub fn revocation(&self, cert: &X509Ref) -> Revocation {
+        let crls = self.crls.load();
+        let mut unavailable = false;
+
+        for crl in crls.iter() {
+            let Some(crl) = crl else {
+                unavailable = true;
+                continue;
+            };
+
+            let issued_by = crl
+                .issuer_name()
+                .try_cmp(cert.issuer_name())
+                .is_ok_and(|ordering| ordering.is_eq());
+            if issued_by
+                && matches!(
+                    crl.get_by_serial(cert.serial_number()),
+                    CrlStatus::Revoked(_)
+                )
+            {
+                return Revocation::Revoked;
+            }
+        }
+
+        if unavailable {
+            Revocation::Unknown
+        } else {
+            Revocation::NotRevoked
+        }
+    }
+
+    /// Verify callback of the handshake, called for each certificate of the chain
+    fn verify(&self, preverified: bool, ctx: &mut X509StoreContextRef) -> bool {
+        let accepted = preverified
+            && self.verify_revocation(ctx)
+            && (ctx.error_depth() != 0 || self.verify_key(ctx));
+        if !accepted {
+            connections::handshake_failed(HandshakeFailure::ClientCertificate, Some(&self.host));
+        }
+
+        accepted
+    }
+
+    fn verify_revocation(&self, ctx: &X509StoreContextRef) -> bool {
+        let Some(cert) = ctx.current_cert() else {
+            return false;
+        };
+
+        match self.revocation(cert) {
+            Revocation::NotRevoked => true,
+            Revocation::Unknown => self.fail_open,
+            Revocation::Revoked => {
+                tracing::debug!(
+                    "rejecting a revoked client certificate of host {} (serial {:?})",
+                    self.host,
+                    cert.serial_number().to_bn().and_then(|bn| bn.to_hex_str())
+                );
+                false
+            }
+        }
+    }
+
+    /// Remembers the client certificate (the leaf) when its key is rejected
+    /// by the policy, failing the handshake only when it cannot be remembered
+    fn verify_key(&self, ctx: &X509StoreContextRef) -> bool {
+        let (Some(policy), Some(cert)) = (&self.key_policy, ctx.current_cert()) else {
+            return true;
+        };
+
+        let Some(reason) = key_violation(policy, cert) else {
+            return true;
+        };
+
+        let Ok(digest) = cert.digest(MessageDigest::sha256()) else {
+            return false;
+        };
+
+        tracing::debug!(
+            "rejecting the key of a client certificate of host {}: {reason}",
+            self.host
+        );
+        self.rejected_keys.insert(digest.to_vec());
+        true
+    }
+
+    /// Whether the key of the client certificate of the session is rejected by the policy
+    pub fn rejects_key(&self, session: &Session) -> bool {
+        !self.rejected_keys.is_empty()
+            && session
+                .digest()
+                .and_then(|digest| digest.ssl_digest.as_ref())
+                .is_some_and(|ssl| self.rejected_keys.contains(&ssl.cert_digest))
+    }
+
+    /// Requests (and requires) a client certificate issued by one of the CAs
+    pub fn apply(self: &Arc<Self>, ssl: &mut SslRef) -> Result<(), ErrorStack> {
+        let mut store = X509StoreBuilder::new()?;
+        let mut names = Stack::new()?;
+        for ca in &self.cas {
+            store.add_cert(ca.clone())?;
+            names.push(ca.subject_name().to_owned()?)?;
+        }
+        ssl.set_verify_cert_store(store.build())?;
+        ssl.set_client_ca_list(names);
+
+        let auth = self.clone();
+        ssl.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            move |preverified, ctx| auth.verify(preverified, ctx),
+        );
+
+        Ok(())
+    }
+}
+
+/// Why the key of the certificate is not accepted by the policy, if it is not
+pub fn key_violation(policy: &RouteSslKeyPolicy, cert: &X509Ref) -> Option<String> {
+    let Ok(key) = cert.public_key() else {
+        return Some("unreadable key".to_string());
+    };
+
+    let (algorithm, min_bits) = match key.id() {
+        Id::RSA => (KeyAlgorithm::Rsa, Some(policy.min_rsa_bits)),
+        Id::EC => (KeyAlgorithm::Ec, Some(policy.min_ec_bits)),
+        Id::ED25519 => (KeyAlgorithm::Ed25519, None),
+        Id::ED448 => (KeyAlgorithm::Ed448, None),
+        _ => return Some("unsupported key type".to_string()),
+    };
+
+    if !policy.algorithms.contains(&algorithm) {
+        return Some(format!("{algorithm:?} keys are not accepted"));
+    }
+
+    let bits = key.bits();
+    if min_bits.is_some_and(|min_bits| bits < min_bits) {
+        return Some(format!("{bits}-bit {algorithm:?} key"));
+    }
+
+    None
+}
+
+/// Whether the next update of the CRL is past
+fn is_expired(crl: &X509Crl) -> bool {
+    let Ok(now) = Asn1Time::days_from_now(0) else {
+        return false;
+    };
+
+    crl.next_update().is_some_and(|next| next < now)
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let response = HTTP_CLIENT
+        .get(url)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Whether the client of the session presented a (verified) certificate
+pub fn has_client_certificate(session: &Session) -> bool {
+    session
+        .digest()
+        .and_then(|digest| digest.ssl_digest.as_ref())
+        .is_some_and(|ssl| !ssl.cert_digest.is_empty())
+}
+
+/// Loads the client authentication of the routes into the store (not the
+/// routes shadowed by another one of the host, which are not served)
+pub fn load_from_config(config: &Config) -> Result<(), anyhow::Error> {
+    for route in &config.routes {
+        if config
+            .routes
+            .iter()
+            .any(|other| route.is_shadowed_by(other))
+        {
+            continue;
+        }
+
+        let Some(client_auth) = route.ssl.as_ref().and_then(|ssl| ssl.client_auth.as_ref()) else {
+            continue;
+        };
+
+        let auth = ClientAuth::new(&route.host, client_auth)
+            .map_err(|err| anyhow!("invalid ssl.client_auth for host {}: {err}", route.host))?;
+        stores::insert_client_auth(&route.host, Arc::new(auth));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        x509::{X509Name, X509NameBuilder},
+    };
+
+    use super::*;
+    use crate::stores::routes::RouteStoreContainer;
+
+    fn name(common_name: &str) -> X509Name {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        name.build()
+    }
+
+    fn cert(serial: u32) -> X509 {
+        signed(
+            serial,
+            &PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+        )
+    }
+
+    fn signed(serial: u32, key: &PKey<Private>) -> X509 {
+        let mut cert = X509::builder().unwrap();
+        let serial = BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name("client")).unwrap();
+        cert.set_issuer_name(&name("proksi-ca")).unwrap();
+        cert.set_pubkey(key).unwrap();
+        cert.sign(key, MessageDigest::sha256()).unwrap();
+        cert.build()
+    }
+
+    fn client_auth(crl_sources: Vec<CrlSource>, fail_open: bool) -> ClientAuth {
+        let crls = crl_sources.iter().map(|_| None).collect();
+        ClientAuth {
+            host: "example.com".to_string(),
+            cas: vec![cert(1)],
+            crl_sources,
+            crl_refresh: Duration::from_secs(3600),
+            fail_open,
+            crls: ArcSwap::from_pointee(crls),
+            key_policy: None,
+            rejected_keys: DashSet::new(),
+        }
+    }
+
+    #[test]
+    fn test_client_auth_by_host() {
+        for host in [
+            "Secure.Client-Auth.Test.",
+            "*.wildcard.client-auth.test",
+            r"~^api-\d+\.client-auth\.test$",
+        ] {
+            stores::insert_route(host.to_string(), RouteStoreContainer::default());
+            stores::insert_client_auth(host, Arc::new(client_auth(Vec::new(), false)));
+        }
+
+        // Looked up as the routes are, not by the host as written
+        for host in [
+            "secure.client-auth.test",
+            "SECURE.Client-Auth.test",
+            "secure.client-auth.test.:443",
+            "a.wildcard.client-auth.test",
+            "API-1.client-auth.test",
+        ] {
+            assert!(stores::find_client_auth_by_host(host).is_some(), "{host}");
+        }
+
+        // A route without client authentication (or an unknown host)
+        stores::insert_route(
+            "open.wildcard.client-auth.test".to_string(),
+            RouteStoreContainer::default(),
+        );
+        assert!(stores::find_client_auth_by_host("open.wildcard.client-auth.test").is_none());
+        assert!(stores::find_client_auth_by_host("other.client-auth.test").is_none());
+    }
+
+    #[test]
+    fn test_revocation_without_crls() {
+        let auth = client_auth(Vec::new(), false);
+        assert!(!auth.has_crls());
+        assert_eq!(auth.revocation(&cert(2)), Revocation::NotRevoked);
+    }
+
+    #[test]
+    fn test_unavailable_crl_is_unknown() {
+        let source = CrlSource::Url("https://ca.example.com/clients.crl".to_string());
+        let auth = client_auth(vec![source.clone()], true);
+        assert!(auth.has_crls());
+        assert_eq!(auth.revocation(&cert(2)), Revocation::Unknown);
+
+        // Invalid contents are not loaded
+        assert!(auth.load(&source, Ok(b"not a crl".to_vec())).is_none());
+        assert!(auth
+            .load(&source, Err(anyhow!("connection refused")))
+            .is_none());
+    }
+
+    #[test]
+    fn test_key_violation() {
+        let policy: RouteSslKeyPolicy = serde_json::from_str("{}").unwrap();
+        let rsa = |bits| PKey::from_rsa(Rsa::generate(bits).unwrap()).unwrap();
+        let ec = |nid| {
+            let group = EcGroup::from_curve_name(nid).unwrap();
+            PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+        };
+
+        assert!(key_violation(&policy, &signed(1, &rsa(2048))).is_none());
+        assert!(key_violation(&policy, &signed(1, &ec(Nid::X9_62_PRIME256V1))).is_none());
+        assert_eq!(
+            key_violation(&policy, &signed(1, &rsa(1024))).as_deref(),
+            Some("1024-bit Rsa key")
+        );
+        assert!(key_violation(&policy, &signed(1, &ec(Nid::SECP224R1))).is_some());
+
+        let ec_only = RouteSslKeyPolicy {
+            algorithms: vec![KeyAlgorithm::Ec],
+            ..policy
+        };
+        assert_eq!(
+            key_violation(&ec_only, &signed(1, &rsa(2048))).as_deref(),
+            Some("Rsa keys are not accepted")
+        );
+    }
+}
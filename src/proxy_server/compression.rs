@@ -0,0 +1,352 @@
+use std::borrow::Cow;
+
+use http::header;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    modules::http::compression::ResponseCompression,
+    protocols::http::compression::Algorithm,
+    proxy::Session,
+};
+
+use crate::config::{Compression, CompressionAlgorithm, RouteCompression};
+
+/// Highest level supported by gzip, brotli goes up to 11 (zstd up to 22)
+const MAX_GZIP_LEVEL: u32 = 9;
+const MAX_BROTLI_LEVEL: u32 = 11;
+const MAX_ZSTD_LEVEL: u32 = 22;
+
+/// Types that are already compressed (or binary) and never worth compressing,
+/// even when they are part of the allowlist
+const NEVER_COMPRESS_PREFIXES: [&str; 3] = ["image/", "video/", "audio/"];
+const NEVER_COMPRESS_TYPES: [&str; 6] = [
+    "application/octet-stream",
+    "application/pdf",
+    "application/wasm",
+    "font/woff",
+    "font/woff2",
+    "binary/octet-stream",
+];
+
+/// Compression settings of a route once merged with the global ones
+#[derive(Debug, PartialEq, Eq)]
+pub struct Settings<'a> {
+    pub level: u32,
+    pub zstd_level: u32,
+    pub algorithms: &'a [CompressionAlgorithm],
+    pub content_types: &'a [Cow<'static, str>],
+    pub min_length: usize,
+}
+
+/// Merges the compression of a route with the global one, the settings of the
+/// route winning over the global ones. Returns `None` when the route is not compressed.
+///
+/// A route with a `compression` block is compressed unless it sets `enabled: false`,
+/// the other routes follow `compression.enabled`.
+pub fn resolve<'a>(
+    route: Option<&'a RouteCompression>,
+    global: &'a Compression,
+) -> Option<Settings<'a>> {
+    let enabled = match route {
+        Some(route) => route.enabled.unwrap_or(true),
+        None => global.enabled,
+    };
+    if !enabled {
+        return None;
+    }
+
+    Some(Settings {
+        level: route.and_then(|r| r.level).unwrap_or(global.level),
+        zstd_level: route
+            .and_then(|r| r.zstd_level)
+            .unwrap_or(global.zstd_level),
+        algorithms: route
+            .and_then(|r| r.algorithms.as_deref())
+            .unwrap_or(&global.algorithms),
+        content_types: route
+            .and_then(|r| r.content_types.as_deref())
+            .unwrap_or(&global.content_types),
+        min_length: route
+            .and_then(|r| r.min_length)
+            .unwrap_or(global.min_length),
+    })
+}
+
+/// The encoding of pingora for the configured one
+fn algorithm(algorithm: CompressionAlgorithm) -> Algorithm {
+    match algorithm {
+        CompressionAlgorithm::Brotli => Algorithm::Brotli,
+        CompressionAlgorithm::Gzip => Algorithm::Gzip,
+        CompressionAlgorithm::Zstd => Algorithm::Zstd,
+    }
+}
+
+/// Picks the encoding used for the response from the `Accept-Encoding` header:
+/// the first of the `preference` the client accepts, regardless of the order
+/// (or weights) they are listed in by the client.
+pub fn negotiate(accept_encoding: &str, preference: &[CompressionAlgorithm]) -> Option<Algorithm> {
+    let mut accepted = Vec::with_capacity(3);
+    let mut any = false;
+
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or_default().to_ascii_lowercase();
+
+        // `q=0` means "not acceptable"
+        let rejected = parts
+            .filter_map(|p| p.strip_prefix("q="))
+            .any(|q| q.parse::<f32>().is_ok_and(|q| q <= 0.0));
+        if rejected {
+            continue;
+        }
+
+        match coding.as_str() {
+            "br" => accepted.push(CompressionAlgorithm::Brotli),
+            "gzip" | "x-gzip" => accepted.push(CompressionAlgorithm::Gzip),
+            "zstd" => accepted.push(CompressionAlgorithm::Zstd),
+            "*" => any = true,
+            _ => {}
+        }
+    }
+
+    preference
+        .iter()
+        .find(|preferred| any || accepted.contains(preferred))
+        .map(|preferred| algorithm(*preferred))
+}
+
+/// Whether a response with the given `Content-Type` should be compressed.
+/// Entries of the allowlist are either a full type (ex: `application/json`)
+/// or a wildcard on the subtype (ex: `text/*`).
+pub fn is_compressible(content_type: &str, allowlist: &[impl AsRef<str>]) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    // SVG is the only image type that is text
+    let binary = mime != "image/svg+xml"
+        && (NEVER_COMPRESS_PREFIXES.iter().any(|p| mime.starts_with(p))
+            || NEVER_COMPRESS_TYPES.contains(&mime.as_str())
+            || mime.contains("zip")
+            || mime.contains("compressed"));
+
+    if mime.is_empty() || binary {
+        return false;
+    }
+
+    allowlist.iter().any(|allowed| {
+        let allowed = allowed.as_ref().trim();
+        match allowed.strip_suffix("/*") {
+            Some(prefix) => mime
+                .split_once('/')
+                .is_some_and(|(kind, _)| kind.eq_ignore_ascii_case(prefix)),
+            None => allowed.eq_ignore_ascii_case(&mime),
+        }
+    })
+}
+
+/// Enables the downstream compression of the session with the level of the route
+/// and the encoding negotiated with the client.
+pub fn enable(session: &mut Session, config: &Settings) {
+    if config.level == 0 {
+        return;
+    }
+
+    let Some(algorithm) = session
+        .req_header()
+        .headers
+        .get(http::header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|accept_encoding| negotiate(accept_encoding, config.algorithms))
+    else {
+        return;
+    };
+
+    let Some(compression) = session
+        .downstream_modules_ctx
+        .get_mut::<ResponseCompression>()
+    else {
+        return;
+    };
+
+    compression.adjust_algorithm_level(Algorithm::Gzip, config.level.min(MAX_GZIP_LEVEL));
+    compression.adjust_algorithm_level(Algorithm::Brotli, config.level.min(MAX_BROTLI_LEVEL));
+    compression.adjust_algorithm_level(Algorithm::Zstd, config.zstd_level.min(MAX_ZSTD_LEVEL));
+
+    // The module only looks at the first accepted encoding, so it is given
+    // the one that was negotiated above instead of the header of the client
+    let Ok(mut request) = RequestHeader::build("GET", b"/", None) else {
+        return;
+    };
+    if request
+        .insert_header(http::header::ACCEPT_ENCODING, algorithm.as_str())
+        .is_ok()
+    {
+        compression.request_filter(&request);
+    }
+}
+
+/// Whether the upstream response is compressed: its content type is allowed,
+/// it is not encoded already and its length (when known) is at least `min_length`
+pub fn should_compress(response: &ResponseHeader, config: &Settings) -> bool {
+    let value = |name| response.headers.get(name).and_then(|v| v.to_str().ok());
+
+    // An encoded response (even `identity`) is never compressed twice
+    if response.headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+
+    let too_short = value(header::CONTENT_LENGTH)
+        .and_then(|length| length.trim().parse::<usize>().ok())
+        .is_some_and(|length| length < config.min_length);
+
+    !too_short
+        && value(header::CONTENT_TYPE).is_some_and(|ct| is_compressible(ct, config.content_types))
+}
+
+/// Disables the compression of the response when it should not be compressed
+/// (see [`should_compress`]). Must be called before the response header is
+/// written downstream.
+pub fn filter_response(session: &mut Session, response: &ResponseHeader, config: &Settings) {
+    let Some(compression) = session
+        .downstream_modules_ctx
+        .get_mut::<ResponseCompression>()
+    else {
+        return;
+    };
+
+    if compression.is_enabled() && !should_compress(response, config) {
+        compression.adjust_level(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_prefers_brotli() {
+        let preference = Compression::default().algorithms;
+        let negotiate = |accept_encoding| negotiate(accept_encoding, &preference);
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Algorithm::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Algorithm::Brotli));
+        assert_eq!(negotiate("gzip, br;q=0"), Some(Algorithm::Gzip));
+        assert_eq!(negotiate("*"), Some(Algorithm::Brotli));
+        assert_eq!(negotiate("deflate, identity"), None);
+        assert_eq!(negotiate(""), None);
+        // Not offered unless configured
+        assert_eq!(negotiate("zstd"), None);
+    }
+
+    #[test]
+    fn test_negotiate_in_order_of_preference() {
+        use CompressionAlgorithm::{Brotli, Gzip, Zstd};
+
+        let preference = [Zstd, Brotli, Gzip];
+        assert_eq!(
+            negotiate("gzip, br, zstd", &preference),
+            Some(Algorithm::Zstd)
+        );
+        assert_eq!(negotiate("gzip, br", &preference), Some(Algorithm::Brotli));
+        assert_eq!(
+            negotiate("zstd;q=0, gzip", &preference),
+            Some(Algorithm::Gzip)
+        );
+        assert_eq!(negotiate("*", &preference), Some(Algorithm::Zstd));
+
+        // Only the configured encodings are offered
+        assert_eq!(negotiate("br", &[Gzip, Zstd]), None);
+    }
+
+    #[test]
+    fn test_resolve_route_over_global() {
+        let global = Compression {
+            level: 9,
+            ..Compression::default()
+        };
+        let route = |enabled, level| RouteCompression {
+            enabled,
+            level,
+            algorithms: None,
+            zstd_level: None,
+            content_types: None,
+            min_length: None,
+        };
+
+        // Globally disabled, enabled by the route (inheriting the global level)
+        assert_eq!(resolve(None, &global), None);
+        let settings = resolve(Some(&route(None, None)), &global).unwrap();
+        assert_eq!(settings.level, 9);
+        assert_eq!(settings.zstd_level, 3);
+        assert_eq!(settings.algorithms, global.algorithms.as_slice());
+        assert_eq!(settings.content_types, global.content_types.as_slice());
+
+        // Globally enabled, disabled by the route
+        let global = Compression {
+            enabled: true,
+            ..global
+        };
+        assert_eq!(resolve(None, &global).map(|s| s.level), Some(9));
+        assert_eq!(resolve(Some(&route(Some(false), Some(4))), &global), None);
+        assert_eq!(
+            resolve(Some(&route(Some(true), Some(4))), &global).map(|s| s.level),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        let allowlist = ["text/*", "application/json", "application/zip"];
+
+        assert!(is_compressible("text/html; charset=utf-8", &allowlist));
+        assert!(is_compressible("Application/JSON", &allowlist));
+        assert!(!is_compressible("application/javascript", &allowlist));
+        // Already compressed types are never compressed, even if listed
+        assert!(!is_compressible("application/zip", &allowlist));
+        assert!(!is_compressible("image/png", &["image/*"]));
+        assert!(is_compressible("image/svg+xml", &["image/*"]));
+        assert!(!is_compressible("", &allowlist));
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let global = Compression {
+            enabled: true,
+            min_length: 1024,
+            ..Compression::default()
+        };
+        let settings = resolve(None, &global).unwrap();
+        let response = |headers: &[(&str, &str)]| {
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            for (name, value) in headers {
+                response.insert_header(name.to_string(), *value).unwrap();
+            }
+            response
+        };
+
+        assert!(should_compress(
+            &response(&[("content-type", "text/html"), ("content-length", "2048")]),
+            &settings
+        ));
+        // Of unknown length (ex: chunked)
+        assert!(should_compress(
+            &response(&[("content-type", "application/json")]),
+            &settings
+        ));
+        assert!(!should_compress(
+            &response(&[("content-type", "text/html"), ("content-length", "100")]),
+            &settings
+        ));
+        assert!(!should_compress(
+            &response(&[("content-type", "text/html"), ("content-encoding", "gzip")]),
+            &settings
+        ));
+        assert!(!should_compress(
+            &response(&[("content-type", "image/png")]),
+            &settings
+        ));
+        assert!(!should_compress(&response(&[]), &settings));
+    }
+}
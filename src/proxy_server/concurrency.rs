@@ -0,0 +1,207 @@
+//! Bound of the requests of a route proxied at once
+//! (`concurrency.max_concurrent_requests`). The requests above the bound are
+//! either answered with a 503 right away, or queued until another request of
+//! the route ends, for at most `queue_timeout_ms`.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http::{header, StatusCode};
+use pingora::{http::ResponseHeader, proxy::Session};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    config::{RouteConcurrency, RouteOverflow},
+    metrics,
+};
+
+/// Why a request did not get a slot of its route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// The route is at capacity and does not queue
+    Rejected,
+    /// The queue of the route is full
+    QueueFull,
+    /// The request waited for `queue_timeout_ms` without getting a slot
+    TimedOut,
+}
+
+impl Overflow {
+    /// Label of the `proksi_http_overflow_requests_total` metric
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Overflow::Rejected => "rejected",
+            Overflow::QueueFull => "queue_full",
+            Overflow::TimedOut => "queue_timeout",
+        }
+    }
+
+    /// The body of the 503 sent to the client
+    fn reason(self) -> &'static str {
+        match self {
+            Overflow::Rejected => "too many concurrent requests for this host",
+            Overflow::QueueFull => "too many queued requests for this host",
+            Overflow::TimedOut => "timed out waiting for a free upstream slot",
+        }
+    }
+}
+
+/// The slots of a route. Clones share the same slots.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    pub config: RouteConcurrency,
+    route: String,
+    slots: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(route: &str, config: &RouteConcurrency) -> Self {
+        ConcurrencyLimit {
+            config: config.clone(),
+            route: route.to_string(),
+            slots: Arc::new(Semaphore::new(config.max_concurrent_requests)),
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Takes a slot of the route, held until the returned permit is dropped
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Overflow> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        if self.config.overflow == RouteOverflow::Reject {
+            return Err(Overflow::Rejected);
+        }
+
+        let max_queued = self
+            .config
+            .max_queued
+            .unwrap_or(self.config.max_concurrent_requests);
+        let Some(_queued) = Queued::enter(self, max_queued) else {
+            return Err(Overflow::QueueFull);
+        };
+
+        let timeout = Duration::from_millis(self.config.queue_timeout_ms);
+        match tokio::time::timeout(timeout, self.slots.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            // The semaphore is never closed
+            Ok(Err(_)) | Err(_) => Err(Overflow::TimedOut),
+        }
+    }
+}
+
+/// A request waiting for a slot, no longer counted once dropped
+/// (including when the client goes away while it waits)
+struct Queued<'a> {
+    limit: &'a ConcurrencyLimit,
+}
+
+impl<'a> Queued<'a> {
+    fn enter(limit: &'a ConcurrencyLimit, max_queued: usize) -> Option<Self> {
+        limit
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                (queued < max_queued).then_some(queued + 1)
+            })
+            .ok()?;
+
+        metrics::HTTP_QUEUED_REQUESTS
+            .with_label_values(&[&limit.route])
+            .inc();
+        Some(Queued { limit })
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.limit.queued.fetch_sub(1, Ordering::Relaxed);
+        metrics::HTTP_QUEUED_REQUESTS
+            .with_label_values(&[&self.limit.route])
+            .dec();
+    }
+}
+
+/// Answers a request that did not get a slot with a 503 stating why
+pub async fn respond(session: &mut Session, overflow: Overflow) -> pingora::Result<()> {
+    metrics::HTTP_OVERFLOW_REQUESTS
+        .with_label_values(&[overflow.as_str()])
+        .inc();
+
+    let body = Bytes::from_static(overflow.reason().as_bytes());
+    let mut res = ResponseHeader::build(StatusCode::SERVICE_UNAVAILABLE, Some(3))?;
+    res.insert_header(header::CONTENT_TYPE, "text/plain")?;
+    res.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+    res.insert_header(header::RETRY_AFTER, "1")?;
+
+    session.write_response_header(Box::new(res), false).await?;
+    session.write_response_body(Some(body), true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn concurrency_limit(overflow: RouteOverflow, max_queued: Option<usize>) -> ConcurrencyLimit {
+        ConcurrencyLimit::new(
+            "example.com",
+            &RouteConcurrency {
+                max_concurrent_requests: 1,
+                overflow,
+                queue_timeout_ms: 20,
+                max_queued,
+            },
+        )
+    }
+
+    #[test]
+    fn test_concurrency_limit_rejects() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let limit = concurrency_limit(RouteOverflow::Reject, None);
+            let permit = limit.acquire().await.unwrap();
+            assert_eq!(limit.acquire().await.unwrap_err(), Overflow::Rejected);
+
+            // The slot is free again once the request ends
+            drop(permit);
+            assert!(limit.acquire().await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_concurrency_limit_queues() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let limit = concurrency_limit(RouteOverflow::Queue, None);
+            let permit = limit.acquire().await.unwrap();
+            assert_eq!(limit.acquire().await.unwrap_err(), Overflow::TimedOut);
+            assert_eq!(limit.queued.load(Ordering::Relaxed), 0);
+
+            // A queued request gets the slot of the request that ends
+            let queued = limit.clone();
+            let waiting = tokio::spawn(async move { queued.acquire().await.is_ok() });
+            tokio::task::yield_now().await;
+            drop(permit);
+            assert!(waiting.await.unwrap());
+
+            let limit = concurrency_limit(RouteOverflow::Queue, Some(0));
+            let _permit = limit.acquire().await.unwrap();
+            assert_eq!(limit.acquire().await.unwrap_err(), Overflow::QueueFull);
+        });
+    }
+}
@@ -0,0 +1,144 @@
+//! Connections open at once from each client IP (`limits.max_connections_per_ip`).
+//!
+//! Pingora does not tell the proxy when a connection opens or closes (see
+//! `connections`), nor lets it refuse one before its first request. A
+//! connection is counted from its first request for as long as its socket
+//! digest lives: the digest is shared by every request of the connection and
+//! dropped along with it. The connections over the limit are answered with a
+//! `429` and closed.
+//!
+//! The trusted proxies of `client_ip` are exempt, they carry the connections of
+//! many clients. Behind a PROXY listener, the client is the one of the header.
+
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use pingora::{protocols::SocketDigest, proxy::Session, ErrorType::HTTPStatus};
+
+use crate::{metrics, tools::client_ip};
+
+/// How often (in connections checked) the clients without open connections are forgotten
+const SWEEP_EVERY: usize = 1024;
+
+/// The open connections of both listeners
+static OPEN: Lazy<ConnectionLimiter> = Lazy::new(ConnectionLimiter::default);
+
+/// The connections open from each client IP
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    open: DashMap<IpAddr, Vec<Weak<SocketDigest>>>,
+    checked: AtomicUsize,
+}
+
+impl ConnectionLimiter {
+    /// Whether the connection from `ip` is accepted: it is already open, or
+    /// fewer than `max` other connections are
+    pub fn admit(&self, ip: IpAddr, connection: &Arc<SocketDigest>, max: usize) -> bool {
+        if self.checked.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == 0 {
+            self.sweep();
+        }
+
+        let mut open = self.open.entry(ip).or_default();
+        open.retain(|c| c.strong_count() > 0);
+        if open
+            .iter()
+            .any(|c| std::ptr::eq(c.as_ptr(), Arc::as_ptr(connection)))
+        {
+            return true;
+        }
+
+        if open.len() >= max {
+            return false;
+        }
+
+        open.push(Arc::downgrade(connection));
+        true
+    }
+
+    /// Forgets the clients whose connections are all closed
+    pub fn sweep(&self) {
+        self.open.retain(|_, open| {
+            open.retain(|c| c.strong_count() > 0);
+            !open.is_empty()
+        });
+    }
+
+    /// Number of connections open from `ip`
+    pub fn open_connections(&self, ip: IpAddr) -> usize {
+        self.open.get(&ip).map_or(0, |open| {
+            open.iter().filter(|c| c.strong_count() > 0).count()
+        })
+    }
+}
+
+/// Refuses the connection of the request when its client IP already has `max`
+/// other connections open
+pub fn check(session: &Session, listener: &'static str, max: Option<usize>) -> pingora::Result<()> {
+    let Some(max) = max else {
+        return Ok(());
+    };
+
+    let Some(connection) = session.digest().and_then(|d| d.socket_digest.as_ref()) else {
+        return Ok(());
+    };
+
+    let Some(ip) = client_ip::peer_ip(session).filter(|ip| !client_ip::is_trusted_proxy(*ip))
+    else {
+        return Ok(());
+    };
+
+    if OPEN.admit(ip, connection, max) {
+        return Ok(());
+    }
+
+    metrics::CONNECTIONS_PER_IP_REJECTED
+        .with_label_values(&[listener])
+        .inc();
+    Err(pingora::Error::explain(
+        HTTPStatus(429),
+        "too many connections from the client IP",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn connection() -> Arc<SocketDigest> {
+        Arc::new(SocketDigest::from_raw_fd(0))
+    }
+
+    #[test]
+    fn test_admit_until_the_limit() {
+        let limiter = ConnectionLimiter::default();
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+        let other = IpAddr::from_str("192.0.2.2").unwrap();
+        let (first, second, third) = (connection(), connection(), connection());
+
+        assert!(limiter.admit(ip, &first, 2));
+        assert!(limiter.admit(ip, &second, 2));
+        // The next requests of an open connection are not counted again
+        assert!(limiter.admit(ip, &first, 2));
+        assert!(!limiter.admit(ip, &third, 2));
+        assert!(limiter.admit(other, &third, 2));
+        assert_eq!(limiter.open_connections(ip), 2);
+
+        // Closing a connection frees its place
+        drop(first);
+        assert!(limiter.admit(ip, &third, 2));
+        assert_eq!(limiter.open_connections(ip), 2);
+
+        drop((second, third));
+        limiter.sweep();
+        assert!(limiter.open.is_empty());
+    }
+}
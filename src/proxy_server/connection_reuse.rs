@@ -0,0 +1,112 @@
+//! Isolation of the connections to the upstreams of a route per client
+//! (`connection_reuse`). By default, every client shares the connections of
+//! each upstream: a connection opened for the request of one client can carry
+//! the next request of another one. An upstream trusting the identity it got
+//! along with the first request of a connection (ex: the certificate of an
+//! mTLS client) would then lend it to the other clients.
+//!
+//! Each identity (a client certificate, a tenant header, a JWT subject) gets
+//! the connections of its own instead: pingora only reuses a connection for
+//! the peers of the same group. The clients without one share a group apart
+//! from the identified ones.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use http::{HeaderName, HeaderValue};
+use pingora::{http::RequestHeader, proxy::Session};
+
+use crate::config::{RouteConnectionReuse, RouteConnectionReuseBy};
+
+use super::https_proxy::RouterContext;
+
+/// The group of the connections to the upstreams the request may use,
+/// 0 (the one of pingora) when they are shared
+pub fn group_key(reuse: &RouteConnectionReuse, session: &Session, ctx: &RouterContext) -> u64 {
+    let identity = match reuse.by {
+        RouteConnectionReuseBy::Shared => return 0,
+        RouteConnectionReuseBy::ClientCertificate => session
+            .digest()
+            .and_then(|digest| digest.ssl_digest.as_ref())
+            .map(|ssl| ssl.cert_digest.clone())
+            .filter(|digest| !digest.is_empty()),
+        RouteConnectionReuseBy::Header => {
+            let name = reuse.header.as_deref().unwrap_or_default();
+            // The header of the server, the one of the client is removed
+            let forwarded = ctx
+                .route_container
+                .forward_auth
+                .as_ref()
+                .is_some_and(|forward_auth| forward_auth.copies(name))
+                .then_some(ctx.forward_auth_headers.as_slice());
+            tenant(session.req_header(), forwarded, name)
+        }
+        RouteConnectionReuseBy::JwtSub => ctx
+            .jwt_subject
+            .as_ref()
+            .map(|subject| subject.as_bytes().to_vec()),
+    };
+
+    hash(reuse.by, identity.as_deref())
+}
+
+/// The value of the header naming the tenant, taken from the headers of the
+/// authentication server when it sets it
+fn tenant(
+    request: &RequestHeader,
+    forwarded: Option<&[(HeaderName, HeaderValue)]>,
+    name: &str,
+) -> Option<Vec<u8>> {
+    let value = match forwarded {
+        Some(headers) => headers
+            .iter()
+            .find(|(header, _)| header.as_str().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value),
+        None => request.headers.get(name),
+    };
+    value.map(|value| value.as_bytes().to_vec())
+}
+
+fn hash(by: RouteConnectionReuseBy, identity: Option<&[u8]>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    by.hash(&mut hasher);
+    identity.hash(&mut hasher);
+    // 0 is the group of the shared connections
+    hasher.finish().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_a_group_per_identity() {
+        let by = RouteConnectionReuseBy::ClientCertificate;
+        let alice = hash(by, Some(b"alice"));
+
+        assert_eq!(alice, hash(by, Some(b"alice")));
+        assert_ne!(alice, hash(by, Some(b"bob")));
+        assert_ne!(alice, hash(by, None));
+        // The same identity from another source is another client
+        assert_ne!(alice, hash(RouteConnectionReuseBy::JwtSub, Some(b"alice")));
+        assert_ne!(hash(by, None), 0);
+    }
+
+    #[test]
+    fn test_tenant_of_the_authentication_server() {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        request.insert_header("x-tenant", "forged").unwrap();
+
+        assert_eq!(tenant(&request, None, "x-tenant"), Some(b"forged".to_vec()));
+
+        let forwarded = [(
+            HeaderName::from_static("x-tenant"),
+            HeaderValue::from_static("acme"),
+        )];
+        assert_eq!(
+            tenant(&request, Some(&forwarded), "X-Tenant"),
+            Some(b"acme".to_vec())
+        );
+        // Not set by the server, the clients cannot pick one
+        assert_eq!(tenant(&request, Some(&[]), "x-tenant"), None);
+    }
+}
@@ -0,0 +1,275 @@
+//! Connection and request counters of the listeners.
+//!
+//! Pingora does not tell the proxy when a connection opens or closes. An HTTPS
+//! connection is followed through its TLS session instead: the session is
+//! created with the first client hello and dropped along with the connection.
+//!
+//! The handshakes that fail are logged and counted by reason. OpenSSL does not
+//! tell why a handshake failed either: the reason is what the listener found
+//! out along the way (the client hello, the certificate lookup).
+
+use once_cell::sync::Lazy;
+use openssl::{
+    error::ErrorStack,
+    ex_data::Index,
+    ssl::{ClientHelloResponse, Ssl, SslAlert, SslRef, SslVersion},
+};
+
+use crate::metrics;
+
+/// Label of the HTTP listener
+pub const HTTP: &str = "http";
+
+/// Label of the HTTPS listener
+pub const HTTPS: &str = "https";
+
+/// Where a connection is kept in its TLS session
+static CONNECTION_INDEX: Lazy<Index<Ssl, Connection>> =
+    Lazy::new(|| Ssl::new_ex_index().expect("failed to create the connection index"));
+
+/// Why a TLS handshake failed (the `reason` label of `proksi_tls_handshake_failures_total`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// The client only offers protocol versions below TLS 1.2 (the minimum of the listener)
+    ProtocolVersion,
+    /// None of the cipher suites offered by the client is supported
+    NoSharedCipher,
+    /// The client sent no server name (SNI) and there is no fallback certificate
+    MissingServerName,
+    /// There is no certificate for the server name, and no fallback certificate
+    UnknownServerName,
+    /// The client certificate of an mTLS route was rejected (once a
+    /// certificate was presented to the client)
+    ClientCertificate,
+    /// The connection closed during the handshake for any other reason
+    Closed,
+}
+
+impl HandshakeFailure {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HandshakeFailure::ProtocolVersion => "protocol_version",
+            HandshakeFailure::NoSharedCipher => "no_shared_cipher",
+            HandshakeFailure::MissingServerName => "missing_server_name",
+            HandshakeFailure::UnknownServerName => "unknown_server_name",
+            HandshakeFailure::ClientCertificate => "client_certificate",
+            HandshakeFailure::Closed => "closed",
+        }
+    }
+}
+
+/// A connection of the HTTPS listener, counted as closed when dropped
+struct Connection {
+    /// Whether a certificate was presented to the client
+    certified: bool,
+    /// The server name (SNI) requested by the client
+    server_name: Option<String>,
+    /// Why the handshake fails, if it does before a certificate is presented
+    failure: Option<HandshakeFailure>,
+}
+
+impl Connection {
+    fn open() -> Self {
+        metrics::LISTENER_CONNECTIONS
+            .with_label_values(&[HTTPS])
+            .inc();
+        metrics::LISTENER_ACTIVE_CONNECTIONS
+            .with_label_values(&[HTTPS])
+            .inc();
+
+        Connection {
+            certified: false,
+            server_name: None,
+            failure: None,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        metrics::LISTENER_ACTIVE_CONNECTIONS
+            .with_label_values(&[HTTPS])
+            .dec();
+
+        if !self.certified {
+            metrics::TLS_HANDSHAKES.with_label_values(&["failed"]).inc();
+            handshake_failed(
+                self.failure.unwrap_or(HandshakeFailure::Closed),
+                self.server_name.as_deref(),
+            );
+        }
+    }
+}
+
+/// Logs and counts a failed handshake
+pub fn handshake_failed(reason: HandshakeFailure, server_name: Option<&str>) {
+    metrics::TLS_HANDSHAKE_FAILURES
+        .with_label_values(&[reason.as_str()])
+        .inc();
+
+    tracing::info!(
+        reason = reason.as_str(),
+        server_name = server_name.unwrap_or_default(),
+        "TLS handshake failed"
+    );
+}
+
+/// What the client hello tells about a handshake bound to fail: the client
+/// only supports older protocol versions, or none of its cipher suites is known.
+/// Clients supporting TLS 1.3 announce TLS 1.2 as their (legacy) version.
+fn client_hello_failure(ssl: &SslRef) -> Option<HandshakeFailure> {
+    let version = ssl.client_hello_legacy_version();
+    if matches!(
+        version,
+        Some(SslVersion::SSL3 | SslVersion::TLS1 | SslVersion::TLS1_1)
+    ) {
+        return Some(HandshakeFailure::ProtocolVersion);
+    }
+
+    let known_suites = ssl
+        .client_hello_ciphers()
+        .and_then(|ciphers| ssl.bytes_to_cipher_list(ciphers, false).ok())
+        .map(|list| !list.suites.is_empty());
+    if known_suites == Some(false) {
+        return Some(HandshakeFailure::NoSharedCipher);
+    }
+
+    None
+}
+
+/// Client hello callback of the HTTPS listener: counts the connection, once
+/// (a client hello is sent again after a TLS 1.3 hello retry request)
+#[allow(clippy::unnecessary_wraps)]
+pub fn client_hello_callback(
+    ssl: &mut SslRef,
+    _alert: &mut SslAlert,
+) -> Result<ClientHelloResponse, ErrorStack> {
+    if ssl.ex_data(*CONNECTION_INDEX).is_none() {
+        ssl.set_ex_data(*CONNECTION_INDEX, Connection::open());
+    }
+
+    let failure = client_hello_failure(ssl);
+    if let Some(connection) = ssl.ex_data_mut(*CONNECTION_INDEX) {
+        connection.failure = failure;
+    }
+
+    Ok(ClientHelloResponse::SUCCESS)
+}
+
+/// Records the server name requested by the client, for the logs
+pub fn server_name(ssl: &mut SslRef, name: &str) {
+    if let Some(connection) = ssl.ex_data_mut(*CONNECTION_INDEX) {
+        connection.server_name = Some(name.to_string());
+    }
+}
+
+/// Records that no certificate can be presented for the server name
+pub fn no_certificate(ssl: &mut SslRef, server_name: &str) {
+    let Some(connection) = ssl.ex_data_mut(*CONNECTION_INDEX) else {
+        return;
+    };
+
+    connection.failure = Some(if server_name.is_empty() {
+        HandshakeFailure::MissingServerName
+    } else {
+        HandshakeFailure::UnknownServerName
+    });
+}
+
+/// Records that a certificate was presented for the connection: the server
+/// accepted the handshake (the client can still reject the certificate).
+/// Connections closed before that count as failed handshakes.
+pub fn certified(ssl: &mut SslRef) {
+    let Some(connection) = ssl.ex_data_mut(*CONNECTION_INDEX) else {
+        return;
+    };
+
+    if !connection.certified {
+        connection.certified = true;
+        metrics::TLS_HANDSHAKES.with_label_values(&["completed"]).inc();
+    }
+}
+
+/// Counts a request received by the given listener
+pub fn request(listener: &str) {
+    metrics::LISTENER_REQUESTS
+        .with_label_values(&[listener])
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::ssl::{SslContext, SslMethod};
+
+    use super::*;
+
+    fn new_ssl() -> Ssl {
+        let context = SslContext::builder(SslMethod::tls()).unwrap().build();
+        Ssl::new(&context).unwrap()
+    }
+
+    fn value(result: &str) -> u64 {
+        metrics::TLS_HANDSHAKES.with_label_values(&[result]).get()
+    }
+
+    fn failures(reason: HandshakeFailure) -> u64 {
+        metrics::TLS_HANDSHAKE_FAILURES
+            .with_label_values(&[reason.as_str()])
+            .get()
+    }
+
+    #[test]
+    fn test_connection_lifecycle() {
+        let active = || {
+            metrics::LISTENER_ACTIVE_CONNECTIONS
+                .with_label_values(&[HTTPS])
+                .get()
+        };
+        let (completed, failed) = (value("completed"), value("failed"));
+        let mut alert = SslAlert::DECODE_ERROR;
+
+        // A certificate was presented: the handshake completed
+        let mut ssl = new_ssl();
+        client_hello_callback(&mut ssl, &mut alert).unwrap();
+        client_hello_callback(&mut ssl, &mut alert).unwrap();
+        assert!(active() >= 1);
+        certified(&mut ssl);
+        certified(&mut ssl);
+        drop(ssl);
+        assert_eq!(value("completed"), completed + 1);
+
+        // Closed before a certificate was presented: the handshake failed
+        let mut ssl = new_ssl();
+        client_hello_callback(&mut ssl, &mut alert).unwrap();
+        drop(ssl);
+        assert_eq!(value("failed"), failed + 1);
+        assert_eq!(value("completed"), completed + 1);
+
+        // Failed handshakes are counted by reason (in the same test, as the
+        // counters above are shared)
+        let (missing, unknown) = (
+            failures(HandshakeFailure::MissingServerName),
+            failures(HandshakeFailure::UnknownServerName),
+        );
+
+        // Nothing known about the failure
+        let closed = failures(HandshakeFailure::Closed);
+        let mut ssl = new_ssl();
+        client_hello_callback(&mut ssl, &mut alert).unwrap();
+        drop(ssl);
+        assert_eq!(failures(HandshakeFailure::Closed), closed + 1);
+
+        let mut ssl = new_ssl();
+        client_hello_callback(&mut ssl, &mut alert).unwrap();
+        no_certificate(&mut ssl, "");
+        drop(ssl);
+        assert_eq!(failures(HandshakeFailure::MissingServerName), missing + 1);
+
+        let mut ssl = new_ssl();
+        client_hello_callback(&mut ssl, &mut alert).unwrap();
+        server_name(&mut ssl, "unknown.example.com");
+        no_certificate(&mut ssl, "unknown.example.com");
+        drop(ssl);
+        assert_eq!(failures(HandshakeFailure::UnknownServerName), unknown + 1);
+    }
+}
@@ -0,0 +1,127 @@
+//! Upstreams picked from a segment of the request path (`dynamic_upstream`),
+//! so a single route serves many tenants (ex: `/t/{tenant}/...`), each from
+//! its own upstream.
+//!
+//! The upstream is resolved for each request. It is neither health checked
+//! nor load balanced: a request whose upstream fails gets a 502.
+
+use std::borrow::Cow;
+
+use pingora::{lb::Backend, protocols::l4::socket::SocketAddr};
+
+use crate::config::{RouteDynamicUpstream, RouteUpstream, UpstreamScheme};
+
+/// Longest value filled in the template (the length of a DNS label)
+const MAX_TEMPLATE_VALUE_LEN: usize = 63;
+
+/// The upstreams of the requests of a route, by path segment
+#[derive(Debug)]
+pub struct DynamicUpstream {
+    config: RouteDynamicUpstream,
+}
+
+impl DynamicUpstream {
+    pub fn new(config: &RouteDynamicUpstream) -> Self {
+        DynamicUpstream {
+            config: config.clone(),
+        }
+    }
+
+    /// The address (`host:port`) of the upstream of the path, `None` when its
+    /// segment is missing or maps to no upstream
+    pub fn address(&self, path: &str) -> Option<String> {
+        let value = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .nth(self.config.segment)?;
+
+        if let Some(upstream) = self.config.upstreams.get(value) {
+            return Some(upstream.to_string());
+        }
+
+        // Only the values that cannot change the host (or port) of the template
+        let template = self.config.template.as_ref()?;
+        let is_label = value.len() <= MAX_TEMPLATE_VALUE_LEN
+            && value
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-');
+        is_label.then(|| template.replace("{segment}", value))
+    }
+
+    /// Resolves the upstream of the path, `None` when there is none (or its
+    /// address does not resolve)
+    pub async fn resolve(&self, path: &str) -> Option<(Backend, RouteUpstream)> {
+        let address = self.address(path)?;
+        let (host, port) = address.rsplit_once(':')?;
+
+        let addr = match tokio::net::lookup_host(address.as_str()).await {
+            Ok(mut addrs) => addrs.next()?,
+            Err(err) => {
+                tracing::debug!("could not resolve the dynamic upstream {address}: {err}");
+                return None;
+            }
+        };
+
+        let upstream = RouteUpstream {
+            ip: Cow::Owned(host.to_string()),
+            port: port.parse().ok()?,
+            sni: self.config.tls.then(|| host.to_string()),
+            scheme: Some(if self.config.tls {
+                UpstreamScheme::Https
+            } else {
+                UpstreamScheme::Http
+            }),
+            ..RouteUpstream::default()
+        };
+        let backend = Backend {
+            addr: SocketAddr::Inet(addr),
+            weight: 1,
+        };
+
+        Some((backend, upstream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dynamic_upstream(template: Option<&'static str>) -> DynamicUpstream {
+        let mut config: RouteDynamicUpstream =
+            serde_json::from_str(r#"{ "segment": 1, "upstreams": { "acme": "10.0.0.5:3000" } }"#)
+                .unwrap();
+        config.template = template.map(Cow::Borrowed);
+        DynamicUpstream::new(&config)
+    }
+
+    #[test]
+    fn test_address_from_the_lookup_table() {
+        let dynamic = dynamic_upstream(None);
+        assert_eq!(
+            dynamic.address("/t/acme/orders").as_deref(),
+            Some("10.0.0.5:3000")
+        );
+        assert_eq!(dynamic.address("/t/acme").as_deref(), Some("10.0.0.5:3000"));
+
+        // Unknown tenants, or no tenant at all
+        assert_eq!(dynamic.address("/t/globex/orders"), None);
+        assert_eq!(dynamic.address("/t/"), None);
+    }
+
+    #[test]
+    fn test_address_from_the_template() {
+        let dynamic = dynamic_upstream(Some("{segment}.tenants.svc:3000"));
+        assert_eq!(
+            dynamic.address("/t/acme/orders").as_deref(),
+            Some("10.0.0.5:3000")
+        );
+        assert_eq!(
+            dynamic.address("/t/globex-2/orders").as_deref(),
+            Some("globex-2.tenants.svc:3000")
+        );
+
+        // Values that would change the host of the template are not used
+        assert_eq!(dynamic.address("/t/evil.com:22%23/orders"), None);
+        assert_eq!(dynamic.address(&format!("/t/{}", "a".repeat(64))), None);
+    }
+}
@@ -0,0 +1,270 @@
+//! Upstream responses intercepted by proksi (`error_handling`): instead of
+//! passing them through, the client gets an error page with the status of the
+//! upstream, or the request is sent to an upstream again first.
+//!
+//! An intercepted response turns into an error of the response filter, so the
+//! upstream connection is dropped along with the rest of the response. The
+//! requests whose body outgrew the retry buffer of pingora are not sent again.
+//!
+//! The `Retry-After` of the intercepted `503` responses is honored when
+//! retrying: the upstream is skipped until the end of the delay (`failover`),
+//! the request waits for it (`delay`) or the response passes through.
+
+use std::{
+    collections::HashSet,
+    fs,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use http::{header, StatusCode};
+use pingora::{http::ResponseHeader, proxy::Session, ErrorSource, ErrorType::HTTPStatus};
+
+use crate::config::{InterceptAction, RetryAfterAction, RouteErrorHandling};
+
+use super::retry_after;
+
+/// What is done with an upstream response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interception {
+    /// Passed through to the client
+    None,
+    /// Replaced by the error page
+    ErrorPage,
+    /// Sent again, once `delay` is over, while the upstream is skipped for `backoff`
+    Retry {
+        delay: Option<Duration>,
+        backoff: Option<Duration>,
+    },
+}
+
+/// The intercepted statuses of a route and what is done with them
+#[derive(Debug)]
+pub struct ErrorHandling {
+    statuses: HashSet<u16>,
+    action: InterceptAction,
+    retries: usize,
+    /// Body of the error page, the error response of proksi without it
+    page: Option<Bytes>,
+    retry_after: RetryAfterAction,
+    max_retry_after: Duration,
+}
+
+impl ErrorHandling {
+    /// Compiles the error handling of a route, `None` when nothing is intercepted.
+    /// A page that cannot be read is replaced by the error response of proksi.
+    pub fn new(host: &str, config: &RouteErrorHandling) -> Option<Self> {
+        if config.intercept_statuses.is_empty() {
+            return None;
+        }
+
+        let page = config.page.as_ref().and_then(|path| match fs::read(path) {
+            Ok(page) => Some(Bytes::from(page)),
+            Err(err) => {
+                tracing::error!("failed to read the error page {path:?} of host {host}: {err}");
+                None
+            }
+        });
+
+        Some(ErrorHandling {
+            statuses: config.intercept_statuses.iter().copied().collect(),
+            action: config.action,
+            retries: config.retries,
+            page,
+            retry_after: config.retry_after,
+            max_retry_after: Duration::from_secs(config.max_retry_after_secs),
+        })
+    }
+
+    /// What is done with the response of the upstream, after `intercepted`
+    /// other responses of the request were. The request can only be sent again
+    /// when `can_retry` (its body is still buffered).
+    pub fn intercept(
+        &self,
+        response: &ResponseHeader,
+        intercepted: usize,
+        can_retry: bool,
+    ) -> Interception {
+        if !self.statuses.contains(&response.status.as_u16()) {
+            return Interception::None;
+        }
+
+        if self.action != InterceptAction::Retry {
+            return Interception::ErrorPage;
+        }
+
+        let retry_after = (response.status == StatusCode::SERVICE_UNAVAILABLE)
+            .then(|| retry_after::delay(response, SystemTime::now()))
+            .flatten()
+            .map(|delay| delay.min(self.max_retry_after));
+        if retry_after.is_some() && self.retry_after == RetryAfterAction::PassThrough {
+            return Interception::None;
+        }
+
+        if intercepted >= self.retries || !can_retry {
+            return Interception::ErrorPage;
+        }
+
+        match self.retry_after {
+            RetryAfterAction::Delay => Interception::Retry {
+                delay: retry_after,
+                backoff: None,
+            },
+            _ => Interception::Retry {
+                delay: None,
+                backoff: retry_after,
+            },
+        }
+    }
+
+    /// The error replacing an intercepted response, retried when `retry`
+    pub fn error(status: u16, retry: bool) -> Box<pingora::Error> {
+        let mut error =
+            pingora::Error::explain(HTTPStatus(status), "intercepted upstream response");
+        error.set_retry(retry);
+        error.into_up()
+    }
+
+    /// Answers the request with the error page
+    pub async fn respond(&self, session: &mut Session, status: u16) -> pingora::Result<()> {
+        let Some(page) = &self.page else {
+            session.respond_error(status).await?;
+            return Ok(());
+        };
+
+        let mut response = ResponseHeader::build(status, Some(3))?;
+        response.insert_header(header::CONTENT_TYPE, "text/html; charset=utf-8")?;
+        response.insert_header(header::CONTENT_LENGTH, page.len().to_string())?;
+        response.insert_header(header::CACHE_CONTROL, "no-store")?;
+
+        session
+            .write_response_header(Box::new(response), false)
+            .await?;
+        session.write_response_body(Some(page.clone()), true).await
+    }
+}
+
+/// The status of the intercepted response the error replaced, if it did
+pub fn intercepted_status(error: &pingora::Error) -> Option<u16> {
+    match (error.etype(), error.esource()) {
+        (HTTPStatus(status), ErrorSource::Upstream) => Some(*status),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_handling(action: InterceptAction, retry_after: RetryAfterAction) -> ErrorHandling {
+        let config = RouteErrorHandling {
+            intercept_statuses: vec![502, 503],
+            action,
+            retries: 1,
+            page: None,
+            retry_after,
+            max_retry_after_secs: 10,
+        };
+        ErrorHandling::new("example.com", &config).unwrap()
+    }
+
+    fn response(status: u16, retry_after: Option<&str>) -> ResponseHeader {
+        let mut response = ResponseHeader::build(status, None).unwrap();
+        if let Some(retry_after) = retry_after {
+            response
+                .insert_header(header::RETRY_AFTER, retry_after)
+                .unwrap();
+        }
+        response
+    }
+
+    #[test]
+    fn test_intercepted_statuses() {
+        let error_handling = error_handling(InterceptAction::ErrorPage, RetryAfterAction::Failover);
+        assert_eq!(
+            error_handling.intercept(&response(503, None), 0, true),
+            Interception::ErrorPage
+        );
+        assert_eq!(
+            error_handling.intercept(&response(500, None), 0, true),
+            Interception::None
+        );
+
+        // Passthrough without statuses
+        let config = RouteErrorHandling {
+            intercept_statuses: Vec::new(),
+            action: InterceptAction::Retry,
+            retries: 1,
+            page: None,
+            retry_after: RetryAfterAction::Failover,
+            max_retry_after_secs: 10,
+        };
+        assert!(ErrorHandling::new("example.com", &config).is_none());
+    }
+
+    #[test]
+    fn test_retries() {
+        let error_handling = error_handling(InterceptAction::Retry, RetryAfterAction::Failover);
+        let retry = Interception::Retry {
+            delay: None,
+            backoff: None,
+        };
+        assert_eq!(
+            error_handling.intercept(&response(502, None), 0, true),
+            retry
+        );
+        assert_eq!(
+            error_handling.intercept(&response(502, None), 1, true),
+            Interception::ErrorPage
+        );
+        // The body of the request is no longer buffered
+        assert_eq!(
+            error_handling.intercept(&response(502, None), 0, false),
+            Interception::ErrorPage
+        );
+
+        let error = ErrorHandling::error(503, true);
+        assert!(error.retry());
+        assert_eq!(intercepted_status(&error), Some(503));
+
+        let timed_out = pingora::Error::explain(HTTPStatus(504), "upstream response timed out");
+        assert_eq!(intercepted_status(&timed_out), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let unavailable = response(503, Some("30"));
+
+        // Bounded by `max_retry_after_secs`
+        let failover = error_handling(InterceptAction::Retry, RetryAfterAction::Failover);
+        assert_eq!(
+            failover.intercept(&unavailable, 0, true),
+            Interception::Retry {
+                delay: None,
+                backoff: Some(Duration::from_secs(10)),
+            }
+        );
+
+        let delay = error_handling(InterceptAction::Retry, RetryAfterAction::Delay);
+        assert_eq!(
+            delay.intercept(&response(503, Some("2")), 0, true),
+            Interception::Retry {
+                delay: Some(Duration::from_secs(2)),
+                backoff: None,
+            }
+        );
+
+        let pass_through = error_handling(InterceptAction::Retry, RetryAfterAction::PassThrough);
+        assert_eq!(
+            pass_through.intercept(&unavailable, 0, true),
+            Interception::None
+        );
+        assert_eq!(
+            pass_through.intercept(&response(503, None), 0, true),
+            Interception::Retry {
+                delay: None,
+                backoff: None,
+            }
+        );
+    }
+}
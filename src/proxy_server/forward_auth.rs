@@ -0,0 +1,317 @@
+//! Authentication of the requests of a route by an external server
+//! (`forward_auth`), the way Traefik's forwardauth does. The server is sent a
+//! `GET` with the headers of each request, along with its method, host and URI
+//! (`X-Forwarded-*`), before any plugin runs.
+//!
+//! A `2xx` lets the request through, with the `copy_headers` of the response
+//! of the server: the client cannot set them itself. Any other response
+//! (ex: a redirect to a login page) is sent to the client as it is. The
+//! server failing to answer gets the request a `502` (`504` once it timed out).
+//!
+//! The decisions allowing the requests are reused for `cache_ttl_secs`, for
+//! the requests with the same credentials (`Authorization` and `Cookie`)
+//! to the same URI.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+use once_cell::sync::Lazy;
+use openssl::sha::Sha256;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RouteForwardAuth, stores::bounded::TtlMap};
+
+use super::headers::HOP_BY_HOP_HEADERS;
+
+/// Redirects are sent to the client, not followed
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap_or_default()
+});
+
+/// The decisions allowing requests, by credentials
+static DECISIONS: Lazy<TtlMap<Option<Allowed>>> = Lazy::new(|| TtlMap::new("forward_auth"));
+
+/// The store of the cached decisions, bounded by the eviction service
+pub fn store() -> &'static TtlMap<Option<Allowed>> {
+    &DECISIONS
+}
+
+/// A request allowed by the server, with the headers copied onto it
+#[derive(Debug, Clone)]
+pub struct Allowed {
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    expires_at: Instant,
+}
+
+/// What the server made of a request
+#[derive(Debug)]
+pub enum Verdict {
+    Allowed(Vec<(HeaderName, HeaderValue)>),
+    /// The response of the server (or the error of proksi), sent to the client
+    Denied(Box<ResponseHeader>, Bytes),
+}
+
+/// The authentication server of a route
+#[derive(Debug)]
+pub struct ForwardAuth {
+    address: String,
+    copy_headers: Vec<HeaderName>,
+    timeout: Duration,
+    cache_ttl: Duration,
+}
+
+impl ForwardAuth {
+    pub fn new(config: &RouteForwardAuth) -> Self {
+        ForwardAuth {
+            address: config.address.clone(),
+            copy_headers: config
+                .copy_headers
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                .collect(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            cache_ttl: Duration::from_secs(config.cache_ttl_secs),
+        }
+    }
+
+    /// Asks the server (or the cache) whether the request to the host is allowed
+    pub async fn check(&self, request: &RequestHeader, host: &str) -> pingora::Result<Verdict> {
+        let key = cache_key(request, host);
+        let now = Instant::now();
+        if !self.cache_ttl.is_zero() {
+            let cached = DECISIONS.with_entry(
+                key.clone(),
+                now,
+                || None,
+                |allowed| allowed.clone().filter(|allowed| allowed.expires_at > now),
+            );
+            if let Some(allowed) = cached {
+                return Ok(Verdict::Allowed(allowed.headers));
+            }
+        }
+
+        let response = match HTTP_CLIENT
+            .get(&self.address)
+            .headers(subrequest_headers(request, host))
+            .timeout(self.timeout)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::warn!("the authentication server {} failed: {err}", self.address);
+                let status = if err.is_timeout() { 504 } else { 502 };
+                let response = ResponseHeader::build(status, Some(1))?;
+                return Ok(Verdict::Denied(Box::new(response), Bytes::new()));
+            }
+        };
+
+        if response.status().is_success() {
+            let headers = self.copied(response.headers());
+            if !self.cache_ttl.is_zero() {
+                let allowed = Allowed {
+                    headers: headers.clone(),
+                    expires_at: now + self.cache_ttl,
+                };
+                DECISIONS.with_entry(key, now, || None, |entry| *entry = Some(allowed));
+            }
+            return Ok(Verdict::Allowed(headers));
+        }
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await.unwrap_or_default();
+
+        let mut denied = ResponseHeader::build(status, Some(headers.len() + 1))?;
+        for (name, value) in &headers {
+            if !is_framing(name) {
+                denied.append_header(name.clone(), value.clone())?;
+            }
+        }
+        denied.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+        Ok(Verdict::Denied(Box::new(denied), body))
+    }
+
+    /// Whether the header of the upstream requests is set by the server
+    pub fn copies(&self, name: &str) -> bool {
+        self.copy_headers
+            .iter()
+            .any(|copied| copied.as_str().eq_ignore_ascii_case(name))
+    }
+
+    /// The `copy_headers` of the response of the server
+    fn copied(&self, headers: &HeaderMap) -> Vec<(HeaderName, HeaderValue)> {
+        self.copy_headers
+            .iter()
+            .flat_map(|name| {
+                headers
+                    .get_all(name)
+                    .iter()
+                    .map(|value| (name.clone(), value.clone()))
+            })
+            .collect()
+    }
+
+    /// Replaces the `copy_headers` of the upstream request with the ones of
+    /// the server, so the client cannot forge them
+    pub fn apply(
+        &self,
+        upstream_request: &mut RequestHeader,
+        headers: &[(HeaderName, HeaderValue)],
+    ) -> pingora::Result<()> {
+        for name in &self.copy_headers {
+            upstream_request.remove_header(name);
+        }
+        for (name, value) in headers {
+            upstream_request.append_header(name.clone(), value.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends the response of the server to the client
+pub async fn respond(
+    session: &mut Session,
+    response: Box<ResponseHeader>,
+    body: Bytes,
+) -> pingora::Result<()> {
+    let empty = body.is_empty();
+    session.write_response_header(response, empty).await?;
+    if !empty {
+        session.write_response_body(Some(body), true).await?;
+    }
+    Ok(())
+}
+
+/// Headers that belong to the connection (or the framing of the body) they
+/// were received on
+fn is_framing(name: &HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+        || name == header::TRANSFER_ENCODING
+        || name == header::CONTENT_LENGTH
+}
+
+/// The headers of the request, as the server gets them: without a body, with
+/// the method, host and URI of the request
+fn subrequest_headers(request: &RequestHeader, host: &str) -> HeaderMap {
+    let mut headers = request.headers.clone();
+    headers.retain(|name, _| !is_framing(name) && name != header::HOST);
+
+    let uri = request
+        .uri
+        .path_and_query()
+        .map_or("/", http::uri::PathAndQuery::as_str);
+    let forwarded = [
+        ("x-forwarded-method", request.method.as_str()),
+        ("x-forwarded-proto", "https"),
+        ("x-forwarded-host", host),
+        ("x-forwarded-uri", uri),
+    ];
+    for (name, value) in forwarded {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+/// The decisions are reused for the same credentials, method and URI
+fn cache_key(request: &RequestHeader, host: &str) -> String {
+    let mut hasher = Sha256::new();
+    let method = request.method.as_str();
+    let uri = request.uri.to_string();
+    for part in [host.as_bytes(), method.as_bytes(), uri.as_bytes()] {
+        hasher.update(part);
+        hasher.update(b"\n");
+    }
+    for name in [header::AUTHORIZATION, header::COOKIE] {
+        for value in request.headers.get_all(name) {
+            hasher.update(value.as_bytes());
+            hasher.update(b"\n");
+        }
+        hasher.update(b"\n");
+    }
+
+    hasher
+        .finish()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("POST", b"/orders?page=2", None).unwrap();
+        for (name, value) in headers {
+            request.append_header(*name, *value).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn test_subrequest_headers() {
+        let headers = subrequest_headers(
+            &request(&[
+                ("host", "example.com"),
+                ("authorization", "Bearer token"),
+                ("content-length", "12"),
+                ("connection", "keep-alive"),
+            ]),
+            "example.com",
+        );
+
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(headers["x-forwarded-method"], "POST");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        assert_eq!(headers["x-forwarded-uri"], "/orders?page=2");
+        assert!(!headers.contains_key("host"));
+        assert!(!headers.contains_key("content-length"));
+        assert!(!headers.contains_key("connection"));
+    }
+
+    #[test]
+    fn test_cache_key_by_credentials() {
+        let key = |headers: &[(&str, &str)]| cache_key(&request(headers), "example.com");
+        let alice = key(&[("authorization", "Bearer alice")]);
+
+        assert_eq!(alice, key(&[("authorization", "Bearer alice")]));
+        assert_ne!(alice, key(&[("authorization", "Bearer bob")]));
+        assert_ne!(alice, key(&[("cookie", "Bearer alice")]));
+        assert_ne!(alice, key(&[]));
+    }
+
+    #[test]
+    fn test_copied_headers_replace_the_forged_ones() {
+        let forward_auth = ForwardAuth::new(&RouteForwardAuth {
+            address: "http://auth:9000/auth".to_string(),
+            copy_headers: vec!["X-User".into()],
+            timeout_ms: 1000,
+            cache_ttl_secs: 0,
+        });
+
+        let mut response = HeaderMap::new();
+        response.insert("x-user", HeaderValue::from_static("alice"));
+        response.insert("x-other", HeaderValue::from_static("ignored"));
+        let headers = forward_auth.copied(&response);
+
+        let mut upstream_request = request(&[("x-user", "admin")]);
+        forward_auth.apply(&mut upstream_request, &headers).unwrap();
+        assert_eq!(upstream_request.headers["x-user"], "alice");
+        assert!(!upstream_request.headers.contains_key("x-other"));
+
+        // The server naming no user, the client cannot name one either
+        let mut upstream_request = request(&[("x-user", "admin")]);
+        forward_auth.apply(&mut upstream_request, &[]).unwrap();
+        assert!(!upstream_request.headers.contains_key("x-user"));
+    }
+}
@@ -0,0 +1,301 @@
+use std::net::IpAddr;
+
+use http::{header, HeaderName, StatusCode};
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::config::Limits;
+
+/// Appended to by every proxy between the client and the upstream
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Hop-by-hop headers (RFC 9110, section 7.6.1) that only apply to the connection
+/// between proksi and the upstream.
+///
+/// `Transfer-Encoding` is not part of the list: pingora relies on it to frame the
+/// body sent downstream and rewrites it according to the downstream protocol.
+/// `Connection` is also set by pingora itself for HTTP/1.1 clients.
+pub const HOP_BY_HOP_HEADERS: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Removes the hop-by-hop headers (plus the ones listed in `Connection`) and the
+/// given extra headers from an upstream response.
+/// `Connection` and `Upgrade` are kept for `101 Switching Protocols` responses
+/// so protocol upgrades (ex: websockets) keep working.
+pub fn strip_from_response(response: &mut ResponseHeader, extra: &[HeaderName]) {
+    let upgrading = response.status == StatusCode::SWITCHING_PROTOCOLS;
+
+    if !upgrading {
+        // Headers declared as hop-by-hop by the upstream itself
+        let listed = response
+            .headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+            .collect::<Vec<_>>();
+
+        for name in &listed {
+            response.remove_header(name);
+        }
+        for name in HOP_BY_HOP_HEADERS {
+            response.remove_header(name);
+        }
+    }
+
+    for name in extra {
+        response.remove_header(name);
+    }
+}
+
+/// Checks the request headers against the configured limits, returning
+/// the limit that was exceeded. The size of each header is counted as it is
+/// sent in HTTP/1 (`name: value\r\n`), for every protocol.
+pub fn check_request_limits(request: &RequestHeader, limits: &Limits) -> Result<(), &'static str> {
+    if request.headers.len() > limits.max_headers {
+        return Err("too many request headers");
+    }
+
+    let size: usize = request
+        .headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    if size > limits.max_header_size {
+        return Err("request headers too large");
+    }
+
+    Ok(())
+}
+
+/// Sets the `X-Forwarded-*` headers of a request to the upstream. The IP of
+/// the client is appended to the `X-Forwarded-For` the request came with, so
+/// the upstream gets the whole chain of the proxies.
+pub fn set_forwarded(
+    request: &mut RequestHeader,
+    client: Option<IpAddr>,
+    host: &str,
+) -> pingora::Result<()> {
+    if let Some(client) = client {
+        let client = client.to_string();
+        let chain = request
+            .headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .chain(std::iter::once(client.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        request.insert_header(X_FORWARDED_FOR, chain)?;
+    }
+
+    request.insert_header("x-forwarded-proto", "https")?;
+    if !host.is_empty() {
+        request.insert_header("x-forwarded-host", host)?;
+    }
+    Ok(())
+}
+
+/// Whether the request opens a long-lived connection: a protocol upgrade
+/// (ex: websockets) or a stream of server-sent events
+pub fn is_long_lived(request: &RequestHeader) -> bool {
+    let upgrade =
+        request.version == http::Version::HTTP_11 && request.headers.contains_key(header::UPGRADE);
+
+    let event_stream = request
+        .headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("text/event-stream"));
+
+    upgrade || event_stream
+}
+
+/// The canonical case of a header name, as HTTP/1.1 clients send it
+/// (`x-request-id` is `X-Request-Id`)
+pub fn title_case(name: &str) -> String {
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Gives the header names of a request received over HTTP/2 (always lowercase,
+/// without an original case) their canonical case, for an HTTP/1.1 upstream.
+/// The request of an HTTP/1.1 client already keeps the case of its names.
+pub fn restore_case(request: &mut RequestHeader) -> pingora::Result<()> {
+    // Only a request built with a case map writes its names as they were inserted
+    let mut cased = RequestHeader::build(
+        request.method.clone(),
+        request.raw_path(),
+        Some(request.headers.len()),
+    )?;
+    cased.set_uri(request.uri.clone());
+    cased.set_version(request.version);
+    for (name, value) in &request.headers {
+        cased.append_header(title_case(name.as_str()), value.clone())?;
+    }
+
+    *request = cased;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, headers: &[(&'static str, &'static str)]) -> ResponseHeader {
+        let mut response = ResponseHeader::build(status, None).unwrap();
+        for (name, value) in headers {
+            response.append_header(*name, *value).unwrap();
+        }
+        response
+    }
+
+    #[test]
+    fn test_strips_hop_by_hop_and_extra_headers() {
+        let mut res = response(
+            200,
+            &[
+                ("connection", "keep-alive, x-internal"),
+                ("keep-alive", "timeout=5"),
+                ("x-internal", "1"),
+                ("x-powered-by", "php"),
+                ("transfer-encoding", "chunked"),
+                ("content-type", "text/plain"),
+            ],
+        );
+
+        strip_from_response(&mut res, &[HeaderName::from_static("x-powered-by")]);
+
+        for name in ["connection", "keep-alive", "x-internal", "x-powered-by"] {
+            assert!(res.headers.get(name).is_none(), "{name} should be removed");
+        }
+        assert!(res.headers.get("transfer-encoding").is_some());
+        assert!(res.headers.get("content-type").is_some());
+    }
+
+    #[test]
+    fn test_check_request_limits() {
+        let limits = Limits {
+            max_header_size: 64,
+            max_headers: 3,
+            max_connections_per_ip: None,
+        };
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("host", "example.com").unwrap();
+        req.append_header("accept", "*/*").unwrap();
+        assert!(check_request_limits(&req, &limits).is_ok());
+
+        req.append_header("x-large", "a".repeat(40)).unwrap();
+        assert_eq!(
+            check_request_limits(&req, &limits),
+            Err("request headers too large")
+        );
+
+        req.append_header("x-extra", "1").unwrap();
+        assert_eq!(
+            check_request_limits(&req, &limits),
+            Err("too many request headers")
+        );
+    }
+
+    #[test]
+    fn test_keeps_upgrade_headers_when_switching_protocols() {
+        let mut res = response(101, &[("connection", "upgrade"), ("upgrade", "websocket")]);
+
+        strip_from_response(&mut res, &[]);
+
+        assert_eq!(res.headers.get("upgrade").unwrap(), "websocket");
+        assert_eq!(res.headers.get("connection").unwrap(), "upgrade");
+    }
+
+    #[test]
+    fn test_is_long_lived() {
+        let request = |headers: &[(&'static str, &'static str)]| {
+            let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+            for (name, value) in headers {
+                req.append_header(*name, *value).unwrap();
+            }
+            req
+        };
+
+        assert!(!is_long_lived(&request(&[("accept", "text/html")])));
+        assert!(is_long_lived(&request(&[
+            ("connection", "upgrade"),
+            ("upgrade", "websocket")
+        ])));
+        assert!(is_long_lived(&request(&[("accept", "text/event-stream")])));
+    }
+
+    #[test]
+    fn test_restore_case() {
+        assert_eq!(title_case("content-type"), "Content-Type");
+        assert_eq!(title_case("x-api-key"), "X-Api-Key");
+        assert_eq!(title_case("te"), "Te");
+
+        // As received from an HTTP/2 client
+        let mut request = RequestHeader::build_no_case("POST", b"/api?page=2", None).unwrap();
+        request
+            .append_header("content-type", "application/json")
+            .unwrap();
+        request.append_header("x-request-id", "1").unwrap();
+        request.append_header("x-request-id", "2").unwrap();
+
+        restore_case(&mut request).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.uri, "/api?page=2");
+
+        let mut wire = vec![];
+        request.header_to_h1_wire(&mut wire);
+        assert_eq!(
+            String::from_utf8(wire).unwrap(),
+            "Content-Type: application/json\r\nX-Request-Id: 1\r\nX-Request-Id: 2\r\n"
+        );
+    }
+
+    #[test]
+    fn test_set_forwarded() {
+        let client = Some("203.0.113.7".parse().unwrap());
+
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        set_forwarded(&mut request, client, "example.com").unwrap();
+        assert_eq!(request.headers["x-forwarded-for"], "203.0.113.7");
+        assert_eq!(request.headers["x-forwarded-proto"], "https");
+        assert_eq!(request.headers["x-forwarded-host"], "example.com");
+
+        // The chain of the proxies in front is kept, even split across headers
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        request
+            .append_header("x-forwarded-for", "198.51.100.1")
+            .unwrap();
+        request
+            .append_header("x-forwarded-for", "10.0.0.1")
+            .unwrap();
+        request.insert_header("x-forwarded-proto", "http").unwrap();
+        set_forwarded(&mut request, client, "").unwrap();
+        assert_eq!(
+            request.headers["x-forwarded-for"],
+            "198.51.100.1, 10.0.0.1, 203.0.113.7"
+        );
+        assert_eq!(request.headers.get_all("x-forwarded-for").iter().count(), 1);
+        assert_eq!(request.headers["x-forwarded-proto"], "https");
+        assert!(request.headers.get("x-forwarded-host").is_none());
+    }
+}
@@ -0,0 +1,194 @@
+//! HTTP/1.0 clients (legacy clients, health checkers).
+//!
+//! Pingora keeps every HTTP/1.x connection alive unless the client sends
+//! `Connection: close`, and frames the bodies of unknown length with chunked
+//! encoding, which HTTP/1.0 clients do not understand (RFC 9112, section 6.1).
+//! Their connections are only kept alive when they ask for it, and such bodies
+//! end with the connection instead.
+
+use http::{header, Method, StatusCode, Version};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    protocols::http::ServerSession,
+};
+
+/// Whether the request was sent by an HTTP/1.0 (or older) client
+pub fn is_http10(request: &RequestHeader) -> bool {
+    matches!(request.version, Version::HTTP_09 | Version::HTTP_10)
+}
+
+/// Whether the request lists `keep-alive` in its `Connection` header
+fn requests_keepalive(request: &RequestHeader) -> bool {
+    request
+        .headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("keep-alive"))
+}
+
+/// Closes the connection of an HTTP/1.0 client after the response, unless it
+/// sent `Connection: keep-alive`
+pub fn respect_keepalive(session: &mut ServerSession) {
+    let request = session.req_header();
+    if is_http10(request) && !requests_keepalive(request) {
+        session.set_keepalive(None);
+    }
+}
+
+/// Removes the chunked encoding of a response, returns whether its body then
+/// ends with the connection (no `Content-Length`)
+fn unchunk(response: &mut ResponseHeader, head_request: bool) -> bool {
+    let chunked = response
+        .headers
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.to_ascii_lowercase().contains("chunked"));
+
+    if chunked {
+        // The length is framed by the chunks, not the header (RFC 9112, section 6.3)
+        response.remove_header(&header::TRANSFER_ENCODING);
+        response.remove_header(&header::CONTENT_LENGTH);
+    }
+
+    let no_body = head_request
+        || response.status.is_informational()
+        || matches!(
+            response.status,
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        );
+
+    !no_body && !response.headers.contains_key(header::CONTENT_LENGTH)
+}
+
+/// Frames the response of an HTTP/1.0 client without chunked encoding: a body
+/// of unknown length is sent as is, and the connection closed after it
+pub fn filter_response(session: &mut ServerSession, response: &mut ResponseHeader) {
+    if !is_http10(session.req_header()) {
+        return;
+    }
+
+    let head_request = session.req_header().method == Method::HEAD;
+    if unchunk(response, head_request) {
+        session.set_keepalive(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use pingora::protocols::l4::stream::Stream;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+
+    use super::*;
+
+    /// Answers the raw request with the given response headers and body (as
+    /// pingora would once proxied), returns the raw response and whether the
+    /// connection was kept alive
+    fn exchange(
+        request: &'static [u8],
+        headers: &[(&str, &str)],
+        body: &'static [u8],
+    ) -> (String, bool) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let (mut client, server) = UnixStream::pair().unwrap();
+            client.write_all(request).await.unwrap();
+
+            let mut session = ServerSession::new_http1(Box::new(Stream::from(server)));
+            assert!(session.read_request().await.unwrap());
+            // Default of pingora for every new request
+            session.set_keepalive(Some(60));
+            respect_keepalive(&mut session);
+
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            for (name, value) in headers {
+                response.insert_header(*name, *value).unwrap();
+            }
+            filter_response(&mut session, &mut response);
+
+            session
+                .write_response_header(Box::new(response))
+                .await
+                .unwrap();
+            session
+                .write_response_body(Bytes::from_static(body), true)
+                .await
+                .unwrap();
+            // Dropped right away, so the response can be read until the end
+            let kept_alive = session.finish().await.unwrap().is_some();
+
+            let mut raw = Vec::new();
+            client.read_to_end(&mut raw).await.unwrap();
+
+            (String::from_utf8(raw).unwrap().to_lowercase(), kept_alive)
+        })
+    }
+
+    #[test]
+    fn test_http10_closes_unless_keepalive() {
+        let length = [("content-length", "2")];
+
+        let (response, kept_alive) = exchange(b"GET / HTTP/1.0\r\n\r\n", &length, b"ok");
+        assert!(!kept_alive);
+        assert!(response.contains("connection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\nok"));
+
+        let (response, kept_alive) = exchange(
+            b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n",
+            &length,
+            b"ok",
+        );
+        assert!(kept_alive);
+        assert!(response.contains("connection: keep-alive\r\n"));
+
+        let (_, kept_alive) = exchange(
+            b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+            &length,
+            b"ok",
+        );
+        assert!(!kept_alive);
+
+        let (_, kept_alive) = exchange(
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            &length,
+            b"ok",
+        );
+        assert!(kept_alive);
+    }
+
+    #[test]
+    fn test_http10_body_without_length_ends_with_connection() {
+        let chunked = [("transfer-encoding", "chunked")];
+
+        // Even when the client asked to keep the connection alive
+        let (response, kept_alive) = exchange(
+            b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+            &chunked,
+            b"hello",
+        );
+        assert!(!kept_alive);
+        assert!(!response.contains("transfer-encoding"));
+        assert!(response.contains("connection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+
+        // HTTP/1.1 clients keep the chunked encoding
+        let (response, kept_alive) = exchange(
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            &chunked,
+            b"hello",
+        );
+        assert!(kept_alive);
+        assert!(response.contains("transfer-encoding: chunked\r\n"));
+        assert!(response.ends_with("5\r\nhello\r\n0\r\n\r\n"));
+    }
+}
@@ -10,9 +10,49 @@ use pingora::upstreams::peer::HttpPeer;
 use pingora::proxy::{ProxyHttp, Session};
 use tracing::info;
 
-use crate::stores;
+use crate::{
+    config::{Config, Limits},
+    stores,
+};
+
+use super::{allowed_hosts::AllowedHosts, connection_limits, connections, http10, matching};
+
+/// Path of the ACME HTTP-01 challenges (RFC 8555, section 8.3)
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge";
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+pub struct HttpLB {
+    pub limits: Limits,
+    /// Whether the ACME challenges are answered
+    pub acme_challenges: bool,
+    /// Whether the other requests are redirected to HTTPS, instead of a 404
+    /// (`listeners.http_redirect`, never for `listeners.acme_challenge_address`)
+    pub redirect_to_https: bool,
+    /// Hosts redirected besides the ones of the routes, when restricted
+    pub allowed_hosts: Option<AllowedHosts>,
+}
+
+impl HttpLB {
+    /// The service of `listeners.http_address`
+    pub fn public(config: &Config) -> Self {
+        HttpLB {
+            limits: config.limits,
+            acme_challenges: config.listeners.acme_challenge_address.is_none(),
+            redirect_to_https: config.listeners.http_redirect,
+            allowed_hosts: AllowedHosts::new(&config.allowed_hosts),
+        }
+    }
 
-pub struct HttpLB {}
+    /// The service of `listeners.acme_challenge_address`
+    pub fn acme_challenges(config: &Config) -> Self {
+        HttpLB {
+            limits: config.limits,
+            acme_challenges: true,
+            redirect_to_https: false,
+            allowed_hosts: None,
+        }
+    }
+}
 
 #[async_trait]
 impl ProxyHttp for HttpLB {
@@ -26,6 +66,14 @@ impl ProxyHttp for HttpLB {
         session: &mut Session,
         _ctx: &mut Self::CTX,
     ) -> pingora::Result<bool> {
+        connections::request(connections::HTTP);
+        connection_limits::check(
+            session,
+            connections::HTTP,
+            self.limits.max_connections_per_ip,
+        )?;
+        http10::respect_keepalive(session);
+
         let req_header = session.req_header();
         let current_uri = &req_header.uri;
 
@@ -47,30 +95,21 @@ impl ProxyHttp for HttpLB {
             return Ok(true);
         }
 
-        // LetsEncrypt/ZeroSSL challenge
-        if current_uri
-            .path()
-            .starts_with("/.well-known/acme-challenge")
+        // LetsEncrypt/ZeroSSL challenge, answered before (and instead of) the redirect
+        // to HTTPS. Unknown or malformed tokens get a 404.
+        let path = current_uri.path();
+        if self.acme_challenges
+            && (path == ACME_CHALLENGE_PATH || path.starts_with(ACME_CHALLENGE_PREFIX))
         {
-            let challenge_from_host = stores::get_challenge_by_key(host);
-
-            if challenge_from_host.is_none() {
-                return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(404)));
-            }
-
-            let challenge_from_host = challenge_from_host.unwrap();
-
-            // Get the token and proof from the challenge store
-            let (token, proof) = challenge_from_host.value();
-            // Get the token from the URL
-            let token_from_url = current_uri.path().split('/').last().unwrap();
-
-            // Token is not the same as the one provided
-            if token != token_from_url {
-                return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(404)));
-            }
-
-            let sample_body = bytes::Bytes::from(proof.clone());
+            let host = matching::host_without_port(host).to_ascii_lowercase();
+            let Some(proof) =
+                challenge_token(path).and_then(|token| key_authorization(&host, token))
+            else {
+                session.respond_error(404).await?;
+                return Ok(true);
+            };
+
+            let sample_body = bytes::Bytes::from(proof);
             let mut res_headers = ResponseHeader::build_no_case(StatusCode::OK, Some(2))?;
             res_headers.append_header(CONTENT_TYPE, "text/plain")?;
             res_headers.append_header(CONTENT_LENGTH, sample_body.len())?;
@@ -83,6 +122,22 @@ impl ProxyHttp for HttpLB {
             return Ok(true);
         }
 
+        if !self.redirect_to_https {
+            session.respond_error(404).await?;
+            return Ok(true);
+        }
+
+        // Forged hosts are not sent back in the redirect
+        let normalized = matching::normalize_host(host);
+        if self
+            .allowed_hosts
+            .as_ref()
+            .is_some_and(|allowed_hosts| !allowed_hosts.accepts(&normalized))
+        {
+            session.respond_error(400).await?;
+            return Ok(true);
+        }
+
         // Redirect to https
         let new_uri = Uri::builder()
             .scheme(Scheme::HTTPS)
@@ -121,6 +176,27 @@ impl ProxyHttp for HttpLB {
     }
 }
 
+/// Extracts the token of an ACME challenge path. Tokens are base64url encoded
+/// (RFC 8555, section 8.1): anything else, such as `/`, `..` or percent-encoded
+/// characters, is rejected.
+fn challenge_token(path: &str) -> Option<&str> {
+    let token = path.strip_prefix(ACME_CHALLENGE_PREFIX)?;
+    let valid = !token.is_empty()
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+    valid.then_some(token)
+}
+
+/// Returns the key authorization of the pending challenge of the host,
+/// only when its token is the requested one
+fn key_authorization(host: &str, token: &str) -> Option<String> {
+    let challenge = stores::get_challenge_by_key(host)?;
+
+    (challenge.token == token).then(|| challenge.key_authorization.clone())
+}
+
 /// Retrieves the host from the request headers based on
 /// whether the request is HTTP/1.1 or HTTP/2
 fn get_host(session: &Session) -> &str {
@@ -134,3 +210,42 @@ fn get_host(session: &Session) -> &str {
 
     ""
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_token() {
+        assert_eq!(
+            challenge_token(
+                "/.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"
+            ),
+            Some("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0")
+        );
+        assert_eq!(challenge_token("/.well-known/acme-challenge/"), None);
+        assert_eq!(challenge_token("/.well-known/acme-challenge"), None);
+        assert_eq!(challenge_token("/.well-known/acme-challenge/a/b"), None);
+        assert_eq!(
+            challenge_token("/.well-known/acme-challenge/../token"),
+            None
+        );
+        assert_eq!(challenge_token("/.well-known/acme-challenge/%2e%2e"), None);
+        assert_eq!(challenge_token("/other/token"), None);
+    }
+
+    #[test]
+    fn test_key_authorization() {
+        stores::insert_challenge(
+            "acme.test".to_string(),
+            ("token".to_string(), "token.thumbprint".to_string()),
+        );
+
+        assert_eq!(
+            key_authorization("acme.test", "token").as_deref(),
+            Some("token.thumbprint")
+        );
+        assert_eq!(key_authorization("acme.test", "other"), None);
+        assert_eq!(key_authorization("unknown.test", "token"), None);
+    }
+}
@@ -1,45 +1,193 @@
-use std::net::ToSocketAddrs;
 use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 use std::{borrow::Cow, collections::HashMap};
 
 use async_trait::async_trait;
 
-use http::uri::PathAndQuery;
-use http::{HeaderName, HeaderValue, Uri};
+use http::{HeaderName, HeaderValue, Uri, Version};
 use once_cell::sync::Lazy;
 
-use openssl::base64;
 use pingora::http::{RequestHeader, ResponseHeader};
-use pingora::protocols::Digest;
+use pingora::lb::Backend;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::protocols::{Digest, ALPN};
 use pingora::proxy::{ProxyHttp, Session};
+use pingora::upstreams::peer::HttpPeer;
 use pingora::upstreams::peer::Peer;
-use pingora::{upstreams::peer::HttpPeer, ErrorType::HTTPStatus};
-
-use pingora_cache::lock::CacheLock;
+use pingora::ErrorSource;
+use pingora::ErrorType::{
+    ConnectTimedout, ConnectionClosed, HTTPStatus, InvalidHTTPHeader, ReadError, ReadTimedout,
+    WriteError, WriteTimedout,
+};
 
 use pingora_cache::{CacheKey, CacheMeta, NoCacheReason, RespCacheable};
+use tokio::sync::OwnedSemaphorePermit;
 
-use crate::cache::disk::storage::DiskCache;
-use crate::config::{RouteCacheType, RouteUpstream};
-use crate::stores::{self, routes::RouteStoreContainer};
+use crate::cache::{coalescing, disk::storage::DiskCache, requests, stale};
+use crate::config::{
+    BodyLengthMismatchAction, BodyLengthMismatches, Compression, Config, ExpectContinue,
+    ForwardPath, Limits, Request, RouteCacheType, RouteStickyBy, RouteUpstream,
+    RouteUpstreamProtocol, Timeouts, Tracing, TruncatedResponses,
+};
+use crate::metrics;
+use crate::plugins::auth;
+use crate::stores::{
+    self,
+    routes::{ActiveRequest, RouteStoreContainer, UpstreamConnection},
+    sticky::StickyKey,
+};
+use crate::tools::{client_ip, path};
 
 use super::{
+    allowed_hosts::AllowedHosts,
+    body_length::{self, BodyLength, Mismatch},
+    client_auth, compression, concurrency, connection_limits, connection_reuse, connections,
+    error_handling::{self, ErrorHandling, Interception},
+    forward_auth::{self, Verdict},
+    headers, http10, informational,
+    matching::{self, RouteMatch},
+    methods::MethodFilter,
     middleware::{
         execute_request_plugins, execute_response_plugins, execute_upstream_request_plugins,
         execute_upstream_response_plugins,
     },
-    DEFAULT_PEER_OPTIONS,
+    mirror::MirroredRequest,
+    no_upstream::{self, NoUpstream},
+    request_buffer,
+    request_compression::RequestCompressor,
+    retry_budget::RetryBudget,
+    sampling,
+    substitution::BodyRewrite,
+    truncation, DEFAULT_PEER_OPTIONS,
 };
 
 static STORAGE_MEM_CACHE: Lazy<pingora_cache::MemCache> = Lazy::new(pingora_cache::MemCache::new);
 static STORAGE_CACHE: Lazy<DiskCache> = Lazy::new(DiskCache::new);
-static CACHEABLE_METHODS: Lazy<Vec<http::Method>> =
-    Lazy::new(|| vec![http::Method::GET, http::Method::HEAD]);
-static CACHE_LOCK: Lazy<CacheLock> = Lazy::new(|| CacheLock::new(Duration::from_secs(1)));
 
 /// Load balancer proxy struct
-pub struct Router {}
+pub struct Router {
+    /// Extra response headers removed from every upstream response
+    strip_response_headers: Vec<HeaderName>,
+
+    /// Compression of the routes that do not override it
+    compression: Compression,
+
+    /// Sampling of the access logs
+    tracing: Tracing,
+
+    /// Limits of the request headers
+    limits: Limits,
+
+    /// Handling of the responses cut short by their upstream
+    truncated_responses: TruncatedResponses,
+
+    /// Handling of the responses whose body does not match their length
+    body_length_mismatches: BodyLengthMismatches,
+
+    /// Requests denied or answered based on their method
+    methods: MethodFilter,
+
+    /// Hosts accepted besides the ones of the routes, when restricted
+    allowed_hosts: Option<AllowedHosts>,
+
+    /// Idle timeout of the long-lived connections
+    timeouts: Timeouts,
+
+    /// Request bodies read before the upstream is connected
+    request: Request,
+
+    /// Status of the requests whose route has no healthy upstream
+    no_upstream_status: u16,
+
+    /// Receive buffer of the upstream connections
+    buffer_size: usize,
+
+    /// Whether the `1xx` responses of the upstreams are relayed
+    informational_responses: bool,
+
+    /// Retries of the requests of all routes, when bounded
+    retry_budget: Option<RetryBudget>,
+
+    /// Requests slower than this are logged as a warning
+    slow_request_threshold: Option<Duration>,
+}
+
+impl Router {
+    pub fn new(config: &Config) -> Self {
+        let strip_response_headers = config
+            .headers
+            .strip_response
+            .iter()
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .collect();
+
+        Self {
+            strip_response_headers,
+            compression: config.compression.clone(),
+            tracing: config.tracing.clone(),
+            limits: config.limits,
+            truncated_responses: config.truncated_responses.clone(),
+            body_length_mismatches: config.body_length_mismatches,
+            methods: MethodFilter::new(&config.methods),
+            allowed_hosts: AllowedHosts::new(&config.allowed_hosts),
+            timeouts: config.timeouts,
+            request: config.request,
+            no_upstream_status: config.no_upstream.status,
+            buffer_size: config.proxy.buffer_size,
+            informational_responses: config.proxy.informational_responses,
+            retry_budget: RetryBudget::new(&config.retry_budget),
+            slow_request_threshold: config
+                .logging
+                .slow_request_threshold
+                .map(Duration::from_millis),
+        }
+    }
+
+    /// Denies the retry of a failed request once the retry budget is spent
+    fn budget_retry(&self, ctx: &RouterContext, error: &mut pingora::Error) {
+        let Some(budget) = &self.retry_budget else {
+            return;
+        };
+
+        if error.retry() && !budget.withdraw(std::time::Instant::now()) {
+            error.set_retry(false);
+            metrics::HTTP_RETRIES_THROTTLED
+                .with_label_values(&[&ctx.host])
+                .inc();
+        }
+    }
+
+    /// Sends the request to another upstream when its route retries the
+    /// connection errors (`retries`), for its method
+    fn retry_on_connect_error(
+        &self,
+        session: &Session,
+        ctx: &mut RouterContext,
+        error: &mut pingora::Error,
+    ) {
+        let Some(retries) = &ctx.route_container.retries else {
+            return;
+        };
+
+        if error.retry() || !retries.on_connect_error(&session.req_header().method, ctx.retried) {
+            return;
+        }
+
+        error.set_retry(true);
+        ctx.retried += 1;
+        if let Some(addr) = &ctx.upstream_addr {
+            ctx.failed_upstreams.push(addr.clone());
+        }
+        metrics::HTTP_UPSTREAM_RETRIES
+            .with_label_values(&[&ctx.host, "connect_error"])
+            .inc();
+    }
+
+    /// The error answering a request whose route has no upstream for it
+    fn no_upstream(&self, ctx: &RouterContext, reason: NoUpstream) -> Box<pingora::Error> {
+        no_upstream::error(&ctx.host, reason, self.no_upstream_status)
+    }
+}
 
 // type Container = mapref::one::Ref<'static, String, RouteStoreContainer>;
 
@@ -47,6 +195,33 @@ pub struct Router {}
 //     ctx.route_container.clone()
 // }
 
+/// Selects the upstream of the request, other than the ones that already
+/// failed it while the route has some (`retries`)
+fn select_backend(
+    route_container: &RouteStoreContainer,
+    pool: Option<&str>,
+    ctx: &RouterContext,
+    client: Option<&StickyKey>,
+) -> Option<Backend> {
+    let mut backend = route_container.select_backend_for(pool, ctx.path_group, client);
+    if ctx.failed_upstreams.is_empty() {
+        return backend;
+    }
+
+    // The load balancer goes through all of the upstreams in as many selections
+    let upstreams = route_container.load_balancer.backends().get_backend().len();
+    for _ in 1..upstreams {
+        match &backend {
+            Some(selected) if ctx.failed_upstreams.contains(&selected.addr) => {
+                backend = route_container.select_backend_for(pool, ctx.path_group, client);
+            }
+            _ => break,
+        }
+    }
+
+    backend
+}
+
 fn get_cache_storage(cache_type: &RouteCacheType) -> &'static (dyn pingora_cache::Storage + Sync) {
     match cache_type {
         RouteCacheType::Disk => &*STORAGE_CACHE,
@@ -56,15 +231,72 @@ fn get_cache_storage(cache_type: &RouteCacheType) -> &'static (dyn pingora_cache
 
 pub struct RouterContext {
     pub host: String,
+    /// The host of the route serving the request (its key in the route store)
+    pub route: Option<String>,
     pub route_container: RouteStoreContainer,
     pub upstream: RouteUpstream,
     pub extensions: HashMap<Cow<'static, str>, String>,
+    /// The request in flight to the selected upstream
+    pub active_request: Option<ActiveRequest>,
+    /// The body of the response being rewritten (`response.substitute`)
+    pub body_rewrite: Option<BodyRewrite>,
+    /// The body of the upstream response against its `Content-Length`
+    pub body_length: BodyLength,
+    /// The address of the selected upstream
+    pub upstream_addr: Option<SocketAddr>,
+    /// Whether the HTTP version of the upstream is negotiated, recorded with its response
+    pub negotiating: bool,
+    /// The body of the request being compressed (`request_compression`)
+    pub request_compressor: Option<RequestCompressor>,
+    /// The slot of the route held by the request (`concurrency`)
+    pub concurrency_permit: Option<OwnedSemaphorePermit>,
+    /// When the upstream must have sent the whole response (`timeouts.response_secs`)
+    pub response_deadline: Option<std::time::Instant>,
+    /// The copy of the request sent to the shadow upstream (`mirror`)
+    pub mirror: Option<MirroredRequest>,
+    /// The connection used to the selected upstream (`max_connections`)
+    pub upstream_connection: Option<UpstreamConnection>,
+    /// The `sub` claim of the JWT validated by the `oauth2` plugin (`sticky.by: jwt:sub`)
+    pub jwt_subject: Option<String>,
+    /// The headers of the authentication server copied onto the request (`forward_auth`)
+    pub forward_auth_headers: Vec<(HeaderName, HeaderValue)>,
+    /// The upstream picked from the path of the request (`dynamic_upstream`)
+    pub dynamic_upstream: Option<(Backend, RouteUpstream)>,
+    /// The group of upstreams serving the path of the request (`match_with`
+    /// of the upstreams), `None` for the default group
+    pub path_group: Option<usize>,
+    /// The upstream responses of the request intercepted so far (`error_handling`)
+    pub intercepted: usize,
+    /// How long the request waits before it is sent again (`Retry-After`)
+    pub retry_delay: Option<Duration>,
+    /// How many times the request was sent again (`retries`)
+    pub retried: usize,
+    /// The upstreams that failed the request, not selected again (`retries`)
+    pub failed_upstreams: Vec<SocketAddr>,
 
     pub timings: RouterTimings,
 }
 
 pub struct RouterTimings {
     request_filter_start: std::time::Instant,
+    /// When the upstream of the request started being selected
+    upstream_peer_start: Option<std::time::Instant>,
+    /// When the connection to the upstream was established (or reused)
+    upstream_connected: Option<std::time::Instant>,
+    /// When the response headers of the upstream were received
+    upstream_response: Option<std::time::Instant>,
+}
+
+impl RouterTimings {
+    /// Time taken to connect to the upstream
+    fn connect(&self) -> Option<Duration> {
+        Some(self.upstream_connected? - self.upstream_peer_start?)
+    }
+
+    /// Time until the first byte (the response headers) of the upstream
+    fn ttfb(&self) -> Option<Duration> {
+        Some(self.upstream_response? - self.request_filter_start)
+    }
 }
 
 #[async_trait]
@@ -76,16 +308,57 @@ impl ProxyHttp for Router {
     fn new_ctx(&self) -> Self::CTX {
         RouterContext {
             host: String::new(),
+            route: None,
             route_container: RouteStoreContainer::default(),
             upstream: RouteUpstream::default(),
             extensions: HashMap::with_capacity(2),
+            active_request: None,
+            body_rewrite: None,
+            body_length: BodyLength::default(),
+            upstream_addr: None,
+            negotiating: false,
+            request_compressor: None,
+            concurrency_permit: None,
+            response_deadline: None,
+            mirror: None,
+            upstream_connection: None,
+            jwt_subject: None,
+            forward_auth_headers: Vec::new(),
+            dynamic_upstream: None,
+            path_group: None,
+            intercepted: 0,
+            retry_delay: None,
+            retried: 0,
+            failed_upstreams: Vec::new(),
 
             timings: RouterTimings {
                 request_filter_start: std::time::Instant::now(),
+                upstream_peer_start: None,
+                upstream_connected: None,
+                upstream_response: None,
             },
         }
     }
 
+    /// Rejects requests whose headers exceed the configured limits (and the
+    /// connections over the limit of their client IP) before any other filter
+    /// (or downstream module) runs.
+    async fn early_request_filter(
+        &self,
+        session: &mut Session,
+        _ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        connections::request(connections::HTTPS);
+        connection_limits::check(
+            session,
+            connections::HTTPS,
+            self.limits.max_connections_per_ip,
+        )?;
+
+        headers::check_request_limits(session.req_header(), &self.limits)
+            .map_err(|reason| pingora::Error::explain(HTTPStatus(431), reason))
+    }
+
     // Define the filter that will be executed before the request is sent to the upstream.
     // If the filter returns `true`, the request has already been handled.
     // If the filter returns `false`, the request will be sent to the upstream.
@@ -94,39 +367,119 @@ impl ProxyHttp for Router {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<bool> {
+        http10::respect_keepalive(session);
+
+        // Denied methods and `OPTIONS` requests answered by proksi, for every host
+        if let Some(status) = self.methods.status(session.req_header()) {
+            self.methods.respond(session, status).await?;
+            return Ok(true);
+        }
+
         let req_host = get_host(session);
-        let host_without_port = req_host.split(':').collect::<Vec<_>>()[0];
-        host_without_port.clone_into(&mut ctx.host);
+        ctx.host = matching::normalize_host(req_host).into_owned();
+
+        if self
+            .allowed_hosts
+            .as_ref()
+            .is_some_and(|allowed| !allowed.accepts(&ctx.host))
+        {
+            session.respond_error(400).await?;
+            return Ok(true);
+        }
 
-        ctx.host = host_without_port.to_string();
+        if let Some(client_auth) = stores::find_client_auth_by_host(&ctx.host) {
+            // A connection reused across hosts (HTTP/2) may not have gone through
+            // the client certificate verification of this host
+            if !client_auth::has_client_certificate(session) {
+                session.respond_error(421).await?;
+                return Ok(true);
+            }
 
-        // If there's no host matching, returns a 404
-        let Some(route_container) = stores::get_route_by_key(host_without_port) else {
+            if client_auth.rejects_key(session) {
+                session.respond_error(403).await?;
+                return Ok(true);
+            }
+        }
+
+        // Match the route based on the host and the request pattern of the URI,
+        // returns a 404 when there is no match
+        // Matched on the normalized path, so `/api/%2e%2e/admin` is `/admin`
+        let uri = get_uri(session);
+        let path = path::normalize(uri.path());
+        let Ok(RouteMatch {
+            route: route_container,
+            ..
+        }) = matching::match_route(&ctx.host, &path)
+        else {
             session.respond_error(404).await?;
             return Ok(true);
         };
+        ctx.route = Some(route_container.key().clone());
 
-        // Match request pattern based on the URI
-        let uri = get_uri(session);
+        // Sensitive paths never reach the plugins nor the upstreams
+        if let Some(blocked_paths) = &route_container.blocked_paths {
+            if blocked_paths.is_blocked(&path) {
+                tracing::debug!("blocked path {path} for {}", ctx.host);
+                session.respond_error(blocked_paths.status).await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(user_agent) = &route_container.user_agent {
+            let agent = session
+                .get_header(http::header::USER_AGENT)
+                .and_then(|value| value.to_str().ok());
+            if user_agent.is_blocked(agent) {
+                tracing::debug!("blocked user agent {agent:?} for {}", ctx.host);
+                session.respond_error(403).await?;
+                return Ok(true);
+            }
+        }
 
-        match &route_container.path_matcher.pattern {
-            Some(pattern) if pattern.find(uri.path()).is_none() => {
+        // Clients over the limit reach neither the plugins nor the upstreams
+        if let Some(rate_limit) = &route_container.rate_limit {
+            if rate_limit.enforce(session, &ctx.host).await? {
+                return Ok(true);
+            }
+        }
+
+        // Unknown tenants get a 404, as unknown paths do
+        if let Some(dynamic_upstream) = &route_container.dynamic_upstream {
+            let Some(upstream) = dynamic_upstream.resolve(&path).await else {
                 session.respond_error(404).await?;
                 return Ok(true);
+            };
+            ctx.dynamic_upstream = Some(upstream);
+        }
+        ctx.path_group = route_container
+            .path_groups
+            .as_ref()
+            .and_then(|groups| groups.group(&path));
+
+        // Unauthenticated requests reach neither the plugins nor the upstreams
+        if auth::authenticate(session, ctx, &route_container.auth).await? {
+            return Ok(true);
+        }
+        if let Some(forward_auth) = &route_container.forward_auth {
+            match forward_auth.check(session.req_header(), &ctx.host).await? {
+                Verdict::Allowed(headers) => ctx.forward_auth_headers = headers,
+                Verdict::Denied(response, body) => {
+                    forward_auth::respond(session, response, body).await?;
+                    return Ok(true);
+                }
             }
-            _ => {}
         }
 
         // Middleware phase: request_filterx
         // We are checking to see if the request has already been handled
         // by the plugins i.e. (ok(true))
-        if let Ok(true) = execute_request_plugins(session, ctx, &route_container.plugins).await {
+        if let Ok(true) = execute_request_plugins(session, ctx, &route_container).await {
             return Ok(true);
         }
 
         if route_container.cache.is_some() {
             let cache = route_container.cache.as_ref().unwrap();
-            if cache.enabled.unwrap_or(false) {
+            if cache.enabled.unwrap_or(false) && requests::is_cached(session.req_header()) {
                 let storage = get_cache_storage(&cache.cache_type);
 
                 stores::insert_cache_routing(
@@ -134,14 +487,48 @@ impl ProxyHttp for Router {
                     cache.path.to_string_lossy().to_string(),
                     false,
                 );
-                session
-                    .cache
-                    .enable(storage, None, None, Some(&*CACHE_LOCK));
+                // Concurrent misses of a key wait for the first one to be cached
+                let lock = cache
+                    .coalesce
+                    .then(|| coalescing::cache_lock(cache.coalesce_timeout_secs));
+                session.cache.enable(storage, None, None, lock);
             }
         }
 
+        // Compressed bodies are chunked, which HTTP/1.0 clients do not understand
+        if let Some(config) =
+            compression::resolve(route_container.compression.as_ref(), &self.compression)
+                .filter(|_| !http10::is_http10(session.req_header()))
+        {
+            compression::enable(session, &config);
+        }
+
         ctx.route_container = route_container.clone();
 
+        ctx.mirror = route_container
+            .mirror
+            .as_ref()
+            .and_then(|config| MirroredRequest::start(config, session.req_header()));
+
+        // Slow clients send their body before an upstream connection is used
+        request_buffer::buffer(
+            session.as_mut(),
+            self.request.buffer_size,
+            self.request.expect_continue,
+        )
+        .await?;
+
+        // Held until the request ends, the requests above the bound wait or get a 503
+        if let Some(limit) = &ctx.route_container.concurrency {
+            match limit.acquire().await {
+                Ok(permit) => ctx.concurrency_permit = Some(permit),
+                Err(overflow) => {
+                    concurrency::respond(session, overflow).await?;
+                    return Ok(true);
+                }
+            }
+        }
+
         Ok(false)
     }
 
@@ -154,6 +541,20 @@ impl ProxyHttp for Router {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<Box<HttpPeer>> {
+        // The intercepted response asked to wait before the request is sent again
+        if let Some(delay) = ctx.retry_delay.take() {
+            tokio::time::sleep(delay).await;
+        }
+
+        // The retries of the request are not counted, only the request itself
+        let now = std::time::Instant::now();
+        if ctx.timings.upstream_peer_start.is_none() {
+            if let Some(budget) = &self.retry_budget {
+                budget.deposit(now);
+            }
+        }
+        ctx.timings.upstream_peer_start = Some(now);
+
         // If there's no host matching, returns a 404
         let route_container = &ctx.route_container;
 
@@ -161,34 +562,160 @@ impl ProxyHttp for Router {
             session.cache.set_max_file_size_bytes(100 * 1024 * 1024);
         }
 
-        let Some(healthy_upstream) = route_container.load_balancer.select(b"", 32) else {
-            return Err(pingora::Error::new(HTTPStatus(503)));
-        };
+        // The pool of the request (from its geo routing header) is tried first
+        let pool = route_container
+            .geo_routing
+            .as_ref()
+            .and_then(|geo| geo.pool(session.req_header()));
+
+        // None of the upstreams of the newly added route passed a health check yet
+        if route_container
+            .warmup
+            .as_ref()
+            .is_some_and(|warmup| warmup.is_warming(std::time::Instant::now()))
+        {
+            return Err(no_upstream::error(&ctx.host, NoUpstream::WarmingUp, 503));
+        }
 
-        let (healthy_ip, healthy_port) = if let Some(scr) = healthy_upstream.addr.as_inet() {
-            (scr.ip().to_string(), scr.port())
+        let client = route_container
+            .sticky
+            .as_ref()
+            .and_then(|sticky| sticky_key(sticky.config.by, session, ctx));
+        let client = client.as_ref();
+        // The upstream picked from the path does not go through the load balancer
+        let (healthy_upstream, upstream) = if let Some((backend, upstream)) = &ctx.dynamic_upstream
+        {
+            (backend.clone(), upstream)
         } else {
-            return Err(pingora::Error::new(HTTPStatus(503)));
+            match select_backend(route_container, pool, ctx, client) {
+                Some(backend) => {
+                    // The upstreams of the route changed since the backend was selected
+                    let Some(upstream) = matching::find_upstream(route_container, &backend) else {
+                        return Err(self.no_upstream(ctx, NoUpstream::Empty));
+                    };
+                    (backend, upstream)
+                }
+                // No upstream is healthy (with a free connection): the fallback (if any)
+                // answers instead of `no_upstream.status`
+                None => match &route_container.fallback_upstream {
+                    Some(fallback) => {
+                        tracing::debug!("no healthy upstream for {}, using the fallback", ctx.host);
+                        (fallback.backend.clone(), &fallback.upstream)
+                    }
+                    None => {
+                        let reason = no_upstream_reason(route_container);
+                        return Err(self.no_upstream(ctx, reason));
+                    }
+                },
+            }
         };
 
-        let Some(upstream) = route_container.upstreams.iter().find(|u| {
-            format!("{}:{}", u.ip, u.port)
-                .to_socket_addrs()
-                .unwrap()
-                .any(|s| s.ip().to_string() == healthy_ip && s.port() == healthy_port)
-        }) else {
-            return Err(pingora::Error::new(HTTPStatus(503)));
+        let Some(healthy_port) = healthy_upstream
+            .addr
+            .as_inet()
+            .map(std::net::SocketAddr::port)
+        else {
+            return Err(self.no_upstream(ctx, NoUpstream::Empty));
         };
 
         ctx.upstream = upstream.clone();
 
+        // Held until the request ends (or another upstream is tried)
+        ctx.upstream_connection = None;
+        let Some(connection) = ctx
+            .route_container
+            .upstream_connections
+            .open(&ctx.host, &healthy_upstream.addr)
+        else {
+            return Err(self.no_upstream(ctx, NoUpstream::Saturated));
+        };
+        ctx.upstream_connection = Some(connection);
+
+        // Counted until the request ends (or another upstream is tried)
+        ctx.active_request = Some(
+            ctx.route_container
+                .active_requests
+                .start(&healthy_upstream.addr),
+        );
+
+        let addr = healthy_upstream.addr.clone();
+        let tls = upstream.tls(healthy_port);
+
         // https://github.com/cloudflare/pingora/blob/main/docs/user_guide/peer.md?plain=1#L17
         let mut peer = HttpPeer::new(
             healthy_upstream,
-            healthy_port == 443,
+            tls,
             upstream.sni.clone().unwrap_or(String::new()),
         );
         peer.options = DEFAULT_PEER_OPTIONS;
+        if let Some(reuse) = &ctx.route_container.connection_reuse {
+            peer.group_key = connection_reuse::group_key(reuse, session, ctx);
+        }
+        peer.options.tcp_recv_buf = Some(self.buffer_size);
+        if let Some(h2) = upstream.h2 {
+            peer.options.max_h2_streams = h2.max_streams;
+            peer.options.h2_ping_interval =
+                (h2.ping_interval_secs > 0).then(|| Duration::from_secs(h2.ping_interval_secs));
+        }
+
+        // The timeouts of the route replace the global ones
+        let timeouts = ctx.route_container.timeouts.unwrap_or_default();
+        let millis =
+            |route: Option<u64>, global: u64| Duration::from_millis(route.unwrap_or(global));
+        let connect_timeout = millis(timeouts.connect_ms, self.timeouts.connect_ms);
+        peer.options.connection_timeout = Some(connect_timeout);
+        // The TLS handshake gets as long as the TCP connection
+        peer.options.total_connection_timeout = Some(connect_timeout * 2);
+        peer.options.read_timeout = Some(millis(timeouts.read_ms, self.timeouts.read_ms));
+        peer.options.write_timeout = Some(millis(timeouts.write_ms, self.timeouts.write_ms));
+        let response_timeout = timeouts
+            .response_secs
+            .or(self.timeouts.response_secs)
+            .map(Duration::from_secs);
+
+        // Restarted for every upstream the request is sent to
+        ctx.response_deadline = None;
+
+        // The upstream read timeout restarts whenever bytes are proxied either way
+        // (pingora waits for both sides together), making it an idle timeout
+        if headers::is_long_lived(session.req_header()) {
+            peer.options.read_timeout = Some(Duration::from_secs(self.timeouts.idle_secs));
+        } else if let Some(timeout) = response_timeout {
+            // An upstream that sends nothing is cut by the read timeout,
+            // one that sends its response slowly by the response filters
+            ctx.response_deadline = Some(std::time::Instant::now() + timeout);
+            peer.options.read_timeout = peer.options.read_timeout.map(|t| t.min(timeout));
+        }
+
+        ctx.upstream_addr = Some(addr.clone());
+        ctx.negotiating = false;
+        if http10::is_http10(session.req_header()) {
+            // Pingora chunks the responses of HTTP/2 upstreams after the response
+            // filter: HTTP/1.0 clients are proxied over HTTP/1.1 instead
+            peer.options.alpn = ALPN::H1;
+        } else if session.is_upgrade_req() {
+            // HTTP/2 has no `Upgrade`: the protocol switch (ex: websockets)
+            // only happens over HTTP/1.1, whatever the protocol of the upstream
+            peer.options.alpn = ALPN::H1;
+        } else {
+            match upstream.effective_protocol() {
+                RouteUpstreamProtocol::H1 => peer.options.alpn = ALPN::H1,
+                RouteUpstreamProtocol::H2 => peer.options.alpn = ALPN::H2,
+                // Without TLS there is no ALPN, pingora speaks HTTP/1.1
+                RouteUpstreamProtocol::Auto if !tls => {}
+                RouteUpstreamProtocol::Auto => {
+                    let negotiated = &ctx.route_container.negotiated_protocols;
+                    // Upstreams that answered in HTTP/1.1 are no longer offered h2 (until
+                    // their version expires), h2 connections are already reused by pingora
+                    if negotiated.get(&addr) == Some(Version::HTTP_11) {
+                        peer.options.alpn = ALPN::H1;
+                    } else {
+                        ctx.negotiating = true;
+                    }
+                }
+            }
+        }
+
         Ok(Box::new(peer))
     }
 
@@ -202,9 +729,92 @@ impl ProxyHttp for Router {
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
+        if response_timed_out(ctx) {
+            return Err(pingora::Error::explain(
+                HTTPStatus(504),
+                "upstream response timed out",
+            ));
+        }
+
+        // The final response follows, the filters below apply to it
+        if informational::is_informational(upstream_response) {
+            if !informational::relayed(session, self.informational_responses) {
+                informational::withhold(upstream_response)?;
+            }
+            return Ok(());
+        }
+
+        if ctx.body_length.is_conflicting() {
+            if self.body_length_mismatches.action == BodyLengthMismatchAction::Reject {
+                return Err(pingora::Error::explain(
+                    InvalidHTTPHeader,
+                    "upstream response with an ambiguous length",
+                )
+                .into_up());
+            }
+            body_length::normalize(upstream_response)?;
+            session.set_keepalive(None);
+        }
+
         // If there's no host matching, returns a 404
         let route_container = &ctx.route_container;
 
+        // A retried status sends the request to another upstream, unless it came
+        // from the cache or its body was not buffered whole
+        if let (Some(retries), Some(addr)) = (&route_container.retries, &ctx.upstream_addr) {
+            let status = upstream_response.status.as_u16();
+            let from_cache = ctx
+                .extensions
+                .get("cache_state")
+                .is_some_and(|s| s == "stale");
+            if !from_cache
+                && !session.as_ref().retry_buffer_truncated()
+                && retries.on_status(status, &session.req_header().method, ctx.retried)
+            {
+                ctx.retried += 1;
+                ctx.failed_upstreams.push(addr.clone());
+                metrics::HTTP_UPSTREAM_RETRIES
+                    .with_label_values(&[&ctx.host, "status"])
+                    .inc();
+                return Err(ErrorHandling::error(status, true));
+            }
+        }
+
+        // Responses of the upstream only, not the ones of the cache
+        if let (Some(error_handling), Some(addr)) =
+            (&route_container.error_handling, &ctx.upstream_addr)
+        {
+            let from_cache = ctx
+                .extensions
+                .get("cache_state")
+                .is_some_and(|s| s == "stale");
+            let interception = if from_cache {
+                Interception::None
+            } else {
+                error_handling.intercept(
+                    upstream_response,
+                    ctx.intercepted,
+                    !session.as_ref().retry_buffer_truncated(),
+                )
+            };
+            let status = upstream_response.status.as_u16();
+            match interception {
+                Interception::None => {}
+                Interception::ErrorPage => {
+                    ctx.intercepted += 1;
+                    return Err(ErrorHandling::error(status, false));
+                }
+                Interception::Retry { delay, backoff } => {
+                    ctx.intercepted += 1;
+                    ctx.retry_delay = delay;
+                    if let Some(backoff) = backoff {
+                        route_container.backed_off_upstreams.pause(addr, backoff);
+                    }
+                    return Err(ErrorHandling::error(status, true));
+                }
+            }
+        }
+
         for (name, value) in &route_container.host_header_add {
             upstream_response.insert_header(name, value)?;
         }
@@ -214,6 +824,10 @@ impl ProxyHttp for Router {
             upstream_response.remove_header(name);
         }
 
+        if let Some(status_map) = &route_container.status_map {
+            status_map.apply(upstream_response)?;
+        }
+
         let cache_state = ctx.extensions.get("cache_state").cloned();
         if session.cache.enabled() && cache_state.is_some() {
             let cache_state = cache_state.unwrap();
@@ -228,11 +842,23 @@ impl ProxyHttp for Router {
                 HeaderName::from_str("cache-duration").unwrap(),
                 elapsed.as_millis().to_string(),
             )?;
+
+            if cache_state == "stale" {
+                stale::mark(upstream_response)?;
+            }
         }
 
         // Middleware phase: response_filterx
         execute_response_plugins(session, ctx).await?;
 
+        http10::filter_response(session, upstream_response);
+
+        if let Some(config) =
+            compression::resolve(ctx.route_container.compression.as_ref(), &self.compression)
+        {
+            compression::filter_response(session, upstream_response, &config);
+        }
+
         Ok(())
     }
 
@@ -251,6 +877,34 @@ impl ProxyHttp for Router {
 
         let upstream = &ctx.upstream;
 
+        // The `100 Continue` of the upstream cannot reach the client when the
+        // informational responses are not relayed, or over HTTP/2 (which drops them)
+        let forward_continue = self.request.expect_continue == ExpectContinue::Forward
+            && informational::relayed(session, self.informational_responses)
+            && upstream.effective_protocol() != RouteUpstreamProtocol::H2;
+        informational::upstream_request(session, upstream_request, forward_continue).await?;
+
+        // Before the headers below are added, they keep the case they are configured with
+        if upstream.preserve_header_case
+            && session.is_http2()
+            && upstream.effective_protocol() != RouteUpstreamProtocol::H2
+        {
+            headers::restore_case(upstream_request)?;
+        }
+
+        // The headers of the route, before the ones of the upstream
+        let route_container = &ctx.route_container;
+        if route_container.forwarded_headers {
+            let client = client_ip::peer_ip(session);
+            headers::set_forwarded(upstream_request, client, &ctx.host)?;
+        }
+        for name in &route_container.upstream_header_remove {
+            upstream_request.remove_header(name);
+        }
+        for (name, value) in &route_container.upstream_header_add {
+            upstream_request.insert_header(name, value)?;
+        }
+
         // TODO: refactor
         if let Some(headers) = upstream.headers.as_ref() {
             if let Some(add) = headers.add.as_ref() {
@@ -262,10 +916,68 @@ impl ProxyHttp for Router {
             }
         }
 
+        // The identity of the client is the one the authentication server gave
+        if let Some(forward_auth) = &ctx.route_container.forward_auth {
+            forward_auth.apply(upstream_request, &ctx.forward_auth_headers)?;
+        }
+
+        if self.request.forward_path == ForwardPath::Normalized {
+            if let Some(uri) = path::normalized_uri(&upstream_request.uri) {
+                upstream_request.set_uri(uri);
+            }
+        }
+
+        // Bodies are rewritten as they are sent by the upstream, not compressed
+        if ctx.route_container.substitutions.is_some() {
+            upstream_request.remove_header(&http::header::ACCEPT_ENCODING);
+        }
+
         execute_upstream_request_plugins(session, upstream_request, ctx)
             .await
             .ok();
 
+        // Started again for every upstream the request is sent to
+        ctx.request_compressor = None;
+        if let Some(config) = &ctx.upstream.request_compression {
+            let advertised = ctx
+                .upstream_addr
+                .as_ref()
+                .is_some_and(|addr| ctx.route_container.gzip_upstreams.accepts_gzip(addr));
+            ctx.request_compressor =
+                RequestCompressor::start(config, upstream_request, advertised)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the body of the request to its mirror (`mirror`), then compresses
+    /// it for the upstream (`request_compression`)
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // Sent once, even when the request is retried
+        if let Some(mut mirror) = ctx.mirror.take() {
+            // Bodies larger than `max_body_size` are not mirrored
+            if mirror.push(body.as_ref()) {
+                if end_of_stream {
+                    mirror.send();
+                } else {
+                    ctx.mirror = Some(mirror);
+                }
+            }
+        }
+
+        if let Some(compressor) = ctx.request_compressor.as_mut() {
+            compressor.filter(body, end_of_stream)?;
+        }
+
         Ok(())
     }
 
@@ -285,11 +997,184 @@ impl ProxyHttp for Router {
         // If there's no host matching, returns a 404
         // let route_container = process_route(ctx);
 
+        headers::strip_from_response(upstream_response, &self.strip_response_headers);
+
+        // The first response headers (ex: `100 Continue`) are the first byte
+        ctx.timings
+            .upstream_response
+            .get_or_insert_with(std::time::Instant::now);
+
+        if informational::is_informational(upstream_response) {
+            return;
+        }
+
+        let head_request = session.req_header().method == http::Method::HEAD;
+        ctx.body_length.response(upstream_response, head_request);
+        if ctx.body_length.is_conflicting() {
+            body_length::report(Mismatch::Conflicting, &ctx.host, ctx.upstream_addr.as_ref());
+        }
+
+        if let Some(addr) = &ctx.upstream_addr {
+            if std::mem::take(&mut ctx.negotiating) {
+                ctx.route_container
+                    .negotiated_protocols
+                    .record(addr, upstream_response.version);
+            }
+            ctx.route_container
+                .gzip_upstreams
+                .record(addr, upstream_response);
+        }
+
+        // The length of a rewritten body is only known once it is rewritten
+        if let Some(substitutions) = &ctx.route_container.substitutions {
+            if substitutions.applies_to(upstream_response, head_request) {
+                upstream_response.remove_header(&http::header::CONTENT_LENGTH);
+                ctx.body_rewrite = Some(BodyRewrite::new(substitutions.clone()));
+            }
+        }
+
         execute_upstream_response_plugins(session, upstream_response, ctx);
 
         //
     }
 
+    /// Rewrites the body of the response before it is cached, so that cached
+    /// responses are served rewritten
+    fn upstream_response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        // The client cannot tell where the response ends, the connection is not reused
+        if let Some(mismatch) = ctx.body_length.body(body.as_ref(), end_of_stream) {
+            body_length::report(mismatch, &ctx.host, ctx.upstream_addr.as_ref());
+            session.set_keepalive(None);
+        }
+
+        if let Some(rewrite) = ctx.body_rewrite.as_mut() {
+            rewrite.filter(&ctx.host, body, end_of_stream);
+        }
+    }
+
+    /// Cuts the responses whose upstream is past `timeouts.response_secs`
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<Option<Duration>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if !end_of_stream && response_timed_out(ctx) {
+            return Err(pingora::Error::create(
+                ReadTimedout,
+                ErrorSource::Upstream,
+                Some("upstream response timed out".into()),
+                None,
+            ));
+        }
+
+        Ok(None)
+    }
+
+    /// The failed connections retried by pingora (or by `retries`, on another
+    /// upstream) spend the retry budget
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        self.retry_on_connect_error(session, ctx, &mut e);
+        self.budget_retry(ctx, &mut e);
+        e
+    }
+
+    /// As pingora does, only the requests sent on a reused connection (or
+    /// asked to by `error_handling` and `retries`) are retried, within the
+    /// retry budget
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        let mut e = e.more_context(format!("Peer: {peer}"));
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        // The connection was closed before the response, none of it reached the client
+        if matches!(e.etype(), ConnectionClosed | ReadError | WriteError)
+            && session.response_written().is_none()
+            && !session.as_ref().retry_buffer_truncated()
+        {
+            self.retry_on_connect_error(session, ctx, &mut e);
+        }
+        self.budget_retry(ctx, &mut e);
+        e
+    }
+
+    /// Called when the request fails. Responses whose upstream failed after the header
+    /// was sent (truncated responses) are ended as configured by `truncated_responses`,
+    /// the others get an error response as they do by default.
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> u16 {
+        let sent_status = session.response_written().map(|r| r.status.as_u16());
+
+        if truncation::is_truncated(e, sent_status.is_some()) {
+            let status = sent_status.unwrap_or_default();
+            if let Some(mismatch) = ctx.body_length.truncated() {
+                body_length::report(mismatch, &ctx.host, ctx.upstream_addr.as_ref());
+            }
+            metrics::HTTP_TRUNCATED_RESPONSES
+                .with_label_values(&[&ctx.host])
+                .inc();
+
+            let trailer = truncation::end(session, &self.truncated_responses).await;
+            tracing::warn!(
+                host = ctx.host,
+                peer_addr = ctx.extensions.get("peer").unwrap_or(&String::new()),
+                status_code = status,
+                trailer,
+                "upstream response truncated: {e}"
+            );
+            return status;
+        }
+
+        coalescing::release_failed_writer(&mut session.cache);
+
+        // An intercepted upstream response gets the error page of the route
+        if let (Some(status), Some(error_handling)) = (
+            error_handling::intercepted_status(e),
+            ctx.route_container.error_handling.clone(),
+        ) {
+            if let Err(err) = error_handling.respond(session, status).await {
+                tracing::debug!("failed to send the error page of {}: {err}", ctx.host);
+            }
+            return status;
+        }
+
+        // A timed out upstream gets a 504 instead of a 502
+        let code = match error_status(e) {
+            502 if response_timed_out(ctx) || is_upstream_timeout(e) => 504,
+            code => code,
+        };
+        if code > 0 {
+            session.as_mut().respond_error(code).await;
+        }
+        code
+    }
+
     /// This filter is called when the entire response is sent to the downstream successfully or
     /// there is a fatal error that terminate the request.
     ///
@@ -298,10 +1183,65 @@ impl ProxyHttp for Router {
     async fn logging(
         &self,
         session: &mut Session,
-        _: Option<&pingora::Error>,
+        error: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
-        let duration_ms = ctx.timings.request_filter_start.elapsed().as_millis();
+        let duration = ctx.timings.request_filter_start.elapsed();
+        let status_code = session
+            .response_written()
+            .map(|v| v.status.as_u16())
+            .unwrap_or_default();
+
+        metrics::HTTP_REQUESTS
+            .with_label_values(&[sampling::status_class(status_code)])
+            .inc();
+        // Requests without a route are counted with an empty host
+        metrics::HTTP_HOST_REQUESTS
+            .with_label_values(&[
+                ctx.route.as_deref().unwrap_or_default(),
+                sampling::status_class(status_code),
+            ])
+            .inc();
+
+        if self
+            .slow_request_threshold
+            .is_some_and(|threshold| duration >= threshold)
+        {
+            let upstream = ctx.upstream_addr.as_ref().map(ToString::to_string);
+            tracing::warn!(
+                route = ctx.host,
+                upstream,
+                method = session.req_header().method.as_str(),
+                path = session.req_header().uri.path(),
+                status_code,
+                duration_ms = duration.as_millis(),
+                connect_ms = ctx.timings.connect().map(|d| d.as_millis()),
+                ttfb_ms = ctx.timings.ttfb().map(|d| d.as_millis()),
+                "slow request"
+            );
+        }
+
+        let sample_rate = ctx
+            .route_container
+            .sample_rate
+            .unwrap_or(self.tracing.sample_rate);
+        let Some(sample_reason) = sampling::sample(
+            status_code,
+            error.is_some(),
+            duration,
+            Duration::from_millis(self.tracing.slow_request_ms),
+            sample_rate,
+            rand::random(),
+            ctx.route_container.access_log_enabled,
+        ) else {
+            return;
+        };
+
+        metrics::HTTP_REQUESTS_SAMPLED
+            .with_label_values(&[sample_reason.as_str()])
+            .inc();
+
+        let duration_ms = duration.as_millis();
 
         let http_version = if session.is_http2() {
             "http/2"
@@ -313,7 +1253,10 @@ impl ProxyHttp for Router {
         let query = session.req_header().uri.query().unwrap_or_default();
         let path = session.req_header().uri.path();
         let empty_header = HeaderValue::from_static("");
-        let host = session.req_header().uri.host();
+        // HTTP/1.1 requests only have their host in the `Host` header
+        let host = session.req_header().uri.host().unwrap_or(&ctx.host);
+        let upstream_addr = ctx.upstream_addr.map(|addr| addr.to_string());
+        let bytes_sent = session.body_bytes_sent();
         let referer = session
             .req_header()
             .headers
@@ -326,14 +1269,8 @@ impl ProxyHttp for Router {
             .get("user-agent")
             .unwrap_or(&empty_header);
 
-        let client_ip = session
-            .client_addr()
-            .map(ToString::to_string)
-            .unwrap_or_default();
-
-        let status_code = session
-            .response_written()
-            .map(|v| v.status.as_u16())
+        let client_ip = client_ip::client_ip(session)
+            .map(|ip| ip.to_string())
             .unwrap_or_default();
 
         tracing::info!(
@@ -342,6 +1279,9 @@ impl ProxyHttp for Router {
             query,
             host,
             duration_ms,
+            latency_ms = duration_ms,
+            bytes_sent,
+            upstream_addr,
             user_agent = user_agent.to_str().unwrap_or(""),
             referer = referer.to_str().unwrap_or(""),
             client_ip,
@@ -350,6 +1290,7 @@ impl ProxyHttp for Router {
             reused_connection = ctx.extensions.get("reused").unwrap_or(&String::new()),
             peer_addr = ctx.extensions.get("peer").unwrap_or(&String::new()),
             request_id = ctx.extensions.get("request_id_header"),
+            sample_reason = sample_reason.as_str(),
             access_log = true
         );
     }
@@ -364,19 +1305,7 @@ impl ProxyHttp for Router {
         session: &Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<CacheKey> {
-        let req_header = session.req_header();
-        Ok(CacheKey::new(
-            ctx.host.clone(),
-            base64::encode_block(
-                req_header
-                    .uri
-                    .path_and_query()
-                    .unwrap_or(&PathAndQuery::from_static("/"))
-                    .as_str()
-                    .as_bytes(),
-            ),
-            "",
-        ))
+        Ok(requests::cache_key(&ctx.host, session.req_header()))
     }
 
     /// This callback is invoked when a cacheable response is ready to be admitted to cache
@@ -408,6 +1337,25 @@ impl ProxyHttp for Router {
         Ok(false)
     }
 
+    /// Whether the expired response is served when the upstream fails
+    fn should_serve_stale(
+        &self,
+        _session: &mut Session,
+        ctx: &mut Self::CTX,
+        error: Option<&pingora::Error>,
+    ) -> bool {
+        let Some(cache) = ctx.route_container.cache.as_ref() else {
+            return false;
+        };
+
+        let serve = stale::should_serve(cache, error);
+        if serve {
+            ctx.extensions
+                .insert(Cow::Borrowed("cache_state"), "stale".into());
+        }
+        serve
+    }
+
     /// Decide if the response is cacheable
     fn response_cache_filter(
         &self,
@@ -420,11 +1368,8 @@ impl ProxyHttp for Router {
             return Ok(RespCacheable::Uncacheable(NoCacheReason::NeverEnabled));
         };
 
-        // Only cache GET and HEAD requests with 2xx responses
-        if !CACHEABLE_METHODS.contains(&session.req_header().method) {
-            return Ok(RespCacheable::Uncacheable(NoCacheReason::Custom(
-                "method or status not cacheable",
-            )));
+        if let Some(reason) = requests::uncacheable_reason(session.req_header(), resp.status) {
+            return Ok(RespCacheable::Uncacheable(reason));
         }
 
         Ok(RespCacheable::Cacheable(CacheMeta::new(
@@ -433,7 +1378,7 @@ impl ProxyHttp for Router {
                 .unwrap(),
             SystemTime::now(),
             cache.stale_while_revalidate_secs,
-            cache.stale_if_error_secs,
+            stale::stale_if_error_secs(cache),
             resp.clone(),
         )))
     }
@@ -453,6 +1398,7 @@ impl ProxyHttp for Router {
     where
         Self::CTX: Send + Sync,
     {
+        ctx.timings.upstream_connected = Some(std::time::Instant::now());
         ctx.extensions
             .insert(Cow::Borrowed("reused"), reused.to_string());
         ctx.extensions
@@ -461,6 +1407,61 @@ impl ProxyHttp for Router {
     }
 }
 
+/// The key pinning the client to its upstream: the subject of its JWT when
+/// there is one (`sticky.by: jwt:sub`), its IP otherwise
+fn sticky_key(by: RouteStickyBy, session: &Session, ctx: &RouterContext) -> Option<StickyKey> {
+    if by == RouteStickyBy::JwtSub {
+        if let Some(subject) = &ctx.jwt_subject {
+            return Some(StickyKey::Subject(subject.clone()));
+        }
+    }
+
+    client_ip::client_ip(session).map(StickyKey::Ip)
+}
+
+/// Why no upstream of the route could be selected
+fn no_upstream_reason(route: &RouteStoreContainer) -> NoUpstream {
+    let backends = route.load_balancer.backends();
+    let all = backends.get_backend();
+    if all.is_empty() {
+        return NoUpstream::Empty;
+    }
+
+    let saturated = all.iter().any(|backend| {
+        backends.ready(backend) && !route.upstream_connections.available(&backend.addr)
+    });
+    if saturated {
+        NoUpstream::Saturated
+    } else {
+        NoUpstream::Unhealthy
+    }
+}
+
+/// Whether the upstream took longer than `timeouts.response_secs` of the route
+fn response_timed_out(ctx: &RouterContext) -> bool {
+    ctx.response_deadline
+        .is_some_and(|deadline| std::time::Instant::now() >= deadline)
+}
+
+/// Whether connecting to the upstream, writing the request or reading the
+/// response timed out
+fn is_upstream_timeout(e: &pingora::Error) -> bool {
+    e.esource() == &ErrorSource::Upstream
+        && matches!(e.etype(), ConnectTimedout | ReadTimedout | WriteTimedout)
+}
+
+/// Status of the error response sent for a failed request, the same as pingora's
+/// (0 when the downstream connection is already gone)
+fn error_status(e: &pingora::Error) -> u16 {
+    match (e.etype(), e.esource()) {
+        (HTTPStatus(code), _) => *code,
+        (_, ErrorSource::Upstream) => 502,
+        (WriteError | ReadError | ConnectionClosed, ErrorSource::Downstream) => 0,
+        (_, ErrorSource::Downstream) => 400,
+        (_, ErrorSource::Internal | ErrorSource::Unset) => 500,
+    }
+}
+
 fn get_uri(session: &mut Session) -> Uri {
     session.req_header().uri.clone()
 }
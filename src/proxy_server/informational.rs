@@ -0,0 +1,114 @@
+//! The `1xx` (informational) responses of the upstreams: the `100 Continue`
+//! of the requests sent with `Expect: 100-continue`, and the `103 Early Hints`
+//! sent before the final response.
+//!
+//! Pingora relays them to the HTTP/1.1 clients as they come, unless
+//! `proxy.informational_responses` is disabled. They cannot be sent to the
+//! HTTP/2 clients: the h2 library sends the headers of a stream once, the
+//! final response would be lost. Nor to the HTTP/1.0 clients, which predate
+//! them. The upstreams are then not asked for a `100 Continue` (`Expect` is
+//! removed), proksi sends it itself to the HTTP/1.1 clients waiting for it
+//! before their body. So it does with `request.expect_continue: answer`,
+//! and for the HTTP/2 upstreams.
+//!
+//! The early hints the upstreams send unasked are reduced to a bare
+//! `100 Continue` for the HTTP/1.1 clients, which must ignore it (RFC 9110,
+//! section 15.2). The upstreams of HTTP/2 clients must not send them.
+
+use http::{header, StatusCode, Version};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    protocols::http::ServerSession,
+    proxy::Session,
+};
+
+/// Whether the informational responses of the upstream of the request are
+/// relayed to its client
+pub fn relayed(session: &Session, enabled: bool) -> bool {
+    // HTTP/1.0 clients do not know them
+    enabled && session.req_header().version == Version::HTTP_11
+}
+
+/// Whether the response is an informational one, `101 Switching Protocols`
+/// being the final response of an upgrade
+pub fn is_informational(response: &ResponseHeader) -> bool {
+    response.status.is_informational() && response.status != StatusCode::SWITCHING_PROTOCOLS
+}
+
+/// Whether the client waits for a `100 Continue` before sending its body
+pub fn expects_continue(request: &RequestHeader) -> bool {
+    request
+        .headers
+        .get(header::EXPECT)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+/// Unless the expectation is `forward`ed, the upstream is not asked for a
+/// `100 Continue`: the HTTP/1.1 clients get it from proksi instead
+pub async fn upstream_request(
+    session: &mut Session,
+    upstream_request: &mut RequestHeader,
+    forward: bool,
+) -> pingora::Result<()> {
+    if forward || !expects_continue(upstream_request) {
+        return Ok(());
+    }
+
+    upstream_request.remove_header(&header::EXPECT);
+    answer_continue(session.as_mut()).await
+}
+
+/// Tells an HTTP/1.1 client waiting for a `100 Continue` to send its body.
+/// Only sent once, before any other response.
+pub async fn answer_continue(session: &mut ServerSession) -> pingora::Result<()> {
+    if session.req_header().version == Version::HTTP_11 {
+        session.write_continue_response().await?;
+    }
+    Ok(())
+}
+
+/// Replaces an informational response that is not relayed by a bare
+/// `100 Continue`
+pub fn withhold(response: &mut ResponseHeader) -> pingora::Result<()> {
+    if response.status != StatusCode::CONTINUE || !response.headers.is_empty() {
+        *response = ResponseHeader::build(StatusCode::CONTINUE, Some(0))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_informational() {
+        let response = |status| ResponseHeader::build(status, None).unwrap();
+        assert!(is_informational(&response(100)));
+        assert!(is_informational(&response(103)));
+        assert!(!is_informational(&response(101)));
+        assert!(!is_informational(&response(200)));
+    }
+
+    #[test]
+    fn test_expects_continue() {
+        let mut request = RequestHeader::build("POST", b"/", None).unwrap();
+        assert!(!expects_continue(&request));
+
+        request
+            .insert_header(header::EXPECT, "100-Continue")
+            .unwrap();
+        assert!(expects_continue(&request));
+    }
+
+    #[test]
+    fn test_withhold_early_hints() {
+        let mut hints = ResponseHeader::build(103, None).unwrap();
+        hints
+            .insert_header(header::LINK, "</style.css>; rel=preload; as=style")
+            .unwrap();
+
+        withhold(&mut hints).unwrap();
+        assert_eq!(hints.status, StatusCode::CONTINUE);
+        assert!(hints.headers.is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+use std::{borrow::Cow, net::ToSocketAddrs};
+
+use dashmap::mapref::one::Ref;
+use pingora::lb::Backend;
+
+use crate::{
+    config::RouteUpstream,
+    stores::{self, routes::RouteStoreContainer},
+};
+
+/// Why a request does not match any route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchError {
+    /// No route exists for the host
+    UnknownHost,
+    /// The route of the host has path patterns and none of them matches
+    PathNotMatched,
+}
+
+impl MatchError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MatchError::UnknownHost => "no route for the host",
+            MatchError::PathNotMatched => "the path does not match the patterns of the route",
+        }
+    }
+}
+
+/// The route a request matched
+pub struct RouteMatch {
+    pub route: Ref<'static, String, RouteStoreContainer>,
+    /// The path pattern that matched, if the route has any
+    pub pattern: Option<String>,
+}
+
+/// Returns the host without its port (ex: `example.com:443` -> `example.com`)
+pub fn host_without_port(host: &str) -> &str {
+    // IPv6 addresses keep their brackets (ex: `[::1]:443` -> `[::1]`)
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next().unwrap_or_default();
+    }
+
+    host.split(':').next().unwrap_or_default()
+}
+
+/// Returns the host as the routes are stored: without port nor trailing dot,
+/// in lowercase (ex: `Example.com.:443` -> `example.com`)
+pub fn normalize_host(host: &str) -> Cow<'_, str> {
+    let host = host_without_port(host.trim()).trim_end_matches('.');
+    if host.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(host.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(host)
+    }
+}
+
+/// The regex of a route host written as `~<regex>` (ex: `~^api-\d+\.example\.com$`)
+pub fn host_regex(host: &str) -> Option<&str> {
+    host.strip_prefix('~')
+}
+
+/// Whether the route host matches other hosts than itself: a wildcard
+/// (`*.example.com`) or a regex (`~<regex>`)
+pub fn is_host_pattern(host: &str) -> bool {
+    host.starts_with("*.") || host_regex(host).is_some()
+}
+
+/// Returns the key a route host is stored by: the regexes as written,
+/// the other hosts normalized
+pub fn route_key(host: &str) -> Cow<'_, str> {
+    if host_regex(host).is_some() {
+        Cow::Borrowed(host)
+    } else {
+        normalize_host(host)
+    }
+}
+
+/// Finds the route of a request from its host (without port) and path,
+/// as the HTTPS service does before running the plugins of the route
+pub fn match_route(host: &str, path: &str) -> Result<RouteMatch, MatchError> {
+    let route = stores::find_route_by_host(host).ok_or(MatchError::UnknownHost)?;
+
+    let pattern = match &route.path_matcher.pattern {
+        Some(tree) => {
+            let (index, _) = tree.find(path).ok_or(MatchError::PathNotMatched)?;
+            route.path_matcher.patterns.get(*index).cloned()
+        }
+        None => None,
+    };
+
+    Ok(RouteMatch { route, pattern })
+}
+
+/// Returns the configured upstream a backend (selected by the load balancer) belongs to
+pub fn find_upstream<'a>(
+    route: &'a RouteStoreContainer,
+    backend: &Backend,
+) -> Option<&'a RouteUpstream> {
+    let addr = backend.addr.as_inet()?;
+
+    route.upstreams.iter().find(|u| {
+        format!("{}:{}", u.ip, u.port)
+            .to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.any(|s| s.ip() == addr.ip() && s.port() == addr.port()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use pingora::lb::{selection::RoundRobin, LoadBalancer};
+
+    use super::*;
+
+    #[test]
+    fn test_match_route_by_host_and_path() {
+        let mut route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4000"]).unwrap(),
+        );
+        route
+            .path_matcher
+            .with_pattern(&[Cow::Borrowed("/api/*"), Cow::Borrowed("/health")]);
+        route.upstreams = vec![RouteUpstream {
+            ip: Cow::Borrowed("127.0.0.1"),
+            port: 4000,
+            ..RouteUpstream::default()
+        }];
+        stores::insert_route("matching.test".to_string(), route);
+
+        let matched = match_route("matching.test", "/api/users").unwrap();
+        assert_eq!(matched.pattern.as_deref(), Some("/api/*"));
+
+        let backend = matched.route.load_balancer.select(b"", 32).unwrap();
+        let upstream = find_upstream(&matched.route, &backend).unwrap();
+        assert_eq!(upstream.port, 4000);
+
+        assert_eq!(
+            match_route("matching.test", "/other").err(),
+            Some(MatchError::PathNotMatched)
+        );
+        assert_eq!(
+            match_route("unknown.test", "/").err(),
+            Some(MatchError::UnknownHost)
+        );
+        assert_eq!(host_without_port("matching.test:443"), "matching.test");
+    }
+
+    #[test]
+    fn test_match_route_by_host_pattern() {
+        let route = |port: u16| {
+            let mut route = RouteStoreContainer::new(
+                LoadBalancer::<RoundRobin>::try_from_iter([format!("127.0.0.1:{port}")]).unwrap(),
+            );
+            route.upstreams = vec![RouteUpstream {
+                ip: Cow::Borrowed("127.0.0.1"),
+                port,
+                ..RouteUpstream::default()
+            }];
+            route
+        };
+        for (host, port) in [
+            ("www.patterns.test", 4010),
+            ("*.patterns.test", 4011),
+            ("*.api.patterns.test", 4012),
+            (r"~^(www|app-\d+)\.patterns\.test$", 4013),
+            (r"~^app-\d+\.regex\.test$", 4014),
+            (r"~^APP-1\.regex\.test$", 4015),
+        ] {
+            stores::insert_route(host.to_string(), route(port));
+        }
+
+        let port = |host: &str| {
+            let matched = match_route(host, "/").ok()?;
+            Some(matched.route.upstreams[0].port)
+        };
+        // Exact, then the closest wildcard, then the regexes
+        assert_eq!(port("www.patterns.test"), Some(4010));
+        assert_eq!(port("app-1.patterns.test"), Some(4011));
+        assert_eq!(port("v1.api.patterns.test"), Some(4012));
+        assert_eq!(port("Deep.Sub.Patterns.Test:443"), Some(4011));
+        assert_eq!(port("patterns.test"), None);
+        // The regexes match the whole host, in alphabetical order
+        assert_eq!(port("app-1.regex.test"), Some(4015));
+        assert_eq!(port("app-2.regex.test"), Some(4014));
+        assert_eq!(port("app-x.regex.test"), None);
+        assert_eq!(port("xapp-2.regex.test.example"), None);
+        // The patterns are only matched, not looked up as hosts
+        assert_eq!(port("*.patterns.test"), None);
+        assert_eq!(port(r"~^app-\d+\.regex\.test$"), None);
+
+        // Stored by their pattern
+        assert!(stores::get_route_by_key("*.Patterns.test").is_some());
+        assert!(stores::get_route_by_key(r"~^APP-1\.regex\.test$").is_some());
+        stores::remove_route(r"~^APP-1\.regex\.test$");
+        assert_eq!(port("app-1.regex.test"), Some(4014));
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("example.com"), "example.com");
+        assert_eq!(normalize_host("Example.COM"), "example.com");
+        assert_eq!(normalize_host("example.com."), "example.com");
+        assert_eq!(normalize_host("Example.com.:8443"), "example.com");
+        assert_eq!(normalize_host("example.com:443"), "example.com");
+        assert_eq!(normalize_host("[::1]:443"), "[::1]");
+        assert!(matches!(normalize_host("example.com"), Cow::Borrowed(_)));
+
+        // Stored and looked up by the normalized host
+        let route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4001"]).unwrap(),
+        );
+        stores::insert_route("Normalized.Test.".to_string(), route);
+
+        for host in [
+            "normalized.test",
+            "NORMALIZED.test",
+            "normalized.test.",
+            "normalized.test:443",
+        ] {
+            assert!(match_route(host, "/").is_ok(), "{host}");
+        }
+        assert!(stores::get_routes().contains_key("normalized.test"));
+    }
+}
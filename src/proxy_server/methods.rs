@@ -0,0 +1,144 @@
+use http::{header, Method, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::config::{Methods, OptionsHandling};
+
+/// Methods listed in the `Allow` header, unless denied
+const KNOWN_METHODS: [Method; 9] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::PATCH,
+    Method::OPTIONS,
+    Method::TRACE,
+    Method::CONNECT,
+];
+
+/// Requests answered by proksi based on their method, before any route
+#[derive(Debug, Clone)]
+pub struct MethodFilter {
+    deny: Vec<Method>,
+    options: OptionsHandling,
+    /// Value of the `Allow` header of the responses
+    allow: String,
+}
+
+impl MethodFilter {
+    /// Invalid methods are skipped (rejected by the configuration validation)
+    pub fn new(config: &Methods) -> Self {
+        let deny: Vec<Method> = config
+            .deny
+            .iter()
+            .filter_map(|method| Method::from_bytes(method.to_uppercase().as_bytes()).ok())
+            .collect();
+
+        let allow = KNOWN_METHODS
+            .iter()
+            .filter(|method| !deny.contains(method))
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        MethodFilter {
+            deny,
+            options: config.options,
+            allow,
+        }
+    }
+
+    /// The status proksi answers the request with, `None` when it is proxied
+    pub fn status(&self, req: &RequestHeader) -> Option<StatusCode> {
+        if self.deny.contains(&req.method) {
+            return Some(StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        if req.method != Method::OPTIONS {
+            return None;
+        }
+
+        // `OPTIONS *` is about the server, not about a resource of a route
+        if req.uri.path() == "*" {
+            return Some(StatusCode::NO_CONTENT);
+        }
+
+        // CORS preflight requests are left to the route (plugins or upstream)
+        (self.options == OptionsHandling::Respond && !is_preflight(req))
+            .then_some(StatusCode::NO_CONTENT)
+    }
+
+    /// Answers the request with the given status and the allowed methods
+    pub async fn respond(&self, session: &mut Session, status: StatusCode) -> pingora::Result<()> {
+        let mut res = ResponseHeader::build(status, Some(2))?;
+        res.insert_header(header::ALLOW, &self.allow)?;
+        res.insert_header(header::CONTENT_LENGTH, "0")?;
+
+        session.write_response_header(Box::new(res), true).await
+    }
+}
+
+/// Whether the request is a CORS preflight request
+fn is_preflight(req: &RequestHeader) -> bool {
+    req.headers.contains_key(header::ORIGIN)
+        && req
+            .headers
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_method_filter() {
+        let filter = MethodFilter::new(&Methods::default());
+        assert_eq!(filter.allow, "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS");
+
+        let status = |method, path| filter.status(&request(method, path, &[]));
+        assert_eq!(status("TRACE", "/"), Some(StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(status("GET", "/"), None);
+        assert_eq!(status("OPTIONS", "*"), Some(StatusCode::NO_CONTENT));
+        // Proxied by default
+        assert_eq!(status("OPTIONS", "/"), None);
+    }
+
+    #[test]
+    fn test_method_filter_responds_to_options() {
+        let filter = MethodFilter::new(&Methods {
+            deny: vec!["delete".into()],
+            options: OptionsHandling::Respond,
+        });
+        assert_eq!(
+            filter.allow,
+            "GET, HEAD, POST, PUT, PATCH, OPTIONS, TRACE, CONNECT"
+        );
+
+        let options = request("OPTIONS", "/", &[]);
+        assert_eq!(filter.status(&options), Some(StatusCode::NO_CONTENT));
+
+        let delete = request("DELETE", "/", &[]);
+        assert_eq!(filter.status(&delete), Some(StatusCode::METHOD_NOT_ALLOWED));
+
+        let preflight = request(
+            "OPTIONS",
+            "/",
+            &[
+                ("origin", "https://example.com"),
+                ("access-control-request-method", "POST"),
+            ],
+        );
+        assert_eq!(filter.status(&preflight), None);
+    }
+}
@@ -1,8 +1,6 @@
-use std::collections::HashMap;
-
 use pingora::Result;
 
-use crate::plugins::MiddlewarePlugin;
+use crate::{plugins::MiddlewarePlugin, stores::routes::RouteStoreContainer};
 
 /// Executes the request and response plugins
 pub async fn execute_response_plugins(
@@ -34,10 +32,10 @@ pub async fn execute_response_plugins(
 pub async fn execute_request_plugins(
     session: &mut pingora::proxy::Session,
     ctx: &mut crate::proxy_server::https_proxy::RouterContext,
-    plugins: &HashMap<String, crate::config::RoutePlugin>,
+    route: &RouteStoreContainer,
 ) -> Result<bool> {
     use crate::plugins::MiddlewarePlugin;
-    for (name, value) in plugins {
+    for (name, value) in &route.plugins {
         match name.as_str() {
             "oauth2" => {
                 if crate::plugins::PLUGINS
@@ -66,6 +64,20 @@ pub async fn execute_request_plugins(
                     return Ok(true);
                 }
             }
+            "rate_limit" => {
+                // Compiled when the route was added, which fails on invalid rules
+                let Some(rules) = &route.rate_limit_rules else {
+                    continue;
+                };
+                if crate::plugins::PLUGINS
+                    .rate_limit
+                    .enforce(session, &ctx.host, rules)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
             _ => {}
         }
     }
@@ -0,0 +1,190 @@
+//! Mirroring of the requests of a route to a shadow upstream (`mirror`), to
+//! try a new backend with live traffic. The client is only ever answered by
+//! the upstream of the route: the copy is sent in the background once the
+//! request body is complete, and its response (or failure) is discarded.
+//!
+//! The body is copied as it streams to the upstream, so only requests with a
+//! body of at most `max_body_size` bytes are mirrored.
+
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderMap, Method};
+use once_cell::sync::Lazy;
+use pingora::http::RequestHeader;
+
+use crate::{config::RouteMirror, metrics};
+
+use super::headers::HOP_BY_HOP_HEADERS;
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// A copy of a request, sent to the shadow upstream once its body is complete
+pub struct MirroredRequest {
+    url: String,
+    method: Method,
+    headers: HeaderMap,
+    body: BytesMut,
+    max_body_size: usize,
+    timeout: Duration,
+}
+
+/// Whether the request is mirrored: it is picked by `percentage` (`roll` is a
+/// random number in `0.0..100.0`), is not an upgrade (ex: websocket) and its
+/// body, when its length is known, fits in `max_body_size`
+fn applies(config: &RouteMirror, request: &RequestHeader, roll: f64) -> bool {
+    if roll >= config.percentage || request.headers.contains_key(header::UPGRADE) {
+        return false;
+    }
+
+    request
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .map_or(true, |length| length <= config.max_body_size)
+}
+
+impl MirroredRequest {
+    /// Starts the copy of the request when it is mirrored
+    pub fn start(config: &RouteMirror, request: &RequestHeader) -> Option<Self> {
+        if !applies(config, request, rand::random::<f64>() * 100.0) {
+            return None;
+        }
+
+        let path = request
+            .uri
+            .path_and_query()
+            .map_or("/", http::uri::PathAndQuery::as_str);
+
+        // The host of the request is kept, the body is framed by the mirror client
+        let mut headers = request.headers.clone();
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(name);
+        }
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.remove(header::CONTENT_LENGTH);
+        if !headers.contains_key(header::HOST) {
+            // HTTP/2 requests carry their host in the URI
+            if let Some(host) = request
+                .uri
+                .authority()
+                .and_then(|authority| authority.as_str().parse().ok())
+            {
+                headers.insert(header::HOST, host);
+            }
+        }
+
+        Some(MirroredRequest {
+            url: format!("{}{path}", config.upstream.trim_end_matches('/')),
+            method: request.method.clone(),
+            headers,
+            body: BytesMut::new(),
+            max_body_size: config.max_body_size,
+            timeout: Duration::from_millis(config.timeout_ms),
+        })
+    }
+
+    /// Copies a chunk of the body, `false` once the body is larger than `max_body_size`
+    pub fn push(&mut self, chunk: Option<&Bytes>) -> bool {
+        if let Some(chunk) = chunk {
+            if self.body.len() + chunk.len() > self.max_body_size {
+                return false;
+            }
+            self.body.extend_from_slice(chunk);
+        }
+
+        true
+    }
+
+    /// Sends the copy in the background, its response is discarded
+    pub fn send(self) {
+        tokio::spawn(async move {
+            let result = HTTP_CLIENT
+                .request(self.method, &self.url)
+                .headers(self.headers)
+                .body(self.body.freeze())
+                .timeout(self.timeout)
+                .send()
+                .await;
+
+            let label = match result {
+                Ok(_) => "sent",
+                Err(err) => {
+                    tracing::debug!("could not mirror a request to {}: {err}", self.url);
+                    "failed"
+                }
+            };
+            metrics::HTTP_MIRRORED_REQUESTS
+                .with_label_values(&[label])
+                .inc();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(percentage: f64) -> RouteMirror {
+        RouteMirror {
+            upstream: "http://shadow:3000/".to_string(),
+            percentage,
+            max_body_size: 8,
+            timeout_ms: 1000,
+        }
+    }
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("POST", b"/users?page=2", None).unwrap();
+        for (name, value) in headers {
+            request.insert_header(*name, *value).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn test_mirror_applies() {
+        assert!(applies(&config(100.0), &request(&[]), 99.9));
+        assert!(applies(
+            &config(10.0),
+            &request(&[("content-length", "8")]),
+            9.9
+        ));
+
+        // Not sampled, body too large, or upgraded
+        assert!(!applies(&config(10.0), &request(&[]), 10.0));
+        assert!(!applies(&config(0.0), &request(&[]), 0.0));
+        assert!(!applies(
+            &config(100.0),
+            &request(&[("content-length", "9")]),
+            0.0
+        ));
+        assert!(!applies(
+            &config(100.0),
+            &request(&[("upgrade", "websocket")]),
+            0.0
+        ));
+    }
+
+    #[test]
+    fn test_mirror_copies_the_request() {
+        let request = request(&[
+            ("host", "example.com"),
+            ("connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+        ]);
+        let mut mirrored = MirroredRequest::start(&config(100.0), &request).unwrap();
+        assert_eq!(mirrored.url, "http://shadow:3000/users?page=2");
+        assert_eq!(mirrored.headers.get("host").unwrap(), "example.com");
+        assert!(!mirrored.headers.contains_key("connection"));
+        assert!(!mirrored.headers.contains_key("transfer-encoding"));
+
+        // Chunked bodies are copied until they exceed `max_body_size`
+        assert!(mirrored.push(Some(&Bytes::from_static(b"abcd"))));
+        assert!(mirrored.push(None));
+        assert!(mirrored.push(Some(&Bytes::from_static(b"efgh"))));
+        assert_eq!(&mirrored.body[..], b"abcdefgh");
+        assert!(!mirrored.push(Some(&Bytes::from_static(b"i"))));
+    }
+}
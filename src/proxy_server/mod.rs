@@ -5,10 +5,41 @@ use pingora::{
     upstreams::peer::PeerOptions,
 };
 
+pub mod allowed_hosts;
+pub mod blocked_paths;
+pub mod body_length;
 pub mod cert_store;
+pub mod client_auth;
+pub mod compression;
+pub mod concurrency;
+pub mod connection_limits;
+pub mod connection_reuse;
+pub mod connections;
+pub mod dynamic_upstream;
+pub mod error_handling;
+pub mod forward_auth;
+pub mod headers;
+pub mod http10;
 pub mod http_proxy;
 pub mod https_proxy;
+pub mod informational;
+pub mod matching;
+pub mod methods;
 pub mod middleware;
+pub mod mirror;
+pub mod no_upstream;
+pub mod proxy_protocol;
+pub mod request_buffer;
+pub mod request_compression;
+pub mod retries;
+pub mod retry_after;
+pub mod retry_budget;
+pub mod sampling;
+pub mod status_map;
+pub mod stream;
+pub mod substitution;
+pub mod truncation;
+pub mod user_agents;
 
 /// Default peer options to be used on every upstream connection
 const DEFAULT_PEER_OPTIONS: PeerOptions = PeerOptions {
@@ -0,0 +1,96 @@
+//! Requests of a route without any upstream to send them to: discovery (Docker,
+//! DNS) can transiently empty the pool of a route, or all of its upstreams can
+//! be unhealthy. They are answered with `no_upstream.status` (a 503 while a new
+//! route warms up), and logged at most once every `LOG_INTERVAL` per route so a
+//! flood of requests does not flood the logs.
+
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use pingora::ErrorType::HTTPStatus;
+
+/// Least time between two warnings of the same route
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// When the last warning of each route was logged
+static LAST_LOGGED: Lazy<DashMap<String, Instant>> = Lazy::new(DashMap::new);
+
+/// Why a request has no upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoUpstream {
+    /// The route has no upstream at all (ex: all removed by discovery)
+    Empty,
+    /// None of the upstreams of the route is healthy
+    Unhealthy,
+    /// The healthy upstreams have all their connections in use (`max_connections`)
+    Saturated,
+    /// The route was just added and none of its upstreams passed a health check
+    /// yet (`warmup`), always answered with a 503
+    WarmingUp,
+}
+
+impl NoUpstream {
+    fn describe(self) -> &'static str {
+        match self {
+            NoUpstream::Empty => "no upstream",
+            NoUpstream::Unhealthy => "no healthy upstream",
+            NoUpstream::Saturated => "no upstream with a free connection",
+            NoUpstream::WarmingUp => "warming up, no upstream passed a health check yet",
+        }
+    }
+}
+
+/// Whether a warning of `host` is due at `now`, recording it when it is
+fn should_log(host: &str, now: Instant) -> bool {
+    if let Some(mut last) = LAST_LOGGED.get_mut(host) {
+        if now.duration_since(*last) < LOG_INTERVAL {
+            return false;
+        }
+        *last = now;
+        return true;
+    }
+
+    LAST_LOGGED.insert(host.to_string(), now);
+    true
+}
+
+/// The error answering the request with `status`, turned into the response by `fail_to_proxy`
+pub fn error(host: &str, reason: NoUpstream, status: u16) -> Box<pingora::Error> {
+    if should_log(host, Instant::now()) {
+        tracing::warn!(
+            "{} for {host}, answering its requests with a {status}",
+            reason.describe()
+        );
+    }
+
+    pingora::Error::explain(HTTPStatus(status), reason.describe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_upstream_logs_are_throttled() {
+        let now = Instant::now();
+        assert!(should_log("throttled.example.com", now));
+        assert!(!should_log(
+            "throttled.example.com",
+            now + Duration::from_secs(1)
+        ));
+        assert!(should_log("other.example.com", now));
+
+        assert!(should_log("throttled.example.com", now + LOG_INTERVAL));
+        assert!(!should_log(
+            "throttled.example.com",
+            now + LOG_INTERVAL + Duration::from_secs(1)
+        ));
+    }
+
+    #[test]
+    fn test_no_upstream_error_status() {
+        let err = error("status.example.com", NoUpstream::Empty, 502);
+        assert_eq!(err.etype(), &HTTPStatus(502));
+    }
+}
@@ -0,0 +1,400 @@
+//! PROXY protocol (v1 and v2) on the HTTP and HTTPS listeners, for Proksi behind
+//! an L4 load balancer. Pingora does not read the header itself, so the public
+//! address is served by a listener of Proksi instead: it decodes the header of
+//! each connection and relays the rest of it (TLS included) to the pingora
+//! service, listening on a loopback address.
+//!
+//! The client address of the header is recorded by the address each connection
+//! is relayed from, `client_ip` resolving it instead of the loopback peer.
+//! Connections without a valid header are closed.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use pingora::{
+    server::{ListenFds, Server, ShutdownWatch},
+    services::Service,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    time::timeout,
+};
+
+use crate::config::{Config, TcpListenerOptions};
+
+/// First bytes of a v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Length of the shortest header (`PROXY UNKNOWN\r\n`), read at once
+const MIN_HEADER_LENGTH: usize = 15;
+
+/// Longest v1 header, CRLF included
+const V1_MAX_LENGTH: usize = 107;
+
+/// Client address of each relayed connection, by the address it is relayed from
+static CLIENTS: Lazy<DashMap<SocketAddr, SocketAddr>> = Lazy::new(DashMap::new);
+
+/// The client address of the PROXY header of the connection relayed from `peer`,
+/// `None` when the connection did not go through a PROXY listener
+pub fn client_addr(peer: &SocketAddr) -> Option<SocketAddr> {
+    CLIENTS.get(peer).map(|client| *client)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Reads the PROXY header at the start of a connection, and nothing past it.
+/// The source address is `None` for the connections of the load balancer itself
+/// (v1 `UNKNOWN`, v2 `LOCAL`) and for sources that are not IP addresses.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; MIN_HEADER_LENGTH];
+    stream.read_exact(&mut start).await?;
+
+    if start[..12] == V2_SIGNATURE {
+        // The version and command, the family, then the length of the addresses
+        let mut length_low = [0; 1];
+        stream.read_exact(&mut length_low).await?;
+        let length = u16::from_be_bytes([start[14], length_low[0]]);
+
+        let mut addresses = vec![0; usize::from(length)];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(start[12], start[13], &addresses);
+    }
+
+    if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).await?;
+            line.push(byte[0]);
+        }
+        return parse_v1(&line);
+    }
+
+    Err(invalid("missing PROXY protocol header"))
+}
+
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("invalid PROXY v1 header"))?;
+    let fields = line.trim_end_matches("\r\n").split(' ').collect::<Vec<_>>();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .ok()
+                .filter(|ip| ip.is_ipv4() == (*family == "TCP4"))
+                .ok_or_else(|| invalid("invalid PROXY v1 source address"))?;
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY v1 header")),
+    }
+}
+
+/// The addresses of a v2 header, given its version and command and its family
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    match version_command & 0x0F {
+        // LOCAL: health checks of the load balancer
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("invalid PROXY v2 command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        // AF_INET: source, destination, source port, destination port
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+        }
+        // AF_INET6, the same layout
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port(32),
+            )))
+        }
+        1 | 2 => Err(invalid("truncated PROXY v2 addresses")),
+        // AF_UNSPEC and AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+/// The address the HTTP (or HTTPS) service listens on: its loopback address when
+/// its public address is served by a PROXY listener (added to the server)
+pub fn service_address<'a>(
+    server: &mut Server,
+    config: &'a Config,
+    https: bool,
+) -> Result<&'a str, anyhow::Error> {
+    let listeners = &config.listeners;
+    let proxy_protocol = &listeners.proxy_protocol;
+    let (name, enabled, address, internal) = if https {
+        (
+            "https",
+            proxy_protocol.https,
+            &listeners.https_address,
+            &proxy_protocol.internal_https_address,
+        )
+    } else {
+        (
+            "http",
+            proxy_protocol.http,
+            &listeners.http_address,
+            &proxy_protocol.internal_http_address,
+        )
+    };
+
+    if !enabled {
+        return Ok(address);
+    }
+
+    let internal_addr = internal
+        .parse::<SocketAddr>()
+        .with_context(|| format!("invalid internal {name} address {internal}"))?;
+    server.add_service(ProxyProtocolListener::new(
+        name,
+        address,
+        internal_addr,
+        Duration::from_secs(proxy_protocol.header_timeout_secs),
+        config.worker_threads,
+    ));
+    Ok(internal)
+}
+
+/// Serves a public address with the PROXY protocol, relaying the connections to
+/// the pingora service listening on `internal`
+pub struct ProxyProtocolListener {
+    name: String,
+    listen: String,
+    internal: SocketAddr,
+    header_timeout: Duration,
+    threads: Option<usize>,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(
+        name: &str,
+        listen: &str,
+        internal: SocketAddr,
+        header_timeout: Duration,
+        threads: Option<usize>,
+    ) -> Self {
+        Self {
+            name: format!("{name} (PROXY protocol)"),
+            listen: listen.to_string(),
+            internal,
+            header_timeout,
+            threads,
+        }
+    }
+
+    fn bind(&self) -> io::Result<TcpListener> {
+        let addr = self
+            .listen
+            .parse::<SocketAddr>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        // The listener is not handed over on upgrade: the new instance binds
+        // the address too while this one drains its connections
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+        socket.listen(TcpListenerOptions::BACKLOG)
+    }
+}
+
+/// Relays a connection once its header is read, recording its client address
+async fn relay(
+    mut downstream: TcpStream,
+    peer: SocketAddr,
+    internal: SocketAddr,
+    header_timeout: Duration,
+) {
+    let client = match timeout(header_timeout, read_header(&mut downstream)).await {
+        // The connections of the load balancer itself keep their own address
+        Ok(Ok(source)) => source.unwrap_or(peer),
+        Ok(Err(err)) => {
+            tracing::debug!("closing the connection of {peer}: {err}");
+            return;
+        }
+        Err(_) => {
+            tracing::debug!("closing the connection of {peer}: no PROXY protocol header in time");
+            return;
+        }
+    };
+
+    let mut upstream = match TcpStream::connect(internal).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            tracing::error!("failed to relay the connection of {client} to {internal}: {err}");
+            return;
+        }
+    };
+    let Ok(relayed_from) = upstream.local_addr() else {
+        return;
+    };
+    downstream.set_nodelay(true).ok();
+    upstream.set_nodelay(true).ok();
+
+    // Recorded before the request is relayed, so it is known when it is read
+    CLIENTS.insert(relayed_from, client);
+    if let Err(err) = tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+        tracing::debug!("relayed connection of {client} closed: {err}");
+    }
+    CLIENTS.remove(&relayed_from);
+}
+
+#[async_trait]
+impl Service for ProxyProtocolListener {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        let listener = match self.bind() {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!("failed to listen on {}: {err}", self.listen);
+                return;
+            }
+        };
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.changed() => break,
+            };
+
+            match accepted {
+                Ok((downstream, peer)) => {
+                    tokio::spawn(relay(downstream, peer, self.internal, self.header_timeout));
+                }
+                Err(err) => tracing::debug!("failed to accept a connection: {err}"),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.threads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The source address of the header and the bytes left after it
+    fn read(mut input: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let source = runtime.block_on(read_header(&mut input));
+        (source, input.to_vec())
+    }
+
+    #[test]
+    fn test_read_v1_header() {
+        let (source, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1");
+        assert_eq!(source.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1");
+
+        let (source, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\n");
+        assert_eq!(
+            source.unwrap(),
+            Some("[2001:db8::7]:51234".parse().unwrap())
+        );
+
+        // The load balancer itself
+        let (source, rest) = read(b"PROXY UNKNOWN\r\n\x16\x03\x01");
+        assert_eq!(source.unwrap(), None);
+        assert_eq!(rest, b"\x16\x03\x01");
+
+        assert!(read(b"PROXY TCP4 2001:db8::7 10.0.0.1 51234 443\r\n")
+            .0
+            .is_err());
+        assert!(read(b"PROXY TCP4 203.0.113.7 10.0.0.1 http 443\r\n")
+            .0
+            .is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat())
+            .0
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_v2_header() {
+        let header = |command: u8, family: u8, addresses: &[u8]| {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.extend([0x20 | command, family]);
+            header.extend(u16::try_from(addresses.len()).unwrap().to_be_bytes());
+            header.extend(addresses);
+            header.extend(b"GET / HTTP/1.1");
+            header
+        };
+
+        // 203.0.113.7:51234 -> 10.0.0.1:443
+        let ipv4 = [203, 0, 113, 7, 10, 0, 0, 1, 0xC8, 0x22, 0x01, 0xBB];
+        let (source, rest) = read(&header(1, 0x11, &ipv4));
+        assert_eq!(source.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1");
+
+        let mut ipv6 = vec![0; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&51234u16.to_be_bytes());
+        let (source, _) = read(&header(1, 0x21, &ipv6));
+        assert_eq!(
+            source.unwrap(),
+            Some("[2001:db8::7]:51234".parse().unwrap())
+        );
+
+        // LOCAL, with or without addresses
+        let (source, rest) = read(&header(0, 0x00, &[]));
+        assert_eq!(source.unwrap(), None);
+        assert_eq!(rest, b"GET / HTTP/1.1");
+
+        assert!(read(&header(1, 0x11, &ipv4[..8])).0.is_err());
+        assert!(read(&header(2, 0x11, &ipv4)).0.is_err());
+    }
+
+    #[test]
+    fn test_missing_header() {
+        assert!(read(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .0
+            .is_err());
+        assert!(
+            read(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00\x00\x00\x00")
+                .0
+                .is_err()
+        );
+        assert!(read(b"PROXY").0.is_err());
+    }
+}
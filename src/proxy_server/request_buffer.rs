@@ -0,0 +1,97 @@
+//! Buffering of the request bodies before the upstream is connected
+//! (`request.buffer_size`), so slow clients do not hold upstream connections
+//! while they send their body.
+//!
+//! The body is read into the retry buffer of the session, which pingora sends
+//! to the upstream first (and again when the request is retried). That buffer
+//! holds at most 64 KiB: only bodies whose length is known and within
+//! `buffer_size` are buffered, the other ones are streamed.
+//!
+//! The bodies of the requests sent with `Expect: 100-continue` are not
+//! buffered when the expectation is forwarded to the upstream.
+
+use http::header;
+use pingora::{http::RequestHeader, protocols::http::ServerSession};
+
+use crate::config::ExpectContinue;
+
+use super::informational;
+
+/// Largest body the retry buffer of a session holds
+pub const MAX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Whether the body of the request is buffered: it has a length
+/// (not chunked), within `buffer_size`
+fn applies(request: &RequestHeader, buffer_size: usize) -> bool {
+    if request.headers.contains_key(header::TRANSFER_ENCODING) {
+        return false;
+    }
+
+    request
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .is_some_and(|length| length > 0 && length <= buffer_size.min(MAX_BUFFER_SIZE))
+}
+
+/// Reads the whole body of the request when it applies. The clients waiting
+/// for a `100 Continue` are sent one first, unless the upstream decides
+/// whether their body is sent (`forward`): it is then streamed.
+pub async fn buffer(
+    session: &mut ServerSession,
+    buffer_size: usize,
+    expect_continue: ExpectContinue,
+) -> pingora::Result<()> {
+    if session.is_upgrade_req() || !applies(session.req_header(), buffer_size) {
+        return Ok(());
+    }
+
+    if informational::expects_continue(session.req_header()) {
+        if expect_continue == ExpectContinue::Forward {
+            return Ok(());
+        }
+        informational::answer_continue(session).await?;
+    }
+
+    session.enable_retry_buffering();
+    while session.read_request_body().await?.is_some() {}
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("POST", b"/upload", None).unwrap();
+        for (name, value) in headers {
+            request.insert_header(*name, *value).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn test_request_buffer_applies() {
+        assert!(applies(&request(&[("content-length", "1024")]), 4096));
+        assert!(applies(&request(&[("content-length", "4096")]), 4096));
+
+        // Too large, of unknown length or without a body
+        assert!(!applies(&request(&[("content-length", "4097")]), 4096));
+        assert!(!applies(
+            &request(&[("transfer-encoding", "chunked")]),
+            4096
+        ));
+        assert!(!applies(&request(&[("content-length", "0")]), 4096));
+        assert!(!applies(&request(&[]), 4096));
+        assert!(!applies(&request(&[("content-length", "1024")]), 0));
+
+        // Bounded by the retry buffer
+        let length = (MAX_BUFFER_SIZE + 1).to_string();
+        assert!(!applies(
+            &request(&[("content-length", &length)]),
+            usize::MAX
+        ));
+    }
+}
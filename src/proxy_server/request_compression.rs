@@ -0,0 +1,220 @@
+//! Compression (gzip) of the request bodies sent to the upstreams
+//! (`request_compression` of an upstream), to save bandwidth on slow links.
+//!
+//! With `mode: auto`, the bodies are only compressed once the upstream has
+//! advertised gzip in the `Accept-Encoding` header of a response (RFC 7694).
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use dashmap::DashSet;
+use http::{header, Version};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    protocols::{
+        http::compression::{Algorithm, Encode},
+        l4::socket::SocketAddr,
+    },
+};
+
+use crate::config::{RequestCompressionMode, UpstreamRequestCompression};
+
+/// Upstreams of a route that advertised gzip request bodies.
+/// Clones share the same upstreams.
+#[derive(Clone, Default)]
+pub struct AdvertisedUpstreams {
+    upstreams: Arc<DashSet<SocketAddr>>,
+}
+
+impl AdvertisedUpstreams {
+    /// Whether the upstream advertised gzip request bodies
+    pub fn accepts_gzip(&self, addr: &SocketAddr) -> bool {
+        self.upstreams.contains(addr)
+    }
+
+    /// Records the request encodings advertised by a response of the upstream,
+    /// responses without `Accept-Encoding` change nothing
+    pub fn record(&self, addr: &SocketAddr, response: &ResponseHeader) {
+        let Some(accept_encoding) = response.headers.get(header::ACCEPT_ENCODING) else {
+            return;
+        };
+
+        let accepts_gzip = accept_encoding.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .any(|coding| coding.trim().eq_ignore_ascii_case("gzip"))
+        });
+
+        if accepts_gzip {
+            self.upstreams.insert(addr.clone());
+        } else {
+            self.upstreams.remove(addr);
+        }
+    }
+}
+
+/// Whether the body of the request is compressed: it is not encoded yet and
+/// either of unknown length (chunked) or at least `min_bytes` long
+fn applies(config: &UpstreamRequestCompression, request: &RequestHeader) -> bool {
+    if request.headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+
+    let content_length = request
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    match content_length {
+        Some(length) => length > 0 && length >= config.min_bytes,
+        None => request.headers.contains_key(header::TRANSFER_ENCODING),
+    }
+}
+
+/// Compresses the body of a request sent to an upstream
+pub struct RequestCompressor {
+    encoder: Box<dyn Encode + Send + Sync>,
+}
+
+impl RequestCompressor {
+    /// Starts the compression of the body of the request (when it applies),
+    /// updating its headers: the length of the compressed body is unknown
+    pub fn start(
+        config: &UpstreamRequestCompression,
+        request: &mut RequestHeader,
+        advertised: bool,
+    ) -> pingora::Result<Option<Self>> {
+        if config.mode == RequestCompressionMode::Auto && !advertised {
+            return Ok(None);
+        }
+
+        if !applies(config, request) {
+            return Ok(None);
+        }
+
+        let Some(encoder) = Algorithm::Gzip.compressor(config.level) else {
+            return Ok(None);
+        };
+
+        request.remove_header(&header::CONTENT_LENGTH);
+        request.insert_header(header::CONTENT_ENCODING, "gzip")?;
+        // HTTP/2 frames the body itself
+        if request.version != Version::HTTP_2 {
+            request.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+        }
+
+        Ok(Some(RequestCompressor { encoder }))
+    }
+
+    /// Compresses the next chunk of the body, the last one ends the gzip stream
+    pub fn filter(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) -> pingora::Result<()> {
+        let input = body.as_deref().unwrap_or_default();
+        if input.is_empty() && !end_of_stream {
+            return Ok(());
+        }
+
+        *body = Some(self.encoder.encode(input, end_of_stream)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(mode: RequestCompressionMode) -> UpstreamRequestCompression {
+        UpstreamRequestCompression {
+            mode,
+            min_bytes: 1024,
+            level: 6,
+        }
+    }
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("POST", b"/upload", None).unwrap();
+        for (name, value) in headers {
+            request.insert_header(*name, *value).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn test_request_compression_applies() {
+        let always = config(RequestCompressionMode::Always);
+        let start = |config, mut request: RequestHeader, advertised| {
+            RequestCompressor::start(config, &mut request, advertised)
+                .unwrap()
+                .map(|_| request)
+        };
+
+        // Below the threshold, already encoded or without a body
+        assert!(start(&always, request(&[("content-length", "10")]), true).is_none());
+        assert!(start(
+            &always,
+            request(&[("content-length", "4096"), ("content-encoding", "br")]),
+            true
+        )
+        .is_none());
+        assert!(start(&always, request(&[]), true).is_none());
+
+        // Not advertised by the upstream yet
+        let auto = config(RequestCompressionMode::Auto);
+        assert!(start(&auto, request(&[("content-length", "4096")]), false).is_none());
+
+        let compressed = start(&auto, request(&[("content-length", "4096")]), true).unwrap();
+        assert_eq!(compressed.headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(compressed.headers[header::TRANSFER_ENCODING], "chunked");
+        assert!(!compressed.headers.contains_key(header::CONTENT_LENGTH));
+
+        let chunked = start(&always, request(&[("transfer-encoding", "chunked")]), false);
+        assert!(chunked.is_some());
+    }
+
+    #[test]
+    fn test_request_body_is_gzipped() {
+        let mut request = request(&[("content-length", "2048")]);
+        let mut compressor =
+            RequestCompressor::start(&config(RequestCompressionMode::Always), &mut request, false)
+                .unwrap()
+                .unwrap();
+
+        let mut compressed = Vec::new();
+        for (chunk, end_of_stream) in [
+            (Some("a".repeat(1024)), false),
+            (None, false),
+            (Some("b".repeat(1024)), false),
+            (None, true),
+        ] {
+            let mut body = chunk.map(Bytes::from);
+            compressor.filter(&mut body, end_of_stream).unwrap();
+            compressed.extend_from_slice(&body.unwrap_or_default());
+        }
+        // A whole gzip stream: magic number first, input size (mod 2^32) last
+        assert_eq!(compressed[..2], [0x1f, 0x8b]);
+        assert_eq!(compressed[compressed.len() - 4..], 2048u32.to_le_bytes());
+        assert!(compressed.len() < 2048);
+    }
+
+    #[test]
+    fn test_advertised_upstreams() {
+        let advertised = AdvertisedUpstreams::default();
+        let addr = SocketAddr::Inet("127.0.0.1:4051".parse().unwrap());
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        advertised.record(&addr, &response);
+        assert!(!advertised.accepts_gzip(&addr));
+
+        response
+            .insert_header("accept-encoding", "br, GZIP")
+            .unwrap();
+        advertised.record(&addr, &response);
+        assert!(advertised.accepts_gzip(&addr));
+
+        response
+            .insert_header("accept-encoding", "identity")
+            .unwrap();
+        advertised.clone().record(&addr, &response);
+        assert!(!advertised.accepts_gzip(&addr));
+    }
+}
@@ -0,0 +1,112 @@
+//! Requests sent again to another upstream of the route when theirs failed
+//! (`retries`): it could not be connected to (or closed the connection before
+//! its response), or it answered one of the statuses retried.
+//!
+//! Only the requests of the idempotent methods are retried unless the route
+//! opts in, so a `POST` is not written twice. The upstreams that failed the
+//! request are not selected again while others are left.
+
+use std::collections::HashSet;
+
+use http::Method;
+
+use crate::config::{RetryCondition, RetryOn, RouteRetries};
+
+/// The failures of a route whose requests are sent again
+#[derive(Debug)]
+pub struct Retries {
+    attempts: usize,
+    connect_error: bool,
+    statuses: HashSet<u16>,
+    non_idempotent: bool,
+}
+
+impl Retries {
+    pub fn new(config: &RouteRetries) -> Self {
+        let mut connect_error = false;
+        let mut statuses = HashSet::new();
+        for retry_on in &config.retry_on {
+            match retry_on {
+                RetryOn::Condition(RetryCondition::ConnectError) => connect_error = true,
+                RetryOn::Status(status) => {
+                    statuses.insert(*status);
+                }
+            }
+        }
+
+        Retries {
+            attempts: config.attempts,
+            connect_error,
+            statuses,
+            non_idempotent: config.non_idempotent,
+        }
+    }
+
+    /// Whether the request is sent again after its upstream failed to connect,
+    /// once it was retried `retried` times
+    pub fn on_connect_error(&self, method: &Method, retried: usize) -> bool {
+        self.connect_error && self.allows(method, retried)
+    }
+
+    /// Whether the request is sent again after its upstream answered `status`,
+    /// once it was retried `retried` times
+    pub fn on_status(&self, status: u16, method: &Method, retried: usize) -> bool {
+        self.statuses.contains(&status) && self.allows(method, retried)
+    }
+
+    fn allows(&self, method: &Method, retried: usize) -> bool {
+        retried < self.attempts && (self.non_idempotent || is_idempotent(method))
+    }
+}
+
+/// The methods whose requests can be sent twice without changing the outcome
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retries(retry_on: Vec<RetryOn>, non_idempotent: bool) -> Retries {
+        Retries::new(&RouteRetries {
+            attempts: 2,
+            retry_on,
+            non_idempotent,
+        })
+    }
+
+    #[test]
+    fn test_retries_idempotent_methods() {
+        let retries = retries(
+            vec![
+                RetryOn::Condition(RetryCondition::ConnectError),
+                RetryOn::Status(503),
+            ],
+            false,
+        );
+
+        assert!(retries.on_connect_error(&Method::GET, 0));
+        assert!(retries.on_connect_error(&Method::PUT, 1));
+        assert!(!retries.on_connect_error(&Method::GET, 2));
+        assert!(!retries.on_connect_error(&Method::POST, 0));
+        assert!(!retries.on_connect_error(&Method::PATCH, 0));
+
+        assert!(retries.on_status(503, &Method::HEAD, 0));
+        assert!(!retries.on_status(502, &Method::HEAD, 0));
+        assert!(!retries.on_status(503, &Method::POST, 0));
+    }
+
+    #[test]
+    fn test_retries_non_idempotent_methods() {
+        let retries = retries(vec![RetryOn::Status(502)], true);
+
+        assert!(retries.on_status(502, &Method::POST, 1));
+        assert!(!retries.on_status(502, &Method::POST, 2));
+        // Only the statuses are retried
+        assert!(!retries.on_connect_error(&Method::GET, 0));
+    }
+}
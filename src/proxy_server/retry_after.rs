@@ -0,0 +1,102 @@
+//! The `Retry-After` of the upstream responses (RFC 9110, section 10.2.3):
+//! a number of seconds, or an HTTP date (`Sun, 06 Nov 1994 08:49:37 GMT`).
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::header;
+use pingora::http::ResponseHeader;
+
+/// The delay asked by the `Retry-After` of the response at `now`, `None`
+/// without a (valid) header
+pub fn delay(response: &ResponseHeader, now: SystemTime) -> Option<Duration> {
+    let value = response
+        .headers
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    // A date in the past asks for no delay
+    let date = http_date(value)?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Parses an IMF-fixdate, the only format of the HTTP dates sent nowadays
+fn http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let mut parts = value.split_whitespace();
+    let (_weekday, day, month, year, clock, zone) = (
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+    );
+    if zone != "GMT" || parts.next().is_some() {
+        return None;
+    }
+
+    let month = MONTHS.iter().position(|name| *name == month)?;
+    let month = time::Month::try_from(u8::try_from(month + 1).ok()?).ok()?;
+    let mut clock = clock.split(':').map(str::parse::<u8>);
+    let datetime = time::Date::from_calendar_date(year.parse().ok()?, month, day.parse().ok()?)
+        .ok()?
+        .with_hms(
+            clock.next()?.ok()?,
+            clock.next()?.ok()?,
+            clock.next()?.ok()?,
+        )
+        .ok()?
+        .assume_utc();
+
+    let secs = u64::try_from(datetime.unix_timestamp()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(retry_after: &str) -> ResponseHeader {
+        let mut response = ResponseHeader::build(503, None).unwrap();
+        response
+            .insert_header(header::RETRY_AFTER, retry_after)
+            .unwrap();
+        response
+    }
+
+    #[test]
+    fn test_delay_in_seconds() {
+        let now = SystemTime::now();
+        assert_eq!(delay(&response("120"), now), Some(Duration::from_secs(120)));
+        assert_eq!(delay(&response("soon"), now), None);
+        assert_eq!(delay(&ResponseHeader::build(503, None).unwrap(), now), None);
+    }
+
+    #[test]
+    fn test_delay_until_a_date() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        let date = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let retry_after = response("Sun, 06 Nov 1994 08:49:37 GMT");
+
+        assert_eq!(
+            delay(&retry_after, date - Duration::from_secs(30)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            delay(&retry_after, date + Duration::from_secs(30)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            delay(&response("Sun, 06 Nov 1994 08:49:37 CET"), date),
+            None
+        );
+    }
+}
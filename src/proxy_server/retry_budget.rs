@@ -0,0 +1,142 @@
+//! The retries of the requests to the upstreams, bounded by a token bucket
+//! shared by all routes (`retry_budget`): during widespread failures, the
+//! requests fail instead of being sent again to upstreams already failing.
+//!
+//! The bucket earns `ratio` tokens by request sent to an upstream, plus
+//! `min_retries_per_sec` tokens each second. A retry spends a token, and is
+//! not made without one.
+
+use std::{sync::Mutex, time::Instant};
+
+use crate::config::RetryBudget as RetryBudgetConfig;
+
+/// The tokens are counted in millionths, so that the ones earned by requests
+/// add up exactly (ten tenths are a token)
+const TOKEN: u64 = 1_000_000;
+
+/// The retries that can still be made
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// Millionths of a token earned by request
+    per_request: u64,
+    /// Millionths of a token earned by microsecond
+    min_retries_per_sec: u64,
+    burst: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: u64,
+    refilled: Instant,
+}
+
+impl RetryBudget {
+    /// The budget of the configuration, `None` when the retries are not bounded.
+    /// It starts full.
+    pub fn new(config: &RetryBudgetConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        let burst = u64::from(config.burst) * TOKEN;
+        Some(RetryBudget {
+            per_request: (config.ratio * TOKEN as f64).round() as u64,
+            min_retries_per_sec: u64::from(config.min_retries_per_sec),
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        })
+    }
+
+    /// Earns the tokens of a request sent to an upstream
+    pub fn deposit(&self, now: Instant) {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket, now);
+        bucket.tokens = (bucket.tokens + self.per_request).min(self.burst);
+    }
+
+    /// Spends the token of a retry, `false` when there is none left
+    pub fn withdraw(&self, now: Instant) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket, now);
+        if bucket.tokens < TOKEN {
+            return false;
+        }
+
+        bucket.tokens -= TOKEN;
+        true
+    }
+
+    /// Earns the tokens of the time elapsed since the last refill
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_micros();
+        let earned = u64::try_from(elapsed)
+            .unwrap_or(u64::MAX)
+            .saturating_mul(self.min_retries_per_sec);
+        bucket.tokens = bucket.tokens.saturating_add(earned).min(self.burst);
+        bucket.refilled = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn budget(min_retries_per_sec: u32, burst: u32) -> RetryBudget {
+        RetryBudget::new(&RetryBudgetConfig {
+            enabled: true,
+            ratio: 0.1,
+            min_retries_per_sec,
+            burst,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_disabled_budget() {
+        assert!(RetryBudget::new(&RetryBudgetConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_retries_bounded_by_the_requests() {
+        let budget = budget(0, 2);
+        let now = Instant::now();
+
+        // The saved tokens, then a retry every 10 requests
+        assert!(budget.withdraw(now));
+        assert!(budget.withdraw(now));
+        assert!(!budget.withdraw(now));
+
+        for _ in 0..9 {
+            budget.deposit(now);
+        }
+        assert!(!budget.withdraw(now));
+        budget.deposit(now);
+        assert!(budget.withdraw(now));
+        assert!(!budget.withdraw(now));
+
+        // Never more than the burst
+        for _ in 0..100 {
+            budget.deposit(now);
+        }
+        assert!(budget.withdraw(now));
+        assert!(budget.withdraw(now));
+        assert!(!budget.withdraw(now));
+    }
+
+    #[test]
+    fn test_minimum_retries_per_second() {
+        let budget = budget(10, 10);
+        let now = Instant::now();
+        while budget.withdraw(now) {}
+
+        let later = now + Duration::from_millis(500);
+        let retries = std::iter::from_fn(|| budget.withdraw(later).then_some(()));
+        assert_eq!(retries.count(), 5);
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+/// Why the access log of a request was emitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleReason {
+    /// The request failed or the response is a server error (5xx)
+    Error,
+    /// The request took longer than the slow request threshold
+    Slow,
+    /// The request was picked by the sample rate
+    Rate,
+}
+
+impl SampleReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SampleReason::Error => "error",
+            SampleReason::Slow => "slow",
+            SampleReason::Rate => "rate",
+        }
+    }
+}
+
+/// Decides whether the access log of a finished request is emitted.
+/// Failed and slow requests are always sampled, the others are sampled
+/// with the probability `sample_rate` (`roll` is a random number in `0.0..1.0`).
+/// Routes without access logs (`enabled` false) only log failed requests.
+pub fn sample(
+    status: u16,
+    failed: bool,
+    duration: Duration,
+    slow_request: Duration,
+    sample_rate: f64,
+    roll: f64,
+    enabled: bool,
+) -> Option<SampleReason> {
+    if failed || status >= 500 {
+        Some(SampleReason::Error)
+    } else if !enabled {
+        None
+    } else if duration >= slow_request {
+        Some(SampleReason::Slow)
+    } else if roll < sample_rate {
+        Some(SampleReason::Rate)
+    } else {
+        None
+    }
+}
+
+/// The status class used as metric label (ex: `2xx`),
+/// `none` when no response was sent
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "none",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_always_keeps_problem_requests() {
+        let fast = Duration::from_millis(5);
+        let slow = Duration::from_secs(1);
+
+        // A rate of 0 drops regular requests only
+        assert_eq!(sample(200, false, fast, slow, 0.0, 0.0, true), None);
+        assert_eq!(
+            sample(503, false, fast, slow, 0.0, 0.99, true),
+            Some(SampleReason::Error)
+        );
+        assert_eq!(
+            sample(0, true, fast, slow, 0.0, 0.99, true),
+            Some(SampleReason::Error)
+        );
+        assert_eq!(
+            sample(200, false, slow, slow, 0.0, 0.99, true),
+            Some(SampleReason::Slow)
+        );
+
+        assert_eq!(
+            sample(404, false, fast, slow, 0.01, 0.005, true),
+            Some(SampleReason::Rate)
+        );
+        assert_eq!(sample(200, false, fast, slow, 0.01, 0.5, true), None);
+        assert_eq!(
+            sample(200, false, fast, slow, 1.0, 0.999, true),
+            Some(SampleReason::Rate)
+        );
+    }
+
+    #[test]
+    fn test_sample_disabled_route_keeps_errors_only() {
+        let fast = Duration::from_millis(5);
+        let slow = Duration::from_secs(1);
+
+        assert_eq!(sample(200, false, fast, slow, 1.0, 0.0, false), None);
+        assert_eq!(sample(200, false, slow, slow, 1.0, 0.0, false), None);
+        assert_eq!(
+            sample(502, false, fast, slow, 1.0, 0.0, false),
+            Some(SampleReason::Error)
+        );
+        assert_eq!(
+            sample(0, true, fast, slow, 1.0, 0.0, false),
+            Some(SampleReason::Error)
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use http::{header, HeaderValue, StatusCode};
+use pingora::http::ResponseHeader;
+
+use crate::config::RouteStatusMapping;
+
+/// The status (and `Location`) a client receives instead of the one of the upstream
+#[derive(Debug, Clone)]
+struct Mapping {
+    status: StatusCode,
+    location: Option<HeaderValue>,
+}
+
+/// Statuses of the upstream responses of a route sent to the clients as other ones
+#[derive(Debug, Clone, Default)]
+pub struct StatusMap {
+    mappings: HashMap<u16, Mapping>,
+}
+
+impl StatusMap {
+    /// Compiles the mappings of a route, `None` when there is none.
+    /// Invalid mappings are skipped (they are rejected when the configuration is validated).
+    pub fn new(mappings: &[RouteStatusMapping]) -> Option<Self> {
+        let mappings = mappings
+            .iter()
+            .filter_map(|mapping| {
+                let status = StatusCode::from_u16(mapping.to).ok()?;
+                let location = match &mapping.location {
+                    Some(location) => Some(HeaderValue::from_str(location).ok()?),
+                    None => None,
+                };
+
+                Some((mapping.from, Mapping { status, location }))
+            })
+            .collect::<HashMap<_, _>>();
+
+        (!mappings.is_empty()).then_some(Self { mappings })
+    }
+
+    /// Rewrites the status of the response when it is mapped
+    pub fn apply(&self, response: &mut ResponseHeader) -> pingora::Result<()> {
+        let Some(mapping) = self.mappings.get(&response.status.as_u16()) else {
+            return Ok(());
+        };
+
+        response.set_status(mapping.status)?;
+        if let Some(location) = &mapping.location {
+            response.insert_header(header::LOCATION, location)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_map() {
+        let status_map = StatusMap::new(&[
+            RouteStatusMapping {
+                from: 418,
+                to: 503,
+                location: None,
+            },
+            RouteStatusMapping {
+                from: 401,
+                to: 302,
+                location: Some("https://example.com/login".into()),
+            },
+        ])
+        .unwrap();
+
+        let mut response = ResponseHeader::build(418, None).unwrap();
+        status_map.apply(&mut response).unwrap();
+        assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers.get(header::LOCATION).is_none());
+
+        let mut response = ResponseHeader::build(401, None).unwrap();
+        response.insert_header(header::LOCATION, "/other").unwrap();
+        status_map.apply(&mut response).unwrap();
+        assert_eq!(response.status, StatusCode::FOUND);
+        assert_eq!(
+            response.headers.get(header::LOCATION).unwrap(),
+            "https://example.com/login"
+        );
+
+        // Untouched statuses pass through unchanged
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        status_map.apply(&mut response).unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+
+        assert!(StatusMap::new(&[]).is_none());
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use pingora::{
+    apps::ServerApp,
+    lb::{discovery::Static, selection::RoundRobin, Backend, Backends, LoadBalancer},
+    protocols::Stream,
+    server::{ListenFds, Server, ShutdownWatch},
+    services::{background::background_service, listening, Service},
+};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    config::{self, Config, StreamProtocol},
+    services::{discovery::resolve_backends, health_check},
+};
+
+/// Maximum number of upstreams tried for a single TCP connection
+const MAX_CONNECT_ATTEMPTS: usize = 3;
+
+/// Time allowed to connect to a TCP upstream before trying the next one
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest UDP datagram that can be forwarded
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// Time between two attempts to bind a UDP port that is still in use
+const UDP_BIND_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Adds a listening service (plus the health check of its upstreams)
+/// for every configured stream
+pub fn add_services(server: &mut Server, config: &Config) -> Result<(), anyhow::Error> {
+    for stream in &config.streams {
+        let name = format!("stream {} ({:?})", stream.listen, stream.protocol);
+        let backends = resolve_backends(&stream.upstreams)?;
+
+        let mut load_balancer =
+            LoadBalancer::<RoundRobin>::from_backends(Backends::new(Static::new(backends)));
+
+        // UDP upstreams can only be probed through a (TCP or gRPC) check set explicitly
+        if stream.protocol == StreamProtocol::Tcp || stream.health_check.is_some() {
+            let targets = health_check::HealthTargets::default();
+            targets.update(&stream.upstreams);
+            load_balancer.set_health_check(health_check::from_config(
+                &stream.listen,
+                stream.health_check.as_ref(),
+                targets,
+            ));
+            load_balancer.health_check_frequency = Some(Duration::from_secs(15));
+        }
+
+        // Discovers the backends on start and runs the health checks
+        let health_service = background_service(&name, load_balancer);
+        let load_balancer = health_service.task();
+        server.add_service(health_service);
+
+        match stream.protocol {
+            StreamProtocol::Tcp => {
+                let mut service = listening::Service::new(name, TcpProxy { load_balancer });
+                service
+                    .add_tcp_with_settings(&stream.listen, config.listeners.tcp.socket_options());
+                server.add_service(service);
+            }
+            StreamProtocol::Udp => {
+                server.add_service(UdpProxy::new(name, stream, load_balancer));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Forwards every TCP connection to a healthy upstream, trying the next one
+/// when the connection cannot be established
+pub struct TcpProxy {
+    load_balancer: Arc<LoadBalancer<RoundRobin>>,
+}
+
+impl TcpProxy {
+    async fn connect(&self) -> Option<TcpStream> {
+        let mut tried: Vec<Backend> = Vec::with_capacity(MAX_CONNECT_ATTEMPTS);
+
+        while tried.len() < MAX_CONNECT_ATTEMPTS {
+            let backend = self
+                .load_balancer
+                .select_with(b"", 256, |b, healthy| healthy && !tried.contains(b))?;
+            let addr = *backend.addr.as_inet()?;
+
+            match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(upstream)) => return Some(upstream),
+                Ok(Err(err)) => {
+                    tracing::warn!("failed to connect to stream upstream {addr}: {err}");
+                }
+                Err(_) => tracing::warn!("timed out connecting to stream upstream {addr}"),
+            }
+
+            tried.push(backend);
+        }
+
+        None
+    }
+}
+
+#[async_trait]
+impl ServerApp for TcpProxy {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut downstream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let Some(mut upstream) = self.connect().await else {
+            tracing::error!("no stream upstream available");
+            return None;
+        };
+
+        if let Err(err) = tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+            tracing::debug!("stream connection closed: {err}");
+        }
+
+        None
+    }
+}
+
+/// The upstream socket a UDP client is bound to
+struct UdpSession {
+    upstream: Arc<UdpSocket>,
+    last_seen: Arc<Mutex<Instant>>,
+}
+
+/// Forwards UDP datagrams to a healthy upstream. Each client address keeps
+/// being forwarded to the same upstream (through its own socket, so replies
+/// can be routed back) until it has been idle for `idle_timeout`.
+pub struct UdpProxy {
+    name: String,
+    listen: String,
+    idle_timeout: Duration,
+    load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    sessions: Arc<DashMap<SocketAddr, UdpSession>>,
+}
+
+impl UdpProxy {
+    pub fn new(
+        name: String,
+        config: &config::Stream,
+        load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    ) -> Self {
+        Self {
+            name,
+            listen: config.listen.to_string(),
+            idle_timeout: Duration::from_secs(config.udp_idle_timeout_secs),
+            load_balancer,
+            sessions: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Returns the upstream socket of the client, creating a new session if needed
+    async fn session(
+        &self,
+        client: SocketAddr,
+        downstream: &Arc<UdpSocket>,
+        shutdown: &ShutdownWatch,
+    ) -> Result<Arc<UdpSocket>, anyhow::Error> {
+        if let Some(session) = self.sessions.get(&client) {
+            *session.last_seen.lock().unwrap() = Instant::now();
+            return Ok(session.upstream.clone());
+        }
+
+        let backend = self
+            .load_balancer
+            .select(b"", 256)
+            .ok_or_else(|| anyhow::anyhow!("no stream upstream available"))?;
+        let addr = *backend
+            .addr
+            .as_inet()
+            .ok_or_else(|| anyhow::anyhow!("invalid upstream address {}", backend.addr))?;
+
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let upstream = Arc::new(UdpSocket::bind(local).await?);
+        upstream.connect(addr).await?;
+
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        self.sessions.insert(
+            client,
+            UdpSession {
+                upstream: upstream.clone(),
+                last_seen: last_seen.clone(),
+            },
+        );
+
+        // Relays the replies of the upstream back to the client
+        let downstream = downstream.clone();
+        let sessions = self.sessions.clone();
+        let idle_timeout = self.idle_timeout;
+        let replies = upstream.clone();
+        let mut shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let received = tokio::select! {
+                    received = timeout(idle_timeout, replies.recv(&mut buf)) => received,
+                    _ = shutdown.changed() => break,
+                };
+
+                match received {
+                    Ok(Ok(len)) => {
+                        *last_seen.lock().unwrap() = Instant::now();
+                        downstream.send_to(&buf[..len], client).await.ok();
+                    }
+                    Ok(Err(err)) => {
+                        tracing::debug!("udp upstream {addr} error: {err}");
+                        break;
+                    }
+                    Err(_) if last_seen.lock().unwrap().elapsed() >= idle_timeout => break,
+                    Err(_) => {}
+                }
+            }
+
+            sessions.remove(&client);
+        });
+
+        Ok(upstream)
+    }
+}
+
+#[async_trait]
+impl Service for UdpProxy {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        // UDP sockets are not handed over on upgrade: the port is bound again
+        // once the instance being replaced releases it (when it starts shutting down)
+        let downstream = loop {
+            match UdpSocket::bind(&self.listen).await {
+                Ok(socket) => break Arc::new(socket),
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                    tracing::warn!("udp {} is in use, retrying: {err}", self.listen);
+                    tokio::select! {
+                        () = tokio::time::sleep(UDP_BIND_RETRY_INTERVAL) => {}
+                        _ = shutdown.changed() => return,
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("failed to listen on udp {}: {err}", self.listen);
+                    return;
+                }
+            }
+        };
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let received = tokio::select! {
+                received = downstream.recv_from(&mut buf) => received,
+                _ = shutdown.changed() => break,
+            };
+
+            let (len, client) = match received {
+                Ok(received) => received,
+                Err(err) => {
+                    tracing::debug!("failed to receive udp datagram: {err}");
+                    continue;
+                }
+            };
+
+            match self.session(client, &downstream, &shutdown).await {
+                Ok(upstream) => {
+                    upstream.send(&buf[..len]).await.ok();
+                }
+                Err(err) => tracing::warn!("dropping udp datagram from {client}: {err}"),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_tcp_proxy_fails_over_to_next_upstream() {
+        let alive = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let proxy = TcpProxy {
+            load_balancer: Arc::new(
+                LoadBalancer::try_from_iter([dead, alive.local_addr().unwrap()]).unwrap(),
+            ),
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // Both upstreams are considered healthy, so the dead one is tried as well
+        for _ in 0..4 {
+            let upstream = runtime.block_on(proxy.connect()).unwrap();
+            assert_eq!(upstream.peer_addr().unwrap(), alive.local_addr().unwrap());
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Rewriting of the bodies of text responses (`response.substitute` of a route).
+//!
+//! A body is buffered until its end, rewritten, then sent as a single chunk.
+//! A body that grows beyond the size cap is sent unchanged from that point.
+
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use pingora::http::ResponseHeader;
+use regex::bytes::{NoExpand, Regex};
+
+use crate::config::RouteResponse;
+
+/// Types that are rewritten, besides `text/*` and the `+json`/`+xml` types
+const TEXT_TYPES: [&str; 5] = [
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/xhtml+xml",
+    "image/svg+xml",
+];
+
+/// A substitution, `from` compiled (escaped unless it is a regular expression)
+#[derive(Debug)]
+struct Rule {
+    from: Regex,
+    to: Vec<u8>,
+    expand: bool,
+}
+
+/// The substitutions of a route
+#[derive(Debug)]
+pub struct Substitutions {
+    rules: Vec<Rule>,
+    max_bytes: usize,
+}
+
+impl Substitutions {
+    /// Compiles the substitutions, `None` when there is nothing to substitute
+    pub fn new(config: &RouteResponse) -> Result<Option<Self>, anyhow::Error> {
+        let rules = config
+            .substitute
+            .iter()
+            .map(|rule| {
+                let pattern = if rule.regex {
+                    rule.from.to_string()
+                } else {
+                    regex::escape(&rule.from)
+                };
+
+                Ok(Rule {
+                    from: Regex::new(&pattern)?,
+                    to: rule.to.as_bytes().to_vec(),
+                    expand: rule.regex,
+                })
+            })
+            .collect::<Result<Vec<_>, regex::Error>>()?;
+
+        if rules.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Substitutions {
+            rules,
+            max_bytes: config.substitute_max_bytes,
+        }))
+    }
+
+    /// Whether the body of the response is rewritten: a text type, not encoded,
+    /// with a body that is not known to exceed the size cap
+    pub fn applies_to(&self, response: &ResponseHeader, head_request: bool) -> bool {
+        let status = response.status;
+        if head_request || status.is_informational() || matches!(status.as_u16(), 204 | 304) {
+            return false;
+        }
+
+        let header = |name| {
+            response
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+        };
+
+        let encoded = header(http::header::CONTENT_ENCODING)
+            .is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity"));
+        let too_large = header(http::header::CONTENT_LENGTH)
+            .and_then(|length| length.parse::<usize>().ok())
+            .is_some_and(|length| length > self.max_bytes);
+
+        !encoded && !too_large && header(http::header::CONTENT_TYPE).is_some_and(is_text)
+    }
+
+    /// Applies every substitution, in order
+    pub fn apply(&self, body: &[u8]) -> Bytes {
+        let mut body = body.to_vec();
+        for rule in &self.rules {
+            let replaced = if rule.expand {
+                rule.from.replace_all(&body, rule.to.as_slice())
+            } else {
+                rule.from.replace_all(&body, NoExpand(&rule.to))
+            };
+            body = replaced.into_owned();
+        }
+
+        Bytes::from(body)
+    }
+}
+
+/// Whether the `Content-Type` is a text type
+fn is_text(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || TEXT_TYPES.contains(&mime.as_str())
+}
+
+/// The body of a response being rewritten
+#[derive(Debug)]
+pub struct BodyRewrite {
+    substitutions: Arc<Substitutions>,
+    buffer: BytesMut,
+    /// The body exceeded the size cap and is passed through unchanged
+    skipped: bool,
+}
+
+impl BodyRewrite {
+    pub fn new(substitutions: Arc<Substitutions>) -> Self {
+        BodyRewrite {
+            substitutions,
+            buffer: BytesMut::new(),
+            skipped: false,
+        }
+    }
+
+    /// Buffers a chunk of the body. The chunk is replaced by the rewritten body
+    /// at the end of the stream, or by the (unchanged) buffered body once the
+    /// size cap is exceeded.
+    pub fn filter(&mut self, host: &str, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if self.skipped {
+            return;
+        }
+
+        if let Some(chunk) = body.take() {
+            self.buffer.extend_from_slice(&chunk);
+        }
+
+        if self.buffer.len() > self.substitutions.max_bytes {
+            tracing::warn!(
+                "skipping the substitutions of a response of host {host}: the body exceeds {} bytes",
+                self.substitutions.max_bytes
+            );
+            self.skipped = true;
+            *body = Some(self.buffer.split().freeze());
+            return;
+        }
+
+        if end_of_stream {
+            *body = Some(self.substitutions.apply(&self.buffer));
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::config::RouteSubstitution;
+
+    use super::*;
+
+    fn substitutions(
+        rules: &[(&'static str, &'static str, bool)],
+        max_bytes: usize,
+    ) -> Arc<Substitutions> {
+        let config = RouteResponse {
+            substitute: rules
+                .iter()
+                .map(|(from, to, regex)| RouteSubstitution {
+                    from: Cow::Borrowed(from),
+                    to: Cow::Borrowed(to),
+                    regex: *regex,
+                })
+                .collect(),
+            substitute_max_bytes: max_bytes,
+        };
+
+        Arc::new(Substitutions::new(&config).unwrap().unwrap())
+    }
+
+    #[test]
+    fn test_apply_substitutions() {
+        let substitutions = substitutions(
+            &[
+                ("http://app.internal:8080", "https://example.com", false),
+                (r"v(\d+)\.example", "api.example/v$1", true),
+                ("$1", "literal", false),
+            ],
+            1024,
+        );
+
+        let body = substitutions.apply(b"<a href=\"http://app.internal:8080/x\">v2.example $1</a>");
+        assert_eq!(
+            &body[..],
+            b"<a href=\"https://example.com/x\">api.example/v2 literal</a>"
+        );
+    }
+
+    #[test]
+    fn test_applies_to_text_responses() {
+        let substitutions = substitutions(&[("a", "b", false)], 10);
+        let response = |headers: &[(&str, &str)]| {
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            for (name, value) in headers {
+                response.insert_header(name.to_string(), *value).unwrap();
+            }
+            response
+        };
+
+        let html = response(&[("content-type", "text/html; charset=utf-8")]);
+        assert!(substitutions.applies_to(&html, false));
+        assert!(!substitutions.applies_to(&html, true));
+        assert!(
+            substitutions.applies_to(&response(&[("content-type", "application/ld+json")]), false)
+        );
+        assert!(!substitutions.applies_to(&response(&[("content-type", "image/png")]), false));
+        assert!(!substitutions.applies_to(&response(&[]), false));
+        assert!(!substitutions.applies_to(
+            &response(&[("content-type", "text/html"), ("content-encoding", "gzip")]),
+            false
+        ));
+        assert!(!substitutions.applies_to(
+            &response(&[("content-type", "text/html"), ("content-length", "11")]),
+            false
+        ));
+    }
+
+    #[test]
+    fn test_body_rewrite() {
+        let substitutions = substitutions(&[("internal", "example", false)], 16);
+
+        let mut rewrite = BodyRewrite::new(substitutions.clone());
+        let mut body = Some(Bytes::from_static(b"app."));
+        rewrite.filter("example.com", &mut body, false);
+        assert_eq!(body, None);
+        let mut body = Some(Bytes::from_static(b"internal"));
+        rewrite.filter("example.com", &mut body, true);
+        assert_eq!(body, Some(Bytes::from_static(b"app.example")));
+
+        // Beyond the size cap, the body is passed through unchanged
+        let mut rewrite = BodyRewrite::new(substitutions);
+        let mut body = Some(Bytes::from_static(b"internal internal"));
+        rewrite.filter("example.com", &mut body, false);
+        assert_eq!(body, Some(Bytes::from_static(b"internal internal")));
+        let mut body = Some(Bytes::from_static(b" internal"));
+        rewrite.filter("example.com", &mut body, true);
+        assert_eq!(body, Some(Bytes::from_static(b" internal")));
+    }
+}
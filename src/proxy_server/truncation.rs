@@ -0,0 +1,54 @@
+use http::{HeaderMap, HeaderName, HeaderValue};
+use pingora::{proxy::Session, Error, ErrorSource};
+
+use crate::config::{TruncatedResponseAction, TruncatedResponses};
+
+/// Whether the error ended a response whose header was already sent downstream
+/// because of the upstream (connection closed mid-body, read timeout, invalid chunk...)
+pub fn is_truncated(error: &Error, header_sent: bool) -> bool {
+    header_sent && error.esource() == &ErrorSource::Upstream
+}
+
+/// Ends the downstream side of a truncated response, returns whether a trailer was sent.
+///
+/// Otherwise the session is dropped without being finished: HTTP/1 connections
+/// are closed before the end of the body (without the last chunk, or short of the
+/// `Content-Length`) and HTTP/2 streams are reset, which clients and caches
+/// treat as an incomplete response.
+pub async fn end(session: &mut Session, config: &TruncatedResponses) -> bool {
+    if config.action != TruncatedResponseAction::Trailer || !session.is_http2() {
+        return false;
+    }
+
+    let Ok(name) = HeaderName::from_bytes(config.trailer.as_bytes()) else {
+        return false;
+    };
+
+    let mut trailers = HeaderMap::with_capacity(1);
+    trailers.insert(name, HeaderValue::from_static("truncated"));
+    session
+        .as_mut()
+        .write_response_trailers(trailers)
+        .await
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use pingora::ErrorType::{ConnectionClosed, HTTPStatus, ReadError};
+
+    use super::*;
+
+    #[test]
+    fn test_is_truncated() {
+        let upstream = Error::new(ConnectionClosed).into_up();
+        assert!(is_truncated(&upstream, true));
+        // Before the header is sent, the client gets a 502 instead
+        assert!(!is_truncated(&upstream, false));
+
+        let downstream = Error::new(ReadError).into_down();
+        assert!(!is_truncated(&downstream, true));
+
+        assert!(!is_truncated(&Error::new(HTTPStatus(500)), true));
+    }
+}
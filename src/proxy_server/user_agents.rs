@@ -0,0 +1,150 @@
+//! Requests of a route answered with a 403 because of their `User-Agent`
+//! (`user_agent`), such as the crawlers and scanners hammering a site, before
+//! any plugin runs or any upstream is picked.
+//!
+//! The agents matching `block` (or the preset) are blocked, unless they match
+//! `allow`: a preset can block many bots while a few are let through.
+
+use regex::RegexSet;
+
+use crate::config::{RouteUserAgent, UserAgentPattern, UserAgentPreset};
+
+/// Aggressive SEO crawlers and vulnerability scanners, ignoring case
+const BAD_BOTS: &[&str] = &[
+    "AhrefsBot",
+    "SemrushBot",
+    "MJ12bot",
+    "DotBot",
+    "BLEXBot",
+    "PetalBot",
+    "MegaIndex",
+    "DataForSeoBot",
+    "serpstatbot",
+    "SeekportBot",
+    "Bytespider",
+    "sqlmap",
+    "Nikto",
+    "Nmap Scripting Engine",
+    "masscan",
+    "zgrab",
+    "WPScan",
+    "Acunetix",
+];
+
+/// The blocked and allowed agents of a route
+#[derive(Debug)]
+pub struct UserAgentFilter {
+    block: RegexSet,
+    allow: RegexSet,
+    block_missing: bool,
+}
+
+impl UserAgentFilter {
+    /// Compiles the patterns of a route, including the ones of its preset
+    pub fn new(config: &RouteUserAgent) -> Result<Self, regex::Error> {
+        let mut block = patterns(&config.block);
+        if config.preset == Some(UserAgentPreset::BadBots) {
+            block.extend(BAD_BOTS.iter().map(|bot| contains(bot)));
+        }
+
+        Ok(UserAgentFilter {
+            block: RegexSet::new(block)?,
+            allow: RegexSet::new(patterns(&config.allow))?,
+            block_missing: config.block_missing,
+        })
+    }
+
+    /// Whether the request with this `User-Agent` (if any) is blocked. An
+    /// empty agent is a missing one.
+    pub fn is_blocked(&self, user_agent: Option<&str>) -> bool {
+        let Some(user_agent) = user_agent.filter(|agent| !agent.is_empty()) else {
+            return self.block_missing;
+        };
+
+        self.block.is_match(user_agent) && !self.allow.is_match(user_agent)
+    }
+}
+
+fn patterns(patterns: &[UserAgentPattern]) -> Vec<String> {
+    patterns
+        .iter()
+        .map(|pattern| match pattern {
+            UserAgentPattern::Contains(value) => contains(value),
+            UserAgentPattern::Regex(pattern) => pattern.clone(),
+        })
+        .collect()
+}
+
+/// The regular expression of a substring, ignoring case
+fn contains(value: &str) -> String {
+    format!("(?i){}", regex::escape(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(
+        preset: Option<UserAgentPreset>,
+        block: Vec<UserAgentPattern>,
+        allow: Vec<UserAgentPattern>,
+    ) -> UserAgentFilter {
+        UserAgentFilter::new(&RouteUserAgent {
+            preset,
+            block,
+            allow,
+            block_missing: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bad_bots_preset() {
+        let filter = filter(Some(UserAgentPreset::BadBots), vec![], vec![]);
+
+        for agent in [
+            "Mozilla/5.0 (compatible; AhrefsBot/7.0; +http://ahrefs.com/robot/)",
+            "Mozilla/5.0 (compatible; mj12bot/v1.4.8; http://mj12bot.com/)",
+            "sqlmap/1.7.2#stable (https://sqlmap.org)",
+        ] {
+            assert!(filter.is_blocked(Some(agent)), "{agent}");
+        }
+
+        for agent in [
+            "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0",
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+        ] {
+            assert!(!filter.is_blocked(Some(agent)), "{agent}");
+        }
+        assert!(!filter.is_blocked(None));
+    }
+
+    #[test]
+    fn test_block_and_allow() {
+        let filter = filter(
+            None,
+            vec![
+                UserAgentPattern::Contains("bot".to_string()),
+                UserAgentPattern::Regex("^curl/".to_string()),
+            ],
+            vec![UserAgentPattern::Contains("Googlebot".to_string())],
+        );
+
+        assert!(filter.is_blocked(Some("EvilBot/1.0")));
+        assert!(filter.is_blocked(Some("curl/8.5.0")));
+        assert!(!filter.is_blocked(Some("libcurl/8.5.0")));
+        assert!(!filter.is_blocked(Some("Googlebot/2.1")));
+        assert!(!filter.is_blocked(Some("Mozilla/5.0")));
+
+        let block_missing = UserAgentFilter::new(&RouteUserAgent {
+            preset: None,
+            block: vec![],
+            allow: vec![],
+            block_missing: true,
+        })
+        .unwrap();
+        assert!(block_missing.is_blocked(None));
+        assert!(block_missing.is_blocked(Some("")));
+        assert!(!block_missing.is_blocked(Some("Mozilla/5.0")));
+    }
+}
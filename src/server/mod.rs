@@ -1 +1,2 @@
-
+pub mod summary;
+pub mod upgrade;
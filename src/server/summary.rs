@@ -0,0 +1,139 @@
+//! Summary of what an instance starts with (routes, listeners, services)
+
+use serde_json::{json, Value};
+
+use crate::{
+    build_info,
+    config::{Config, SummaryFormat},
+};
+
+/// Name and address of each listener
+fn listeners(config: &Config) -> Vec<(String, String)> {
+    let mut listeners = vec![
+        (
+            "http".to_string(),
+            config.listeners.http_address.to_string(),
+        ),
+        (
+            "https".to_string(),
+            config.listeners.https_address.to_string(),
+        ),
+    ];
+
+    if let Some(address) = &config.listeners.acme_challenge_address {
+        listeners.push(("acme_challenge".to_string(), address.to_string()));
+    }
+
+    for stream in &config.streams {
+        let protocol = serde_json::to_value(stream.protocol).unwrap_or_default();
+        let name = format!("stream/{}", protocol.as_str().unwrap_or_default());
+        listeners.push((name, stream.listen.to_string()));
+    }
+
+    if config.admin.enabled {
+        listeners.push(("admin".to_string(), config.admin.bind.to_string()));
+    }
+
+    listeners
+}
+
+/// The background services that are enabled
+fn services(config: &Config) -> Vec<&'static str> {
+    [
+        ("docker", config.docker.enabled.unwrap_or(true)),
+        ("lets_encrypt", config.lets_encrypt.enabled.unwrap_or(true)),
+        ("auto_reload", config.auto_reload.enabled.unwrap_or(true)),
+        ("admin", config.admin.enabled),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+/// Where certificates are ordered: `staging`, `production` or the directory
+/// of another ACME provider (`disabled` without the Let's Encrypt service)
+fn acme_environment(config: &Config) -> String {
+    let lets_encrypt = &config.lets_encrypt;
+    if !lets_encrypt.enabled.unwrap_or(true) {
+        return "disabled".to_string();
+    }
+
+    match (lets_encrypt.directory_url.as_deref(), lets_encrypt.staging) {
+        (Some(url), _) => url.to_string(),
+        (None, Some(false)) => "production".to_string(),
+        (None, _) => "staging".to_string(),
+    }
+}
+
+/// The summary, as printed with `--format json`
+pub fn to_json(config: &Config) -> Value {
+    let listeners = listeners(config)
+        .into_iter()
+        .map(|(name, address)| json!({ "name": name, "address": address }))
+        .collect::<Vec<_>>();
+
+    json!({
+        "version": build_info::VERSION,
+        "git_commit": build_info::GIT_COMMIT,
+        "worker_threads": config.worker_threads,
+        "routes": config.routes.len(),
+        "streams": config.streams.len(),
+        "listeners": listeners,
+        "services": services(config),
+        "acme": acme_environment(config),
+    })
+}
+
+/// Emits the summary at startup, logged or printed to stdout as JSON
+pub fn print_startup_summary(config: &Config) {
+    if config.format == SummaryFormat::Json {
+        println!("{}", to_json(config));
+        return;
+    }
+
+    let listeners = listeners(config)
+        .iter()
+        .map(|(name, address)| format!("{name}={address}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    tracing::info!(
+        version = build_info::VERSION,
+        git_commit = build_info::GIT_COMMIT,
+        pingora_version = build_info::PINGORA_VERSION,
+        workers = config.worker_threads,
+        routes = config.routes.len(),
+        streams = config.streams.len(),
+        listeners,
+        services = services(config).join(", "),
+        acme = acme_environment(config),
+        "starting proksi"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut config = Config::default();
+        let summary = to_json(&config);
+        assert_eq!(summary["routes"], 0);
+        assert_eq!(summary["acme"], "staging");
+        assert_eq!(summary["listeners"][1]["name"], "https");
+        assert_eq!(summary["listeners"][1]["address"], "0.0.0.0:443");
+        assert_eq!(summary["services"], json!(["lets_encrypt"]));
+
+        config.lets_encrypt.enabled = Some(false);
+        config.docker.enabled = Some(true);
+        config.admin.enabled = true;
+        config.admin.bind = Cow::Borrowed("127.0.0.1:9091");
+        let summary = to_json(&config);
+        assert_eq!(summary["acme"], "disabled");
+        assert_eq!(summary["listeners"][2]["name"], "admin");
+        assert_eq!(summary["services"], json!(["docker", "admin"]));
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use tokio::sync::broadcast::Sender;
+
+use crate::{config::Config, services::discovery::RoutingService, MsgProxy};
+
+/// Time given to this process to listen on the upgrade socket
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Returns the pid of the running instance, as written in the pid file
+pub fn running_pid(pid_file: &Path) -> Result<Pid, anyhow::Error> {
+    let content = fs::read_to_string(pid_file).map_err(|err| {
+        anyhow!(
+            "failed to read the pid file {}: {err}",
+            pid_file.to_string_lossy()
+        )
+    })?;
+
+    let pid = content
+        .trim()
+        .parse::<i32>()
+        .map(Pid::from_raw)
+        .map_err(|_| anyhow!("invalid pid file {}", pid_file.to_string_lossy()))?;
+
+    // No signal is sent, this only checks that the process exists
+    kill(pid, None).map_err(|err| anyhow!("no running instance with pid {pid}: {err}"))?;
+
+    Ok(pid)
+}
+
+/// Writes the pid of this process to the pid file.
+/// Pingora only writes it itself when running as a daemon.
+pub fn write_pid_file(pid_file: &Path) -> Result<(), anyhow::Error> {
+    fs::write(pid_file, std::process::id().to_string()).map_err(|err| {
+        anyhow!(
+            "failed to write the pid file {}: {err}",
+            pid_file.to_string_lossy()
+        )
+    })
+}
+
+/// Asks the running instance to hand its listening sockets over to this process.
+///
+/// The running instance is sent a `SIGQUIT` as soon as this process listens on
+/// the upgrade socket (from `Server::bootstrap`, which blocks until the sockets
+/// are received). It then stops accepting connections and exits once the
+/// in-flight ones are done.
+pub fn request_handoff(
+    pid_file: &Path,
+    upgrade_sock: &Path,
+) -> Result<JoinHandle<Result<(), anyhow::Error>>, anyhow::Error> {
+    let pid = running_pid(pid_file)?;
+
+    // A socket left behind by a previous upgrade would be mistaken for ours
+    match fs::remove_file(upgrade_sock) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(anyhow!(
+                "failed to remove the upgrade socket {}: {err}",
+                upgrade_sock.to_string_lossy()
+            ));
+        }
+        _ => {}
+    }
+
+    let upgrade_sock = PathBuf::from(upgrade_sock);
+    Ok(thread::spawn(move || {
+        let started = Instant::now();
+        while !upgrade_sock.exists() {
+            if started.elapsed() > READY_TIMEOUT {
+                return Err(anyhow!("timed out waiting for the upgrade socket"));
+            }
+            thread::sleep(READY_POLL_INTERVAL);
+        }
+
+        kill(pid, Signal::SIGQUIT)
+            .map_err(|err| anyhow!("failed to signal the running instance {pid}: {err}"))
+    }))
+}
+
+/// Loads the routes (and their certificates) of the configuration before
+/// the sockets are taken over: the running instance keeps accepting connections
+/// for a few seconds after the handoff, and this one must not answer them
+/// with 404s until its routing service has started.
+pub fn preload_routes(
+    config: Arc<Config>,
+    broadcast: Sender<MsgProxy>,
+) -> Result<(), anyhow::Error> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(RoutingService::new(config, broadcast).add_routes_from_config());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_running_pid_from_pid_file() {
+        let dir = std::env::temp_dir().join(format!("proksi-upgrade-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pid_file = dir.join("proksi.pid");
+
+        assert!(running_pid(&pid_file).is_err());
+
+        write_pid_file(&pid_file).unwrap();
+        let pid = running_pid(&pid_file).unwrap();
+        assert_eq!(pid.as_raw().unsigned_abs(), std::process::id());
+
+        fs::write(&pid_file, "not a pid").unwrap();
+        assert!(running_pid(&pid_file).is_err());
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,706 @@
+use std::{collections::HashMap, sync::Arc, time::UNIX_EPOCH};
+
+use async_trait::async_trait;
+use http::{header, Method, Response, StatusCode};
+use pingora::{
+    apps::http_app::ServeHttp, protocols::http::ServerSession, server::Server, services::listening,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    build_info,
+    config::Config,
+    error::{AcmeFailure, Error},
+    proxy_server::{compression, matching},
+    services::{health_check, letsencrypt::http01},
+    stores::{
+        self,
+        certificates::{self, CertificateSource},
+        routes::RouteStoreContainer,
+    },
+    tools::path,
+};
+
+/// Largest request body accepted by the admin API
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Settings whose values are never returned, by (a part of) their name
+const SENSITIVE_SETTINGS: &[&str] = &["secret", "token", "pass", "hmac", "private_key", "api_key"];
+
+/// Headers whose values are never returned (ex: added to the upstream requests)
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+/// Replaces the returned sensitive values
+const REDACTED: &str = "[redacted]";
+
+/// Adds the admin API to the server, when enabled
+pub fn add_service(server: &mut Server, config: &Arc<Config>) {
+    if !config.admin.enabled {
+        return;
+    }
+
+    let mut service = listening::Service::new("admin".to_string(), HttpApi::new(config.clone()));
+    service.add_tcp(&config.admin.bind);
+    server.add_service(service);
+}
+
+/// JSON admin API, served on its own address (see `admin.bind`)
+pub struct HttpApi {
+    config: Arc<Config>,
+}
+
+impl HttpApi {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config }
+    }
+
+    /// Whether the request carries the configured bearer token (if any)
+    fn is_authorized(&self, session: &ServerSession) -> bool {
+        let Some(token) = self.config.admin.token.as_deref() else {
+            return true;
+        };
+
+        let provided = session
+            .req_header()
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        provided.len() == token.len() && openssl::memcmp::eq(provided.as_bytes(), token.as_bytes())
+    }
+}
+
+/// A request to test against the routes, as it would reach the HTTPS service
+#[derive(Debug, Deserialize)]
+pub struct RouteTestRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// The host of the request (default: the `host` header)
+    pub host: Option<String>,
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+/// Finds the route (and the upstream the load balancer selects) for the given request,
+/// using the same matching as the HTTPS service. Plugins are not run.
+/// The upstream is picked by the real selection of the route, so this counts
+/// as one selection for the round-robin rotation.
+pub fn test_route(request: &RouteTestRequest) -> Result<Value, &'static str> {
+    let host = request
+        .host
+        .as_deref()
+        .or_else(|| {
+            request
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+                .map(|(_, value)| value.as_str())
+        })
+        .ok_or("a host (or host header) is required")?;
+    let host = matching::normalize_host(host);
+    let path = path::normalize(request.path.split('?').next().unwrap_or_default());
+
+    let matched = match matching::match_route(&host, &path) {
+        Ok(matched) => matched,
+        Err(err) => {
+            return Ok(json!({
+                "matched": false,
+                "method": request.method,
+                "host": host,
+                "path": path,
+                "reason": err.as_str(),
+            }))
+        }
+    };
+
+    let route = &matched.route;
+    let upstream = route
+        .select_backend(None)
+        .and_then(|backend| matching::find_upstream(route, &backend));
+
+    let mut plugins = route.plugins.keys().collect::<Vec<_>>();
+    plugins.sort();
+
+    Ok(json!({
+        "matched": true,
+        "method": request.method,
+        "host": host,
+        "path": path,
+        "route": {
+            "host": route.key(),
+            "pattern": matched.pattern,
+            "plugins": plugins,
+            "upstreams": route.upstreams,
+        },
+        // `null` when no upstream is healthy (the request would get a 503)
+        "upstream": upstream,
+    }))
+}
+
+/// Replaces the values of the sensitive settings (and headers) with `REDACTED`
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            // `{ name, value }` entries of the headers
+            let sensitive_header = map.get("name").and_then(Value::as_str).is_some_and(|name| {
+                SENSITIVE_HEADERS.contains(&name.to_ascii_lowercase().as_str())
+            });
+
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                let sensitive = SENSITIVE_SETTINGS.iter().any(|name| key.contains(name))
+                    || (sensitive_header && key == "value");
+
+                if sensitive && !value.is_null() {
+                    *value = json!(REDACTED);
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// The configuration of the route of the host as the HTTPS service applies it:
+/// the route (with its middleware profiles expanded), the global settings it
+/// inherits and the state of the route store (matchers, plugins, upstreams).
+/// Routes discovered at runtime (ex: docker) only have the latter.
+/// `None` when there is no route for the host.
+pub fn route_config(config: &Config, host: &str) -> Option<Value> {
+    let host = matching::normalize_host(host);
+
+    // The route of the highest priority serves the host
+    let route = config
+        .routes
+        .iter()
+        .filter(|route| matching::normalize_host(&route.host) == host)
+        .max_by_key(|route| route.priority());
+    let stored = stores::get_route_by_key(&host).map(|route| route.value().clone());
+    if route.is_none() && stored.is_none() {
+        return None;
+    }
+
+    let effective = route.map(|route| {
+        let compression = compression::resolve(route.compression.as_ref(), &config.compression)
+            .map(|settings| {
+                json!({
+                    "level": settings.level,
+                    "zstd_level": settings.zstd_level,
+                    "algorithms": settings.algorithms,
+                    "content_types": settings.content_types,
+                })
+            });
+
+        json!({
+            "compression": compression,
+            "access_log": {
+                "enabled": route.access_log_enabled(),
+                "sample_rate": route
+                    .access_log_sample_rate()
+                    .unwrap_or(config.tracing.sample_rate),
+            },
+            "selection": route.selection.unwrap_or_default(),
+            "strip_response_headers": config.headers.strip_response,
+            "limits": config.limits,
+            "timeouts": config.timeouts,
+            "local_zone": config.local_zone,
+        })
+    });
+
+    let runtime = stored.map(|route| {
+        let mut plugins = route.plugins.keys().collect::<Vec<_>>();
+        plugins.sort();
+
+        json!({
+            "patterns": route.path_matcher.patterns,
+            "plugins": plugins,
+            // In the order they are tried
+            "auth": route.auth.iter().map(|middleware| &middleware.name).collect::<Vec<_>>(),
+            "headers": {
+                "add": route
+                    .host_header_add
+                    .iter()
+                    .map(|(name, value)| json!({ "name": name.as_str(), "value": value.to_str().ok() }))
+                    .collect::<Vec<_>>(),
+                "remove": route.host_header_remove,
+                "add_request": route
+                    .upstream_header_add
+                    .iter()
+                    .map(|(name, value)| json!({ "name": name.as_str(), "value": value.to_str().ok() }))
+                    .collect::<Vec<_>>(),
+                "remove_request": route.upstream_header_remove,
+                "forwarded": route.forwarded_headers,
+            },
+            "upstreams": route.upstreams,
+            "self_signed_certificate": route.self_signed_certificate,
+        })
+    });
+
+    let mut result = json!({
+        "host": host,
+        "source": if route.is_some() { "config" } else { "discovery" },
+        "route": route,
+        "effective": effective,
+        "runtime": runtime,
+    });
+    redact(&mut result);
+
+    Some(result)
+}
+
+/// The whole configuration proksi runs with (files, environment and defaults
+/// merged), with the sensitive values redacted
+pub fn effective_config(config: &Config) -> Value {
+    let mut result = serde_json::to_value(config).unwrap_or_default();
+    redact(&mut result);
+    result
+}
+
+/// The certificates of the store (their validity as unix timestamps), by host
+pub fn certificates() -> Value {
+    let mut certificates = stores::get_certificates()
+        .iter()
+        .map(|(host, cert)| {
+            json!({
+                "host": host,
+                "issuer": cert.issuer(),
+                "not_before": certificates::unix_timestamp(cert.leaf.not_before()),
+                "not_after": certificates::unix_timestamp(cert.leaf.not_after()),
+                "source": cert.source,
+            })
+        })
+        .collect::<Vec<_>>();
+    certificates.sort_by(|a, b| a["host"].as_str().cmp(&b["host"].as_str()));
+
+    json!({ "certificates": certificates })
+}
+
+/// The upstreams of the route, with their health as its load balancer sees it
+fn upstreams_health(route: &RouteStoreContainer) -> Vec<Value> {
+    let backends = route.load_balancer.backends();
+    backends
+        .get_backend()
+        .iter()
+        .map(|backend| {
+            let history = route.health_targets.history(&backend.addr);
+            json!({
+                "address": backend.addr.to_string(),
+                "weight": backend.weight,
+                "healthy": backends.ready(backend),
+                "checks": {
+                    "successes": history.successes,
+                    "failures": history.failures,
+                },
+                "last_transition": history
+                    .last_transition
+                    .and_then(|changed| changed.duration_since(UNIX_EPOCH).ok())
+                    .map(|since| since.as_secs()),
+            })
+        })
+        .collect()
+}
+
+/// The routes of the store, by host: their path patterns and their upstreams
+/// with the health of each
+pub fn routes() -> Value {
+    let mut routes = stores::get_routes()
+        .iter()
+        .map(|(host, route)| {
+            json!({
+                "host": host,
+                "patterns": route.path_matcher.patterns,
+                "upstreams": upstreams_health(route),
+            })
+        })
+        .collect::<Vec<_>>();
+    routes.sort_by(|a, b| a["host"].as_str().cmp(&b["host"].as_str()));
+
+    json!({ "routes": routes })
+}
+
+/// The health of the upstreams of every route, as their load balancer sees it,
+/// with the healthy and unhealthy counts of each route. Routes with less than
+/// `min_healthy` healthy upstreams are `degraded`.
+pub fn health(min_healthy: usize) -> Value {
+    let mut degraded = 0;
+    let mut routes = stores::get_routes()
+        .iter()
+        .map(|(host, route)| {
+            let upstreams = upstreams_health(route);
+
+            let healthy = upstreams
+                .iter()
+                .filter(|upstream| upstream["healthy"] == true)
+                .count();
+            let is_degraded = healthy < min_healthy;
+            degraded += usize::from(is_degraded);
+
+            json!({
+                "host": host,
+                "healthy": healthy,
+                "unhealthy": upstreams.len() - healthy,
+                "degraded": is_degraded,
+                "last_checked": health_check::last_checked(host)
+                    .and_then(|checked| checked.duration_since(UNIX_EPOCH).ok())
+                    .map(|since| since.as_secs()),
+                "upstreams": upstreams,
+            })
+        })
+        .collect::<Vec<_>>();
+    routes.sort_by(|a, b| a["host"].as_str().cmp(&b["host"].as_str()));
+
+    json!({
+        "min_healthy": min_healthy,
+        "degraded": degraded,
+        "routes": routes,
+    })
+}
+
+/// Reads the `min_healthy` parameter of the query (default: 1)
+fn min_healthy(query: Option<&str>) -> Result<usize, &'static str> {
+    let value = query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("min_healthy="));
+
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| "min_healthy must be a non-negative integer"),
+        None => Ok(1),
+    }
+}
+
+/// Revokes the certificate of the host, only the ones issued through ACME can be.
+/// The revocation cannot be undone: it is refused unless `admin.token` is set.
+async fn revoke_certificate(config: &Arc<Config>, host: &str) -> Response<Vec<u8>> {
+    if config.admin.token.is_none() {
+        return error_response(
+            StatusCode::FORBIDDEN,
+            "revoking a certificate requires admin.token",
+        );
+    }
+
+    let host = matching::normalize_host(host).into_owned();
+    let source = stores::get_certificate_by_key(&host).map(|cert| cert.source);
+
+    match source {
+        None => return error_response(StatusCode::NOT_FOUND, "no certificate for the host"),
+        Some(CertificateSource::Acme) => {}
+        Some(_) => {
+            return error_response(
+                StatusCode::CONFLICT,
+                "the certificate was not issued through ACME, it cannot be revoked",
+            )
+        }
+    }
+
+    let config = config.clone();
+    let revoked_host = host.clone();
+    let result =
+        tokio::task::spawn_blocking(move || http01::revoke_certificate(&config, &revoked_host))
+            .await;
+
+    match result {
+        Ok(Ok(())) => json_response(StatusCode::OK, &json!({ "host": host, "revoked": true })),
+        // Listed in the store, but not in the storage of the ACME client
+        Ok(Err(Error::Acme(AcmeFailure::NotFound, message))) => {
+            error_response(StatusCode::NOT_FOUND, &message)
+        }
+        Ok(Err(err)) => error_response(StatusCode::BAD_GATEWAY, &err.to_string()),
+        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "revocation failed"),
+    }
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Vec<u8>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap_or_default()
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response<Vec<u8>> {
+    json_response(status, &json!({ "error": error }))
+}
+
+/// Reads the whole request body, up to `MAX_BODY_SIZE`
+async fn read_body(session: &mut ServerSession) -> Result<Vec<u8>, StatusCode> {
+    let mut body = Vec::new();
+
+    while let Some(chunk) = session
+        .read_request_body()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        if body.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+#[async_trait]
+impl ServeHttp for HttpApi {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        if !self.is_authorized(session) {
+            return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+        }
+
+        let method = session.req_header().method.clone();
+        let path = session.req_header().uri.path().to_string();
+
+        match (method, path.as_str()) {
+            (Method::GET, "/routes") => json_response(StatusCode::OK, &routes()),
+            (_, "/routes") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (Method::POST, "/routes/test") => {
+                let body = match read_body(session).await {
+                    Ok(body) => body,
+                    Err(status) => return error_response(status, "invalid request body"),
+                };
+
+                let request = match serde_json::from_slice::<RouteTestRequest>(&body) {
+                    Ok(request) => request,
+                    Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+                };
+
+                match test_route(&request) {
+                    Ok(result) => json_response(StatusCode::OK, &result),
+                    Err(err) => error_response(StatusCode::BAD_REQUEST, err),
+                }
+            }
+            (_, "/routes/test") => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            (method, path) if path.starts_with("/routes/") && path.ends_with("/config") => {
+                let host = &path["/routes/".len()..path.len() - "/config".len()];
+                if host.is_empty() || host.contains('/') {
+                    return error_response(StatusCode::NOT_FOUND, "not found");
+                }
+                if method != Method::GET {
+                    return error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+                }
+
+                match route_config(&self.config, host) {
+                    Some(result) => json_response(StatusCode::OK, &result),
+                    None => error_response(StatusCode::NOT_FOUND, "no route for the host"),
+                }
+            }
+            (Method::GET, "/config") => {
+                json_response(StatusCode::OK, &effective_config(&self.config))
+            }
+            (_, "/config") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (Method::GET, "/certs" | "/certificates") => {
+                json_response(StatusCode::OK, &certificates())
+            }
+            (_, "/certs" | "/certificates") => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            (method, path) if path.starts_with("/certs/") && path.ends_with("/revoke") => {
+                let host = &path["/certs/".len()..path.len() - "/revoke".len()];
+                if host.is_empty() || host.contains('/') {
+                    return error_response(StatusCode::NOT_FOUND, "not found");
+                }
+                if method != Method::POST {
+                    return error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+                }
+
+                revoke_certificate(&self.config, host).await
+            }
+            (Method::GET, "/health") => {
+                let min_healthy = match min_healthy(session.req_header().uri.query()) {
+                    Ok(min_healthy) => min_healthy,
+                    Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+                };
+
+                // Monitoring can alert on the status alone
+                let result = health(min_healthy);
+                let status = if result["degraded"] == 0 {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                json_response(status, &result)
+            }
+            (_, "/health") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (Method::GET, "/proksi/version") => {
+                json_response(StatusCode::OK, &build_info::to_json())
+            }
+            (_, "/proksi/version") => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_config_is_redacted() {
+        let mut config = Config::default();
+        config.routes = vec![serde_json::from_value(json!({
+            "host": "config.admin.test",
+            "upstreams": [{ "ip": "10.0.0.1", "port": 3000 }],
+            "headers": {
+                "add": [
+                    { "name": "Authorization", "value": "Bearer upstream-token" },
+                    { "name": "x-served-by", "value": "proksi" },
+                ],
+            },
+            "plugins": [{
+                "name": "oauth2",
+                "config": { "client_id": "id", "client_secret": "secret", "jwt_secret": "jwt" },
+            }],
+        }))
+        .unwrap()];
+
+        let result = route_config(&config, "Config.Admin.Test:443").unwrap();
+        assert_eq!(result["source"], "config");
+        assert_eq!(result["runtime"], Value::Null);
+        assert_eq!(result["effective"]["access_log"]["enabled"], true);
+
+        let plugin = &result["route"]["plugins"][0]["config"];
+        assert_eq!(plugin["client_id"], "id");
+        assert_eq!(plugin["client_secret"], REDACTED);
+        assert_eq!(plugin["jwt_secret"], REDACTED);
+
+        let headers = &result["route"]["headers"]["add"];
+        assert_eq!(headers[0]["value"], REDACTED);
+        assert_eq!(headers[1]["value"], "proksi");
+
+        assert!(route_config(&config, "unknown.admin.test").is_none());
+    }
+
+    #[test]
+    fn test_effective_config_is_redacted() {
+        let mut config = Config::default();
+        config.admin.token = Some("admin-token".into());
+        config.lets_encrypt.eab_hmac_key = Some("hmac-key".into());
+
+        let result = effective_config(&config);
+        assert_eq!(result["admin"]["token"], REDACTED);
+        assert_eq!(result["lets_encrypt"]["eab_hmac_key"], REDACTED);
+        assert_eq!(result["lets_encrypt"]["eab_kid"], Value::Null);
+        assert_eq!(result["admin"]["bind"], "127.0.0.1:9091");
+        assert_eq!(result["service_name"], config.service_name.as_ref());
+    }
+
+    #[test]
+    fn test_health() {
+        let load_balancer =
+            pingora::lb::LoadBalancer::try_from_iter(["127.0.0.1:4001", "127.0.0.1:4002"]).unwrap();
+        let unhealthy = load_balancer
+            .backends()
+            .get_backend()
+            .iter()
+            .find(|backend| backend.addr.to_string() == "127.0.0.1:4002")
+            .cloned()
+            .unwrap();
+        load_balancer.backends().set_enable(&unhealthy, false);
+        stores::insert_route(
+            "health.admin.test".to_string(),
+            RouteStoreContainer::new(load_balancer),
+        );
+
+        let result = health(1);
+        let route = result["routes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|route| route["host"] == "health.admin.test")
+            .unwrap();
+        assert_eq!(route["healthy"], 1);
+        assert_eq!(route["unhealthy"], 1);
+        assert_eq!(route["degraded"], false);
+        assert_eq!(route["last_checked"], Value::Null);
+        assert_eq!(route["upstreams"][0]["address"], "127.0.0.1:4001");
+        assert_eq!(route["upstreams"][0]["healthy"], true);
+        assert_eq!(route["upstreams"][1]["healthy"], false);
+        assert_eq!(route["upstreams"][0]["checks"]["failures"], 0);
+        assert_eq!(route["upstreams"][0]["last_transition"], Value::Null);
+
+        let result = health(2);
+        assert!(result["degraded"].as_u64() >= Some(1));
+
+        let result = routes();
+        let route = result["routes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|route| route["host"] == "health.admin.test")
+            .unwrap();
+        assert_eq!(route["patterns"], json!([]));
+        assert_eq!(route["upstreams"][1]["address"], "127.0.0.1:4002");
+        assert_eq!(route["upstreams"][1]["healthy"], false);
+
+        assert_eq!(min_healthy(None), Ok(1));
+        assert_eq!(min_healthy(Some("verbose=1&min_healthy=3")), Ok(3));
+        assert!(min_healthy(Some("min_healthy=-1")).is_err());
+    }
+
+    #[test]
+    fn test_certificates_and_revocation() {
+        let certificate = certificates::Certificate::self_signed("certs.admin.test").unwrap();
+        stores::insert_certificate("certs.admin.test".to_string(), certificate);
+
+        let result = certificates();
+        let listed = result["certificates"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|cert| cert["host"] == "certs.admin.test")
+            .unwrap();
+        assert_eq!(listed["source"], "self_signed");
+        assert_eq!(listed["issuer"], "CN=certs.admin.test, ST=TX, O=Proksi");
+        assert!(listed["not_after"].as_i64() > listed["not_before"].as_i64());
+
+        // Revoking requires a token
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let response = runtime.block_on(revoke_certificate(
+            &Arc::new(Config::default()),
+            "Certs.Admin.Test",
+        ));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Only the certificates issued through ACME can be revoked
+        let mut config = Config::default();
+        config.admin.token = Some("admin-token".into());
+        let config = Arc::new(config);
+        let response = runtime.block_on(revoke_certificate(&config, "Certs.Admin.Test"));
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(stores::get_certificate_by_key("certs.admin.test").is_some());
+
+        let response = runtime.block_on(revoke_certificate(&config, "unknown.admin.test"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,7 +1,10 @@
 use std::{
-    os::unix::process::CommandExt,
+    collections::HashMap,
     path::{self, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use async_trait::async_trait;
@@ -10,16 +13,37 @@ use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::broadcast::Sender,
+};
 
-use crate::config::Config;
+use crate::{
+    config::{self, Config, Route},
+    MsgProxy,
+};
 
+/// Reloads the routes of the configuration when its files change (or on
+/// `SIGHUP`), without restarting: the routes added, changed or removed are sent
+/// to the routing service. The other settings (and `ssl.client_auth`) only
+/// apply after a restart (or `proksi upgrade`).
 pub struct FileWatcherService {
     config: Arc<Config>,
+    broadcast: Sender<MsgProxy>,
+    /// The routes (serialized) of the configuration loaded last, by host
+    routes: HashMap<String, serde_json::Value>,
 }
 
 impl FileWatcherService {
-    pub fn new(config: Arc<Config>) -> Self {
-        Self { config }
+    pub fn new(config: Arc<Config>, broadcast: Sender<MsgProxy>) -> Self {
+        let routes = effective_routes(&config)
+            .map(|(host, route)| (host.to_string(), serialized(route)))
+            .collect();
+        Self {
+            config,
+            broadcast,
+            routes,
+        }
     }
 
     /// Watchs a file or directory for changes
@@ -41,58 +65,134 @@ impl FileWatcherService {
             tracing::debug!("file or directory does not exist: {:?}", absolute_path);
         }
     }
-}
 
-pub struct FileWatcherServiceHandler {}
-impl EventHandler for FileWatcherServiceHandler {
-    /// Handles configuration file changes and restarts the server
-    fn handle_event(&mut self, notif: notify::Result<notify::Event>) {
-        let Ok(n) = notif else {
-            tracing::error!("error handling auto_reload event: {:?}", notif);
-            return;
+    /// Loads the configuration again and sends the changes of its routes.
+    /// An invalid configuration keeps the current routes.
+    fn reload(&mut self) {
+        let config = match config::load(&self.config.config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!("invalid configuration, keeping the current routes: {err}");
+                return;
+            }
         };
 
-        // If no .hcl can be found, skip
-        if !n
-            .paths
-            .iter()
-            .any(|v| v.extension().is_some_and(|v| v == "hcl"))
-        {
-            tracing::info!("no .hcl file found, skipping {:?}", n.paths);
+        let (changes, routes) = route_changes(&self.routes, config);
+        if let Some(host) = client_auth_changed(&self.routes, &routes) {
+            tracing::error!(
+                "ssl.client_auth of host {host} changed, which requires a restart: keeping the current routes"
+            );
             return;
         }
 
-        let Ok(cmd) = std::env::current_exe() else {
-            return;
-        };
+        tracing::info!("configuration reloaded, {} route change(s)", changes.len());
+        for change in changes {
+            if self.broadcast.send(change).is_err() {
+                tracing::error!("the routing service is not running, the routes are not reloaded");
+                return;
+            }
+        }
 
-        let current_pid = std::process::id();
+        self.routes = routes;
+    }
+}
 
-        // remove the command path, take the rest
-        let current_args = std::env::args().skip(1);
+fn serialized(route: &Route) -> serde_json::Value {
+    serde_json::to_value(route).unwrap_or_default()
+}
 
-        // restart the process
-        std::process::Command::new(cmd).args(current_args).exec();
+/// The routes of a configuration with their host, without the ones shadowed
+/// by a route of a higher priority
+fn effective_routes(config: &Config) -> impl Iterator<Item = (&str, &Route)> {
+    config
+        .routes
+        .iter()
+        .filter(|route| {
+            !config
+                .routes
+                .iter()
+                .any(|other| route.is_shadowed_by(other))
+        })
+        .map(|route| (route.host.as_ref(), route))
+}
 
-        tracing::warn!("restarting Proksi server");
+/// The routes added or changed (`UpdatedRoute`) and removed (`RemovedRoute`)
+/// from the current routes to the ones of the new configuration, along with
+/// the new routes (serialized) by host
+fn route_changes(
+    current: &HashMap<String, serde_json::Value>,
+    mut config: Config,
+) -> (Vec<MsgProxy>, HashMap<String, serde_json::Value>) {
+    let effective = effective_routes(&config)
+        .map(|(host, route)| (host.to_string(), serialized(route)))
+        .collect::<HashMap<_, _>>();
 
-        // kill existing process
-        nix::sys::signal::kill(
-            nix::unistd::Pid::from_raw(current_pid.try_into().unwrap()),
-            nix::sys::signal::Signal::SIGQUIT,
-        )
-        .unwrap();
+    let mut changes = Vec::new();
+    for route in std::mem::take(&mut config.routes) {
+        let Some(value) = effective.get(route.host.as_ref()) else {
+            continue;
+        };
+        // The shadowed routes of the host are not the effective one
+        if current.get(route.host.as_ref()) != Some(value) && serialized(&route) == *value {
+            changes.push(MsgProxy::UpdatedRoute(Arc::new(route)));
+        }
+    }
+
+    for host in current.keys().filter(|host| !effective.contains_key(*host)) {
+        changes.push(MsgProxy::RemovedRoute(host.clone()));
     }
+
+    (changes, effective)
 }
 
-#[async_trait]
-impl Service for FileWatcherService {
-    async fn start_service(&mut self, _fds: Option<ListenFds>, _shutdown: ShutdownWatch) {
-        if self.config.auto_reload.enabled.is_some_and(|v| !v) {
-            // Nothing to do, auto reload is  disabled
+/// A host whose `ssl.client_auth` differs between the current routes and the
+/// new ones, if any. The client auth is only loaded at startup: the CRL service
+/// refreshes the revocation lists of the ones it started with. Removing a route
+/// is not a change, its client auth is dropped with it.
+fn client_auth_changed<'a>(
+    current: &HashMap<String, serde_json::Value>,
+    routes: &'a HashMap<String, serde_json::Value>,
+) -> Option<&'a str> {
+    fn client_auth(route: Option<&serde_json::Value>) -> &serde_json::Value {
+        route.map_or(&serde_json::Value::Null, |route| {
+            &route["ssl"]["client_auth"]
+        })
+    }
+
+    routes
+        .iter()
+        .find(|(host, route)| client_auth(Some(route)) != client_auth(current.get(*host)))
+        .map(|(host, _)| host.as_str())
+}
+
+/// Marks the configuration as changed, the service reloads it after the poll
+pub struct FileWatcherServiceHandler {
+    changed: Arc<AtomicBool>,
+}
+
+impl EventHandler for FileWatcherServiceHandler {
+    fn handle_event(&mut self, notif: notify::Result<notify::Event>) {
+        let Ok(n) = notif else {
+            tracing::error!("error handling auto_reload event: {:?}", notif);
+            return;
+        };
+
+        // Only the configuration files trigger a reload
+        if !n.paths.iter().any(|v| {
+            v.extension()
+                .is_some_and(|v| v == "hcl" || v == "yaml" || v == "yml")
+        }) {
+            tracing::info!("no configuration file found, skipping {:?}", n.paths);
             return;
         }
 
+        self.changed.store(true, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl Service for FileWatcherService {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
         // watch main config file:
         let path_buf = PathBuf::from(self.config.config_path.to_string());
         let config_file_yaml = path_buf.join("proksi.yaml");
@@ -100,30 +200,63 @@ impl Service for FileWatcherService {
 
         tracing::info!("starting config watcher service");
 
+        // Without auto_reload, the configuration is only reloaded on SIGHUP
+        let watching = self.config.auto_reload.enabled.unwrap_or(true);
+
+        let changed = Arc::new(AtomicBool::new(false));
         let mut watcher = notify::poll::PollWatcher::new(
-            FileWatcherServiceHandler {},
+            FileWatcherServiceHandler {
+                changed: changed.clone(),
+            },
             notify::Config::default().with_manual_polling(),
         )
         .unwrap();
 
-        Self::watch_file_or_dir(&mut watcher, &config_file_hcl);
-        Self::watch_file_or_dir(&mut watcher, &config_file_yaml);
+        if watching {
+            Self::watch_file_or_dir(&mut watcher, &config_file_hcl);
+            Self::watch_file_or_dir(&mut watcher, &config_file_yaml);
 
-        // Watch for paths in the config
-        for watch_path in &self.config.auto_reload.paths {
-            Self::watch_file_or_dir(&mut watcher, watch_path);
+            // Watch for paths in the config
+            for watch_path in &self.config.auto_reload.paths {
+                Self::watch_file_or_dir(&mut watcher, watch_path);
+            }
         }
 
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(err) => {
+                tracing::error!("failed to listen for SIGHUP: {err}");
+                None
+            }
+        };
+
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(
             self.config.auto_reload.interval_secs.unwrap_or(60),
         ));
         interval.tick().await;
 
         loop {
-            interval.tick().await;
-            if watcher.poll().is_ok() {
-                tracing::debug!("config watcher service tick");
+            tokio::select! {
+                _ = interval.tick(), if watching => {
+                    if watcher.poll().is_ok() {
+                        tracing::debug!("config watcher service tick");
+                    }
+                    if !changed.swap(false, Ordering::Relaxed) {
+                        continue;
+                    }
+                }
+                Some(()) = async {
+                    match hangup.as_mut() {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    tracing::info!("received SIGHUP, reloading the configuration");
+                }
+                _ = shutdown.changed() => break,
             }
+
+            self.reload();
         }
     }
 
@@ -135,3 +268,109 @@ impl Service for FileWatcherService {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(host: &str, port: u16) -> Route {
+        serde_json::from_value(serde_json::json!({
+            "host": host,
+            "upstreams": [{ "ip": "10.0.0.1", "port": port }],
+        }))
+        .unwrap()
+    }
+
+    fn config(routes: Vec<Route>) -> Config {
+        Config {
+            routes,
+            ..Config::default()
+        }
+    }
+
+    fn hosts(changes: &[MsgProxy]) -> (Vec<String>, Vec<String>) {
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+        for change in changes {
+            match change {
+                MsgProxy::UpdatedRoute(route) => updated.push(route.host.to_string()),
+                MsgProxy::RemovedRoute(host) => removed.push(host.clone()),
+                _ => unreachable!(),
+            }
+        }
+        updated.sort();
+        removed.sort();
+        (updated, removed)
+    }
+
+    #[test]
+    fn test_route_changes() {
+        let (changes, current) = route_changes(
+            &HashMap::new(),
+            config(vec![route("kept.test", 3000), route("changed.test", 3000)]),
+        );
+        assert_eq!(hosts(&changes).0, ["changed.test", "kept.test"]);
+
+        let (changes, current) = route_changes(
+            &current,
+            config(vec![
+                route("kept.test", 3000),
+                route("changed.test", 3001),
+                route("added.test", 3000),
+            ]),
+        );
+        let (updated, removed) = hosts(&changes);
+        assert_eq!(updated, ["added.test", "changed.test"]);
+        assert!(removed.is_empty());
+
+        let (changes, _) = route_changes(&current, config(vec![route("kept.test", 3000)]));
+        let (updated, removed) = hosts(&changes);
+        assert!(updated.is_empty());
+        assert_eq!(removed, ["added.test", "changed.test"]);
+    }
+
+    #[test]
+    fn test_client_auth_changes() {
+        let mtls = |host: &str, ca: &str| {
+            let mut route = route(host, 3000);
+            route.ssl =
+                serde_json::from_value(serde_json::json!({ "client_auth": { "ca": ca } })).unwrap();
+            route
+        };
+
+        let (_, current) = route_changes(
+            &HashMap::new(),
+            config(vec![
+                mtls("mtls.test", "/etc/ca.pem"),
+                route("open.test", 3000),
+            ]),
+        );
+
+        // Other settings, and the removal of a route, are reloaded
+        let (_, routes) = route_changes(
+            &current,
+            config(vec![
+                mtls("mtls.test", "/etc/ca.pem"),
+                route("open.test", 3001),
+            ]),
+        );
+        assert_eq!(client_auth_changed(&current, &routes), None);
+        let (_, routes) = route_changes(&current, config(vec![route("open.test", 3000)]));
+        assert_eq!(client_auth_changed(&current, &routes), None);
+
+        for routes in [
+            vec![
+                mtls("mtls.test", "/etc/other-ca.pem"),
+                route("open.test", 3000),
+            ],
+            vec![route("mtls.test", 3000), route("open.test", 3000)],
+            vec![
+                mtls("mtls.test", "/etc/ca.pem"),
+                mtls("open.test", "/etc/ca.pem"),
+            ],
+        ] {
+            let (_, routes) = route_changes(&current, config(routes));
+            assert!(client_auth_changed(&current, &routes).is_some());
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::{proxy_server::client_auth::ClientAuth, services::tick_or_shutdown, stores};
+
+/// Periodically loads again the certificate revocation lists of the mTLS routes
+pub struct CrlService;
+
+/// Refreshes the CRLs of a route, right away and then every `crl.refresh_secs`
+async fn refresh_periodically(auth: Arc<ClientAuth>, mut shutdown: ShutdownWatch) {
+    let mut interval = tokio::time::interval(auth.crl_refresh());
+    while tick_or_shutdown(&mut interval, &mut shutdown).await {
+        auth.refresh().await;
+    }
+}
+
+#[async_trait]
+impl Service for CrlService {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        // The store is filled at startup, before the services start
+        let mut refreshes = tokio::task::JoinSet::new();
+        for auth in stores::get_client_auths() {
+            if auth.has_crls() {
+                refreshes.spawn(refresh_periodically(auth, shutdown.clone()));
+            }
+        }
+
+        while refreshes.join_next().await.is_some() {}
+    }
+
+    fn name(&self) -> &str {
+        "crl_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
@@ -1,26 +1,54 @@
+use std::collections::{BTreeSet, HashSet};
 use std::net::ToSocketAddrs;
-use std::{borrow::Cow, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 
 use http::{HeaderName, HeaderValue};
-use openssl::pkey::PKey;
-use openssl::x509::X509;
-use pingora::lb::{health_check::TcpHealthCheck, selection::RoundRobin, LoadBalancer};
+use pingora::lb::{selection::RoundRobin, Backend, Backends, LoadBalancer};
+use pingora::protocols::l4::socket::SocketAddr;
 use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
-use tokio::sync::broadcast::Sender;
+use tokio::sync::broadcast::{error::RecvError, Sender};
 
-use crate::config::{Route, RouteCache, RouteUpstream};
+use crate::config::{
+    Route, RouteBlockedPaths, RouteGeoRouting, RouteHealthCheck, RouteRateLimit, RouteResponse,
+    RouteSelection, RouteSticky, RouteUpstream, RouteUserAgent, UpstreamScheme,
+};
+use crate::error::Error;
+use crate::plugins::rate_limit::{RateLimitRules, RouteRateLimiter};
+use crate::proxy_server::{
+    blocked_paths::BlockedPaths, concurrency::ConcurrencyLimit, dynamic_upstream::DynamicUpstream,
+    error_handling::ErrorHandling, forward_auth::ForwardAuth, retries::Retries,
+    status_map::StatusMap, substitution::Substitutions, user_agents::UserAgentFilter,
+};
+use crate::services::health_check::{self, HealthTargets};
 use crate::MsgRoute;
 use crate::{
     config::{Config, RouteHeader, RouteMatcher, RoutePathMatcher, RoutePlugin},
-    stores::{self, routes::RouteStoreContainer},
+    stores::{
+        self,
+        certificates::Certificate,
+        routes::{
+            FallbackUpstream, GeoRouting, PathGroups, RouteStoreContainer, RouteStorePathMatcher,
+            Warmup,
+        },
+        sticky::StickyClients,
+    },
     MsgProxy,
 };
 
+use reconcile::DynamicBackends;
+
+pub mod reconcile;
+
 // Service discovery for load balancers
 pub struct RoutingService {
     config: Arc<Config>,
@@ -33,36 +61,57 @@ impl RoutingService {
     }
 
     /// From a given configuration file, create the static load balancing configuration
-    fn add_routes_from_config(&mut self) {
+    pub async fn add_routes_from_config(&mut self) {
         for route in &self.config.routes {
-            let self_signed_cert_on_failure = route
-                .ssl_certificate
-                .as_ref()
-                .and_then(|v| v.self_signed_on_failure);
-
-            if let Err(err) = add_route_ssl_to_store(route) {
-                tracing::error!(
-                    "failed to add SSL certificate to store for host {:?}: {err}",
-                    route.host
+            // Another route declares the same host with a higher priority
+            if let Some(other) = self.config.routes.iter().find(|r| route.is_shadowed_by(r)) {
+                tracing::warn!(
+                    "ignoring a route of host {} (priority {}): a route with priority {} takes precedence",
+                    route.host,
+                    route.priority(),
+                    other.priority()
                 );
+                continue;
             }
 
-            add_route_to_router(
-                &route.host,
-                route.upstreams.clone(),
-                route.match_with.clone(),
-                route.headers.as_ref(),
-                route.plugins.as_ref(),
-                route.cache.as_ref(),
-                self_signed_cert_on_failure.unwrap_or(false),
+            self.add_config_route(route, false).await;
+        }
+    }
+
+    /// Adds a route of the configuration to the store. An existing route of
+    /// the host is kept when its upstreams did not change, unless `replace`
+    /// (a reload changed its other settings).
+    async fn add_config_route(&self, route: &Route, replace: bool) {
+        let self_signed_cert_on_failure = route
+            .ssl_certificate
+            .as_ref()
+            .and_then(|v| v.self_signed_on_failure);
+
+        if let Err(err) = add_route_ssl_to_store(route) {
+            tracing::error!(
+                "failed to add SSL certificate to store for host {:?}: {err}",
+                route.host
             );
+        }
+
+        let result = add_route_to_router(
+            route,
+            self.config.local_zone.as_deref(),
+            self_signed_cert_on_failure.unwrap_or(false),
+            replace,
+        )
+        .await;
 
-            tracing::debug!("Added route: {}, {:?}", route.host, route.upstreams);
+        if let Err(err) = result {
+            tracing::error!("failed to add route {}: {err}", route.host);
+            return;
         }
+
+        tracing::debug!("Added route: {}, {:?}", route.host, route.upstreams);
     }
 
     /// Watch for new routes being added and update the Router Store
-    fn watch_for_route_changes(route: MsgRoute) {
+    async fn watch_for_route_changes(route: MsgRoute) {
         // TODO: refactor
         let mut matcher: Option<RouteMatcher> = None;
         let route_clone = route.path_matchers.clone();
@@ -77,6 +126,7 @@ impl RoutingService {
         let route_header = RouteHeader {
             add: Some(route.host_headers_add),
             remove: Some(route.host_headers_remove),
+            ..RouteHeader::default()
         };
 
         // create route upstreams from ip + port
@@ -85,14 +135,33 @@ impl RoutingService {
             .upstreams
             .iter()
             .flat_map(|u| {
-                if let Ok(scr) = u.to_socket_addrs() {
+                // Discovered upstreams may be given as `scheme://ip` too
+                let (scheme, ip) = match UpstreamScheme::split(&u.ip) {
+                    Ok(split) => split,
+                    Err(err) => {
+                        tracing::error!("upstream {} of route {} {err}", u.ip, route.host);
+                        return vec![];
+                    }
+                };
+
+                if let Ok(scr) = format!("{ip}:{}", u.port).to_socket_addrs() {
                     scr.map(|f| RouteUpstream {
                         ip: Cow::Owned(f.ip().to_string()),
                         port: f.port(),
-                        network: None,
-                        weight: Some(1),
+                        network: u.network.clone(),
+                        weight: u.weight.or(Some(1)),
                         headers: None,
                         sni: None,
+                        pool: None,
+                        zone: None,
+                        protocol: u.protocol,
+                        scheme: scheme.or(u.scheme),
+                        request_compression: u.request_compression.clone(),
+                        health_check: u.health_check.clone(),
+                        max_connections: u.max_connections,
+                        preserve_header_case: u.preserve_header_case,
+                        h2: u.h2,
+                        match_with: None,
                     })
                     .collect::<Vec<_>>()
                 } else {
@@ -101,15 +170,22 @@ impl RoutingService {
             })
             .collect::<Vec<_>>();
 
-        add_route_to_router(
-            &route.host,
+        // The docker routes only set these, the rest keeps the defaults of a route
+        let docker_route = Route {
+            host: route.host.clone(),
             upstreams,
-            matcher,
-            Some(&route_header),
-            Some(&route.plugins),
-            None,
-            route.self_signed_certs,
-        );
+            match_with: matcher,
+            headers: Some(route_header),
+            plugins: Some(route.plugins),
+            ..Route::default()
+        };
+
+        let result = add_route_to_router(&docker_route, None, route.self_signed_certs, false).await;
+
+        if let Err(err) = result {
+            tracing::error!("failed to add route {}: {err}", route.host);
+            return;
+        }
 
         tracing::debug!(
             "Added route: {}, {:?} self-signed: {}",
@@ -122,14 +198,36 @@ impl RoutingService {
 
 #[async_trait]
 impl Service for RoutingService {
-    async fn start_service(&mut self, _fds: Option<ListenFds>, _shutdown: ShutdownWatch) {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
         // Setup initial routes from config file
-        self.add_routes_from_config();
+        self.add_routes_from_config().await;
 
         // Watch for new hosts being added and configure them accordingly
         let mut receiver = self.broadcast.subscribe();
-        while let Ok(MsgProxy::NewRoute(route)) = receiver.recv().await {
-            Self::watch_for_route_changes(route);
+        loop {
+            let message = tokio::select! {
+                message = receiver.recv() => message,
+                _ = shutdown.changed() => break,
+            };
+            match message {
+                Ok(MsgProxy::NewRoute(route)) => Self::watch_for_route_changes(route).await,
+                Ok(MsgProxy::UpdatedRoute(route)) => {
+                    tracing::info!("reloading the route of host {}", route.host);
+                    self.add_config_route(&route, true).await;
+                }
+                Ok(MsgProxy::RemovedRoute(host)) => {
+                    // Not left behind for a route added later on the host
+                    stores::remove_client_auth(&host);
+                    if stores::remove_route(&host).is_some() {
+                        tracing::info!("removed the route of host {host}");
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("missed {missed} route changes");
+                }
+                Err(RecvError::Closed) => break,
+            }
         }
     }
 
@@ -142,65 +240,186 @@ impl Service for RoutingService {
     }
 }
 
-// Check whether the host already exists and if the the upstream list has changed
-fn has_new_backend(host: &str, upstream_input: &LoadBalancer<RoundRobin>) -> bool {
-    if let Some(route_container) = stores::get_route_by_key(host) {
-        let backends = route_container.load_balancer.backends().get_backend();
-        let new_backends = upstream_input.backends().get_backend();
-        // If upstreams are not the same length, return true (update)
-        if backends.len() != new_backends.len() {
-            return true;
-        }
-
-        !backends.iter().all(|be| new_backends.contains(be))
-    } else {
-        false
-    }
-}
-
 /// Adds new routes to the store if there are changes to an existing route or
-/// if the host does not exist in the store.
-fn add_route_to_router(
-    host: &str,
-    upstream_input: Vec<RouteUpstream>,
-    match_with: Option<RouteMatcher>,
-    headers: Option<&RouteHeader>,
-    plugins: Option<&Vec<RoutePlugin>>,
-    cache: Option<&RouteCache>,
+/// if the host does not exist in the store (or `replace` is set).
+/// The load balancer of an existing route is kept and its backends are
+/// reconciled (health state of the unchanged ones is preserved).
+async fn add_route_to_router(
+    route: &Route,
+    local_zone: Option<&str>,
     should_self_sign_cert_on_failure: bool,
-) {
-    // Check if current route already exists
-    let upstream_str = upstream_input
-        .iter()
-        .map(|u| format!("{}:{}", u.ip, u.port))
-        .collect::<Vec<String>>();
-
-    let Ok(mut upstreams) = LoadBalancer::<RoundRobin>::try_from_iter(upstream_str) else {
-        tracing::info!(
-            "Could not create upstreams for host: {}, upstreams {:?}",
-            host,
-            upstream_input
-        );
-        return;
+    replace: bool,
+) -> Result<(), Error> {
+    let host = route.host.as_ref();
+    let upstream_input = route.upstreams.clone();
+    let health_check = route.health_check.as_ref();
+    let warmup = route.warmup.as_ref();
+    let backends = resolve_backends(&upstream_input)?;
+    // An invalid plugin keeps the route out instead of letting every request through
+    let rate_limit_rules = compile_rate_limit_rules(host, route.plugins.as_deref())?;
+
+    // Clone the existing route so the store is not locked across awaits
+    let existing_route = stores::get_route_by_key(host).map(|route| route.value().clone());
+
+    let mut route_store_container = match existing_route {
+        Some(existing)
+            if !replace && *existing.load_balancer.backends().get_backend() == backends =>
+        {
+            existing.health_targets.update(&upstream_input);
+            existing.upstream_connections.update(&upstream_input);
+            tracing::debug!("skipping update, no routing changes for host: {}", host);
+            return Ok(());
+        }
+        Some(RouteStoreContainer {
+            load_balancer,
+            backends: Some(dynamic_backends),
+            active_requests,
+            negotiated_protocols,
+            gzip_upstreams,
+            health_targets,
+            upstream_connections,
+            sticky: existing_sticky,
+            concurrency: existing_concurrency,
+            warmup: existing_warmup,
+            health_check: existing_health_check,
+            ..
+        }) => {
+            health_targets.update(&upstream_input);
+            let (load_balancer, dynamic_backends) = if existing_health_check.as_ref()
+                == health_check
+            {
+                dynamic_backends
+                    .reconcile(&load_balancer, backends)
+                    .await
+                    .map_err(|err| {
+                        Error::Upstream(format!(
+                            "failed to reconcile upstreams for host {host}: {err}"
+                        ))
+                    })?;
+                (load_balancer, dynamic_backends)
+            } else {
+                // The check is set when the load balancer is built. The results
+                // of the previous check say nothing of the new one: the upstreams
+                // are checked again before the new load balancer serves requests.
+                tracing::info!(
+                    "the health check of host {host} changed, checking its upstreams again"
+                );
+                let (load_balancer, dynamic_backends) =
+                    checked_load_balancer(host, backends, health_check, &health_targets).await?;
+                (Arc::new(load_balancer), dynamic_backends)
+            };
+
+            // Requests in flight to the surviving upstreams keep being counted
+            let mut container = RouteStoreContainer::with_shared_load_balancer(load_balancer);
+            container.backends = Some(dynamic_backends);
+            container.active_requests = active_requests;
+            container.negotiated_protocols = negotiated_protocols;
+            container.gzip_upstreams = gzip_upstreams;
+            container.health_targets = health_targets;
+            container.upstream_connections = upstream_connections;
+            // The pins survive, the ring follows the new upstreams
+            container.sticky = existing_sticky;
+            // Requests in flight keep their slots
+            container.concurrency = existing_concurrency;
+            // Not warming up again, the route was already added
+            container.warmup = existing_warmup.filter(|_| warmup.is_some());
+            container
+        }
+        _ => {
+            let health_targets = HealthTargets::default();
+            health_targets.update(&upstream_input);
+            let (load_balancer, dynamic_backends) =
+                checked_load_balancer(host, backends, health_check, &health_targets).await?;
+
+            let mut container = RouteStoreContainer::new(load_balancer);
+            container.backends = Some(dynamic_backends);
+            container.health_targets = health_targets;
+            container.warmup = warmup.map(|warmup| {
+                let warmup = Warmup::new(Duration::from_secs(warmup.period_secs));
+                warmup.record(container.load_balancer.backends(), Instant::now());
+                Arc::new(warmup)
+            });
+            container
+        }
     };
 
-    if stores::get_route_by_key(host).is_some() && !has_new_backend(host, &upstreams) {
-        tracing::debug!("skipping update, no routing changes for host: {}", host);
-        return;
-    }
-
-    // TODO: support defining health checks in the configuration file
-    let tcp_health_check = TcpHealthCheck::new();
-    upstreams.set_health_check(tcp_health_check);
-    upstreams.health_check_frequency = Some(Duration::from_secs(15));
-
-    // Create new routing container
-    let mut route_store_container = RouteStoreContainer::new(upstreams);
+    // Update routing container
+    route_store_container.health_check = route.health_check.clone();
     route_store_container.self_signed_certificate = should_self_sign_cert_on_failure;
+    route_store_container.fallback_upstream = route
+        .fallback_upstream
+        .as_ref()
+        .and_then(|upstream| compile_fallback_upstream(host, upstream));
+    route_store_container
+        .upstream_connections
+        .update(&upstream_input);
     route_store_container.upstreams = upstream_input;
-    route_store_container.cache = cache.cloned();
-
-    if let Some(headers) = headers {
+    route_store_container.cache = route.cache.clone();
+    route_store_container.compression = route.compression.clone();
+    route_store_container.substitutions = route
+        .response
+        .as_ref()
+        .and_then(|response| compile_substitutions(host, response));
+    route_store_container.status_map = StatusMap::new(&route.status_map).map(Arc::new);
+    route_store_container.sample_rate = route.access_log_sample_rate();
+    route_store_container.access_log_enabled = route.access_log_enabled();
+    let selection = route.selection.unwrap_or_default();
+    route_store_container.selection = selection;
+    // `consistent` is the ring of `sticky` (which takes precedence) without the pins
+    let consistent = (route.sticky.is_none() && selection == RouteSelection::Consistent)
+        .then(RouteSticky::consistent);
+    let sticky = route.sticky.as_ref().or(consistent.as_ref());
+    let existing_sticky = route_store_container
+        .sticky
+        .take()
+        .filter(|existing| Some(&existing.config) == sticky);
+    route_store_container.sticky = sticky
+        .map(|config| existing_sticky.unwrap_or_else(|| Arc::new(StickyClients::new(config))));
+    let existing_concurrency = route_store_container
+        .concurrency
+        .take()
+        .filter(|limit| Some(&limit.config) == route.concurrency.as_ref());
+    route_store_container.concurrency = route
+        .concurrency
+        .as_ref()
+        .map(|config| existing_concurrency.unwrap_or_else(|| ConcurrencyLimit::new(host, config)));
+    route_store_container.connection_reuse = route.connection_reuse.clone();
+    route_store_container.timeouts = route.timeouts;
+    route_store_container.mirror = route.mirror.clone();
+    route_store_container.blocked_paths = route
+        .blocked_paths
+        .as_ref()
+        .and_then(|blocked_paths| compile_blocked_paths(host, blocked_paths));
+    route_store_container.user_agent = route
+        .user_agent
+        .as_ref()
+        .and_then(|user_agent| compile_user_agent(host, user_agent));
+    route_store_container.rate_limit = route
+        .rate_limit
+        .as_ref()
+        .and_then(|rate_limit| compile_rate_limit(host, rate_limit));
+    route_store_container.geo_routing = route
+        .geo_routing
+        .as_ref()
+        .and_then(|geo| compile_geo_routing(geo, &upstream_input));
+    route_store_container.path_groups = compile_path_groups(&upstream_input);
+    route_store_container.local_upstreams =
+        local_zone.and_then(|zone| local_upstreams(zone, &upstream_input));
+    route_store_container.dynamic_upstream = route
+        .dynamic_upstream
+        .as_ref()
+        .map(|config| Arc::new(DynamicUpstream::new(config)));
+    route_store_container.error_handling = route
+        .error_handling
+        .as_ref()
+        .and_then(|config| ErrorHandling::new(host, config))
+        .map(Arc::new);
+    route_store_container.retries = route
+        .retries
+        .as_ref()
+        .map(|config| Arc::new(Retries::new(config)));
+
+    if let Some(headers) = &route.headers {
         if let Some(headers) = headers.add.as_ref() {
             route_store_container.host_header_add = headers
                 .iter()
@@ -217,12 +436,31 @@ fn add_route_to_router(
             route_store_container.host_header_remove =
                 to_remove.iter().map(|v| v.name.to_string()).collect();
         }
+
+        if let Some(headers) = headers.add_request.as_ref() {
+            route_store_container.upstream_header_add = headers
+                .iter()
+                .map(|v| {
+                    (
+                        HeaderName::from_str(&v.name).unwrap(),
+                        HeaderValue::from_str(&v.value).unwrap(),
+                    )
+                })
+                .collect();
+        }
+
+        if let Some(to_remove) = headers.remove_request.as_ref() {
+            route_store_container.upstream_header_remove =
+                to_remove.iter().map(|v| v.name.to_string()).collect();
+        }
+
+        route_store_container.forwarded_headers = headers.forwarded;
     }
 
-    if let Some(plugins) = plugins {
+    if let Some(plugins) = &route.plugins {
         for plugin in plugins {
             match plugin.name.as_ref() {
-                "oauth2" | "request_id" | "basic_auth" => {
+                "oauth2" | "request_id" | "basic_auth" | "rate_limit" => {
                     route_store_container
                         .plugins
                         .insert(plugin.name.to_string(), plugin.clone());
@@ -233,20 +471,268 @@ fn add_route_to_router(
         }
     }
 
-    // Prepare route matchers
-    // TODO: enable matchers for upstreams for true load balancing based on path
-    if let Some(match_with) = match_with {
+    route_store_container.rate_limit_rules = rate_limit_rules;
+    route_store_container.auth = route.auth.clone();
+    route_store_container.forward_auth = route
+        .forward_auth
+        .as_ref()
+        .map(|config| Arc::new(ForwardAuth::new(config)));
+
+    // Prepare route matchers (the ones of the upstreams are `path_groups`)
+    if let Some(match_with) = &route.match_with {
         // Path matchers
-        match match_with.path {
+        match &match_with.path {
             Some(path_matcher) if !path_matcher.patterns.is_empty() => {
-                let pattern = path_matcher.patterns;
-                route_store_container.path_matcher.with_pattern(&pattern);
+                route_store_container
+                    .path_matcher
+                    .with_pattern(&path_matcher.patterns);
             }
             _ => {}
         }
     }
 
     stores::insert_route(host.to_string(), route_store_container);
+    Ok(())
+}
+
+/// Resolves the upstreams into backends, applying their weights.
+/// Upstreams without a (positive) weight default to 1.
+pub fn resolve_backends(upstreams: &[RouteUpstream]) -> Result<BTreeSet<Backend>, Error> {
+    let mut backends = BTreeSet::new();
+    for upstream in upstreams {
+        let weight = upstream
+            .weight
+            .and_then(|w| usize::try_from(w).ok())
+            .filter(|w| *w > 0)
+            .unwrap_or(1);
+
+        let addrs = format!("{}:{}", upstream.ip, upstream.port)
+            .to_socket_addrs()
+            .map_err(|err| {
+                Error::Upstream(format!(
+                    "could not resolve upstream {}:{}: {err}",
+                    upstream.ip, upstream.port
+                ))
+            })?;
+        backends.extend(addrs.map(|addr| Backend {
+            addr: SocketAddr::Inet(addr),
+            weight,
+        }));
+    }
+
+    Ok(backends)
+}
+
+/// Resolves the upstream used when no upstream of a route is healthy
+fn compile_fallback_upstream(host: &str, upstream: &RouteUpstream) -> Option<FallbackUpstream> {
+    let backend = resolve_backends(std::slice::from_ref(upstream))
+        .ok()
+        .and_then(|backends| backends.into_iter().next());
+
+    let Some(backend) = backend else {
+        tracing::error!(
+            "could not resolve the fallback upstream {}:{} of host {host}",
+            upstream.ip,
+            upstream.port
+        );
+        return None;
+    };
+
+    Some(FallbackUpstream {
+        backend,
+        upstream: upstream.clone(),
+    })
+}
+
+/// Compiles the substitutions of the responses of a route
+fn compile_substitutions(host: &str, response: &RouteResponse) -> Option<Arc<Substitutions>> {
+    match Substitutions::new(response) {
+        Ok(substitutions) => substitutions.map(Arc::new),
+        Err(err) => {
+            tracing::error!("invalid substitutions for host {host}: {err}");
+            None
+        }
+    }
+}
+
+fn compile_blocked_paths(host: &str, config: &RouteBlockedPaths) -> Option<Arc<BlockedPaths>> {
+    match BlockedPaths::new(config) {
+        Ok(blocked_paths) => Some(Arc::new(blocked_paths)),
+        Err(err) => {
+            tracing::error!("invalid blocked paths for host {host}: {err}");
+            None
+        }
+    }
+}
+
+fn compile_user_agent(host: &str, config: &RouteUserAgent) -> Option<Arc<UserAgentFilter>> {
+    match UserAgentFilter::new(config) {
+        Ok(user_agent) => Some(Arc::new(user_agent)),
+        Err(err) => {
+            tracing::error!("invalid user agent patterns for host {host}: {err}");
+            None
+        }
+    }
+}
+
+fn compile_rate_limit(host: &str, config: &RouteRateLimit) -> Option<Arc<RouteRateLimiter>> {
+    match RouteRateLimiter::new(config) {
+        Ok(rate_limit) => Some(Arc::new(rate_limit)),
+        Err(err) => {
+            tracing::error!("invalid rate limit for host {host}: {err}");
+            None
+        }
+    }
+}
+
+/// Compiles the rules of the `rate_limit` plugin of a route, if any
+fn compile_rate_limit_rules(
+    host: &str,
+    plugins: Option<&[RoutePlugin]>,
+) -> Result<Option<Arc<RateLimitRules>>, Error> {
+    let Some(plugin) = plugins
+        .into_iter()
+        .flatten()
+        .find(|plugin| plugin.name == "rate_limit")
+    else {
+        return Ok(None);
+    };
+
+    RateLimitRules::new(plugin)
+        .map(|rules| Some(Arc::new(rules)))
+        .map_err(|err| Error::Config(format!("invalid rate_limit plugin for host {host}: {err}")))
+}
+
+/// Compiles the geo routing of a route: the pool serving each header value
+/// and the pool of each (resolved) upstream
+fn compile_geo_routing(geo: &RouteGeoRouting, upstreams: &[RouteUpstream]) -> Option<GeoRouting> {
+    let Ok(header) = HeaderName::from_str(&geo.header) else {
+        tracing::error!("invalid geo_routing header: {}", geo.header);
+        return None;
+    };
+
+    let pools = geo
+        .pools
+        .iter()
+        .flat_map(|(pool, values)| {
+            values
+                .iter()
+                .map(move |value| (value.trim().to_ascii_uppercase(), pool.to_string()))
+        })
+        .collect();
+
+    let upstreams = upstreams
+        .iter()
+        .filter_map(|upstream| {
+            let pool = upstream.pool.as_ref()?;
+            let addrs = format!("{}:{}", upstream.ip, upstream.port)
+                .to_socket_addrs()
+                .ok()?;
+            Some(addrs.map(move |addr| (addr, pool.to_string())))
+        })
+        .flatten()
+        .collect();
+
+    Some(GeoRouting {
+        header,
+        pools,
+        upstreams,
+    })
+}
+
+/// Groups the (resolved) upstreams by the path patterns of their `match_with`,
+/// `None` when no upstream of the route has any
+fn compile_path_groups(upstreams: &[RouteUpstream]) -> Option<PathGroups> {
+    let mut groups: Vec<(&[Cow<'static, str>], HashSet<std::net::SocketAddr>)> = Vec::new();
+    for upstream in upstreams {
+        let patterns = match upstream.match_with.as_ref().and_then(|m| m.path.as_ref()) {
+            Some(path) if !path.patterns.is_empty() => path.patterns.as_slice(),
+            _ => continue,
+        };
+        let Ok(addrs) = format!("{}:{}", upstream.ip, upstream.port).to_socket_addrs() else {
+            continue;
+        };
+
+        match groups.iter_mut().find(|(group, _)| *group == patterns) {
+            Some((_, group)) => group.extend(addrs),
+            None => groups.push((patterns, addrs.collect())),
+        }
+    }
+
+    if groups.is_empty() {
+        return None;
+    }
+
+    let groups = groups
+        .into_iter()
+        .map(|(patterns, upstreams)| {
+            let mut matcher = RouteStorePathMatcher::new();
+            matcher.with_pattern(patterns);
+            (matcher, upstreams)
+        })
+        .collect();
+    Some(PathGroups { groups })
+}
+
+/// The (resolved) upstreams in the zone of this instance, `None` when
+/// the route has none
+fn local_upstreams(
+    local_zone: &str,
+    upstreams: &[RouteUpstream],
+) -> Option<HashSet<std::net::SocketAddr>> {
+    let local = upstreams
+        .iter()
+        .filter(|upstream| upstream.zone.as_deref() == Some(local_zone))
+        .filter_map(|upstream| {
+            format!("{}:{}", upstream.ip, upstream.port)
+                .to_socket_addrs()
+                .ok()
+        })
+        .flatten()
+        .collect::<HashSet<_>>();
+
+    (!local.is_empty()).then_some(local)
+}
+
+/// Creates the load balancer of a route with its health check, its upstreams
+/// checked once already so traffic does not reach a dead upstream until the
+/// next run of the health check service
+async fn checked_load_balancer(
+    host: &str,
+    backends: BTreeSet<Backend>,
+    health_check: Option<&RouteHealthCheck>,
+    health_targets: &HealthTargets,
+) -> Result<(LoadBalancer<RoundRobin>, DynamicBackends), Error> {
+    let (mut load_balancer, dynamic_backends) =
+        create_load_balancer(backends).await.map_err(|err| {
+            Error::Upstream(format!(
+                "could not create the load balancer for host {host}: {err}"
+            ))
+        })?;
+
+    load_balancer.set_health_check(health_check::from_config(
+        host,
+        health_check,
+        health_targets.clone(),
+    ));
+    load_balancer.health_check_frequency = Some(health_check::frequency(health_check));
+    load_balancer.backends().run_health_check(false).await;
+
+    Ok((load_balancer, dynamic_backends))
+}
+
+/// Creates a load balancer for the given backends, returning the handle
+/// used to reconcile its backends later on.
+async fn create_load_balancer(
+    backends: BTreeSet<Backend>,
+) -> Result<(LoadBalancer<RoundRobin>, DynamicBackends), anyhow::Error> {
+    let dynamic_backends = DynamicBackends::new(backends);
+    let load_balancer = LoadBalancer::<RoundRobin>::from_backends(Backends::new(Box::new(
+        dynamic_backends.clone(),
+    )));
+    load_balancer.update().await?;
+
+    Ok((load_balancer, dynamic_backends))
 }
 
 // TODO: refactor this into its own module
@@ -255,48 +741,30 @@ fn add_route_ssl_to_store(route: &Route) -> Result<(), anyhow::Error> {
         return Ok(());
     };
 
-    let key_from_file = std::fs::read_to_string(ssl_path.key.as_os_str()).map_err(|err| {
-        anyhow::anyhow!(
-            "Failed to load private key from file {:?}: {err}",
-            ssl_path.key
-        )
-    })?;
-    let pem_from_file = std::fs::read_to_string(ssl_path.pem.as_os_str()).map_err(|err| {
-        anyhow::anyhow!(
-            "Failed to load certificate from file {:?}: {err}",
-            ssl_path.pem
-        )
-    })?;
+    // The file can be a bundle: the leaf followed by its intermediates
+    let certificate = Certificate::from_files(&ssl_path.pem, &ssl_path.key)?;
 
-    let key = PKey::private_key_from_pem(key_from_file.as_bytes()).map_err(|err| {
-        anyhow::anyhow!(
-            "Failed to load private key from file {:?}: {err}",
-            ssl_path.key
-        )
-    })?;
-    let pem = X509::from_pem(pem_from_file.as_bytes()).map_err(|err| {
-        anyhow::anyhow!(
-            "Failed to load certificate from file {:?}: {err}",
+    if let Some(issue) = certificate.chain_issue() {
+        tracing::warn!(
+            "certificate of host {} ({:?}) {issue}",
+            route.host,
             ssl_path.pem
-        )
-    })?;
-
-    stores::insert_certificate(
-        route.host.to_string(),
-        stores::certificates::Certificate {
-            key,
-            leaf: pem,
-            chain: None,
-        },
-    );
+        );
+    }
+
+    stores::insert_manual_certificate(route.host.to_string(), certificate);
 
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use std::borrow::Cow;
     use std::net::ToSocketAddrs;
 
+    use super::resolve_backends;
+    use crate::config::RouteUpstream;
+
     #[test]
     fn test_socket_addr() {
         let addr = "127.0.0.1:8080".to_string();
@@ -305,6 +773,54 @@ mod test {
         assert_eq!(addr.port(), 8080);
     }
 
+    #[test]
+    fn test_resolve_backends_with_weights() {
+        let upstreams = vec![
+            RouteUpstream {
+                ip: Cow::Borrowed("127.0.0.1"),
+                port: 3000,
+                weight: Some(3),
+                ..RouteUpstream::default()
+            },
+            RouteUpstream {
+                ip: Cow::Borrowed("127.0.0.2"),
+                port: 3000,
+                weight: None,
+                ..RouteUpstream::default()
+            },
+        ];
+
+        let backends = resolve_backends(&upstreams).unwrap();
+        let weights = backends
+            .iter()
+            .map(|b| (b.addr.to_string(), b.weight))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            weights,
+            vec![
+                ("127.0.0.1:3000".to_string(), 3),
+                ("127.0.0.2:3000".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_fallback_upstream() {
+        let upstream = RouteUpstream {
+            ip: Cow::Borrowed("127.0.0.1"),
+            port: 8080,
+            sni: Some("maintenance.example.com".to_string()),
+            ..RouteUpstream::default()
+        };
+
+        let fallback = compile_fallback_upstream("example.com", &upstream).unwrap();
+        assert_eq!(fallback.backend.addr.to_string(), "127.0.0.1:8080");
+        assert_eq!(
+            fallback.upstream.sni.as_deref(),
+            Some("maintenance.example.com")
+        );
+    }
+
     #[test]
     fn test_domain_addr() {
         let addr = "example.com:80";
@@ -0,0 +1,86 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora::{
+    lb::{discovery::ServiceDiscovery, selection::RoundRobin, Backend, LoadBalancer},
+    Result,
+};
+
+/// Service discovery whose backends can be replaced at runtime (ex: on reload).
+/// Clones share the same backends, so one handle can be given to the load balancer
+/// while another one is kept in the route store to reconcile the upstreams later.
+#[derive(Clone, Default)]
+pub struct DynamicBackends {
+    backends: Arc<ArcSwap<BTreeSet<Backend>>>,
+}
+
+impl DynamicBackends {
+    pub fn new(backends: BTreeSet<Backend>) -> Self {
+        Self {
+            backends: Arc::new(ArcSwap::from_pointee(backends)),
+        }
+    }
+
+    /// Replaces the backends of the load balancer with the given ones.
+    /// New backends are added, removed ones are dropped and the surviving ones
+    /// keep their health (and enablement) state as pingora carries it over
+    /// for backends that are still present. Connections to them are not affected.
+    pub async fn reconcile(
+        &self,
+        load_balancer: &LoadBalancer<RoundRobin>,
+        backends: BTreeSet<Backend>,
+    ) -> Result<()> {
+        self.backends.store(Arc::new(backends));
+        load_balancer.update().await
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DynamicBackends {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        Ok((BTreeSet::clone(&self.backends.load()), HashMap::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pingora::lb::Backends;
+
+    use super::*;
+
+    #[test]
+    fn test_reconcile_preserves_surviving_backends() {
+        let a = Backend::new("127.0.0.1:3000").unwrap();
+        let b = Backend::new("127.0.0.2:3000").unwrap();
+        let c = Backend::new("127.0.0.3:3000").unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let discovery = DynamicBackends::new(BTreeSet::from([a.clone(), b.clone()]));
+        let load_balancer =
+            LoadBalancer::<RoundRobin>::from_backends(Backends::new(Box::new(discovery.clone())));
+        runtime.block_on(load_balancer.update()).unwrap();
+
+        // State of a surviving backend
+        load_balancer.backends().set_enable(&b, false);
+
+        runtime
+            .block_on(discovery.reconcile(&load_balancer, BTreeSet::from([b.clone(), c.clone()])))
+            .unwrap();
+
+        let backends = load_balancer.backends();
+        assert_eq!(
+            *backends.get_backend(),
+            BTreeSet::from([b.clone(), c.clone()])
+        );
+        assert!(!backends.ready(&b));
+        assert!(backends.ready(&c));
+        assert_eq!(load_balancer.select(b"", 32), Some(c));
+    }
+}
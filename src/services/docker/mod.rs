@@ -1,28 +1,50 @@
 use std::{
-    borrow::Cow, collections::HashMap, hash::Hash, net::SocketAddr, str::FromStr, sync::Arc,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    net::SocketAddr,
+    pin::pin,
+    str::FromStr,
+    sync::Arc,
     time::Duration,
 };
 
-use anyhow::anyhow;
 use async_trait::async_trait;
 use bollard::{
-    container::ListContainersOptions, service::ListServicesOptions, Docker, API_DEFAULT_VERSION,
+    container::ListContainersOptions,
+    service::{EndpointSettings, ListServicesOptions},
+    system::EventsOptions,
+    Docker, API_DEFAULT_VERSION,
 };
+use futures_util::StreamExt;
 use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
 use serde_json::json;
 use tokio::sync::broadcast::Sender;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use crate::{
-    config::{Config, DockerServiceMode, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin},
+    config::{
+        Config, Docker as DockerConfig, DockerServiceMode, RouteHeaderAdd, RouteHeaderRemove,
+        RoutePlugin, RouteUpstream,
+    },
     MsgProxy, MsgRoute,
 };
 
-/// Based on the provided endpoint, returns the correct Docker client
-fn connect_to_docker(endpoint: &str) -> Result<Docker, bollard::errors::Error> {
+/// Based on the provided endpoint (and TLS files), returns the correct Docker client
+fn connect_to_docker(config: &DockerConfig) -> Result<Docker, bollard::errors::Error> {
+    let endpoint = config
+        .host
+        .as_deref()
+        .or(config.endpoint.as_deref())
+        .unwrap_or_default();
+
+    if let (Some(cert), Some(key), Some(ca)) = (&config.tls_cert, &config.tls_key, &config.tls_ca) {
+        return Docker::connect_with_ssl(endpoint, key, cert, ca, 120, API_DEFAULT_VERSION);
+    }
+
     if endpoint.starts_with("unix:///") {
         return Docker::connect_with_unix(endpoint, 120, API_DEFAULT_VERSION);
     }
@@ -33,9 +55,33 @@ fn connect_to_docker(endpoint: &str) -> Result<Docker, bollard::errors::Error> {
     Docker::connect_with_local_defaults()
 }
 
+/// Parses the `proksi.weight` label, defaulting to 1 when missing or invalid
+fn parse_weight(value: Option<&String>) -> i8 {
+    value
+        .and_then(|v| v.parse::<i8>().ok())
+        .filter(|w| *w > 0)
+        .unwrap_or(1)
+}
+
+/// Returns the IPs of a container, one per network it is attached to.
+/// When a network is given, only the IP on that network is returned,
+/// or `None` if the container is not attached to it.
+fn container_ips<'a>(
+    networks: &'a HashMap<String, EndpointSettings>,
+    network: Option<&str>,
+) -> Option<Vec<&'a str>> {
+    let ip_of =
+        |settings: &'a EndpointSettings| settings.ip_address.as_deref().filter(|ip| !ip.is_empty());
+
+    match network {
+        Some(name) => networks.get(name).and_then(ip_of).map(|ip| vec![ip]),
+        None => Some(networks.values().filter_map(ip_of).collect()),
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ProksiDockerRoute {
-    upstreams: Vec<String>,
+    upstreams: Vec<RouteUpstream>,
     path_matchers: Vec<String>,
 
     host_header_add: Option<Vec<RouteHeaderAdd>>,
@@ -45,7 +91,7 @@ pub struct ProksiDockerRoute {
 }
 
 impl ProksiDockerRoute {
-    pub fn new(upstreams: Vec<String>, path_matchers: Vec<String>) -> Self {
+    pub fn new(upstreams: Vec<RouteUpstream>, path_matchers: Vec<String>) -> Self {
         Self {
             upstreams,
             path_matchers,
@@ -62,22 +108,26 @@ impl ProksiDockerRoute {
 /// This service will run in a separate thread.
 pub struct LabelService {
     config: Arc<Config>,
-    inner: Docker,
+    /// The docker client (none if the connection could not be configured)
+    inner: Option<Docker>,
     sender: Sender<MsgProxy>,
+    /// The hosts of the routes discovered last
+    hosts: HashSet<String>,
 }
 
 impl LabelService {
     pub fn new(config: Arc<Config>, sender: Sender<MsgProxy>) -> Self {
-        let endpoint = config.docker.endpoint.clone().unwrap_or_default();
-
-        let docker = connect_to_docker(&endpoint);
+        let inner = connect_to_docker(&config.docker)
+            .map_err(|e| {
+                tracing::error!("could not connect to the docker daemon: {e}");
+            })
+            .ok();
 
         Self {
             config,
+            inner,
             sender,
-            inner: docker
-                .map_err(|e| anyhow!("could not connect to the docker daemon: {e}"))
-                .unwrap(),
+            hosts: HashSet::new(),
         }
     }
 
@@ -86,15 +136,14 @@ impl LabelService {
     /// Only works for docker in Swarm mode.
     #[allow(clippy::too_many_lines)]
     async fn list_services<T>(
-        &self,
+        docker: &Docker,
         filters: HashMap<T, Vec<T>>,
-    ) -> HashMap<String, ProksiDockerRoute>
+    ) -> Option<HashMap<String, ProksiDockerRoute>>
     where
         T: Into<String> + Hash + serde::ser::Serialize + Eq,
     {
         let mut host_map = HashMap::<String, ProksiDockerRoute>::new();
-        let services = self
-            .inner
+        let services = docker
             .list_services(Some(ListServicesOptions {
                 filters,
                 status: true,
@@ -103,7 +152,7 @@ impl LabelService {
 
         if services.is_err() {
             info!("Could not list services {:?}", services.err().unwrap());
-            return host_map;
+            return None;
         }
 
         let services = services.unwrap();
@@ -124,6 +173,7 @@ impl LabelService {
             let mut proxy_enabled = false;
             let mut proxy_host = "";
             let mut proxy_port = "";
+            let mut proxy_weight = None;
             let mut match_with_path_patterns = vec![];
             let mut route_header_add: Option<Vec<RouteHeaderAdd>> = None;
             let mut route_header_remove: Option<Vec<RouteHeaderRemove>> = None;
@@ -148,6 +198,7 @@ impl LabelService {
                         "proksi.enabled" => proxy_enabled = v == "true",
                         "proksi.host" => proxy_host = v,
                         "proksi.port" => proxy_port = v,
+                        "proksi.weight" => proxy_weight = Some(v),
                         k if k.starts_with("proksi.match_with.path.pattern.") => {
                             match_with_path_patterns.push(v.clone());
                         }
@@ -197,21 +248,24 @@ impl LabelService {
                 continue;
             }
 
-            if proxy_host.is_empty() || proxy_port.is_empty() {
+            let (false, Ok(proxy_port)) = (proxy_host.is_empty(), proxy_port.parse::<u16>()) else {
                 info!(
                     "Service {service_name:?} does not have the label
                     proksi.host set to a valid host or proksi.port set to a valid port"
                 );
                 continue;
-            }
+            };
 
             // TODO offer an option to load balance directly to the container IPs
             // of the service instead of through the docker dns
             if !host_map.contains_key(proxy_host) {
                 let mut routed = ProksiDockerRoute::default();
-                routed
-                    .upstreams
-                    .push(format!("tasks.{service_name}:{proxy_port}"));
+                routed.upstreams.push(RouteUpstream {
+                    ip: Cow::Owned(format!("tasks.{service_name}")),
+                    port: proxy_port,
+                    weight: Some(parse_weight(proxy_weight)),
+                    ..RouteUpstream::default()
+                });
                 routed.path_matchers = match_with_path_patterns;
                 routed.host_header_add = route_header_add;
                 routed.host_header_remove = route_header_remove;
@@ -253,22 +307,23 @@ impl LabelService {
             }
         }
 
-        host_map
+        Some(host_map)
     }
 
     /// Generate a list of containers based on the provided filters
     /// This will return a mapping between host <> ips for each container
     /// Does not work for docker in Swarm mode
+    #[allow(clippy::too_many_lines)]
     async fn list_containers<T>(
-        &self,
+        docker: &Docker,
         filters: HashMap<T, Vec<T>>,
-    ) -> HashMap<String, ProksiDockerRoute>
+        default_network: Option<&str>,
+    ) -> Option<HashMap<String, ProksiDockerRoute>>
     where
         T: Into<String> + Hash + serde::ser::Serialize + Eq,
     {
         let mut host_map = HashMap::<String, ProksiDockerRoute>::new();
-        let containers = self
-            .inner
+        let containers = docker
             .list_containers(Some(ListContainersOptions {
                 all: false,
                 limit: Some(1000),
@@ -279,7 +334,7 @@ impl LabelService {
 
         if containers.is_err() {
             info!("Could not list containers {:?}", containers.err().unwrap());
-            return host_map;
+            return None;
         }
 
         let containers = containers.unwrap();
@@ -293,6 +348,8 @@ impl LabelService {
             let mut proxy_enabled = false;
             let mut proxy_host = "";
             let mut proxy_port = "";
+            let mut proxy_weight = None;
+            let mut proxy_network = default_network;
             let mut match_with_path_patterns = vec![];
             let mut route_header_add: Option<Vec<RouteHeaderAdd>> = None;
             let mut route_header_remove: Option<Vec<RouteHeaderRemove>> = None;
@@ -306,6 +363,8 @@ impl LabelService {
                         "proksi.enabled" => proxy_enabled = v == "true",
                         "proksi.host" => proxy_host = v,
                         "proksi.port" => proxy_port = v,
+                        "proksi.weight" => proxy_weight = Some(v),
+                        "proksi.network" => proxy_network = Some(v),
                         "proksi.headers.add" => {
                             let deser: Vec<RouteHeaderAdd> =
                                 serde_json::from_str(v).unwrap_or(vec![]);
@@ -345,6 +404,19 @@ impl LabelService {
                 continue;
             }
 
+            // map container endpoints
+            let networks = container
+                .network_settings
+                .as_ref()
+                .and_then(|settings| settings.networks.as_ref());
+            let Some(ips) = networks.and_then(|networks| container_ips(networks, proxy_network))
+            else {
+                warn!(
+                    "Container {container_names:?} is not attached to the network {proxy_network:?}, skipping"
+                );
+                continue;
+            };
+
             // Create a new entry in the host_map if it does not exist
             if !host_map.contains_key(proxy_host) {
                 let mut routed = ProksiDockerRoute::new(vec![], match_with_path_patterns);
@@ -355,31 +427,32 @@ impl LabelService {
                 host_map.insert(proxy_host.to_string(), routed);
             }
 
-            // map container endpoints
-            let network_settings = &container.network_settings.as_ref().unwrap();
-            let networks = network_settings.networks.as_ref().unwrap();
-
-            for network in networks.values() {
-                let ip_on_network = network.ip_address.as_ref().unwrap();
+            for ip_on_network in ips {
                 let ip_plus_port = format!("{ip_on_network}:{proxy_port}");
 
                 let socket_addr = SocketAddr::from_str(&ip_plus_port);
 
                 // skip values from networks that Proksi does not have access to
-                if ip_on_network.is_empty() || socket_addr.is_err() {
+                if socket_addr.is_err() {
                     debug!("Could not parse the ip address {ip_plus_port} of the container {container_names:?}");
                     continue;
                 }
 
+                let socket_addr = socket_addr.unwrap();
                 host_map
                     .get_mut(proxy_host)
                     .unwrap()
                     .upstreams
-                    .push(ip_plus_port);
+                    .push(RouteUpstream {
+                        ip: Cow::Owned(socket_addr.ip().to_string()),
+                        port: socket_addr.port(),
+                        weight: Some(parse_weight(proxy_weight)),
+                        ..RouteUpstream::default()
+                    });
             }
         }
 
-        host_map
+        Some(host_map)
     }
 
     // Parses the oauth2 configuration and returns a RoutePlugin
@@ -417,8 +490,21 @@ impl LabelService {
         })
     }
 
-    /// Sends a message to the route discovery service through mspc
-    fn send_route_message(&self, hosts: HashMap<String, ProksiDockerRoute>) {
+    /// Sends a message to the route discovery service through mspc, removing
+    /// the routes of the hosts no container (or service) serves anymore
+    fn send_route_message(&mut self, hosts: HashMap<String, ProksiDockerRoute>) {
+        let discovered = hosts
+            .iter()
+            .filter(|(_, value)| !value.upstreams.is_empty())
+            .map(|(host, _)| host.clone())
+            .collect::<HashSet<_>>();
+
+        for host in vanished_hosts(&self.hosts, &discovered, &self.config) {
+            info!("no container serves the host {host} anymore, removing its route");
+            self.sender.send(MsgProxy::RemovedRoute(host)).ok();
+        }
+        self.hosts = discovered;
+
         for (host, value) in hosts {
             // If no upstreams can be found, skip adding the route
             if value.upstreams.is_empty() {
@@ -445,7 +531,11 @@ impl LabelService {
 
     // By default every container or service should have these 3 labels
     // So that Proksi can route the appropriate traffic
-    async fn get_routes_from_docker(&self) -> HashMap<String, ProksiDockerRoute> {
+    // The routes are `None` when the docker daemon could not list them
+    async fn get_routes_from_docker(
+        &self,
+        docker: &Docker,
+    ) -> Option<HashMap<String, ProksiDockerRoute>> {
         let mut filters = HashMap::new();
         filters.insert(
             "label",
@@ -453,19 +543,21 @@ impl LabelService {
         );
 
         match self.config.docker.mode {
-            DockerServiceMode::Swarm => self.list_services(filters).await,
-            DockerServiceMode::Container => self.list_containers(filters).await,
+            DockerServiceMode::Swarm => Self::list_services(docker, filters).await,
+            DockerServiceMode::Container => {
+                Self::list_containers(docker, filters, self.config.docker.network.as_deref()).await
+            }
         }
     }
 }
 
 #[async_trait]
 impl Service for LabelService {
-    async fn start_service(&mut self, _fds: Option<ListenFds>, mut _shutdown: ShutdownWatch) {
-        if self.config.docker.enabled.is_some_and(|v| !v) {
-            // Nothing to do, docker is disabled
+    async fn start_service(&mut self, _fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        let Some(docker) = self.inner.clone() else {
+            // Nothing to do, the client could not be created
             return;
-        }
+        };
 
         info!(service = "docker", "Started Docker service");
 
@@ -473,10 +565,39 @@ impl Service for LabelService {
             self.config.docker.interval_secs.unwrap_or(15),
         ));
 
+        // Containers (or services) starting and stopping refresh the routes
+        // right away, instead of on the next interval
+        let mut events = pin!(docker.events(Some(EventsOptions {
+            filters: event_filters(&self.config.docker.mode),
+            ..EventsOptions::default()
+        })));
+        let mut streaming = true;
+
         interval.tick().await;
         loop {
-            interval.tick().await;
-            self.send_route_message(self.get_routes_from_docker().await);
+            tokio::select! {
+                _ = interval.tick() => {}
+                event = events.next(), if streaming => match event {
+                    Some(Ok(event)) => debug!("docker event {:?}, refreshing the routes", event.action),
+                    Some(Err(err)) => {
+                        warn!("could not listen to the docker events, polling only: {err}");
+                        streaming = false;
+                    }
+                    None => streaming = false,
+                },
+                _ = shutdown.changed() => break,
+            }
+
+            // A slow docker daemon does not hold the shutdown back
+            let routes = tokio::select! {
+                routes = self.get_routes_from_docker(&docker) => routes,
+                _ = shutdown.changed() => break,
+            };
+
+            // The routes are kept when docker could not list them
+            if let Some(routes) = routes {
+                self.send_route_message(routes);
+            }
         }
     }
 
@@ -488,3 +609,91 @@ impl Service for LabelService {
         Some(1)
     }
 }
+
+/// The events of the containers (or services) that change the routes
+fn event_filters(mode: &DockerServiceMode) -> HashMap<&'static str, Vec<&'static str>> {
+    match mode {
+        DockerServiceMode::Swarm => HashMap::from([
+            ("type", vec!["service"]),
+            ("event", vec!["create", "update", "remove"]),
+        ]),
+        DockerServiceMode::Container => HashMap::from([
+            ("type", vec!["container"]),
+            ("event", vec!["start", "die"]),
+            ("label", vec!["proksi.enabled=true"]),
+        ]),
+    }
+}
+
+/// The hosts discovered before that are not anymore. Hosts also routed by the
+/// configuration keep their route.
+fn vanished_hosts(
+    previous: &HashSet<String>,
+    discovered: &HashSet<String>,
+    config: &Config,
+) -> Vec<String> {
+    previous
+        .iter()
+        .filter(|host| !discovered.contains(*host))
+        .filter(|host| {
+            !config
+                .routes
+                .iter()
+                .any(|route| route.host.as_ref() == host.as_str())
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(ip: &str) -> EndpointSettings {
+        EndpointSettings {
+            ip_address: Some(ip.to_string()),
+            ..EndpointSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_container_ips_by_network() {
+        let networks = HashMap::from([
+            ("frontend".to_string(), endpoint("10.0.1.2")),
+            ("backend".to_string(), endpoint("10.0.2.2")),
+            ("none".to_string(), endpoint("")),
+        ]);
+
+        let mut all = container_ips(&networks, None).unwrap();
+        all.sort_unstable();
+        assert_eq!(all, vec!["10.0.1.2", "10.0.2.2"]);
+
+        assert_eq!(
+            container_ips(&networks, Some("backend")),
+            Some(vec!["10.0.2.2"])
+        );
+        assert_eq!(container_ips(&networks, Some("none")), None);
+        assert_eq!(container_ips(&networks, Some("missing")), None);
+    }
+
+    #[test]
+    fn test_vanished_hosts_keep_the_configured_routes() {
+        let config = Config {
+            routes: vec![serde_json::from_value(json!({
+                "host": "configured.test",
+                "upstreams": [{ "ip": "10.0.0.1", "port": 3000 }],
+            }))
+            .unwrap()],
+            ..Config::default()
+        };
+        let hosts = |hosts: &[&str]| hosts.iter().map(ToString::to_string).collect();
+
+        let mut vanished = vanished_hosts(
+            &hosts(&["kept.test", "stopped.test", "configured.test"]),
+            &hosts(&["kept.test", "started.test"]),
+            &config,
+        );
+        vanished.sort();
+        assert_eq!(vanished, ["stopped.test"]);
+    }
+}
@@ -0,0 +1,221 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use pingora::{
+    connectors::http::Connector,
+    http::RequestHeader,
+    lb::{health_check::HealthCheck, Backend},
+    protocols::{http::client::HttpSession, ALPN},
+    upstreams::peer::HttpPeer,
+    Custom, CustomCode, Error, Result,
+};
+
+/// The RPC defined by the standard gRPC health checking protocol
+const GRPC_HEALTH_CHECK_PATH: &[u8] = b"/grpc.health.v1.Health/Check";
+
+/// `HealthCheckResponse.ServingStatus.SERVING`
+const SERVING_STATUS: u64 = 1;
+
+/// Health check that issues the `grpc.health.v1.Health/Check` RPC over an h2 connection
+/// and only considers the backend healthy when it answers with `SERVING`.
+#[allow(clippy::module_name_repetitions)]
+pub struct GrpcHealthCheck {
+    pub consecutive_success: usize,
+    pub consecutive_failure: usize,
+    /// The service name sent in the `HealthCheckRequest` (empty means the whole server)
+    pub service: String,
+    peer_template: HttpPeer,
+    connector: Connector,
+}
+
+impl GrpcHealthCheck {
+    pub fn new(service: &str, timeout: Duration) -> Self {
+        let mut peer_template = HttpPeer::new("0.0.0.0:1", false, String::new());
+        peer_template.options.alpn = ALPN::H2;
+        peer_template.options.connection_timeout = Some(timeout);
+        peer_template.options.read_timeout = Some(timeout);
+
+        GrpcHealthCheck {
+            consecutive_success: 1,
+            consecutive_failure: 1,
+            service: service.to_string(),
+            peer_template,
+            connector: Connector::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for GrpcHealthCheck {
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.consecutive_success
+        } else {
+            self.consecutive_failure
+        }
+    }
+
+    async fn check(&self, target: &Backend) -> Result<()> {
+        let mut peer = self.peer_template.clone();
+        peer._address = target.addr.clone();
+
+        let (session, _) = self.connector.get_http_session(&peer).await?;
+        let HttpSession::H2(mut session) = session else {
+            return Error::e_explain(
+                Custom("no h2 session"),
+                "during grpc healthcheck, upstream does not speak h2",
+            );
+        };
+        session.read_timeout = peer.options.read_timeout;
+
+        let mut req = RequestHeader::build("POST", GRPC_HEALTH_CHECK_PATH, None)?;
+        req.insert_header("Host", target.addr.to_string())?;
+        req.insert_header("Content-Type", "application/grpc")?;
+        req.insert_header("TE", "trailers")?;
+
+        session.write_request_header(Box::new(req), false)?;
+        session.write_request_body(encode_request(&self.service), true)?;
+        session.read_response_header().await?;
+
+        let resp = session.response_header().expect("just read");
+        if resp.status != 200 {
+            return Error::e_explain(
+                CustomCode("non 200 code", resp.status.as_u16()),
+                "during grpc healthcheck",
+            );
+        }
+
+        // Trailers-only responses carry the grpc-status in the headers
+        let mut grpc_status = resp
+            .headers
+            .get("grpc-status")
+            .map(|v| v.as_bytes().to_vec());
+
+        let mut body = Vec::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            body.extend_from_slice(&chunk);
+        }
+
+        if let Some(trailers) = session.read_trailers().await? {
+            if let Some(status) = trailers.get("grpc-status") {
+                grpc_status = Some(status.as_bytes().to_vec());
+            }
+        }
+
+        if grpc_status.as_deref() != Some(b"0") {
+            return Error::e_explain(Custom("grpc status not OK"), "during grpc healthcheck");
+        }
+
+        match decode_serving_status(&body) {
+            Some(SERVING_STATUS) => Ok(()),
+            _ => Error::e_explain(
+                Custom("grpc service not SERVING"),
+                "during grpc healthcheck",
+            ),
+        }
+    }
+}
+
+/// Encodes a `HealthCheckRequest { service }` message inside a gRPC length-prefixed frame
+fn encode_request(service: &str) -> Bytes {
+    let mut message = Vec::with_capacity(service.len() + 6);
+    if !service.is_empty() {
+        // field 1, wire type 2 (length-delimited)
+        message.push(0x0a);
+        encode_varint(service.len() as u64, &mut message);
+        message.extend_from_slice(service.as_bytes());
+    }
+
+    let mut frame = Vec::with_capacity(message.len() + 5);
+    // uncompressed
+    frame.push(0);
+    #[allow(clippy::cast_possible_truncation)]
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&message);
+    Bytes::from(frame)
+}
+
+/// Reads the `status` field out of a framed `HealthCheckResponse`.
+/// A response without the field is `UNKNOWN` (0) as per proto3 defaults.
+fn decode_serving_status(frame: &[u8]) -> Option<u64> {
+    let (header, message) = (frame.get(..5)?, frame.get(5..)?);
+    if header[0] != 0 {
+        // compressed responses are not negotiated by the probe
+        return None;
+    }
+
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let mut message = message.get(..len)?;
+    let mut status = 0;
+
+    while !message.is_empty() {
+        let key = decode_varint(&mut message)?;
+        match key & 0x07 {
+            0 => {
+                let value = decode_varint(&mut message)?;
+                if key >> 3 == 1 {
+                    status = value;
+                }
+            }
+            2 => {
+                let len = usize::try_from(decode_varint(&mut message)?).ok()?;
+                message = message.get(len..)?;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(status)
+}
+
+fn encode_varint(mut value: u64, buf: &mut Vec<u8>) {
+    while value >= 0x80 {
+        #[allow(clippy::cast_possible_truncation)]
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    buf.push(value as u8);
+}
+
+fn decode_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request_without_service() {
+        let frame = encode_request("");
+        assert_eq!(frame.as_ref(), &[0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_encode_request_with_service() {
+        let frame = encode_request("api");
+        assert_eq!(frame.as_ref(), &[0, 0, 0, 0, 5, 0x0a, 3, b'a', b'p', b'i']);
+    }
+
+    #[test]
+    fn test_decode_serving_status() {
+        assert_eq!(decode_serving_status(&[0, 0, 0, 0, 2, 0x08, 1]), Some(1));
+        assert_eq!(decode_serving_status(&[0, 0, 0, 0, 2, 0x08, 2]), Some(2));
+        // Empty message is the proto3 default: UNKNOWN
+        assert_eq!(decode_serving_status(&[0, 0, 0, 0, 0]), Some(0));
+        // Truncated frame
+        assert_eq!(decode_serving_status(&[0, 0, 0, 0, 2, 0x08]), None);
+        assert_eq!(decode_serving_status(&[0, 0]), None);
+    }
+}
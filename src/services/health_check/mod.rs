@@ -1,12 +1,288 @@
-use std::time::Duration;
+use std::{
+    net::ToSocketAddrs,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use async_trait::async_trait;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
 use pingora::{
+    http::ResponseHeader,
+    lb::{
+        health_check::{HealthCheck, HttpHealthCheck, TcpHealthCheck},
+        Backend,
+    },
+    protocols::l4::socket::SocketAddr,
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
 
-use crate::stores::{self};
+use crate::{
+    config::{RouteHealthCheck, RouteHealthCheckType, RouteUpstream},
+    metrics,
+    services::tick_or_shutdown,
+    stores::{self},
+};
+
+mod grpc;
+
+/// When the upstreams of each route were last checked
+static LAST_CHECKS: Lazy<DashMap<String, SystemTime>> = Lazy::new(DashMap::new);
+
+/// When the upstreams of the route were last checked (all at once), if ever
+pub fn last_checked(host: &str) -> Option<SystemTime> {
+    LAST_CHECKS.get(host).map(|checked| *checked)
+}
+
+/// How often the upstreams of a route are checked (every 15 seconds when no
+/// health check is configured)
+pub fn frequency(config: Option<&RouteHealthCheck>) -> Duration {
+    let default_config = RouteHealthCheck::default();
+    Duration::from_secs(config.unwrap_or(&default_config).interval_secs)
+}
+
+/// Builds the health check configured for a route (TCP when none is configured),
+/// probing the `targets` of the upstreams that have one. `route` labels the
+/// metrics of its checks, and is the `Host` of its HTTP checks.
+pub fn from_config(
+    route: &str,
+    config: Option<&RouteHealthCheck>,
+    targets: HealthTargets,
+) -> Box<dyn HealthCheck + Send + Sync> {
+    let default_config = RouteHealthCheck::default();
+    let config = config.unwrap_or(&default_config);
+    let timeout = Duration::from_secs(config.timeout_secs);
+
+    let inner: Box<dyn HealthCheck + Send + Sync> = match config.check_type {
+        RouteHealthCheckType::Tcp => {
+            let mut health_check = TcpHealthCheck::new();
+            health_check.peer_template.options.connection_timeout = Some(timeout);
+            health_check.consecutive_failure = config.consecutive_failures;
+            health_check.consecutive_success = config.consecutive_successes;
+            health_check
+        }
+        RouteHealthCheckType::Http => Box::new(http_health_check(route, config, timeout)),
+        RouteHealthCheckType::Grpc => {
+            let mut health_check = grpc::GrpcHealthCheck::new(
+                config.grpc_service.as_deref().unwrap_or_default(),
+                timeout,
+            );
+            health_check.consecutive_failure = config.consecutive_failures;
+            health_check.consecutive_success = config.consecutive_successes;
+            Box::new(health_check)
+        }
+    };
+
+    Box::new(TargetedHealthCheck {
+        inner,
+        targets,
+        route: route.to_string(),
+    })
+}
+
+/// A `GET` of the `path` of the upstream, healthy when it answers with the
+/// `expected_status`. Certificates are not verified, as for the proxied requests.
+fn http_health_check(route: &str, config: &RouteHealthCheck, timeout: Duration) -> HttpHealthCheck {
+    let mut health_check = HttpHealthCheck::new(route, config.tls);
+    health_check.peer_template.options.connection_timeout = Some(timeout);
+    health_check.peer_template.options.read_timeout = Some(timeout);
+    health_check.peer_template.options.verify_cert = false;
+    health_check.peer_template.options.verify_hostname = false;
+    health_check.consecutive_failure = config.consecutive_failures;
+    health_check.consecutive_success = config.consecutive_successes;
+    if let Ok(uri) = config.path.parse() {
+        health_check.req.set_uri(uri);
+    }
+
+    let expected = config.expected_status;
+    health_check.validator = Some(Box::new(move |response: &ResponseHeader| {
+        if response.status.as_u16() == expected {
+            return Ok(());
+        }
+        Err(pingora::Error::explain(
+            pingora::ErrorType::CustomCode(
+                "unexpected health check status",
+                response.status.as_u16(),
+            ),
+            format!("expected {expected}"),
+        ))
+    }));
+    health_check
+}
+
+/// The results of the health checks of an upstream, to spot the ones that
+/// fail now and then while they are healthy most of the time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckHistory {
+    pub successes: u64,
+    pub failures: u64,
+    /// The health of the upstream after the last run of the checks, `None`
+    /// until checked (the load balancer deems new upstreams healthy)
+    pub healthy: Option<bool>,
+    /// When the upstream last turned healthy or unhealthy, if ever
+    pub last_transition: Option<SystemTime>,
+}
+
+/// Address probed instead of each upstream whose `health_check` targets another
+/// address or port than the traffic does, and the history of the checks of
+/// each upstream. Clones share the same targets and history.
+#[derive(Clone, Default)]
+pub struct HealthTargets {
+    targets: Arc<DashMap<SocketAddr, SocketAddr>>,
+    history: Arc<DashMap<SocketAddr, CheckHistory>>,
+}
+
+impl HealthTargets {
+    /// Resolves the targets of the upstreams, replacing the previous ones
+    pub fn update(&self, upstreams: &[RouteUpstream]) {
+        let mut targets = Vec::new();
+        for upstream in upstreams {
+            let Some(health_check) = &upstream.health_check else {
+                continue;
+            };
+
+            let address = health_check.address.as_deref().unwrap_or(&upstream.ip);
+            let port = health_check.port.unwrap_or(upstream.port);
+            let Some(target) = resolve(address, port).into_iter().next() else {
+                tracing::error!(
+                    "could not resolve the health check target {address}:{port} of upstream {}:{}",
+                    upstream.ip,
+                    upstream.port
+                );
+                continue;
+            };
+
+            for addr in resolve(&upstream.ip, upstream.port) {
+                targets.push((addr, target.clone()));
+            }
+        }
+
+        self.targets
+            .retain(|addr, _| targets.iter().any(|(upstream, _)| upstream == addr));
+        for (addr, target) in targets {
+            self.targets.insert(addr, target);
+        }
+    }
+
+    /// The address probed for the given upstream, if not the upstream itself
+    pub fn get(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        self.targets.get(addr).map(|target| target.value().clone())
+    }
+
+    /// Counts the result of a check of the upstream
+    fn record(&self, addr: &SocketAddr, success: bool) {
+        let mut history = self.history.entry(addr.clone()).or_default();
+        if success {
+            history.successes += 1;
+        } else {
+            history.failures += 1;
+        }
+    }
+
+    /// Records the health of the upstream after a run of the checks, `true`
+    /// when it changed
+    pub fn observe(&self, addr: &SocketAddr, healthy: bool, now: SystemTime) -> bool {
+        let mut history = self.history.entry(addr.clone()).or_default();
+        let changed = history.healthy.unwrap_or(true) != healthy;
+        if changed {
+            history.last_transition = Some(now);
+        }
+        history.healthy = Some(healthy);
+        changed
+    }
+
+    /// The history of the checks of the upstream, empty until it is checked
+    pub fn history(&self, addr: &SocketAddr) -> CheckHistory {
+        self.history
+            .get(addr)
+            .map(|history| *history)
+            .unwrap_or_default()
+    }
+
+    /// Forgets the history of the upstreams that are gone
+    fn retain_history(&self, addrs: &[&SocketAddr]) {
+        self.history.retain(|addr, _| addrs.contains(&addr));
+    }
+}
+
+fn resolve(address: &str, port: u16) -> Vec<SocketAddr> {
+    format!("{address}:{port}")
+        .to_socket_addrs()
+        .map(|addrs| addrs.map(SocketAddr::Inet).collect())
+        .unwrap_or_default()
+}
+
+/// Runs a health check against the target of the upstream, the result being
+/// the health of the upstream itself
+struct TargetedHealthCheck {
+    inner: Box<dyn HealthCheck + Send + Sync>,
+    targets: HealthTargets,
+    route: String,
+}
+
+#[async_trait]
+impl HealthCheck for TargetedHealthCheck {
+    fn health_threshold(&self, success: bool) -> usize {
+        self.inner.health_threshold(success)
+    }
+
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        let result = match self.targets.get(&target.addr) {
+            Some(addr) => {
+                let target = Backend {
+                    addr,
+                    weight: target.weight,
+                };
+                self.inner.check(&target).await
+            }
+            None => self.inner.check(target).await,
+        };
+
+        self.targets.record(&target.addr, result.is_ok());
+        metrics::HEALTH_CHECKS
+            .with_label_values(&[
+                &self.route,
+                &target.addr.to_string(),
+                if result.is_ok() { "success" } else { "failure" },
+            ])
+            .inc();
+
+        result
+    }
+}
+
+/// Records the health of the upstreams of the route after a run of the checks
+fn observe_transitions(host: &str, route: &stores::routes::RouteStoreContainer) {
+    let now = SystemTime::now();
+    let backends = route.load_balancer.backends();
+    let upstreams = backends.get_backend();
+
+    for backend in upstreams.iter() {
+        if !route
+            .health_targets
+            .observe(&backend.addr, backends.ready(backend), now)
+        {
+            continue;
+        }
+
+        let labels = [host, &backend.addr.to_string()];
+        metrics::HEALTH_CHECK_TRANSITIONS
+            .with_label_values(&labels)
+            .inc();
+        if let Ok(since) = now.duration_since(UNIX_EPOCH) {
+            metrics::HEALTH_CHECK_LAST_TRANSITION
+                .with_label_values(&labels)
+                .set(i64::try_from(since.as_secs()).unwrap_or(i64::MAX));
+        }
+    }
+
+    let addrs = upstreams
+        .iter()
+        .map(|backend| &backend.addr)
+        .collect::<Vec<_>>();
+    route.health_targets.retain_history(&addrs);
+}
 
 /// Health check service that will run health checks on all upstreams
 /// And update the route store with the new healthy upstreams.
@@ -19,27 +295,47 @@ impl HealthService {
     }
 }
 
-async fn run_health_check_loop() {
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
-    interval.tick().await;
+/// Whether the upstreams of a route last checked at `last_check` are due for
+/// another check
+fn is_due(last_check: Option<SystemTime>, frequency: Option<Duration>, now: SystemTime) -> bool {
+    let frequency = frequency.unwrap_or_else(|| self::frequency(None));
+    last_check.map_or(true, |last_check| {
+        now.duration_since(last_check)
+            .is_ok_and(|elapsed| elapsed >= frequency)
+    })
+}
 
-    loop {
-        interval.tick().await;
+async fn run_health_check_loop(mut shutdown: ShutdownWatch) {
+    // Each route is checked at its own frequency (`health_check.interval_secs`)
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    interval.tick().await;
 
+    while tick_or_shutdown(&mut interval, &mut shutdown).await {
         for data in stores::get_mutable_routes() {
+            let frequency = data.load_balancer.health_check_frequency;
+            if !is_due(last_checked(data.key()), frequency, SystemTime::now()) {
+                continue;
+            }
+
             tracing::trace!("Running health check for host {}", data.key());
             data.load_balancer.update().await.ok();
             data.load_balancer.backends().run_health_check(false).await;
+            observe_transitions(data.key(), data.value());
+            LAST_CHECKS.insert(data.key().clone(), SystemTime::now());
+            if let Some(warmup) = &data.warmup {
+                warmup.record(data.load_balancer.backends(), Instant::now());
+            }
         }
+        LAST_CHECKS.retain(|host, _| stores::get_route_by_key(host).is_some());
     }
 }
 
 #[async_trait]
 impl Service for HealthService {
-    async fn start_service(&mut self, _fds: Option<ListenFds>, _shutdown: ShutdownWatch) {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, shutdown: ShutdownWatch) {
         tracing::info!("Starting health check service");
 
-        run_health_check_loop().await;
+        run_health_check_loop(shutdown).await;
     }
 
     fn name(&self) -> &str {
@@ -50,3 +346,154 @@ impl Service for HealthService {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, sync::Mutex};
+
+    use crate::config::UpstreamHealthCheck;
+
+    use super::*;
+
+    /// Records the addresses it probes
+    #[derive(Clone, Default)]
+    struct Probes(Arc<Mutex<Vec<SocketAddr>>>);
+
+    #[async_trait]
+    impl HealthCheck for Probes {
+        fn health_threshold(&self, _success: bool) -> usize {
+            1
+        }
+
+        async fn check(&self, target: &Backend) -> pingora::Result<()> {
+            self.0.lock().unwrap().push(target.addr.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_routes_checked_at_their_frequency() {
+        let now = SystemTime::now();
+        let frequency = Some(Duration::from_secs(5));
+
+        assert!(is_due(None, frequency, now));
+        assert!(!is_due(Some(now - Duration::from_secs(4)), frequency, now));
+        assert!(is_due(Some(now - Duration::from_secs(5)), frequency, now));
+        // Every 15 seconds without a frequency
+        assert!(!is_due(Some(now - Duration::from_secs(10)), None, now));
+        assert!(is_due(Some(now - Duration::from_secs(15)), None, now));
+    }
+
+    #[test]
+    fn test_http_health_check() {
+        let config = RouteHealthCheck {
+            check_type: RouteHealthCheckType::Http,
+            path: "/healthz?full=1".to_string(),
+            expected_status: 204,
+            consecutive_failures: 3,
+            ..RouteHealthCheck::default()
+        };
+        let health_check = http_health_check("example.com", &config, Duration::from_secs(1));
+
+        assert_eq!(health_check.req.uri, "/healthz?full=1");
+        assert_eq!(health_check.req.headers["host"], "example.com");
+        assert_eq!(health_check.health_threshold(false), 3);
+        assert_eq!(health_check.health_threshold(true), 1);
+
+        let validator = health_check.validator.as_ref().unwrap();
+        let response = |status| ResponseHeader::build(status, None).unwrap();
+        assert!(validator(&response(204)).is_ok());
+        assert!(validator(&response(200)).is_err());
+        assert!(validator(&response(500)).is_err());
+    }
+
+    #[test]
+    fn test_health_check_probes_the_target() {
+        let upstream = |port: u16, health_check: Option<UpstreamHealthCheck>| RouteUpstream {
+            ip: Cow::Borrowed("127.0.0.1"),
+            port,
+            health_check,
+            ..RouteUpstream::default()
+        };
+        let addr = |addr: &str| SocketAddr::Inet(addr.parse().unwrap());
+
+        let targets = HealthTargets::default();
+        targets.update(&[
+            upstream(3000, None),
+            upstream(
+                3001,
+                Some(UpstreamHealthCheck {
+                    address: None,
+                    port: Some(9000),
+                }),
+            ),
+        ]);
+        assert_eq!(targets.get(&addr("127.0.0.1:3000")), None);
+        assert_eq!(
+            targets.get(&addr("127.0.0.1:3001")),
+            Some(addr("127.0.0.1:9000"))
+        );
+
+        // The traffic port is probed once the upstream has no target anymore
+        targets.clone().update(&[upstream(3001, None)]);
+        assert_eq!(targets.get(&addr("127.0.0.1:3001")), None);
+
+        targets.update(&[upstream(
+            3001,
+            Some(UpstreamHealthCheck {
+                address: Some(Cow::Borrowed("127.0.0.2")),
+                port: None,
+            }),
+        )]);
+        let probes = Probes::default();
+        let health_check = TargetedHealthCheck {
+            inner: Box::new(probes.clone()),
+            targets: targets.clone(),
+            route: "probes.test".to_string(),
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        for backend in ["127.0.0.1:3000", "127.0.0.1:3001"] {
+            let backend = Backend::new(backend).unwrap();
+            runtime.block_on(health_check.check(&backend)).unwrap();
+        }
+        assert_eq!(
+            *probes.0.lock().unwrap(),
+            vec![addr("127.0.0.1:3000"), addr("127.0.0.2:3001")]
+        );
+        assert_eq!(targets.history(&addr("127.0.0.1:3001")).successes, 1);
+    }
+
+    #[test]
+    fn test_check_history() {
+        let targets = HealthTargets::default();
+        let upstream = SocketAddr::Inet("127.0.0.1:3000".parse().unwrap());
+        let other = SocketAddr::Inet("127.0.0.1:3001".parse().unwrap());
+        let start = SystemTime::now();
+
+        targets.record(&upstream, true);
+        targets.record(&upstream, false);
+        // Healthy from the start, which is no transition
+        assert!(!targets.observe(&upstream, true, start));
+
+        let failed = start + Duration::from_secs(30);
+        targets.record(&upstream, false);
+        assert!(targets.observe(&upstream, false, failed));
+        assert!(!targets.observe(&upstream, false, failed + Duration::from_secs(30)));
+        assert_eq!(
+            targets.history(&upstream),
+            CheckHistory {
+                successes: 1,
+                failures: 2,
+                healthy: Some(false),
+                last_transition: Some(failed),
+            }
+        );
+
+        targets.record(&other, true);
+        targets.retain_history(&[&upstream]);
+        assert_eq!(targets.history(&other), CheckHistory::default());
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use serde_json::{json, Value};
+
+use crate::config::Dns01Cloudflare;
+
+use super::DnsProvider;
+
+const API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// How long a call to the API is given
+const API_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Writes the records through the Cloudflare API
+pub struct Cloudflare {
+    agent: ureq::Agent,
+    authorization: String,
+    zone_id: Option<String>,
+}
+
+impl Cloudflare {
+    pub fn new(config: &Dns01Cloudflare) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(API_TIMEOUT).build(),
+            authorization: format!("Bearer {}", config.api_token),
+            zone_id: config.zone_id.as_deref().map(str::to_string),
+        }
+    }
+
+    /// Calls the API, returning the `result` of its answer
+    fn send(&self, request: ureq::Request, body: Option<&Value>) -> Result<Value, anyhow::Error> {
+        let request = request.set("Authorization", &self.authorization);
+        let response = match body {
+            Some(body) => request
+                .set("Content-Type", "application/json")
+                .send_string(&body.to_string()),
+            None => request.call(),
+        };
+
+        // The errors are detailed in the answer
+        let response = match response {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut answer: Value = serde_json::from_str(&response.into_string()?)?;
+        if answer["success"] != true {
+            bail!("the Cloudflare API failed: {}", answer["errors"]);
+        }
+        Ok(answer["result"].take())
+    }
+
+    /// The configured zone, or else the closest parent domain of the record
+    /// that is a zone of the account
+    fn zone_of(&self, name: &str) -> Result<String, anyhow::Error> {
+        if let Some(zone_id) = &self.zone_id {
+            return Ok(zone_id.clone());
+        }
+
+        let mut domain = name;
+        while let Some((_, parent)) = domain.split_once('.') {
+            let zones = self.send(
+                self.agent
+                    .get(&format!("{API_URL}/zones"))
+                    .query("name", parent),
+                None,
+            )?;
+            if let Some(id) = zones[0]["id"].as_str() {
+                return Ok(id.to_string());
+            }
+            domain = parent;
+        }
+
+        Err(anyhow!("no Cloudflare zone of the account holds {name}"))
+    }
+}
+
+impl DnsProvider for Cloudflare {
+    fn add_txt_record(&self, name: &str, value: &str) -> Result<(), anyhow::Error> {
+        let zone_id = self.zone_of(name)?;
+        self.send(
+            self.agent
+                .post(&format!("{API_URL}/zones/{zone_id}/dns_records")),
+            Some(&json!({ "type": "TXT", "name": name, "content": value, "ttl": 60 })),
+        )?;
+        Ok(())
+    }
+
+    fn remove_txt_record(&self, name: &str, value: &str) -> Result<(), anyhow::Error> {
+        let zone_id = self.zone_of(name)?;
+        let records = self.send(
+            self.agent
+                .get(&format!("{API_URL}/zones/{zone_id}/dns_records"))
+                .query("type", "TXT")
+                .query("name", name)
+                .query("content", value),
+            None,
+        )?;
+
+        for id in records
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|record| record["id"].as_str())
+        {
+            self.send(
+                self.agent
+                    .delete(&format!("{API_URL}/zones/{zone_id}/dns_records/{id}")),
+                None,
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//! The DNS-01 challenge, the only one for the wildcard certificates: the
+//! control of a domain is proven by a TXT record of `_acme-challenge.<domain>`,
+//! written by a DNS provider. The record is waited for (on the `resolver`)
+//! before the provider is asked to validate it, then removed.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use acme_v2::{order::NewOrder, persist::FilePersist};
+use anyhow::{anyhow, bail};
+use tracing::info;
+
+use crate::config::LetsEncryptDns01;
+
+mod cloudflare;
+mod rfc2136;
+mod wire;
+
+/// Resolver of the records written through a provider API
+const DEFAULT_RESOLVER: &str = "1.1.1.1:53";
+
+/// How long the resolver is given to answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Writes the TXT records of the challenges. Blocking, as the ACME client.
+pub trait DnsProvider: Send + Sync {
+    /// Creates the TXT record `name` with the value
+    fn add_txt_record(&self, name: &str, value: &str) -> Result<(), anyhow::Error>;
+
+    /// Removes the TXT record `name` with the value, once its challenge ended
+    fn remove_txt_record(&self, name: &str, value: &str) -> Result<(), anyhow::Error>;
+}
+
+/// The DNS-01 challenges of the orders, through the configured provider
+pub struct Dns01 {
+    provider: Box<dyn DnsProvider>,
+    resolver: String,
+    propagation_timeout: Duration,
+    propagation_interval: Duration,
+}
+
+impl Dns01 {
+    pub fn new(config: &LetsEncryptDns01) -> Result<Self, anyhow::Error> {
+        let (provider, resolver): (Box<dyn DnsProvider>, String) =
+            match (&config.cloudflare, &config.rfc2136) {
+                (Some(cloudflare), None) => (
+                    Box::new(cloudflare::Cloudflare::new(cloudflare)),
+                    DEFAULT_RESOLVER.to_string(),
+                ),
+                (None, Some(rfc2136)) => {
+                    let provider = rfc2136::Rfc2136::new(rfc2136)?;
+                    let server = provider.server().to_string();
+                    (Box::new(provider), server)
+                }
+                _ => bail!("lets_encrypt.dns01 needs one provider: cloudflare or rfc2136"),
+            };
+
+        Ok(Self {
+            provider,
+            resolver: config.resolver.as_deref().map_or(resolver, str::to_string),
+            propagation_timeout: Duration::from_secs(config.propagation_timeout_secs),
+            propagation_interval: Duration::from_secs(config.propagation_interval_secs),
+        })
+    }
+
+    /// Proves the control of the domains of the order with their TXT records,
+    /// removed once validated (or failed)
+    pub fn handle_challenge(
+        &self,
+        order: &mut NewOrder<FilePersist>,
+        interval: Duration,
+    ) -> Result<(), anyhow::Error> {
+        for auth in order.authorizations()? {
+            if !auth.need_challenge() {
+                continue;
+            }
+            if auth.api_auth().dns_challenge().is_none() {
+                bail!("the ACME provider offers no DNS-01 challenge");
+            }
+
+            let challenge = auth.dns_challenge();
+            let name = record_name(auth.domain_name());
+            let proof = challenge.dns_proof();
+
+            info!("DNS-01 challenge for domain: {}", auth.domain_name());
+            self.provider.add_txt_record(&name, &proof)?;
+
+            #[allow(clippy::cast_possible_truncation)]
+            let result = self.wait_for_propagation(&name, &proof).and_then(|()| {
+                info!("DNS-01 validating (retry: {interval:?})...");
+                Ok(challenge.validate(interval.as_millis() as u64)?)
+            });
+
+            if let Err(err) = self.provider.remove_txt_record(&name, &proof) {
+                tracing::warn!("failed to remove the TXT record {name}: {err}");
+            }
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the resolver to answer the record with the proof, so the
+    /// provider does not validate it too early
+    fn wait_for_propagation(&self, name: &str, proof: &str) -> Result<(), anyhow::Error> {
+        let deadline = Instant::now() + self.propagation_timeout;
+
+        loop {
+            match self.txt_records(name) {
+                Ok(values) if values.iter().any(|value| value == proof) => return Ok(()),
+                Ok(_) => tracing::debug!("the TXT record {name} is not visible yet"),
+                Err(err) => tracing::debug!("failed to query the TXT record {name}: {err}"),
+            }
+
+            if Instant::now() + self.propagation_interval >= deadline {
+                return Err(anyhow!(
+                    "the TXT record {name} was not visible on {} after {:?}",
+                    self.resolver,
+                    self.propagation_timeout
+                ));
+            }
+            thread::sleep(self.propagation_interval);
+        }
+    }
+
+    fn txt_records(&self, name: &str) -> Result<Vec<String>, anyhow::Error> {
+        let id = rand::random();
+        let response = wire::exchange(&self.resolver, &wire::txt_query(id, name)?, QUERY_TIMEOUT)?;
+        wire::txt_answers(&response, id)
+    }
+}
+
+/// The name of the TXT record of the domain, the one of its wildcard too
+fn record_name(domain: &str) -> String {
+    format!("_acme-challenge.{}", domain.trim_start_matches("*."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_name() {
+        assert_eq!(record_name("example.com"), "_acme-challenge.example.com");
+        assert_eq!(record_name("*.example.com"), "_acme-challenge.example.com");
+    }
+}
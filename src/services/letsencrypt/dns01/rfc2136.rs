@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use crate::config::Dns01Rfc2136;
+
+use super::{
+    wire::{self, TsigKey, Update},
+    DnsProvider,
+};
+
+/// How long the server is given to apply an update
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Writes the records through the dynamic updates (RFC 2136) of the primary
+/// server of the zone, signed with a TSIG key
+pub struct Rfc2136 {
+    server: String,
+    zone: String,
+    key: TsigKey,
+}
+
+impl Rfc2136 {
+    pub fn new(config: &Dns01Rfc2136) -> Result<Self, anyhow::Error> {
+        let secret = openssl::base64::decode_block(&config.key_secret)
+            .map_err(|err| anyhow!("invalid rfc2136 key_secret: {err}"))?;
+
+        Ok(Self {
+            server: config.server.to_string(),
+            zone: config.zone.to_string(),
+            key: TsigKey {
+                name: config.key_name.to_string(),
+                algorithm: config.key_algorithm,
+                secret,
+            },
+        })
+    }
+
+    /// The primary server, also queried for the records it was updated with
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    fn update(&self, name: &str, value: &str, update: Update) -> Result<(), anyhow::Error> {
+        let id = rand::random();
+        let message = wire::txt_update(id, &self.zone, name, value, update, &self.key)?;
+        let response = wire::exchange(&self.server, &message, UPDATE_TIMEOUT)?;
+        wire::check_update(&response, id)
+            .map_err(|err| anyhow!("failed to update {name} on {}: {err}", self.server))
+    }
+}
+
+impl DnsProvider for Rfc2136 {
+    fn add_txt_record(&self, name: &str, value: &str) -> Result<(), anyhow::Error> {
+        self.update(name, value, Update::Add)
+    }
+
+    fn remove_txt_record(&self, name: &str, value: &str) -> Result<(), anyhow::Error> {
+        self.update(name, value, Update::Delete)
+    }
+}
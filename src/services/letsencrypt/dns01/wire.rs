@@ -0,0 +1,406 @@
+//! The DNS messages of the DNS-01 challenges: the queries of the TXT records
+//! (waiting for their propagation) and the dynamic updates (RFC 2136) signed
+//! with a TSIG key (RFC 8945). Both are sent over TCP.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+use crate::config::TsigAlgorithm;
+
+const TYPE_TXT: u16 = 16;
+const TYPE_SOA: u16 = 6;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+/// Standard query, recursion desired
+const FLAGS_QUERY: u16 = 0x0100;
+/// Opcode UPDATE
+const FLAGS_UPDATE: u16 = 5 << 11;
+
+/// Seconds of difference allowed between the clocks of proksi and the server
+const TSIG_FUDGE: u16 = 300;
+
+/// The TTL of the TXT records of the challenges
+const RECORD_TTL: u32 = 60;
+
+/// A TSIG key signing the updates
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    fn algorithm_name(&self) -> &'static str {
+        match self.algorithm {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn digest(&self) -> MessageDigest {
+        match self.algorithm {
+            TsigAlgorithm::HmacSha256 => MessageDigest::sha256(),
+            TsigAlgorithm::HmacSha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+/// Whether the update adds or deletes the TXT record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Update {
+    Add,
+    Delete,
+}
+
+fn push_u16(message: &mut Vec<u8>, value: u16) {
+    message.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(message: &mut Vec<u8>, value: u32) {
+    message.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_header(message: &mut Vec<u8>, id: u16, flags: u16, counts: [u16; 4]) {
+    push_u16(message, id);
+    push_u16(message, flags);
+    for count in counts {
+        push_u16(message, count);
+    }
+}
+
+/// Appends a domain name, in its (lowercase) wire format
+fn push_name(message: &mut Vec<u8>, name: &str) -> Result<(), anyhow::Error> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid domain name {name}");
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        message.push(label.len() as u8);
+        message.extend(label.bytes().map(|byte| byte.to_ascii_lowercase()));
+    }
+    message.push(0);
+    Ok(())
+}
+
+/// The value of a TXT record, in strings of at most 255 bytes
+fn txt_data(value: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(value.len() + 1);
+    for chunk in value.as_bytes().chunks(255) {
+        #[allow(clippy::cast_possible_truncation)]
+        data.push(chunk.len() as u8);
+        data.extend_from_slice(chunk);
+    }
+    data
+}
+
+/// Query of the TXT records of the name
+pub fn txt_query(id: u16, name: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut message = Vec::with_capacity(64);
+    push_header(&mut message, id, FLAGS_QUERY, [1, 0, 0, 0]);
+    push_name(&mut message, name)?;
+    push_u16(&mut message, TYPE_TXT);
+    push_u16(&mut message, CLASS_IN);
+    Ok(message)
+}
+
+/// Update of the TXT record `name` of the zone, signed with the key
+pub fn txt_update(
+    id: u16,
+    zone: &str,
+    name: &str,
+    value: &str,
+    update: Update,
+    key: &TsigKey,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut message = Vec::with_capacity(256);
+    push_header(&mut message, id, FLAGS_UPDATE, [1, 0, 1, 0]);
+
+    // Zone section
+    push_name(&mut message, zone)?;
+    push_u16(&mut message, TYPE_SOA);
+    push_u16(&mut message, CLASS_IN);
+
+    // Update section: deleting a single record has the class NONE (and no TTL)
+    let (class, ttl) = match update {
+        Update::Add => (CLASS_IN, RECORD_TTL),
+        Update::Delete => (CLASS_NONE, 0),
+    };
+    let data = txt_data(value);
+    push_name(&mut message, name)?;
+    push_u16(&mut message, TYPE_TXT);
+    push_u16(&mut message, class);
+    push_u32(&mut message, ttl);
+    #[allow(clippy::cast_possible_truncation)]
+    push_u16(&mut message, data.len() as u16);
+    message.extend_from_slice(&data);
+
+    let time_signed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    sign(&mut message, key, time_signed)?;
+    Ok(message)
+}
+
+/// Appends the TSIG record of the message (RFC 8945), signed at the time
+fn sign(message: &mut Vec<u8>, key: &TsigKey, time_signed: u64) -> Result<(), anyhow::Error> {
+    let id = [message[0], message[1]];
+    // 48 bits
+    let time_signed = time_signed.to_be_bytes();
+    let time = &time_signed[2..];
+
+    let mut key_name = Vec::new();
+    push_name(&mut key_name, &key.name)?;
+    let mut algorithm = Vec::new();
+    push_name(&mut algorithm, key.algorithm_name())?;
+
+    // The MAC covers the message and the variables of the TSIG record
+    let hmac = PKey::hmac(&key.secret)?;
+    let mut signer = Signer::new(key.digest(), &hmac)?;
+    signer.update(message)?;
+    signer.update(&key_name)?;
+    signer.update(&CLASS_ANY.to_be_bytes())?;
+    signer.update(&0u32.to_be_bytes())?;
+    signer.update(&algorithm)?;
+    signer.update(time)?;
+    signer.update(&TSIG_FUDGE.to_be_bytes())?;
+    // No error, no other data
+    signer.update(&[0, 0, 0, 0])?;
+    let mac = signer.sign_to_vec()?;
+
+    let mut data = algorithm;
+    data.extend_from_slice(time);
+    data.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    #[allow(clippy::cast_possible_truncation)]
+    push_u16(&mut data, mac.len() as u16);
+    data.extend_from_slice(&mac);
+    data.extend_from_slice(&id);
+    data.extend_from_slice(&[0, 0, 0, 0]);
+
+    message.extend_from_slice(&key_name);
+    push_u16(message, TYPE_TSIG);
+    push_u16(message, CLASS_ANY);
+    push_u32(message, 0);
+    #[allow(clippy::cast_possible_truncation)]
+    push_u16(message, data.len() as u16);
+    message.extend_from_slice(&data);
+
+    // One more additional record
+    let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    Ok(())
+}
+
+/// Reads the `u16` at the position of the message
+fn read_u16(message: &[u8], position: usize) -> Result<u16, anyhow::Error> {
+    message
+        .get(position..position + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| anyhow!("truncated DNS message"))
+}
+
+/// The position after the (possibly compressed) name at the position
+fn skip_name(message: &[u8], mut position: usize) -> Result<usize, anyhow::Error> {
+    loop {
+        let length = *message
+            .get(position)
+            .ok_or_else(|| anyhow!("truncated DNS message"))?;
+        match length {
+            0 => return Ok(position + 1),
+            // A pointer ends the name
+            length if length & 0xC0 == 0xC0 => return Ok(position + 2),
+            length => position += 1 + usize::from(length),
+        }
+    }
+}
+
+/// The response code of the answer, checking it is the one of the message
+fn response_code(response: &[u8], id: u16) -> Result<u16, anyhow::Error> {
+    if read_u16(response, 0)? != id {
+        bail!("the DNS answer is not the one of the request");
+    }
+    Ok(read_u16(response, 2)? & 0x000F)
+}
+
+/// The values of the TXT records of the answer to a query
+pub fn txt_answers(response: &[u8], id: u16) -> Result<Vec<String>, anyhow::Error> {
+    // No record yet (NXDOMAIN) is not an error
+    let code = response_code(response, id)?;
+    if code != 0 && code != 3 {
+        bail!("the DNS server refused the query (rcode {code})");
+    }
+
+    let questions = read_u16(response, 4)?;
+    let answers = read_u16(response, 6)?;
+    let mut position = 12;
+    for _ in 0..questions {
+        position = skip_name(response, position)? + 4;
+    }
+
+    let mut values = Vec::new();
+    for _ in 0..answers {
+        position = skip_name(response, position)?;
+        let kind = read_u16(response, position)?;
+        let length = usize::from(read_u16(response, position + 8)?);
+        position += 10;
+        let data = response
+            .get(position..position + length)
+            .ok_or_else(|| anyhow!("truncated DNS message"))?;
+        position += length;
+
+        if kind != TYPE_TXT {
+            continue;
+        }
+
+        let mut value = Vec::with_capacity(length);
+        let mut strings = data;
+        while let Some((&size, rest)) = strings.split_first() {
+            let size = usize::from(size).min(rest.len());
+            value.extend_from_slice(&rest[..size]);
+            strings = &rest[size..];
+        }
+        values.push(String::from_utf8_lossy(&value).into_owned());
+    }
+
+    Ok(values)
+}
+
+/// Checks the server applied an update
+pub fn check_update(response: &[u8], id: u16) -> Result<(), anyhow::Error> {
+    match response_code(response, id)? {
+        0 => Ok(()),
+        code => Err(anyhow!("the DNS server refused the update (rcode {code})")),
+    }
+}
+
+/// Sends the message to the server over TCP and returns its answer
+pub fn exchange(server: &str, message: &[u8], timeout: Duration) -> Result<Vec<u8>, anyhow::Error> {
+    let address = server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("the DNS server {server} has no address"))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    // Over TCP, the messages are prefixed by their length
+    let length = u16::try_from(message.len())?;
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(message)?;
+
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+    let mut response = vec![0; usize::from(u16::from_be_bytes(length))];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An answer with the TXT records, the name compressed
+    fn answer(id: u16, name: &str, values: &[&str]) -> Vec<u8> {
+        let mut message = txt_query(id, name).unwrap();
+        message[2] |= 0x80;
+        #[allow(clippy::cast_possible_truncation)]
+        message[6..8].copy_from_slice(&(values.len() as u16).to_be_bytes());
+        for value in values {
+            let data = txt_data(value);
+            message.extend_from_slice(&[0xC0, 12]);
+            push_u16(&mut message, TYPE_TXT);
+            push_u16(&mut message, CLASS_IN);
+            push_u32(&mut message, 60);
+            #[allow(clippy::cast_possible_truncation)]
+            push_u16(&mut message, data.len() as u16);
+            message.extend_from_slice(&data);
+        }
+        message
+    }
+
+    #[test]
+    fn test_txt_query() {
+        let query = txt_query(0x1234, "_acme-challenge.Example.com.").unwrap();
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(
+            &query[12..],
+            b"\x0f_acme-challenge\x07example\x03com\x00\x00\x10\x00\x01"
+        );
+
+        assert!(txt_query(1, "invalid..example.com").is_err());
+    }
+
+    #[test]
+    fn test_txt_answers() {
+        let long = "a".repeat(300);
+        let response = answer(7, "_acme-challenge.example.com", &["proof", &long]);
+        assert_eq!(
+            txt_answers(&response, 7).unwrap(),
+            vec!["proof".to_string(), long]
+        );
+
+        // Not the answer of the query
+        assert!(txt_answers(&response, 8).is_err());
+        // Truncated
+        assert!(txt_answers(&response[..response.len() - 1], 7).is_err());
+    }
+
+    #[test]
+    fn test_signed_txt_update() {
+        let key = TsigKey {
+            name: "proksi".to_string(),
+            algorithm: TsigAlgorithm::HmacSha256,
+            secret: b"secret".to_vec(),
+        };
+        let message = txt_update(
+            9,
+            "example.com",
+            "_acme-challenge.example.com",
+            "proof",
+            Update::Delete,
+            &key,
+        )
+        .unwrap();
+
+        assert_eq!(read_u16(&message, 2).unwrap(), FLAGS_UPDATE);
+        // Zone, no prerequisite, the update and the TSIG record
+        assert_eq!(&message[4..12], &[0, 1, 0, 0, 0, 1, 0, 1]);
+
+        // The deleted record has the class NONE
+        let zone_end = skip_name(&message, 12).unwrap() + 4;
+        let update = skip_name(&message, zone_end).unwrap();
+        assert_eq!(read_u16(&message, update + 2).unwrap(), CLASS_NONE);
+
+        // The TSIG record follows, with a MAC of the size of the digest
+        let tsig = update + 10 + usize::from(read_u16(&message, update + 8).unwrap());
+        assert_eq!(&message[tsig..tsig + 8], b"\x06proksi\x00");
+        let algorithm = skip_name(&message, tsig + 8 + 10).unwrap();
+        assert_eq!(read_u16(&message, algorithm + 8).unwrap(), 32);
+    }
+
+    #[test]
+    fn test_tsig_mac_depends_on_the_key() {
+        let mac = |secret: &[u8]| {
+            let key = TsigKey {
+                name: "proksi".to_string(),
+                algorithm: TsigAlgorithm::HmacSha512,
+                secret: secret.to_vec(),
+            };
+            let mut message = txt_query(1, "example.com").unwrap();
+            sign(&mut message, &key, 1_700_000_000).unwrap();
+            message
+        };
+
+        assert_eq!(mac(b"secret"), mac(b"secret"));
+        assert_ne!(mac(b"secret"), mac(b"other"));
+    }
+}
@@ -0,0 +1,228 @@
+//! External account binding (RFC 8555, section 7.3.4), required by some ACME
+//! providers (ex: ZeroSSL) to create an account. The ACME client does not support
+//! it: the account is registered here instead, and its key persisted where the
+//! client reads it. The provider then returns the existing account for that key.
+
+use acme_v2::{
+    api::ApiDirectory,
+    persist::{FilePersist, Persist, PersistKey, PersistKind},
+};
+use anyhow::anyhow;
+use openssl::{
+    base64,
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+use serde_json::{json, Value};
+
+/// Credentials of the external account, given by the ACME provider
+pub struct ExternalAccount<'a> {
+    pub kid: &'a str,
+    /// base64url encoded
+    pub hmac_key: &'a str,
+}
+
+/// base64url encoding without padding (RFC 7515, section 2)
+fn base64url(data: &[u8]) -> String {
+    base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+fn base64url_decode(data: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut padded = data.trim().replace('-', "+").replace('_', "/");
+    while padded.len() % 4 != 0 {
+        padded.push('=');
+    }
+
+    Ok(base64::decode_block(&padded)?)
+}
+
+/// Public JWK of a P-256 account key (RFC 7518, section 6.2)
+fn jwk(key: &EcKey<Private>) -> Result<Value, anyhow::Error> {
+    let mut ctx = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    key.public_key()
+        .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)?;
+
+    Ok(json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": base64url(&x.to_vec_padded(32)?),
+        "y": base64url(&y.to_vec_padded(32)?),
+    }))
+}
+
+/// Binds the account key to the external account: the JWK signed (HS256)
+/// with the HMAC key of the external account
+fn binding(jwk: &Value, account: &ExternalAccount, url: &str) -> Result<Value, anyhow::Error> {
+    let protected = json!({ "alg": "HS256", "kid": account.kid, "url": url });
+    let protected = base64url(protected.to_string().as_bytes());
+    let payload = base64url(jwk.to_string().as_bytes());
+
+    let key = PKey::hmac(&base64url_decode(account.hmac_key)?)?;
+    let signature = Signer::new(MessageDigest::sha256(), &key)?
+        .sign_oneshot_to_vec(format!("{protected}.{payload}").as_bytes())?;
+
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": base64url(&signature),
+    }))
+}
+
+/// Signs the request (ES256, the signature being `r || s`) with the account key
+fn sign(
+    key: &EcKey<Private>,
+    jwk: &Value,
+    nonce: &str,
+    url: &str,
+    payload: &Value,
+) -> Result<Value, anyhow::Error> {
+    let protected = json!({ "alg": "ES256", "jwk": jwk, "nonce": nonce, "url": url });
+    let protected = base64url(protected.to_string().as_bytes());
+    let payload = base64url(payload.to_string().as_bytes());
+
+    let digest = hash(
+        MessageDigest::sha256(),
+        format!("{protected}.{payload}").as_bytes(),
+    )?;
+    let signature = EcdsaSig::sign(&digest, key)?;
+    let signature = [
+        signature.r().to_vec_padded(32)?,
+        signature.s().to_vec_padded(32)?,
+    ]
+    .concat();
+
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": base64url(&signature),
+    }))
+}
+
+/// Registers the account of `email` bound to the external account, unless a key
+/// was already persisted for it (the account then already exists).
+pub async fn register(
+    persist: &FilePersist,
+    directory: &ApiDirectory,
+    email: &str,
+    account: &ExternalAccount<'_>,
+) -> Result<(), anyhow::Error> {
+    // Same key as the one the ACME client looks for (see `Directory::account`)
+    let persist_key = PersistKey::new(email, PersistKind::AccountPrivateKey, "acme_account");
+    if persist.get(&persist_key)?.is_some() {
+        return Ok(());
+    }
+
+    let key = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)?;
+    let jwk = jwk(&key)?;
+
+    let client = reqwest::Client::new();
+    let nonce = client
+        .head(&directory.newNonce)
+        .send()
+        .await?
+        .headers()
+        .get("replay-nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("the ACME provider returned no nonce"))?;
+
+    let payload = json!({
+        "termsOfServiceAgreed": true,
+        "contact": [format!("mailto:{email}")],
+        "externalAccountBinding": binding(&jwk, account, &directory.newAccount)?,
+    });
+    let body = sign(&key, &jwk, &nonce, &directory.newAccount, &payload)?;
+
+    let response = client
+        .post(&directory.newAccount)
+        .header(reqwest::header::CONTENT_TYPE, "application/jose+json")
+        .body(body.to_string())
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let problem = response.text().await.unwrap_or_default();
+        return Err(anyhow!(
+            "failed to register the ACME account ({status}): {problem}"
+        ));
+    }
+
+    persist.put(&persist_key, &key.private_key_to_pem()?)?;
+    tracing::info!("registered the ACME account of {email} with its external account binding");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_json(data: &Value) -> Value {
+        serde_json::from_slice(&base64url_decode(data.as_str().unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_base64url() {
+        let data = [0xfb, 0xff, 0xfe, 0x01];
+        assert_eq!(base64url(&data), "-__-AQ");
+        assert_eq!(base64url_decode("-__-AQ").unwrap(), data);
+    }
+
+    #[test]
+    fn test_binding_and_signature() {
+        let key =
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let jwk = jwk(&key).unwrap();
+        let account = ExternalAccount {
+            kid: "kid-1",
+            hmac_key: &base64url(b"secret"),
+        };
+        let url = "https://acme.example/new-account";
+
+        // The binding carries the JWK of the account, signed with the HMAC key
+        let eab = binding(&jwk, &account, url).unwrap();
+        assert_eq!(decode_json(&eab["protected"])["kid"], "kid-1");
+        assert_eq!(decode_json(&eab["payload"]), jwk);
+
+        let signed = format!(
+            "{}.{}",
+            eab["protected"].as_str().unwrap(),
+            eab["payload"].as_str().unwrap()
+        );
+        let hmac = Signer::new(MessageDigest::sha256(), &PKey::hmac(b"secret").unwrap())
+            .unwrap()
+            .sign_oneshot_to_vec(signed.as_bytes())
+            .unwrap();
+        assert_eq!(eab["signature"], base64url(&hmac));
+
+        // The request is signed with the account key
+        let jws = sign(&key, &jwk, "nonce", url, &json!({ "eab": eab })).unwrap();
+        assert_eq!(decode_json(&jws["protected"])["nonce"], "nonce");
+
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        let signature = base64url_decode(jws["signature"].as_str().unwrap()).unwrap();
+        assert_eq!(signature.len(), 64);
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(&signature[..32]).unwrap(),
+            BigNum::from_slice(&signature[32..]).unwrap(),
+        )
+        .unwrap();
+        let digest = hash(MessageDigest::sha256(), signed.as_bytes()).unwrap();
+        assert!(signature.verify(&digest, &key).unwrap());
+    }
+}
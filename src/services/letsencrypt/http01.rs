@@ -2,14 +2,21 @@ use std::{
     fs::create_dir_all,
     path::{self, PathBuf},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-use acme_v2::{order::NewOrder, persist::FilePersist, Account, DirectoryUrl};
-use anyhow::anyhow;
+use acme_v2::{
+    order::NewOrder,
+    persist::{FilePersist, PersistKey, PersistKind},
+    Account, DirectoryUrl, RevocationReason,
+};
 use async_trait::async_trait;
 
-use openssl::{pkey::PKey, x509::X509};
+use openssl::{
+    asn1::{Asn1Time, Asn1TimeRef},
+    pkey::PKey,
+    x509::X509,
+};
 use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
@@ -18,21 +25,116 @@ use tokio::time;
 use tracing::info;
 
 use crate::{
-    config::Config,
-    stores::{self, certificates::Certificate},
+    config::{AcmeChallenge, Config},
+    error::{AcmeFailure, Error},
+    metrics,
+    proxy_server::matching,
+    services::tick_or_shutdown,
+    stores::{
+        self,
+        certificates::{Certificate, CertificateSource},
+    },
 };
 
+use super::{
+    dns01::Dns01,
+    eab::{self, ExternalAccount},
+    orders::{OrderStore, PendingOrder},
+    queue,
+    rate_limit::RateLimit,
+    webhook::{self, CertificateAction, CertificateEvent, Webhook},
+};
+
+/// The longest delay between two attempts of the same order
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Certificates expiring within this many days are renewed
+const RENEWAL_WINDOW_DAYS: u32 = 5;
+
+/// What a persisted certificate is good for, from its expiration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Freshness {
+    Valid,
+    /// Still served while it is renewed
+    Renewable,
+    /// Not served, a new one is ordered
+    Expired,
+}
+
+impl Freshness {
+    fn of(not_after: &Asn1TimeRef) -> Result<Self, anyhow::Error> {
+        if *not_after <= Asn1Time::days_from_now(0)? {
+            return Ok(Freshness::Expired);
+        }
+        if *not_after <= Asn1Time::days_from_now(RENEWAL_WINDOW_DAYS)? {
+            return Ok(Freshness::Renewable);
+        }
+        Ok(Freshness::Valid)
+    }
+}
+
+/// Retry and timeout settings of the HTTP-01 challenge
+#[derive(Debug, Clone, Copy)]
+struct ChallengeOptions {
+    attempts: u32,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl ChallengeOptions {
+    /// Exponential backoff (based on `interval`) before the next attempt
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        self.interval
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
 /// A service that handles the creation of certificates using the Let's Encrypt API
 
 pub struct LetsencryptService {
     pub(crate) config: Arc<Config>,
     // pub(crate) route_store: RouteStore,
     // pub(crate) cert_store: CertificateStore,
+    /// Receives certificate lifecycle events (if `webhook_url` is configured)
+    webhook: Option<Webhook>,
+    /// Orders in flight, resumed after a restart
+    orders: OrderStore,
+    /// Pause of the orders after a rate limit of the provider
+    rate_limit: RateLimit,
+    /// Interrupts the orders once the shutdown started
+    shutdown: Option<ShutdownWatch>,
+    /// The DNS-01 challenges (if `dns01` is configured)
+    dns01: Option<Arc<Dns01>>,
 }
 
 impl LetsencryptService {
     pub fn new(config: Arc<Config>) -> Self {
-        Self { config }
+        let webhook = config.lets_encrypt.webhook_url.as_deref().and_then(|url| {
+            Webhook::new(url)
+                .map_err(|e| tracing::error!("failed to create certificate webhook: {e}"))
+                .ok()
+        });
+
+        let dns01 = config.lets_encrypt.dns01.as_ref().and_then(|dns01| {
+            Dns01::new(dns01)
+                .map(Arc::new)
+                .map_err(|e| tracing::error!("failed to configure the DNS-01 challenges: {e}"))
+                .ok()
+        });
+
+        let mut service = Self {
+            config,
+            webhook,
+            orders: OrderStore::default(),
+            rate_limit: RateLimit::default(),
+            shutdown: None,
+            dns01,
+        };
+
+        // Kept along with the certificates (and account) of the provider
+        service.orders = OrderStore::new(&service.get_lets_encrypt_directory());
+        service
     }
 
     /// Parse a PEM-encoded X509 certificate from a string slice
@@ -46,54 +148,63 @@ impl LetsencryptService {
     }
 
     /// Update global certificate store with new `X509` and `PKey` for the
-    /// given domain also considering that the certificate could be a bundle file.
+    /// given domain also considering that the certificate could be a bundle file
+    /// (the leaf followed by every intermediate).
     fn insert_certificate(domain: &str, bundle: &str, key_pem: &str) -> Result<(), anyhow::Error> {
-        let end = "-----END CERTIFICATE-----";
-        // Split certificates (leaf and chain) from the bundle
-        let split = bundle
-            .split_inclusive(end)
-            .map(str::trim)
-            .collect::<Vec<&str>>();
-
-        let leaf_pem = split.first();
-        let chain_pem = split.get(1);
-
-        let Some(leaf) = leaf_pem else {
-            return Err(anyhow::anyhow!("Certificate is empty"));
-        };
-        let leaf = Self::parse_x509_cert(leaf)?;
-        let mut chain: Option<X509> = None;
+        let key = Self::parse_private_key(key_pem)?;
+        let mut certificate = Certificate::from_pem(bundle.as_bytes(), key)?;
+        certificate.source = CertificateSource::Acme;
 
-        if let Some(chain_pem) = chain_pem {
-            tracing::trace!("chain PEM: {:?}", chain_pem);
-            chain = Some(Self::parse_x509_cert(chain_pem)?);
+        if let Some(issue) = certificate.chain_issue() {
+            tracing::warn!("certificate of {domain} {issue}");
         }
 
-        let key = Self::parse_private_key(key_pem)?;
-
-        stores::insert_certificate(domain.to_string(), Certificate { key, leaf, chain });
+        stores::insert_certificate(domain.to_string(), certificate);
 
         Ok(())
     }
 
-    /// Start an HTTP-01 challenge for a given order
-    fn handle_http_01_challenge(order: &mut NewOrder<FilePersist>) -> Result<(), anyhow::Error> {
+    /// Start an HTTP-01 challenge for a given order, persisting it until the order ends
+    fn handle_http_01_challenge(
+        order: &mut NewOrder<FilePersist>,
+        interval: Duration,
+        orders: &OrderStore,
+    ) -> Result<(), anyhow::Error> {
+        let authorizations = order.api_order().authorizations.clone().unwrap_or_default();
+
         for auth in order.authorizations()? {
             let challenge = auth.http_challenge();
+            let domain = auth.domain_name();
+
+            info!("HTTP-01 challenge for domain: {domain}");
 
-            info!("HTTP-01 challenge for domain: {}", auth.domain_name());
+            // The provider returns the pending order of the domain when it is placed again
+            if orders
+                .get(domain)
+                .is_some_and(|pending| pending.authorizations == authorizations)
+            {
+                info!("resumed the pending order of {domain}");
+            }
+
+            let pending = PendingOrder::new(
+                domain,
+                authorizations.clone(),
+                challenge.http_token(),
+                &challenge.http_proof(),
+            );
+            if let Err(err) = orders.save(&pending) {
+                tracing::warn!("failed to persist the pending order of {domain}: {err}");
+            }
 
             stores::insert_challenge(
-                auth.domain_name().to_string(),
-                (
-                    challenge.http_token().to_string(),
-                    challenge.http_proof().to_string(),
-                ),
+                domain.to_string(),
+                (pending.token, pending.key_authorization),
             );
 
             // Let's Encrypt will check the domain's URL to validate the challenge
-            tracing::info!("HTTP-01 validating (retry: 5s)...");
-            challenge.validate(5000)?; // Retry every 5000 ms
+            tracing::info!("HTTP-01 validating (retry: {interval:?})...");
+            #[allow(clippy::cast_possible_truncation)]
+            challenge.validate(interval.as_millis() as u64)?;
         }
         Ok(())
     }
@@ -112,72 +223,143 @@ impl LetsencryptService {
 
         tracing::info!("creating an in-memory self-signed certificate for {domain}");
 
-        let rsa = openssl::rsa::Rsa::generate(2048)?;
-        let mut openssl_cert = openssl::x509::X509Builder::new()?;
-        let mut x509_name = openssl::x509::X509NameBuilder::new()?;
-
-        x509_name.append_entry_by_text("CN", domain)?;
-        x509_name.append_entry_by_text("ST", "TX")?;
-        x509_name.append_entry_by_text("O", "Proksi")?;
-        // x509_name.append_entry_by_text("CN", "Test")?;
-        let x509_name = x509_name.build();
-
-        let hash = pingora::tls::hash::MessageDigest::sha256();
-        let key = pingora::tls::pkey::PKey::from_rsa(rsa)?;
-        let one_year = openssl::asn1::Asn1Time::days_from_now(365)?;
-        let today = openssl::asn1::Asn1Time::days_from_now(0)?;
-        openssl_cert.set_version(2)?;
-        openssl_cert.set_subject_name(&x509_name)?;
-        openssl_cert.set_issuer_name(&x509_name)?;
-        openssl_cert.set_pubkey(&key)?;
-        openssl_cert.set_not_before(&today)?;
-        openssl_cert.set_not_after(&one_year)?;
-        openssl_cert.sign(&key, hash)?;
-
-        let openssl_cert = openssl_cert.build();
-
-        stores::insert_certificate(
-            domain.to_string(),
-            Certificate {
-                key,
-                leaf: openssl_cert,
-                chain: None,
-            },
-        );
+        stores::insert_certificate(domain.to_string(), Certificate::self_signed(domain)?);
 
         Ok(())
     }
 
     // Based on the letsencrypt configuration, return the appropriate URL
     fn get_lets_encrypt_url(&self) -> DirectoryUrl {
-        match self.config.lets_encrypt.staging {
-            Some(false) => DirectoryUrl::LetsEncrypt,
-            _ => DirectoryUrl::LetsEncryptStaging,
-        }
+        lets_encrypt_url(&self.config)
     }
 
     /// Return the appropriate Let's Encrypt directories for certificates based on the environment
+    /// (accounts and certificates of other providers are kept apart, by host)
     fn get_lets_encrypt_directory(&self) -> PathBuf {
-        let suffix = match self.config.lets_encrypt.staging {
-            Some(false) => "production",
-            _ => "staging",
-        };
+        lets_encrypt_directory(&self.config)
+    }
 
-        let path = self.config.paths.lets_encrypt.join(suffix);
+    /// Returns the challenge settings from the letsencrypt configuration
+    fn challenge_options(&self) -> ChallengeOptions {
+        let lets_encrypt = &self.config.lets_encrypt;
 
-        if let Ok(res) = path::absolute(&path) {
-            return res;
+        ChallengeOptions {
+            attempts: lets_encrypt.challenge_attempts.unwrap_or(5).max(1),
+            interval: Duration::from_secs(lets_encrypt.challenge_interval_secs.unwrap_or(5)),
+            timeout: Duration::from_secs(lets_encrypt.challenge_timeout_secs.unwrap_or(300)),
         }
+    }
 
-        path
+    /// Whether the shutdown started: no more orders are placed
+    fn is_stopping(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .is_some_and(|shutdown| *shutdown.borrow())
     }
 
-    /// Create a new order for a domain (HTTP-01 challenge)
-    fn create_order_for_domain(
+    /// How many orders are placed at once
+    fn max_concurrent_orders(&self) -> usize {
+        self.config
+            .lets_encrypt
+            .max_concurrent_orders
+            .unwrap_or(4)
+            .max(1)
+    }
+
+    /// Create a new order for a domain (HTTP-01 challenge, or DNS-01 with
+    /// `dns01`), backing off and retrying the whole order on failures until
+    /// `options.attempts` or `options.timeout` are exhausted, or the shutdown
+    /// started. The calls to the ACME provider run on the blocking threads,
+    /// so the other background services keep running during an order.
+    /// Returns the expiration (unix timestamp) of the new certificate
+    async fn create_order_for_domain(
         domain: &str,
         account: &Account<FilePersist>,
-    ) -> Result<(), anyhow::Error> {
-        let mut order = account.new_order(domain, &[])?;
+        options: ChallengeOptions,
+        orders: &OrderStore,
+        dns01: Option<&Arc<Dns01>>,
+        shutdown: Option<&ShutdownWatch>,
+    ) -> Result<Option<i64>, Error> {
+        let deadline = Instant::now() + options.timeout;
+        let stopping = || shutdown.is_some_and(|shutdown| *shutdown.borrow());
+        let mut attempt = 1;
+
+        loop {
+            info!(
+                "ordering certificate for {domain} (attempt {attempt}/{})",
+                options.attempts
+            );
+
+            let order = {
+                let domain = domain.to_string();
+                let account = account.clone();
+                let orders = orders.clone();
+                let dns01 = dns01.cloned();
+                let shutdown = shutdown.cloned();
+                tokio::task::spawn_blocking(move || {
+                    let stopping = || shutdown.as_ref().is_some_and(|shutdown| *shutdown.borrow());
+                    Self::try_order_for_domain(
+                        &domain,
+                        &account,
+                        options,
+                        deadline,
+                        &orders,
+                        dns01.as_deref(),
+                        &stopping,
+                    )
+                })
+            };
+
+            let err = match order.await {
+                Ok(Ok(expires_at)) => return Ok(expires_at),
+                Ok(Err(err)) => err,
+                Err(err) => {
+                    Error::Acme(AcmeFailure::Other, format!("the order was aborted: {err}"))
+                }
+            };
+            let failure = err.acme_failure().unwrap_or(AcmeFailure::Other);
+
+            // Retrying a rate limited order only makes things worse
+            let delay = options.retry_delay(attempt);
+            if attempt >= options.attempts
+                || Instant::now() + delay >= deadline
+                || failure == AcmeFailure::RateLimited
+                || stopping()
+            {
+                return Err(Error::Acme(
+                    failure,
+                    format!("order for {domain} failed after {attempt} attempt(s): {err}"),
+                ));
+            }
+
+            tracing::warn!(
+                "order attempt {attempt}/{} for {domain} failed: {err}. Retrying in {delay:?}",
+                options.attempts
+            );
+            sleep_or_shutdown(delay, shutdown).await;
+            if stopping() {
+                return Err(Error::Acme(
+                    AcmeFailure::Other,
+                    format!("order for {domain} interrupted by the shutdown after {attempt} attempt(s): {err}"),
+                ));
+            }
+            attempt += 1;
+        }
+    }
+
+    /// A single attempt of an order, giving up once the `deadline` is reached
+    /// or the order is `stopping`
+    fn try_order_for_domain(
+        domain: &str,
+        account: &Account<FilePersist>,
+        options: ChallengeOptions,
+        deadline: Instant,
+        orders: &OrderStore,
+        dns01: Option<&Dns01>,
+        stopping: &dyn Fn() -> bool,
+    ) -> Result<Option<i64>, Error> {
+        let mut order = account.new_order(domain, &[]).map_err(acme_error)?;
+        let challenge = if dns01.is_some() { "DNS-01" } else { "HTTP-01" };
 
         let order_csr = loop {
             // Break if we are done confirming validations
@@ -185,76 +367,377 @@ impl LetsencryptService {
                 break csr;
             }
 
+            if Instant::now() >= deadline {
+                return Err(Error::Acme(
+                    AcmeFailure::Validation,
+                    format!(
+                        "{challenge} challenge timed out after {:?}",
+                        options.timeout
+                    ),
+                ));
+            }
+
+            if stopping() {
+                return Err(Error::Acme(
+                    AcmeFailure::Other,
+                    format!("{challenge} challenge interrupted by the shutdown"),
+                ));
+            }
+
             // Get the possible authorizations (for a single domain
             // this will only be one element).
-            Self::handle_http_01_challenge(&mut order)
-                .map_err(|err| anyhow!("Failed to handle HTTP-01 challenge: {err}"))?;
+            match dns01 {
+                Some(dns01) => dns01.handle_challenge(&mut order, options.interval),
+                None => Self::handle_http_01_challenge(&mut order, options.interval, orders),
+            }
+            .map_err(|err| {
+                Error::Acme(
+                    challenge_failure(&err),
+                    format!("Failed to handle {challenge} challenge: {err}"),
+                )
+            })?;
 
             order.refresh().unwrap_or_default();
         };
 
         // Order OK
         let pkey = acme_v2::create_p384_key();
-        let order_cert = order_csr.finalize_pkey(pkey, 5000)?;
+        #[allow(clippy::cast_possible_truncation)]
+        let order_cert = order_csr
+            .finalize_pkey(pkey, options.interval.as_millis() as u64)
+            .map_err(acme_error)?;
 
         info!("certificate created for order {:?}", order_cert.api_order());
 
-        let cert = order_cert.download_and_save_cert()?;
+        let cert = order_cert.download_and_save_cert().map_err(acme_error)?;
 
-        Self::insert_certificate(domain, cert.certificate(), cert.private_key())?;
+        Self::insert_certificate(domain, cert.certificate(), cert.private_key())
+            .map_err(|err| Error::Acme(AcmeFailure::Other, err.to_string()))?;
 
-        Ok(())
+        let expires_at = Self::parse_x509_cert(cert.certificate())
+            .ok()
+            .and_then(|leaf| webhook::expires_at(&leaf));
+
+        Ok(expires_at)
+    }
+
+    /// Orders a certificate for the domain and notifies the webhook (if any) of the result
+    async fn order_certificate(
+        &self,
+        domain: &str,
+        account: &Account<FilePersist>,
+        action: CertificateAction,
+    ) -> Result<(), Error> {
+        if let Some(remaining) = self.rate_limit.remaining() {
+            return Err(Error::Acme(
+                AcmeFailure::RateLimited,
+                format!(
+                    "ACME orders are paused by a rate limit for another {}s",
+                    remaining.as_secs()
+                ),
+            ));
+        }
+
+        // The hosts waiting their turn are ordered on the next start
+        if self.is_stopping() {
+            return Err(Error::Acme(
+                AcmeFailure::Other,
+                format!("the order of {domain} is not placed, proksi is shutting down"),
+            ));
+        }
+
+        let dns01 = match challenge_of(&self.config, domain) {
+            AcmeChallenge::Http01 => None,
+            AcmeChallenge::Dns01 => Some(self.dns01.as_ref().ok_or_else(|| {
+                Error::Acme(
+                    AcmeFailure::Other,
+                    format!(
+                        "the DNS-01 challenge of {domain} has no DNS provider (lets_encrypt.dns01)"
+                    ),
+                )
+            })?),
+        };
+
+        metrics::ACME_ORDERS_STARTED.inc();
+        metrics::ACME_ORDERS_PENDING.inc();
+        let result = Self::create_order_for_domain(
+            domain,
+            account,
+            self.challenge_options(),
+            &self.orders,
+            dns01,
+            self.shutdown.as_ref(),
+        )
+        .await;
+        metrics::ACME_ORDERS_PENDING.dec();
+
+        // Interrupted, the order is resumed on the next start
+        if result.is_err() && self.is_stopping() {
+            return result.map(|_| ());
+        }
+
+        // Completed or abandoned, the order is not resumed
+        Self::end_order(&self.orders, domain);
+
+        match &result {
+            Ok(_) => {
+                metrics::ACME_ORDERS_COMPLETED.inc();
+                if action == CertificateAction::Renewed {
+                    metrics::ACME_ORDERS_RENEWED.inc();
+                }
+            }
+            Err(err) => {
+                metrics::ACME_ORDERS_FAILED
+                    .with_label_values(&[failure_reason(err)])
+                    .inc();
+
+                if let Some(pause) = self.rate_limit.record(err) {
+                    tracing::warn!(
+                        "the ACME provider rate limited the order of {domain}: no order is placed for {}s, until {}",
+                        pause.as_secs(),
+                        ::time::OffsetDateTime::from(SystemTime::now() + pause)
+                    );
+                }
+            }
+        }
+
+        if let Some(webhook) = &self.webhook {
+            let event = match &result {
+                Ok(expires_at) => CertificateEvent::success(domain, action, *expires_at),
+                Err(err) => CertificateEvent::failure(domain, action, err),
+            };
+            webhook.notify(event);
+        }
+
+        result.map(|_| ())
+    }
+
+    /// Forgets the order of the domain and stops answering its challenge
+    fn end_order(orders: &OrderStore, domain: &str) {
+        orders.remove(domain);
+        stores::remove_challenge(domain);
+    }
+
+    /// How long the challenge of an order that never ends is answered
+    fn challenge_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.lets_encrypt.challenge_ttl_secs.unwrap_or(3600))
+    }
+
+    /// Periodically removes the challenges (and persisted orders) outliving the
+    /// challenge TTL: their orders were abandoned without ending
+    async fn sweep_abandoned_orders(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = time::interval(Duration::from_secs(60));
+        let ttl = self.challenge_ttl();
+
+        while tick_or_shutdown(&mut interval, &mut shutdown).await {
+            let removed = stores::remove_stale_challenges(ttl, Instant::now());
+            if removed > 0 {
+                tracing::info!("removed {removed} stale ACME challenge(s)");
+            }
+
+            for pending in self.orders.load() {
+                if pending.age() >= ttl {
+                    tracing::info!("removing the abandoned order of {}", pending.domain);
+                    self.orders.remove(&pending.domain);
+                }
+            }
+        }
+    }
+
+    /// Resumes the orders interrupted by a restart. Their challenges are answered
+    /// right away (the provider may still be validating them) and the orders are
+    /// placed again, getting back the pending order instead of a new one.
+    async fn resume_orders(&self, account: &Account<FilePersist>) {
+        let (expired, pending_orders): (Vec<_>, Vec<_>) = self
+            .orders
+            .load()
+            .into_iter()
+            .partition(PendingOrder::is_expired);
+        for pending in expired {
+            self.orders.remove(&pending.domain);
+        }
+
+        queue::process(
+            pending_orders,
+            self.max_concurrent_orders(),
+            |pending| async move {
+                let domain = pending.domain.clone();
+                info!("resuming the interrupted order of {domain}");
+                stores::insert_challenge(
+                    domain.clone(),
+                    (pending.token, pending.key_authorization),
+                );
+
+                let action = match account.certificate(&domain) {
+                    Ok(Some(_)) => CertificateAction::Renewed,
+                    _ => CertificateAction::Issued,
+                };
+                if let Err(err) = self.order_certificate(&domain, account, action).await {
+                    tracing::error!("failed to resume the order of {domain}: {err}");
+                }
+            },
+        )
+        .await;
+    }
+
+    /// Loads or issues the certificates of the configured hosts on boot (`preissue`),
+    /// so their first requests do not wait for an order. The hosts with a
+    /// persisted certificate only load it.
+    async fn preissue_certificates(&self, account: &Account<FilePersist>) {
+        let hosts = preissued_hosts(&self.config, |host| {
+            stores::get_certificate_by_key(host).is_some()
+        });
+        if hosts.is_empty() {
+            return;
+        }
+
+        info!(
+            "preparing the certificates of {} configured host(s)",
+            hosts.len()
+        );
+        queue::process(
+            hosts,
+            self.max_concurrent_orders(),
+            |(domain, self_signed_on_failure)| async move {
+                self.handle_certificate_for_domain(&domain, account, self_signed_on_failure)
+                    .await;
+            },
+        )
+        .await;
+    }
+
+    /// Loads the persisted certificates of the configured hosts on boot, before
+    /// any order is placed, so a restart does not issue them again. The expired
+    /// ones are ordered again, the ones in the renewal window renewed.
+    async fn load_persisted_certificates(&self, account: &Account<FilePersist>) {
+        let certificates = stores::get_certificates();
+        let hosts = preissued_hosts(&self.config, |host| certificates.contains_key(host))
+            .into_iter()
+            .filter(|(host, _)| matches!(account.certificate(host), Ok(Some(_))))
+            .collect::<Vec<_>>();
+        if hosts.is_empty() {
+            return;
+        }
+
+        info!(
+            "loading the persisted certificates of {} host(s)",
+            hosts.len()
+        );
+        queue::process(
+            hosts,
+            self.max_concurrent_orders(),
+            |(domain, self_signed_on_failure)| async move {
+                self.handle_certificate_for_domain(&domain, account, self_signed_on_failure)
+                    .await;
+            },
+        )
+        .await;
+    }
+
+    /// Loads or orders the certificates of `lets_encrypt.certificates` (ex: the
+    /// wildcard ones), which have no route of their own
+    async fn prepare_configured_certificates(&self, account: &Account<FilePersist>) {
+        let domains = configured_domains(&self.config).collect::<Vec<_>>();
+        if domains.is_empty() {
+            return;
+        }
+
+        info!("preparing {} configured certificate(s)", domains.len());
+        queue::process(domains, self.max_concurrent_orders(), |domain| async move {
+            self.handle_certificate_for_domain(&domain, account, false)
+                .await;
+        })
+        .await;
     }
 
     /// Watch for route changes and create or update certificates for new routes
-    async fn watch_for_route_changes(&self, account: &Account<FilePersist>) {
+    async fn watch_for_route_changes(
+        &self,
+        account: &Account<FilePersist>,
+        mut shutdown: ShutdownWatch,
+    ) {
         let mut interval = time::interval(Duration::from_secs(20));
 
-        loop {
-            interval.tick().await;
+        while tick_or_shutdown(&mut interval, &mut shutdown).await {
             tracing::debug!("checking for new routes to create certificates for");
-            for (key, value) in stores::get_routes().iter() {
-                if stores::get_certificates().contains_key(key) {
-                    continue;
-                }
+            // The hosts served by a wildcard certificate need none of their own,
+            // the wildcard and regex hosts of the routes cannot get one here
+            let new_routes = stores::get_routes()
+                .iter()
+                .filter(|(key, _)| !matching::is_host_pattern(key))
+                .filter(|(key, _)| stores::get_certificate_by_key(key).is_none())
+                .map(|(key, value)| (key.clone(), value.self_signed_certificate))
+                .collect();
 
-                Self::handle_certificate_for_domain(key, account, value.self_signed_certificate);
-            }
+            queue::process(
+                new_routes,
+                self.max_concurrent_orders(),
+                |(domain, self_signed_on_failure)| async move {
+                    self.handle_certificate_for_domain(&domain, account, self_signed_on_failure)
+                        .await;
+                },
+            )
+            .await;
         }
     }
 
     /// Check for certificates expiration and renew them if needed
-    async fn check_for_certificates_expiration(&self, account: &Account<FilePersist>) {
+    async fn check_for_certificates_expiration(
+        &self,
+        account: &Account<FilePersist>,
+        mut shutdown: ShutdownWatch,
+    ) {
         let mut interval = time::interval(Duration::from_secs(84_600));
 
-        loop {
-            interval.tick().await;
+        while tick_or_shutdown(&mut interval, &mut shutdown).await {
             tracing::debug!("checking for certificates to renew");
-            for (domain, _) in stores::get_routes().iter() {
-                let Ok(Some(cert)) = account.certificate(domain) else {
-                    continue;
-                };
+            let expiring = stores::get_routes()
+                .iter()
+                .map(|(domain, _)| domain.clone())
+                .filter(|domain| !matching::is_host_pattern(domain))
+                .chain(configured_domains(&self.config))
+                .filter(|domain| !stores::has_manual_certificate(domain))
+                .filter(|domain| {
+                    let Ok(Some(cert)) = account.certificate(domain) else {
+                        return false;
+                    };
 
-                let valid_days_left = cert.valid_days_left();
-                tracing::info!("certificate for domain {domain} expires in {valid_days_left} days",);
+                    let valid_days_left = cert.valid_days_left();
+                    tracing::info!(
+                        "certificate for domain {domain} expires in {valid_days_left} days",
+                    );
 
-                // Nothing to do
-                if valid_days_left > 5 {
-                    continue;
-                }
+                    valid_days_left <= i64::from(RENEWAL_WINDOW_DAYS)
+                })
+                .collect();
 
-                Self::create_order_for_domain(domain, account)
-                    .map_err(|e| anyhow!("Failed to create order for {domain}: {e}"))
-                    .unwrap();
-            }
+            queue::process(
+                expiring,
+                self.max_concurrent_orders(),
+                |domain| async move {
+                    if let Err(e) = self
+                        .order_certificate(&domain, account, CertificateAction::Renewed)
+                        .await
+                    {
+                        tracing::error!("failed to renew certificate for {domain}: {e}");
+                    }
+                },
+            )
+            .await;
         }
     }
 
-    fn handle_certificate_for_domain(
+    async fn handle_certificate_for_domain(
+        &self,
         domain: &str,
         account: &Account<FilePersist>,
         self_signed_on_failure: bool,
     ) {
+        // The certificate provided as files (`tls.certificates`) is served instead
+        if stores::has_manual_certificate(domain) {
+            return;
+        }
+
         match account.certificate(domain) {
             Ok(Some(cert)) => {
                 // Certificate already exists
@@ -262,14 +745,55 @@ impl LetsencryptService {
                     return;
                 }
 
-                if let Err(err) =
-                    Self::insert_certificate(domain, cert.certificate(), cert.private_key())
-                {
-                    tracing::error!("failed to insert certificate for domain {domain}: {err}");
-                };
+                let freshness = Self::parse_x509_cert(cert.certificate())
+                    .and_then(|x509| Freshness::of(x509.not_after()));
+                match freshness {
+                    Ok(Freshness::Expired) => {
+                        info!("the persisted certificate of {domain} expired, ordering a new one");
+                        if self
+                            .order_certificate(domain, account, CertificateAction::Renewed)
+                            .await
+                            .is_err()
+                        {
+                            Self::create_self_signed_certificate(domain, self_signed_on_failure)
+                                .ok();
+                        }
+                        return;
+                    }
+                    Ok(freshness) => {
+                        if let Err(err) =
+                            Self::insert_certificate(domain, cert.certificate(), cert.private_key())
+                        {
+                            tracing::error!(
+                                "failed to insert certificate for domain {domain}: {err}"
+                            );
+                            return;
+                        }
+
+                        // Served until the renewed one replaces it
+                        if freshness == Freshness::Renewable {
+                            info!(
+                                "the persisted certificate of {domain} expires soon, renewing it"
+                            );
+                            if let Err(err) = self
+                                .order_certificate(domain, account, CertificateAction::Renewed)
+                                .await
+                            {
+                                tracing::error!("failed to renew certificate for {domain}: {err}");
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        tracing::error!("invalid persisted certificate for domain {domain}: {err}");
+                    }
+                }
             }
             Ok(None) => {
-                if Self::create_order_for_domain(domain, account).is_err() {
+                if self
+                    .order_certificate(domain, account, CertificateAction::Issued)
+                    .await
+                    .is_err()
+                {
                     Self::create_self_signed_certificate(domain, self_signed_on_failure).ok();
                 }
             }
@@ -278,14 +802,194 @@ impl LetsencryptService {
     }
 }
 
+/// Waits for `delay`, or until the shutdown started
+async fn sleep_or_shutdown(delay: Duration, shutdown: Option<&ShutdownWatch>) {
+    let Some(mut shutdown) = shutdown.cloned() else {
+        return time::sleep(delay).await;
+    };
+
+    if *shutdown.borrow() {
+        return;
+    }
+
+    tokio::select! {
+        () = time::sleep(delay) => {}
+        _ = shutdown.changed() => {}
+    }
+}
+
+/// The domains of `lets_encrypt.certificates`
+fn configured_domains(config: &Config) -> impl Iterator<Item = String> + '_ {
+    config
+        .lets_encrypt
+        .certificates
+        .iter()
+        .map(|certificate| certificate.domain.to_lowercase())
+}
+
+/// The challenge of the order of the domain: DNS-01 for the wildcards, or the
+/// one of its configured certificate (or route)
+fn challenge_of(config: &Config, domain: &str) -> AcmeChallenge {
+    if domain.starts_with("*.") {
+        return AcmeChallenge::Dns01;
+    }
+
+    let configured = config
+        .lets_encrypt
+        .certificates
+        .iter()
+        .find(|certificate| certificate.domain.eq_ignore_ascii_case(domain))
+        .map(|certificate| certificate.challenge);
+    let routed = || {
+        config
+            .routes
+            .iter()
+            .find(|route| matching::normalize_host(&route.host) == domain)
+            .and_then(|route| route.ssl_certificate.as_ref()?.challenge)
+    };
+
+    configured.or_else(routed).unwrap_or_default()
+}
+
+/// The configured hosts without a certificate yet (and whether their route
+/// falls back to a self-signed one), once each. The wildcard and regex hosts
+/// are left out.
+fn preissued_hosts(config: &Config, has_certificate: impl Fn(&str) -> bool) -> Vec<(String, bool)> {
+    let mut hosts: Vec<(String, bool)> = Vec::new();
+    for route in &config.routes {
+        if matching::is_host_pattern(&route.host) {
+            continue;
+        }
+
+        let host = matching::normalize_host(&route.host).into_owned();
+        if has_certificate(&host) || hosts.iter().any(|(known, _)| *known == host) {
+            continue;
+        }
+
+        let self_signed_on_failure = route
+            .ssl_certificate
+            .as_ref()
+            .and_then(|ssl| ssl.self_signed_on_failure)
+            .unwrap_or(false);
+        hosts.push((host, self_signed_on_failure));
+    }
+    hosts
+}
+
+fn lets_encrypt_url(config: &Config) -> DirectoryUrl {
+    if let Some(url) = config.lets_encrypt.directory_url.as_deref() {
+        return DirectoryUrl::Other(url);
+    }
+
+    match config.lets_encrypt.staging {
+        Some(false) => DirectoryUrl::LetsEncrypt,
+        _ => DirectoryUrl::LetsEncryptStaging,
+    }
+}
+
+/// The URL of an ACME directory, for logging
+fn directory_url_str<'a>(url: &DirectoryUrl<'a>) -> &'a str {
+    match url {
+        DirectoryUrl::LetsEncrypt => "https://acme-v02.api.letsencrypt.org/directory",
+        DirectoryUrl::LetsEncryptStaging => {
+            "https://acme-staging-v02.api.letsencrypt.org/directory"
+        }
+        DirectoryUrl::Other(url) => url,
+    }
+}
+
+fn lets_encrypt_directory(config: &Config) -> PathBuf {
+    let provider = config.lets_encrypt.directory_url.as_deref().map(|url| {
+        url.parse::<http::Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_string))
+            .unwrap_or_else(|| "custom".to_string())
+    });
+
+    let suffix = match (provider.as_deref(), config.lets_encrypt.staging) {
+        (Some(host), _) => host,
+        (None, Some(false)) => "production",
+        (None, _) => "staging",
+    };
+
+    let path = config.paths.lets_encrypt.join(suffix);
+
+    if let Ok(res) = path::absolute(&path) {
+        return res;
+    }
+
+    path
+}
+
+/// Revokes the certificate issued to the domain through ACME, then removes it
+/// from the store and from the disk. While the domain has a route, a new
+/// certificate is ordered by the next check of the routes.
+/// Blocking: the ACME client does not run on the async runtime.
+pub fn revoke_certificate(config: &Config, domain: &str) -> Result<(), Error> {
+    let acme_error = |err: acme_v2::Error| {
+        Error::Acme(
+            AcmeFailure::from(&err),
+            format!("failed to revoke the certificate of {domain}: {err}"),
+        )
+    };
+
+    let dir = lets_encrypt_directory(config);
+    let persist = FilePersist::new(&dir);
+    let account = acme_v2::Directory::from_url(persist, lets_encrypt_url(config))
+        .and_then(|directory| directory.account(&config.lets_encrypt.email))
+        .map_err(acme_error)?;
+
+    let certificate = account
+        .certificate(domain)
+        .map_err(acme_error)?
+        .ok_or_else(|| {
+            Error::Acme(
+                AcmeFailure::NotFound,
+                format!("no ACME certificate is stored for {domain}"),
+            )
+        })?;
+
+    account
+        .revoke_certificate(&certificate, RevocationReason::Unspecified)
+        .map_err(acme_error)?;
+    info!("revoked the certificate of {domain}");
+
+    // Not loaded again (nor renewed) by the service, a new one is ordered instead.
+    // The name of the file is the one of the persistence of the ACME client.
+    let key = PersistKey::new(&config.lets_encrypt.email, PersistKind::Certificate, domain);
+    let path = dir.join(key.to_string()).with_extension("crt");
+    if let Err(err) = std::fs::remove_file(&path) {
+        tracing::warn!("failed to remove the revoked certificate {path:?}: {err}");
+    }
+
+    stores::remove_certificate(domain);
+    Ok(())
+}
+
+/// The coarse reason of an order error, used as metric label
+fn failure_reason(err: &Error) -> &'static str {
+    err.acme_failure().unwrap_or(AcmeFailure::Other).as_str()
+}
+
+/// An error of the ACME client, classified by the problem of the provider
+fn acme_error(err: acme_v2::Error) -> Error {
+    Error::Acme(AcmeFailure::from(&err), err.to_string())
+}
+
+/// What a challenge failed on: a failure of its validation, unless the
+/// provider rate limited it or could not be reached
+fn challenge_failure(err: &anyhow::Error) -> AcmeFailure {
+    match err.downcast_ref::<acme_v2::Error>().map(AcmeFailure::from) {
+        Some(failure @ (AcmeFailure::RateLimited | AcmeFailure::Network)) => failure,
+        _ => AcmeFailure::Validation,
+    }
+}
+
 #[async_trait]
 impl Service for LetsencryptService {
-    async fn start_service(&mut self, _fds: Option<ListenFds>, mut _shutdown: ShutdownWatch) {
-        if self.config.lets_encrypt.enabled.is_some_and(|v| !v) {
-            // Nothing to do, lets encrypt is disabled
-            return;
-        }
+    async fn start_service(&mut self, _fds: Option<ListenFds>, shutdown: ShutdownWatch) {
         info!("started LetsEncrypt service");
+        self.shutdown = Some(shutdown.clone());
 
         // Get directory based on whether we are running on staging/production
         // LetsEncrypt configurations
@@ -306,16 +1010,70 @@ impl Service for LetsencryptService {
         // Key-Value Store
         let persist = acme_v2::persist::FilePersist::new(certificates_dir);
 
-        let dir = acme_v2::Directory::from_url(persist, self.get_lets_encrypt_url())
-            .expect("failed to create LetsEncrypt directory");
+        let url = self.get_lets_encrypt_url();
+        let dir = match acme_v2::Directory::from_url(persist.clone(), url.clone()) {
+            Ok(dir) => dir,
+            Err(err) => {
+                tracing::error!(
+                    "failed to load the ACME directory {}: {err}",
+                    directory_url_str(&url)
+                );
+                return;
+            }
+        };
 
-        let account = dir
-            .account(&self.config.lets_encrypt.email)
-            .expect("failed to create or retrieve existing account");
+        // Providers requiring an external account binding reject accounts created without it
+        let lets_encrypt = &self.config.lets_encrypt;
+        let requires_eab = dir
+            .api_directory()
+            .meta
+            .as_ref()
+            .is_some_and(acme_v2::api::ApiDirectoryMeta::externalAccountRequired);
+
+        match (
+            lets_encrypt.eab_kid.as_deref(),
+            lets_encrypt.eab_hmac_key.as_deref(),
+        ) {
+            (Some(kid), Some(hmac_key)) => {
+                let account = ExternalAccount { kid, hmac_key };
+                if let Err(err) =
+                    eab::register(&persist, dir.api_directory(), &lets_encrypt.email, &account)
+                        .await
+                {
+                    tracing::error!("failed to create the ACME account: {err}");
+                    return;
+                }
+            }
+            _ if requires_eab => {
+                tracing::error!("the ACME provider requires an external account binding: set lets_encrypt.eab_kid and lets_encrypt.eab_hmac_key");
+                return;
+            }
+            _ => {}
+        }
+
+        let account = match dir.account(&self.config.lets_encrypt.email) {
+            Ok(account) => account,
+            Err(err) => {
+                tracing::error!(
+                    "failed to create or retrieve the ACME account of {}: {err}",
+                    directory_url_str(&url)
+                );
+                return;
+            }
+        };
+
+        self.resume_orders(&account).await;
+        self.load_persisted_certificates(&account).await;
+        self.prepare_configured_certificates(&account).await;
+
+        if self.config.lets_encrypt.preissue.unwrap_or(false) {
+            self.preissue_certificates(&account).await;
+        }
 
         let _ = tokio::join!(
-            self.watch_for_route_changes(&account),
-            self.check_for_certificates_expiration(&account)
+            self.watch_for_route_changes(&account, shutdown.clone()),
+            self.check_for_certificates_expiration(&account, shutdown.clone()),
+            self.sweep_abandoned_orders(shutdown)
         );
     }
 
@@ -327,3 +1085,189 @@ impl Service for LetsencryptService {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_retry_delay() {
+        let options = ChallengeOptions {
+            attempts: 5,
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(300),
+        };
+
+        assert_eq!(options.retry_delay(1), Duration::from_secs(5));
+        assert_eq!(options.retry_delay(2), Duration::from_secs(10));
+        assert_eq!(options.retry_delay(3), Duration::from_secs(20));
+        assert_eq!(options.retry_delay(10), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_ended_order_forgets_its_challenge() {
+        let dir = std::env::temp_dir().join(format!("proksi-orders-{}", uuid::Uuid::new_v4()));
+        let orders = OrderStore::new(&dir);
+        let domain = "issued.example.com";
+
+        let pending = PendingOrder::new(domain, vec![], "token", "token.thumbprint");
+        orders.save(&pending).unwrap();
+        stores::insert_challenge(
+            domain.to_string(),
+            (pending.token, pending.key_authorization),
+        );
+        assert!(stores::get_challenge_by_key(domain).is_some());
+
+        // Once the certificate is issued, the token is no longer answered
+        LetsencryptService::end_order(&orders, domain);
+        assert!(stores::get_challenge_by_key(domain).is_none());
+        assert_eq!(orders.get(domain), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_freshness_of_persisted_certificates() {
+        let freshness = |days: u32| Freshness::of(&Asn1Time::days_from_now(days).unwrap()).unwrap();
+
+        assert_eq!(freshness(30), Freshness::Valid);
+        assert_eq!(freshness(RENEWAL_WINDOW_DAYS - 1), Freshness::Renewable);
+        assert_eq!(freshness(0), Freshness::Expired);
+
+        let expired = Asn1Time::from_unix(0).unwrap();
+        assert_eq!(Freshness::of(&expired).unwrap(), Freshness::Expired);
+    }
+
+    #[test]
+    fn test_preissued_hosts() {
+        let mut config = Config::default();
+        config.routes = [
+            "Example.com",
+            "api.example.com",
+            "example.com.",
+            "cached.example.com",
+            "*.example.com",
+            "~^app-\\d+\\.example\\.com$",
+        ]
+        .into_iter()
+        .map(|host| {
+            serde_json::from_value(serde_json::json!({ "host": host, "upstreams": [] })).unwrap()
+        })
+        .collect();
+
+        // Each host once, skipping the ones already with a certificate and
+        // the patterns
+        let hosts = preissued_hosts(&config, |host| host == "cached.example.com");
+        assert_eq!(
+            hosts,
+            vec![
+                ("example.com".to_string(), false),
+                ("api.example.com".to_string(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directory_of_the_environment() {
+        let mut config = Config::default();
+        config.lets_encrypt.staging = None;
+        config.lets_encrypt.directory_url = None;
+        assert!(matches!(
+            lets_encrypt_url(&config),
+            DirectoryUrl::LetsEncryptStaging
+        ));
+        assert!(lets_encrypt_directory(&config).ends_with("staging"));
+
+        config.lets_encrypt.staging = Some(false);
+        assert!(matches!(
+            lets_encrypt_url(&config),
+            DirectoryUrl::LetsEncrypt
+        ));
+        assert!(lets_encrypt_directory(&config).ends_with("production"));
+
+        // The account (and its key) of each provider is kept apart
+        config.lets_encrypt.directory_url = Some("https://acme.zerossl.com/v2/DV90".into());
+        assert!(matches!(
+            lets_encrypt_url(&config),
+            DirectoryUrl::Other("https://acme.zerossl.com/v2/DV90")
+        ));
+        assert!(lets_encrypt_directory(&config).ends_with("acme.zerossl.com"));
+    }
+
+    #[test]
+    fn test_challenge_of_the_domains() {
+        let mut config = Config::default();
+        config.lets_encrypt.certificates = serde_json::from_value(serde_json::json!([
+            { "domain": "*.example.com", "challenge": "dns01" },
+            { "domain": "Apex.example.com", "challenge": "dns01" },
+        ]))
+        .unwrap();
+        config.routes = vec![
+            serde_json::from_value(serde_json::json!({
+                "host": "api.example.com",
+                "ssl_certificate": { "challenge": "dns01" },
+                "upstreams": [],
+            }))
+            .unwrap(),
+            serde_json::from_value(
+                serde_json::json!({ "host": "www.example.com", "upstreams": [] }),
+            )
+            .unwrap(),
+        ];
+
+        assert_eq!(challenge_of(&config, "*.example.com"), AcmeChallenge::Dns01);
+        assert_eq!(challenge_of(&config, "*.other.com"), AcmeChallenge::Dns01);
+        assert_eq!(
+            challenge_of(&config, "apex.example.com"),
+            AcmeChallenge::Dns01
+        );
+        assert_eq!(
+            challenge_of(&config, "api.example.com"),
+            AcmeChallenge::Dns01
+        );
+        assert_eq!(
+            challenge_of(&config, "www.example.com"),
+            AcmeChallenge::Http01
+        );
+        assert_eq!(challenge_of(&config, "unknown.com"), AcmeChallenge::Http01);
+
+        assert_eq!(
+            configured_domains(&config).collect::<Vec<_>>(),
+            ["*.example.com", "apex.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_failure_reason() {
+        let problem = |problem_type: &str| {
+            acme_v2::Error::ApiProblem(acme_v2::api::ApiProblem {
+                _type: problem_type.to_string(),
+                detail: None,
+                subproblems: None,
+            })
+        };
+
+        assert_eq!(
+            failure_reason(&acme_error(problem(
+                "urn:ietf:params:acme:error:rateLimited"
+            ))),
+            "rate_limited"
+        );
+        assert_eq!(
+            failure_reason(&acme_error(problem("httpReqError"))),
+            "network"
+        );
+        assert_eq!(
+            failure_reason(&Error::Config("local_zone cannot be empty".to_string())),
+            "other"
+        );
+
+        // The failures of the client while validating are validation failures
+        let invalid = anyhow::Error::from(acme_v2::Error::Other(
+            "Failed: Invalid response from http://example.com".to_string(),
+        ));
+        assert_eq!(challenge_failure(&invalid), AcmeFailure::Validation);
+        let unreachable = anyhow::Error::from(problem("httpReqError"));
+        assert_eq!(challenge_failure(&unreachable), AcmeFailure::Network);
+    }
+}
@@ -1 +1,7 @@
+pub mod dns01;
+mod eab;
 pub mod http01;
+mod orders;
+pub(crate) mod queue;
+mod rate_limit;
+mod webhook;
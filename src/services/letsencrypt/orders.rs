@@ -0,0 +1,172 @@
+//! In-flight ACME orders, persisted so that an order interrupted by a restart
+//! is resumed instead of started over. The ACME client cannot load an order
+//! from its URL: the order is placed again instead, which the provider answers
+//! with the pending order (and its authorizations) of the account.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How long the providers keep a pending order (Let's Encrypt: 7 days)
+const ORDER_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The state of an order waiting for its HTTP-01 challenge to be validated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingOrder {
+    pub domain: String,
+    /// URLs of the authorizations of the order
+    pub authorizations: Vec<String>,
+    /// Token of the HTTP-01 challenge
+    pub token: String,
+    /// Answer to the HTTP-01 challenge
+    pub key_authorization: String,
+    /// When the order was placed (unix timestamp)
+    pub created_at: u64,
+}
+
+impl PendingOrder {
+    pub fn new(
+        domain: &str,
+        authorizations: Vec<String>,
+        token: &str,
+        key_authorization: &str,
+    ) -> Self {
+        PendingOrder {
+            domain: domain.to_string(),
+            authorizations,
+            token: token.to_string(),
+            key_authorization: key_authorization.to_string(),
+            created_at: unix_now(),
+        }
+    }
+
+    /// Whether the provider has already dropped the order
+    pub fn is_expired(&self) -> bool {
+        self.age() >= ORDER_LIFETIME
+    }
+
+    /// Time since the order was placed
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.created_at))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Pending orders, one JSON file per domain
+#[derive(Debug, Clone, Default)]
+pub struct OrderStore {
+    dir: PathBuf,
+}
+
+impl OrderStore {
+    /// Orders are kept in the `orders` folder of the given (certificates) directory
+    pub fn new(dir: &Path) -> Self {
+        OrderStore {
+            dir: dir.join("orders"),
+        }
+    }
+
+    fn path(&self, domain: &str) -> PathBuf {
+        self.dir.join(format!("{domain}.json"))
+    }
+
+    /// Saves the order, replacing the previous order of the domain
+    pub fn save(&self, order: &PendingOrder) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(&self.dir)?;
+
+        // Written aside first, a restart never leaves a partial file behind
+        let path = self.path(&order.domain);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(order)?)?;
+        fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// The pending order of the domain, if any
+    pub fn get(&self, domain: &str) -> Option<PendingOrder> {
+        let content = fs::read(self.path(domain)).ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    /// Forgets the order of the domain, once completed or abandoned
+    pub fn remove(&self, domain: &str) {
+        let path = self.path(domain);
+        if path.exists() {
+            if let Err(err) = fs::remove_file(&path) {
+                tracing::warn!("failed to remove the pending order {path:?}: {err}");
+            }
+        }
+    }
+
+    /// Every pending order (unreadable files are skipped)
+    pub fn load(&self) -> Vec<PendingOrder> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+
+        entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| {
+                let order = fs::read(&path)
+                    .ok()
+                    .and_then(|content| serde_json::from_slice(&content).ok());
+                if order.is_none() {
+                    tracing::warn!("ignoring the unreadable pending order {path:?}");
+                }
+                order
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_store() {
+        let dir = std::env::temp_dir().join(format!("proksi-orders-{}", uuid::Uuid::new_v4()));
+        let store = OrderStore::new(&dir);
+        assert!(store.load().is_empty());
+
+        let order = PendingOrder::new(
+            "example.com",
+            vec!["https://acme.example/authz/1".to_string()],
+            "token",
+            "token.thumbprint",
+        );
+        store.save(&order).unwrap();
+        store
+            .save(&PendingOrder::new("other.com", vec![], "t", "t.k"))
+            .unwrap();
+        fs::write(dir.join("orders/broken.json"), b"{").unwrap();
+
+        assert_eq!(store.get("example.com"), Some(order.clone()));
+        assert_eq!(store.load().len(), 2);
+        assert!(!order.is_expired());
+
+        store.remove("example.com");
+        assert_eq!(store.get("example.com"), None);
+        assert_eq!(store.load().len(), 1);
+
+        let expired = PendingOrder {
+            created_at: unix_now() - ORDER_LIFETIME.as_secs(),
+            ..order
+        };
+        assert!(expired.is_expired());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+//! Bounded processing of the ACME orders. On a first boot with many new hosts,
+//! placing all their orders at once quickly hits the rate limits of the
+//! provider: at most `max_concurrent_orders` orders are placed at once, the
+//! other hosts wait their turn (in order).
+
+use std::future::Future;
+
+use futures_util::{stream, StreamExt};
+
+/// Runs `order` for each item, at most `max_concurrent` at once.
+/// Returns once every item is processed. `order` runs its blocking calls on
+/// the blocking threads (`spawn_blocking`), so a batch does not hold the
+/// runtime of the other background services.
+pub async fn process<T, F, Fut>(items: Vec<T>, max_concurrent: usize, order: F)
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let workers = max_concurrent.max(1).min(items.len());
+    if items.len() > workers {
+        tracing::info!(
+            "placing {workers} ACME orders at once, {} waiting their turn",
+            items.len() - workers
+        );
+    }
+
+    stream::iter(items)
+        .for_each_concurrent(workers.max(1), order)
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_process_bounds_concurrent_orders() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let running = AtomicUsize::new(0);
+            let max_running = AtomicUsize::new(0);
+            let done = Mutex::new(Vec::new());
+            let (running, max_running, done) = (&running, &max_running, &done);
+
+            process((0..10).collect(), 3, |item: usize| async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                done.lock().unwrap().push(item);
+            })
+            .await;
+
+            assert_eq!(max_running.load(Ordering::SeqCst), 3);
+            let mut done = done.lock().unwrap().clone();
+            done.sort_unstable();
+            assert_eq!(done, (0..10).collect::<Vec<_>>());
+
+            // One at a time, in order
+            let done = Mutex::new(Vec::new());
+            process(vec![1, 2, 3], 0, |item| {
+                done.lock().unwrap().push(item);
+                async {}
+            })
+            .await;
+            assert_eq!(done.into_inner().unwrap(), vec![1, 2, 3]);
+        });
+    }
+}
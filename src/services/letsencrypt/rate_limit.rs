@@ -0,0 +1,112 @@
+//! Pause of the ACME orders once the provider rate limits them. Retrying right
+//! away would only keep the limit from expiring.
+//!
+//! The ACME client does not expose the `Retry-After` header of its responses:
+//! the time is read from the problem detail instead, which Let's Encrypt ends
+//! with `retry after <date> UTC`.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::error::{AcmeFailure, Error};
+
+/// Pause when the provider does not tell when to retry (Let's Encrypt limits
+/// are mostly counted per hour or more)
+const DEFAULT_PAUSE: Duration = Duration::from_secs(60 * 60);
+
+/// The time given by `retry after YYYY-MM-DD HH:MM:SS UTC` in the message
+fn retry_after(message: &str) -> Option<SystemTime> {
+    let lowercase = message.to_lowercase();
+    let start = lowercase.find("retry after ")? + "retry after ".len();
+    let datetime = message.get(start..start + "YYYY-MM-DD HH:MM:SS".len())?;
+
+    let (date, clock) = datetime.split_once(' ')?;
+    let mut date = date.split('-').map(str::parse::<u16>);
+    let mut clock = clock.split(':').map(str::parse::<u8>);
+
+    let year = i32::from(date.next()?.ok()?);
+    let month = time::Month::try_from(u8::try_from(date.next()?.ok()?).ok()?).ok()?;
+    let day = u8::try_from(date.next()?.ok()?).ok()?;
+    let datetime = time::Date::from_calendar_date(year, month, day)
+        .ok()?
+        .with_hms(
+            clock.next()?.ok()?,
+            clock.next()?.ok()?,
+            clock.next()?.ok()?,
+        )
+        .ok()?
+        .assume_utc();
+
+    let secs = u64::try_from(datetime.unix_timestamp()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// When the next order can be placed, once rate limited
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    paused_until: Mutex<Option<SystemTime>>,
+}
+
+impl RateLimit {
+    /// The remaining pause of the orders, if any
+    pub fn remaining(&self) -> Option<Duration> {
+        let paused_until = (*self.paused_until.lock().ok()?)?;
+        paused_until.duration_since(SystemTime::now()).ok()
+    }
+
+    /// Pauses the orders when the error is a rate limit, returns the pause
+    pub fn record(&self, err: &Error) -> Option<Duration> {
+        let Error::Acme(AcmeFailure::RateLimited, message) = err else {
+            return None;
+        };
+
+        let now = SystemTime::now();
+        let until = retry_after(message)
+            .filter(|until| *until > now)
+            .unwrap_or(now + DEFAULT_PAUSE);
+
+        if let Ok(mut paused_until) = self.paused_until.lock() {
+            *paused_until = Some(until);
+        }
+
+        until.duration_since(now).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_after() {
+        let message = "urn:ietf:params:acme:error:rateLimited: Error creating new order :: too many certificates (5) already issued for this exact set of domains in the last 168h0m0s, retry after 2024-05-17 00:36:51 UTC: see https://letsencrypt.org/docs/rate-limits/";
+        assert_eq!(
+            retry_after(message),
+            Some(UNIX_EPOCH + Duration::from_secs(1_715_906_211))
+        );
+
+        assert_eq!(retry_after("retry after tomorrow"), None);
+        assert_eq!(retry_after("retry after 2024-13-17 00:36:51 UTC"), None);
+    }
+
+    #[test]
+    fn test_rate_limit_pauses_orders() {
+        let rate_limit = RateLimit::default();
+        let err = Error::Acme(AcmeFailure::Other, "Certificate is empty".to_string());
+        assert_eq!(rate_limit.record(&err), None);
+        assert_eq!(rate_limit.remaining(), None);
+
+        // A date in the past falls back to the default pause
+        let pause = rate_limit
+            .record(&Error::Acme(
+                AcmeFailure::RateLimited,
+                "urn:ietf:params:acme:error:rateLimited: retry after 2020-01-01 00:00:00 UTC"
+                    .to_string(),
+            ))
+            .unwrap();
+        assert!(pause > DEFAULT_PAUSE - Duration::from_secs(5));
+        assert!(rate_limit.remaining().is_some());
+    }
+}
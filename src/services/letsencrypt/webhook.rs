@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use openssl::x509::X509;
+use serde::Serialize;
+
+use crate::{error::Error, stores::certificates};
+
+/// Maximum time to wait for the webhook endpoint to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The certificate operation that triggered an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertificateAction {
+    Issued,
+    Renewed,
+}
+
+/// The JSON payload sent to the webhook on each certificate lifecycle event
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateEvent {
+    pub host: String,
+    pub action: CertificateAction,
+    pub success: bool,
+    /// Expiration of the certificate (unix timestamp, in seconds)
+    pub expires_at: Option<i64>,
+    pub error: Option<String>,
+}
+
+impl CertificateEvent {
+    pub fn success(host: &str, action: CertificateAction, expires_at: Option<i64>) -> Self {
+        Self {
+            host: host.to_string(),
+            action,
+            success: true,
+            expires_at,
+            error: None,
+        }
+    }
+
+    pub fn failure(host: &str, action: CertificateAction, error: &Error) -> Self {
+        Self {
+            host: host.to_string(),
+            action,
+            success: false,
+            expires_at: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Returns the expiration (unix timestamp) of the given certificate
+pub fn expires_at(cert: &X509) -> Option<i64> {
+    certificates::unix_timestamp(cert.not_after())
+}
+
+/// Delivers certificate events to the configured `webhook_url`
+pub struct Webhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Self, anyhow::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            url: url.to_string(),
+            client,
+        })
+    }
+
+    /// Sends the event in the background. Delivery failures are only logged
+    /// so they never block or fail the certificate operation itself.
+    pub fn notify(&self, event: CertificateEvent) {
+        let request = self.client.post(&self.url).json(&event);
+
+        tokio::spawn(async move {
+            match request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+            {
+                Ok(_) => tracing::debug!("certificate event sent for {}", event.host),
+                Err(err) => {
+                    tracing::error!("failed to send certificate event for {}: {err}", event.host);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+
+    use super::*;
+    use crate::error::AcmeFailure;
+
+    #[test]
+    fn test_event_payload() {
+        let event = CertificateEvent::success("example.com", CertificateAction::Renewed, Some(42));
+        assert_eq!(
+            serde_json::to_value(event).unwrap(),
+            serde_json::json!({
+                "host": "example.com",
+                "action": "renewed",
+                "success": true,
+                "expires_at": 42,
+                "error": null
+            })
+        );
+
+        let err = Error::Acme(AcmeFailure::RateLimited, "rate limited".to_string());
+        let event = CertificateEvent::failure("example.com", CertificateAction::Issued, &err);
+        let value = serde_json::to_value(event).unwrap();
+        assert_eq!(value["action"], "issued");
+        assert_eq!(value["success"], false);
+        assert_eq!(value["error"], "rate limited");
+    }
+
+    #[test]
+    fn test_expires_at() {
+        let key =
+            openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix(1_700_000_000).unwrap())
+            .unwrap();
+        builder
+            .sign(&key, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+
+        assert_eq!(expires_at(&builder.build()), Some(1_700_000_000));
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use http::{header, Method, Response, StatusCode};
+use pingora::{
+    apps::http_app::ServeHttp, protocols::http::ServerSession, server::Server, services::listening,
+};
+use prometheus::{Encoder, TextEncoder};
+
+use crate::{
+    config::Config,
+    metrics,
+    stores::{self, certificates},
+};
+
+/// Adds the Prometheus metrics to the server, when enabled
+pub fn add_service(server: &mut Server, config: &Arc<Config>) {
+    if !config.metrics.enabled {
+        return;
+    }
+
+    let mut service = listening::Service::new("metrics".to_string(), HttpMetrics);
+    service.add_tcp(&config.metrics.bind);
+    server.add_service(service);
+}
+
+/// Prometheus text format on `/metrics`, served on its own address (see `metrics.bind`)
+pub struct HttpMetrics;
+
+/// Sets the gauges read from the stores (upstream health and certificate expiry).
+/// They are reset first, so the removed routes and certificates are not reported anymore.
+pub fn refresh_gauges() {
+    metrics::UPSTREAM_HEALTHY.reset();
+    for (host, route) in stores::get_routes().iter() {
+        let backends = route.load_balancer.backends();
+        for backend in backends.get_backend().iter() {
+            metrics::UPSTREAM_HEALTHY
+                .with_label_values(&[host.as_str(), &backend.addr.to_string()])
+                .set(i64::from(backends.ready(backend)));
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| {
+            i64::try_from(since.as_secs()).unwrap_or(i64::MAX)
+        });
+
+    metrics::CERTIFICATE_EXPIRY_DAYS.reset();
+    for (host, cert) in stores::get_certificates().iter() {
+        if let Some(not_after) = certificates::unix_timestamp(cert.leaf.not_after()) {
+            metrics::CERTIFICATE_EXPIRY_DAYS
+                .with_label_values(&[host.as_str()])
+                .set(days_until(not_after, now));
+        }
+    }
+}
+
+/// Whole days from `now` until the timestamp, negative once it passed
+fn days_until(timestamp: i64, now: i64) -> i64 {
+    (timestamp - now).div_euclid(86_400)
+}
+
+fn text_response(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl ServeHttp for HttpMetrics {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let request = session.req_header();
+        if request.uri.path() != "/metrics" {
+            return text_response(StatusCode::NOT_FOUND, "text/plain", b"not found\n".to_vec());
+        }
+        if request.method != Method::GET {
+            return text_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "text/plain",
+                b"method not allowed\n".to_vec(),
+            );
+        }
+
+        refresh_gauges();
+
+        let encoder = TextEncoder::new();
+        let mut body = Vec::new();
+        if let Err(err) = encoder.encode(&prometheus::gather(), &mut body) {
+            tracing::error!("failed to encode the metrics: {err}");
+            return text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain",
+                b"failed to encode the metrics\n".to_vec(),
+            );
+        }
+
+        text_response(StatusCode::OK, encoder.format_type(), body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_days_until() {
+        assert_eq!(days_until(86_400 * 3, 0), 3);
+        assert_eq!(days_until(86_400 * 3 - 1, 0), 2);
+        assert_eq!(days_until(0, 1), -1);
+    }
+
+    #[test]
+    fn test_refresh_gauges() {
+        let load_balancer =
+            pingora::lb::LoadBalancer::try_from_iter(["127.0.0.1:4101", "127.0.0.1:4102"]).unwrap();
+        let unhealthy = load_balancer
+            .backends()
+            .get_backend()
+            .iter()
+            .find(|backend| backend.addr.to_string() == "127.0.0.1:4102")
+            .cloned()
+            .unwrap();
+        load_balancer.backends().set_enable(&unhealthy, false);
+        stores::insert_route(
+            "gauges.metrics.test".to_string(),
+            stores::routes::RouteStoreContainer::new(load_balancer),
+        );
+        let certificate = certificates::Certificate::self_signed("gauges.metrics.test").unwrap();
+        stores::insert_certificate("gauges.metrics.test".to_string(), certificate);
+
+        refresh_gauges();
+        let healthy = |upstream: &str| {
+            metrics::UPSTREAM_HEALTHY
+                .with_label_values(&["gauges.metrics.test", upstream])
+                .get()
+        };
+        assert_eq!(healthy("127.0.0.1:4101"), 1);
+        assert_eq!(healthy("127.0.0.1:4102"), 0);
+
+        // Self-signed certificates are valid for a year
+        let days = metrics::CERTIFICATE_EXPIRY_DAYS
+            .with_label_values(&["gauges.metrics.test"])
+            .get();
+        assert!((364..=365).contains(&days), "{days}");
+
+        let mut body = Vec::new();
+        TextEncoder::new()
+            .encode(&prometheus::gather(), &mut body)
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains(
+            r#"proksi_upstream_healthy{route="gauges.metrics.test",upstream="127.0.0.1:4102"} 0"#
+        ));
+    }
+}
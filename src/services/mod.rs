@@ -1,21 +1,30 @@
-use std::sync::Arc;
+use std::{future::Future, pin::pin, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use config::FileWatcherService;
+use crl::CrlService;
 use discovery::RoutingService;
 use docker::LabelService;
 use letsencrypt::http01::LetsencryptService;
-use pingora::server::{ListenFds, ShutdownWatch};
-use tokio::sync::broadcast::Sender;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use stores::EvictionService;
+use tokio::{sync::broadcast::Sender, time::Interval};
 
 use crate::{config::Config, MsgProxy};
 
+pub mod admin;
 pub mod config;
+pub mod crl;
 pub mod discovery;
 pub mod docker;
 pub mod health_check;
 pub mod letsencrypt;
 pub mod logger;
+pub mod metrics;
+pub mod stores;
 
 /// Exploring: what if we grouped all the services into a single service using a single thread?
 pub struct BackgroundFunctionService {
@@ -29,23 +38,95 @@ impl BackgroundFunctionService {
     }
 }
 
+/// Waits for the next tick of the interval, `false` instead once the shutdown started
+pub(crate) async fn tick_or_shutdown(
+    interval: &mut Interval,
+    shutdown: &mut ShutdownWatch,
+) -> bool {
+    if *shutdown.borrow() {
+        return false;
+    }
+
+    tokio::select! {
+        _ = interval.tick() => true,
+        _ = shutdown.changed() => false,
+    }
+}
+
+/// Runs the services until they end, or until the shutdown starts. Once it
+/// started, the services still busy after `timeout` are dropped.
+/// Returns whether they were force-stopped.
+async fn run_until_shutdown(
+    services: impl Future<Output = ()>,
+    mut shutdown: ShutdownWatch,
+    timeout: Duration,
+) -> bool {
+    let mut services = pin!(services);
+    tokio::select! {
+        () = &mut services => return false,
+        _ = shutdown.changed() => {}
+    }
+
+    tracing::info!("shutting down background services");
+    if tokio::time::timeout(timeout, services).await.is_err() {
+        tracing::warn!("background services still running after {timeout:?}, force-stopping them");
+        return true;
+    }
+
+    false
+}
+
+/// Runs the given service, if it was enabled (and therefore constructed)
+async fn start_if_enabled<S: Service>(service: Option<S>, shutdown: ShutdownWatch) {
+    if let Some(mut service) = service {
+        service.start_service(None, shutdown).await;
+    }
+}
+
 #[async_trait]
-impl pingora::services::Service for BackgroundFunctionService {
+impl Service for BackgroundFunctionService {
     async fn start_service(&mut self, _fds: Option<ListenFds>, shutdown: ShutdownWatch) {
         let mut routing_service = RoutingService::new(self.config.clone(), self.broadcast.clone());
 
         let mut health_service = health_check::HealthService::new();
-        let mut docker_service = LabelService::new(self.config.clone(), self.broadcast.clone());
-        let mut letsencrypt_service = LetsencryptService::new(self.config.clone());
-        let mut config_server = FileWatcherService::new(self.config.clone());
+        let mut eviction_service = EvictionService::new(self.config.clone());
+        let mut crl_service = CrlService;
+        // Always running, to reload the routes on `SIGHUP`
+        let mut config_server =
+            FileWatcherService::new(self.config.clone(), self.broadcast.clone());
+
+        // Optional services are only created when enabled
+        let docker_service = self
+            .config
+            .docker
+            .enabled
+            .unwrap_or(true)
+            .then(|| LabelService::new(self.config.clone(), self.broadcast.clone()));
+        let letsencrypt_service = self
+            .config
+            .lets_encrypt
+            .enabled
+            .unwrap_or(true)
+            .then(|| LetsencryptService::new(self.config.clone()));
+
+        let services = async {
+            tokio::join!(
+                routing_service.start_service(None, shutdown.clone()),
+                health_service.start_service(None, shutdown.clone()),
+                eviction_service.start_service(None, shutdown.clone()),
+                crl_service.start_service(None, shutdown.clone()),
+                config_server.start_service(None, shutdown.clone()),
+                start_if_enabled(docker_service, shutdown.clone()),
+                start_if_enabled(letsencrypt_service, shutdown.clone()),
+            );
+        };
 
-        let _ = tokio::join!(
-            routing_service.start_service(None, shutdown.clone()),
-            health_service.start_service(None, shutdown.clone()),
-            config_server.start_service(None, shutdown.clone()),
-            docker_service.start_service(None, shutdown.clone()),
-            letsencrypt_service.start_service(None, shutdown),
-        );
+        // Stop as soon as a graceful shutdown (or upgrade) starts, so an instance
+        // that is being replaced does not keep ordering certificates or reloading
+        // routes while the new one runs the same services. The ones still busy
+        // after the grace period (ex: an ACME order) are dropped.
+        let timeout = Duration::from_secs(self.config.shutdown.background_services_timeout_secs);
+        run_until_shutdown(services, shutdown.clone(), timeout).await;
     }
 
     fn name(&self) -> &str {
@@ -56,3 +137,44 @@ impl pingora::services::Service for BackgroundFunctionService {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_force_stop_a_service_stuck_in_an_order() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let (stop, shutdown) = tokio::sync::watch::channel(false);
+
+            // An ACME order whose provider never answers, next to a service
+            // stopping with the shutdown
+            let order = letsencrypt::queue::process(vec!["stuck.example.com"], 1, |_| async {
+                tokio::task::spawn_blocking(|| std::thread::sleep(Duration::from_secs(30)))
+                    .await
+                    .ok();
+            });
+            let mut watch = shutdown.clone();
+            let services = async {
+                tokio::join!(order, async {
+                    watch.changed().await.ok();
+                });
+            };
+
+            let started = Instant::now();
+            stop.send(true).unwrap();
+            assert!(run_until_shutdown(services, shutdown, Duration::from_millis(100)).await);
+            assert!(started.elapsed() < Duration::from_secs(5));
+        });
+
+        // The blocking thread of the order is left behind
+        runtime.shutdown_background();
+    }
+}
@@ -0,0 +1,71 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::{
+    config::Config, metrics, plugins::PLUGINS, proxy_server::forward_auth,
+    services::tick_or_shutdown, stores::bounded::EvictableStore,
+};
+
+/// In-memory stores that grow with client traffic (and must therefore be bounded)
+fn evictable_stores() -> [&'static dyn EvictableStore; 2] {
+    [PLUGINS.rate_limit.store(), forward_auth::store()]
+}
+
+/// Periodically evicts stale entries from the in-memory stores
+/// and reports how many entries each store holds.
+pub struct EvictionService {
+    config: Arc<Config>,
+}
+
+impl EvictionService {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Service for EvictionService {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        let stores_config = &self.config.stores;
+        for store in evictable_stores() {
+            store.configure(
+                Duration::from_secs(stores_config.ttl_secs),
+                stores_config.max_entries,
+            );
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            stores_config.eviction_interval_secs.max(1),
+        ));
+
+        while tick_or_shutdown(&mut interval, &mut shutdown).await {
+            let now = std::time::Instant::now();
+            for store in evictable_stores() {
+                let evicted = store.evict(now);
+                if evicted > 0 {
+                    tracing::debug!("evicted {evicted} entries from store {}", store.name());
+                }
+
+                metrics::STORE_EVICTIONS
+                    .with_label_values(&[store.name()])
+                    .inc_by(evicted as u64);
+                metrics::STORE_ENTRIES
+                    .with_label_values(&[store.name()])
+                    .set(i64::try_from(store.len()).unwrap_or(i64::MAX));
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "eviction_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
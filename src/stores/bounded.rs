@@ -0,0 +1,175 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+/// Default time (in seconds) an entry can stay unused before being evicted
+const DEFAULT_TTL_SECS: u64 = 3600;
+
+/// Default maximum amount of entries kept in a single store
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+/// A store that can be bounded by the eviction service
+pub trait EvictableStore: Send + Sync {
+    /// Name of the store (used as the metrics label)
+    fn name(&self) -> &'static str;
+
+    /// Current amount of entries
+    fn len(&self) -> usize;
+
+    /// Sets the TTL and the maximum amount of entries of the store
+    fn configure(&self, ttl: Duration, max_entries: usize);
+
+    /// Removes expired entries (and the least recently used ones when over capacity).
+    /// Returns how many entries were removed.
+    fn evict(&self, now: Instant) -> usize;
+}
+
+struct TtlEntry<V> {
+    value: V,
+    last_access: Instant,
+}
+
+/// A `DashMap` whose entries expire once they have not been accessed for `ttl`
+/// and that never grows beyond `max_entries`.
+pub struct TtlMap<V> {
+    name: &'static str,
+    entries: DashMap<String, TtlEntry<V>>,
+    ttl_secs: AtomicU64,
+    max_entries: AtomicUsize,
+}
+
+impl<V: Send + Sync> TtlMap<V> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            entries: DashMap::new(),
+            ttl_secs: AtomicU64::new(DEFAULT_TTL_SECS),
+            max_entries: AtomicUsize::new(DEFAULT_MAX_ENTRIES),
+        }
+    }
+
+    /// Runs `f` against the entry for `key`, creating it with `init` if it does not exist.
+    /// When the store is full, room is made before a new entry is inserted.
+    pub fn with_entry<R>(
+        &self,
+        key: String,
+        now: Instant,
+        init: impl FnOnce() -> V,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> R {
+        if self.entries.len() >= self.max_entries.load(Ordering::Relaxed)
+            && !self.entries.contains_key(&key)
+        {
+            self.evict(now);
+        }
+
+        let mut entry = self.entries.entry(key).or_insert_with(|| TtlEntry {
+            value: init(),
+            last_access: now,
+        });
+        entry.last_access = now;
+
+        f(&mut entry.value)
+    }
+}
+
+impl<V: Send + Sync> EvictableStore for TtlMap<V> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn configure(&self, ttl: Duration, max_entries: usize) {
+        self.ttl_secs.store(ttl.as_secs(), Ordering::Relaxed);
+        self.max_entries
+            .store(max_entries.max(1), Ordering::Relaxed);
+    }
+
+    fn evict(&self, now: Instant) -> usize {
+        let before = self.entries.len();
+        let ttl = Duration::from_secs(self.ttl_secs.load(Ordering::Relaxed));
+        self.entries
+            .retain(|_, entry| now.saturating_duration_since(entry.last_access) < ttl);
+
+        // Still over capacity: drop the least recently used entries,
+        // leaving some headroom so that eviction doesn't run on every insert
+        let max_entries = self.max_entries.load(Ordering::Relaxed);
+        if self.entries.len() >= max_entries {
+            let target = max_entries - max_entries / 10 - 1;
+            let mut by_access = self
+                .entries
+                .iter()
+                .map(|entry| (entry.last_access, entry.key().clone()))
+                .collect::<Vec<_>>();
+            by_access.sort_unstable();
+
+            let excess = by_access.len().saturating_sub(target);
+            for (_, key) in by_access.into_iter().take(excess) {
+                self.entries.remove(&key);
+            }
+        }
+
+        before.saturating_sub(self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_expired_entries() {
+        let map = TtlMap::<u32>::new("test");
+        map.configure(Duration::from_secs(10), 100);
+        let now = Instant::now();
+
+        map.with_entry("a".into(), now, || 1, |_| ());
+        map.with_entry("b".into(), now + Duration::from_secs(5), || 1, |_| ());
+
+        assert_eq!(map.evict(now + Duration::from_secs(12)), 1);
+        assert_eq!(map.len(), 1);
+        assert!(map.entries.contains_key("b"));
+    }
+
+    #[test]
+    fn test_bounded_by_max_entries() {
+        let map = TtlMap::<u32>::new("test");
+        map.configure(Duration::from_secs(3600), 10);
+        let now = Instant::now();
+
+        for i in 0..100u64 {
+            map.with_entry(i.to_string(), now + Duration::from_secs(i), || 0, |_| ());
+            assert!(map.len() <= 10);
+        }
+
+        // The most recently used entry is kept
+        assert!(map.entries.contains_key("99"));
+        assert!(!map.entries.contains_key("0"));
+    }
+
+    #[test]
+    fn test_existing_entries_are_updated() {
+        let map = TtlMap::<u32>::new("test");
+        let now = Instant::now();
+
+        map.with_entry("a".into(), now, || 0, |v| *v += 1);
+        let value = map.with_entry(
+            "a".into(),
+            now,
+            || 0,
+            |v| {
+                *v += 1;
+                *v
+            },
+        );
+
+        assert_eq!(value, 2);
+        assert_eq!(map.len(), 1);
+    }
+}
@@ -1,15 +1,228 @@
+use std::{fs, path::Path};
+
 use dashmap::DashMap;
 use openssl::{
+    asn1::{Asn1Time, Asn1TimeRef},
+    hash::MessageDigest,
     pkey::{PKey, Private},
-    x509::X509,
+    rsa::Rsa,
+    x509::{X509NameBuilder, X509VerifyResult, X509},
 };
+use serde::Serialize;
+
+/// Where a certificate of the store comes from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertificateSource {
+    /// Issued (or renewed) through ACME
+    Acme,
+    /// Loaded from files (`tls.certificates` or the `ssl.path` of a route)
+    #[default]
+    File,
+    /// Generated in memory
+    SelfSigned,
+}
 
 #[derive(Debug, Clone)]
 pub struct Certificate {
     pub key: PKey<Private>,
     #[allow(clippy::struct_field_names)]
     pub leaf: X509,
-    pub chain: Option<X509>,
+    /// Intermediate certificates served after the leaf, in order
+    pub chain: Vec<X509>,
+    pub source: CertificateSource,
+}
+
+impl Certificate {
+    /// Creates a certificate from a PEM bundle: the leaf first, then its
+    /// intermediates in order (each one issuing the previous one)
+    pub fn from_pem(bundle: &[u8], key: PKey<Private>) -> Result<Self, anyhow::Error> {
+        let mut certs = X509::stack_from_pem(bundle)?.into_iter();
+        let leaf = certs
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Certificate is empty"))?;
+
+        Ok(Self {
+            key,
+            leaf,
+            chain: certs.collect(),
+            source: CertificateSource::File,
+        })
+    }
+
+    /// Loads a certificate from its PEM files: the certificate (or bundle,
+    /// see [`Certificate::from_pem`]) and its private key
+    pub fn from_files(pem: &Path, key: &Path) -> Result<Self, anyhow::Error> {
+        let key_from_file = fs::read_to_string(key).map_err(|err| {
+            anyhow::anyhow!("Failed to load private key from file {key:?}: {err}")
+        })?;
+        let pem_from_file = fs::read_to_string(pem).map_err(|err| {
+            anyhow::anyhow!("Failed to load certificate from file {pem:?}: {err}")
+        })?;
+
+        let key = PKey::private_key_from_pem(key_from_file.as_bytes()).map_err(|err| {
+            anyhow::anyhow!("Failed to load private key from file {key:?}: {err}")
+        })?;
+        Self::from_pem(pem_from_file.as_bytes(), key)
+            .map_err(|err| anyhow::anyhow!("Failed to load certificate from file {pem:?}: {err}"))
+    }
+
+    /// The issuer of the leaf (ex: `C=US, O=Let's Encrypt, CN=R11`)
+    pub fn issuer(&self) -> String {
+        self.leaf
+            .issuer_name()
+            .entries()
+            .filter_map(|entry| {
+                let name = entry.object().nid().short_name().ok()?;
+                let value = entry.data().as_utf8().ok()?;
+                Some(format!("{name}={value}"))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Describes what looks wrong with the chain served with the leaf, if anything:
+    /// intermediates missing (the leaf is not self-signed but comes alone) or
+    /// certificates that are not issued by the next one (out of order or unrelated).
+    /// Only names and key identifiers are compared, signatures are not verified.
+    pub fn chain_issue(&self) -> Option<String> {
+        if self.chain.is_empty() {
+            let self_signed = self.leaf.issued(&self.leaf) == X509VerifyResult::OK;
+            return (!self_signed).then(|| {
+                "has no intermediate certificate, some clients may fail to verify it".into()
+            });
+        }
+
+        let mut subject = &self.leaf;
+        for (index, issuer) in self.chain.iter().enumerate() {
+            if issuer.issued(subject) != X509VerifyResult::OK {
+                return Some(format!(
+                    "chain certificate {} is not the issuer of the one before it (out of order?)",
+                    index + 1
+                ));
+            }
+            subject = issuer;
+        }
+
+        None
+    }
+
+    /// Creates an in-memory self-signed certificate (valid for a year) for the given name
+    pub fn self_signed(common_name: &str) -> Result<Self, anyhow::Error> {
+        let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+        let mut cert = X509::builder()?;
+        let mut x509_name = X509NameBuilder::new()?;
+
+        x509_name.append_entry_by_text("CN", common_name)?;
+        x509_name.append_entry_by_text("ST", "TX")?;
+        x509_name.append_entry_by_text("O", "Proksi")?;
+        let x509_name = x509_name.build();
+
+        cert.set_version(2)?;
+        cert.set_subject_name(&x509_name)?;
+        cert.set_issuer_name(&x509_name)?;
+        cert.set_pubkey(&key)?;
+        let today = Asn1Time::days_from_now(0)?;
+        let one_year = Asn1Time::days_from_now(365)?;
+        cert.set_not_before(&today)?;
+        cert.set_not_after(&one_year)?;
+        cert.sign(&key, MessageDigest::sha256())?;
+
+        Ok(Self {
+            key,
+            leaf: cert.build(),
+            chain: Vec::new(),
+            source: CertificateSource::SelfSigned,
+        })
+    }
+}
+
+/// Converts a time of a certificate (ex: `not_after`) to a unix timestamp
+pub fn unix_timestamp(time: &Asn1TimeRef) -> Option<i64> {
+    let epoch = Asn1Time::from_unix(0).ok()?;
+    let diff = epoch.diff(time).ok()?;
+
+    Some(i64::from(diff.days) * 86_400 + i64::from(diff.secs))
 }
 
 pub type CertificateStore = DashMap<String, Certificate>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_signed_certificate() {
+        let cert = Certificate::self_signed("example.com").unwrap();
+        let cn = cert
+            .leaf
+            .subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .next()
+            .unwrap();
+
+        assert_eq!(cn.data().as_slice(), b"example.com");
+        assert!(cert.leaf.public_key().unwrap().public_eq(&cert.key));
+        // Self-signed certificates have no issuer to serve
+        assert_eq!(cert.chain_issue(), None);
+    }
+
+    /// Issues a certificate for `name`, signed by `issuer` (self-signed without one)
+    fn issue(name: &str, issuer: Option<&Certificate>) -> Certificate {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(issuer.map_or(&*subject, |i| i.leaf.subject_name()))
+            .unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(issuer.map_or(&key, |i| &i.key), MessageDigest::sha256())
+            .unwrap();
+
+        Certificate {
+            key,
+            leaf: cert.build(),
+            chain: Vec::new(),
+            source: CertificateSource::File,
+        }
+    }
+
+    #[test]
+    fn test_certificate_from_pem_bundle() {
+        let root = issue("root", None);
+        let intermediate = issue("intermediate", Some(&root));
+        let leaf = issue("example.com", Some(&intermediate));
+
+        let pem = |cert: &Certificate| cert.leaf.to_pem().unwrap();
+        let bundle = [pem(&leaf), pem(&intermediate)].concat();
+
+        let cert = Certificate::from_pem(&bundle, leaf.key.clone()).unwrap();
+        assert_eq!(cert.leaf.to_der().unwrap(), leaf.leaf.to_der().unwrap());
+        assert_eq!(cert.chain.len(), 1);
+        assert_eq!(cert.chain_issue(), None);
+        assert_eq!(cert.issuer(), "CN=intermediate");
+        assert_eq!(cert.source, CertificateSource::File);
+
+        assert!(Certificate::from_pem(b"", leaf.key.clone()).is_err());
+
+        // The leaf alone, without the intermediate that issued it
+        let alone = Certificate::from_pem(&pem(&leaf), leaf.key.clone()).unwrap();
+        assert!(alone.chain_issue().unwrap().contains("no intermediate"));
+
+        // Intermediates out of order
+        let bundle = [pem(&leaf), pem(&root), pem(&intermediate)].concat();
+        let unordered = Certificate::from_pem(&bundle, leaf.key).unwrap();
+        assert!(unordered
+            .chain_issue()
+            .unwrap()
+            .contains("chain certificate 1"));
+    }
+}
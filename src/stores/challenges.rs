@@ -1,3 +1,44 @@
+use std::time::{Duration, Instant};
+
 use dashmap::DashMap;
 
-pub type ChallengeStore = DashMap<String, (String, String)>;
+/// The answer to the HTTP-01 challenge of a host, until its order ends
+#[derive(Debug, Clone)]
+pub struct PendingChallenge {
+    pub token: String,
+    pub key_authorization: String,
+    /// When the challenge was (last) started
+    pub created_at: Instant,
+}
+
+impl PendingChallenge {
+    pub fn new(token: String, key_authorization: String) -> Self {
+        PendingChallenge {
+            token,
+            key_authorization,
+            created_at: Instant::now(),
+        }
+    }
+
+    /// Whether the challenge outlived the `ttl`, its order being abandoned
+    pub fn is_stale(&self, ttl: Duration, now: Instant) -> bool {
+        now.saturating_duration_since(self.created_at) >= ttl
+    }
+}
+
+pub type ChallengeStore = DashMap<String, PendingChallenge>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_challenge() {
+        let challenge = PendingChallenge::new("token".to_string(), "token.key".to_string());
+        let ttl = Duration::from_secs(60);
+
+        assert!(!challenge.is_stale(ttl, challenge.created_at));
+        assert!(!challenge.is_stale(ttl, challenge.created_at + Duration::from_secs(59)));
+        assert!(challenge.is_stale(ttl, challenge.created_at + ttl));
+    }
+}
@@ -1,37 +1,98 @@
-use std::{hash::RandomState, sync::Arc};
+use std::{
+    hash::RandomState,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use certificates::{Certificate, CertificateStore};
-use challenges::ChallengeStore;
+use challenges::{ChallengeStore, PendingChallenge};
 use dashmap::{mapref, DashMap, ReadOnlyView};
 use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
 use routes::{RouteStore, RouteStoreContainer};
 
+use crate::proxy_server::{
+    client_auth::ClientAuth,
+    matching::{self, normalize_host},
+};
+
+pub mod bounded;
 pub mod cache;
 pub mod certificates;
 pub mod challenges;
 pub mod routes;
+pub mod sticky;
 
 // CHALLENGE store
 static CHALLENGE_STORE: Lazy<Arc<ChallengeStore>> = Lazy::new(|| Arc::new(DashMap::new()));
 
 pub fn get_challenge_by_key(
     key: &str,
-) -> Option<mapref::one::Ref<'static, String, (String, String)>> {
+) -> Option<mapref::one::Ref<'static, String, PendingChallenge>> {
     CHALLENGE_STORE.get(key)
 }
 
-/// Insert given challenge into the store
+/// Insert given challenge (token, key authorization) into the store
 pub fn insert_challenge(key: String, value: (String, String)) {
-    CHALLENGE_STORE.insert(key, value);
+    CHALLENGE_STORE.insert(key, PendingChallenge::new(value.0, value.1));
+}
+
+/// Stop answering the challenge of the host, once its order ends
+pub fn remove_challenge(key: &str) {
+    CHALLENGE_STORE.remove(key);
+}
+
+/// Removes the challenges older than `ttl` (abandoned orders), returning how many
+pub fn remove_stale_challenges(ttl: Duration, now: Instant) -> usize {
+    let before = CHALLENGE_STORE.len();
+    CHALLENGE_STORE.retain(|_, challenge| !challenge.is_stale(ttl, now));
+    before.saturating_sub(CHALLENGE_STORE.len())
 }
 
 // ROUTE store
 static ROUTE_STORE: Lazy<Arc<RouteStore>> = Lazy::new(|| Arc::new(DashMap::new()));
+// ROUTE HOST PATTERN store (the compiled `~<regex>` hosts, by route key)
+static ROUTE_HOST_PATTERNS: Lazy<DashMap<String, Regex>> = Lazy::new(DashMap::new);
 
+/// The route stored by the key. Hosts are case-insensitive, with or without
+/// port or trailing dot (the regexes are looked up as written).
 pub fn get_route_by_key(
     key: &str,
 ) -> Option<mapref::one::Ref<'static, String, RouteStoreContainer>> {
-    ROUTE_STORE.get(key)
+    ROUTE_STORE.get(matching::route_key(key).as_ref())
+}
+
+/// The route serving the host of a request. The route of that exact host
+/// wins, then the one of its closest wildcard (`*.api.example.com`, then
+/// `*.example.com`), then the first regex (in alphabetical order) matching
+/// the whole host.
+pub fn find_route_by_host(
+    host: &str,
+) -> Option<mapref::one::Ref<'static, String, RouteStoreContainer>> {
+    let host = normalize_host(host);
+    // The keys of the patterns are no hosts a client can send
+    if matching::is_host_pattern(&host) {
+        return None;
+    }
+
+    if let Some(route) = ROUTE_STORE.get(host.as_ref()) {
+        return Some(route);
+    }
+
+    let mut parent = host.as_ref();
+    while let Some((_, domain)) = parent.split_once('.') {
+        if let Some(route) = ROUTE_STORE.get(&format!("*.{domain}")) {
+            return Some(route);
+        }
+        parent = domain;
+    }
+
+    let key = ROUTE_HOST_PATTERNS
+        .iter()
+        .filter(|pattern| pattern.value().is_match(&host))
+        .map(|pattern| pattern.key().clone())
+        .min()?;
+    ROUTE_STORE.get(&key)
 }
 
 pub fn get_routes() -> ReadOnlyView<String, RouteStoreContainer> {
@@ -39,7 +100,26 @@ pub fn get_routes() -> ReadOnlyView<String, RouteStoreContainer> {
 }
 
 pub fn insert_route(key: String, value: RouteStoreContainer) {
-    ROUTE_STORE.insert(key, value);
+    if let Some(regex) = matching::host_regex(&key) {
+        match RegexBuilder::new(&format!("^(?:{regex})$"))
+            .case_insensitive(true)
+            .build()
+        {
+            Ok(regex) => {
+                ROUTE_HOST_PATTERNS.insert(key.clone(), regex);
+            }
+            Err(err) => tracing::warn!("route host {key} is an invalid regex: {err}"),
+        }
+    }
+
+    ROUTE_STORE.insert(matching::route_key(&key).into_owned(), value);
+}
+
+/// Removes the route of a host, the requests in flight keep their copy of it
+pub fn remove_route(key: &str) -> Option<RouteStoreContainer> {
+    let key = matching::route_key(key);
+    ROUTE_HOST_PATTERNS.remove(key.as_ref());
+    ROUTE_STORE.remove(key.as_ref()).map(|(_, route)| route)
 }
 
 pub fn get_mutable_routes(
@@ -47,21 +127,89 @@ pub fn get_mutable_routes(
     (*ROUTE_STORE).iter_mut()
 }
 
-// CERTIFICATE store
+// CERTIFICATE store (ACME and self-signed certificates)
 static CERTIFICATE_STORE: Lazy<Arc<CertificateStore>> = Lazy::new(|| Arc::new(DashMap::new()));
+// MANUAL CERTIFICATE store (`tls.certificates` and the `ssl.path` of the routes)
+static MANUAL_CERTIFICATE_STORE: Lazy<Arc<CertificateStore>> =
+    Lazy::new(|| Arc::new(DashMap::new()));
 
+/// The certificate of the host, or else the wildcard one of its parent domain
+/// (`*.example.com` for `api.example.com`). The certificates provided as files
+/// are looked up first, then the other ones.
 pub fn get_certificate_by_key(key: &str) -> Option<mapref::one::Ref<'static, String, Certificate>> {
-    CERTIFICATE_STORE.get(key)
+    lookup_certificate(&MANUAL_CERTIFICATE_STORE, key)
+        .or_else(|| lookup_certificate(&CERTIFICATE_STORE, key))
 }
 
+fn lookup_certificate(
+    store: &'static CertificateStore,
+    key: &str,
+) -> Option<mapref::one::Ref<'static, String, Certificate>> {
+    store.get(key).or_else(|| {
+        let (_, parent) = key.split_once('.')?;
+        store.get(&format!("*.{parent}"))
+    })
+}
+
+/// Whether a certificate provided as files serves the host, which then gets
+/// none from ACME
+pub fn has_manual_certificate(key: &str) -> bool {
+    lookup_certificate(&MANUAL_CERTIFICATE_STORE, key).is_some()
+}
+
+pub fn insert_manual_certificate(key: String, value: Certificate) {
+    MANUAL_CERTIFICATE_STORE.insert(key, value);
+}
+
+/// Every certificate, the ones provided as files replacing the others of
+/// the same host
 pub fn get_certificates() -> ReadOnlyView<String, Certificate> {
-    (**CERTIFICATE_STORE).clone().into_read_only()
+    let certificates = (**CERTIFICATE_STORE).clone();
+    for certificate in MANUAL_CERTIFICATE_STORE.iter() {
+        certificates.insert(certificate.key().clone(), certificate.value().clone());
+    }
+    certificates.into_read_only()
 }
 
 pub fn insert_certificate(key: String, value: Certificate) {
     CERTIFICATE_STORE.insert(key, value);
 }
 
+pub fn remove_certificate(key: &str) -> Option<Certificate> {
+    CERTIFICATE_STORE.remove(key).map(|(_, value)| value)
+}
+
+// CLIENT AUTH store (mTLS routes)
+static CLIENT_AUTH_STORE: Lazy<Arc<DashMap<String, Arc<ClientAuth>>>> =
+    Lazy::new(|| Arc::new(DashMap::new()));
+
+/// Finds the client authentication of a request host (or SNI): the one of
+/// the route it matches, whether by its host, a wildcard or a regex
+pub fn find_client_auth_by_host(host: &str) -> Option<Arc<ClientAuth>> {
+    let route = find_route_by_host(host)?;
+    CLIENT_AUTH_STORE
+        .get(route.key())
+        .map(|auth| auth.value().clone())
+}
+
+pub fn get_client_auths() -> Vec<Arc<ClientAuth>> {
+    CLIENT_AUTH_STORE
+        .iter()
+        .map(|auth| auth.value().clone())
+        .collect()
+}
+
+/// Stores the client authentication of a route, by the key of its host
+pub fn insert_client_auth(key: &str, value: Arc<ClientAuth>) {
+    CLIENT_AUTH_STORE.insert(matching::route_key(key).into_owned(), value);
+}
+
+pub fn remove_client_auth(key: &str) -> Option<Arc<ClientAuth>> {
+    CLIENT_AUTH_STORE
+        .remove(matching::route_key(key).as_ref())
+        .map(|(_, auth)| auth)
+}
+
 // Cache Routing store
 static CACHE_ROUTING_STORE: Lazy<Arc<cache::PathCacheStorage>> =
     Lazy::new(|| Arc::new(DashMap::new()));
@@ -1,15 +1,53 @@
-use std::{borrow::Cow, collections::HashMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    net::{self, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use dashmap::DashMap;
-use http::{HeaderName, HeaderValue};
+use http::{HeaderName, HeaderValue, Version};
+use once_cell::sync::OnceCell;
 use path_tree::PathTree;
-use pingora::lb::{selection::RoundRobin, LoadBalancer};
+use pingora::{
+    http::RequestHeader,
+    lb::{selection::RoundRobin, Backend, Backends, LoadBalancer},
+    protocols::l4::socket::SocketAddr,
+};
+use prometheus::IntGauge;
+use rand::{seq::index, Rng};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
-use crate::config::{RouteCache, RoutePlugin, RouteUpstream};
+use crate::{
+    config::{
+        RouteCache, RouteCompression, RouteConnectionReuse, RouteHealthCheck, RouteMirror,
+        RoutePlugin, RouteSelection, RouteTimeouts, RouteUpstream,
+    },
+    metrics,
+    plugins::rate_limit::{RateLimitRules, RouteRateLimiter},
+    proxy_server::{
+        blocked_paths::BlockedPaths, concurrency::ConcurrencyLimit,
+        dynamic_upstream::DynamicUpstream, error_handling::ErrorHandling,
+        forward_auth::ForwardAuth, request_compression::AdvertisedUpstreams, retries::Retries,
+        status_map::StatusMap, substitution::Substitutions, user_agents::UserAgentFilter,
+    },
+    services::{discovery::reconcile::DynamicBackends, health_check::HealthTargets},
+};
+
+use super::sticky::{StickyClients, StickyKey};
+
+/// Turns of the weighted rotation tried before any eligible upstream is picked
+const ROUND_ROBIN_ATTEMPTS: usize = 32;
 
 #[derive(Debug, Default, Clone)]
 pub struct RouteStorePathMatcher {
     pub pattern: Option<PathTree<usize>>,
+    /// The patterns of the tree, by the index stored in it
+    pub patterns: Vec<String>,
 }
 
 impl RouteStorePathMatcher {
@@ -30,23 +68,362 @@ impl RouteStorePathMatcher {
         }
 
         self.pattern = Some(path_tree);
+        self.patterns = pattern.iter().map(ToString::to_string).collect();
         self
     }
 }
 
+/// Number of requests in flight to each upstream of a route.
+/// Clones share the same counters.
+#[derive(Clone, Default)]
+pub struct ActiveRequests {
+    counts: Arc<DashMap<SocketAddr, Arc<AtomicUsize>>>,
+}
+
+impl ActiveRequests {
+    /// Number of requests in flight to the given upstream
+    pub fn count(&self, addr: &SocketAddr) -> usize {
+        self.counts
+            .get(addr)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// Counts a new request to the given upstream until the returned guard is dropped
+    pub fn start(&self, addr: &SocketAddr) -> ActiveRequest {
+        let count = self.counts.entry(addr.clone()).or_default().clone();
+        count.fetch_add(1, Ordering::Relaxed);
+        ActiveRequest { count }
+    }
+}
+
+/// A request in flight, no longer counted once dropped
+pub struct ActiveRequest {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Connections in use to each upstream of a route, bounded for the upstreams
+/// with `max_connections`. Clones share the same slots.
+#[derive(Clone, Default)]
+pub struct UpstreamConnections {
+    slots: Arc<DashMap<SocketAddr, (usize, Arc<Semaphore>)>>,
+}
+
+impl UpstreamConnections {
+    /// Follows the `max_connections` of the upstreams, the upstreams whose
+    /// limit did not change keep their slots
+    pub fn update(&self, upstreams: &[RouteUpstream]) {
+        let mut limits = HashMap::new();
+        for upstream in upstreams {
+            let Some(max_connections) = upstream.max_connections else {
+                continue;
+            };
+
+            if let Ok(addrs) = format!("{}:{}", upstream.ip, upstream.port).to_socket_addrs() {
+                limits.extend(addrs.map(|addr| (SocketAddr::Inet(addr), max_connections)));
+            }
+        }
+
+        self.slots
+            .retain(|addr, (max_connections, _)| limits.get(addr) == Some(max_connections));
+        for (addr, max_connections) in limits {
+            self.slots
+                .entry(addr)
+                .or_insert_with(|| (max_connections, Arc::new(Semaphore::new(max_connections))));
+        }
+    }
+
+    /// Whether a connection to the upstream can be used
+    pub fn available(&self, addr: &SocketAddr) -> bool {
+        self.slots
+            .get(addr)
+            .map_or(true, |slot| slot.1.available_permits() > 0)
+    }
+
+    /// Takes a connection of the upstream until the returned guard is dropped,
+    /// `None` when all of them are in use
+    pub fn open(&self, route: &str, addr: &SocketAddr) -> Option<UpstreamConnection> {
+        let permit = match self.slots.get(addr) {
+            Some(slot) => Some(slot.1.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+
+        let gauge = metrics::UPSTREAM_CONNECTIONS.with_label_values(&[route, &addr.to_string()]);
+        gauge.inc();
+        Some(UpstreamConnection {
+            _permit: permit,
+            gauge,
+        })
+    }
+}
+
+/// Upstreams of a route skipped by the load balancer until a given time, as
+/// asked by the `Retry-After` of their responses. Clones share the same entries.
+#[derive(Clone, Default)]
+pub struct BackedOffUpstreams {
+    until: Arc<DashMap<SocketAddr, Instant>>,
+}
+
+impl BackedOffUpstreams {
+    /// Skips the upstream for `delay`
+    pub fn pause(&self, addr: &SocketAddr, delay: Duration) {
+        self.until.insert(addr.clone(), Instant::now() + delay);
+    }
+
+    /// Whether the upstream is skipped at `now`, forgetting it once it is not
+    pub fn is_paused(&self, addr: &SocketAddr, now: Instant) -> bool {
+        if self.until.is_empty() {
+            return false;
+        }
+
+        self.until.remove_if(addr, |_, until| *until <= now);
+        self.until.contains_key(addr)
+    }
+}
+
+/// A connection in use, released once dropped
+pub struct UpstreamConnection {
+    _permit: Option<OwnedSemaphorePermit>,
+    gauge: IntGauge,
+}
+
+impl Drop for UpstreamConnection {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// HTTP version negotiated (through ALPN) with each upstream of a route whose
+/// `protocol` is auto. Clones share the same versions.
+#[derive(Clone, Default)]
+pub struct NegotiatedProtocols {
+    versions: Arc<DashMap<SocketAddr, (Version, Instant)>>,
+}
+
+impl NegotiatedProtocols {
+    /// Negotiated versions are forgotten after this long, so upstreams that
+    /// start speaking h2 are noticed
+    const TTL: Duration = Duration::from_secs(10 * 60);
+
+    /// The version last negotiated with the given upstream, if still known
+    pub fn get(&self, addr: &SocketAddr) -> Option<Version> {
+        let entry = self.versions.get(addr)?;
+        let (version, negotiated_at) = *entry;
+        (negotiated_at.elapsed() < Self::TTL).then_some(version)
+    }
+
+    /// Records the version of a response of the given upstream
+    pub fn record(&self, addr: &SocketAddr, version: Version) {
+        let version = if version == Version::HTTP_2 {
+            Version::HTTP_2
+        } else {
+            Version::HTTP_11
+        };
+
+        if self.get(addr) != Some(version) {
+            self.versions
+                .insert(addr.clone(), (version, Instant::now()));
+        }
+    }
+}
+
+/// The upstream of a route used when none of its upstreams is healthy.
+/// It is not health checked: a failing fallback gets the request a 502.
+#[derive(Debug, Clone)]
+pub struct FallbackUpstream {
+    pub backend: Backend,
+    pub upstream: RouteUpstream,
+}
+
+/// Upstream pool of each request, picked from the value of a request header
+#[derive(Debug, Clone)]
+pub struct GeoRouting {
+    pub header: HeaderName,
+    /// Pool serving each (uppercase) header value
+    pub pools: HashMap<String, String>,
+    /// Pool of each upstream, the ones without a pool form the default pool
+    pub upstreams: HashMap<net::SocketAddr, String>,
+}
+
+impl GeoRouting {
+    /// Pool serving the request, `None` (default pool) when the header
+    /// is absent or no pool serves its value
+    pub fn pool(&self, request: &RequestHeader) -> Option<&str> {
+        let value = request.headers.get(&self.header)?.to_str().ok()?;
+        self.pools
+            .get(&value.trim().to_ascii_uppercase())
+            .map(String::as_str)
+    }
+
+    fn pool_of(&self, backend: &Backend) -> Option<&str> {
+        let addr = backend.addr.as_inet()?;
+        self.upstreams.get(addr).map(String::as_str)
+    }
+}
+
+/// Upstream groups serving the paths matched by their patterns, the
+/// upstreams of a route without patterns forming the default group
+#[derive(Debug, Clone)]
+pub struct PathGroups {
+    /// The patterns of each group and its (resolved) upstreams, in the order
+    /// the groups are tried
+    pub groups: Vec<(RouteStorePathMatcher, HashSet<net::SocketAddr>)>,
+}
+
+impl PathGroups {
+    /// Group serving the (normalized) path, `None` (default group) when
+    /// the patterns of no group match it
+    pub fn group(&self, path: &str) -> Option<usize> {
+        self.groups.iter().position(|(matcher, _)| {
+            matcher
+                .pattern
+                .as_ref()
+                .is_some_and(|tree| tree.find(path).is_some())
+        })
+    }
+
+    fn group_of(&self, backend: &Backend) -> Option<usize> {
+        let addr = backend.addr.as_inet()?;
+        self.groups
+            .iter()
+            .position(|(_, upstreams)| upstreams.contains(addr))
+    }
+}
+
+/// The warmup of a newly added route: its requests are answered with a 503
+/// until one of its upstreams passes a health check, for `period` at most
+#[derive(Debug)]
+pub struct Warmup {
+    period: Duration,
+    /// When the route was added
+    pub created_at: Instant,
+    /// When one of its upstreams was first found healthy
+    first_healthy_at: OnceCell<Instant>,
+}
+
+impl Warmup {
+    pub fn new(period: Duration) -> Self {
+        Warmup {
+            period,
+            created_at: Instant::now(),
+            first_healthy_at: OnceCell::new(),
+        }
+    }
+
+    /// Records the result of a health check of the backends, ending the
+    /// warmup once one of them is healthy
+    pub fn record(&self, backends: &Backends, now: Instant) {
+        let healthy = backends
+            .get_backend()
+            .iter()
+            .any(|backend| backends.ready(backend));
+        if healthy {
+            self.first_healthy_at.get_or_init(|| now);
+        }
+    }
+
+    pub fn first_healthy_at(&self) -> Option<Instant> {
+        self.first_healthy_at.get().copied()
+    }
+
+    /// Whether the requests are still answered with a 503 at `now`
+    pub fn is_warming(&self, now: Instant) -> bool {
+        self.first_healthy_at.get().is_none()
+            && now.saturating_duration_since(self.created_at) < self.period
+    }
+}
+
 #[derive(Clone)]
 pub struct RouteStoreContainer {
     pub load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    /// Handle to the backends of `load_balancer`, used to reconcile them on changes
+    pub backends: Option<DynamicBackends>,
     pub path_matcher: RouteStorePathMatcher,
     pub host_header_remove: Vec<String>,
     pub host_header_add: Vec<(HeaderName, HeaderValue)>,
+    /// Headers set on (and removed from) the requests sent to the upstreams
+    pub upstream_header_add: Vec<(HeaderName, HeaderValue)>,
+    pub upstream_header_remove: Vec<String>,
+    /// Whether the `X-Forwarded-*` headers are sent to the upstreams
+    pub forwarded_headers: bool,
 
     pub upstreams: Vec<RouteUpstream>,
+    /// Upstream of the requests when none of `upstreams` is healthy
+    pub fallback_upstream: Option<FallbackUpstream>,
     pub self_signed_certificate: bool,
 
     pub plugins: HashMap<String, RoutePlugin>,
+    /// Rules of the `rate_limit` plugin, compiled when the route is added
+    pub rate_limit_rules: Option<Arc<RateLimitRules>>,
+    /// Authentication middlewares of the route, in order
+    pub auth: Vec<RoutePlugin>,
+    /// External server the requests are authenticated by
+    pub forward_auth: Option<Arc<ForwardAuth>>,
 
     pub cache: Option<RouteCache>,
+    pub compression: Option<RouteCompression>,
+    /// Rewriting of the bodies of the text responses
+    pub substitutions: Option<Arc<Substitutions>>,
+    /// Statuses of the upstream responses sent as other ones
+    pub status_map: Option<Arc<StatusMap>>,
+
+    /// Share of the requests that are logged, overriding `tracing.sample_rate`
+    pub sample_rate: Option<f64>,
+    /// Whether the requests are logged, failed ones always are
+    pub access_log_enabled: bool,
+
+    /// How the upstream of each request is selected
+    pub selection: RouteSelection,
+    /// Requests in flight to each upstream
+    pub active_requests: ActiveRequests,
+    /// HTTP version negotiated with each upstream
+    pub negotiated_protocols: NegotiatedProtocols,
+    /// Upstreams that advertised gzip request bodies
+    pub gzip_upstreams: AdvertisedUpstreams,
+    /// Addresses probed by the health check instead of the upstreams
+    pub health_targets: HealthTargets,
+    /// The health check `load_balancer` was built with
+    pub health_check: Option<RouteHealthCheck>,
+    /// Connections in use to each upstream (`max_connections`)
+    pub upstream_connections: UpstreamConnections,
+    /// Upstreams skipped until the end of their `Retry-After` (`error_handling`)
+    pub backed_off_upstreams: BackedOffUpstreams,
+    /// Upstream of each client, taking precedence over `selection`
+    pub sticky: Option<Arc<StickyClients>>,
+    /// Clients getting connections to the upstreams of their own
+    pub connection_reuse: Option<RouteConnectionReuse>,
+    /// Slots of the requests proxied at once
+    pub concurrency: Option<ConcurrencyLimit>,
+    /// Timeouts of the requests, over the global ones
+    pub timeouts: Option<RouteTimeouts>,
+    /// Shadow upstream the requests are copied to
+    pub mirror: Option<RouteMirror>,
+    /// Paths answered without reaching the upstreams
+    pub blocked_paths: Option<Arc<BlockedPaths>>,
+    /// Agents answered with a 403 instead of being proxied
+    pub user_agent: Option<Arc<UserAgentFilter>>,
+    /// Requests of each client allowed per second (`rate_limit`)
+    pub rate_limit: Option<Arc<RouteRateLimiter>>,
+
+    /// Upstream pools picked from a request header
+    pub geo_routing: Option<GeoRouting>,
+    /// Upstream groups picked from the path of the request
+    pub path_groups: Option<PathGroups>,
+    /// Upstreams in the zone of this instance, preferred over the other ones
+    pub local_upstreams: Option<HashSet<net::SocketAddr>>,
+    /// Warmup of the route since it was added, if configured
+    pub warmup: Option<Arc<Warmup>>,
+    /// Upstreams picked from the path of the requests, instead of the load balancer
+    pub dynamic_upstream: Option<Arc<DynamicUpstream>>,
+    /// Upstream responses answered with an error page (or retried)
+    pub error_handling: Option<Arc<ErrorHandling>>,
+    /// Failed requests sent again to another upstream
+    pub retries: Option<Arc<Retries>>,
 }
 
 impl Default for RouteStoreContainer {
@@ -55,28 +432,293 @@ impl Default for RouteStoreContainer {
             load_balancer: Arc::new(
                 LoadBalancer::<RoundRobin>::try_from_iter(vec!["127.0.0.1:80"]).unwrap(),
             ),
+            backends: None,
             path_matcher: RouteStorePathMatcher::default(),
             host_header_remove: Vec::with_capacity(0),
             host_header_add: Vec::with_capacity(0),
+            upstream_header_add: Vec::with_capacity(0),
+            upstream_header_remove: Vec::with_capacity(0),
+            forwarded_headers: false,
             self_signed_certificate: false,
             plugins: HashMap::new(),
+            rate_limit_rules: None,
+            auth: Vec::new(),
+            forward_auth: None,
             upstreams: Vec::with_capacity(0),
+            fallback_upstream: None,
             cache: None,
+            compression: None,
+            substitutions: None,
+            status_map: None,
+            sample_rate: None,
+            access_log_enabled: true,
+            selection: RouteSelection::default(),
+            active_requests: ActiveRequests::default(),
+            negotiated_protocols: NegotiatedProtocols::default(),
+            gzip_upstreams: AdvertisedUpstreams::default(),
+            health_targets: HealthTargets::default(),
+            health_check: None,
+            upstream_connections: UpstreamConnections::default(),
+            backed_off_upstreams: BackedOffUpstreams::default(),
+            sticky: None,
+            connection_reuse: None,
+            concurrency: None,
+            timeouts: None,
+            mirror: None,
+            blocked_paths: None,
+            user_agent: None,
+            rate_limit: None,
+            geo_routing: None,
+            path_groups: None,
+            local_upstreams: None,
+            warmup: None,
+            dynamic_upstream: None,
+            error_handling: None,
+            retries: None,
         }
     }
 }
 
 impl RouteStoreContainer {
     pub fn new(load_balancer: LoadBalancer<RoundRobin>) -> Self {
+        Self::with_shared_load_balancer(Arc::new(load_balancer))
+    }
+
+    /// Selects the upstream of a request among the healthy ones of the given pool,
+    /// or of the default pool when there is none (or the pool has no healthy upstream).
+    /// Within a pool, the upstreams of the local zone are tried first.
+    pub fn select_backend(&self, pool: Option<&str>) -> Option<Backend> {
+        self.select_backend_for(pool, None, None)
+    }
+
+    /// Same as [`Self::select_backend`], among the upstreams of the path group
+    /// of the request (the default group when `None`), sending the client to
+    /// its own upstream when the route is sticky (and the key of the client is known).
+    /// A group without healthy upstream does not fall back to another one.
+    pub fn select_backend_for(
+        &self,
+        pool: Option<&str>,
+        path_group: Option<usize>,
+        client: Option<&StickyKey>,
+    ) -> Option<Backend> {
+        if pool.is_some() {
+            if let Some(backend) = self.select_in_zones(pool, path_group, client) {
+                return Some(backend);
+            }
+        }
+
+        self.select_in_zones(None, path_group, client)
+    }
+
+    fn select_in_zones(
+        &self,
+        pool: Option<&str>,
+        path_group: Option<usize>,
+        client: Option<&StickyKey>,
+    ) -> Option<Backend> {
+        if self.local_upstreams.is_some() {
+            if let Some(backend) = self.select_in_pool(pool, path_group, true, client) {
+                return Some(backend);
+            }
+        }
+
+        self.select_in_pool(pool, path_group, false, client)
+    }
+
+    fn select_in_pool(
+        &self,
+        pool: Option<&str>,
+        path_group: Option<usize>,
+        local_only: bool,
+        client: Option<&StickyKey>,
+    ) -> Option<Backend> {
+        let now = Instant::now();
+        let eligible = |backend: &Backend| {
+            self.in_pool(backend, pool)
+                && self.in_path_group(backend, path_group)
+                && (!local_only || self.is_local(backend))
+                && self.upstream_connections.available(&backend.addr)
+                && !self.backed_off_upstreams.is_paused(&backend.addr, now)
+        };
+
+        if let (Some(sticky), Some(client)) = (&self.sticky, client) {
+            return sticky.select(&self.load_balancer, client, eligible);
+        }
+
+        match self.selection {
+            // Clients without a key (IP) are not hashed on the ring
+            RouteSelection::RoundRobin | RouteSelection::Consistent => {
+                self.select_round_robin(eligible)
+            }
+            RouteSelection::LeastRequest => self.select_least_request(eligible),
+            RouteSelection::LeastConnections => self.select_least_connections(eligible),
+            RouteSelection::Random => self.select_random(eligible),
+        }
+    }
+
+    /// Weighted round-robin. The upstreams skipped (ex: unhealthy) give their
+    /// turns to the next ones of the weighted rotation, so their share goes to
+    /// the other upstreams in proportion to their weights: the fallback of
+    /// pingora ignores the weights.
+    fn select_round_robin(&self, eligible: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        for _ in 0..ROUND_ROBIN_ATTEMPTS {
+            let backend = self
+                .load_balancer
+                .select_with(b"", 1, |backend, healthy| healthy && eligible(backend));
+            if backend.is_some() {
+                return backend;
+            }
+        }
+
+        // Most of the weight is skipped
+        self.select_random(eligible)
+    }
+
+    /// The healthy upstreams passing the filter
+    fn healthy_backends(&self, eligible: impl Fn(&Backend) -> bool) -> Vec<Backend> {
+        let backends = self.load_balancer.backends();
+        backends
+            .get_backend()
+            .iter()
+            .filter(|b| backends.ready(b) && eligible(b))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the upstream belongs to the pool (every upstream does without `geo_routing`)
+    fn in_pool(&self, backend: &Backend, pool: Option<&str>) -> bool {
+        self.geo_routing
+            .as_ref()
+            .map_or(true, |geo| geo.pool_of(backend) == pool)
+    }
+
+    /// Whether the upstream belongs to the path group (every upstream does
+    /// without groups)
+    fn in_path_group(&self, backend: &Backend, path_group: Option<usize>) -> bool {
+        self.path_groups
+            .as_ref()
+            .map_or(true, |groups| groups.group_of(backend) == path_group)
+    }
+
+    /// Whether the upstream is in the zone of this instance
+    fn is_local(&self, backend: &Backend) -> bool {
+        let (Some(local), Some(addr)) = (&self.local_upstreams, backend.addr.as_inet()) else {
+            return false;
+        };
+
+        local.contains(addr)
+    }
+
+    /// The healthy upstream with the fewest active requests relative to its
+    /// weight, the first one listed on a tie
+    fn select_least_connections(&self, eligible: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let load = |backend: &Backend| self.active_requests.count(&backend.addr);
+        self.healthy_backends(eligible)
+            .into_iter()
+            .reduce(|best, backend| {
+                // load / weight < best_load / best_weight, without the divisions
+                if load(&backend) * best.weight.max(1) < load(&best) * backend.weight.max(1) {
+                    backend
+                } else {
+                    best
+                }
+            })
+    }
+
+    /// A random healthy upstream, each one picked in proportion to its weight
+    fn select_random(&self, eligible: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let healthy = self.healthy_backends(eligible);
+        let total = healthy.iter().map(|b| b.weight.max(1)).sum::<usize>();
+        if total == 0 {
+            return None;
+        }
+
+        let mut picked = rand::thread_rng().gen_range(0..total);
+        healthy.into_iter().find(|backend| {
+            let weight = backend.weight.max(1);
+            if picked < weight {
+                return true;
+            }
+            picked -= weight;
+            false
+        })
+    }
+
+    /// Power of two choices: out of two random healthy upstreams, picks the one
+    /// with the fewest active requests relative to its weight
+    fn select_least_request(&self, eligible: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let backends = self.load_balancer.backends();
+        let all = backends.get_backend();
+        let healthy = all
+            .iter()
+            .filter(|b| backends.ready(b) && eligible(b))
+            .collect::<Vec<_>>();
+
+        let (a, b) = match healthy.len() {
+            0 => return None,
+            1 => return Some(healthy[0].clone()),
+            len => {
+                let picked = index::sample(&mut rand::thread_rng(), len, 2);
+                (healthy[picked.index(0)], healthy[picked.index(1)])
+            }
+        };
+
+        // a_load / a_weight <= b_load / b_weight, without the divisions
+        let load = |backend: &Backend| self.active_requests.count(&backend.addr) + 1;
+        if load(a) * b.weight.max(1) <= load(b) * a.weight.max(1) {
+            Some(a.clone())
+        } else {
+            Some(b.clone())
+        }
+    }
+
+    /// Creates a container that shares an (existing) load balancer
+    pub fn with_shared_load_balancer(load_balancer: Arc<LoadBalancer<RoundRobin>>) -> Self {
         RouteStoreContainer {
-            load_balancer: Arc::new(load_balancer),
+            load_balancer,
+            backends: None,
             path_matcher: RouteStorePathMatcher::new(),
             host_header_remove: Vec::with_capacity(5),
             host_header_add: Vec::with_capacity(5),
+            upstream_header_add: Vec::with_capacity(0),
+            upstream_header_remove: Vec::with_capacity(0),
+            forwarded_headers: false,
             self_signed_certificate: false,
             plugins: HashMap::new(),
+            rate_limit_rules: None,
+            auth: Vec::new(),
+            forward_auth: None,
             upstreams: Vec::with_capacity(5),
+            fallback_upstream: None,
             cache: None,
+            compression: None,
+            substitutions: None,
+            status_map: None,
+            sample_rate: None,
+            access_log_enabled: true,
+            selection: RouteSelection::default(),
+            active_requests: ActiveRequests::default(),
+            negotiated_protocols: NegotiatedProtocols::default(),
+            gzip_upstreams: AdvertisedUpstreams::default(),
+            health_targets: HealthTargets::default(),
+            health_check: None,
+            upstream_connections: UpstreamConnections::default(),
+            backed_off_upstreams: BackedOffUpstreams::default(),
+            sticky: None,
+            connection_reuse: None,
+            concurrency: None,
+            timeouts: None,
+            mirror: None,
+            blocked_paths: None,
+            user_agent: None,
+            rate_limit: None,
+            geo_routing: None,
+            path_groups: None,
+            local_upstreams: None,
+            warmup: None,
+            dynamic_upstream: None,
+            error_handling: None,
+            retries: None,
         }
     }
 }
@@ -118,4 +760,354 @@ mod tests {
 
         assert!(pattern.find("/invalid").is_none());
     }
+
+    #[test]
+    fn test_negotiated_protocols() {
+        let negotiated = NegotiatedProtocols::default();
+        let h1 = Backend::new("127.0.0.1:4441").unwrap();
+        let h2 = Backend::new("127.0.0.1:4442").unwrap();
+        assert_eq!(negotiated.get(&h1.addr), None);
+
+        negotiated.record(&h1.addr, Version::HTTP_10);
+        negotiated.record(&h2.addr, Version::HTTP_2);
+        assert_eq!(negotiated.get(&h1.addr), Some(Version::HTTP_11));
+        assert_eq!(negotiated.get(&h2.addr), Some(Version::HTTP_2));
+
+        // Shared by the clones of the route
+        negotiated.clone().record(&h2.addr, Version::HTTP_11);
+        assert_eq!(negotiated.get(&h2.addr), Some(Version::HTTP_11));
+    }
+
+    #[test]
+    fn test_least_request_selection_prefers_idle_upstream() {
+        let mut route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4001", "127.0.0.1:4002"])
+                .unwrap(),
+        );
+        route.selection = RouteSelection::LeastRequest;
+
+        let busy = Backend::new("127.0.0.1:4001").unwrap();
+        let idle = Backend::new("127.0.0.1:4002").unwrap();
+
+        let guards = (0..3)
+            .map(|_| route.active_requests.start(&busy.addr))
+            .collect::<Vec<_>>();
+        assert_eq!(route.active_requests.count(&busy.addr), 3);
+
+        // With two upstreams, both are always compared
+        for _ in 0..10 {
+            assert_eq!(route.select_backend(None), Some(idle.clone()));
+        }
+
+        drop(guards);
+        assert_eq!(route.active_requests.count(&busy.addr), 0);
+
+        // Unhealthy upstreams are never picked
+        route.load_balancer.backends().set_enable(&idle, false);
+        let _guards = (0..3)
+            .map(|_| route.active_requests.start(&busy.addr))
+            .collect::<Vec<_>>();
+        assert_eq!(route.select_backend(None), Some(busy));
+    }
+
+    #[test]
+    fn test_least_connections_selection_weighs_the_active_requests() {
+        let mut route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter([
+                "127.0.0.1:4041",
+                "127.0.0.1:4042",
+                "127.0.0.1:4043",
+            ])
+            .unwrap(),
+        );
+        route.selection = RouteSelection::LeastConnections;
+
+        let first = Backend::new("127.0.0.1:4041").unwrap();
+        let second = Backend::new("127.0.0.1:4042").unwrap();
+        let third = Backend::new("127.0.0.1:4043").unwrap();
+
+        let _guards = [(&first, 2), (&second, 1), (&third, 1)]
+            .into_iter()
+            .flat_map(|(backend, count)| {
+                (0..count).map(|_| route.active_requests.start(&backend.addr))
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..5 {
+            assert_eq!(route.select_backend(None), Some(second.clone()));
+        }
+
+        route.load_balancer.backends().set_enable(&second, false);
+        assert_eq!(route.select_backend(None), Some(third));
+    }
+
+    #[test]
+    fn test_round_robin_redistributes_by_weight() {
+        use std::collections::BTreeSet;
+
+        let backend = |addr: &str, weight: usize| Backend {
+            weight,
+            ..Backend::new(addr).unwrap()
+        };
+        let heavy = backend("127.0.0.1:4061", 4);
+        let medium = backend("127.0.0.1:4062", 3);
+        let light = backend("127.0.0.1:4063", 1);
+
+        let load_balancer = LoadBalancer::<RoundRobin>::from_backends(Backends::new(
+            pingora::lb::discovery::Static::new(BTreeSet::from([
+                heavy.clone(),
+                medium.clone(),
+                light.clone(),
+            ])),
+        ));
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(load_balancer.update())
+            .unwrap();
+        let route = RouteStoreContainer::new(load_balancer);
+
+        let mut hits = HashMap::<Backend, usize>::new();
+        for _ in 0..80 {
+            *hits.entry(route.select_backend(None).unwrap()).or_default() += 1;
+        }
+        assert_eq!(hits[&heavy], 40);
+        assert_eq!(hits[&medium], 30);
+        assert_eq!(hits[&light], 10);
+
+        // The share of the heavy upstream goes 3 to 1 to the other ones
+        route.load_balancer.backends().set_enable(&heavy, false);
+        let mut hits = HashMap::<Backend, usize>::new();
+        for _ in 0..80 {
+            *hits.entry(route.select_backend(None).unwrap()).or_default() += 1;
+        }
+        assert_eq!(hits.get(&heavy), None);
+        assert_eq!(hits[&medium], 60);
+        assert_eq!(hits[&light], 20);
+    }
+
+    #[test]
+    fn test_random_selection_picks_healthy_upstreams() {
+        let mut route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4051", "127.0.0.1:4052"])
+                .unwrap(),
+        );
+        route.selection = RouteSelection::Random;
+
+        let down = Backend::new("127.0.0.1:4051").unwrap();
+        let up = Backend::new("127.0.0.1:4052").unwrap();
+        let picked = (0..50)
+            .filter_map(|_| route.select_backend(None))
+            .collect::<HashSet<_>>();
+        assert_eq!(picked.len(), 2);
+
+        route.load_balancer.backends().set_enable(&down, false);
+        for _ in 0..10 {
+            assert_eq!(route.select_backend(None), Some(up.clone()));
+        }
+    }
+
+    #[test]
+    fn test_upstreams_at_max_connections_are_skipped() {
+        let route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4005", "127.0.0.1:4006"])
+                .unwrap(),
+        );
+        let limited = Backend::new("127.0.0.1:4005").unwrap();
+        let other = Backend::new("127.0.0.1:4006").unwrap();
+        let upstream = |port: u16, max_connections: Option<usize>| RouteUpstream {
+            ip: Cow::Borrowed("127.0.0.1"),
+            port,
+            max_connections,
+            ..RouteUpstream::default()
+        };
+        let connections = &route.upstream_connections;
+        connections.update(&[upstream(4005, Some(1)), upstream(4006, None)]);
+
+        let connection = connections.open("limited.test", &limited.addr).unwrap();
+        assert!(!connections.available(&limited.addr));
+        assert!(connections.open("limited.test", &limited.addr).is_none());
+        for _ in 0..5 {
+            assert_eq!(route.select_backend(None), Some(other.clone()));
+        }
+
+        // Unbounded upstreams always have a connection
+        let _others = (0..10)
+            .map(|_| connections.open("limited.test", &other.addr).unwrap())
+            .collect::<Vec<_>>();
+
+        // The connection is released with its request, the slots survive updates
+        connections.update(&[upstream(4005, Some(1)), upstream(4006, None)]);
+        assert!(!connections.available(&limited.addr));
+        drop(connection);
+        assert!(connections.available(&limited.addr));
+
+        connections.update(&[upstream(4005, None), upstream(4006, None)]);
+        assert!(connections.open("limited.test", &limited.addr).is_some());
+    }
+
+    #[test]
+    fn test_backed_off_upstreams_are_skipped() {
+        let route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4007", "127.0.0.1:4008"])
+                .unwrap(),
+        );
+        let paused = Backend::new("127.0.0.1:4007").unwrap();
+        let other = Backend::new("127.0.0.1:4008").unwrap();
+
+        route
+            .backed_off_upstreams
+            .pause(&paused.addr, Duration::from_secs(30));
+        for _ in 0..5 {
+            assert_eq!(route.select_backend(None), Some(other.clone()));
+        }
+
+        // Picked again once the delay is over
+        let later = Instant::now() + Duration::from_secs(31);
+        assert!(!route.backed_off_upstreams.is_paused(&paused.addr, later));
+        assert!(route.backed_off_upstreams.until.is_empty());
+    }
+
+    #[test]
+    fn test_geo_routing_selects_the_pool_of_the_request() {
+        let mut route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter([
+                "127.0.0.1:4011",
+                "127.0.0.1:4012",
+                "127.0.0.1:4013",
+            ])
+            .unwrap(),
+        );
+        route.geo_routing = Some(GeoRouting {
+            header: HeaderName::from_static("cf-ipcountry"),
+            pools: HashMap::from([
+                ("DE".to_string(), "eu".to_string()),
+                ("US".to_string(), "us".to_string()),
+            ]),
+            upstreams: HashMap::from([
+                ("127.0.0.1:4011".parse().unwrap(), "eu".to_string()),
+                ("127.0.0.1:4012".parse().unwrap(), "us".to_string()),
+            ]),
+        });
+
+        let eu = Backend::new("127.0.0.1:4011").unwrap();
+        let default = Backend::new("127.0.0.1:4013").unwrap();
+        let geo = route.geo_routing.as_ref().unwrap();
+
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        assert_eq!(geo.pool(&request), None);
+        request.insert_header("cf-ipcountry", "BR").unwrap();
+        assert_eq!(geo.pool(&request), None);
+        request.insert_header("cf-ipcountry", "de").unwrap();
+        assert_eq!(geo.pool(&request), Some("eu"));
+
+        for _ in 0..5 {
+            assert_eq!(route.select_backend(Some("eu")), Some(eu.clone()));
+            assert_eq!(route.select_backend(None), Some(default.clone()));
+        }
+
+        // Without a healthy upstream, the pool falls back to the default one
+        route.load_balancer.backends().set_enable(&eu, false);
+        assert_eq!(route.select_backend(Some("eu")), Some(default));
+    }
+
+    #[test]
+    fn test_path_groups_select_the_upstreams_of_the_path() {
+        let mut route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4031", "127.0.0.1:4032"])
+                .unwrap(),
+        );
+        let mut api = RouteStorePathMatcher::new();
+        api.with_pattern(&["/api/*".into()]);
+        route.path_groups = Some(PathGroups {
+            groups: vec![(api, HashSet::from(["127.0.0.1:4031".parse().unwrap()]))],
+        });
+
+        let api = Backend::new("127.0.0.1:4031").unwrap();
+        let default = Backend::new("127.0.0.1:4032").unwrap();
+        let groups = route.path_groups.as_ref().unwrap();
+        assert_eq!(groups.group("/api/users"), Some(0));
+        assert_eq!(groups.group("/"), None);
+        assert_eq!(groups.group("/apis"), None);
+
+        for _ in 0..5 {
+            assert_eq!(
+                route.select_backend_for(None, Some(0), None),
+                Some(api.clone())
+            );
+            assert_eq!(
+                route.select_backend_for(None, None, None),
+                Some(default.clone())
+            );
+        }
+
+        // A group without a healthy upstream does not use the other ones
+        route.load_balancer.backends().set_enable(&api, false);
+        assert_eq!(route.select_backend_for(None, Some(0), None), None);
+    }
+
+    #[test]
+    fn test_zone_aware_selection_prefers_local_upstreams() {
+        let mut route = RouteStoreContainer::new(
+            LoadBalancer::<RoundRobin>::try_from_iter([
+                "127.0.0.1:4021",
+                "127.0.0.1:4022",
+                "127.0.0.1:4023",
+            ])
+            .unwrap(),
+        );
+        route.local_upstreams = Some(HashSet::from([
+            "127.0.0.1:4021".parse().unwrap(),
+            "127.0.0.1:4022".parse().unwrap(),
+        ]));
+
+        let local = [
+            Backend::new("127.0.0.1:4021").unwrap(),
+            Backend::new("127.0.0.1:4022").unwrap(),
+        ];
+        let remote = Backend::new("127.0.0.1:4023").unwrap();
+
+        for selection in [RouteSelection::RoundRobin, RouteSelection::LeastRequest] {
+            route.selection = selection;
+            for backend in &local {
+                route.load_balancer.backends().set_enable(backend, true);
+            }
+
+            for _ in 0..10 {
+                let selected = route.select_backend(None).unwrap();
+                assert!(local.contains(&selected));
+            }
+
+            // The remote zones are only used once every local upstream is down
+            route.load_balancer.backends().set_enable(&local[0], false);
+            assert_eq!(route.select_backend(None), Some(local[1].clone()));
+            route.load_balancer.backends().set_enable(&local[1], false);
+            assert_eq!(route.select_backend(None), Some(remote.clone()));
+        }
+    }
+
+    #[test]
+    fn test_warmup_ends_once_an_upstream_is_healthy() {
+        let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4030"]).unwrap();
+        let backends = load_balancer.backends();
+        let backend = Backend::new("127.0.0.1:4030").unwrap();
+        let warmup = Warmup::new(Duration::from_secs(30));
+        let now = warmup.created_at + Duration::from_secs(5);
+
+        // Not healthy yet: warming until the end of the period
+        backends.set_enable(&backend, false);
+        warmup.record(backends, now);
+        assert!(warmup.is_warming(now));
+        assert!(!warmup.is_warming(warmup.created_at + Duration::from_secs(30)));
+
+        backends.set_enable(&backend, true);
+        warmup.record(backends, now);
+        assert!(!warmup.is_warming(now));
+        assert_eq!(warmup.first_healthy_at(), Some(now));
+
+        // Not warming again when the upstream turns unhealthy
+        backends.set_enable(&backend, false);
+        warmup.record(backends, now + Duration::from_secs(1));
+        assert!(!warmup.is_warming(now + Duration::from_secs(1)));
+        assert_eq!(warmup.first_healthy_at(), Some(now));
+    }
 }
@@ -0,0 +1,366 @@
+//! Clients sent to the same upstream of a route, by IP (`sticky.by: ip`) or by
+//! the subject of their JWT (`sticky.by: jwt:sub`, validated by the `oauth2`
+//! plugin before the upstream is selected, the IP otherwise).
+//!
+//! The key of the client is hashed on a consistent hashing ring of the
+//! upstreams, where each upstream owns `virtual_nodes` points per unit of
+//! weight. Unhealthy upstreams are skipped on the ring, so only their own
+//! clients move (to the next upstream of the ring). Clients are also pinned to
+//! their upstream until they stop sending requests for `ttl_secs`, so they stay
+//! there when the ring changes (ex: an upstream comes back or is added).
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use pingora::lb::{selection::RoundRobin, Backend, LoadBalancer};
+
+use crate::config::RouteSticky;
+
+/// FNV-1a, finished by the splitmix64 mixer so close inputs (ex: the points
+/// of an upstream) spread over the whole ring
+fn hash(seed: u64, parts: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64 ^ seed;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// The ring of a set of upstreams
+struct Ring {
+    backends: Arc<BTreeSet<Backend>>,
+    nodes: Vec<Backend>,
+    /// The points of the upstreams (their index in `nodes`), sorted by hash
+    points: Vec<(u64, usize)>,
+    seed: u64,
+}
+
+impl Ring {
+    fn new(backends: Arc<BTreeSet<Backend>>, virtual_nodes: usize, seed: u64) -> Self {
+        let nodes = backends.iter().cloned().collect::<Vec<_>>();
+
+        let mut points = Vec::new();
+        for (index, backend) in nodes.iter().enumerate() {
+            let addr = backend.addr.to_string();
+            for point in 0..virtual_nodes * backend.weight.max(1) {
+                let point = (point as u64).to_le_bytes();
+                points.push((hash(seed, &[addr.as_bytes(), &point]), index));
+            }
+        }
+        points.sort_unstable();
+        points.dedup_by_key(|(hash, _)| *hash);
+
+        Ring {
+            backends,
+            nodes,
+            points,
+            seed,
+        }
+    }
+
+    /// The first usable upstream of the ring, starting at the point of the key
+    fn find(&self, key: &[u8], usable: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let key = hash(self.seed, &[key]);
+        let start = self.points.partition_point(|(hash, _)| *hash < key);
+        let mut tried = HashSet::new();
+
+        for step in 0..self.points.len() {
+            let (_, index) = self.points[(start + step) % self.points.len()];
+            let backend = &self.nodes[index];
+            if usable(backend) {
+                return Some(backend.clone());
+            }
+
+            tried.insert(index);
+            if tried.len() >= self.nodes.len() {
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+/// What pins a client to its upstream
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StickyKey {
+    Ip(IpAddr),
+    /// The `sub` claim of the JWT of the client
+    Subject(String),
+}
+
+impl StickyKey {
+    /// The bytes hashed on the ring
+    fn ring_key(&self) -> Vec<u8> {
+        match self {
+            StickyKey::Ip(ip) => ip.to_string().into_bytes(),
+            StickyKey::Subject(subject) => subject.as_bytes().to_vec(),
+        }
+    }
+}
+
+struct Pin {
+    backend: Backend,
+    last_request: Instant,
+}
+
+/// The upstream of each client of a route
+pub struct StickyClients {
+    pub config: RouteSticky,
+    ring: ArcSwap<Ring>,
+    pins: DashMap<StickyKey, Pin>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl StickyClients {
+    pub fn new(config: &RouteSticky) -> Self {
+        StickyClients {
+            config: config.clone(),
+            ring: ArcSwap::from_pointee(Ring::new(
+                Arc::default(),
+                config.virtual_nodes,
+                config.hash_seed.unwrap_or_default(),
+            )),
+            pins: DashMap::new(),
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_entries: config.max_entries,
+        }
+    }
+
+    /// Number of clients pinned to an upstream
+    pub fn pinned(&self) -> usize {
+        self.pins.len()
+    }
+
+    /// The ring of the current upstreams, rebuilt when they change
+    fn ring(&self, load_balancer: &LoadBalancer<RoundRobin>) -> Arc<Ring> {
+        let backends = load_balancer.backends().get_backend();
+        let ring = self.ring.load_full();
+        if Arc::ptr_eq(&ring.backends, &backends) {
+            return ring;
+        }
+
+        let ring = Arc::new(Ring::new(
+            backends,
+            self.config.virtual_nodes,
+            self.config.hash_seed.unwrap_or_default(),
+        ));
+        self.ring.store(ring.clone());
+        ring
+    }
+
+    /// Selects the upstream of the client among the healthy (and eligible) ones
+    pub fn select(
+        &self,
+        load_balancer: &LoadBalancer<RoundRobin>,
+        client: &StickyKey,
+        eligible: impl Fn(&Backend) -> bool,
+    ) -> Option<Backend> {
+        let now = Instant::now();
+        let ring = self.ring(load_balancer);
+        let usable = |backend: &Backend| {
+            load_balancer.backends().ready(backend)
+                && eligible(backend)
+                && ring.backends.contains(backend)
+        };
+
+        if let Some(mut pin) = self.pins.get_mut(client) {
+            if now.saturating_duration_since(pin.last_request) < self.ttl && usable(&pin.backend) {
+                pin.last_request = now;
+                return Some(pin.backend.clone());
+            }
+        }
+
+        let backend = ring.find(&client.ring_key(), usable)?;
+        self.pin(client, &backend, now);
+        Some(backend)
+    }
+
+    fn pin(&self, client: &StickyKey, backend: &Backend, now: Instant) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        if self.pins.len() >= self.max_entries && !self.pins.contains_key(client) {
+            self.pins
+                .retain(|_, pin| now.saturating_duration_since(pin.last_request) < self.ttl);
+
+            // Still full: the client only relies on the ring
+            if self.pins.len() >= self.max_entries {
+                return;
+            }
+        }
+
+        self.pins.insert(
+            client.clone(),
+            Pin {
+                backend: backend.clone(),
+                last_request: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RouteStickyBy;
+
+    use super::*;
+
+    fn sticky(ttl_secs: u64) -> StickyClients {
+        StickyClients::new(&RouteSticky {
+            by: RouteStickyBy::Ip,
+            ttl_secs,
+            max_entries: 100,
+            virtual_nodes: 160,
+            hash_seed: None,
+        })
+    }
+
+    fn clients() -> Vec<StickyKey> {
+        (1..=50)
+            .map(|i| StickyKey::Ip(IpAddr::from([10, 0, 0, i])))
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn test_clients_keep_their_upstream() {
+        let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter([
+            "127.0.0.1:4021",
+            "127.0.0.1:4022",
+            "127.0.0.1:4023",
+        ])
+        .unwrap();
+        // Without pins, the ring alone
+        let sticky = sticky(0);
+
+        let selected = clients()
+            .into_iter()
+            .map(|ip| {
+                let backend = sticky.select(&load_balancer, &ip, |_| true).unwrap();
+                (ip, backend)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sticky.pinned(), 0);
+
+        // The clients are spread over the upstreams, always to the same one
+        let used = selected.iter().map(|(_, b)| b).collect::<HashSet<_>>();
+        assert_eq!(used.len(), 3);
+        for (ip, backend) in &selected {
+            assert_eq!(
+                sticky.select(&load_balancer, ip, |_| true).as_ref(),
+                Some(backend)
+            );
+        }
+
+        // Only the clients of an unhealthy upstream move
+        let down = selected[0].1.clone();
+        load_balancer.backends().set_enable(&down, false);
+        for (ip, backend) in &selected {
+            let now = sticky.select(&load_balancer, ip, |_| true).unwrap();
+            if *backend == down {
+                assert_ne!(now, down);
+            } else {
+                assert_eq!(now, *backend);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pinned_clients_stay_when_upstream_comes_back() {
+        let load_balancer =
+            LoadBalancer::<RoundRobin>::try_from_iter(["127.0.0.1:4031", "127.0.0.1:4032"])
+                .unwrap();
+        let sticky = sticky(3600);
+
+        let client = &clients()[0];
+        let first = sticky.select(&load_balancer, client, |_| true).unwrap();
+        assert_eq!(sticky.pinned(), 1);
+
+        load_balancer.backends().set_enable(&first, false);
+        let moved = sticky.select(&load_balancer, client, |_| true).unwrap();
+        assert_ne!(moved, first);
+
+        // Back to healthy: the client is pinned to its new upstream
+        load_balancer.backends().set_enable(&first, true);
+        assert_eq!(sticky.select(&load_balancer, client, |_| true), Some(moved));
+
+        // Nothing is usable
+        assert_eq!(sticky.select(&load_balancer, client, |_| false), None);
+    }
+
+    #[test]
+    fn test_clients_pinned_by_subject() {
+        let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter([
+            "127.0.0.1:4051",
+            "127.0.0.1:4052",
+            "127.0.0.1:4053",
+        ])
+        .unwrap();
+        let sticky = sticky(3600);
+
+        // The same user keeps its upstream, whatever its IP
+        let user = StickyKey::Subject("user-1".to_string());
+        let first = sticky.select(&load_balancer, &user, |_| true).unwrap();
+        for _ in 0..10 {
+            assert_eq!(
+                sticky.select(&load_balancer, &user, |_| true).as_ref(),
+                Some(&first)
+            );
+        }
+
+        let users = (1..=50)
+            .map(|i| StickyKey::Subject(format!("user-{i}")))
+            .map(|user| sticky.select(&load_balancer, &user, |_| true).unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(users.len(), 3);
+    }
+
+    #[test]
+    fn test_ring_placement() {
+        let backends = ["127.0.0.1:4041", "127.0.0.1:4042", "127.0.0.1:4043"]
+            .into_iter()
+            .map(|addr| Backend::new(addr).unwrap())
+            .collect::<BTreeSet<_>>();
+        let backends = Arc::new(backends);
+        let placement = |ring: &Ring| {
+            clients()
+                .into_iter()
+                .map(|ip| ring.find(&ip.ring_key(), |_| true).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // The same on every instance, unless the seed differs
+        let ring = Ring::new(backends.clone(), 160, 0);
+        assert_eq!(ring.points.len(), 3 * 160);
+        assert_eq!(
+            placement(&ring),
+            placement(&Ring::new(backends.clone(), 160, 0))
+        );
+        assert_ne!(
+            placement(&ring),
+            placement(&Ring::new(backends.clone(), 160, 7))
+        );
+
+        // Removing an upstream only moves its own clients
+        let mut fewer = (*backends).clone();
+        let removed = fewer.pop_last().unwrap();
+        let fewer = Ring::new(Arc::new(fewer), 160, 0);
+        for (before, after) in placement(&ring).into_iter().zip(placement(&fewer)) {
+            if before != removed {
+                assert_eq!(before, after);
+            }
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::{net::IpAddr, str::FromStr};
+
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use pingora::proxy::Session;
+
+use crate::{config::ClientIp, proxy_server::proxy_protocol};
+
+/// The resolver configured at startup (see `init`)
+static RESOLVER: OnceCell<Resolver> = OnceCell::new();
+
+/// An IP network in CIDR notation (ex: `10.0.0.0/8`, `2001:db8::/32`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr = IpAddr::from_str(addr.trim()).map_err(|e| anyhow!("invalid CIDR {s}: {e}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = if prefix.is_empty() {
+            max_prefix
+        } else {
+            prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| anyhow!("invalid CIDR prefix in {s}"))?
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Resolves the IP of the client that originated a request, taking into account
+/// the proxies (ex: a CDN or load balancer) that are trusted to set `X-Forwarded-For`.
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    trusted_proxies: Vec<IpNetwork>,
+    trust_hops: Option<usize>,
+}
+
+impl Resolver {
+    pub fn from_config(config: &ClientIp) -> Result<Self, anyhow::Error> {
+        if config.trust_hops.is_some() && !config.trusted_proxies.is_empty() {
+            return Err(anyhow!(
+                "client_ip.trust_hops and client_ip.trusted_proxies cannot be used together"
+            ));
+        }
+
+        let trusted_proxies = config
+            .trusted_proxies
+            .iter()
+            .map(|cidr| IpNetwork::from_str(cidr))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            trusted_proxies,
+            trust_hops: config.trust_hops,
+        })
+    }
+
+    /// Returns the client IP given the address of the peer connected to proksi
+    /// and the value of its `X-Forwarded-For` header.
+    ///
+    /// - With `trust_hops = N`, the Nth-from-last entry of the header is used
+    ///   (the leftmost one if there are fewer entries).
+    /// - With `trusted_proxies`, the header is only read when the peer is trusted,
+    ///   and the rightmost entry that is not a trusted proxy is used.
+    /// - Otherwise the header is ignored and the peer address is used.
+    pub fn resolve(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let forwarded = || {
+            forwarded_for
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        if let Some(hops) = self.trust_hops.filter(|hops| *hops > 0) {
+            let entries = forwarded();
+            let Some(entry) = entries.get(entries.len().saturating_sub(hops)) else {
+                return peer;
+            };
+
+            return IpAddr::from_str(entry).ok().or(peer);
+        }
+
+        let is_trusted = |ip: IpAddr| self.trusted_proxies.iter().any(|net| net.contains(ip));
+        let peer_ip = peer?;
+        if !is_trusted(peer_ip) {
+            return Some(peer_ip);
+        }
+
+        let mut client = peer_ip;
+        for entry in forwarded().iter().rev() {
+            let Ok(ip) = IpAddr::from_str(entry) else {
+                // Anything left of an invalid entry cannot be trusted
+                break;
+            };
+
+            client = ip;
+            if !is_trusted(ip) {
+                break;
+            }
+        }
+
+        Some(client)
+    }
+
+    /// Whether the peer is a proxy carrying the requests of other clients: any
+    /// peer with `trust_hops`, a listed one with `trusted_proxies`
+    pub fn is_trusted_proxy(&self, peer: IpAddr) -> bool {
+        self.trust_hops.is_some_and(|hops| hops > 0)
+            || self.trusted_proxies.iter().any(|net| net.contains(peer))
+    }
+}
+
+/// Sets up the resolver used by `client_ip` from the configuration
+pub fn init(config: &ClientIp) -> Result<(), anyhow::Error> {
+    RESOLVER
+        .set(Resolver::from_config(config)?)
+        .map_err(|_| anyhow!("client ip resolver already initialized"))
+}
+
+/// Returns the IP of the peer connected to proksi, before `X-Forwarded-For`
+pub fn peer_ip(session: &Session) -> Option<IpAddr> {
+    // Behind a PROXY listener, the peer is the address the connection is relayed from
+    session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| proxy_protocol::client_addr(addr).unwrap_or(*addr))
+        .map(|addr| addr.ip())
+}
+
+/// Whether the peer is a trusted proxy of `client_ip`
+pub fn is_trusted_proxy(peer: IpAddr) -> bool {
+    RESOLVER
+        .get()
+        .is_some_and(|resolver| resolver.is_trusted_proxy(peer))
+}
+
+/// Returns the IP of the client that originated the request
+pub fn client_ip(session: &Session) -> Option<IpAddr> {
+    let peer = peer_ip(session);
+
+    let Some(resolver) = RESOLVER.get() else {
+        return peer;
+    };
+
+    let forwarded_for = session
+        .req_header()
+        .headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok());
+
+    resolver.resolve(peer, forwarded_for)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(IpAddr::from_str(value).unwrap())
+    }
+
+    fn resolver(trusted_proxies: &[&'static str], trust_hops: Option<usize>) -> Resolver {
+        Resolver::from_config(&ClientIp {
+            trusted_proxies: trusted_proxies.iter().map(|v| (*v).into()).collect(),
+            trust_hops,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ip_network() {
+        let net = IpNetwork::from_str("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("10.1.2.3").unwrap()));
+        assert!(!net.contains(ip("11.0.0.1").unwrap()));
+        assert!(IpNetwork::from_str("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("1.1.1.1").unwrap()));
+        assert!(IpNetwork::from_str("2001:db8::/32")
+            .unwrap()
+            .contains(ip("2001:db8::1").unwrap()));
+        assert!(IpNetwork::from_str("10.0.0.0/33").is_err());
+        assert!(IpNetwork::from_str("nope/8").is_err());
+    }
+
+    #[test]
+    fn test_resolve_without_trust() {
+        let resolver = resolver(&[], None);
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), Some("1.1.1.1")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_resolve_with_trust_hops() {
+        let resolver = resolver(&[], Some(2));
+        let header = Some("6.6.6.6, 1.1.1.1, 172.16.0.1");
+
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), header), ip("1.1.1.1"));
+        // Fewer entries than hops: the leftmost entry is the client
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), Some("1.1.1.1")),
+            ip("1.1.1.1")
+        );
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), None), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_resolve_with_trusted_proxies() {
+        let resolver = resolver(&["10.0.0.0/8", "172.16.0.1"], None);
+        let header = Some("6.6.6.6, 1.1.1.1, 172.16.0.1");
+
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), header), ip("1.1.1.1"));
+        // Untrusted peers cannot spoof the header
+        assert_eq!(resolver.resolve(ip("8.8.8.8"), header), ip("8.8.8.8"));
+    }
+
+    #[test]
+    fn test_is_trusted_proxy() {
+        let peer = ip("10.0.0.1").unwrap();
+        assert!(resolver(&["10.0.0.0/8"], None).is_trusted_proxy(peer));
+        assert!(!resolver(&["172.16.0.1"], None).is_trusted_proxy(peer));
+        assert!(resolver(&[], Some(1)).is_trusted_proxy(peer));
+        assert!(!resolver(&[], None).is_trusted_proxy(peer));
+    }
+
+    #[test]
+    fn test_trust_hops_and_proxies_are_exclusive() {
+        let config = ClientIp {
+            trusted_proxies: vec!["10.0.0.0/8".into()],
+            trust_hops: Some(1),
+        };
+        assert!(Resolver::from_config(&config).is_err());
+    }
+}
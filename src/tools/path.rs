@@ -0,0 +1,143 @@
+//! Normalization of the request paths, so the routes and `blocked_paths` match
+//! the path the upstream resolves, whatever its encoding: the path is
+//! percent-decoded once, `\` is read as `/`, empty and `.` segments are dropped
+//! and `..` segments are resolved (without going above the root).
+//!
+//! `/api/%2e%2e/admin`, `/api/..%2fadmin` and `//admin` are all `/admin`.
+
+use std::borrow::Cow;
+
+use http::{uri::PathAndQuery, Uri};
+
+/// Decodes the `%XX` sequences of a path, the other bytes are kept as they are
+fn percent_decode(path: &str) -> Cow<'_, str> {
+    if !path.contains('%') {
+        return Cow::Borrowed(path);
+    }
+
+    let hex = |b: u8| char::from(b).to_digit(16);
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// The decoded path as the upstream would resolve it, always starting with `/`.
+/// A trailing slash is kept (`/.git/` is still a directory).
+pub fn normalize(path: &str) -> String {
+    let decoded = percent_decode(path).replace('\\', "/");
+
+    let mut segments: Vec<&str> = vec![];
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if decoded.ends_with('/') && !segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Encodes a normalized path back for a request URI: the bytes other than the
+/// unreserved ones, the sub-delimiters, `:`, `@` and `/` are percent-encoded
+pub fn encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// The URI with its path normalized (and encoded back), `None` when it is unchanged
+pub fn normalized_uri(uri: &Uri) -> Option<Uri> {
+    let path = encode(&normalize(uri.path()));
+    if path == uri.path() {
+        return None;
+    }
+
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_traversals() {
+        assert_eq!(normalize("/"), "/");
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("/api/users"), "/api/users");
+        assert_eq!(normalize("/a//b/./c"), "/a/b/c");
+        assert_eq!(normalize("/a/../../b/"), "/b/");
+        assert_eq!(normalize("/../../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize("/dir\\..\\.ssh\\id_rsa"), "/.ssh/id_rsa");
+        assert_eq!(normalize("/api/./admin/."), "/api/admin");
+    }
+
+    #[test]
+    fn test_normalize_encodings() {
+        assert_eq!(normalize("/api/%2e%2e/admin"), "/admin");
+        assert_eq!(normalize("/api/%2E%2E/admin"), "/admin");
+        assert_eq!(normalize("/api/..%2fadmin"), "/admin");
+        assert_eq!(normalize("/api/%2e%2e%5cadmin"), "/admin");
+        assert_eq!(normalize("/%2egit/%63onfig"), "/.git/config");
+        assert_eq!(normalize("/caf%C3%A9"), "/café");
+        // Decoded once: `%252e` is the literal `%2e` for the upstream too
+        assert_eq!(normalize("/api/%252e%252e/admin"), "/api/%2e%2e/admin");
+        // Invalid sequences are kept
+        assert_eq!(normalize("/100%/%zz%4"), "/100%/%zz%4");
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("/api/users"), "/api/users");
+        assert_eq!(encode("/a b/café"), "/a%20b/caf%C3%A9");
+        assert_eq!(encode("/what?/100%"), "/what%3F/100%25");
+        assert_eq!(
+            encode(&normalize("/docs/%7Euser/a%20b")),
+            "/docs/~user/a%20b"
+        );
+    }
+
+    #[test]
+    fn test_normalized_uri() {
+        let uri = Uri::from_static("/api/%2e%2e/admin?q=%2e%2e");
+        assert_eq!(normalized_uri(&uri).unwrap(), "/admin?q=%2e%2e");
+
+        let uri = Uri::from_static("https://example.com//a/./b");
+        assert_eq!(normalized_uri(&uri).unwrap(), "https://example.com/a/b");
+
+        assert_eq!(normalized_uri(&Uri::from_static("/api/users?page=2")), None);
+    }
+}
@@ -0,0 +1,105 @@
+//! End to end tests of the admin API
+
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{free_port, http_request, route, MockUpstream, Proksi};
+
+fn start_with_admin(routes: &str) -> (Proksi, SocketAddr) {
+    let admin: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let proksi = Proksi::start_with_config(
+        routes,
+        &format!("admin:\n  enabled: true\n  bind: \"{admin}\"\n  token: \"secret\"\n"),
+    );
+    (proksi, admin)
+}
+
+fn test_route(admin: SocketAddr, token: &str, body: &str) -> common::Response {
+    let auth = format!("Bearer {token}");
+    http_request(
+        admin,
+        "POST",
+        "/routes/test",
+        &[
+            ("authorization", &auth),
+            ("content-type", "application/json"),
+        ],
+        body,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_admin_route_test() {
+    let upstream = MockUpstream::start("a");
+    let path_matcher = "    match_with:\n      path:\n        patterns: [\"/api/*\"]\n";
+    let (proksi, admin) = start_with_admin(&route("admin.test", &[upstream.addr], path_matcher));
+    proksi.wait_for_path("admin.test", "/api/ready");
+
+    // The token is required
+    let res = test_route(admin, "wrong", r#"{"host": "admin.test"}"#);
+    assert_eq!(res.status, 401);
+
+    let res = test_route(
+        admin,
+        "secret",
+        r#"{"method": "POST", "host": "admin.test:443", "path": "/api/users?page=2"}"#,
+    );
+    assert_eq!(res.status, 200);
+    let result: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(result["matched"], true);
+    assert_eq!(result["route"]["host"], "admin.test");
+    assert_eq!(result["route"]["pattern"], "/api/*");
+    assert_eq!(result["upstream"]["port"], upstream.addr.port());
+
+    // The host header is used when no host is given
+    let res = test_route(
+        admin,
+        "secret",
+        r#"{"path": "/other", "headers": {"Host": "admin.test"}}"#,
+    );
+    let result: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(result["matched"], false);
+    assert_eq!(
+        result["reason"],
+        "the path does not match the patterns of the route"
+    );
+
+    let res = test_route(admin, "secret", r#"{"host": "unknown.test"}"#);
+    let result: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(result["reason"], "no route for the host");
+
+    let res = test_route(admin, "secret", "{}");
+    assert_eq!(res.status, 400);
+}
+
+#[test]
+fn test_version_info() {
+    let (_proksi, admin) = start_with_admin("");
+
+    let res = http_request(
+        admin,
+        "GET",
+        "/proksi/version",
+        &[("authorization", "Bearer secret")],
+        "",
+    )
+    .unwrap();
+    assert_eq!(res.status, 200);
+    let info: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert!(info["pingora_version"].is_string());
+
+    // `proksi version` prints the same information, without any configuration
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_proksi"))
+        .args(["--config-path", "/nonexistent", "version"])
+        .output()
+        .unwrap();
+    let printed = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success());
+    assert!(
+        printed.contains(info["git_commit"].as_str().unwrap()),
+        "{printed}"
+    );
+}
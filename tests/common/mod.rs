@@ -0,0 +1,585 @@
+//! Test harness that starts proksi (the compiled binary) with a generated
+//! configuration, in front of in-process mock HTTP upstreams.
+//! Each test crate only uses part of it.
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+
+static INSTANCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a port that is free at the time of the call
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("no free port available")
+}
+
+/// A request as received by a mock upstream
+#[derive(Debug, Clone)]
+struct ReceivedRequest {
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+/// An HTTP/1.1 upstream running in a background thread.
+/// Every response carries the name of the upstream in the `x-upstream` header
+/// (and body) plus an `x-secret` header that routes can choose to remove.
+pub struct MockUpstream {
+    pub name: &'static str,
+    pub addr: SocketAddr,
+}
+
+impl MockUpstream {
+    pub fn start(name: &'static str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind mock upstream");
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || Self::handle(name, stream));
+            }
+        });
+
+        Self { name, addr }
+    }
+
+    fn handle(name: &str, stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        // TCP health checks connect and close without sending anything
+        let Some(request) = read_request(&mut reader) else {
+            return;
+        };
+
+        let mut stream = stream;
+
+        // `x-delay-ms` asks for the response to be sent that late, as a slow
+        // upstream would
+        if let Some(delay) = request.headers.get("x-delay-ms") {
+            thread::sleep(Duration::from_millis(delay.parse().unwrap_or(0)));
+        }
+
+        // `x-early-hints` asks for a `103 Early Hints` before the response
+        if request.headers.contains_key("x-early-hints") {
+            let hints = "HTTP/1.1 103 Early Hints\r\nlink: </style.css>; rel=preload\r\n\r\n";
+            stream.write_all(hints.as_bytes()).ok();
+        }
+
+        // The body of the requests sent with `Expect: 100-continue` is read
+        // once they are told to send it, its length is echoed back
+        let mut body_length = String::new();
+        if request.headers.get("expect").map(String::as_str) == Some("100-continue") {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").ok();
+        }
+        if let Some(length) = request.headers.get("content-length") {
+            let mut body = vec![0; length.parse().unwrap_or(0)];
+            reader.read_exact(&mut body).ok();
+            body_length = format!("x-body-length: {}\r\n", body.len());
+        }
+
+        let echo = request
+            .headers
+            .iter()
+            .fold(String::new(), |mut echo, (k, v)| {
+                let _ = write!(echo, "x-echo-{k}: {v}\r\n");
+                echo
+            });
+
+        // `x-content-type` asks for a (compressible) body of the given type
+        let (content_type, body) = match request.headers.get("x-content-type") {
+            Some(content_type) => (
+                format!("content-type: {content_type}\r\n"),
+                name.repeat(512),
+            ),
+            None => (String::new(), name.to_string()),
+        };
+
+        // `x-conflicting-length` frames the body both with chunks and a
+        // `Content-Length`, as a buggy upstream would
+        if request.headers.contains_key("x-conflicting-length") {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nx-upstream: {name}\r\ntransfer-encoding: chunked\r\ncontent-length: 1\r\nconnection: close\r\n\r\n{:x}\r\n{body}\r\n0\r\n\r\n",
+                body.len(),
+            );
+            stream.write_all(response.as_bytes()).ok();
+            return;
+        }
+
+        // `x-truncate` announces twice the length of the body that is sent,
+        // as an upstream dying mid-response would
+        let content_length = if request.headers.contains_key("x-truncate") {
+            body.len() * 2
+        } else {
+            body.len()
+        };
+
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nx-upstream: {name}\r\nx-secret: 1\r\nx-path: {}\r\n{echo}{body_length}{content_type}content-length: {content_length}\r\nconnection: close\r\n\r\n{body}",
+            request.path,
+        );
+
+        stream.write_all(response.as_bytes()).ok();
+    }
+}
+
+/// A WebSocket upstream running in a background thread. Each connection is
+/// greeted with a `hello` text frame, then gets back every frame it sends.
+/// Only the frames of up to 125 bytes are supported.
+pub struct WebSocketUpstream {
+    pub addr: SocketAddr,
+}
+
+impl WebSocketUpstream {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind websocket upstream");
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || Self::handle(stream));
+            }
+        });
+
+        Self { addr }
+    }
+
+    fn handle(stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let Some(request) = read_request(&mut reader) else {
+            return;
+        };
+
+        let mut stream = stream;
+        let upgrading = request
+            .headers
+            .get("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+        let Some(key) = request
+            .headers
+            .get("sec-websocket-key")
+            .filter(|_| upgrading)
+        else {
+            let response = "HTTP/1.1 426 Upgrade Required\r\nupgrade: websocket\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+            stream.write_all(response.as_bytes()).ok();
+            return;
+        };
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\nconnection: Upgrade\r\nsec-websocket-accept: {}\r\n\r\n",
+            websocket_accept(key)
+        );
+        stream.write_all(response.as_bytes()).ok();
+        stream.write_all(&websocket_frame(b"hello", None)).ok();
+
+        // The frames sent right after the request may already be buffered
+        while let Some(payload) = read_websocket_frame(&mut reader) {
+            if stream.write_all(&websocket_frame(&payload, None)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// The `Sec-WebSocket-Accept` answering the `Sec-WebSocket-Key` of a handshake
+pub fn websocket_accept(key: &str) -> String {
+    let digest =
+        openssl::sha::sha1(format!("{key}258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes());
+    openssl::base64::encode_block(&digest)
+}
+
+/// A (final) text frame, masked as the clients send them when given a mask
+pub fn websocket_frame(payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let length = u8::try_from(payload.len()).expect("frame too large");
+    assert!(length <= 125, "frame too large");
+
+    let mut frame = vec![0x81];
+    match mask {
+        Some(mask) => {
+            frame.push(0x80 | length);
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        }
+        None => {
+            frame.push(length);
+            frame.extend_from_slice(payload);
+        }
+    }
+    frame
+}
+
+/// Reads the payload of a frame (unmasked), `None` once the connection is
+/// closed (or a close frame received)
+pub fn read_websocket_frame(reader: &mut impl Read) -> Option<Vec<u8>> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).ok()?;
+    if head[0] & 0x0f == 0x08 {
+        return None;
+    }
+
+    let mut mask = [0; 4];
+    if head[1] & 0x80 != 0 {
+        reader.read_exact(&mut mask).ok()?;
+    }
+
+    let mut payload = vec![0; usize::from(head[1] & 0x7f)];
+    reader.read_exact(&mut payload).ok()?;
+    for (byte, mask) in payload.iter_mut().zip(mask.iter().cycle()) {
+        *byte ^= mask;
+    }
+    Some(payload)
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<ReceivedRequest> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok().filter(|n| *n > 0)?;
+    let path = line.split_whitespace().nth(1)?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok().filter(|n| *n > 0)?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.insert(name.to_lowercase(), value.trim().to_string());
+    }
+
+    Some(ReceivedRequest { path, headers })
+}
+
+/// A response received from proksi
+#[derive(Debug)]
+pub struct Response {
+    /// Statuses of the `1xx` responses received before the final one
+    pub informational: Vec<u16>,
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// A running proksi process, killed when dropped
+pub struct Proksi {
+    child: Child,
+    dir: PathBuf,
+    https_addr: SocketAddr,
+}
+
+impl Proksi {
+    /// Starts proksi with the given YAML `routes` (a list, indented as a top-level key)
+    pub fn start(routes: &str) -> Self {
+        Self::start_with_config(routes, "")
+    }
+
+    /// Same as `start`, with extra top-level YAML configuration (ex: `streams`)
+    pub fn start_with_config(routes: &str, extra: &str) -> Self {
+        let routes = if routes.is_empty() { "  []" } else { routes };
+
+        let id = INSTANCE.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!("proksi-test-{}-{id}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let https_addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+        let config = format!(
+            r#"
+lets_encrypt:
+  enabled: false
+docker:
+  enabled: false
+auto_reload:
+  enabled: false
+logging:
+  level: warn
+  access_logs_enabled: false
+paths:
+  lets_encrypt: "{lets_encrypt}"
+  pid_file: "{pid_file}"
+  upgrade_sock: "{upgrade_sock}"
+listeners:
+  http_address: "127.0.0.1:{http_port}"
+  https_address: "{https_addr}"
+routes:
+{routes}
+{extra}
+"#,
+            lets_encrypt = dir.join("certificates").to_string_lossy(),
+            pid_file = dir.join("proksi.pid").to_string_lossy(),
+            upgrade_sock = dir.join("upgrade.sock").to_string_lossy(),
+            http_port = free_port(),
+        );
+        fs::write(dir.join("proksi.yaml"), config).unwrap();
+
+        let child = Self::spawn(&dir, &[]);
+
+        Self {
+            child,
+            dir,
+            https_addr,
+        }
+    }
+
+    fn spawn(dir: &PathBuf, args: &[&str]) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_proksi"))
+            .arg("--config-path")
+            .arg(dir)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start proksi")
+    }
+
+    /// Runs `proksi upgrade` with the same configuration. The new process
+    /// replaces the running one, which is returned (it is not killed on drop).
+    pub fn upgrade(&mut self) -> Child {
+        let pid_file = self.dir.join("proksi.pid");
+        let deadline = Instant::now() + Duration::from_secs(15);
+        while !pid_file.exists() {
+            assert!(
+                Instant::now() < deadline,
+                "proksi did not write its pid file"
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let child = Self::spawn(&self.dir, &["upgrade"]);
+        std::mem::replace(&mut self.child, child)
+    }
+
+    /// Sends a GET request for the given host (used both as SNI and `Host` header)
+    pub fn get(&self, host: &str, path: &str) -> std::io::Result<Response> {
+        self.get_with_headers(host, path, &[])
+    }
+
+    /// Same as `get`, with extra request headers
+    pub fn get_with_headers(
+        &self,
+        host: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> std::io::Result<Response> {
+        let mut stream = self.connect(host)?;
+
+        let headers = headers.iter().fold(String::new(), |mut list, (k, v)| {
+            let _ = write!(list, "{k}: {v}\r\n");
+            list
+        });
+        let request =
+            format!("GET {path} HTTP/1.1\r\nhost: {host}\r\n{headers}connection: close\r\n\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        let mut raw = Vec::new();
+        // proksi closes the TLS session without a close_notify in some cases
+        stream.read_to_end(&mut raw).ok();
+        parse_response(&raw)
+    }
+
+    /// Sends a POST request with `Expect: 100-continue`, its body only once
+    /// told to with a `100 Continue`
+    pub fn post_expecting_continue(
+        &self,
+        host: &str,
+        path: &str,
+        body: &str,
+    ) -> std::io::Result<Response> {
+        let mut stream = self.connect(host)?;
+
+        let request = format!(
+            "POST {path} HTTP/1.1\r\nhost: {host}\r\nexpect: 100-continue\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(request.as_bytes())?;
+
+        let mut raw = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&raw).contains("\r\n\r\n") {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return parse_response(&raw);
+            }
+            raw.extend_from_slice(&buf[..n]);
+        }
+
+        stream.write_all(body.as_bytes())?;
+        stream.read_to_end(&mut raw).ok();
+        parse_response(&raw)
+    }
+
+    /// Opens a WebSocket on the given host, returning the response to the
+    /// handshake and the connection the frames are then exchanged on
+    pub fn websocket(
+        &self,
+        host: &str,
+        path: &str,
+        key: &str,
+    ) -> std::io::Result<(Response, SslStream<TcpStream>)> {
+        let mut stream = self.connect(host)?;
+
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nhost: {host}\r\nupgrade: websocket\r\nconnection: Upgrade\r\nsec-websocket-key: {key}\r\nsec-websocket-version: 13\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes())?;
+
+        // Read byte by byte, the frames of the upstream can follow the response
+        let mut raw = Vec::new();
+        let mut byte = [0; 1];
+        while !raw.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte)?;
+            raw.push(byte[0]);
+        }
+
+        Ok((parse_response(&raw)?, stream))
+    }
+
+    fn connect(&self, host: &str) -> std::io::Result<SslStream<TcpStream>> {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let connector = connector.build();
+
+        let stream = TcpStream::connect_timeout(&self.https_addr, Duration::from_secs(2))?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        connector
+            .configure()
+            .unwrap()
+            .verify_hostname(false)
+            .connect(host, stream)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+    }
+
+    /// Waits until proksi serves the given host (routes are loaded asynchronously)
+    pub fn wait_for_route(&self, host: &str) {
+        self.wait_for_path(host, "/");
+    }
+
+    /// Same as `wait_for_route`, for routes that only match some paths
+    pub fn wait_for_path(&self, host: &str, path: &str) {
+        let deadline = Instant::now() + Duration::from_secs(15);
+
+        while Instant::now() < deadline {
+            if self.get(host, path).is_ok_and(|res| res.status != 404) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        panic!("proksi did not start serving {host} in time");
+    }
+}
+
+impl Drop for Proksi {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Sends a plain HTTP/1.1 request (ex: to the admin API), retrying the
+/// connection until the listener is up
+pub fn http_request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> std::io::Result<Response> {
+    let deadline = Instant::now() + Duration::from_secs(15);
+    let mut stream = loop {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(2)) {
+            Ok(stream) => break stream,
+            Err(err) if Instant::now() > deadline => return Err(err),
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let headers = headers.iter().fold(String::new(), |mut list, (k, v)| {
+        let _ = write!(list, "{k}: {v}\r\n");
+        list
+    });
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nhost: {addr}\r\n{headers}content-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).ok();
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> std::io::Result<Response> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid response");
+    let text = String::from_utf8_lossy(raw);
+    let mut informational = Vec::new();
+    let mut rest = text.as_ref();
+    let (head, body, status) = loop {
+        let (head, body) = rest.split_once("\r\n\r\n").ok_or_else(invalid)?;
+        let status: u16 = head
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(invalid)?;
+
+        // The `1xx` responses come before the final one
+        if !(100..200).contains(&status) || status == 101 {
+            break (head, body, status);
+        }
+        informational.push(status);
+        rest = body;
+    };
+    let mut lines = head.lines();
+    lines.next();
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.to_lowercase(), v.trim().to_string()))
+        .collect();
+
+    Ok(Response {
+        informational,
+        status,
+        headers,
+        body: body.to_string(),
+    })
+}
+
+/// Formats the `upstreams` (YAML list items) of a route or stream
+pub fn upstreams(upstreams: &[SocketAddr]) -> String {
+    upstreams.iter().fold(String::new(), |mut list, addr| {
+        let _ = write!(
+            list,
+            "      - ip: \"{}\"\n        port: {}\n",
+            addr.ip(),
+            addr.port()
+        );
+        list
+    })
+}
+
+/// Formats a route (YAML list item) for the given host and upstreams
+pub fn route(host: &str, upstreams: &[SocketAddr], extra: &str) -> String {
+    let upstreams = self::upstreams(upstreams);
+    format!("  - host: \"{host}\"\n    upstreams:\n{upstreams}{extra}")
+}
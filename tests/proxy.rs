@@ -0,0 +1,697 @@
+//! End to end tests of the HTTPS router against mock upstreams
+
+mod common;
+
+use std::{collections::HashMap, io::Write};
+
+use common::{
+    free_port, read_websocket_frame, route, websocket_frame, MockUpstream, Proksi,
+    WebSocketUpstream,
+};
+
+#[test]
+fn test_routes_requests_by_host() {
+    let a = MockUpstream::start("a");
+    let b = MockUpstream::start("b");
+    let routes = [
+        route("a.test", &[a.addr], ""),
+        route("b.test", &[b.addr], ""),
+    ]
+    .join("");
+
+    let proksi = Proksi::start(&routes);
+    proksi.wait_for_route("a.test");
+    proksi.wait_for_route("b.test");
+
+    let res = proksi.get("a.test", "/hello?x=1").unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, a.name);
+    assert_eq!(res.header("x-path"), Some("/hello?x=1"));
+
+    let res = proksi.get("b.test", "/").unwrap();
+    assert_eq!(res.body, b.name);
+
+    assert_eq!(proksi.get("unknown.test", "/").unwrap().status, 404);
+}
+
+#[test]
+fn test_route_headers() {
+    let upstream = MockUpstream::start("a");
+    let headers = r#"    headers:
+      add:
+        - name: "x-added"
+          value: "yes"
+      remove:
+        - name: "x-secret"
+"#;
+
+    let proksi = Proksi::start(&route("headers.test", &[upstream.addr], headers));
+    proksi.wait_for_route("headers.test");
+
+    let res = proksi.get("headers.test", "/").unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("x-added"), Some("yes"));
+    assert_eq!(res.header("x-secret"), None);
+    // The upstream receives the original host
+    assert_eq!(res.header("x-echo-host"), Some("headers.test"));
+}
+
+#[test]
+fn test_route_request_headers() {
+    let upstream = MockUpstream::start("a");
+    let headers = r#"    headers:
+      add_request:
+        - name: "host"
+          value: "internal.test"
+      remove_request:
+        - name: "x-debug"
+      forwarded: true
+"#;
+
+    let proksi = Proksi::start(&route("request-headers.test", &[upstream.addr], headers));
+    proksi.wait_for_route("request-headers.test");
+
+    let res = proksi
+        .get_with_headers(
+            "request-headers.test",
+            "/",
+            &[("x-debug", "1"), ("x-forwarded-for", "198.51.100.1")],
+        )
+        .unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("x-echo-host"), Some("internal.test"));
+    assert_eq!(res.header("x-echo-x-debug"), None);
+    // The client is appended to the chain it came with
+    assert_eq!(
+        res.header("x-echo-x-forwarded-for"),
+        Some("198.51.100.1, 127.0.0.1")
+    );
+    assert_eq!(res.header("x-echo-x-forwarded-proto"), Some("https"));
+    assert_eq!(
+        res.header("x-echo-x-forwarded-host"),
+        Some("request-headers.test")
+    );
+}
+
+#[test]
+fn test_load_balances_between_upstreams() {
+    let a = MockUpstream::start("a");
+    let b = MockUpstream::start("b");
+
+    let proksi = Proksi::start(&route("lb.test", &[a.addr, b.addr], ""));
+    proksi.wait_for_route("lb.test");
+
+    let mut hits = HashMap::<String, usize>::new();
+    for _ in 0..20 {
+        let res = proksi.get("lb.test", "/").unwrap();
+        *hits.entry(res.body).or_default() += 1;
+    }
+
+    assert!(hits.get(a.name).is_some_and(|n| *n >= 5), "{hits:?}");
+    assert!(hits.get(b.name).is_some_and(|n| *n >= 5), "{hits:?}");
+}
+
+#[test]
+fn test_fails_over_to_healthy_upstreams() {
+    let alive = MockUpstream::start("alive");
+    let dead = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+
+    let proksi = Proksi::start(&route("failover.test", &[dead, alive.addr], ""));
+    proksi.wait_for_route("failover.test");
+
+    for _ in 0..10 {
+        let res = proksi.get("failover.test", "/").unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, alive.name);
+    }
+}
+
+#[test]
+fn test_route_compression() {
+    let upstream = MockUpstream::start("a");
+    let compression = r#"    compression:
+      level: 4
+      content_types: ["text/*", "image/png"]
+"#;
+
+    let proksi = Proksi::start(&route("compression.test", &[upstream.addr], compression));
+    proksi.wait_for_route("compression.test");
+
+    let get = |accept_encoding: &str, content_type: &str| {
+        proksi
+            .get_with_headers(
+                "compression.test",
+                "/",
+                &[
+                    ("accept-encoding", accept_encoding),
+                    ("x-content-type", content_type),
+                ],
+            )
+            .unwrap()
+    };
+
+    // Brotli wins over gzip when both are offered
+    let res = get("gzip, br", "text/plain");
+    assert_eq!(res.header("content-encoding"), Some("br"));
+
+    let res = get("gzip", "text/html; charset=utf-8");
+    assert_eq!(res.header("content-encoding"), Some("gzip"));
+
+    // Not in the allowlist
+    let res = get("gzip, br", "application/json");
+    assert_eq!(res.header("content-encoding"), None);
+
+    // Binary types are never compressed, even when listed
+    let res = get("gzip, br", "image/png");
+    assert_eq!(res.header("content-encoding"), None);
+    assert_eq!(res.body, upstream.name.repeat(512));
+}
+
+#[test]
+fn test_route_compression_min_length() {
+    // Bodies of 512 and 1024 bytes
+    let short = MockUpstream::start("a");
+    let long = MockUpstream::start("ab");
+    let compression = r#"    compression:
+      min_length: 1000
+"#;
+
+    let routes = [
+        route("short.compression.test", &[short.addr], compression),
+        route("long.compression.test", &[long.addr], compression),
+    ]
+    .join("");
+    let proksi = Proksi::start(&routes);
+    proksi.wait_for_route("short.compression.test");
+    proksi.wait_for_route("long.compression.test");
+
+    let get = |host| {
+        proksi
+            .get_with_headers(
+                host,
+                "/",
+                &[
+                    ("accept-encoding", "gzip"),
+                    ("x-content-type", "text/plain"),
+                ],
+            )
+            .unwrap()
+    };
+
+    let res = get("short.compression.test");
+    assert_eq!(res.header("content-encoding"), None);
+    assert_eq!(res.body, short.name.repeat(512));
+
+    let res = get("long.compression.test");
+    assert_eq!(res.header("content-encoding"), Some("gzip"));
+}
+
+#[test]
+fn test_route_compression_zstd() {
+    let upstream = MockUpstream::start("a");
+    let compression = r#"    compression:
+      algorithms: ["zstd", "br"]
+      zstd_level: 10
+"#;
+
+    let proksi = Proksi::start(&route("zstd.test", &[upstream.addr], compression));
+    proksi.wait_for_route("zstd.test");
+
+    let get = |accept_encoding: &str| {
+        proksi
+            .get_with_headers(
+                "zstd.test",
+                "/",
+                &[
+                    ("accept-encoding", accept_encoding),
+                    ("x-content-type", "text/plain"),
+                ],
+            )
+            .unwrap()
+    };
+
+    // The preference of the route wins over the order of the client
+    let res = get("br, gzip, zstd");
+    assert_eq!(res.header("content-encoding"), Some("zstd"));
+
+    let res = get("gzip, br");
+    assert_eq!(res.header("content-encoding"), Some("br"));
+
+    // Gzip is not offered by the route
+    let res = get("gzip");
+    assert_eq!(res.header("content-encoding"), None);
+}
+
+#[test]
+fn test_route_compression_overrides_global() {
+    let upstream = MockUpstream::start("a");
+    let get = |proksi: &Proksi, host: &str| {
+        proksi
+            .get_with_headers(
+                host,
+                "/",
+                &[
+                    ("accept-encoding", "gzip"),
+                    ("x-content-type", "text/plain"),
+                ],
+            )
+            .unwrap()
+    };
+
+    // Globally enabled, one route opts out
+    let routes = [
+        route("inherit.test", &[upstream.addr], ""),
+        route(
+            "opt-out.test",
+            &[upstream.addr],
+            "    compression:\n      enabled: false\n",
+        ),
+    ]
+    .join("");
+    let proksi = Proksi::start_with_config(&routes, "compression:\n  enabled: true\n");
+    proksi.wait_for_route("inherit.test");
+    proksi.wait_for_route("opt-out.test");
+
+    assert_eq!(
+        get(&proksi, "inherit.test").header("content-encoding"),
+        Some("gzip")
+    );
+    assert_eq!(
+        get(&proksi, "opt-out.test").header("content-encoding"),
+        None
+    );
+
+    // Globally disabled (the default), one route opts in
+    let routes = [
+        route("inherit.test", &[upstream.addr], ""),
+        route(
+            "opt-in.test",
+            &[upstream.addr],
+            "    compression:\n      level: 1\n",
+        ),
+    ]
+    .join("");
+    let proksi = Proksi::start(&routes);
+    proksi.wait_for_route("inherit.test");
+    proksi.wait_for_route("opt-in.test");
+
+    assert_eq!(
+        get(&proksi, "inherit.test").header("content-encoding"),
+        None
+    );
+    assert_eq!(
+        get(&proksi, "opt-in.test").header("content-encoding"),
+        Some("gzip")
+    );
+}
+
+#[test]
+fn test_rejects_requests_over_header_limits() {
+    let upstream = MockUpstream::start("a");
+    let proksi = Proksi::start_with_config(
+        &route("limits.test", &[upstream.addr], ""),
+        "limits:\n  max_header_size: 4096\n  max_headers: 20\n",
+    );
+    proksi.wait_for_route("limits.test");
+
+    let large = "a".repeat(5000);
+    let res = proksi
+        .get_with_headers("limits.test", "/", &[("x-large", &large)])
+        .unwrap();
+    assert_eq!(res.status, 431);
+
+    let names = (0..25).map(|i| format!("x-header-{i}")).collect::<Vec<_>>();
+    let many = names.iter().map(|n| (n.as_str(), "1")).collect::<Vec<_>>();
+    let res = proksi.get_with_headers("limits.test", "/", &many).unwrap();
+    assert_eq!(res.status, 431);
+
+    let res = proksi
+        .get_with_headers("limits.test", "/", &many[..10])
+        .unwrap();
+    assert_eq!(res.status, 200);
+}
+
+#[test]
+fn test_aborts_truncated_upstream_responses() {
+    let upstream = MockUpstream::start("truncated");
+    let proksi = Proksi::start(&route("truncated.test", &[upstream.addr], ""));
+    proksi.wait_for_route("truncated.test");
+
+    // The connection is closed before the announced length is reached
+    let res = proksi
+        .get_with_headers("truncated.test", "/", &[("x-truncate", "1")])
+        .unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("content-length"), Some("18"));
+    assert!(res.body.len() < 18, "{}", res.body);
+
+    let res = proksi.get("truncated.test", "/").unwrap();
+    assert_eq!(res.body, "truncated");
+}
+
+#[test]
+fn test_allowed_hosts() {
+    let upstream = MockUpstream::start("allowed");
+    let proksi = Proksi::start_with_config(
+        &route("allowed.test", &[upstream.addr], ""),
+        "allowed_hosts: [\"*.apps.test\"]\n",
+    );
+    proksi.wait_for_route("allowed.test");
+
+    // The hosts of the routes are always accepted
+    assert_eq!(proksi.get("allowed.test", "/").unwrap().status, 200);
+    assert_eq!(proksi.get("api.apps.test", "/").unwrap().status, 404);
+    assert_eq!(proksi.get("forged.test", "/").unwrap().status, 400);
+}
+
+#[test]
+fn test_rejects_ambiguous_body_lengths() {
+    let upstream = MockUpstream::start("ambiguous");
+    let proksi = Proksi::start(&route("ambiguous.test", &[upstream.addr], ""));
+    proksi.wait_for_route("ambiguous.test");
+
+    let res = proksi
+        .get_with_headers("ambiguous.test", "/", &[("x-conflicting-length", "1")])
+        .unwrap();
+    assert_eq!(res.status, 502);
+
+    let res = proksi.get("ambiguous.test", "/").unwrap();
+    assert_eq!(res.body, "ambiguous");
+}
+
+#[test]
+fn test_passes_ambiguous_body_lengths() {
+    let upstream = MockUpstream::start("passed");
+    let proksi = Proksi::start_with_config(
+        &route("passed.test", &[upstream.addr], ""),
+        "body_length_mismatches:\n  action: pass\n",
+    );
+    proksi.wait_for_route("passed.test");
+
+    // Read and relayed as chunked, without the `Content-Length`
+    let res = proksi
+        .get_with_headers("passed.test", "/", &[("x-conflicting-length", "1")])
+        .unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("content-length"), None);
+    assert!(res.body.contains("passed"), "{}", res.body);
+}
+
+#[test]
+fn test_relays_informational_responses() {
+    let upstream = MockUpstream::start("continue");
+    let proksi = Proksi::start(&route("continue.test", &[upstream.addr], ""));
+    proksi.wait_for_route("continue.test");
+
+    // The upstream asks for the body, which only then is sent
+    let res = proksi
+        .post_expecting_continue("continue.test", "/upload", "hello")
+        .unwrap();
+    assert_eq!(res.informational, [100]);
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("x-echo-expect"), Some("100-continue"));
+    assert_eq!(res.header("x-body-length"), Some("5"));
+
+    let res = proksi
+        .get_with_headers("continue.test", "/", &[("x-early-hints", "1")])
+        .unwrap();
+    assert_eq!(res.informational, [103]);
+    assert_eq!(res.status, 200);
+}
+
+#[test]
+fn test_withholds_informational_responses() {
+    let upstream = MockUpstream::start("withheld");
+    let proksi = Proksi::start_with_config(
+        &route("withheld.test", &[upstream.addr], ""),
+        "proxy:\n  informational_responses: false\n",
+    );
+    proksi.wait_for_route("withheld.test");
+
+    // proksi asks for the body itself, the upstream gets no `Expect`
+    let res = proksi
+        .post_expecting_continue("withheld.test", "/upload", "hello")
+        .unwrap();
+    assert_eq!(res.informational, [100]);
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("x-echo-expect"), None);
+    assert_eq!(res.header("x-body-length"), Some("5"));
+
+    let res = proksi
+        .get_with_headers("withheld.test", "/", &[("x-early-hints", "1")])
+        .unwrap();
+    // Reduced to a bare `100 Continue`
+    assert_eq!(res.informational, [100]);
+    assert_eq!(res.status, 200);
+}
+
+#[test]
+fn test_answers_expect_continue() {
+    let upstream = MockUpstream::start("answered");
+    let proksi = Proksi::start_with_config(
+        &route("answered.test", &[upstream.addr], ""),
+        "request:\n  buffer_size: 1024\n  expect_continue: answer\n",
+    );
+    proksi.wait_for_route("answered.test");
+
+    // The body is buffered once proksi asked for it, the upstream gets no `Expect`
+    let res = proksi
+        .post_expecting_continue("answered.test", "/upload", "hello")
+        .unwrap();
+    assert_eq!(res.informational, [100]);
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("x-echo-expect"), None);
+    assert_eq!(res.header("x-body-length"), Some("5"));
+}
+
+#[test]
+fn test_auth_middlewares_in_order() {
+    let upstream = MockUpstream::start("authenticated");
+    let auth = r#"    auth:
+      - name: "api_key"
+        config:
+          api_keys: ["script-key"]
+      - name: "basic_auth"
+        config:
+          user: "admin"
+          pass: "secret"
+"#;
+
+    let proksi = Proksi::start(&route("auth.test", &[upstream.addr], auth));
+    proksi.wait_for_route("auth.test");
+
+    // Either middleware authenticates the request
+    let res = proksi
+        .get_with_headers("auth.test", "/", &[("x-api-key", "script-key")])
+        .unwrap();
+    assert_eq!(res.body, "authenticated");
+    let res = proksi
+        .get_with_headers(
+            "auth.test",
+            "/",
+            &[("authorization", "Basic YWRtaW46c2VjcmV0")],
+        )
+        .unwrap();
+    assert_eq!(res.body, "authenticated");
+
+    // Rejected with the challenge of basic auth
+    let res = proksi
+        .get_with_headers("auth.test", "/", &[("x-api-key", "forged")])
+        .unwrap();
+    assert_eq!(res.status, 401);
+    assert_eq!(
+        res.header("www-authenticate"),
+        Some("Basic realm=\"auth.test\", charset=\"UTF-8\"")
+    );
+}
+
+#[test]
+fn test_forward_auth() {
+    let upstream = MockUpstream::start("protected");
+    // Allows every request, naming itself in `x-upstream`
+    let auth_server = MockUpstream::start("auth-server");
+    let forward_auth = |address: String| {
+        format!(
+            r#"    forward_auth:
+      address: "{address}"
+      copy_headers: ["x-upstream"]
+      cache_ttl_secs: 0
+"#
+        )
+    };
+
+    let routes = [
+        route(
+            "forward-auth.test",
+            &[upstream.addr],
+            &forward_auth(format!("http://{}/verify", auth_server.addr)),
+        ),
+        route(
+            "forward-auth-down.test",
+            &[upstream.addr],
+            &forward_auth(format!("http://127.0.0.1:{}/verify", free_port())),
+        ),
+    ]
+    .join("");
+
+    let proksi = Proksi::start(&routes);
+    proksi.wait_for_route("forward-auth.test");
+    proksi.wait_for_route("forward-auth-down.test");
+
+    // The header of the server replaces the one forged by the client
+    let res = proksi
+        .get_with_headers("forward-auth.test", "/", &[("x-upstream", "forged")])
+        .unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, "protected");
+    assert_eq!(res.header("x-echo-x-upstream"), Some("auth-server"));
+
+    // A server that does not answer lets nothing through
+    let res = proksi.get("forward-auth-down.test", "/").unwrap();
+    assert_eq!(res.status, 502);
+}
+
+#[test]
+fn test_route_rate_limit() {
+    let upstream = MockUpstream::start("a");
+    let rate_limit = r#"    rate_limit:
+      requests_per_second: 0.01
+      burst: 2
+      key: "header:x-api-key"
+"#;
+
+    let proksi = Proksi::start(&route("rate-limit.test", &[upstream.addr], rate_limit));
+    proksi.wait_for_route("rate-limit.test");
+
+    let key = [("x-api-key", "key-1")];
+    for _ in 0..2 {
+        let res = proksi
+            .get_with_headers("rate-limit.test", "/", &key)
+            .unwrap();
+        assert_eq!(res.status, 200);
+    }
+    let res = proksi
+        .get_with_headers("rate-limit.test", "/", &key)
+        .unwrap();
+    assert_eq!(res.status, 429);
+    assert!(res.header("retry-after").is_some());
+
+    // Another key has a bucket of its own
+    let res = proksi
+        .get_with_headers("rate-limit.test", "/", &[("x-api-key", "key-2")])
+        .unwrap();
+    assert_eq!(res.status, 200);
+}
+
+#[test]
+fn test_upstream_path_groups() {
+    let web = MockUpstream::start("web");
+    let api = MockUpstream::start("api");
+    // More upstreams of the route, listed after the default one
+    let api_upstream = format!(
+        r#"      - ip: "{}"
+        port: {}
+        match_with:
+          path:
+            patterns: ["/api/*"]
+"#,
+        api.addr.ip(),
+        api.addr.port()
+    );
+
+    let proksi = Proksi::start(&route("paths.test", &[web.addr], &api_upstream));
+    proksi.wait_for_route("paths.test");
+
+    for _ in 0..5 {
+        assert_eq!(
+            proksi.get("paths.test", "/api/users").unwrap().body,
+            api.name
+        );
+        assert_eq!(proksi.get("paths.test", "/").unwrap().body, web.name);
+        assert_eq!(proksi.get("paths.test", "/apis").unwrap().body, web.name);
+    }
+}
+
+#[test]
+fn test_consistent_selection() {
+    let a = MockUpstream::start("a");
+    let b = MockUpstream::start("b");
+
+    let proksi = Proksi::start(&route(
+        "consistent.test",
+        &[a.addr, b.addr],
+        "    selection: \"consistent\"\n",
+    ));
+    proksi.wait_for_route("consistent.test");
+
+    // The same client IP always lands on the same upstream
+    let first = proksi.get("consistent.test", "/").unwrap().body;
+    for _ in 0..10 {
+        assert_eq!(proksi.get("consistent.test", "/").unwrap().body, first);
+    }
+}
+
+#[test]
+fn test_websocket_upgrade() {
+    let upstream = WebSocketUpstream::start();
+
+    let proksi = Proksi::start(&route("ws.test", &[upstream.addr], ""));
+    proksi.wait_for_route("ws.test");
+
+    // The key of the example handshake of RFC 6455
+    let (res, mut socket) = proksi
+        .websocket("ws.test", "/chat", "dGhlIHNhbXBsZSBub25jZQ==")
+        .unwrap();
+    assert_eq!(res.status, 101);
+    assert_eq!(res.header("upgrade"), Some("websocket"));
+    assert_eq!(
+        res.header("sec-websocket-accept"),
+        Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+    );
+
+    // The upstream speaks first, then echoes the frames of the client
+    assert_eq!(read_websocket_frame(&mut socket).unwrap(), b"hello");
+    for message in ["first", "second"] {
+        socket
+            .write_all(&websocket_frame(message.as_bytes(), Some([1, 2, 3, 4])))
+            .unwrap();
+        assert_eq!(
+            read_websocket_frame(&mut socket).unwrap(),
+            message.as_bytes()
+        );
+    }
+}
+
+#[test]
+fn test_route_read_timeout() {
+    let upstream = MockUpstream::start("slow");
+    let routes = [
+        route(
+            "read-timeout.test",
+            &[upstream.addr],
+            "    timeouts:\n      read_ms: 300\n",
+        ),
+        route("default-timeout.test", &[upstream.addr], ""),
+    ]
+    .join("");
+
+    let proksi = Proksi::start(&routes);
+    proksi.wait_for_route("read-timeout.test");
+    proksi.wait_for_route("default-timeout.test");
+
+    let res = proksi.get("read-timeout.test", "/").unwrap();
+    assert_eq!(res.status, 200);
+
+    // An upstream sending nothing for longer than the timeout gets a 504
+    let res = proksi
+        .get_with_headers("read-timeout.test", "/", &[("x-delay-ms", "1000")])
+        .unwrap();
+    assert_eq!(res.status, 504);
+
+    // The other routes keep the global timeouts
+    let res = proksi
+        .get_with_headers("default-timeout.test", "/", &[("x-delay-ms", "1000")])
+        .unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, "slow");
+}
@@ -0,0 +1,114 @@
+//! End to end tests of the TCP/UDP stream proxy
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{free_port, Proksi};
+
+/// A TCP upstream answering every connection with its name
+fn tcp_upstream(name: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            stream.write_all(name.as_bytes()).ok();
+        }
+    });
+
+    addr
+}
+
+/// A UDP upstream echoing every datagram prefixed with its name
+fn udp_upstream(name: &'static str) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+            let reply = [name.as_bytes(), b":", &buf[..len]].concat();
+            socket.send_to(&reply, peer).ok();
+        }
+    });
+
+    addr
+}
+
+fn stream(protocol: &str, listen: u16, upstreams: &[SocketAddr]) -> String {
+    let upstreams = common::upstreams(upstreams);
+    format!("  - listen: \"127.0.0.1:{listen}\"\n    protocol: \"{protocol}\"\n    upstreams:\n{upstreams}")
+}
+
+/// Retries `f` until it succeeds, as the stream services start in the background
+fn eventually<T>(mut f: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while Instant::now() < deadline {
+        if let Some(value) = f() {
+            return value;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("stream did not answer in time");
+}
+
+#[test]
+fn test_tcp_stream_fails_over_to_healthy_upstream() {
+    let alive = tcp_upstream("alive");
+    let dead = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let port = free_port();
+
+    let config = format!("streams:\n{}", stream("tcp", port, &[dead, alive]));
+    let _proksi = Proksi::start_with_config("", &config);
+
+    for _ in 0..5 {
+        let body = eventually(|| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+            let mut body = String::new();
+            stream.read_to_string(&mut body).ok()?;
+            Some(body).filter(|b| !b.is_empty())
+        });
+        assert_eq!(body, "alive");
+    }
+}
+
+#[test]
+fn test_udp_stream_keeps_client_affinity() {
+    let a = udp_upstream("a");
+    let b = udp_upstream("b");
+    let port = free_port();
+
+    let config = format!("streams:\n{}", stream("udp", port, &[a, b]));
+    let _proksi = Proksi::start_with_config("", &config);
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+
+    let exchange = |message: &str| {
+        eventually(|| {
+            client
+                .send_to(message.as_bytes(), ("127.0.0.1", port))
+                .ok()?;
+            let mut buf = [0; 1024];
+            let len = client.recv(&mut buf).ok()?;
+            Some(String::from_utf8_lossy(&buf[..len]).to_string())
+        })
+    };
+
+    let first = exchange("ping");
+    let upstream = first.split(':').next().unwrap().to_string();
+    assert!(first.ends_with(":ping"), "{first}");
+
+    // The same client keeps talking to the same upstream
+    for _ in 0..5 {
+        assert_eq!(exchange("ping"), format!("{upstream}:ping"));
+    }
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{route, MockUpstream, Proksi};
+
+#[test]
+fn test_upgrade_hands_over_listening_sockets() {
+    let upstream = MockUpstream::start("a");
+    let mut proksi = Proksi::start(&route("upgrade.test", &[upstream.addr], ""));
+    proksi.wait_for_route("upgrade.test");
+
+    let mut old = proksi.upgrade();
+
+    // The old instance keeps accepting connections for a few seconds after
+    // handing over its sockets: requests must keep succeeding past that point
+    let deadline = Instant::now() + Duration::from_secs(8);
+    while Instant::now() < deadline {
+        let res = proksi.get("upgrade.test", "/").unwrap();
+        assert_eq!(res.status, 200);
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // Only the new instance is left listening
+    old.kill().ok();
+    old.wait().ok();
+
+    let res = proksi.get("upgrade.test", "/").unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, "a");
+}